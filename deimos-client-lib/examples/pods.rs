@@ -0,0 +1,67 @@
+//! Lists all pods on a Deimos server, then enables the pod with the given ID if one is provided.
+//!
+//! The server URI and a base64-encoded token are read from the environment:
+//!
+//! ```sh
+//! DEIMOS_URI=https://deimos.example.com DEIMOS_TOKEN=<token> cargo run -p deimos-client-lib --example pods -- [pod id]
+//! ```
+
+use std::{process::ExitCode, sync::Arc};
+
+use deimos_client_lib::{pod::PodState, proto::auth::DeimosTokenKey, DeimosClientBuilder, DeimosToken};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let (Ok(uri), Ok(token)) = (std::env::var("DEIMOS_URI"), std::env::var("DEIMOS_TOKEN")) else {
+        eprintln!("DEIMOS_URI and DEIMOS_TOKEN must be set");
+        return ExitCode::FAILURE
+    };
+
+    let uri = match uri.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            eprintln!("Invalid server URI '{}': {}", uri, e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let key = match DeimosTokenKey::from_base64(&token) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Invalid token: {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let token = DeimosToken::new(Arc::from("example"), chrono::Utc::now(), key);
+    let mut client = match DeimosClientBuilder::new(uri).token(token).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let pods = match client.query_pods(false).await {
+        Ok(pods) => pods,
+        Err(e) => {
+            eprintln!("Failed to query pods: {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    for pod in &pods {
+        println!("{:<24} {:<32} {:?}", pod.id, pod.title, pod.state);
+    }
+
+    if let Some(id) = std::env::args().nth(1) {
+        if let Err(e) = client.update_pod(&id, PodState::Enabled, None).await {
+            eprintln!("Failed to enable pod {}: {}", id, e);
+            return ExitCode::FAILURE
+        }
+
+        println!("Enabled pod {}", id);
+    }
+
+    ExitCode::SUCCESS
+}
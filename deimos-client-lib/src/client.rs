@@ -0,0 +1,264 @@
+use std::{sync::Arc, time::Duration};
+
+use deimosproto::{authclient::DeimosAuthorizationClient, client::DeimosServiceClient, limit::MessageSizeLimit};
+use futures::{Stream, StreamExt};
+use http::Uri;
+use tokio::sync::Notify;
+use tonic::transport::{Channel, ClientTlsConfig};
+
+use crate::{
+    layer::{
+        auth::{AuthorizationLayer, AuthorizationService, TokenSource},
+        cancel::{CancelLayer, CancelService},
+        conn::{ConnectionObserver, ConnectionTracker, ConnectionTrackerLayer},
+        limit::{MessageSizeLayer, MessageSizeService},
+    },
+    pod::{PodState, PodStatusEvent, PodSummary},
+    DeimosClientError,
+    DeimosToken,
+};
+
+/// Service stack used for requests to the authorized pod control API
+pub type ApiService<T, O> = MessageSizeService<
+    AuthorizationService<
+        CancelService<
+            ConnectionTracker<Channel, O>
+        >,
+        T,
+    >
+>;
+
+/// A client for the authorized pod control API
+pub type ApiClient<T, O> = DeimosServiceClient<ApiService<T, O>>;
+
+/// A client for the restricted authorization API, requests are multiplexed over the same channel
+/// as standard API requests
+pub type AuthClient = DeimosAuthorizationClient<CancelService<Channel>>;
+
+/// Builder for a [DeimosClient] with the connection settings, token, and optional hooks into the
+/// client's service stack
+#[derive(Debug)]
+pub struct DeimosClientBuilder<T = Option<DeimosToken>, O = ()> {
+    uri: Uri,
+    tls: Option<ClientTlsConfig>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    limit: MessageSizeLimit,
+    token: T,
+    observer: O,
+    cancel: Arc<Notify>,
+}
+
+/// A client for the Deimos API with typed methods for common requests.
+/// Requests without a typed method can be made with the underlying clients from
+/// [api](Self::api) and [auth](Self::auth)
+#[derive(Debug, Clone)]
+pub struct DeimosClient<T = Option<DeimosToken>, O = ()> {
+    api: ApiClient<T, O>,
+    auth: AuthClient,
+}
+
+impl DeimosClientBuilder {
+    /// Create a builder for a client connecting to the server at the given URI using TLS with
+    /// the WebPKI root certificates and no token
+    pub fn new(uri: Uri) -> Self {
+        Self {
+            uri,
+            tls: Some(ClientTlsConfig::new().with_webpki_roots()),
+            connect_timeout: None,
+            request_timeout: None,
+            limit: MessageSizeLimit::default(),
+            token: None,
+            observer: (),
+            cancel: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl<T: TokenSource, O: ConnectionObserver> DeimosClientBuilder<T, O> {
+    /// Use the given TLS configuration instead of the WebPKI root certificates
+    pub fn tls(self, tls: ClientTlsConfig) -> Self {
+        Self { tls: Some(tls), ..self }
+    }
+
+    /// Connect without TLS, only suitable for servers on a trusted network
+    pub fn plaintext(self) -> Self {
+        Self { tls: None, ..self }
+    }
+
+    pub fn connect_timeout(self, timeout: Duration) -> Self {
+        Self { connect_timeout: Some(timeout), ..self }
+    }
+
+    pub fn request_timeout(self, timeout: Duration) -> Self {
+        Self { request_timeout: Some(timeout), ..self }
+    }
+
+    /// Set the maximum size of a single message sent to or received from the server
+    pub fn message_size_limit(self, limit: MessageSizeLimit) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// Notifier that cancels all in-flight requests of the built client when notified
+    pub fn cancel(self, cancel: Arc<Notify>) -> Self {
+        Self { cancel, ..self }
+    }
+
+    /// Authorize all requests with the given token
+    pub fn token(self, token: DeimosToken) -> DeimosClientBuilder<Option<DeimosToken>, O> {
+        self.token_source(Some(token))
+    }
+
+    /// Read the token used to authorize each request from the given source
+    pub fn token_source<U: TokenSource>(self, token: U) -> DeimosClientBuilder<U, O> {
+        DeimosClientBuilder {
+            uri: self.uri,
+            tls: self.tls,
+            connect_timeout: self.connect_timeout,
+            request_timeout: self.request_timeout,
+            limit: self.limit,
+            token,
+            observer: self.observer,
+            cancel: self.cancel,
+        }
+    }
+
+    /// Report the state of the connection after every request to the given observer
+    pub fn observer<P: ConnectionObserver>(self, observer: P) -> DeimosClientBuilder<T, P> {
+        DeimosClientBuilder {
+            uri: self.uri,
+            tls: self.tls,
+            connect_timeout: self.connect_timeout,
+            request_timeout: self.request_timeout,
+            limit: self.limit,
+            token: self.token,
+            observer,
+            cancel: self.cancel,
+        }
+    }
+
+    /// Create the client, connecting lazily when the first request is made
+    pub fn build(self) -> Result<DeimosClient<T, O>, DeimosClientError> {
+        let mut endpoint = Channel::builder(self.uri);
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = self.request_timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+
+        if let Some(tls) = self.tls {
+            endpoint = endpoint.tls_config(tls)?;
+        }
+
+        let channel = endpoint.connect_lazy();
+        let api = DeimosServiceClient::new(
+            tower::ServiceBuilder::new()
+                .layer(MessageSizeLayer::new(self.limit))
+                .layer(AuthorizationLayer::new(self.token))
+                .layer(CancelLayer::new(self.cancel.clone()))
+                .layer(ConnectionTrackerLayer::new(self.observer))
+                .service(channel.clone())
+        )
+        .max_decoding_message_size(self.limit.bytes())
+        .max_encoding_message_size(self.limit.bytes());
+
+        let auth = AuthClient::new(
+            tower::ServiceBuilder::new()
+                .layer(CancelLayer::new(self.cancel))
+                .service(channel),
+        )
+        .max_decoding_message_size(self.limit.bytes())
+        .max_encoding_message_size(self.limit.bytes());
+
+        Ok(DeimosClient { api, auth })
+    }
+}
+
+impl<T: TokenSource, O: ConnectionObserver> DeimosClient<T, O> {
+    /// Get the underlying client for the authorized pod control API
+    pub fn api(&mut self) -> &mut ApiClient<T, O> {
+        &mut self.api
+    }
+
+    /// Get the underlying client for the authorization API
+    pub fn auth(&mut self) -> &mut AuthClient {
+        &mut self.auth
+    }
+
+    /// Get a brief description of every pod on the server, including archived pods if requested
+    pub async fn query_pods(&mut self, include_archived: bool) -> Result<Vec<PodSummary>, DeimosClientError> {
+        let response = self
+            .api
+            .query_pods(deimosproto::QueryPodsRequest { include_archived })
+            .await?
+            .into_inner();
+
+        Ok(response.pods.into_iter().map(PodSummary::from).collect())
+    }
+
+    /// Request that the given pod be transitioned to the given state, optionally disabling it
+    /// again after the given duration when enabling it
+    pub async fn update_pod(&mut self, id: &str, state: PodState, duration: Option<Duration>) -> Result<(), DeimosClientError> {
+        let request = deimosproto::UpdatePodRequest {
+            id: id.to_owned(),
+            method: state as i32,
+            duration_seconds: duration.map(|duration| duration.as_secs()).unwrap_or_default(),
+        };
+
+        self.api.update_pod(request).await?;
+        Ok(())
+    }
+
+    /// Request that the given pod be restarted, restarting its container in place if it is
+    /// enabled and starting it otherwise
+    pub async fn restart_pod(&mut self, id: &str) -> Result<(), DeimosClientError> {
+        self.api.restart_pod(deimosproto::RestartPodRequest { id: id.to_owned() }).await?;
+        Ok(())
+    }
+
+    /// Subscribe to changes in the state of all pods and annotations reported by them
+    pub async fn subscribe_status(&mut self) -> Result<impl Stream<Item = Result<PodStatusEvent, DeimosClientError>>, DeimosClientError> {
+        let stream = self
+            .api
+            .subscribe_pod_status(deimosproto::PodStatusStreamRequest {})
+            .await?
+            .into_inner();
+
+        Ok(stream.map(|event| event.map(PodStatusEvent::from).map_err(DeimosClientError::from)))
+    }
+
+    /// Subscribe to the output of the given pod's container, starting with all output written
+    /// since the container started
+    pub async fn subscribe_logs(&mut self, id: &str) -> Result<impl Stream<Item = Result<Vec<u8>, DeimosClientError>>, DeimosClientError> {
+        let request = deimosproto::PodLogStreamRequest {
+            id: id.to_owned(),
+            follow: true,
+            ..Default::default()
+        };
+
+        let stream = self
+            .api
+            .subscribe_pod_logs(request)
+            .await?
+            .into_inner();
+
+        Ok(stream.map(|chunk| chunk.map(|chunk| chunk.chunk).map_err(DeimosClientError::from)))
+    }
+
+    /// Request a new token for the given user, waiting until an administrator approves or denies
+    /// the request
+    pub async fn request_token(&mut self, user: String) -> Result<DeimosToken, DeimosClientError> {
+        let request = deimosproto::TokenRequest {
+            user,
+            datetime: chrono::Utc::now().timestamp(),
+        };
+
+        let mut stream = self.auth.request_token(request).await?.into_inner();
+        match stream.next().await {
+            Some(token) => Ok(DeimosToken::from_proto(token?)?),
+            None => Err(DeimosClientError::TokenNotReceived),
+        }
+    }
+}
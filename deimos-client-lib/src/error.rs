@@ -0,0 +1,32 @@
+use deimosproto::ErrorCode;
+
+use crate::token::DeimosTokenConvertError;
+
+/// Error returned by the typed methods of a [DeimosClient](crate::DeimosClient)
+#[derive(Debug, thiserror::Error)]
+pub enum DeimosClientError {
+    #[error("Failed to configure connection: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("Request failed: {}: {}", .0.code(), .0.message())]
+    Status(Box<tonic::Status>),
+    #[error("Failed to decode token: {0}")]
+    Token(#[from] DeimosTokenConvertError),
+    #[error("Token request stream closed before a token was received")]
+    TokenNotReceived,
+}
+
+impl DeimosClientError {
+    /// Get the error code attached to the status returned by the server, if any
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Status(status) => deimosproto::ErrorDetail::from_status(status).map(|detail| detail.code()),
+            _ => None,
+        }
+    }
+}
+
+impl From<tonic::Status> for DeimosClientError {
+    fn from(value: tonic::Status) -> Self {
+        Self::Status(Box::new(value))
+    }
+}
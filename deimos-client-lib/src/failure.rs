@@ -0,0 +1,264 @@
+use std::{error::Error, fmt};
+
+use deimosproto::ErrorCode;
+use tonic::Code;
+
+/// Description of the most recent failure to reach the server, classified so that a concise
+/// reason can be shown to the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionFailure {
+    pub kind: ConnectionFailureKind,
+    /// Full description of the failure including all underlying causes
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionFailureKind {
+    /// The host name of the server could not be resolved
+    Dns,
+    /// Nothing accepted the connection at the server's address
+    Refused,
+    /// The connection or request did not complete before the configured timeout
+    Timeout,
+    /// No route to the server's network or host exists
+    Unreachable,
+    /// The server presented an expired TLS certificate
+    CertificateExpired,
+    /// The server's TLS certificate is not trusted or is not valid for its host name
+    CertificateInvalid,
+    /// The TLS handshake failed for another reason
+    Tls,
+    /// The connection was closed before a response was received
+    Reset,
+    /// The server rejected the authorization token
+    TokenRejected,
+    /// The server or a proxy in front of it reported that it is unavailable
+    Unavailable,
+    /// The failure could not be classified
+    Other,
+}
+
+impl ConnectionFailure {
+    /// Classify a transport error by inspecting each error in its chain of sources
+    pub fn from_error(err: &(dyn Error + 'static)) -> Self {
+        let chain = std::iter::successors(Some(err), |err| (*err).source()).collect::<Vec<_>>();
+        let kind = chain
+            .iter()
+            .find_map(|err| ConnectionFailureKind::classify(*err))
+            .unwrap_or(ConnectionFailureKind::Other);
+
+        let message = chain
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ");
+
+        Self { kind, message }
+    }
+
+    /// Classify a status returned in place of a response
+    pub fn from_status(status: &tonic::Status) -> Self {
+        Self {
+            kind: ConnectionFailureKind::from_status(status).unwrap_or(ConnectionFailureKind::Other),
+            message: format!("{:?}: {}", status.code(), status.message()),
+        }
+    }
+}
+
+impl ConnectionFailureKind {
+    /// Description given by the HTTP connector to failures resolving the server's host name.
+    /// Neither the connector nor the system resolver give these failures a type of their own, so
+    /// the connector's error is recognized by its fixed description
+    const DNS_ERROR: &str = "dns error";
+
+    /// Get the kind of failure described by a single error in a chain, if it is specific enough
+    /// to be classified
+    fn classify(err: &(dyn Error + 'static)) -> Option<Self> {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            return match io.kind() {
+                std::io::ErrorKind::ConnectionRefused => Some(Self::Refused),
+                std::io::ErrorKind::TimedOut => Some(Self::Timeout),
+                std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => Some(Self::Unreachable),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof => Some(Self::Reset),
+                //IO errors wrapping another error, such as those returned for TLS failures, do
+                //not give it as their source
+                _ => io.get_ref().and_then(|inner| Self::classify(inner)),
+            }
+        }
+
+        if let Some(tls) = err.downcast_ref::<rustls::Error>() {
+            return Some(Self::from_tls(tls))
+        }
+
+        if let Some(hyper) = err.downcast_ref::<hyper::Error>() {
+            return if hyper.is_timeout() {
+                Some(Self::Timeout)
+            } else if hyper.is_closed() || hyper.is_incomplete_message() || hyper.is_canceled() {
+                Some(Self::Reset)
+            } else {
+                None
+            }
+        }
+
+        if err.is::<tonic::TimeoutExpired>() {
+            return Some(Self::Timeout)
+        }
+
+        if let Some(status) = err.downcast_ref::<tonic::Status>() {
+            return Self::from_status(status)
+        }
+
+        (err.to_string() == Self::DNS_ERROR).then_some(Self::Dns)
+    }
+
+    /// Get the kind of failure reported by a status, if it is specific enough to be classified
+    fn from_status(status: &tonic::Status) -> Option<Self> {
+        match deimosproto::ErrorDetail::from_status(status).map(|detail| detail.code()) {
+            Some(ErrorCode::TokenMissing | ErrorCode::TokenInvalid) => Some(Self::TokenRejected),
+            _ => match status.code() {
+                Code::Unauthenticated => Some(Self::TokenRejected),
+                Code::Unavailable => Some(Self::Unavailable),
+                Code::DeadlineExceeded => Some(Self::Timeout),
+                _ => None,
+            }
+        }
+    }
+
+    /// Get the kind of a failed TLS handshake, from our verification of the server's
+    /// certificate or from the alert that the server sent
+    fn from_tls(err: &rustls::Error) -> Self {
+        use rustls::{AlertDescription, CertificateError};
+
+        match err {
+            rustls::Error::InvalidCertificate(CertificateError::Expired | CertificateError::ExpiredContext { .. })
+                | rustls::Error::AlertReceived(AlertDescription::CertificateExpired) => Self::CertificateExpired,
+            rustls::Error::InvalidCertificate(..)
+                | rustls::Error::AlertReceived(AlertDescription::BadCertificate | AlertDescription::UnknownCA) => Self::CertificateInvalid,
+            _ => Self::Tls,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionFailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dns => "Server address could not be resolved",
+            Self::Refused => "Connection refused",
+            Self::Timeout => "Connection timed out",
+            Self::Unreachable => "Server unreachable",
+            Self::CertificateExpired => "TLS certificate expired",
+            Self::CertificateInvalid => "TLS certificate not trusted",
+            Self::Tls => "TLS handshake failed",
+            Self::Reset => "Connection closed by server",
+            Self::TokenRejected => "Token rejected",
+            Self::Unavailable => "Server unavailable",
+            Self::Other => "Connection failed",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Error wrapping another, like those of the transport's connectors
+    #[derive(Debug)]
+    struct Wrapped(&'static str, Box<dyn Error + Send + Sync>);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(self.1.as_ref())
+        }
+    }
+
+    fn classify(err: impl Into<Box<dyn Error + Send + Sync>>) -> ConnectionFailureKind {
+        let err = Wrapped("transport error", Box::new(Wrapped("tcp connect error", err.into())));
+        ConnectionFailure::from_error(&err).kind
+    }
+
+    fn io(kind: std::io::ErrorKind) -> std::io::Error {
+        std::io::Error::new(kind, "io")
+    }
+
+    #[test]
+    fn io_kinds() {
+        assert_eq!(classify(io(std::io::ErrorKind::ConnectionRefused)), ConnectionFailureKind::Refused);
+        assert_eq!(classify(io(std::io::ErrorKind::TimedOut)), ConnectionFailureKind::Timeout);
+        assert_eq!(classify(io(std::io::ErrorKind::NetworkUnreachable)), ConnectionFailureKind::Unreachable);
+        assert_eq!(classify(io(std::io::ErrorKind::BrokenPipe)), ConnectionFailureKind::Reset);
+        assert_eq!(classify(io(std::io::ErrorKind::Other)), ConnectionFailureKind::Other);
+    }
+
+    #[test]
+    fn tls_errors_inside_io_errors() {
+        let tls = |err: rustls::Error| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        assert_eq!(
+            classify(tls(rustls::Error::InvalidCertificate(rustls::CertificateError::Expired))),
+            ConnectionFailureKind::CertificateExpired,
+        );
+        assert_eq!(
+            classify(tls(rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer))),
+            ConnectionFailureKind::CertificateInvalid,
+        );
+        assert_eq!(
+            classify(tls(rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidForName))),
+            ConnectionFailureKind::CertificateInvalid,
+        );
+        assert_eq!(
+            classify(tls(rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure))),
+            ConnectionFailureKind::Tls,
+        );
+    }
+
+    #[test]
+    fn resolver_errors() {
+        let err = Wrapped(ConnectionFailureKind::DNS_ERROR, Box::new(io(std::io::ErrorKind::Other)));
+        assert_eq!(classify(err), ConnectionFailureKind::Dns);
+
+        //Only the connector's own description is recognized
+        let err = Wrapped("dns error: lookup failed", Box::new(io(std::io::ErrorKind::Other)));
+        assert_eq!(classify(err), ConnectionFailureKind::Other);
+    }
+
+    #[test]
+    fn timeouts_and_statuses() {
+        assert_eq!(classify(tonic::TimeoutExpired(())), ConnectionFailureKind::Timeout);
+        assert_eq!(classify(tonic::Status::unavailable("down")), ConnectionFailureKind::Unavailable);
+        assert_eq!(classify(tonic::Status::internal("down")), ConnectionFailureKind::Other);
+    }
+
+    #[test]
+    fn outermost_classified_error_wins() {
+        let err = Wrapped(ConnectionFailureKind::DNS_ERROR, Box::new(io(std::io::ErrorKind::TimedOut)));
+        assert_eq!(classify(err), ConnectionFailureKind::Dns);
+    }
+
+    #[test]
+    fn messages_include_every_cause() {
+        let err = Wrapped("transport error", Box::new(io(std::io::ErrorKind::ConnectionRefused)));
+        let failure = ConnectionFailure::from_error(&err);
+        assert_eq!(failure.kind, ConnectionFailureKind::Refused);
+        assert_eq!(failure.message, "transport error: io");
+    }
+
+    #[test]
+    fn statuses() {
+        let failure = ConnectionFailure::from_status(&tonic::Status::unauthenticated("no token"));
+        assert_eq!(failure.kind, ConnectionFailureKind::TokenRejected);
+        assert_eq!(failure.message, "Unauthenticated: no token");
+
+        let status = deimosproto::ErrorDetail::new(ErrorCode::TokenInvalid, "bad token").into_status(Code::PermissionDenied);
+        assert_eq!(ConnectionFailure::from_status(&status).kind, ConnectionFailureKind::TokenRejected);
+        assert_eq!(ConnectionFailure::from_status(&tonic::Status::deadline_exceeded("slow")).kind, ConnectionFailureKind::Timeout);
+        assert_eq!(ConnectionFailure::from_status(&tonic::Status::not_found("gone")).kind, ConnectionFailureKind::Other);
+    }
+}
@@ -0,0 +1,70 @@
+use deimosproto::auth::DeimosTokenKey;
+use http::{HeaderValue, Request};
+use tower::{Layer, Service};
+
+use crate::DeimosToken;
+
+/// Source of the token used to authorize each request, read again for every request so that the
+/// token may change while the client is in use
+pub trait TokenSource: Clone + Send + Sync + 'static {
+    /// Get the authorization header value for the current token, if one is available
+    fn header(&self) -> Option<HeaderValue>;
+}
+
+/// Layer that will wrap a service in an [AuthorizationService]
+pub struct AuthorizationLayer<T> {
+    token: T,
+}
+
+/// A service that attaches the current token from a [TokenSource] to every request
+#[derive(Debug, Clone)]
+pub struct AuthorizationService<S, T> {
+    inner: S,
+    token: T,
+}
+
+impl<T> AuthorizationLayer<T> {
+    pub const fn new(token: T) -> Self {
+        Self {
+            token
+        }
+    }
+}
+
+impl<S, T: Clone> Layer<S> for AuthorizationLayer<T> {
+    type Service = AuthorizationService<S, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthorizationService {
+            inner,
+            token: self.token.clone(),
+        }
+    }
+}
+
+impl<S, T, B, R> Service<Request<B>> for AuthorizationService<S, T>
+where 
+    S: Service<Request<B>, Response = R>,
+    T: TokenSource,
+{
+    type Response = R;
+    type Future = S::Future;
+    type Error = S::Error;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+    
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Some(auth) = self.token.header() {
+            req.headers_mut().insert(DeimosTokenKey::HTTP_HEADER_NAME, auth);
+        }
+        self.inner.call(req)
+    }
+}
+
+impl TokenSource for Option<DeimosToken> {
+    fn header(&self) -> Option<HeaderValue> {
+        self.as_ref().and_then(DeimosToken::header)
+    }
+}
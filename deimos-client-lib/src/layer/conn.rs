@@ -0,0 +1,147 @@
+use std::{future::Future, task::Poll};
+
+use deimosproto::ErrorCode;
+use pin_project::pin_project;
+use tonic::Code;
+use tower::{Layer, Service};
+
+use crate::failure::ConnectionFailure;
+
+/// State of the connection to the server as determined from the most recent response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Unknown,
+    /// The server rejected the request because no token or an invalid token was provided
+    NoToken,
+    Connected,
+    Error,
+}
+
+/// Receives the connection state determined from each response of a [ConnectionTracker]
+pub trait ConnectionObserver: Clone + Send + Sync + 'static {
+    /// Record the state of the connection after a response, with the reason for the failure if
+    /// the request failed and a reason is known
+    fn observe(&self, state: ConnectionState, failure: Option<ConnectionFailure>);
+}
+
+/// A layer that will wrap a service with a [ConnectionTracker]
+pub struct ConnectionTrackerLayer<O> {
+    observer: O,
+}
+
+/// A [Service] that tracks responses from each request and reports the state of the connection
+/// to a [ConnectionObserver], with the reason for each failure
+#[derive(Debug, Clone,)]
+pub struct ConnectionTracker<S, O> {
+    inner: S,
+    observer: O,
+}
+
+#[pin_project]
+pub struct ConnectionTrackerFuture<F, O> {
+    #[pin]
+    inner: F,
+    observer: O,
+}
+
+impl<O> ConnectionTrackerLayer<O> {
+    /// Create a new layer that will report the results of a wrapped service to the given
+    /// observer
+    pub const fn new(observer: O) -> Self {
+        Self {
+            observer,
+        }
+    }
+}
+
+impl<S, O: Clone> Layer<S> for ConnectionTrackerLayer<O> {
+    type Service = ConnectionTracker<S, O>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectionTracker {
+            inner,
+            observer: self.observer.clone(),
+        }
+    }
+}
+
+impl<S, O, B> Service<http::Request<B>> for ConnectionTracker<S, O>
+where 
+    S: Service<http::Request<B>, Response = http::Response<B>> + Clone + Send + 'static,
+    S::Error: std::error::Error + Send + 'static,
+    S::Future: Send + 'static,
+    O: ConnectionObserver,
+    B: tonic::transport::Body + Send + 'static
+{
+    type Response = http::Response<B>;
+    type Error = S::Error;
+    type Future = ConnectionTrackerFuture<S::Future, O>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let inner = self.inner.call(request);
+
+        ConnectionTrackerFuture {
+            inner,
+            observer: self.observer.clone(),
+        }
+    }
+}
+
+impl<F, O, T, E> Future for ConnectionTrackerFuture<F, O>
+where 
+    F: Future<Output = Result<http::Response<T>, E>>,
+    O: ConnectionObserver,
+    E: std::error::Error + 'static {
+
+    type Output = F::Output;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        let project = self.project();
+        let response = futures::ready!(project.inner.poll(cx));
+        match response {
+            Ok(response) => {
+                let status = tonic::Status::from_header_map(response.headers());
+                let connstat = if let Some(ref status) = status {
+                    match deimosproto::ErrorDetail::from_status(status) {
+                        //Failures reported with a detail come from the server's handlers, so the
+                        //connection itself is fine unless our token was rejected
+                        Some(detail) => match detail.code() {
+                            ErrorCode::TokenMissing | ErrorCode::TokenInvalid => ConnectionState::NoToken,
+                            _ => ConnectionState::Connected,
+                        },
+                        None => match status.code() {
+                            Code::Ok => ConnectionState::Connected,
+                            Code::Unauthenticated => ConnectionState::NoToken,
+                            _ => ConnectionState::Error,
+                        },
+                    }
+                } else {
+                    ConnectionState::Connected
+                };
+
+                let failure = match (connstat, status) {
+                    (ConnectionState::Connected, _) | (_, None) => None,
+                    (_, Some(status)) => Some(ConnectionFailure::from_status(&status)),
+                };
+
+                project.observer.observe(connstat, failure);
+
+                Poll::Ready(Ok(response))
+            },
+            Err(e) => {
+                let failure = ConnectionFailure::from_error(&e);
+                tracing::debug!("Connection failed ({}): {}", failure.kind, failure.message);
+                project.observer.observe(ConnectionState::Error, Some(failure));
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+impl ConnectionObserver for () {
+    fn observe(&self, _: ConnectionState, _: Option<ConnectionFailure>) {}
+}
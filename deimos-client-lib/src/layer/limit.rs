@@ -0,0 +1,51 @@
+use deimosproto::limit::MessageSizeLimit;
+use http::{HeaderValue, Request};
+use tower::{Layer, Service};
+
+/// Layer that advertises the client's maximum message size to the server in every request
+pub struct MessageSizeLayer {
+    limit: MessageSizeLimit,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSizeService<S> {
+    inner: S,
+    limit: HeaderValue,
+}
+
+impl MessageSizeLayer {
+    pub const fn new(limit: MessageSizeLimit) -> Self {
+        Self {
+            limit
+        }
+    }
+}
+
+impl<S> Layer<S> for MessageSizeLayer {
+    type Service = MessageSizeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MessageSizeService {
+            inner,
+            limit: HeaderValue::from(self.limit.bytes()),
+        }
+    }
+}
+
+impl<S, B, R> Service<Request<B>> for MessageSizeService<S>
+where 
+    S: Service<Request<B>, Response = R>
+{
+    type Response = R;
+    type Future = S::Future;
+    type Error = S::Error;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+    
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        req.headers_mut().insert(MessageSizeLimit::HTTP_HEADER_NAME, self.limit.clone());
+        self.inner.call(req)
+    }
+}
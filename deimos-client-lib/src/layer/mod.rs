@@ -0,0 +1,7 @@
+//! Tower layers making up the service stack of a [DeimosClient](crate::DeimosClient), exposed so
+//! that they can be reused with custom stacks
+
+pub mod conn;
+pub mod cancel;
+pub mod auth;
+pub mod limit;
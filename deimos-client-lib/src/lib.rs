@@ -0,0 +1,35 @@
+//! Typed Rust client for the Deimos API, providing the connection and authorization plumbing
+//! used by the Deimos GUI client without any GUI dependencies.
+//!
+//! ```no_run
+//! # async fn example(token: deimos_client_lib::DeimosToken) -> Result<(), deimos_client_lib::DeimosClientError> {
+//! use deimos_client_lib::{DeimosClientBuilder, pod::PodState};
+//!
+//! let mut client = DeimosClientBuilder::new("https://deimos.example.com".parse().unwrap())
+//!     .token(token)
+//!     .build()?;
+//!
+//! for pod in client.query_pods(false).await? {
+//!     println!("{} - {:?}", pod.id, pod.state);
+//! }
+//!
+//! client.update_pod("minecraft", PodState::Enabled, None).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+pub mod failure;
+pub mod layer;
+pub mod pod;
+mod token;
+
+/// Generated protocol types, for requests made with the underlying clients
+pub use deimosproto as proto;
+
+pub use client::{ApiClient, ApiService, AuthClient, DeimosClient, DeimosClientBuilder};
+pub use error::DeimosClientError;
+pub use failure::{ConnectionFailure, ConnectionFailureKind};
+pub use layer::{auth::TokenSource, conn::{ConnectionObserver, ConnectionState}};
+pub use token::{DeimosToken, DeimosTokenConvertError};
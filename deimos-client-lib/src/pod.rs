@@ -0,0 +1,175 @@
+use std::{collections::BTreeMap, fmt, net::{IpAddr, SocketAddr}};
+
+use chrono::{DateTime, Utc};
+
+pub use deimosproto::{PodAnnotationLevel, PodBlock, PodState, PodStateCause};
+
+/// Brief description of a pod as returned when querying all pods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PodSummary {
+    /// ID used to refer to the pod in API requests
+    pub id: String,
+    /// User-facing title of the pod
+    pub title: String,
+    pub state: PodState,
+    /// Next transition planned by the pod's schedule, if it has one
+    pub next_transition: Option<PodTransition>,
+    /// Time that the server will automatically disable the pod at, if it was enabled for a
+    /// limited duration
+    pub enabled_until: Option<DateTime<Utc>>,
+    /// Resource usage alerts currently firing for the pod
+    pub alerts: Vec<PodAlert>,
+    /// Set if the pod has been archived and cannot be enabled
+    pub archived: bool,
+    /// Reason that the pod cannot currently be enabled
+    pub blocked: PodBlock,
+    /// Addresses that the pod's published ports can be reached at, empty unless the pod is
+    /// enabled
+    pub endpoints: Vec<PodEndpoint>,
+}
+
+/// Best-known reachable address of a port published by a pod
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PodEndpoint {
+    /// Either `tcp` or `udp`
+    pub protocol: String,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Set if the address is the external address of the server's gateway
+    pub external: bool,
+}
+
+/// A transition planned by a pod's schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PodTransition {
+    pub action: PodState,
+    pub at: DateTime<Utc>,
+    /// Set if the transition has been overridden and will not occur
+    pub skipped: bool,
+}
+
+/// A resource usage alert that is firing for a pod
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PodAlert {
+    /// Human-readable description of the alert rule
+    pub rule: String,
+    pub since: DateTime<Utc>,
+}
+
+/// A change in a pod's state, or an annotation reported from inside its container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PodStatusEvent {
+    pub id: String,
+    pub state: PodState,
+    pub cause: PodStateCause,
+    /// Set if the event forwards an annotation, in which case the state is unchanged
+    pub annotation: Option<PodAnnotation>,
+    /// Position of the event in the stream, skipping events that the server dropped
+    pub sequence: u64,
+    /// ID of the daemon run that sent the event, changed whenever the daemon restarts
+    pub epoch: u64,
+    /// Next transition planned by the pod's schedule when the event was sent
+    pub next_transition: Option<PodTransition>,
+    /// Resource usage alerts of the pod that were firing when the event was sent
+    pub alerts: Vec<PodAlert>,
+    /// Set if the pod was archived when the event was sent
+    pub archived: bool,
+    /// Time that the pod's container last stopped, sent with events of disabled pods
+    pub stopped_at: Option<DateTime<Utc>>,
+}
+
+/// An event reported by the application running inside a pod's container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PodAnnotation {
+    pub at: DateTime<Utc>,
+    pub level: PodAnnotationLevel,
+    pub message: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Convert a UNIX timestamp received from the server, where 0 indicates that no time is set
+fn timestamp(secs: i64) -> Option<DateTime<Utc>> {
+    DateTime::<Utc>::from_timestamp(secs, 0).filter(|_| secs != 0)
+}
+
+impl From<deimosproto::PodBrief> for PodSummary {
+    fn from(proto: deimosproto::PodBrief) -> Self {
+        Self {
+            state: proto.state(),
+            blocked: proto.blocked(),
+            id: proto.id,
+            title: proto.title,
+            next_transition: proto.next_transition.and_then(PodTransition::from_proto),
+            enabled_until: timestamp(proto.enabled_until),
+            alerts: proto
+                .alerts
+                .into_iter()
+                .filter_map(|alert| Some(PodAlert { since: timestamp(alert.since)?, rule: alert.rule }))
+                .collect(),
+            archived: proto.archived,
+            endpoints: proto.endpoints.into_iter().filter_map(PodEndpoint::from_proto).collect(),
+        }
+    }
+}
+
+impl PodEndpoint {
+    /// Decode an endpoint, returning `None` if its address or port is invalid
+    pub fn from_proto(proto: deimosproto::PodEndpoint) -> Option<Self> {
+        Some(Self {
+            address: proto.address.parse().ok()?,
+            port: u16::try_from(proto.port).ok()?,
+            protocol: proto.protocol,
+            external: proto.external,
+        })
+    }
+}
+
+impl fmt::Display for PodEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        SocketAddr::new(self.address, self.port).fmt(f)
+    }
+}
+
+impl PodTransition {
+    /// Decode a scheduled transition, returning `None` if its time is out of range
+    pub fn from_proto(proto: deimosproto::PodScheduledTransition) -> Option<Self> {
+        Some(Self {
+            action: proto.action(),
+            at: timestamp(proto.at)?,
+            skipped: proto.skipped,
+        })
+    }
+}
+
+impl From<deimosproto::PodStatusNotification> for PodStatusEvent {
+    fn from(proto: deimosproto::PodStatusNotification) -> Self {
+        Self {
+            state: proto.state(),
+            cause: proto.cause(),
+            id: proto.id,
+            annotation: proto.annotation.and_then(PodAnnotation::from_proto),
+            sequence: proto.sequence,
+            epoch: proto.epoch,
+            next_transition: proto.next_transition.and_then(PodTransition::from_proto),
+            alerts: proto
+                .alerts
+                .into_iter()
+                .filter_map(|alert| Some(PodAlert { since: timestamp(alert.since)?, rule: alert.rule }))
+                .collect(),
+            archived: proto.archived,
+            stopped_at: timestamp(proto.stopped_at),
+        }
+    }
+}
+
+impl PodAnnotation {
+    /// Decode an annotation, returning `None` if its time is out of range
+    pub fn from_proto(proto: deimosproto::PodAnnotation) -> Option<Self> {
+        Some(Self {
+            at: timestamp(proto.at)?,
+            level: proto.level(),
+            message: proto.message,
+            fields: proto.fields.into_iter().collect(),
+        })
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use deimosproto::auth::DeimosTokenKey;
+use http::HeaderValue;
+
+/// An unprotected token as it is sent in the API
+#[derive(Clone,)]
+pub struct DeimosToken {
+    pub user: Arc<str>,
+    pub issued: DateTime<Utc>,
+    pub key: DeimosTokenKey,
+    base64: Arc<str>,
+}
+
+impl DeimosToken {
+    pub fn new(user: Arc<str>, issued: DateTime<Utc>, key: DeimosTokenKey) -> Self {
+        Self {
+            user,
+            issued,
+            base64: key.to_base64().into(),
+            key,
+        }
+    }
+
+    /// Get a chached base64 string representing the token
+    pub fn base64(&self) -> &str {
+        &self.base64
+    }
+
+    /// Get the value of the header used to authorize requests with this token
+    pub fn header(&self) -> Option<HeaderValue> {
+        match HeaderValue::from_str(self.base64()) {
+            Ok(header) => Some(header),
+            Err(e) => {
+                tracing::error!("Failed to create authorization token header value: {}", e);
+                None
+            }
+        }
+    }
+    
+    /// Decode a protobuf containing a token
+    pub fn from_proto(proto: deimosproto::Token) -> Result<Self, DeimosTokenConvertError>  {
+        let user = proto.name.into();
+        let issued = DateTime::<Utc>::from_timestamp(proto.issued, 0).ok_or(DeimosTokenConvertError::DateTime)?;
+        let key = DeimosTokenKey::from_bytes(proto.key);
+        let base64 = key.to_base64().into();
+
+        Ok(Self {
+            user,
+            issued,
+            key,
+            base64,
+        })
+    }
+}
+
+impl std::fmt::Debug for DeimosToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f
+            .debug_struct("DeimosToken")
+            .field("user", &self.user)
+            .field("issued", &self.issued)
+            .field("token", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeimosTokenConvertError {
+    #[error("Out of range UNIX timestamp")]
+    DateTime,
+}
@@ -2,7 +2,7 @@ use fltk::{button::Button, enums::{Align, CallbackTrigger, FrameType}, frame::Fr
 
 use crate::context::client::auth::{PersistentTokenKind, TokenStatus};
 
-use super::{orbit, style::{self}, DeimosStateHandle};
+use super::{error, orbit, server::{server_choice, SelectedWatch}, style::{self}, time::{self, TimeRefresh}, ui::UiLock, DeimosStateHandle, DeimosView};
 
 
 
@@ -16,6 +16,8 @@ pub fn authorization(state: DeimosStateHandle) -> Group {
     let header = header(state.clone());
     top.fixed(&header, 42);
 
+    let (server, _) = server_choice(state.clone());
+    top.fixed(&server, 60);
     
     top.fixed(&label("Current Token"), 40);
     top.fixed(&token_box(state.clone()), 140);
@@ -24,7 +26,7 @@ pub fn authorization(state: DeimosStateHandle) -> Group {
 
     top.fixed(&label("Token Request"), 40);
     let request = request_group(state.clone());
-    top.fixed(&request, 140);
+    top.fixed(&request, 184);
 
     Frame::default_fill();
 
@@ -44,7 +46,7 @@ pub fn authorization(state: DeimosStateHandle) -> Group {
     {
         let state = state.clone();
         dpapi_button.set_callback(move |_| {
-            let token_protect = state.ctx.clients.token_protect.clone();
+            let token_protect = state.selected_server().clients.token_protect.clone();
             let current = *token_protect.read();
             token_protect.set(
                 match current {
@@ -62,29 +64,29 @@ pub fn authorization(state: DeimosStateHandle) -> Group {
     {
         let state = state.clone();
         tokio::task::spawn(async move {
-            let mut sub = state.ctx.clients.token_protect.subscribe();
+            let mut sub = SelectedWatch::new(&state, |server| &server.clients.token_protect);
             loop {
                 let protect = *sub.borrow_and_update();
 
-                fltk::app::lock().ok();
-                match protect {
-                    PersistentTokenKind::Plaintext => {
-                        protection_status.set_label("Token is not encrypted at-rest");
-                        protection_status.set_label_color(orbit::MARS[1]);
-                        dpapi_button.set_label("Enable encryption");
-                    },
-                    #[cfg(windows)]
-                    PersistentTokenKind::Dpapi => {
-                        protection_status.set_label("Token is encrypted at-rest");
-                        protection_status.set_label_color(orbit::EARTH[1]);
-                        dpapi_button.set_label("Disable encryption");
+                {
+                    let _ui = UiLock::acquire();
+                    match protect {
+                        PersistentTokenKind::Plaintext => {
+                            protection_status.set_label("Token is not encrypted at-rest");
+                            protection_status.set_label_color(orbit::MARS[1]);
+                            dpapi_button.set_label("Enable encryption");
+                        },
+                        #[cfg(windows)]
+                        PersistentTokenKind::Dpapi => {
+                            protection_status.set_label("Token is encrypted at-rest");
+                            protection_status.set_label_color(orbit::EARTH[1]);
+                            dpapi_button.set_label("Disable encryption");
+                        }
                     }
+
+                    dpapi_button.set_damage(true);
+                    protection_status.set_damage(true);
                 }
-                
-                dpapi_button.set_damage(true);
-                protection_status.set_damage(true);
-                fltk::app::unlock();
-                fltk::app::awake();
 
                 if sub.changed().await.is_err() {
                     break
@@ -109,6 +111,14 @@ fn label(lbl: &str) -> Frame {
 fn request_group(state: DeimosStateHandle) -> Pack {
     let pack = Pack::default_fill();
 
+    //Shown first so that requests made against the wrong server are noticed
+    let mut server = Frame::default();
+    server.set_size(pack.width(), 24);
+    server.set_label_font(crate::app::SUBTITLE_FONT);
+    server.set_label_size(14);
+    server.set_label_color(orbit::SOL[1]);
+    server.set_align(Align::Left | Align::Inside);
+
     let (mut frame, mut username) = style::input::input_box::<Input>("Username");
     frame.set_size(pack.width(), 40);
     match hostname::get() {
@@ -125,6 +135,13 @@ fn request_group(state: DeimosStateHandle) -> Pack {
         }
     }
 
+    let mut warning = Frame::default();
+    warning.set_size(pack.width(), 20);
+    warning.set_label_font(crate::app::GENERAL_FONT);
+    warning.set_label_size(12);
+    warning.set_label_color(orbit::MARS[1]);
+    warning.set_align(Align::Left | Align::Inside);
+
     let mut request_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
     request_button.set_size(pack.width(), 40);
     request_button.set_label("Submit Token Request");
@@ -137,10 +154,20 @@ fn request_group(state: DeimosStateHandle) -> Pack {
     status.set_label_font(crate::app::SUBTITLE_FONT);
     status.set_align(Align::Center | Align::Inside);
 
+    let mut details_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    details_button.set_size(pack.width(), 24);
+    details_button.set_label("Show Details");
+    details_button.set_label_font(crate::app::GENERAL_FONT);
+    details_button.set_label_size(12);
+    details_button.set_label_color(orbit::MERCURY[1]);
+    details_button.hide();
+
+    warn_token_like(&mut warning, &username.value());
     username.set_trigger(CallbackTrigger::Changed);
     username.set_callback(move |u| {
         u.set_color(orbit::NIGHT[1]);
         u.redraw();
+        warn_token_like(&mut warning, &u.value());
     });
 
     pack.end();
@@ -148,7 +175,6 @@ fn request_group(state: DeimosStateHandle) -> Pack {
     {
         let state = state.clone();
         request_button.set_callback(move |_| {
-            let state = state.clone();
             let name = username.value();
             if name.is_empty() {
                 username.set_color(orbit::MARS[3]);
@@ -156,43 +182,105 @@ fn request_group(state: DeimosStateHandle) -> Pack {
                 return
             }
 
+            let server = state.selected_server();
+
+            //Tokens are only replaced once the new token is received, but a token approved by
+            //mistake would still replace the one in use
+            let existing = server.clients.token.read().token().map(|token| token.user.clone());
+            if let Some(existing) = existing {
+                let message = format!(
+                    "A token for '{}' is already in use. Request a new token as '{}' to replace it once approved?",
+                    existing,
+                    name,
+                );
+
+                if fltk::dialog::choice2_default(&message, "Cancel", "Replace existing token", "") != Some(1) {
+                    return
+                }
+            }
+
             tokio::task::spawn(async move {
-                state.ctx.clients.request_token(name).await;
+                server.clients.request_token(name).await;
             });
         });
     }
 
+    {
+        let state = state.clone();
+        details_button.set_callback(move |_| {
+            if let TokenStatus::Denied { error: Some(ref error), .. } = *state.selected_server().clients.token.read() {
+                error::error_window(error);
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            let mut sub = SelectedWatch::new(&state, |server| &server.clients.settings);
+            loop {
+                {
+                    let settings = sub.borrow_and_update();
+                    let _ui = UiLock::acquire();
+                    server.set_label(&format!("Server: {}", settings.server_uri));
+                    server.set_damage(true);
+                }
+
+                if sub.changed().await.is_err() {
+                    break
+                }
+            }
+        });
+    }
+
     {
         tokio::task::spawn(
             async move {
-                let mut sub = state.ctx.clients.token.subscribe();
+                let mut sub = SelectedWatch::new(&state, |server| &server.clients.token);
                 loop {
                     {
-                        let token = sub.borrow();
-                        fltk::app::lock().ok();
+                        let token = sub.borrow_and_update();
+                        let _ui = UiLock::acquire();
                         request_button.activate();
+                        details_button.hide();
 
                         match *token {
-                            TokenStatus::Denied { ref reason } => {
+                            TokenStatus::Denied { ref reason, ref error, ref previous } => {
                                 status.set_label_color(orbit::MARS[2]);
                                 status.set_label_size(12);
-                                status.set_label(reason);
+                                match previous {
+                                    Some(_) => status.set_label(&format!("{} - keeping the current token", reason)),
+                                    None => status.set_label(reason),
+                                }
+
+                                if error.is_some() {
+                                    details_button.show();
+                                }
                             },
-                            TokenStatus::Requested { ref user, .. } => {
+                            TokenStatus::Requested { ref user, ref previous } => {
                                 status.set_label_color(orbit::EARTH[1]);
                                 status.set_label_size(14);
-                                status.set_label(&format!("Requested token with username '{}'", user));
+                                match previous {
+                                    Some(_) => status.set_label(&format!("Requested token with username '{}', the current token is used until it is approved", user)),
+                                    None => status.set_label(&format!("Requested token with username '{}'", user)),
+                                }
                                 request_button.deactivate();
                             },
                             TokenStatus::None | TokenStatus::Token(..) => {
                                 status.set_label("");
                             },
                         }
+
+                        request_button.set_label(match token.token() {
+                            Some(_) => "Request Replacement Token",
+                            None => "Submit Token Request",
+                        });
                         
                         status.set_damage(true);
                         request_button.set_damage(true);
-                        fltk::app::unlock();
-                        fltk::app::awake();
+                        if let Some(mut parent) = details_button.parent() {
+                            parent.redraw();
+                        }
                     }
 
                     if sub.changed().await.is_err() {
@@ -206,6 +294,16 @@ fn request_group(state: DeimosStateHandle) -> Pack {
     pack
 }
 
+/// Show a warning under the username input if the entered username looks like a pasted token
+fn warn_token_like(warning: &mut Frame, user: &str) {
+    match TokenStatus::username_looks_like_token(user) {
+        true => warning.set_label("This looks like a token, not a username - enter a name for the server operator to recognize"),
+        false => warning.set_label(""),
+    }
+
+    warning.set_damage(true);
+}
+
 fn token_box(state: DeimosStateHandle) -> Flex {
     let mut container = Flex::default_fill().column();
     container.set_margins(8, 8, 8, 8);
@@ -217,31 +315,33 @@ fn token_box(state: DeimosStateHandle) -> Flex {
     let mut fingerprint = token_box_field("Fingerprint");
     
     tokio::task::spawn(async move {
-        let mut sub = state.ctx.clients.token.subscribe();
+        let mut sub = SelectedWatch::new(&state, |server| &server.clients.token);
+        let mut refresh = TimeRefresh::new(&state, DeimosView::Authorization);
         loop {
             {
                 let token = sub.borrow_and_update();
-
-                fltk::app::lock().ok();
+                let _ui = UiLock::acquire();
 
                 if let Some(token) = token.token() {
                     username.set_label(&token.user);
-                    issued.set_label(&token.issued.naive_local().format("%B %d %Y").to_string());
+                    issued.set_label(&time::relative(token.issued, chrono::Utc::now()));
+                    issued.set_tooltip(&time::full(token.issued));
                     fingerprint.set_label(&token.key.fingerprint());
                 } else {
                     username.set_label("");
                     issued.set_label("");
+                    issued.set_tooltip("");
                     fingerprint.set_label("");
                 }
 
                 fltk::app::redraw();
-
-                fltk::app::unlock();
-                fltk::app::awake();
             }
 
-            if sub.changed().await.is_err() {
-                break
+            tokio::select! {
+                result = sub.changed() => if result.is_err() {
+                    break
+                },
+                _ = refresh.tick() => {},
             }
         }
     });
@@ -284,7 +384,7 @@ fn header(state: DeimosStateHandle) -> Flex {
     back_button.set_callback(move |_| {
         let state = state.clone();
         tokio::task::spawn(async move {
-            state.set_view(state.overview.clone()).await;
+            state.set_view(DeimosView::Overview).await;
         });
     });
 
@@ -0,0 +1,176 @@
+use chrono::Utc;
+use fltk::{browser::HoldBrowser, button::Button, enums::{Align, FrameType}, frame::Frame, group::Flex, prelude::{BrowserExt, DisplayExt, GroupExt, WidgetBase, WidgetExt, WindowExt}, text::{TextBuffer, TextDisplay}, window::Window};
+
+use crate::context::{dir::CacheDirChoice, error::{ErrorLog, ErrorRecord}, NotifyMutation};
+
+use super::{orbit, style, time};
+
+/// Open a window showing the full status returned for the given error, with a button to copy it
+pub fn error_window(error: &ErrorRecord) {
+    let mut window = Window::default()
+        .with_size(560, 320)
+        .with_label("Error details");
+    window.make_resizable(true);
+
+    let mut flex = Flex::default_fill().column();
+    flex.set_margins(8, 8, 8, 8);
+    flex.set_spacing(8);
+
+    let mut summary = Frame::default();
+    summary.set_label_font(crate::app::HEADER_FONT);
+    summary.set_label_size(16);
+    summary.set_label_color(orbit::MARS[1]);
+    summary.set_align(Align::Inside | Align::Left | Align::Clip);
+    summary.set_label(&error.summary);
+    flex.fixed(&summary, 24);
+
+    let mut message = Frame::default();
+    message.set_label_font(crate::app::GENERAL_FONT);
+    message.set_label_size(12);
+    message.set_label_color(orbit::MERCURY[1]);
+    message.set_align(Align::Inside | Align::Left | Align::Clip);
+    message.set_label(&format!("{} {}", error.message, time::relative(error.at, Utc::now())));
+    flex.fixed(&message, 16);
+
+    let details = error.details();
+    let mut buffer = TextBuffer::default();
+    buffer.set_text(&details);
+    details_display(buffer);
+
+    let mut copy = copy_button();
+    copy.set_callback(move |_| fltk::app::copy(&details));
+    flex.fixed(&copy, 32);
+
+    flex.end();
+    window.set_color(orbit::NIGHT[1]);
+    window.end();
+    window.show();
+}
+
+/// Open a window listing the most recent errors returned by the server, showing the details of
+/// the selected error
+pub fn error_log_window(errors: NotifyMutation<ErrorLog>) {
+    let mut window = Window::default()
+        .with_size(720, 480)
+        .with_label("Recent errors");
+    window.make_resizable(true);
+
+    let mut flex = Flex::default_fill().column();
+    flex.set_margins(8, 8, 8, 8);
+    flex.set_spacing(8);
+
+    let mut list = HoldBrowser::default();
+    list.set_frame(FrameType::FlatBox);
+    list.set_color(orbit::NIGHT[3]);
+    list.set_selection_color(orbit::NIGHT[0]);
+    list.set_text_size(12);
+    list.set_text_color(orbit::MERCURY[0]);
+    flex.fixed(&list, 160);
+
+    let mut buffer = TextBuffer::default();
+    details_display(buffer.clone());
+    let mut copy = copy_button();
+    flex.fixed(&copy, 32);
+
+    flex.end();
+    window.set_color(orbit::NIGHT[1]);
+    window.end();
+    window.show();
+
+    //The log is read once when the window is opened so that entries do not move while one is
+    //selected
+    let records = errors.read().iter().cloned().collect::<Vec<_>>();
+    let now = Utc::now();
+    for record in records.iter() {
+        let count = match record.count {
+            1 => String::new(),
+            count => format!(" (x{})", count),
+        };
+
+        list.add(&format!("{}\t{}{}", time::relative(record.at, now), record.summary, count));
+    }
+
+    if records.is_empty() {
+        buffer.set_text("No errors have occurred");
+        copy.deactivate();
+    }
+
+    list.set_column_char('\t');
+    list.set_column_widths(&[120, 0]);
+
+    {
+        let mut buffer = buffer.clone();
+        let mut copy = copy.clone();
+        list.set_callback(move |list| {
+            let selected = usize::try_from(list.value()).ok().and_then(|line| line.checked_sub(1));
+            match selected.and_then(|idx| records.get(idx)) {
+                Some(record) => {
+                    buffer.set_text(&record.details());
+                    copy.activate();
+                },
+                None => {
+                    buffer.set_text("");
+                    copy.deactivate();
+                },
+            }
+        });
+    }
+
+    copy.set_callback(move |_| fltk::app::copy(&buffer.text()));
+}
+
+/// Show a blocking dialog explaining that no directory tried could be written to, letting the
+/// user choose another directory, continue without saving, or quit. Must be called from the
+/// thread running the FLTK event loop
+pub fn cache_dir_dialog(failures: &[String]) -> CacheDirChoice {
+    let tried = failures
+        .iter()
+        .map(|failure| format!("\n{}", failure))
+        .collect::<String>();
+
+    let message = format!(
+        "Settings, tokens, and cached pods cannot be saved as none of these directories can be written to:\n{}\n\nChoose another directory to save them in, or continue without saving anything.",
+        tried,
+    );
+
+    loop {
+        match fltk::dialog::choice2_default(&message, "Quit", "Continue without saving", "Choose directory") {
+            Some(2) => {
+                let mut chooser = fltk::dialog::NativeFileChooser::new(fltk::dialog::NativeFileChooserType::BrowseDir);
+                chooser.set_title("Choose a directory to save settings in");
+                chooser.show();
+
+                //The chosen path is empty if the chooser was cancelled
+                let dir = chooser.filename();
+                if !dir.as_os_str().is_empty() {
+                    return CacheDirChoice::Directory(dir)
+                }
+            },
+            Some(1) => return CacheDirChoice::Ephemeral,
+            _ => return CacheDirChoice::Quit,
+        }
+    }
+}
+
+/// Create a read-only text display for the details of an error shown in the given buffer
+fn details_display(buffer: TextBuffer) -> TextDisplay {
+    let mut display = TextDisplay::default();
+    display.set_frame(FrameType::FlatBox);
+    display.set_color(orbit::NIGHT[3]);
+    display.set_text_font(crate::app::GENERAL_FONT);
+    display.set_text_size(12);
+    display.set_text_color(orbit::SOL[1]);
+    display.set_selection_color(orbit::NIGHT[0]);
+    display.set_buffer(buffer);
+    display
+}
+
+/// Create a button that copies the details of an error to the clipboard
+fn copy_button() -> Button {
+    let mut copy = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[0]);
+    copy.set_label("Copy details");
+    copy.set_label_font(crate::app::GENERAL_FONT);
+    copy.set_label_size(12);
+    copy.set_label_color(orbit::SOL[0]);
+    copy
+}
@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use fltk::{button::Button, enums::{Align, Color, FrameType}, frame::Frame, group::{Flex, Group}, image::SvgImage, prelude::{DisplayExt, GroupExt, WidgetBase, WidgetExt}, text::{PositionType, TextBuffer, TextDisplay}};
+use tokio::sync::Notify;
+
+use crate::context::pod::CachedPod;
+
+use super::{orbit, style, ui::{self, UiLock}, DeimosStateHandle, DeimosView};
+
+/// Maximum number of bytes of output kept in the display, after which the oldest lines are
+/// removed as new output arrives
+const MAX_LOG_LENGTH: i32 = 1 << 20;
+
+/// View displaying the output of the pod selected by the user as it is written.
+/// The stream of output is only held open while the view is shown, and is reopened from the
+/// start of the container's output when the view is shown again
+pub fn logs(state: DeimosStateHandle) -> Group {
+    let mut top = Group::default_fill();
+    top.hide();
+
+    let mut flex = Flex::default_fill().column();
+    flex.set_margins(8, 8, 8, 8);
+    flex.set_spacing(8);
+
+    let mut header = Flex::default().row();
+    header.set_spacing(8);
+    flex.fixed(&header, 32);
+
+    let mut title = Frame::default();
+    title.set_label_font(crate::app::HEADER_FONT);
+    title.set_label_size(18);
+    title.set_label_color(orbit::SOL[1]);
+    title.set_align(Align::Inside | Align::Left | Align::Clip);
+
+    let mut stats = Frame::default();
+    stats.set_label_font(crate::app::SUBTITLE_FONT);
+    stats.set_label_size(12);
+    stats.set_label_color(orbit::MERCURY[1]);
+    stats.set_align(Align::Inside | Align::Right | Align::Clip);
+    stats.set_tooltip("Resource usage of the pod's container");
+
+    let mut status = Frame::default();
+    status.set_label_font(crate::app::SUBTITLE_FONT);
+    status.set_label_size(12);
+    status.set_label_color(orbit::MERCURY[1]);
+    status.set_align(Align::Inside | Align::Right | Align::Clip);
+
+    let reload_svg = SvgImage::from_data(include_str!("../../assets/reload.svg")).unwrap();
+    let reload_rgb = style::svg::svg_color(reload_svg, 24, orbit::EARTH[1]);
+    let mut reconnect_button = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[0]);
+    reconnect_button.set_image(Some(reload_rgb));
+    reconnect_button.set_tooltip("Reconnect to the pod's logs");
+    reconnect_button.hide();
+    header.fixed(&reconnect_button, 32);
+
+    let close_svg = SvgImage::from_data(include_str!("../../assets/close.svg")).unwrap();
+    let close_rgb = style::svg::svg_color(close_svg, 24, orbit::MERCURY[1]);
+    let mut close_button = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[0]);
+    close_button.set_image(Some(close_rgb));
+    close_button.set_tooltip("Return to the pod list");
+    header.fixed(&close_button, 32);
+
+    header.end();
+
+    let buffer = TextBuffer::default();
+    let mut display = TextDisplay::default();
+    display.set_frame(FrameType::FlatBox);
+    display.set_color(orbit::NIGHT[3]);
+    display.set_text_font(crate::app::GENERAL_FONT);
+    display.set_text_size(12);
+    display.set_text_color(orbit::SOL[1]);
+    display.set_selection_color(orbit::NIGHT[0]);
+    display.set_buffer(buffer.clone());
+
+    flex.end();
+    top.end();
+
+    {
+        let state = state.clone();
+        close_button.set_callback(move |_| {
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                state.set_view(DeimosView::Overview).await;
+                state.ctx.logs.set(None);
+            });
+        });
+    }
+
+    let reconnect = Arc::new(Notify::new());
+
+    {
+        let reconnect = reconnect.clone();
+        reconnect_button.set_callback(move |_| reconnect.notify_waiters());
+    }
+
+    tokio::task::spawn(async move {
+        let mut pod_sub = state.ctx.logs.subscribe();
+        let mut view_sub = state.view.subscribe();
+        loop {
+            let pod = pod_sub.borrow_and_update().clone();
+            let shown = *view_sub.borrow_and_update() == DeimosView::Logs;
+
+            //Leaving the view or selecting another pod drops the stream of the current pod
+            let changed = match pod.filter(|_| shown) {
+                Some(pod) => {
+                    let widgets = LogWidgets {
+                        header: header.clone(),
+                        title: title.clone(),
+                        status: status.clone(),
+                        reconnect_button: reconnect_button.clone(),
+                        display: display.clone(),
+                        buffer: buffer.clone(),
+                    };
+
+                    tokio::select! {
+                        never = show_logs(&state, &pod, &reconnect, widgets) => never,
+                        never = show_stats(&state, &pod, stats.clone()) => never,
+                        changed = pod_sub.changed() => changed,
+                        changed = view_sub.changed() => changed,
+                    }
+                },
+                None => tokio::select! {
+                    changed = pod_sub.changed() => changed,
+                    changed = view_sub.changed() => changed,
+                },
+            };
+
+            if changed.is_err() {
+                break
+            }
+        }
+    });
+
+    top
+}
+
+/// Widgets of the log view updated while a pod's output is streamed
+struct LogWidgets {
+    header: Flex,
+    title: Frame,
+    status: Frame,
+    reconnect_button: Button,
+    display: TextDisplay,
+    buffer: TextBuffer,
+}
+
+/// Stream the output of the given pod into the display until the stream is dropped, waiting for
+/// the user to reconnect when the stream fails
+async fn show_logs(state: &DeimosStateHandle, pod: &Arc<CachedPod>, reconnect: &Notify, mut widgets: LogWidgets) -> ! {
+    loop {
+        {
+            let name = pod.data.name.read().clone();
+            let _ui = UiLock::acquire();
+            widgets.title.set_label(&name);
+            widgets.title.set_damage(true);
+            widgets.buffer.set_text("");
+            widgets.set_status("Connecting", orbit::MERCURY[1], false);
+        }
+
+        let end = match state.ctx.subscribe_logs(pod).await {
+            Ok(mut stream) => {
+                ui::with_lock(|| widgets.set_status("Connected", orbit::EARTH[1], false));
+                loop {
+                    match stream.next().await {
+                        Ok(Some(text)) => ui::with_lock(|| widgets.append(&text)),
+                        Ok(None) => break String::from("The server closed the stream"),
+                        Err(e) => {
+                            state.ctx.errors.record(format!("Log stream of pod {} failed", pod.data.id), &e);
+                            break format!("Connection failed: {}", e.message())
+                        },
+                    }
+                }
+            },
+            Err(e) => format!("Failed to subscribe: {}", e),
+        };
+
+        //The waiter is registered before the button is shown so that no click is missed
+        let notified = reconnect.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        ui::with_lock(|| widgets.set_status(&end, orbit::MARS[0], true));
+        notified.await;
+    }
+}
+
+/// Show the resource usage of the given pod in the header while it is enabled
+async fn show_stats(state: &DeimosStateHandle, pod: &Arc<CachedPod>, mut frame: Frame) -> ! {
+    state.ctx.stream_pod_stats(pod, |stats| ui::with_lock(|| {
+        match stats {
+            Some(stats) => {
+                let cpu = stats.cpu.map(|cpu| format!("{:.1}%", cpu)).unwrap_or_else(|| String::from("-"));
+                let memory = match stats.memory_limit {
+                    0 => format_bytes(stats.memory_used),
+                    limit => format!("{} / {}", format_bytes(stats.memory_used), format_bytes(limit)),
+                };
+
+                frame.set_label(&format!(
+                    "CPU {}  RAM {}  RX {}  TX {}",
+                    cpu,
+                    memory,
+                    format_bytes(stats.network_rx),
+                    format_bytes(stats.network_tx),
+                ));
+            },
+            None => frame.set_label(""),
+        }
+
+        frame.set_damage(true);
+    })).await
+}
+
+/// Format a number of bytes with the largest binary unit that keeps the number above one
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{}{}", bytes, UNITS[0]),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+impl LogWidgets {
+    /// Set the status shown in the header, showing the reconnect button if the stream has ended.
+    /// The UI lock must be held
+    fn set_status(&mut self, label: &str, color: Color, ended: bool) {
+        self.status.set_label(label);
+        self.status.set_label_color(color);
+        self.status.set_damage(true);
+        match ended {
+            true => self.reconnect_button.show(),
+            false => self.reconnect_button.hide(),
+        }
+
+        let header = self.header.clone();
+        fltk::app::awake_callback(move || header.layout());
+    }
+
+    /// Append output to the display, scrolling to the new output if the end of the output was
+    /// already visible so that scrolling up to read earlier output is not interrupted.
+    /// The UI lock must be held
+    fn append(&mut self, text: &str) {
+        let follow = self.at_bottom();
+        self.buffer.append(text);
+
+        let excess = self.buffer.length() - MAX_LOG_LENGTH;
+        if excess > 0 {
+            let cut = (self.buffer.line_end(excess) + 1).min(self.buffer.length());
+            self.buffer.remove(0, cut);
+        }
+
+        if follow {
+            self.display.set_insert_position(self.buffer.length());
+            self.display.show_insert_position();
+        }
+
+        self.display.redraw();
+    }
+
+    /// Check if the last line of output is visible in the display
+    fn at_bottom(&self) -> bool {
+        let scrollbar = self.display.scrollbar_size().max(fltk::app::scrollbar_size());
+        let bottom = self.display.xy_to_position(
+            self.display.x() + 4,
+            self.display.y() + self.display.h() - scrollbar - 4,
+            PositionType::Character,
+        );
+
+        self.display.count_lines(bottom, self.buffer.length(), true) <= 1
+    }
+}
@@ -4,14 +4,20 @@ use fltk::{app::App, enums::{Align, Font}, group::Group, prelude::{GroupExt, Wid
 use once_cell::sync::OnceCell;
 use tokio::sync::Mutex;
 
-use crate::context::Context;
+use crate::context::{dir::CacheDirChoice, server::ServerId, Context};
 
 
 pub mod orbit;
 pub mod style;
+pub mod time;
+pub mod ui;
+mod error;
+mod logs;
 mod over;
 mod auth;
+mod server;
 mod settings;
+mod terminal;
 
 
 pub struct DeimosState {
@@ -20,6 +26,22 @@ pub struct DeimosState {
     settings: Group,
     overview: Group,
     authorization: Group,
+    terminal: Group,
+    logs: Group,
+    /// View that is currently shown, used to pause refreshing labels in hidden views
+    view: tokio::sync::watch::Sender<DeimosView>,
+    /// Server whose settings and token are shown in the settings and authorization views
+    selected: tokio::sync::watch::Sender<ServerId>,
+}
+
+/// Top-level views of the window, only one of which is shown at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeimosView {
+    Overview,
+    Settings,
+    Authorization,
+    Terminal,
+    Logs,
 }
 
 #[derive(Clone, Default)]
@@ -37,28 +59,65 @@ pub const SUBTITLE_FONT: Font = Font::Helvetica;
 pub const GENERAL_FONT: Font = Font::Courier;
 
 impl DeimosStateHandle {
-    /// Hide the current view widget and show the given group
-    pub async fn set_view(&self, group: Group) {
-        fltk::app::lock().ok();
-
+    /// Hide the current view widget and show the group of the given view
+    pub async fn set_view(&self, view: DeimosView) {
         let mut active = self.active.lock().await;
-        active.hide();
-        *active = group;
-        active.show();
+        let group = match view {
+            DeimosView::Overview => self.overview.clone(),
+            DeimosView::Settings => self.settings.clone(),
+            DeimosView::Authorization => self.authorization.clone(),
+            DeimosView::Terminal => self.terminal.clone(),
+            DeimosView::Logs => self.logs.clone(),
+        };
+
+        ui::with_lock(|| {
+            active.hide();
+            *active = group;
+            active.show();
+        });
+
+        self.view.send_replace(view);
+    }
+}
 
-        fltk::app::unlock();
-        fltk::app::awake();
+/// Load the context on the runtime, showing dialogs to choose a cache directory on this thread.
+/// The future returned by [run] is driven by the main thread, which owns the FLTK event loop and
+/// must be the only thread to show dialogs
+async fn load_context() -> Option<Context> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<(Vec<String>, tokio::sync::oneshot::Sender<CacheDirChoice>)>();
+    let mut load = tokio::task::spawn(Context::load(move |failures| {
+        let failures = failures.iter().map(ToString::to_string).collect();
+        let (reply, choice) = tokio::sync::oneshot::channel();
+        let _ = tx.send((failures, reply));
+        async move { choice.await.unwrap_or(CacheDirChoice::Quit) }
+    }));
+
+    loop {
+        tokio::select! {
+            result = &mut load => break result.unwrap_or_else(|e| {
+                tracing::error!("Failed to join context loading task: {}", e);
+                None
+            }),
+            Some((failures, reply)) = rx.recv() => {
+                let _ = reply.send(error::cache_dir_dialog(&failures));
+            },
+        }
     }
 }
 
 /// Create a new FLTK event loop, load state from the save directory, and run the UI to completion
 pub async fn run() -> ExitCode {
-    let ctx = Context::load().await;
     let fltk_ev = App::default()
         .with_scheme(fltk::app::Scheme::Gtk);
     
     style::orbit_scheme();
 
+    //A directory is chosen before the window is created, as the user may choose to quit.
+    //Cached pods are loaded with the rest of the state so that they are shown in the first frame
+    let Some(ctx) = load_context().await else {
+        return ExitCode::FAILURE
+    };
+
     let mut window = Window::default()
         .with_size(400, 600);
     window.set_color(orbit::NIGHT[2]);
@@ -74,12 +133,17 @@ pub async fn run() -> ExitCode {
     window.resizable(&settings);
     let authorization = auth::authorization(state.clone());
     window.resizable(&authorization);
+    let terminal = terminal::terminal(state.clone());
+    window.resizable(&terminal);
+    let logs = logs::logs(state.clone());
+    window.resizable(&logs);
 
     window.end();
     window.show();
     
     overview.show();
 
+    let selected = ctx.servers.read()[0].id.clone();
     let _ = state.0.set(
         DeimosState {
             ctx,
@@ -87,11 +151,21 @@ pub async fn run() -> ExitCode {
             settings,
             overview,
             authorization,
+            terminal,
+            logs,
+            view: tokio::sync::watch::channel(DeimosView::Overview).0,
+            selected: tokio::sync::watch::channel(selected).0,
         }
     );
 
     window.redraw();
 
+    //Draw the window with the cached pods before connecting, as creating the API client and
+    //starting the event loops would otherwise delay the first frame on slow links
+    if let Err(e) = fltk::app::wait_for(0.) {
+        tracing::warn!("Failed to draw the window before connecting: {}", e);
+    }
+
     state.ctx.init().await;
     
     let ctx_loop = {
@@ -101,10 +175,29 @@ pub async fn run() -> ExitCode {
         })
     };
 
+    let summary_loop = {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            state.ctx.summary_loop().await;
+        })
+    };
+
+    let queue_loop = {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            state.ctx.queue_loop().await;
+        })
+    };
+
     match fltk_ev.run() {
         Ok(()) => {
             ctx_loop.abort();
-            state.ctx.save();
+            summary_loop.abort();
+            queue_loop.abort();
+            state.ctx.close_terminal();
+            //The FLTK loop has returned so no UI callback can modify the context while it is
+            //saved, and the runtime is kept alive until every file is written
+            state.ctx.save().await;
             ExitCode::SUCCESS
         },
         Err(e) => {
@@ -0,0 +1,267 @@
+use fltk::{button::Button, enums::{Align, FrameType}, frame::Frame, group::Flex, prelude::{GroupExt, WidgetBase, WidgetExt}};
+
+use crate::{app::{orbit, server::ServersWatch, style, time::{self, TimeRefresh}, ui::UiLock, DeimosStateHandle, DeimosView}, context::{client::ContextConnectionState, queue::QueuedAction, server::ContextServer}};
+
+
+/// Banner shown across the top of the overview while a server cannot be reached, showing the
+/// reason for the most recent failure with a button to retry every disconnected server
+/// immediately.
+/// The banner stays hidden after being dismissed until every connection is restored
+pub fn connection_banner(state: DeimosStateHandle, parent: Flex) -> Flex {
+    let mut row = Flex::default().row();
+    row.set_frame(FrameType::FlatBox);
+    row.set_color(orbit::MARS[0]);
+    row.set_margins(8, 4, 4, 4);
+    row.set_spacing(8);
+
+    let mut reason = Frame::default();
+    reason.set_label_font(crate::app::GENERAL_FONT);
+    reason.set_label_size(12);
+    reason.set_label_color(orbit::SOL[0]);
+    reason.set_align(Align::Inside | Align::Left | Align::Clip);
+
+    let mut retry_button = style::button::button::<Button>(orbit::MARS[1], orbit::MARS[2]);
+    retry_button.set_label("Retry");
+    retry_button.set_label_font(crate::app::SUBTITLE_FONT);
+    retry_button.set_label_size(12);
+    retry_button.set_label_color(orbit::SOL[0]);
+    row.fixed(&retry_button, 64);
+
+    let mut dismiss_button = style::button::button::<Button>(orbit::MARS[0], orbit::MARS[1]);
+    dismiss_button.set_label("x");
+    dismiss_button.set_label_font(crate::app::SUBTITLE_FONT);
+    dismiss_button.set_label_size(12);
+    dismiss_button.set_label_color(orbit::SOL[0]);
+    dismiss_button.set_tooltip("Dismiss until the connection is restored");
+    row.fixed(&dismiss_button, 24);
+
+    row.end();
+    row.hide();
+
+    let dismissed = tokio::sync::watch::Sender::new(false);
+
+    {
+        let state = state.clone();
+        retry_button.set_callback(move |_| {
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                let servers = state.ctx.servers.read().clone();
+                for server in servers.iter().filter(|server| *server.clients.conn.read() != ContextConnectionState::Connected) {
+                    server.clients.retry().await;
+                }
+            });
+        });
+    }
+
+    {
+        let dismissed = dismissed.clone();
+        dismiss_button.set_callback(move |_| {
+            dismissed.send_replace(true);
+        });
+    }
+
+    {
+        let mut row = row.clone();
+        tokio::task::spawn(
+            async move {
+                let mut conn_sub = ServersWatch::new(&state, |server| &server.clients.conn);
+                let mut failure_sub = ServersWatch::new(&state, |server| &server.clients.failure);
+                let mut dismissed_sub = dismissed.subscribe();
+                let mut refresh = TimeRefresh::new(&state, DeimosView::Overview);
+                loop {
+                    {
+                        let servers = conn_sub.servers();
+                        failure_sub.servers();
+                        if servers.iter().all(|server| *server.clients.conn.read() == ContextConnectionState::Connected) {
+                            dismissed.send_if_modified(|dismissed| std::mem::replace(dismissed, false));
+                        }
+
+                        //Servers are only named once there is more than one to tell apart
+                        let from = |server: &ContextServer| match servers.len() {
+                            1 => String::new(),
+                            _ => format!(" from {}", server.label()),
+                        };
+
+                        let failing = |states: &[ContextConnectionState]| servers.iter().find_map(|server| {
+                            let conn = *server.clients.conn.read();
+                            let failure = server.clients.failure.read().clone()?;
+                            states.contains(&conn).then_some((server, failure))
+                        });
+
+                        let disconnected = failing(&[ContextConnectionState::Error, ContextConnectionState::NoToken]);
+                        let reconnecting = failing(&[ContextConnectionState::Unknown]);
+                        let dismissed = *dismissed_sub.borrow_and_update();
+                        let _ui = UiLock::acquire();
+
+                        match (disconnected, reconnecting) {
+                            (Some((server, failure)), _) if !dismissed => {
+                                reason.set_label(&format!("Disconnected{}: {}", from(server), failure.kind));
+                                reason.set_tooltip(&failure.message);
+                                row.show();
+                            },
+                            (None, Some((server, failure))) if !dismissed && row.visible() => {
+                                reason.set_label(&format!("Reconnecting{} after: {}", from(server), failure.kind));
+                            },
+                            _ => row.hide(),
+                        }
+
+                        reason.set_damage(true);
+                        let mut parent = parent.clone();
+                        fltk::app::awake_callback(move || parent.layout());
+                    }
+
+                    tokio::select! {
+                        result = conn_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = failure_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = dismissed_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        _ = refresh.tick() => {},
+                    }
+                }
+            }
+        );
+    }
+
+    row
+}
+
+/// Banner shown below the connection banner while pod state changes are queued until the
+/// connection is restored, with a menu to cancel queued changes.
+/// Queued changes that could not be applied are reported until dismissed
+pub fn queue_banner(state: DeimosStateHandle, parent: Flex) -> Flex {
+    let mut row = Flex::default().row();
+    row.set_frame(FrameType::FlatBox);
+    row.set_color(orbit::NIGHT[1]);
+    row.set_margins(8, 4, 4, 4);
+    row.set_spacing(8);
+
+    let mut summary = Frame::default();
+    summary.set_label_font(crate::app::GENERAL_FONT);
+    summary.set_label_size(12);
+    summary.set_label_color(orbit::SOL[0]);
+    summary.set_align(Align::Inside | Align::Left | Align::Clip);
+
+    let mut cancel_button = style::button::button::<Button>(orbit::NIGHT[0], orbit::NIGHT[2]);
+    cancel_button.set_label("Cancel");
+    cancel_button.set_label_font(crate::app::SUBTITLE_FONT);
+    cancel_button.set_label_size(12);
+    cancel_button.set_label_color(orbit::SOL[0]);
+    cancel_button.set_tooltip("Cancel a queued change");
+    row.fixed(&cancel_button, 64);
+
+    let mut dismiss_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    dismiss_button.set_label("x");
+    dismiss_button.set_label_font(crate::app::SUBTITLE_FONT);
+    dismiss_button.set_label_size(12);
+    dismiss_button.set_label_color(orbit::SOL[0]);
+    dismiss_button.set_tooltip("Dismiss queued change results");
+    row.fixed(&dismiss_button, 24);
+
+    row.end();
+    row.hide();
+
+    //Time of the most recent result that was dismissed
+    let dismissed = tokio::sync::watch::Sender::new(None);
+
+    {
+        let state = state.clone();
+        cancel_button.set_callback(move |_| {
+            let queued = state.ctx.queue.read().clone();
+            let labels = queued.iter().map(QueuedAction::to_string).collect::<Vec<_>>();
+            let Some(selected) = fltk::menu::MenuItem::new(&labels.iter().map(String::as_str).collect::<Vec<_>>())
+                .popup(fltk::app::event_x(), fltk::app::event_y())
+                .and_then(|item| item.label()) else {
+                return
+            };
+
+            if let Some(action) = labels.iter().position(|label| *label == selected).and_then(|idx| queued.get(idx)) {
+                state.ctx.cancel_queued(&action.key());
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        let dismissed = dismissed.clone();
+        dismiss_button.set_callback(move |_| {
+            let latest = state.ctx.actions.read().back().map(|record| record.at);
+            dismissed.send_replace(latest);
+        });
+    }
+
+    {
+        let mut row = row.clone();
+        tokio::task::spawn(
+            async move {
+                let mut queue_sub = state.ctx.queue.subscribe();
+                let mut actions_sub = state.ctx.actions.subscribe();
+                let mut dismissed_sub = dismissed.subscribe();
+                loop {
+                    {
+                        let queued = queue_sub.borrow_and_update().len();
+                        let actions = actions_sub.borrow_and_update();
+                        let dismissed = *dismissed_sub.borrow_and_update();
+                        let problems = actions
+                            .iter()
+                            .filter(|record| record.outcome.is_problem() && dismissed.is_none_or(|at| record.at > at))
+                            .collect::<Vec<_>>();
+
+                        let now = chrono::Utc::now();
+                        let history = actions
+                            .iter()
+                            .rev()
+                            .map(|record| format!("{} {}: {}", time::relative(record.at, now), record.action, record.outcome))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+
+                        let _ui = UiLock::acquire();
+                        match (queued, problems.last()) {
+                            (0, None) => row.hide(),
+                            (0, Some(problem)) => {
+                                summary.set_label(&format!("{} not applied: {}", problem.action, problem.outcome));
+                                row.show();
+                            },
+                            (1, _) => {
+                                summary.set_label("1 change queued until reconnected");
+                                row.show();
+                            },
+                            (count, _) => {
+                                summary.set_label(&format!("{} changes queued until reconnected", count));
+                                row.show();
+                            },
+                        }
+
+                        match queued {
+                            0 => cancel_button.deactivate(),
+                            _ => cancel_button.activate(),
+                        }
+
+                        summary.set_tooltip(&history);
+                        summary.set_damage(true);
+                        let mut parent = parent.clone();
+                        fltk::app::awake_callback(move || parent.layout());
+                    }
+
+                    tokio::select! {
+                        result = queue_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = actions_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = dismissed_sub.changed() => if result.is_err() {
+                            break
+                        },
+                    }
+                }
+            }
+        );
+    }
+
+    row
+}
@@ -1,11 +1,16 @@
-use fltk::{button::Button, enums::Align, frame::Frame, group::Flex, image::SvgImage, prelude::{GroupExt, WidgetBase, WidgetExt}};
+use std::{sync::Arc, time::Duration};
 
-use crate::{app::{orbit, style, DeimosStateHandle}, context::client::ContextConnectionState};
+use chrono::Utc;
+use fltk::{button::Button, enums::{Align, Color, FrameType}, frame::Frame, group::Flex, image::SvgImage, prelude::{GroupExt, WidgetBase, WidgetExt}};
 
+use crate::{app::{orbit, server::ServersWatch, style, time, ui::{self, UiLock}, DeimosStateHandle, DeimosView}, context::{client::ContextConnectionState, server::ContextServer, summary::PodCategory, sync::ServerInfo}};
+
+/// Height of the overview header, including the pod summary below the connection status
+pub const HEADER_HEIGHT: i32 = 76;
 
 pub fn header(state: DeimosStateHandle) -> impl GroupExt {
     let mut row = Flex::default()
-        .with_size(0, 64)
+        .with_size(0, HEADER_HEIGHT)
         .row()
         .with_align(Align::Center);
     row.set_margins(8, 0, 0, 8);
@@ -29,43 +34,105 @@ pub fn header(state: DeimosStateHandle) -> impl GroupExt {
         connection_status.set_label_font(crate::app::GENERAL_FONT);
         connection_status.set_label_size(10);
         title_col.fixed(&connection_status, 16);
+
+        let summary = host_summary(state.clone());
+        title_col.fixed(&summary, 12);
+
+        let mut restart_notice = Frame::default();
+        restart_notice.set_label_font(crate::app::GENERAL_FONT);
+        restart_notice.set_label_size(9);
+        restart_notice.set_label_color(orbit::VENUS[1]);
+        title_col.fixed(&restart_notice, 12);
+
+        {
+            let state = state.clone();
+            tokio::task::spawn(
+                async move {
+                    const NOTICE_DURATION: Duration = Duration::from_secs(30);
+
+                    let mut sub = ServersWatch::new(&state, |server| &server.info);
+                    loop {
+                        let servers = sub.servers();
+                        let notice = servers
+                            .iter()
+                            .find_map(|server| server.info.read().filter(ServerInfo::restarted_unexpectedly).map(|info| (server, info)))
+                            .map(|(server, info)| (
+                                format!(
+                                    "{} restarted after {} {}",
+                                    match servers.len() {
+                                        1 => String::from("Server"),
+                                        _ => server.label(),
+                                    },
+                                    match info.previous_termination {
+                                        deimosproto::DaemonTermination::HostReboot => "a host reboot",
+                                        _ => "a crash",
+                                    },
+                                    time::relative(info.started, Utc::now()),
+                                ),
+                                time::full(info.started),
+                            ));
+
+                        if let Some((notice, full)) = notice {
+                            ui::with_lock(|| {
+                                restart_notice.set_label(&notice);
+                                restart_notice.set_tooltip(&format!("Started {}", full));
+                                restart_notice.set_damage(true);
+                            });
+
+                            let hide = tokio::time::sleep(NOTICE_DURATION);
+                            tokio::select! {
+                                result = sub.changed() => if result.is_err() {
+                                    break
+                                } else {
+                                    continue
+                                },
+                                _ = hide => {},
+                            }
+
+                            ui::with_lock(|| {
+                                restart_notice.set_label("");
+                                restart_notice.set_tooltip("");
+                                restart_notice.set_damage(true);
+                            });
+                        }
+
+                        if sub.changed().await.is_err() {
+                            break
+                        }
+                    }
+                }
+            );
+        }
         
         let state = state.clone();
         tokio::task::spawn(
             async move {
-                let mut sub = state.ctx.clients.conn.subscribe();
+                let mut sub = ServersWatch::new(&state, |server| &server.clients.conn);
+                let mut unreliable_sub = ServersWatch::new(&state, |server| &server.clients.streaming_unreliable);
+                let mut settings_sub = ServersWatch::new(&state, |server| &server.clients.settings);
                 loop {
                     {
-                        let state = sub.borrow_and_update();
-
-                        fltk::app::lock().ok();
-                        match *state {
-                            ContextConnectionState::Unknown => {
-                                connection_status.set_label("Connecting");
-                                connection_status.set_label_color(orbit::MERCURY[2]);
-                            },
-                            ContextConnectionState::Connected => {
-                                connection_status.set_label("Connected");
-                                connection_status.set_label_color(orbit::EARTH[0]);
-                            },
-                            ContextConnectionState::Error => {
-                                connection_status.set_label("Disconnected");
-                                connection_status.set_label_color(orbit::MARS[1]);
-                            },
-                            ContextConnectionState::NoToken => {
-                                connection_status.set_label("No Authorization Token");
-                                connection_status.set_label_color(orbit::VENUS[1]);
-                            }
-                        }
+                        let servers = sub.servers();
+                        unreliable_sub.servers();
+                        settings_sub.servers();
+                        let (label, color) = servers_connection_label(&servers);
+                        let _ui = UiLock::acquire();
 
+                        connection_status.set_label(&label);
+                        connection_status.set_label_color(color);
                         connection_status.set_damage(true);
-
-                        fltk::app::unlock();
-                        fltk::app::awake();
                     }
 
-                    if sub.changed().await.is_err() {
-                        break
+                    tokio::select! {
+                        result = sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = unreliable_sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = settings_sub.changed() => if result.is_err() {
+                            break
+                        },
                     }
                 }
             }
@@ -84,7 +151,7 @@ pub fn header(state: DeimosStateHandle) -> impl GroupExt {
         authentication_button.set_callback(move |_| {
             let state = state.clone();
             tokio::task::spawn(async move {
-                state.set_view(state.authorization.clone()).await;
+                state.set_view(DeimosView::Authorization).await;
             });
         });
     }
@@ -94,22 +161,18 @@ pub fn header(state: DeimosStateHandle) -> impl GroupExt {
         let mut authentication_button = authentication_button.clone();
         tokio::task::spawn(
             async move {
-                let mut sub = state.ctx.clients.token.subscribe();
+                let mut sub = ServersWatch::new(&state, |server| &server.clients.token);
                 loop {
                     {
-                        let token = sub.borrow_and_update();
-
-                        fltk::app::lock().ok();
+                        let authorized = sub.servers().iter().all(|server| server.clients.token.read().token().is_some());
+                        let _ui = UiLock::acquire();
 
                         authentication_button.set_image(
                             Some(
-                                if token.token().is_some() { authentication_grey.clone() } else { authentication_red.clone() }
+                                if authorized { authentication_grey.clone() } else { authentication_red.clone() }
                             )
                         );
                         authentication_button.set_damage(true);
-
-                        fltk::app::unlock();
-                        fltk::app::awake();
                     }
 
                     if sub.changed().await.is_err() {
@@ -128,7 +191,7 @@ pub fn header(state: DeimosStateHandle) -> impl GroupExt {
         let state = state.clone();
         tokio::spawn(
             async move {
-                state.set_view(state.settings.clone()).await;
+                state.set_view(DeimosView::Settings).await;
             }
         );
     });
@@ -144,3 +207,132 @@ pub fn header(state: DeimosStateHandle) -> impl GroupExt {
     row.end();
     row
 }
+
+/// Get the label and color showing the connection state of a single server
+pub fn connection_label(server: &ContextServer) -> (&'static str, Color) {
+    match *server.clients.conn.read() {
+        ContextConnectionState::Unknown => ("Connecting", orbit::MERCURY[2]),
+        ContextConnectionState::Connected => match server.clients.polling() {
+            true => ("Connected (polling)", orbit::EARTH[0]),
+            false => ("Connected", orbit::EARTH[0]),
+        },
+        ContextConnectionState::Error => ("Disconnected", orbit::MARS[1]),
+        ContextConnectionState::NoToken => ("No Authorization Token", orbit::VENUS[1]),
+    }
+}
+
+/// Get the label and color summarizing the connection state of all servers, which is the state
+/// of the only server if there is just one
+fn servers_connection_label(servers: &[Arc<ContextServer>]) -> (String, Color) {
+    if let [server] = servers {
+        let (label, color) = connection_label(server);
+        return (label.to_owned(), color)
+    }
+
+    let count = |state: ContextConnectionState| servers.iter().filter(|server| *server.clients.conn.read() == state).count();
+    let connected = count(ContextConnectionState::Connected);
+    let connecting = count(ContextConnectionState::Unknown);
+    match connected {
+        _ if connected == servers.len() => (format!("Connected to all {} servers", connected), orbit::EARTH[0]),
+        _ if connected + connecting == servers.len() => (String::from("Connecting"), orbit::MERCURY[2]),
+        0 => (format!("Connected to none of {} servers", servers.len()), orbit::MARS[1]),
+        _ => (format!("Connected to {} of {} servers", connected, servers.len()), orbit::VENUS[1]),
+    }
+}
+
+/// Row of buttons counting the pods in each [PodCategory], clicking a count shows only the pods
+/// in that category
+fn host_summary(state: DeimosStateHandle) -> Flex {
+    const FONT_SIZE: i32 = 9;
+
+    let mut row = Flex::default().row();
+    row.set_spacing(4);
+
+    let buttons = PodCategory::ALL.map(|category| {
+        let mut button = Button::default();
+        button.set_frame(FrameType::NoBox);
+        button.set_down_frame(FrameType::NoBox);
+        button.clear_visible_focus();
+        button.set_label_size(FONT_SIZE);
+        button.set_label_color(summary_color(category));
+        button.hide();
+
+        let state = state.clone();
+        button.set_callback(move |_| {
+            let filter = *state.ctx.filter.read();
+            state.ctx.filter.set((filter != Some(category)).then_some(category));
+        });
+
+        (category, button)
+    });
+
+    //Remaining space after the counts
+    Frame::default();
+    row.end();
+
+    {
+        let mut row = row.clone();
+        let mut buttons = buttons;
+        tokio::task::spawn(
+            async move {
+                let mut sub = state.ctx.summary.subscribe();
+                let mut filter_sub = state.ctx.filter.subscribe();
+                loop {
+                    {
+                        let summary = *sub.borrow_and_update();
+                        let filter = *filter_sub.borrow_and_update();
+                        let _ui = UiLock::acquire();
+
+                        for (category, button) in buttons.iter_mut() {
+                            let count = summary.get(*category);
+                            let selected = filter == Some(*category);
+                            if count == 0 && !selected {
+                                button.hide();
+                                continue
+                            }
+
+                            let font = if selected { crate::app::HEADER_FONT } else { crate::app::GENERAL_FONT };
+                            let label = format!("{} {}", count, category);
+                            fltk::draw::set_font(font, FONT_SIZE);
+                            let (width, _) = fltk::draw::measure(&label, false);
+
+                            button.set_label_font(font);
+                            button.set_label(&label);
+                            button.set_tooltip(&match selected {
+                                true => String::from("Show all pods"),
+                                false => format!("Show only {} pods", category),
+                            });
+                            row.fixed(&*button, width + 2);
+                            button.show();
+                        }
+
+                        let row = row.clone();
+                        fltk::app::awake_callback(move || row.layout());
+                    }
+
+                    tokio::select! {
+                        result = sub.changed() => if result.is_err() {
+                            break
+                        },
+                        result = filter_sub.changed() => if result.is_err() {
+                            break
+                        },
+                    }
+                }
+            }
+        );
+    }
+
+    row
+}
+
+/// Get the color used to display the count of pods in the given category
+fn summary_color(category: PodCategory) -> Color {
+    match category {
+        PodCategory::Enabled => orbit::EARTH[1],
+        PodCategory::Disabled => orbit::NIGHT[0].lighter(),
+        PodCategory::Paused => orbit::VENUS[3],
+        PodCategory::Updating => orbit::MERCURY[1],
+        PodCategory::Failed => orbit::MARS[2],
+    }
+}
@@ -1,11 +1,14 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use fltk::{button::Button, enums::{Align, Event, FrameType}, frame::Frame, group::{Flex, Group, Pack, PackType, Scroll, ScrollType}, image::SvgImage, prelude::{GroupExt, WidgetBase, WidgetExt}};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use deimosproto::capability::Capability;
+use fltk::{button::Button, enums::{Align, Event, FrameType}, frame::Frame, group::{Flex, Group, Pack, PackType, Scroll, ScrollType}, image::{GifImage, JpegImage, PngImage, SharedImage, SvgImage}, prelude::{DisplayExt, GroupExt, ImageExt, WidgetBase, WidgetExt, WindowExt}, text::{TextBuffer, TextDisplay}, window::Window};
 
-use crate::context::pod::{CachedPod, CachedPodState};
+use crate::context::{confirm::CachedPodConfirmLevel, exit::{CachedPodExit, CachedPodExitSeverity}, pod::{self, CachedPod, CachedPodFailure, CachedPodImage, CachedPodImageFormat, CachedPodState, CachedPodTransition, PodKey}, server::ServerId, summary::PodCategory, sync::SyncProgress, terminal::TerminalSize, NotifyMutation};
 
-use super::{orbit, style, DeimosStateHandle};
+use super::{error, orbit, server::ServersWatch, style, time::{self, TimeRefresh}, ui::{self, UiLock}, DeimosStateHandle, DeimosView};
 
+pub mod banner;
 pub mod header;
 
 
@@ -18,7 +21,13 @@ pub fn overview(state: DeimosStateHandle) -> Group {
 
         {
             let header = header::header(state.clone());
-            flex.fixed(&header, 64);
+            flex.fixed(&header, header::HEADER_HEIGHT);
+
+            let banner = banner::connection_banner(state.clone(), flex.clone());
+            flex.fixed(&banner, 32);
+
+            let queue_banner = banner::queue_banner(state.clone(), flex.clone());
+            flex.fixed(&queue_banner, 32);
 
             {
                 let mut servers_container = Flex::default().row();
@@ -33,11 +42,84 @@ pub fn overview(state: DeimosStateHandle) -> Group {
                 label.set_align(Align::Inside | Align::Left);
                 
                 let reload_svg = SvgImage::from_data(include_str!("../../../assets/reload.svg")).unwrap();
-                let reload_rgb = style::svg::svg_color(reload_svg, servers_container.height(), orbit::MERCURY[2]);
+                let reload_rgb = style::svg::svg_color(reload_svg.clone(), servers_container.height(), orbit::MERCURY[2]);
+                let reload_busy_rgb = style::svg::svg_color(reload_svg, servers_container.height(), orbit::EARTH[1]);
                 let mut reload_button = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[0]);
                 servers_container.fixed(&reload_button, servers_container.height());
-                reload_button.set_image(Some(reload_rgb));
+                reload_button.set_image(Some(reload_rgb.clone()));
                 reload_button.set_align(Align::Center);
+
+                {
+                    let state = state.clone();
+                    let mut reload_button = reload_button.clone();
+                    tokio::task::spawn(
+                        async move {
+                            let mut sub = ServersWatch::new(&state, |server| &server.sync);
+                            let mut in_flight_sub = ServersWatch::new(&state, |server| &server.requests.in_flight);
+                            let mut synchronized_sub = ServersWatch::new(&state, |server| &server.synchronized);
+                            let mut refresh = TimeRefresh::new(&state, DeimosView::Overview);
+                            loop {
+                                {
+                                    let servers = sub.servers();
+                                    in_flight_sub.servers();
+                                    synchronized_sub.servers();
+
+                                    let progress = servers
+                                        .iter()
+                                        .fold(SyncProgress::Idle, |progress, server| match (progress, *server.sync.read()) {
+                                            (SyncProgress::Idle, running) | (running, SyncProgress::Idle) => running,
+                                            (SyncProgress::Running { done, total }, SyncProgress::Running { done: more, total: of }) => {
+                                                SyncProgress::Running { done: done + more, total: total + of }
+                                            },
+                                        });
+                                    let in_flight = servers.iter().map(|server| *server.requests.in_flight.read()).sum::<usize>();
+                                    //The oldest synchronization is shown, and none until every server has synchronized
+                                    let synchronized = servers
+                                        .iter()
+                                        .map(|server| *server.synchronized.read())
+                                        .collect::<Option<Vec<_>>>()
+                                        .and_then(|times| times.into_iter().min());
+                                    let details = [
+                                        (in_flight > 0).then(|| format!("{} requests in flight", in_flight)),
+                                        synchronized.map(|at| format!("last synchronized {}", time::relative(at, Utc::now()))),
+                                    ]
+                                    .into_iter()
+                                    .flatten()
+                                    .collect::<Vec<_>>();
+                                    let _ui = UiLock::acquire();
+
+                                    match progress {
+                                        SyncProgress::Idle if details.is_empty() => {
+                                            reload_button.set_image(Some(reload_rgb.clone()));
+                                            reload_button.set_tooltip("Synchronize pods");
+                                        },
+                                        SyncProgress::Idle => {
+                                            reload_button.set_image(Some(reload_rgb.clone()));
+                                            reload_button.set_tooltip(&format!("Synchronize pods ({})", details.join(", ")));
+                                        },
+                                        SyncProgress::Running { done, total } => {
+                                            reload_button.set_image(Some(reload_busy_rgb.clone()));
+                                            reload_button.set_tooltip(&format!("Synchronized {} of {} pods", done, total));
+                                        }
+                                    }
+
+                                    reload_button.set_damage(true);
+                                }
+
+                                let changed = tokio::select! {
+                                    result = sub.changed() => result,
+                                    result = in_flight_sub.changed() => result,
+                                    result = synchronized_sub.changed() => result,
+                                    _ = refresh.tick() => Ok(()),
+                                };
+
+                                if changed.is_err() {
+                                    break
+                                }
+                            }
+                        }
+                    );
+                }
                 
                 {
                     let state = state.clone();
@@ -78,40 +160,123 @@ pub fn overview(state: DeimosStateHandle) -> Group {
 
                     pods_pack.end();
 
+                    //Archived pods are listed in a collapsed section after all other pods
+                    let mut archived_toggle = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[1]);
+                    archived_toggle.set_size(0, 24);
+                    archived_toggle.set_label_font(crate::app::SUBTITLE_FONT);
+                    archived_toggle.set_label_size(14);
+                    archived_toggle.set_label_color(orbit::MERCURY[1]);
+                    archived_toggle.set_align(Align::Inside | Align::Left);
+                    scroll.remove(&archived_toggle);
+
+                    let expanded = tokio::sync::watch::Sender::new(false);
+                    {
+                        let expanded = expanded.clone();
+                        archived_toggle.set_callback(move |_| {
+                            expanded.send_modify(|expanded| *expanded = !*expanded);
+                        });
+                    }
+
                     tokio::spawn(
                         async move {
-                            let mut buttons = BTreeMap::<String, Flex>::new();
+                            let mut buttons = BTreeMap::<PodKey, PodButton>::new();
+                            let mut headers = BTreeMap::<ServerId, Frame>::new();
                             let mut sub = state.ctx.pods.subscribe();
+                            let mut conn_sub = ServersWatch::new(&state, |server| &server.clients.conn);
+                            let mut expanded_sub = expanded.subscribe();
+                            let mut filter_sub = state.ctx.filter.subscribe();
+                            //The summary is recomputed whenever a pod changes category, so the
+                            //filtered list is refreshed with it
+                            let mut summary_sub = state.ctx.summary.subscribe();
                             loop {
                                 {
-                                    fltk::app::lock().ok();
+                                    let _ui = UiLock::acquire();
 
                                     for button in buttons.values() {
-                                        pods_pack.remove(button);
+                                        pods_pack.remove(&button.row);
                                     }
+                                    for header in headers.values() {
+                                        pods_pack.remove(header);
+                                    }
+                                    pods_pack.remove(&archived_toggle);
 
                                     let pods = sub.borrow_and_update();
+                                    let servers = conn_sub.servers();
+                                    headers.retain(|id, header| {
+                                        let kept = servers.iter().any(|server| server.id == *id);
+                                        if !kept {
+                                            fltk::app::delete_widget(header.clone());
+                                        }
+                                        kept
+                                    });
+
+                                    //A pod that was removed and added again under the same ID
+                                    //gets a new button bound to the new cached pod
+                                    pod::rebind_pods(&mut buttons, &pods, |button| &button.pod, |pod| pod_button(state.clone(), pod.clone()));
+
+                                    let (archived, active) = buttons
+                                        .values()
+                                        .partition::<Vec<_>, _>(|button| *button.pod.data.archived.read());
+
+                                    let filter = *filter_sub.borrow_and_update();
+                                    summary_sub.mark_unchanged();
+                                    let shown = active
+                                        .into_iter()
+                                        .filter(|button| filter.is_none_or(|filter| PodCategory::of(&button.pod.data) == Some(filter)))
+                                        .collect::<Vec<_>>();
+
+                                    //Pods are only grouped under their server once there is more than one server
+                                    for server in servers.iter() {
+                                        if servers.len() > 1 {
+                                            let title = headers.entry(server.id.clone()).or_insert_with(server_header);
+                                            let (conn, color) = header::connection_label(server);
+                                            title.set_label(&format!("{} - {}", server.label(), conn));
+                                            title.set_label_color(color);
+                                            pods_pack.add(title);
+                                        }
 
-                                    buttons.retain(|id,_| pods.contains_key(id));
-                                    for (id, pod) in pods.clone() {
-                                        buttons
-                                            .entry(id)
-                                            .or_insert_with(|| pod_button(state.clone(), pod.clone()));
+                                        for button in shown.iter().filter(|button| button.pod.server == server.id) {
+                                            pods_pack.add(&button.row);
+                                        }
                                     }
 
-                                    for button in buttons.values_mut() {
-                                        pods_pack.add(button);
+                                    //Archived pods are never counted in a category
+                                    if !archived.is_empty() && filter.is_none() {
+                                        let expanded = *expanded_sub.borrow_and_update();
+                                        archived_toggle.set_label(&format!(
+                                            "{} archived ({})",
+                                            if expanded { "Hide" } else { "Show" },
+                                            archived.len(),
+                                        ));
+                                        pods_pack.add(&archived_toggle);
+
+                                        if expanded {
+                                            for button in archived {
+                                                pods_pack.add(&button.row);
+                                            }
+                                        }
                                     }
-                                    
 
                                     pods_pack.set_damage(true);
-                                    fltk::app::unlock();
-                                    fltk::app::awake();
                                 }
 
-                                let Ok(_) = sub.changed().await else {
-                                    break
-                                };
+                                tokio::select! {
+                                    result = sub.changed() => if result.is_err() {
+                                        break
+                                    },
+                                    result = conn_sub.changed() => if result.is_err() {
+                                        break
+                                    },
+                                    result = expanded_sub.changed() => if result.is_err() {
+                                        break
+                                    },
+                                    result = filter_sub.changed() => if result.is_err() {
+                                        break
+                                    },
+                                    result = summary_sub.changed(), if filter_sub.borrow().is_some() => if result.is_err() {
+                                        break
+                                    },
+                                }
                             }
                         }
                     );
@@ -127,12 +292,106 @@ pub fn overview(state: DeimosStateHandle) -> Group {
     top
 }
 
+/// Create the row shown above the pods of a server, labelled with the server and its connection
+/// state when the pods of more than one server are listed
+fn server_header() -> Frame {
+    let mut header = Frame::default().with_size(0, 24);
+    header.set_label_font(crate::app::SUBTITLE_FONT);
+    header.set_label_size(16);
+    header.set_align(Align::Inside | Align::Left);
+    header
+}
+
+/// Row of widgets displaying a single pod, along with the tasks that update them
+pub struct PodButton {
+    pub row: Flex,
+    /// Cached pod that the widgets display
+    pod: Arc<CachedPod>,
+    /// Tasks subscribed to the pod's state, stopped when the button is dropped
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for PodButton {
+    /// Stop all tasks updating the button and delete its widgets. Buttons may be dropped on any
+    /// thread with or without the UI lock, so the widgets are deleted by the event loop
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        let row = self.row.clone();
+        fltk::app::awake_callback(move || fltk::app::delete_widget(row.clone()));
+    }
+}
+
 /// Create a button with a brief overview of the given pod
-pub fn pod_button(state: DeimosStateHandle, pod: Arc<CachedPod>) -> Flex {
+/// Decode the cached icon of a pod and scale it to fit a square of the given size, returning
+/// `None` if the image could not be decoded
+fn pod_icon(image: &CachedPodImage, size: i32) -> Option<SharedImage> {
+    let decoded = match image.format {
+        CachedPodImageFormat::Png => PngImage::from_data(&image.data).and_then(SharedImage::from_image),
+        CachedPodImageFormat::Jpeg => JpegImage::from_data(&image.data).and_then(SharedImage::from_image),
+        CachedPodImageFormat::Gif => GifImage::from_data(&image.data).and_then(SharedImage::from_image),
+        CachedPodImageFormat::Svg => std::str::from_utf8(&image.data)
+            .map_err(|_| fltk::prelude::FltkError::Unknown(String::from("SVG icon is not valid UTF-8")))
+            .and_then(SvgImage::from_data)
+            .and_then(SharedImage::from_image),
+    };
+
+    match decoded {
+        Ok(mut decoded) => {
+            decoded.scale(size, size, true, true);
+            Some(decoded)
+        },
+        Err(e) => {
+            tracing::warn!("Failed to decode pod icon: {}", e);
+            None
+        }
+    }
+}
+
+pub fn pod_button(state: DeimosStateHandle, pod: Arc<CachedPod>) -> PodButton {
     let mut row = Flex::default().with_size(0, 64).row();
     row.set_spacing(1);
+    let mut tasks = Vec::new();
+
+    {
+        let mut icon = Frame::default();
+        icon.set_frame(FrameType::RShadowBox);
+        icon.set_color(orbit::NIGHT[1]);
+        icon.hide();
+        row.fixed(&icon, row.height());
+
+        let mut row = row.clone();
+        let pod = pod.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = pod.data.icon.subscribe();
+            loop {
+                let image = sub.borrow_and_update().clone();
+                ui::with_lock(|| {
+                    match image.as_ref().and_then(|image| pod_icon(image, icon.height() - 8)) {
+                        Some(image) => {
+                            icon.set_image(Some(image));
+                            icon.show();
+                        },
+                        None => {
+                            icon.set_image(None::<SharedImage>);
+                            icon.hide();
+                        }
+                    }
+
+                    row.layout();
+                    row.set_damage(true);
+                });
+
+                if sub.changed().await.is_err() {
+                    break
+                }
+            }
+        }));
+    }
 
-    let up_state = {
+    let (up_state, schedule) = {
         let mut column = Flex::default().column();
         column.set_frame(FrameType::RShadowBox);
         column.set_color(orbit::NIGHT[1]);
@@ -149,121 +408,712 @@ pub fn pod_button(state: DeimosStateHandle, pod: Arc<CachedPod>) -> Flex {
         up_state.set_align(Align::Inside | Align::Left);
         up_state.set_label_size(12);
 
+        let mut schedule = Frame::default();
+        schedule.set_label_font(crate::app::SUBTITLE_FONT);
+        schedule.set_label_color(orbit::MERCURY[2]);
+        schedule.set_align(Align::Inside | Align::Left);
+        schedule.set_label_size(10);
+
         column.end();
+
+        column.set_tooltip("Click to show the pod's logs");
+        {
+            let state = state.clone();
+            let pod = pod.clone();
+            column.handle(move |_, ev| match ev {
+                Event::Push => true,
+                Event::Released => {
+                    let state = state.clone();
+                    let pod = pod.clone();
+                    tokio::task::spawn(async move {
+                        state.ctx.logs.set(Some(pod));
+                        state.set_view(DeimosView::Logs).await;
+                    });
+                    true
+                },
+                _ => false,
+            });
+        }
         
         let pod = pod.clone();
-        tokio::task::spawn(async move {
+        tasks.push(tokio::task::spawn(async move {
             let mut sub = pod.data.name.subscribe();
             loop {
-                fltk::app::lock().ok();
-                title.set_label(&sub.borrow_and_update());
-                title.set_damage(true);
-                fltk::app::unlock();
-                fltk::app::awake();
+                ui::with_lock(|| {
+                    title.set_label(&sub.borrow_and_update());
+                    title.set_damage(true);
+                });
 
                 if sub.changed().await.is_err() {
                     break
                 }
             }
-        });
+        }));
 
-        up_state
+        (up_state, schedule)
     };
 
     let dim = row.height() - 16;
     
     let start_svg = SvgImage::from_data(include_str!("../../../assets/start.svg")).unwrap();
-    let start_rgb = style::svg::svg_color(start_svg, dim, orbit::MERCURY[1]);
+    let start_rgb = style::svg::svg_color(start_svg.clone(), dim, orbit::MERCURY[1]);
+    let resume_rgb = style::svg::svg_color(start_svg, dim, orbit::VENUS[3]);
     
     let stop_svg = SvgImage::from_data(include_str!("../../../assets/stop.svg")).unwrap();
     let stop_rgb = style::svg::svg_color(stop_svg, dim, orbit::MARS[2]);
 
     let load_svg = SvgImage::from_data(include_str!("../../../assets/reload.svg")).unwrap();
-    let load_rgb = style::svg::svg_color(load_svg, dim, orbit::EARTH[1]);
+    let load_rgb = style::svg::svg_color(load_svg.clone(), dim, orbit::EARTH[1]);
 
     let pause_svg = SvgImage::from_data(include_str!("../../../assets/pause.svg")).unwrap();
     let pause_rgb = style::svg::svg_color(pause_svg, dim - 16, orbit::VENUS[3]);
 
+    let restart_rgb = style::svg::svg_color(load_svg, dim - 16, orbit::EARTH[1]);
+
+    let annotation_svg = SvgImage::from_data(include_str!("../../../assets/annotation.svg")).unwrap();
+    let annotation_rgb = [orbit::MERCURY[1], orbit::VENUS[3], orbit::MARS[2]]
+        .map(|color| style::svg::svg_color(annotation_svg.clone(), dim / 2, color));
+
+    //Annotations reported from inside the container are shown with a distinct icon, listing
+    //them in the tooltip until dismissed
+    let mut annotation_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    annotation_button.hide();
+    row.fixed(&annotation_button, row.height() / 2);
+
+    {
+        let state = state.clone();
+        let row = row.clone();
+        let mut annotation_button = annotation_button.clone();
+        let annotations = pod.data.annotations.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = annotations.subscribe();
+            let mut refresh = TimeRefresh::new(&state, DeimosView::Overview);
+            loop {
+                {
+                    let annotations = sub.borrow_and_update();
+                    let now = Utc::now();
+                    let _ui = UiLock::acquire();
+
+                    match annotations.last() {
+                        Some(last) => {
+                            let icon = match last.level {
+                                deimosproto::PodAnnotationLevel::Info => &annotation_rgb[0],
+                                deimosproto::PodAnnotationLevel::Warn => &annotation_rgb[1],
+                                deimosproto::PodAnnotationLevel::Error => &annotation_rgb[2],
+                            };
+
+                            let tooltip = annotations
+                                .iter()
+                                .rev()
+                                .map(|annotation| {
+                                    let fields = annotation
+                                        .fields
+                                        .iter()
+                                        .map(|(k, v)| format!(" {}={}", k, v))
+                                        .collect::<String>();
+                                    format!("{} {}{}", time::relative(annotation.at, now), annotation.message, fields)
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n");
+
+                            annotation_button.set_image(Some(icon.clone()));
+                            annotation_button.set_tooltip(&format!("Reported by container (click to dismiss):\n{}", tooltip));
+                            annotation_button.show();
+                        },
+                        None => annotation_button.hide(),
+                    }
+
+                    annotation_button.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                tokio::select! {
+                    result = sub.changed() => if result.is_err() {
+                        break
+                    },
+                    _ = refresh.tick() => {},
+                }
+            }
+        }));
+    }
+
+    {
+        let annotations = pod.data.annotations.clone();
+        annotation_button.set_callback(move |_| {
+            annotations.set(Vec::new());
+        });
+    }
+
+    //Failures of the container are shown while the pod is disabled, with the container's final
+    //output available in a separate window. Failed requests to update the pod are shown in its
+    //place with the status returned by the server
+    let mut failure_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    failure_button.set_label("!");
+    failure_button.set_label_font(crate::app::HEADER_FONT);
+    failure_button.set_label_size(18);
+    failure_button.set_label_color(orbit::MARS[1]);
+    failure_button.hide();
+    row.fixed(&failure_button, row.height() / 2);
+
+    {
+        let state = state.clone();
+        let row = row.clone();
+        let mut failure_button = failure_button.clone();
+        let up = pod.data.up.clone();
+        let last_error = pod.data.last_error.clone();
+        let update_error = pod.data.update_error.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = up.subscribe();
+            let mut failure_sub = last_error.subscribe();
+            let mut update_sub = update_error.subscribe();
+            let mut refresh = TimeRefresh::new(&state, DeimosView::Overview);
+            loop {
+                {
+                    let disabled = *sub.borrow_and_update() == CachedPodState::Disabled;
+                    let failure = failure_sub.borrow_and_update();
+                    let update = update_sub.borrow_and_update();
+                    let _ui = UiLock::acquire();
+
+                    match (update.as_ref(), failure.as_ref().filter(|_| disabled)) {
+                        (Some(error), _) => {
+                            failure_button.set_tooltip(&format!(
+                                "{}: {} {} (click to show details)",
+                                error.summary,
+                                error.message,
+                                time::relative(error.at, Utc::now()),
+                            ));
+                            failure_button.show();
+                        },
+                        (None, Some(failure)) => {
+                            failure_button.set_tooltip(&format!(
+                                "{} {} (click to show output)",
+                                failure.reason,
+                                time::relative(failure.at, Utc::now()),
+                            ));
+                            failure_button.show();
+                        },
+                        (None, None) => failure_button.hide(),
+                    }
+
+                    failure_button.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                let changed = tokio::select! {
+                    result = sub.changed() => result,
+                    result = failure_sub.changed() => result,
+                    result = update_sub.changed() => result,
+                    _ = refresh.tick() => Ok(()),
+                };
+
+                if changed.is_err() {
+                    break
+                }
+            }
+        }));
+    }
+
+    {
+        let pod = pod.clone();
+        failure_button.set_callback(move |_| {
+            if let Some(error) = pod.data.update_error.read().as_ref() {
+                error::error_window(error);
+            } else if let Some(failure) = pod.data.last_error.read().as_ref() {
+                failure_window(&pod.data.name.read(), failure);
+            }
+        });
+    }
+
+    //The first endpoint is shown and copied on click, others can be copied from a menu by
+    //right clicking
+    let mut endpoint_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    endpoint_button.set_label_font(crate::app::SUBTITLE_FONT);
+    endpoint_button.set_label_size(12);
+    endpoint_button.set_label_color(orbit::MERCURY[0]);
+    endpoint_button.hide();
+    row.fixed(&endpoint_button, row.height() * 5 / 2);
+
+    {
+        let row = row.clone();
+        let mut endpoint_button = endpoint_button.clone();
+        let endpoints = pod.data.endpoints.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = endpoints.subscribe();
+            loop {
+                {
+                    let endpoints = sub.borrow_and_update();
+                    let _ui = UiLock::acquire();
+
+                    match endpoints.first() {
+                        Some(first) => {
+                            let tooltip = endpoints
+                                .iter()
+                                .map(|endpoint| format!(
+                                    "{} {}{}",
+                                    endpoint.protocol,
+                                    endpoint,
+                                    if endpoint.external { "" } else { " (local network)" },
+                                ))
+                                .collect::<Vec<_>>()
+                                .join("\n");
+
+                            endpoint_button.set_label(&first.to_string());
+                            endpoint_button.set_tooltip(&format!("Click to copy, right click for all ports:\n{}", tooltip));
+                            endpoint_button.show();
+                        },
+                        None => endpoint_button.hide(),
+                    }
+
+                    endpoint_button.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                if sub.changed().await.is_err() {
+                    break
+                }
+            }
+        }));
+    }
+
+    {
+        let endpoints = pod.data.endpoints.clone();
+        endpoint_button.set_callback(move |_| {
+            let endpoints = endpoints.read().iter().map(ToString::to_string).collect::<Vec<_>>();
+            let copy = match fltk::app::event_mouse_button() {
+                fltk::app::MouseButton::Right if endpoints.len() > 1 => fltk::menu::MenuItem::new(&endpoints.iter().map(String::as_str).collect::<Vec<_>>())
+                    .popup(fltk::app::event_x(), fltk::app::event_y())
+                    .and_then(|item| item.label()),
+                _ => endpoints.first().cloned(),
+            };
+
+            if let Some(copy) = copy {
+                fltk::app::copy(&copy);
+            }
+        });
+    }
+
+    let mut terminal_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    terminal_button.set_label(">_");
+    terminal_button.set_label_font(crate::app::GENERAL_FONT);
+    terminal_button.set_label_size(14);
+    terminal_button.set_label_color(orbit::MERCURY[0]);
+    terminal_button.hide();
+    row.fixed(&terminal_button, row.height() / 2);
+
+    {
+        let row = row.clone();
+        let mut terminal_button = terminal_button.clone();
+        let up = pod.data.up.clone();
+        let attachable = pod.data.attachable.clone();
+        //Servers that have not been queried yet are assumed to allow terminals
+        let info = match state.ctx.server_of(&pod) {
+            Some(server) => server.info.clone(),
+            None => NotifyMutation::new(None),
+        };
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = up.subscribe();
+            let mut attachable_sub = attachable.subscribe();
+            let mut info_sub = info.subscribe();
+            loop {
+                {
+                    let enabled = sub.borrow_and_update().satisfies(CachedPodState::Enabled);
+                    let attachable = *attachable_sub.borrow_and_update();
+                    let supported = info_sub.borrow_and_update().is_none_or(|info| info.capabilities.contains(Capability::Attach));
+                    let _ui = UiLock::acquire();
+
+                    match enabled && attachable && supported {
+                        true => {
+                            terminal_button.set_label_color(orbit::MERCURY[0]);
+                            terminal_button.set_tooltip("Open a terminal in the pod");
+                            terminal_button.show();
+                        },
+                        false => terminal_button.hide(),
+                    }
+
+                    terminal_button.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                let changed = tokio::select! {
+                    result = sub.changed() => result,
+                    result = attachable_sub.changed() => result,
+                    result = info_sub.changed() => result,
+                };
+
+                if changed.is_err() {
+                    break
+                }
+            }
+        }));
+    }
+
+    {
+        let state = state.clone();
+        let pod = pod.clone();
+        terminal_button.set_callback(move |b| {
+            let state = state.clone();
+            let pod = pod.clone();
+            let mut b = b.clone();
+            tokio::task::spawn(async move {
+                match state.ctx.open_terminal(&pod, TerminalSize::DEFAULT).await {
+                    Ok(_) => state.set_view(DeimosView::Terminal).await,
+                    Err(e) => {
+                        tracing::warn!("Failed to open terminal in pod {}: {}", pod.data.id, e);
+                        ui::with_lock(|| {
+                            b.set_label_color(orbit::MARS[1]);
+                            b.set_tooltip(&format!("Failed to open a terminal: {}", e));
+                            b.redraw();
+                        });
+                    }
+                }
+            });
+        });
+    }
+
+    let mut alert_badge = Frame::default();
+    alert_badge.set_frame(FrameType::RShadowBox);
+    alert_badge.set_color(orbit::NIGHT[1]);
+    alert_badge.set_label_font(crate::app::SUBTITLE_FONT);
+    alert_badge.set_label_size(12);
+    alert_badge.set_label_color(orbit::MARS[1]);
+    alert_badge.hide();
+    row.fixed(&alert_badge, row.height());
+
+    {
+        let row = row.clone();
+        let alerts = pod.data.alerts.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = alerts.subscribe();
+            loop {
+                {
+                    let alerts = sub.borrow_and_update().clone();
+                    let _ui = UiLock::acquire();
+                    match alerts.len() {
+                        0 => alert_badge.hide(),
+                        count => {
+                            alert_badge.set_label(&format!("{} alert{}", count, if count == 1 { "" } else { "s" }));
+                            let now = Utc::now();
+                            let tooltip = alerts
+                                .iter()
+                                .map(|alert| format!("{} since {}", alert.rule, time::relative(alert.since, now)))
+                                .collect::<Vec<_>>()
+                                .join("\n");
+                            alert_badge.set_tooltip(&tooltip);
+                            alert_badge.show();
+                        },
+                    }
+
+                    alert_badge.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                if sub.changed().await.is_err() {
+                    break
+                }
+            }
+        }));
+    }
+
+    let mut skip_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    skip_button.set_label_font(crate::app::SUBTITLE_FONT);
+    skip_button.set_label_size(12);
+    skip_button.set_label_color(orbit::MERCURY[0]);
+    skip_button.hide();
+    row.fixed(&skip_button, row.height());
+
+    {
+        let row = row.clone();
+        let mut schedule = schedule.clone();
+        let mut skip_button = skip_button.clone();
+        let next_transition = pod.data.next_transition.clone();
+        let enabled_until = pod.data.enabled_until.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let mut sub = next_transition.subscribe();
+            let mut until_sub = enabled_until.subscribe();
+            loop {
+                {
+                    let next = *sub.borrow_and_update();
+                    let until = *until_sub.borrow_and_update();
+                    let _ui = UiLock::acquire();
+
+                    match (until, next) {
+                        //A timed enable overrides the schedule until it expires
+                        (Some(until), _) => {
+                            let timed = CachedPodTransition {
+                                action: CachedPodState::Disabled,
+                                at: until,
+                                skipped: false,
+                            };
+
+                            schedule.set_label(&schedule_hint(&timed, Utc::now()));
+                            skip_button.hide();
+                        },
+                        (None, Some(transition)) => {
+                            schedule.set_label(&schedule_hint(&transition, Utc::now()));
+                            skip_button.set_label(if transition.skipped { "Undo" } else { "Skip" });
+                            skip_button.set_tooltip(if transition.skipped { "Restore the next scheduled transition" } else { "Skip the next scheduled transition" });
+                            skip_button.show();
+                        },
+                        (None, None) => {
+                            schedule.set_label("");
+                            skip_button.hide();
+                        }
+                    }
+
+                    schedule.set_damage(true);
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || row.layout());
+                }
+
+                //Refresh periodically so that countdowns to upcoming transitions stay current
+                tokio::select! {
+                    result = sub.changed() => if result.is_err() {
+                        break
+                    },
+                    result = until_sub.changed() => if result.is_err() {
+                        break
+                    },
+                    _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+                }
+            }
+        }));
+    }
+
+    {
+        let state = state.clone();
+        let pod = pod.clone();
+        skip_button.set_callback(move |_| {
+            let Some(transition) = *pod.data.next_transition.read() else {
+                return
+            };
+
+            let state = state.clone();
+            let pod = pod.clone();
+            tokio::task::spawn(async move {
+                state.ctx.skip_scheduled(&pod, !transition.skipped).await;
+            });
+        });
+    }
+
     let mut pause_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
     pause_button.hide();
     row.fixed(&pause_button, row.height());
     pause_button.set_image(Some(pause_rgb));
 
+    let mut restart_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    restart_button.hide();
+    row.fixed(&restart_button, row.height());
+    restart_button.set_image(Some(restart_rgb));
+    restart_button.set_tooltip("Restart the pod");
+
     let mut button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
     row.fixed(&button, row.height());
 
 
     {
+        let state = state.clone();
         let row = row.clone();
         let mut up_state = up_state.clone();
         let mut button = button.clone();
         let mut pause_button = pause_button.clone();
+        let mut restart_button = restart_button.clone();
         let up = pod.data.up.clone();
-        let load_rgb = load_rgb.clone();
-        tokio::task::spawn(async move {
+        let requested = pod.data.requested.clone();
+        let image_missing = pod.data.image_missing.clone();
+        let last_error = pod.data.last_error.clone();
+        let stopped_at = pod.data.stopped_at.clone();
+        let restart_breaker = pod.data.restart_breaker.clone();
+        //A pod whose server was removed is about to be removed with it, so it is shown as stale
+        let synchronized = match state.ctx.server_of(&pod) {
+            Some(server) => server.synchronized.clone(),
+            None => NotifyMutation::new(None),
+        };
+        tasks.push(tokio::task::spawn(async move {
             let mut sub = up.subscribe();
+            let mut requested_sub = requested.subscribe();
+            let mut missing_sub = image_missing.subscribe();
+            let mut failure_sub = last_error.subscribe();
+            let mut stopped_sub = stopped_at.subscribe();
+            let mut breaker_sub = restart_breaker.subscribe();
+            let mut synchronized_sub = synchronized.subscribe();
+            let mut refresh = TimeRefresh::new(&state, DeimosView::Overview);
             loop {
-                fltk::app::lock().unwrap();
-                match *sub.borrow_and_update() {
-                    CachedPodState::Paused => {
-                        up_state.set_label("Paused");
-                        up_state.set_label_color(orbit::VENUS[3]);
-                        button.set_image(Some(start_rgb.clone()));
-                        pause_button.hide();
+                {
+                    let up = *sub.borrow_and_update();
+                    let requested = *requested_sub.borrow_and_update();
+                    let missing = *missing_sub.borrow_and_update();
+                    let exit = CachedPodExit::select(failure_sub.borrow_and_update().as_ref(), *stopped_sub.borrow_and_update());
+                    let breaker = breaker_sub.borrow_and_update().clone();
+                    //States loaded from the cache are shown until the first synchronization
+                    let stale = synchronized_sub.borrow_and_update().is_none();
+                    let _ui = UiLock::acquire();
+                    up_state.set_tooltip("");
+                    button.set_tooltip("");
+                    match (requested, up) {
+                        //Requested updates are shown in transit regardless of the state last
+                        //reported, so that the pod's controls cannot be used until the server
+                        //responds
+                        (Some(target), from) => {
+                            up_state.set_label(transit_label(from, target));
+                            up_state.set_label_color(orbit::MERCURY[1]);
+                            button.set_image(Some(load_rgb.clone()));
+                            pause_button.hide();
+                            restart_button.hide();
+                        },
+                        (None, CachedPodState::Paused) => {
+                            up_state.set_label("Paused");
+                            up_state.set_label_color(orbit::VENUS[3]);
+                            button.set_image(Some(resume_rgb.clone()));
+                            button.set_tooltip("Resume the pod");
+                            pause_button.hide();
+                            restart_button.hide();
+                        }
+                        (None, CachedPodState::Disabled) if missing => {
+                            up_state.set_label("Image not pulled");
+                            up_state.set_label_color(orbit::MARS[2]);
+                            up_state.set_tooltip("The pod's image must be pulled on the server before it can be enabled");
+                            button.set_image(Some(start_rgb.clone()));
+                            pause_button.hide();
+                            restart_button.hide();
+                        },
+                        (None, CachedPodState::Disabled) => {
+                            match (breaker.as_ref(), exit) {
+                                //Pods that the server stopped restarting are shown prominently,
+                                //as they stay disabled until they are started again
+                                (Some(breaker), _) => {
+                                    up_state.set_label(&format!("Restarts stopped after {} failures", breaker.failures));
+                                    up_state.set_label_color(orbit::MARS[1]);
+                                    let probe = match breaker.probe_at {
+                                        Some(at) => format!("\nRestarting once at {}", time::full(at)),
+                                        None => String::new(),
+                                    };
+                                    up_state.set_tooltip(&format!(
+                                        "Stopped {}: {}{}",
+                                        time::relative(breaker.opened, Utc::now()),
+                                        breaker.last_error,
+                                        probe,
+                                    ));
+                                    button.set_tooltip("Retry: start the pod and reset its restart policy");
+                                },
+                                (None, Some(exit)) => {
+                                    up_state.set_label(&format!("{} {}", exit.kind.label(), time::relative(exit.at, Utc::now())));
+                                    up_state.set_label_color(match exit.kind.severity() {
+                                        CachedPodExitSeverity::Neutral => orbit::NIGHT[0].lighter(),
+                                        CachedPodExitSeverity::Warning => orbit::VENUS[3],
+                                        CachedPodExitSeverity::Error => orbit::MARS[2],
+                                    });
+                                    up_state.set_tooltip(&time::full(exit.at));
+                                },
+                                (None, None) => {
+                                    up_state.set_label("Disabled");
+                                    up_state.set_label_color(orbit::NIGHT[0].lighter());
+                                },
+                            }
+                            button.set_image(Some(start_rgb.clone()));
+                            pause_button.hide();
+                            restart_button.hide();
+                        },
+                        (None, CachedPodState::Transit) => {
+                            up_state.set_label("");
+                            button.set_color(orbit::NIGHT[1]);
+                            button.set_image(Some(load_rgb.clone()));
+                            pause_button.hide();
+                            restart_button.hide();
+                        },
+                        (None, CachedPodState::Enabled) => {
+                            up_state.set_label("Enabled");
+                            up_state.set_label_color(orbit::EARTH[1]);
+                            button.set_image(Some(stop_rgb.clone()));
+                            pause_button.show();
+                            restart_button.show();
+                        },
+                        (None, CachedPodState::Degraded) => {
+                            up_state.set_label("Degraded");
+                            up_state.set_label_color(orbit::VENUS[3]);
+                            up_state.set_tooltip("The pod is enabled but failing its health check");
+                            button.set_image(Some(stop_rgb.clone()));
+                            pause_button.show();
+                            restart_button.show();
+                        },
                     }
-                    CachedPodState::Disabled => {
-                        up_state.set_label("Disabled");
-                        up_state.set_label_color(orbit::NIGHT[0].lighter());
-                        button.set_image(Some(start_rgb.clone()));
-                        pause_button.hide();
-                    },
-                    CachedPodState::Transit => {
-                        up_state.set_label("");
-                        button.set_color(orbit::NIGHT[1]);
-                        button.set_image(Some(load_rgb.clone()));
-                        pause_button.hide();
-                    },
-                    CachedPodState::Enabled => {
-                        up_state.set_label("Enabled");
-                        up_state.set_label_color(orbit::EARTH[1]);
-                        button.set_image(Some(stop_rgb.clone()));
-                        pause_button.show();
+
+                    if stale && requested.is_none() && !up_state.label().is_empty() {
+                        up_state.set_label(&format!("{} (last known)", up_state.label()));
+                        up_state.set_label_color(orbit::MERCURY[2]);
+                        up_state.set_tooltip("State cached when the client last ran, the pod has not been synchronized with the server yet");
                     }
+
+                    let row = row.clone();
+                    fltk::app::awake_callback(move || {
+                        row.layout();
+                        if let Some(mut under) = fltk::app::belowmouse::<fltk::button::Button>() {
+                            under.handle_event(Event::Enter);
+                        }
+                    });
                 }
-                
-                let row = row.clone();
-                fltk::app::awake_callback(move || {
-                    row.layout();
-                    if let Some(mut under) = fltk::app::belowmouse::<fltk::button::Button>() {
-                        under.handle_event(Event::Enter);
-                    }
-                });
-                fltk::app::unlock();
-                fltk::app::awake();
 
-                let Ok(_) = sub.changed().await else {
-                    break;
+                let changed = tokio::select! {
+                    result = sub.changed() => result,
+                    result = requested_sub.changed() => result,
+                    result = missing_sub.changed() => result,
+                    result = failure_sub.changed() => result,
+                    result = stopped_sub.changed() => result,
+                    result = breaker_sub.changed() => result,
+                    result = synchronized_sub.changed() => result,
+                    _ = refresh.tick() => Ok(()),
                 };
+
+                if changed.is_err() {
+                    break
+                }
             }
-        });
+        }));
     }
     
     {
         let state = state.clone();
         let pod = pod.clone();
-        let up = pod.data.up.clone();
-        button.set_callback(move |b| {
-            let current = *up.read();
+        button.set_callback(move |_| {
+            let current = pod.data.shown_state();
+
+            //Right clicking a stopped pod offers to start it for a limited time
+            if fltk::app::event_mouse_button() == fltk::app::MouseButton::Right {
+                if !matches!(current, CachedPodState::Disabled | CachedPodState::Paused) {
+                    return
+                }
+
+                let menu = fltk::menu::MenuItem::new(&TIMED_ENABLE_PRESETS.map(|(label, _)| label));
+                let Some(duration) = menu
+                    .popup(fltk::app::event_x(), fltk::app::event_y())
+                    .and_then(|item| item.label())
+                    .and_then(|label| TIMED_ENABLE_PRESETS.iter().find(|(preset, _)| *preset == label))
+                    .map(|(_, duration)| *duration) else {
+                    return
+                };
+
+                let state = state.clone();
+                let pod = pod.clone();
+                tokio::task::spawn(async move {
+                    state.ctx.enable_for(&pod, duration).await;
+                });
+
+                return
+            }
+
             let to = match current {
                 CachedPodState::Disabled | CachedPodState::Paused => CachedPodState::Enabled,
                 CachedPodState::Transit => return,
-                CachedPodState::Enabled => CachedPodState::Disabled,
+                CachedPodState::Enabled | CachedPodState::Degraded => CachedPodState::Disabled,
             };
-            
-            fltk::app::lock().ok();
-            b.set_image(Some(load_rgb.clone()));
-            fltk::app::unlock();
-            fltk::app::awake();
+
+            if to == CachedPodState::Disabled && !confirm_action(&state, &pod, "Stop") {
+                return
+            }
             
             let state = state.clone();
             let pod = pod.clone();
@@ -274,9 +1124,31 @@ pub fn pod_button(state: DeimosStateHandle, pod: Arc<CachedPod>) -> Flex {
     }
 
     {
-        let up = pod.data.up.clone();
+        let state = state.clone();
+        let pod = pod.clone();
+        restart_button.set_callback(move |_| {
+            //The button may still be shown briefly after another update was requested
+            if !pod.data.shown_state().satisfies(CachedPodState::Enabled) {
+                return
+            }
+
+            if !confirm_action(&state, &pod, "Restart") {
+                return
+            }
+
+            let state = state.clone();
+            let pod = pod.clone();
+            tokio::task::spawn(async move {
+                state.ctx.restart(&pod).await;
+            });
+        });
+    }
+
+    {
+        let pod = pod.clone();
         pause_button.set_callback(move |_| {
-            if *up.read() != CachedPodState::Enabled {
+            //The button may still be shown briefly after a resume or stop was requested
+            if !pod.data.shown_state().satisfies(CachedPodState::Enabled) {
                 return
             }
             
@@ -290,5 +1162,131 @@ pub fn pod_button(state: DeimosStateHandle, pod: Arc<CachedPod>) -> Flex {
 
     row.end();
 
-    row
+    PodButton {
+        row,
+        pod,
+        tasks,
+    }
+}
+
+/// Durations offered when right clicking the start button of a stopped pod
+const TIMED_ENABLE_PRESETS: [(&str, Duration); 4] = [
+    ("1 hour", Duration::from_secs(60 * 60)),
+    ("2 hours", Duration::from_secs(2 * 60 * 60)),
+    ("4 hours", Duration::from_secs(4 * 60 * 60)),
+    ("8 hours", Duration::from_secs(8 * 60 * 60)),
+];
+
+/// Ask the user to confirm stopping or restarting the given pod as strictly as the pod's server
+/// and the local settings require, returning `true` if the action should be made.
+/// Must be called from the UI thread, as the dialogs shown block it until they are closed
+fn confirm_action(state: &DeimosStateHandle, pod: &CachedPod, action: &str) -> bool {
+    let name = pod.data.name.read().clone();
+    match state.ctx.confirm_level(pod) {
+        CachedPodConfirmLevel::None => true,
+        CachedPodConfirmLevel::Confirm => {
+            let message = format!("{} {}?", action, name);
+            fltk::dialog::choice2_default(&message, "Cancel", action, "") == Some(1)
+        },
+        CachedPodConfirmLevel::TypeToConfirm => {
+            let message = format!("{} {}?\nType '{}' to confirm", action, name, pod.data.id);
+            let Some(typed) = fltk::dialog::input_default(&message, "") else {
+                return false
+            };
+
+            let confirmed = CachedPodConfirmLevel::matches_typed(&typed, pod.data.id.as_str());
+            if !confirmed {
+                fltk::dialog::alert_default(&format!("'{}' does not match the pod ID '{}'", typed.trim(), pod.data.id));
+            }
+
+            confirmed
+        },
+    }
+}
+
+/// Get the label shown for a pod while an update from the given state to the target state is
+/// in flight
+fn transit_label(from: CachedPodState, target: CachedPodState) -> &'static str {
+    match (from, target) {
+        (CachedPodState::Paused, CachedPodState::Enabled) => "Resuming",
+        (CachedPodState::Enabled | CachedPodState::Degraded, CachedPodState::Enabled) => "Restarting",
+        (_, CachedPodState::Enabled | CachedPodState::Degraded) => "Starting",
+        (_, CachedPodState::Paused) => "Pausing",
+        (_, CachedPodState::Disabled | CachedPodState::Transit) => "Stopping",
+    }
+}
+
+/// Open a window showing the reason for the given failure of a pod's container and the output
+/// that the container wrote before it failed
+fn failure_window(name: &str, failure: &CachedPodFailure) {
+    let mut window = Window::default()
+        .with_size(720, 480)
+        .with_label(&format!("{} - container failure", name));
+    window.make_resizable(true);
+
+    let mut flex = Flex::default_fill().column();
+    flex.set_margins(8, 8, 8, 8);
+    flex.set_spacing(8);
+
+    let mut reason = Frame::default();
+    reason.set_label_font(crate::app::HEADER_FONT);
+    reason.set_label_size(16);
+    reason.set_label_color(orbit::MARS[1]);
+    reason.set_align(Align::Inside | Align::Left | Align::Clip);
+    reason.set_label(&failure.reason);
+    flex.fixed(&reason, 24);
+
+    let mut at = Frame::default();
+    at.set_label_font(crate::app::SUBTITLE_FONT);
+    at.set_label_size(12);
+    at.set_label_color(orbit::MERCURY[1]);
+    at.set_align(Align::Inside | Align::Left | Align::Clip);
+    at.set_label(&time::full(failure.at));
+    flex.fixed(&at, 16);
+
+    let mut buffer = TextBuffer::default();
+    buffer.set_text(match failure.output.is_empty() {
+        true => "The container's output could not be read",
+        false => &failure.output,
+    });
+
+    let mut display = TextDisplay::default();
+    display.set_frame(FrameType::FlatBox);
+    display.set_color(orbit::NIGHT[3]);
+    display.set_text_font(crate::app::GENERAL_FONT);
+    display.set_text_size(12);
+    display.set_text_color(orbit::SOL[1]);
+    display.set_selection_color(orbit::NIGHT[0]);
+    display.set_buffer(buffer.clone());
+
+    //The end of the output is most likely to explain the failure
+    display.set_insert_position(buffer.length());
+    display.show_insert_position();
+
+    flex.end();
+    window.set_color(orbit::NIGHT[1]);
+    window.end();
+    window.show();
+}
+
+/// Get a short description of when the given scheduled transition will occur, converted to the
+/// local time zone
+fn schedule_hint(transition: &CachedPodTransition, now: DateTime<Utc>) -> String {
+    let verb = match transition.action {
+        CachedPodState::Enabled => "Starts",
+        _ => "Stops",
+    };
+
+    let local = transition.at.with_timezone(&Local);
+    let time = match local.date_naive() == now.with_timezone(&Local).date_naive() {
+        true => local.format("%H:%M").to_string(),
+        false => local.format("%a %H:%M").to_string(),
+    };
+
+    let remaining = transition.at - now;
+    match transition.skipped {
+        true => format!("Scheduled transition at {} skipped", time),
+        false if remaining < TimeDelta::hours(1) => format!("{} in {}m", verb, remaining.num_minutes().max(1)),
+        false => format!("{} at {}", verb, time),
+    }
 }
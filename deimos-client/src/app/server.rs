@@ -0,0 +1,155 @@
+//! Selection of the server whose settings and token are shown in the settings and authorization
+//! views, shared between both views so that switching to either keeps the same server
+
+use std::sync::Arc;
+
+use fltk::{menu::Choice, prelude::{GroupExt, MenuExt, WidgetExt}};
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::sync::watch;
+
+use crate::context::{server::{ContextServer, ServerId}, NotifyMutation};
+
+use super::{style::input::choice_box, ui::UiLock, DeimosStateHandle};
+
+/// Receiver of a value belonging to the selected server, following the selection as it changes
+pub struct SelectedWatch<T: 'static> {
+    state: DeimosStateHandle,
+    field: fn(&ContextServer) -> &NotifyMutation<T>,
+    selected: watch::Receiver<ServerId>,
+    sub: watch::Receiver<T>,
+}
+
+/// Receivers of a value belonging to every server, replaced whenever a server is added or removed
+pub struct ServersWatch<T: 'static> {
+    field: fn(&ContextServer) -> &NotifyMutation<T>,
+    servers: watch::Receiver<Vec<Arc<ContextServer>>>,
+    subs: Vec<watch::Receiver<T>>,
+}
+
+impl DeimosStateHandle {
+    /// Get the selected server, or the first server if the selected server has been removed
+    pub fn selected_server(&self) -> Arc<ContextServer> {
+        let selected = self.selected.borrow().clone();
+        self.ctx
+            .server(&selected)
+            .unwrap_or_else(|| self.ctx.servers.read()[0].clone())
+    }
+
+    /// Select the server shown in the settings and authorization views
+    pub fn select_server(&self, id: ServerId) {
+        self.selected.send_replace(id);
+    }
+}
+
+impl<T: 'static> SelectedWatch<T> {
+    /// Watch the given field of the selected server, waiting for the state to be set
+    pub fn new(state: &DeimosStateHandle, field: fn(&ContextServer) -> &NotifyMutation<T>) -> Self {
+        let selected = state.selected.subscribe();
+        let sub = field(&state.selected_server()).subscribe();
+        Self {
+            state: state.clone(),
+            field,
+            selected,
+            sub,
+        }
+    }
+
+    /// Get the current value of the selected server, marking it as seen
+    pub fn borrow_and_update(&mut self) -> watch::Ref<'_, T> {
+        self.sub.borrow_and_update()
+    }
+
+    /// Wait until the value changes or another server is selected
+    pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        tokio::select! {
+            result = self.sub.changed() => result,
+            result = self.selected.changed() => {
+                result?;
+                self.sub = (self.field)(&self.state.selected_server()).subscribe();
+                Ok(())
+            },
+        }
+    }
+}
+
+impl<T: 'static> ServersWatch<T> {
+    /// Watch the given field of every server, waiting for the state to be set
+    pub fn new(state: &DeimosStateHandle, field: fn(&ContextServer) -> &NotifyMutation<T>) -> Self {
+        Self {
+            field,
+            servers: state.ctx.servers.subscribe(),
+            subs: Vec::new(),
+        }
+    }
+
+    /// Get every server, marking the server list and the value of each server as seen
+    pub fn servers(&mut self) -> Vec<Arc<ContextServer>> {
+        let servers = self.servers.borrow_and_update().clone();
+        self.subs = servers.iter().map(|server| (self.field)(server).subscribe()).collect();
+        servers
+    }
+
+    /// Wait until the value of any server changes, or a server is added or removed
+    pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        let mut values = self.subs.iter_mut().map(|sub| sub.changed()).collect::<FuturesUnordered<_>>();
+        tokio::select! {
+            result = self.servers.changed() => result,
+            Some(result) = values.next() => result,
+        }
+    }
+}
+
+/// Create a drop-down listing every server, selecting the server shown in the settings and
+/// authorization views. Returns a tuple with (container with label and choice, choice)
+pub fn server_choice(state: DeimosStateHandle) -> (impl GroupExt, Choice) {
+    let (frame, mut choice) = choice_box("Server", &[]);
+
+    {
+        let state = state.clone();
+        choice.set_callback(move |choice| {
+            let Ok(idx) = usize::try_from(choice.value()) else { return };
+            let server = state.ctx.servers.read().get(idx).map(|server| server.id.clone());
+            if let Some(id) = server {
+                state.select_server(id);
+            }
+        });
+    }
+
+    {
+        let mut choice = choice.clone();
+        tokio::task::spawn(async move {
+            let mut servers_sub = state.ctx.servers.subscribe();
+            //Labels are taken from the server URI, which only changes for the selected server
+            let mut settings_sub = SelectedWatch::new(&state, |server| &server.clients.settings);
+            loop {
+                {
+                    let servers = servers_sub.borrow_and_update().clone();
+                    settings_sub.borrow_and_update();
+                    let selected = state.selected_server();
+
+                    let _ui = UiLock::acquire();
+                    choice.clear();
+                    for server in servers.iter() {
+                        //Slashes would otherwise nest the entry in a submenu
+                        choice.add_choice(&server.label().replace('/', "\\/"));
+                    }
+
+                    let idx = servers.iter().position(|server| server.id == selected.id).unwrap_or_default();
+                    choice.set_value(idx as i32);
+                    choice.set_damage(true);
+                }
+
+                let changed = tokio::select! {
+                    result = servers_sub.changed() => result,
+                    result = settings_sub.changed() => result,
+                };
+
+                if changed.is_err() {
+                    break
+                }
+            }
+        });
+    }
+
+    (frame, choice)
+}
@@ -1,11 +1,11 @@
 use std::{str::FromStr, time::Duration};
 
-use fltk::{button::Button, group::{Group, Pack, PackType}, image::SvgImage, input::{Input, IntInput}, prelude::{GroupExt, InputExt, WidgetBase, WidgetExt}};
+use fltk::{button::Button, enums::Align, frame::Frame, group::{Group, Pack, PackType}, image::SvgImage, input::{Input, IntInput}, prelude::{GroupExt, InputExt, WidgetBase, WidgetExt}};
 use http::Uri;
 
-use crate::context::client::ContextSettings;
+use crate::context::{client::{stream::StreamMode, ContextSettings}, confirm::CachedPodConfirmLevel};
 
-use super::{orbit, style::{self, input::input_box}, DeimosStateHandle};
+use super::{error, orbit, server::{server_choice, SelectedWatch}, style::{self, input::{choice_box, input_box}}, ui::{self, UiLock}, DeimosStateHandle, DeimosView};
 
 
 pub fn settings(state: DeimosStateHandle) -> Group {
@@ -23,32 +23,145 @@ pub fn settings(state: DeimosStateHandle) -> Group {
     save_button.set_size(42, 42);
     save_button.set_image(Some(save_img));
 
+    let (frame, _) = server_choice(state.clone());
+    frame.with_size(top.width() - 16, 60);
+    let mut server_buttons = Pack::default().with_size(top.width() - 16, 32);
+    server_buttons.set_type(PackType::Horizontal);
+    server_buttons.set_spacing(8);
+    let mut add_button = server_button("Add Server", (server_buttons.width() - 8) / 2);
+    let mut remove_button = server_button("Remove Server", (server_buttons.width() - 8) / 2);
+    server_buttons.end();
+
     let (frame, mut host_url) = input_box::<Input>("Host URL");
     frame.center_of_parent().with_size(top.width() - 16, 60);
     let (frame, mut request_timeout) = input_box::<IntInput>("gRPC Request Timeout (seconds)");
     frame.center_of_parent().with_size(top.width() - 16, 60);
     let (frame, mut connect_timeout) = input_box::<IntInput>("gRPC Connection Timeout (seconds)");
+    frame.center_of_parent().with_size(top.width() - 16, 60);
+    let (frame, mut max_message_size) = input_box::<IntInput>("Maximum gRPC Message Size (KiB)");
+    frame.with_size(top.width() - 16, 60);
+    let modes = StreamMode::ALL.map(|mode| mode.to_string());
+    let (frame, mut stream_mode) = choice_box("Pod Status Updates", &modes.each_ref().map(String::as_str));
+    frame.with_size(top.width() - 16, 60);
+    let (frame, mut poll_interval) = input_box::<IntInput>("Polling Interval (seconds)");
     frame.with_size(top.width() - 16, 60);
+    let (frame, mut max_concurrent_requests) = input_box::<IntInput>("Maximum Concurrent Requests");
+    frame.with_size(top.width() - 16, 60);
+    let (frame, mut queue_offline_actions) = choice_box("Offline Actions", &OFFLINE_ACTION_CHOICES);
+    frame.with_size(top.width() - 16, 60);
+    let mut queue_explanation = Frame::default().with_size(top.width() - 16, 36);
+    queue_explanation.set_align(Align::Inside | Align::Left | Align::Top | Align::Wrap);
+    queue_explanation.set_label_font(crate::app::GENERAL_FONT);
+    queue_explanation.set_label_size(12);
+    queue_explanation.set_label_color(orbit::MERCURY[1]);
+    queue_explanation.set_label(
+        "When queued, pods started or stopped while the server is unreachable are changed once it reconnects, \
+        which may be long after the change was requested. Changes are skipped if the pod was changed on the server in the meantime."
+    );
+    let levels = CachedPodConfirmLevel::ALL.map(|level| level.to_string());
+    let (frame, mut confirm_level) = choice_box("Confirm Stop and Restart", &levels.each_ref().map(String::as_str));
+    frame.with_size(top.width() - 16, 60);
+
+    let mut errors_button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    errors_button.set_size(top.width() - 16, 32);
+    errors_button.set_label("Show Recent Errors");
+    errors_button.set_label_font(crate::app::GENERAL_FONT);
+    errors_button.set_label_size(14);
+    errors_button.set_label_color(orbit::SOL[0]);
+    {
+        let errors = state.ctx.errors.clone();
+        errors_button.set_callback(move |_| error::error_log_window(errors.clone()));
+    }
+
+    {
+        let state = state.clone();
+        add_button.set_callback(move |_| {
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                let server = state.ctx.add_server(ContextSettings::default()).await;
+                state.select_server(server.id.clone());
+            });
+        });
+    }
+
+    {
+        let state = state.clone();
+        remove_button.set_callback(move |_| {
+            let server = state.selected_server();
+            let message = format!(
+                "Remove server '{}'? Its token, cached pods, and queued actions will be deleted.",
+                server.label(),
+            );
+
+            if fltk::dialog::choice2_default(&message, "Cancel", "Remove server", "") != Some(1) {
+                return
+            }
+
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                state.ctx.remove_server(&server.id).await;
+                let first = state.ctx.servers.read()[0].id.clone();
+                state.select_server(first);
+            });
+        });
+    }
+
+    {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            let mut sub = state.ctx.servers.subscribe();
+            loop {
+                {
+                    //The last server cannot be removed
+                    let removable = sub.borrow_and_update().len() > 1;
+                    let _ui = UiLock::acquire();
+                    match removable {
+                        true => remove_button.activate(),
+                        false => remove_button.deactivate(),
+                    }
+
+                    remove_button.set_damage(true);
+                }
+
+                if sub.changed().await.is_err() {
+                    break
+                }
+            }
+        });
+    }
 
     {
         let state = state.clone();
         let mut host_url = host_url.clone();
         let mut request_timeout = request_timeout.clone();
         let mut connect_timeout = connect_timeout.clone();
+        let mut max_message_size = max_message_size.clone();
+        let mut stream_mode = stream_mode.clone();
+        let mut poll_interval = poll_interval.clone();
+        let mut max_concurrent_requests = max_concurrent_requests.clone();
+        let mut queue_offline_actions = queue_offline_actions.clone();
+        let mut confirm_level = confirm_level.clone();
         tokio::task::spawn(
             async move {
-                let mut sub = state.ctx.clients.settings.subscribe();
+                let mut sub = SelectedWatch::new(&state, |server| &server.clients.settings);
                 loop {
                     {
                         let settings = sub.borrow_and_update();
-                        
-                        fltk::app::lock().ok();
+                        let _ui = UiLock::acquire();
 
                         host_url.set_value(&settings.server_uri.to_string());
                         request_timeout.set_value(&settings.request_timeout.as_secs().to_string());
                         connect_timeout.set_value(&settings.connect_timeout.as_secs().to_string());
-
-                        fltk::app::unlock();
+                        max_message_size.set_value(&(settings.max_message_size / 1024).to_string());
+                        stream_mode.set_value(
+                            StreamMode::ALL.iter().position(|mode| *mode == settings.stream_mode).unwrap_or_default() as i32
+                        );
+                        poll_interval.set_value(&settings.poll_interval.as_secs().to_string());
+                        max_concurrent_requests.set_value(&settings.max_concurrent_requests.to_string());
+                        queue_offline_actions.set_value(settings.queue_offline_actions as i32);
+                        confirm_level.set_value(
+                            CachedPodConfirmLevel::ALL.iter().position(|level| *level == settings.confirm_level).unwrap_or_default() as i32
+                        );
                     }
 
                     let Ok(_) = sub.changed().await else {
@@ -62,8 +175,6 @@ pub fn settings(state: DeimosStateHandle) -> Group {
     top.end();
 
     save_button.set_callback(move |_| {
-        fltk::app::lock().ok();
-        
         fn parse_from<T, I: InputExt, M: FnOnce(String) -> Option<T>>(input: &mut I, map: M) -> Option<T> {
             match map(input.value()) {
                 None => {
@@ -75,18 +186,26 @@ pub fn settings(state: DeimosStateHandle) -> Group {
             }
         }
 
-        let server_uri = parse_from(&mut host_url, |val| Uri::from_str(&val).ok());
-        let request_timeout = parse_from(&mut request_timeout, |val| u64::from_str(&val).ok().map(Duration::from_secs));
-        let connect_timeout = parse_from(&mut connect_timeout, |val| u64::from_str(&val).ok().map(Duration::from_secs));
-        
-        fltk::app::unlock();
-        fltk::app::awake();
+        let (server_uri, request_timeout, connect_timeout, max_message_size, poll_interval, max_concurrent_requests, stream_mode, queue_offline_actions, confirm_level) = ui::with_lock(|| (
+            parse_from(&mut host_url, |val| Uri::from_str(&val).ok()),
+            parse_from(&mut request_timeout, |val| u64::from_str(&val).ok().map(Duration::from_secs)),
+            parse_from(&mut connect_timeout, |val| u64::from_str(&val).ok().map(Duration::from_secs)),
+            parse_from(&mut max_message_size, |val| usize::from_str(&val).ok().and_then(|kib| kib.checked_mul(1024))),
+            parse_from(&mut poll_interval, |val| u64::from_str(&val).ok().filter(|secs| *secs != 0).map(Duration::from_secs)),
+            parse_from(&mut max_concurrent_requests, |val| usize::from_str(&val).ok().filter(|count| *count != 0)),
+            usize::try_from(stream_mode.value()).ok().and_then(|idx| StreamMode::ALL.get(idx).copied()).unwrap_or_default(),
+            queue_offline_actions.value() == 1,
+            usize::try_from(confirm_level.value()).ok().and_then(|idx| CachedPodConfirmLevel::ALL.get(idx).copied()).unwrap_or_default(),
+        ));
 
         let (
             Some(server_uri),
             Some(request_timeout),
-            Some(connect_timeout)
-        ) = (server_uri, request_timeout, connect_timeout) else {
+            Some(connect_timeout),
+            Some(max_message_size),
+            Some(poll_interval),
+            Some(max_concurrent_requests),
+        ) = (server_uri, request_timeout, connect_timeout, max_message_size, poll_interval, max_concurrent_requests) else {
             return
         };
         
@@ -94,6 +213,12 @@ pub fn settings(state: DeimosStateHandle) -> Group {
             server_uri,
             request_timeout,
             connect_timeout,
+            max_message_size,
+            stream_mode,
+            poll_interval,
+            max_concurrent_requests,
+            queue_offline_actions,
+            confirm_level,
         };
 
         tracing::trace!("Got new settings {:?}", settings);
@@ -101,11 +226,25 @@ pub fn settings(state: DeimosStateHandle) -> Group {
         let state = state.clone();
         tokio::task::spawn(
             async move {
-                state.set_view(state.overview.clone()).await;
-                state.ctx.clients.reload(settings).await;
+                state.set_view(DeimosView::Overview).await;
+                state.selected_server().clients.reload(settings).await;
             }
         );
     });
     
     top.as_group().unwrap()
 }
+
+/// Create a button for adding or removing servers with the given label and width
+fn server_button(label: &str, width: i32) -> Button {
+    let mut button = style::button::button::<Button>(orbit::NIGHT[1], orbit::NIGHT[0]);
+    button.set_size(width, 32);
+    button.set_label(label);
+    button.set_label_font(crate::app::GENERAL_FONT);
+    button.set_label_size(14);
+    button.set_label_color(orbit::SOL[0]);
+    button
+}
+
+/// Options shown for the offline actions setting, indexed by whether actions are queued
+const OFFLINE_ACTION_CHOICES: [&str; 2] = ["Fail while disconnected", "Queue until reconnected"];
@@ -1,4 +1,4 @@
-use fltk::{enums::{Align, CallbackTrigger}, frame::Frame, group::{Pack, PackType}, prelude::{GroupExt, InputExt, WidgetBase, WidgetExt}};
+use fltk::{enums::{Align, CallbackTrigger}, frame::Frame, group::{Pack, PackType}, menu::Choice, prelude::{GroupExt, InputExt, MenuExt, WidgetBase, WidgetExt}};
 
 use crate::app::orbit;
 
@@ -41,3 +41,28 @@ pub fn input_box<I: InputExt + Default>(label: &str) -> (impl GroupExt, I) {
 
     (frame, input)
 }
+
+/// Create a new drop-down selection with the given label and options, styled to match
+/// [input_box]. Returns a tuple with (container with label and choice, choice)
+pub fn choice_box(label: &str, options: &[&str]) -> (impl GroupExt, Choice) {
+    let mut frame = Pack::default_fill();
+    frame.set_spacing(8);
+    frame.set_type(PackType::Vertical);
+    input_lbl(label).with_size(frame.width(), 20);
+
+    let mut choice = Choice::default().with_size(frame.width(), 40);
+    choice.set_frame(fltk::enums::FrameType::RShadowBox);
+    choice.set_down_frame(fltk::enums::FrameType::RShadowBox);
+    choice.set_text_color(orbit::MERCURY[1]);
+    choice.set_text_font(crate::app::GENERAL_FONT);
+    choice.set_text_size(18);
+    choice.set_color(orbit::NIGHT[1]);
+    choice.set_selection_color(orbit::NIGHT[0]);
+    for option in options {
+        choice.add_choice(option);
+    }
+
+    frame.end();
+
+    (frame, choice)
+}
@@ -0,0 +1,326 @@
+use std::{sync::Arc, time::Duration};
+
+use fltk::{button::Button, enums::{Align, Color, Event, FrameType, Key}, frame::Frame, group::{Flex, Group}, image::SvgImage, prelude::{DisplayExt, GroupExt, WidgetBase, WidgetExt}, text::{StyleTableEntry, TextBuffer, TextDisplay}};
+
+use crate::context::terminal::{TerminalColor, TerminalScreen, TerminalSession, TerminalSize};
+
+use super::{orbit, style, ui::{self, UiLock}, DeimosStateHandle, DeimosView};
+
+/// Size of the monospace font used to display terminal sessions
+const TERMINAL_FONT_SIZE: i32 = 14;
+
+/// Colors of the 16 standard and bright ANSI colors
+const ANSI_COLORS: [Color; 16] = [
+    Color::from_u32(0x1B1B1C),
+    Color::from_u32(0xC0504D),
+    Color::from_u32(0x7EA65A),
+    Color::from_u32(0xC49656),
+    Color::from_u32(0x3689B3),
+    Color::from_u32(0x9B6BAF),
+    Color::from_u32(0x4BA3A3),
+    Color::from_u32(0x9B98A1),
+    Color::from_u32(0x5C5C60),
+    Color::from_u32(0xE07470),
+    Color::from_u32(0x9CC97A),
+    Color::from_u32(0xE3B873),
+    Color::from_u32(0x5AAAD6),
+    Color::from_u32(0xBB8DCF),
+    Color::from_u32(0x6CC6C6),
+    Color::from_u32(0xFFF4EA),
+];
+
+/// View displaying the interactive terminal session that the user has opened in a pod.
+/// Keys typed while the terminal has focus are sent to the session, except for Ctrl+] which
+/// releases focus so that the rest of the window can be used with the keyboard
+pub fn terminal(state: DeimosStateHandle) -> Group {
+    let mut top = Group::default_fill();
+    top.hide();
+
+    let mut flex = Flex::default_fill().column();
+    flex.set_margins(8, 8, 8, 8);
+    flex.set_spacing(8);
+
+    let mut header = Flex::default().row();
+    header.set_spacing(8);
+    flex.fixed(&header, 32);
+
+    let mut title = Frame::default();
+    title.set_label_font(crate::app::HEADER_FONT);
+    title.set_label_size(18);
+    title.set_label_color(orbit::SOL[1]);
+    title.set_align(Align::Inside | Align::Left | Align::Clip);
+
+    let mut status = Frame::default();
+    status.set_label_font(crate::app::SUBTITLE_FONT);
+    status.set_label_size(12);
+    status.set_label_color(orbit::MERCURY[1]);
+    status.set_align(Align::Inside | Align::Right | Align::Clip);
+
+    let close_svg = SvgImage::from_data(include_str!("../../assets/close.svg")).unwrap();
+    let close_rgb = style::svg::svg_color(close_svg, 24, orbit::MERCURY[1]);
+    let mut close_button = style::button::button::<Button>(orbit::NIGHT[2], orbit::NIGHT[0]);
+    close_button.set_image(Some(close_rgb));
+    close_button.set_tooltip("Close the session and return to the pod list");
+    header.fixed(&close_button, 32);
+
+    header.end();
+
+    let buffer = TextBuffer::default();
+    let style_buffer = TextBuffer::default();
+    let mut display = TextDisplay::default();
+    display.set_frame(FrameType::FlatBox);
+    display.set_color(orbit::NIGHT[3]);
+    display.set_text_font(crate::app::GENERAL_FONT);
+    display.set_text_size(TERMINAL_FONT_SIZE);
+    display.set_text_color(orbit::SOL[1]);
+    display.set_selection_color(orbit::NIGHT[0]);
+    display.set_cursor_style(fltk::text::Cursor::Block);
+    display.set_cursor_color(orbit::SOL[1]);
+    display.set_buffer(buffer.clone());
+    display.set_highlight_data(style_buffer.clone(), terminal_styles());
+
+    let mut hint = Frame::default();
+    hint.set_label_font(crate::app::SUBTITLE_FONT);
+    hint.set_label_size(10);
+    hint.set_label_color(orbit::MERCURY[2]);
+    hint.set_align(Align::Inside | Align::Left);
+    hint.set_label("Click the terminal to type, Ctrl+] releases the keyboard");
+    flex.fixed(&hint, 12);
+
+    flex.end();
+    top.end();
+
+    {
+        let state = state.clone();
+        close_button.set_callback(move |_| {
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                state.ctx.close_terminal();
+                state.set_view(DeimosView::Overview).await;
+            });
+        });
+    }
+
+    {
+        let state = state.clone();
+        let close_button = close_button.clone();
+        display.handle(move |d, ev| match ev {
+            Event::Focus | Event::Unfocus => {
+                d.show_cursor(ev == Event::Focus);
+                d.redraw();
+                true
+            },
+            Event::Push => {
+                let _ = d.take_focus();
+                false
+            },
+            Event::KeyDown if d.has_focus() => {
+                let Some(session) = state.ctx.terminal.read().clone() else {
+                    return false
+                };
+
+                let key = fltk::app::event_key();
+                let ctrl = fltk::app::is_event_ctrl();
+                if ctrl && key == Key::from_char(']') {
+                    fltk::app::set_focus(&close_button);
+                    d.show_cursor(false);
+                    d.redraw();
+                    return true
+                }
+
+                if let Some(input) = key_input(key, &fltk::app::event_text(), ctrl) {
+                    session.write(&input);
+                }
+
+                //Keys are consumed even if they send nothing so that they do not navigate focus
+                true
+            },
+            _ => false,
+        });
+    }
+
+    {
+        let state = state.clone();
+        display.resize_callback(move |d, _, _, w, h| {
+            //The display is laid out when the window is first shown, before the state is set
+            let Some(state) = state.0.get() else {
+                return
+            };
+
+            if let Some(session) = state.ctx.terminal.read().as_ref() {
+                session.resize(terminal_size(d, w, h));
+            }
+        });
+    }
+
+    {
+        let mut display = display.clone();
+        tokio::task::spawn(async move {
+            let mut sub = state.ctx.terminal.subscribe();
+            loop {
+                let session = sub.borrow_and_update().clone();
+                let changed = match session {
+                    Some(session) => {
+                        ui::with_lock(|| {
+                            title.set_label(&session.name);
+                            title.set_damage(true);
+                            let (w, h) = (display.w(), display.h());
+                            session.resize(terminal_size(&display, w, h));
+                            let _ = display.take_focus();
+                        });
+
+                        tokio::select! {
+                            never = show_session(&session, display.clone(), buffer.clone(), style_buffer.clone(), status.clone()) => never,
+                            changed = sub.changed() => changed,
+                        }
+                    },
+                    None => sub.changed().await,
+                };
+
+                if changed.is_err() {
+                    break
+                }
+            }
+        });
+    }
+
+    top
+}
+
+/// Display the screen of the given session and its status until the session is replaced
+async fn show_session(session: &Arc<TerminalSession>, mut display: TextDisplay, mut buffer: TextBuffer, mut style_buffer: TextBuffer, mut status: Frame) -> ! {
+    let mut screen_sub = session.screen.subscribe();
+    let mut ended_sub = session.ended.subscribe();
+    loop {
+        {
+            let (text, styles, cursor) = render(&screen_sub.borrow_and_update());
+            let ended = ended_sub.borrow_and_update().clone();
+            let _ui = UiLock::acquire();
+
+            buffer.set_text(&text);
+            style_buffer.set_text(&styles);
+            display.set_insert_position(cursor as i32);
+            display.show_insert_position();
+
+            match ended {
+                Some(end) => {
+                    status.set_label(&end.to_string());
+                    status.set_label_color(orbit::MARS[0]);
+                },
+                None => {
+                    status.set_label("Connected");
+                    status.set_label_color(orbit::EARTH[1]);
+                }
+            }
+
+            status.set_damage(true);
+            display.redraw();
+        }
+
+        let changed = tokio::select! {
+            result = screen_sub.changed() => result,
+            result = ended_sub.changed() => result,
+        };
+
+        if changed.is_err() {
+            std::future::pending::<()>().await;
+        }
+
+        //Output often arrives in many small chunks, which are drawn together
+        tokio::time::sleep(Duration::from_millis(16)).await;
+    }
+}
+
+/// Get the text of the given screen with a style character for each byte of the text, and the
+/// byte offset of the cursor in the text
+fn render(screen: &TerminalScreen) -> (String, String, usize) {
+    let (cursor_line, cursor_column) = screen.cursor();
+    let mut text = String::new();
+    let mut styles = String::new();
+    let mut cursor = 0;
+
+    for (idx, line) in screen.lines().enumerate() {
+        if idx != 0 {
+            text.push('\n');
+            styles.push('A');
+        }
+
+        for (column, cell) in line.iter().enumerate() {
+            if idx == cursor_line && column == cursor_column {
+                cursor = text.len();
+            }
+
+            text.push(cell.ch);
+            let style = match cell.color {
+                TerminalColor::Default => 'A',
+                TerminalColor::Ansi(color) => char::from(b'B' + color.min(15)),
+            };
+
+            styles.extend(std::iter::repeat_n(style, cell.ch.len_utf8()));
+        }
+
+        if idx == cursor_line && cursor_column >= line.len() {
+            cursor = text.len();
+        }
+    }
+
+    (text, styles, cursor)
+}
+
+/// Styles indexed by the characters of the style buffer, starting at `A` for the default color
+/// followed by each ANSI color
+fn terminal_styles() -> Vec<StyleTableEntry> {
+    std::iter::once(orbit::SOL[1])
+        .chain(ANSI_COLORS)
+        .map(|color| StyleTableEntry {
+            color,
+            font: crate::app::GENERAL_FONT,
+            size: TERMINAL_FONT_SIZE,
+        })
+        .collect()
+}
+
+/// Get the number of character cells that fit in a terminal display of the given size
+fn terminal_size(display: &TextDisplay, w: i32, h: i32) -> TerminalSize {
+    fltk::draw::set_font(crate::app::GENERAL_FONT, TERMINAL_FONT_SIZE);
+    let cell_w = fltk::draw::width("M").max(1.) as i32;
+    let cell_h = fltk::draw::height().max(1);
+    let scrollbar = display.scrollbar_size().max(fltk::app::scrollbar_size());
+
+    TerminalSize {
+        columns: ((w - scrollbar - 8) / cell_w).clamp(1, u16::MAX as i32) as u16,
+        rows: ((h - scrollbar) / cell_h).clamp(1, u16::MAX as i32) as u16,
+    }
+}
+
+/// Get the bytes sent to a terminal session for the given key, using the sequences of a VT100
+/// for keys that do not produce text
+fn key_input(key: Key, text: &str, ctrl: bool) -> Option<Vec<u8>> {
+    let sequence: &[u8] = match key {
+        Key::Enter | Key::KPEnter => b"\r",
+        Key::BackSpace => b"\x7f",
+        Key::Tab => b"\t",
+        Key::Escape => b"\x1b",
+        Key::Up => b"\x1b[A",
+        Key::Down => b"\x1b[B",
+        Key::Right => b"\x1b[C",
+        Key::Left => b"\x1b[D",
+        Key::Home => b"\x1b[H",
+        Key::End => b"\x1b[F",
+        Key::Insert => b"\x1b[2~",
+        Key::Delete => b"\x1b[3~",
+        Key::PageUp => b"\x1b[5~",
+        Key::PageDown => b"\x1b[6~",
+        _ if ctrl => {
+            //Control characters are the low five bits of the letter or symbol typed with Ctrl
+            return u8::try_from(key.bits())
+                .ok()
+                .filter(|key| (0x40..0x80).contains(key))
+                .map(|key| vec![key & 0x1f])
+        },
+        _ if text.is_empty() => return None,
+        _ => text.as_bytes(),
+    };
+
+    Some(sequence.to_vec())
+}
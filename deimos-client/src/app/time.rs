@@ -0,0 +1,93 @@
+//! Formatting of timestamps shown in the UI. Recent times are phrased relative to the current
+//! time and refreshed once a minute while the view showing them is visible, older times are shown
+//! as local dates with the full timestamp available as secondary text
+
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, TimeDelta, Utc};
+use tokio::sync::watch;
+
+use super::{DeimosStateHandle, DeimosView};
+
+/// Phrase the given time relative to `now` in the local time zone, such as "3 minutes ago" or
+/// "yesterday 14:02" for times within a week and the date for older times
+pub fn relative(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let elapsed = now - at;
+
+    //Times slightly in the future are from clocks that disagree with ours
+    if elapsed > -TimeDelta::minutes(1) && elapsed < TimeDelta::hours(1) {
+        return match elapsed.num_minutes() {
+            0 => String::from("just now"),
+            1 => String::from("1 minute ago"),
+            minutes => format!("{} minutes ago", minutes),
+        }
+    }
+
+    //Days are counted between local calendar dates so that a day shortened or lengthened by a
+    //daylight saving transition is still one day
+    let local = at.with_timezone(&Local);
+    let today = now.with_timezone(&Local).date_naive();
+    let clock = local.format("%H:%M");
+    match (today - local.date_naive()).num_days() {
+        0 => format!("today {}", clock),
+        1 => format!("yesterday {}", clock),
+        -1 => format!("tomorrow {}", clock),
+        -6..=-2 | 2..=6 => format!("{} {}", local.format("%A"), clock),
+        _ if local.year() == today.year() => local.format("%B %-d").to_string(),
+        _ => local.format("%B %-d %Y").to_string(),
+    }
+}
+
+/// Get the full local date and time of the given time, shown alongside relative times
+pub fn full(at: DateTime<Utc>) -> String {
+    at.with_timezone(&Local).format("%A %B %-d %Y, %H:%M:%S (UTC%:z)").to_string()
+}
+
+/// Clock used by tasks to redraw relative times in a single view.
+/// Ticks are aligned to the start of each minute so that all labels are redrawn in a single wake
+/// of the UI, and no timer runs while the view is hidden
+pub struct TimeRefresh {
+    view: DeimosView,
+    active: watch::Receiver<DeimosView>,
+}
+
+impl TimeRefresh {
+    /// Create a clock for labels in the given view, waiting for the state to be set
+    pub fn new(state: &DeimosStateHandle, view: DeimosView) -> Self {
+        Self {
+            view,
+            active: state.view.subscribe(),
+        }
+    }
+
+    /// Wait until relative times in the view should be redrawn: at the start of the next minute
+    /// while the view is shown, or as soon as the view is shown again after being hidden
+    pub async fn tick(&mut self) {
+        let mut hidden = false;
+        loop {
+            let shown = *self.active.borrow_and_update() == self.view;
+            let changed = match shown {
+                true if hidden => return,
+                true => tokio::select! {
+                    _ = tokio::time::sleep(Self::until_next_minute()) => return,
+                    result = self.active.changed() => result,
+                },
+                false => {
+                    hidden = true;
+                    self.active.changed().await
+                },
+            };
+
+            //The UI is closing, and no view will be shown again
+            if changed.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    fn until_next_minute() -> Duration {
+        const MINUTE_MS: i64 = 60 * 1000;
+        let into = Utc::now().timestamp_millis().rem_euclid(MINUTE_MS);
+        Duration::from_millis((MINUTE_MS - into) as u64)
+    }
+}
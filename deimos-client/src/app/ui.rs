@@ -0,0 +1,120 @@
+//! Helpers for safely accessing FLTK widgets from async tasks
+
+use std::{cell::Cell, marker::PhantomData};
+
+thread_local! {
+    /// Number of [UiLock]s currently held by this thread, used to detect re-entrant locking
+    static HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+/// RAII guard for the FLTK global lock that unlocks and wakes the event loop when dropped.
+/// The guard is neither [Send] nor [Sync], so holding it across an `.await` in a spawned task is
+/// a compile error instead of a UI deadlock.
+pub struct UiLock {
+    /// Set if the lock was acquired and must be released when the guard is dropped
+    locked: bool,
+    _unsend: PhantomData<*const ()>,
+}
+
+impl UiLock {
+    /// Acquire the FLTK global lock, blocking until it is available
+    pub fn acquire() -> Self {
+        let depth = HELD.with(|held| held.replace(held.get() + 1));
+        debug_assert_eq!(depth, 0, "FLTK lock acquired while already held by this thread");
+
+        let locked = match fltk::app::lock() {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Failed to acquire FLTK lock: {}", e);
+                false
+            }
+        };
+
+        Self {
+            locked,
+            _unsend: PhantomData,
+        }
+    }
+}
+
+impl Drop for UiLock {
+    fn drop(&mut self) {
+        HELD.with(|held| held.set(held.get().saturating_sub(1)));
+        if self.locked {
+            fltk::app::unlock();
+        }
+
+        fltk::app::awake();
+    }
+}
+
+/// Run the given closure while holding the FLTK global lock, waking the event loop afterwards
+pub fn with_lock<R>(f: impl FnOnce() -> R) -> R {
+    let _lock = UiLock::acquire();
+    f()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+
+    use fltk::prelude::WidgetExt;
+
+    use super::*;
+    use crate::context::NotifyMutation;
+
+    /// Drive a widget from a watched value the way views do, while another thread takes the lock
+    /// repeatedly in place of the event loop. The stand-in takes the raw lock, as it cannot drain
+    /// the messages sent to wake the event loop
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn watched_updates_run_under_lock() {
+        const LAST: u32 = 200;
+
+        let state = NotifyMutation::new(0u32);
+        let frame = fltk::frame::Frame::default();
+
+        let mut rx = state.subscribe();
+        let mut label = frame.clone();
+        let view = tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let value = *rx.borrow_and_update();
+                let _ui = UiLock::acquire();
+                HELD.with(|held| assert_eq!(held.get(), 1));
+                label.set_label(&value.to_string());
+                if value == LAST {
+                    break
+                }
+            }
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let event_loop = std::thread::spawn({
+            let stop = stop.clone();
+            move || while !stop.load(Ordering::Relaxed) {
+                fltk::app::lock().unwrap();
+                std::thread::yield_now();
+                fltk::app::unlock();
+            }
+        });
+
+        for value in 1..=LAST {
+            state.set(value);
+            tokio::task::yield_now().await;
+        }
+
+        let updated = tokio::time::timeout(Duration::from_secs(10), view).await;
+        stop.store(true, Ordering::Relaxed);
+        event_loop.join().unwrap();
+
+        updated.expect("UI update deadlocked").unwrap();
+        assert_eq!(frame.label(), LAST.to_string());
+        HELD.with(|held| assert_eq!(held.get(), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "already held")]
+    fn reentrant_lock_is_detected() {
+        let _ui = UiLock::acquire();
+        with_lock(|| ());
+    }
+}
@@ -2,7 +2,10 @@ use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 use deimosproto::auth::DeimosTokenKey;
-use tokio::sync::Notify;
+
+use crate::context::{error::ErrorRecord, NotifyMutation};
+
+pub use deimos_client_lib::{DeimosToken, DeimosTokenConvertError};
 
 #[cfg(windows)]
 mod dpapi;
@@ -21,27 +24,27 @@ pub struct PersistentToken {
 #[derive(Debug, Clone)]
 pub enum TokenStatus {
     None,
+    /// A token was requested and is awaiting approval. The request is owned by
+    /// [ContextClients](super::ContextClients) and is cancelled when a new request is made or the
+    /// connection changes
     Requested {
         user: String,
-        cancel: Arc<Notify>,
+        /// Token held before the request was made, which stays in use until the requested token
+        /// is received
+        previous: Option<DeimosToken>,
     },
     Denied {
         reason: String,
+        /// Status returned by the server, if the request was denied by the server rather than
+        /// failing locally
+        error: Option<Arc<ErrorRecord>>,
+        /// Token held before the request was made, which stays in use after the denial
+        previous: Option<DeimosToken>,
     },
     Token(DeimosToken),
 }
 
 
-/// An unprotected token as it is sent in the API
-#[derive(Clone,)]
-pub struct DeimosToken {
-    pub user: Arc<str>,
-    pub issued: DateTime<Utc>,
-    pub key: DeimosTokenKey,
-    base64: Arc<str>,
-}
-
-
 #[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub enum PersistentTokenKind {
     Plaintext,
@@ -50,13 +53,23 @@ pub enum PersistentTokenKind {
 }
 
 impl TokenStatus {
-    /// Get the token if one is stored
+    /// Minimum decoded length of a username that is assumed to be a pasted token key
+    const TOKEN_LIKE_BYTES: usize = 32;
+
+    /// Get the token if one is stored, including a token kept while a replacement is requested
     pub const fn token(&self) -> Option<&DeimosToken> {
         match self {
             Self::Token(ref tok) => Some(tok),
-            _ => None,
+            Self::Requested { ref previous, .. } | Self::Denied { ref previous, .. } => previous.as_ref(),
+            Self::None => None,
         }
     }
+
+    /// Check if the given username entered for a token request looks like a token key that was
+    /// pasted into the wrong field, as token keys are long base64 strings
+    pub fn username_looks_like_token(user: &str) -> bool {
+        DeimosTokenKey::from_base64(user.trim()).is_ok_and(|key| key.as_bytes().len() >= Self::TOKEN_LIKE_BYTES)
+    }
     
     /// Convert an optional token into a [TokenStatus]
     pub fn from_token(tok: Option<DeimosToken>) -> Self {
@@ -67,42 +80,10 @@ impl TokenStatus {
     }
 }
 
-impl DeimosToken {
-    pub fn new(user: Arc<str>, issued: DateTime<Utc>, key: DeimosTokenKey) -> Self {
-        Self {
-            user,
-            issued,
-            base64: key.to_base64().into(),
-            key,
-        }
+impl deimos_client_lib::TokenSource for NotifyMutation<TokenStatus> {
+    fn header(&self) -> Option<http::HeaderValue> {
+        self.read().token().and_then(DeimosToken::header)
     }
-
-    /// Get a chached base64 string representing the token
-    pub fn base64(&self) -> &str {
-        &self.base64
-    }
-    
-    /// Decode a protobuf containing a token
-    pub fn from_proto(proto: deimosproto::Token) -> Result<Self, DeimosTokenConvertError>  {
-        let user = proto.name.into();
-        let issued = DateTime::<Utc>::from_timestamp(proto.issued, 0).ok_or(DeimosTokenConvertError::DateTime)?;
-        let key = DeimosTokenKey::from_bytes(proto.key);
-        let base64 = key.to_base64().into();
-
-        Ok(Self {
-            user,
-            issued,
-            key,
-            base64,
-        })
-    }
-}
-
-
-#[derive(Debug, thiserror::Error)]
-pub enum DeimosTokenConvertError {
-    #[error("Out of range UNIX timestamp")]
-    DateTime,
 }
 
 impl PersistentToken {
@@ -134,25 +115,6 @@ impl PersistentToken {
     }
 }
 
-impl Drop for TokenStatus {
-    fn drop(&mut self) {
-        if let Self::Requested { user: _, cancel } = self {
-            cancel.notify_waiters();
-        }
-    }
-}
-
-impl std::fmt::Debug for DeimosToken {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f
-            .debug_struct("PersistentToken")
-            .field("user", &self.user)
-            .field("issued", &self.issued)
-            .field("token", &self.key)
-            .finish_non_exhaustive()
-    }
-}
-
 impl Default for PersistentTokenKind {
     fn default() -> Self {
         #[cfg(windows)]
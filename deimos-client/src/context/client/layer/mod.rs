@@ -1,3 +0,0 @@
-pub mod conn;
-pub mod cancel;
-pub mod auth;
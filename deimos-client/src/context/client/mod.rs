@@ -1,70 +1,81 @@
-use std::{sync::Arc, time::Duration};
+use std::{sync::{Arc, PoisonError}, time::Duration};
 
 use auth::{DeimosToken, PersistentToken, PersistentTokenKind, TokenStatus};
 use chrono::Utc;
-use deimosproto::client::DeimosServiceClient;
+use deimos_client_lib::{AuthClient, ConnectionObserver, DeimosClient, DeimosClientBuilder};
 use futures::StreamExt;
 use http::Uri;
-use layer::{auth::{AuthorizationLayer, AuthorizationService}, cancel::{CancelLayer, CancelService}, conn::{ConnectionTracker, ConnectionTrackerLayer}};
+use deimosproto::limit::MessageSizeLimit;
+use stream::StreamMode;
 use tokio::sync::{Mutex, Notify};
-use tonic::transport::{Channel, ClientTlsConfig};
 
-use super::NotifyMutation;
+use super::{confirm::CachedPodConfirmLevel, error::{ErrorLog, ErrorRecord}, queue::QueuedAction, server::PersistentServer, NotifyMutation};
+
+pub use deimos_client_lib::{ConnectionFailure, ConnectionFailureKind, ConnectionState as ContextConnectionState};
 
 pub mod auth;
-mod layer;
+pub mod stream;
 
 
 /// A client for the authorized pod control API
-pub type ApiClient = DeimosServiceClient<
-    AuthorizationService<
-        CancelService<
-            ConnectionTracker<Channel>
-        >
-    >
->;
-
-/// A client for the restricted authorization API, requests are multiplexed over the same channel
-/// as standard API requests
-pub type AuthClient = deimosproto::authclient::DeimosAuthorizationClient<CancelService<Channel>>;
-
-/// All state required for accessing the authorized pod control APIs
+pub type ApiClient = deimos_client_lib::ApiClient<NotifyMutation<TokenStatus>, ContextConnectionObserver>;
+
+/// All state required for accessing the authorized pod control APIs of a single server
 #[derive(Debug)]
 pub struct ContextClients {
     /// Current connection state, updated by middleware in the client stack
     pub conn: NotifyMutation<ContextConnectionState>,
+    /// Reason for the most recent failure to reach the server, cleared once a request succeeds
+    pub failure: NotifyMutation<Option<ConnectionFailure>>,
+    /// Set when status streams have been repeatedly terminated early, switching to polling if
+    /// the stream mode setting allows
+    pub streaming_unreliable: NotifyMutation<bool>,
     pub settings: NotifyMutation<ContextSettings>,
     pub token_protect: NotifyMutation<PersistentTokenKind>,
     pub token: NotifyMutation<TokenStatus>,
+    /// Most recent errors returned by any server, shared with the [Context](super::Context) so
+    /// that failures of both token requests and pod requests can be inspected
+    pub errors: NotifyMutation<ErrorLog>,
     /// Notifier semaphore used to stop ongoing API requests when reloading settings or token
     cancel: Arc<Notify>,
+    /// Notifier used to wake tasks waiting to reconnect when the user requests a retry
+    retry: Notify,
+    /// Cancellation notifier of the token request awaiting approval, if any
+    token_request: ActiveTokenRequest,
     /// Collection of all service clients - these are reset whenever the API has to be reconnected
     /// due to token or settings change
     clients: Mutex<Option<ClientCollection>>,
 }
 
-#[derive(Debug)]
-struct ClientCollection {
-    pods: ApiClient,
-    auth: AuthClient,
+type ClientCollection = DeimosClient<NotifyMutation<TokenStatus>, ContextConnectionObserver>;
+
+/// The token request awaiting approval, shared with the task receiving its response so that only
+/// the most recent request may change the token status
+type ActiveTokenRequest = Arc<std::sync::Mutex<Option<Arc<Notify>>>>;
+
+/// Reason that a token request failed, shown in place of the requested token
+struct TokenDenial {
+    reason: String,
+    /// Status returned by the server, if the request failed on the server
+    error: Option<Arc<ErrorRecord>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ContextConnectionState {
-    Unknown,
-    NoToken,
-    Connected,
-    Error,
+/// Records the connection state reported by the client stack for display in the UI
+#[derive(Debug, Clone)]
+pub struct ContextConnectionObserver {
+    conn: NotifyMutation<ContextConnectionState>,
+    failure: NotifyMutation<Option<ConnectionFailure>>,
 }
 
-/// Persistent state kept for the [Context]'s connection and authorization data
-#[derive(Debug, Default, Clone, serde::Deserialize, serde::Serialize)]
+/// Persistent state kept for the [Context]'s servers and its queued actions, loaded from a
+/// separate file for each section
+#[derive(Debug, Clone)]
 pub struct ContextPersistent {
-    pub settings: ContextSettings,
-
-    pub token_protect: PersistentTokenKind,
-    #[serde(default)]
-    pub token: Option<PersistentToken>,
+    /// Connection and authorization data of every server, never empty
+    pub servers: Vec<PersistentServer>,
+    /// Pod state changes queued while disconnected, owned by the [Context] rather than the
+    /// clients
+    pub queued: Vec<QueuedAction>,
 }
 
 /// Settings that may be adjusted by the user
@@ -74,16 +85,40 @@ pub struct ContextSettings {
     pub server_uri: Uri,
     pub request_timeout: Duration,
     pub connect_timeout: Duration,
+    /// Maximum size in bytes of a single gRPC message sent to or received from the server
+    #[serde(default = "ContextSettings::default_max_message_size")]
+    pub max_message_size: usize,
+    /// Method used to receive pod status updates
+    #[serde(default)]
+    pub stream_mode: StreamMode,
+    /// Time between queries of the pod list when polling for status updates
+    #[serde(default = "ContextSettings::default_poll_interval")]
+    pub poll_interval: Duration,
+    /// Maximum number of API requests that may be in flight at once, excluding streams
+    #[serde(default = "ContextSettings::default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Queue pod state changes made while the server cannot be reached and apply them once the
+    /// connection is restored. Off by default as the changes may be applied long after they were
+    /// requested
+    #[serde(default)]
+    pub queue_offline_actions: bool,
+    /// Least confirmation to ask for before stopping or restarting any pod on the server. Pods
+    /// that the server asks more confirmation for still use the server's level
+    #[serde(default)]
+    pub confirm_level: CachedPodConfirmLevel,
 }
 
 impl ContextClients {
-    /// Create a new client collection and attempt an API connection using the given settings
-    pub async fn new(persistent: ContextPersistent) -> Self {
+    /// Create a new client collection from the given settings and saved token, recording errors
+    /// in the given log. No API client is created until [connect](Self::connect) is called, so
+    /// that loading the collection never waits on the network
+    pub fn new(settings: ContextSettings, token_protect: PersistentTokenKind, token: Option<PersistentToken>, errors: NotifyMutation<ErrorLog>) -> Self {
         let conn = NotifyMutation::new(ContextConnectionState::Unknown);
+        let failure = NotifyMutation::new(None);
         let cancel = Arc::new(Notify::new());
         let clients = Mutex::new(None);
 
-        let token = persistent.token.and_then(|tok| match tok.unprotect() {
+        let token = token.and_then(|tok| match tok.unprotect() {
             Ok(tok) => Some(tok),
             Err(e) => {
                 tracing::error!("Failed to unprotect persistent token: {}", e);
@@ -91,160 +126,257 @@ impl ContextClients {
             }
         });
         
-        let token_protect = NotifyMutation::new(persistent.token_protect);
+        let token_protect = NotifyMutation::new(token_protect);
         let token = TokenStatus::from_token(token);
-        let settings = NotifyMutation::new(persistent.settings);
+        let settings = NotifyMutation::new(settings);
         let token = NotifyMutation::new(token);
 
-        let this = Self {
+        Self {
             conn,
+            failure,
+            streaming_unreliable: NotifyMutation::new(false),
             settings,
             token_protect,
             token,
+            errors,
             cancel,
+            retry: Notify::new(),
+            token_request: Default::default(),
             clients,
-        };
-
-        this.connect_api().await;
+        }
+    }
 
-        this
+    /// Create the API client from the loaded settings, after which connections are attempted
+    /// as requests are made
+    pub async fn connect(&self) {
+        self.connect_api().await;
     }
     
-    /// Request a new token with the given username from the server
+    /// Request a new token with the given username from the server, cancelling any request that
+    /// is already awaiting approval. A token that is already held stays in use until the new
+    /// token is received, and is kept if the request is denied or cancelled
     pub async fn request_token(&self, user: String) {
         self.cancel.notify_waiters();
         let Some(mut auth) = self.authapi().await else { return };
 
-        let cancel = Arc::new(Notify::new());
-        self.token.set(TokenStatus::Requested { user: user.clone(), cancel: cancel.clone() });
-        
+        let cancel = self.begin_token_request(user.clone());
         let request = deimosproto::TokenRequest {
             user,
             datetime: Utc::now().timestamp(),
         };
 
-        let mut stream = match auth.request_token(request).await {
+        let result = tokio::select! {
+            _ = cancel.notified() => return,
+            result = auth.request_token(request) => result,
+        };
+
+        let mut stream = match result {
             Ok(stream) => stream.into_inner(),
             Err(e) => {
-                tracing::warn!("Failed to request token from server: {}", e);
-                self.token.set(
-                    TokenStatus::Denied { reason: e.message().to_owned() }
-                );
+                let error = self.errors.record("Failed to request token from server", &e);
+                let denial = TokenDenial { reason: e.message().to_owned(), error: Some(error) };
+                Self::resolve_token_request(&self.token_request, &self.token, &cancel, Err(denial));
                 return;
             }
         };
 
+        let active = self.token_request.clone();
         let own_token = self.token.clone();
+        let errors = self.errors.clone();
 
         tokio::task::spawn(async move {
+            //The status was already set by whatever cancelled the request
             let next = tokio::select! {
                 _ = cancel.notified() => {
-                    own_token.set(TokenStatus::None);
+                    tracing::trace!("Token request task cancelled");
                     return
                 },
                 result = stream.next() => result,
             };
 
-            let reason = match next {
-                Some(Ok(token)) => match DeimosToken::from_proto(token) {
-                    Ok(token) => {
-                        own_token.set(TokenStatus::Token(token));
-                        return
-                    },
-                    Err(e) => format!("Failed to decode received token: {}", e),
-                }
-                Some(Err(e)) => format!("Failed to receive token from server: {}", e.message()),
-                None => String::from("Token request stream closed before token was received"),
+            let outcome = match next {
+                Some(Ok(token)) => DeimosToken::from_proto(token)
+                    .map_err(|e| TokenDenial { reason: format!("Failed to decode received token: {}", e), error: None }),
+                Some(Err(e)) => Err(TokenDenial {
+                    reason: format!("Failed to receive token from server: {}", e.message()),
+                    error: Some(errors.record("Failed to receive token from server", &e)),
+                }),
+                None => Err(TokenDenial {
+                    reason: String::from("Token request stream closed before token was received"),
+                    error: None,
+                }),
             };
 
-            own_token.set(TokenStatus::Denied { reason });
+            Self::resolve_token_request(&active, &own_token, &cancel, outcome);
         });
     }
+
+    /// Make a new token request the active request, cancelling the previous one. The token held
+    /// before the request is kept in use until the request is resolved
+    fn begin_token_request(&self, user: String) -> Arc<Notify> {
+        let cancel = Arc::new(Notify::new());
+        let mut active = self.token_request.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = active.replace(cancel.clone()) {
+            tracing::trace!("Cancelling previous token request");
+            previous.notify_one();
+        }
+
+        let previous = self.token.read().token().cloned();
+        self.token.set(TokenStatus::Requested { user, previous });
+        cancel
+    }
+
+    /// Cancel the token request awaiting approval if there is one, showing the given reason in
+    /// its place
+    fn cancel_token_request(&self, reason: &str) {
+        let mut active = self.token_request.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(request) = active.take() {
+            tracing::info!("{}", reason);
+            request.notify_one();
+            let previous = self.token.read().token().cloned();
+            self.token.set(TokenStatus::Denied { reason: reason.to_owned(), error: None, previous });
+        }
+    }
+
+    /// Set the token status to the outcome of the given request if it is still the active
+    /// request, keeping the token held before the request if it was denied. Outcomes of requests
+    /// that were cancelled or replaced by a newer request are discarded
+    fn resolve_token_request(active: &ActiveTokenRequest, token: &NotifyMutation<TokenStatus>, request: &Arc<Notify>, outcome: Result<DeimosToken, TokenDenial>) {
+        let mut active = active.lock().unwrap_or_else(PoisonError::into_inner);
+        match active.as_ref().is_some_and(|current| Arc::ptr_eq(current, request)) {
+            true => {
+                *active = None;
+                let status = match outcome {
+                    Ok(received) => {
+                        if token.read().token().is_some() {
+                            tracing::info!("Replaced existing token with newly approved token");
+                        }
+
+                        TokenStatus::Token(received)
+                    },
+                    Err(TokenDenial { reason, error }) => {
+                        let previous = token.read().token().cloned();
+                        TokenStatus::Denied { reason, error, previous }
+                    },
+                };
+
+                token.set(status);
+            },
+            false => tracing::trace!("Discarding outcome of cancelled token request"),
+        }
+    }
     
-    /// Get the authorized pods API client if one is available
-    pub async fn podapi(&self) -> Option<tokio::sync::MappedMutexGuard<ApiClient>> {
-        tokio::sync::MutexGuard::try_map(
-            self.clients.lock().await,
-            |opt| opt.as_mut().map(|c| &mut c.pods)
-        ).ok()
+    /// Get the authorized pods API client if one is available.
+    /// The client is cloned so that the lock is not held while requests are in flight, allowing
+    /// requests to be made concurrently over the same channel
+    pub async fn podapi(&self) -> Option<ApiClient> {
+        self.clients.lock().await.as_mut().map(|clients| clients.api().clone())
     }
 
     async fn authapi(&self) -> Option<tokio::sync::MappedMutexGuard<AuthClient>> {
         tokio::sync::MutexGuard::try_map(
             self.clients.lock().await,
-            |opt| opt.as_mut().map(|c| &mut c.auth)
+            |opt| opt.as_mut().map(ClientCollection::auth)
         ).ok()
     }
 
     /// Reload the API connection using the given context settings
     pub async fn reload(&self, settings: ContextSettings) {
+        self.cancel_token_request("Token request was cancelled because the connection settings changed");
         self.settings.set(settings.clone());
         self.connect_api().await;
     }
 
+    /// Recreate the API connection and wake tasks waiting to reconnect so that they retry
+    /// immediately instead of waiting for their timeout
+    pub async fn retry(&self) {
+        self.conn.set(ContextConnectionState::Unknown);
+        self.cancel_token_request("Token request was cancelled because the connection was retried");
+        self.connect_api().await;
+        self.retry.notify_waiters();
+    }
+
+    /// Wait until the user requests a reconnect with [Self::retry]
+    pub async fn retry_requested(&self) {
+        self.retry.notified().await
+    }
+
+    /// Check if pod status updates should currently be received by polling instead of streaming
+    pub fn polling(&self) -> bool {
+        match self.settings.read().stream_mode {
+            StreamMode::Auto => *self.streaming_unreliable.read(),
+            StreamMode::Stream => false,
+            StreamMode::Poll => true,
+        }
+    }
+
     /// Create a new gRPC client with the given connection settings, used to refresh the connection
     /// as settings are updated
     async fn connect_api(&self) {
-        let channel = {
+        let builder = {
             let settings = self.settings.read();
-            Channel::builder(settings.server_uri.clone())
+            DeimosClientBuilder::new(settings.server_uri.clone())
                 .connect_timeout(settings.connect_timeout)
-                .timeout(settings.request_timeout)
-                .tls_config(ClientTlsConfig::new().with_webpki_roots())
-                .ok()
+                .request_timeout(settings.request_timeout)
+                .message_size_limit(settings.message_size_limit())
         };
 
-        let Some(channel) = channel else { return };
+        let observer = ContextConnectionObserver {
+            conn: self.conn.clone(),
+            failure: self.failure.clone(),
+        };
 
-        self.cancel.notify_waiters();
-        let mut lock = self.clients.lock().await;
-        
-        let channel = channel.connect_lazy();
-        let pods = DeimosServiceClient::new(
-            tower::ServiceBuilder::new()
-                .layer(AuthorizationLayer::new(self.token.clone()))
-                .layer(CancelLayer::new(self.cancel.clone()))
-                .layer(ConnectionTrackerLayer::new(self.conn.clone()))
-                .service(channel.clone())
-        );
-
-        let auth = AuthClient::new(
-            tower::ServiceBuilder::new()
-                .layer(CancelLayer::new(self.cancel.clone()))
-                .service(channel),
-        );
-        
-        *lock = Some(
-            ClientCollection {
-                pods,
-                auth,
+        let client = builder
+            .cancel(self.cancel.clone())
+            .token_source(self.token.clone())
+            .observer(observer)
+            .build();
+
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::error!("Failed to create API client: {}", e);
+                return
             }
-        );
+        };
+
+        self.cancel.notify_waiters();
+        *self.clients.lock().await = Some(client);
     }
     
-    /// Get a copy of the current state that can be serialized to a save file
-    pub fn persistent(&self) -> ContextPersistent {
-        let token_protect = *self.token_protect.read();
-        let settings = self.settings.read().clone();
-
-        let token = self
+    /// Protect the current token so that it can be saved, returning an error without a token if
+    /// it could not be protected
+    pub fn persistent_token(&self) -> Result<Option<PersistentToken>, String> {
+        self
             .token
             .read()
             .token()
             .cloned()
-            .and_then(|tok| match PersistentToken::protect(*self.token_protect.read(), tok) {
-            Ok(tok) => Some(tok),
-            Err(e) => {
-                tracing::error!("Failed to protect persistent token: {}", e);
-                None
-            }
-        });
+            .map(|tok| PersistentToken::protect(*self.token_protect.read(), tok))
+            .transpose()
+    }
+}
 
-        ContextPersistent {
-            settings,
-            token_protect,
-            token,
+impl Default for ContextPersistent {
+    fn default() -> Self {
+        Self {
+            servers: vec![PersistentServer::default()],
+            queued: Vec::new(),
+        }
+    }
+}
+
+impl ConnectionObserver for ContextConnectionObserver {
+    fn observe(&self, state: ContextConnectionState, failure: Option<ConnectionFailure>) {
+        match (state, failure) {
+            (ContextConnectionState::Connected, _) => if self.failure.read().is_some() {
+                self.failure.set(None);
+            },
+            (_, Some(failure)) => self.failure.set(Some(failure)),
+            (_, None) => {},
         }
+
+        self.conn.set(state);
     }
 }
 
@@ -254,6 +386,132 @@ impl Default for ContextSettings {
             server_uri: Uri::default(),
             request_timeout: Duration::from_secs(30),
             connect_timeout: Duration::from_secs(60),
+            max_message_size: Self::default_max_message_size(),
+            stream_mode: StreamMode::default(),
+            poll_interval: Self::default_poll_interval(),
+            max_concurrent_requests: Self::default_max_concurrent_requests(),
+            queue_offline_actions: false,
+            confirm_level: CachedPodConfirmLevel::default(),
+        }
+    }
+}
+
+impl ContextSettings {
+    /// Get the default maximum gRPC message size, matching the server's default
+    pub const fn default_max_message_size() -> usize {
+        MessageSizeLimit::DEFAULT.bytes()
+    }
+
+    pub const fn default_poll_interval() -> Duration {
+        Duration::from_secs(15)
+    }
+
+    pub const fn default_max_concurrent_requests() -> usize {
+        4
+    }
+
+    /// Get the configured maximum gRPC message size, raised to the smallest allowed limit
+    pub const fn message_size_limit(&self) -> MessageSizeLimit {
+        MessageSizeLimit::new(self.max_message_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use deimosproto::auth::DeimosTokenKey;
+
+    use super::*;
+
+    fn token(user: &str, key: u8) -> DeimosToken {
+        DeimosToken::new(Arc::from(user), Utc::now(), DeimosTokenKey::from_bytes(vec![key; 32]))
+    }
+
+    fn clients(held: Option<DeimosToken>) -> ContextClients {
+        let clients = ContextClients::new(ContextSettings::default(), PersistentTokenKind::default(), None, NotifyMutation::default());
+        clients.token.set(TokenStatus::from_token(held));
+        clients
+    }
+
+    fn held(clients: &ContextClients) -> Option<String> {
+        clients.token.read().token().map(|token| token.base64().to_owned())
+    }
+
+    fn denial(reason: &str) -> Result<DeimosToken, TokenDenial> {
+        Err(TokenDenial { reason: reason.to_owned(), error: None })
+    }
+
+    #[test]
+    fn old_token_is_kept_until_replaced() {
+        let (old, new) = (token("user", 1), token("user", 2));
+        let clients = clients(Some(old.clone()));
+
+        let request = clients.begin_token_request("user".to_owned());
+        assert!(matches!(*clients.token.read(), TokenStatus::Requested { .. }));
+        assert_eq!(held(&clients).as_deref(), Some(old.base64()));
+        assert_eq!(deimos_client_lib::TokenSource::header(&clients.token), old.header());
+
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &request, Ok(new.clone()));
+        assert!(matches!(*clients.token.read(), TokenStatus::Token(_)));
+        assert_eq!(held(&clients).as_deref(), Some(new.base64()));
+    }
+
+    #[test]
+    fn denied_replacement_rolls_back() {
+        let old = token("user", 1);
+        let clients = clients(Some(old.clone()));
+
+        let request = clients.begin_token_request("user".to_owned());
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &request, denial("denied"));
+        match *clients.token.read() {
+            TokenStatus::Denied { ref reason, ref previous, .. } => {
+                assert_eq!(reason, "denied");
+                assert_eq!(previous.as_ref().map(DeimosToken::base64), Some(old.base64()));
+            },
+            ref other => panic!("expected denial, got {:?}", other),
         }
+
+        //A request made after the denial still replaces the token that was kept
+        let request = clients.begin_token_request("user".to_owned());
+        assert_eq!(held(&clients).as_deref(), Some(old.base64()));
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &request, denial("denied again"));
+        assert_eq!(held(&clients).as_deref(), Some(old.base64()));
+    }
+
+    #[test]
+    fn cancelled_request_keeps_old_token() {
+        let (old, new) = (token("user", 1), token("user", 2));
+        let clients = clients(Some(old.clone()));
+
+        let request = clients.begin_token_request("user".to_owned());
+        clients.cancel_token_request("cancelled");
+        assert!(matches!(*clients.token.read(), TokenStatus::Denied { .. }));
+        assert_eq!(held(&clients).as_deref(), Some(old.base64()));
+
+        //Tokens received for a cancelled request are discarded
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &request, Ok(new));
+        assert_eq!(held(&clients).as_deref(), Some(old.base64()));
+    }
+
+    #[test]
+    fn superseded_request_is_discarded() {
+        let (first, second) = (token("first", 1), token("second", 2));
+        let clients = clients(None);
+
+        let superseded = clients.begin_token_request("first".to_owned());
+        let request = clients.begin_token_request("second".to_owned());
+
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &superseded, Ok(first));
+        assert!(matches!(*clients.token.read(), TokenStatus::Requested { ref user, previous: None } if user == "second"));
+
+        ContextClients::resolve_token_request(&clients.token_request, &clients.token, &request, Ok(second.clone()));
+        assert_eq!(held(&clients).as_deref(), Some(second.base64()));
+    }
+
+    #[test]
+    fn pasted_token_is_detected_as_username() {
+        assert!(TokenStatus::username_looks_like_token(token("user", 7).base64()));
+        assert!(TokenStatus::username_looks_like_token(&format!(" {}\n", token("user", 7).base64())));
+        assert!(!TokenStatus::username_looks_like_token("alice"));
+        assert!(!TokenStatus::username_looks_like_token(&DeimosTokenKey::from_bytes(vec![1; 8]).to_base64()));
     }
 }
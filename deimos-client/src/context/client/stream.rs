@@ -0,0 +1,162 @@
+use std::{collections::VecDeque, fmt, time::{Duration, Instant}};
+
+/// Method used to receive pod status updates from the server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StreamMode {
+    /// Hold a status stream open, falling back to polling if the network repeatedly terminates
+    /// streams early
+    #[default]
+    Auto,
+    /// Always hold a status stream open
+    Stream,
+    /// Always poll the pod list at the configured interval
+    Poll,
+}
+
+/// Record of recent status streams that were terminated before becoming established, used to
+/// detect proxies that kill long-lived HTTP/2 streams
+#[derive(Debug, Default)]
+pub struct StreamHealth {
+    terminations: VecDeque<Instant>,
+}
+
+impl StreamHealth {
+    /// Time that a stream must stay open for before it is considered established
+    pub const ESTABLISHED: Duration = Duration::from_secs(120);
+    /// Number of premature terminations within [Self::WINDOW] after which streaming is
+    /// considered unreliable
+    const LIMIT: usize = 3;
+    const WINDOW: Duration = Duration::from_secs(600);
+    /// Time to spend polling before attempting to open a stream again
+    pub const PROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+    /// Record a stream that terminated at the given time before it became established,
+    /// returning `true` if streaming should be considered unreliable
+    pub fn terminated(&mut self, now: Instant) -> bool {
+        self.terminations.push_back(now);
+        while self.terminations.front().is_some_and(|at| now.duration_since(*at) > Self::WINDOW) {
+            self.terminations.pop_front();
+        }
+
+        self.terminations.len() >= Self::LIMIT
+    }
+
+    /// Record that a stream stayed open long enough to be established, forgetting all previous
+    /// terminations
+    pub fn established(&mut self) {
+        self.terminations.clear();
+    }
+}
+
+/// Epoch and sequence number of the last status notification received, used to detect
+/// notifications that were dropped before reaching the client
+#[derive(Debug, Default)]
+pub struct StreamSequence {
+    /// Epoch of the daemon run that sent the last notification, kept across streams
+    epoch: Option<u64>,
+    /// Sequence number of the last notification received on the current stream
+    last: Option<u64>,
+}
+
+/// Notifications that were missed before the one just received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamGap {
+    /// The given number of notifications were dropped
+    Missed(u64),
+    /// The daemon restarted since the last notification was received
+    Restarted,
+}
+
+impl StreamSequence {
+    /// Forget the sequence number of the previous stream, as every stream is numbered from 0
+    pub fn opened(&mut self) {
+        self.last = None;
+    }
+
+    /// Record a received notification, returning the notifications missed before it if any.
+    /// Servers that do not number notifications always send 0 and never report a gap
+    pub fn received(&mut self, epoch: u64, sequence: u64) -> Option<StreamGap> {
+        let restarted = self.epoch.replace(epoch).is_some_and(|previous| previous != epoch);
+        let expected = self.last.replace(sequence).map_or(0, |last| last + 1);
+
+        if restarted {
+            return Some(StreamGap::Restarted)
+        }
+
+        match sequence.saturating_sub(expected) {
+            0 => None,
+            missed => Some(StreamGap::Missed(missed)),
+        }
+    }
+}
+
+impl StreamMode {
+    pub const ALL: [Self; 3] = [Self::Auto, Self::Stream, Self::Poll];
+}
+
+impl fmt::Display for StreamMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "Automatic",
+            Self::Stream => "Always stream",
+            Self::Poll => "Always poll",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repeated_terminations_are_unreliable() {
+        let mut health = StreamHealth::default();
+        let start = Instant::now();
+        assert!(!health.terminated(start));
+        assert!(!health.terminated(start + Duration::from_secs(10)));
+        assert!(health.terminated(start + Duration::from_secs(20)));
+
+        health.established();
+        assert!(!health.terminated(start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn old_terminations_are_forgotten() {
+        let mut health = StreamHealth::default();
+        let start = Instant::now();
+        assert!(!health.terminated(start));
+        assert!(!health.terminated(start + StreamHealth::WINDOW / 2));
+        assert!(!health.terminated(start + StreamHealth::WINDOW + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn dropped_notifications_are_counted() {
+        let mut sequence = StreamSequence::default();
+        assert_eq!(sequence.received(1, 0), None);
+        assert_eq!(sequence.received(1, 1), None);
+        assert_eq!(sequence.received(1, 4), Some(StreamGap::Missed(2)));
+
+        //Every stream is numbered from 0
+        sequence.opened();
+        assert_eq!(sequence.received(1, 0), None);
+        sequence.opened();
+        assert_eq!(sequence.received(1, 2), Some(StreamGap::Missed(2)));
+    }
+
+    #[test]
+    fn new_epoch_is_a_restart() {
+        let mut sequence = StreamSequence::default();
+        assert_eq!(sequence.received(1, 0), None);
+        sequence.opened();
+        assert_eq!(sequence.received(2, 0), Some(StreamGap::Restarted));
+        assert_eq!(sequence.received(2, 1), None);
+    }
+
+    #[test]
+    fn unnumbered_servers_never_report_gaps() {
+        let mut sequence = StreamSequence::default();
+        for _ in 0..3 {
+            assert_eq!(sequence.received(0, 0), None);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Confirmation asked of the user before a pod is stopped or restarted. Servers hint the level
+//! each pod needs from its config, and each server's settings give the level the user wants for
+//! all of its pods. The stricter of the two is used, so the client may ask for more confirmation
+//! than the server hints but never less
+
+use std::fmt;
+
+use super::{pod::CachedPod, Context};
+
+/// Confirmation asked for before a pod is stopped or restarted, ordered from least to most strict
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub enum CachedPodConfirmLevel {
+    /// The action is made immediately
+    #[default]
+    None,
+    /// The user must accept a dialog before the action is made
+    Confirm,
+    /// The user must type the pod's ID before the action is made
+    TypeToConfirm,
+}
+
+impl CachedPodConfirmLevel {
+    /// Every level, from least to most strict
+    pub const ALL: [Self; 3] = [Self::None, Self::Confirm, Self::TypeToConfirm];
+
+    /// Get the level hinted by a server
+    pub fn from_proto(proto: deimosproto::PodConfirmLevel) -> Self {
+        match proto {
+            deimosproto::PodConfirmLevel::None => Self::None,
+            deimosproto::PodConfirmLevel::Confirm => Self::Confirm,
+            deimosproto::PodConfirmLevel::Typed => Self::TypeToConfirm,
+        }
+    }
+
+    /// Get the level to ask for given the server's hint for a pod and the local setting, which
+    /// may escalate the hint but never downgrade it
+    pub fn resolve(hint: Self, local: Self) -> Self {
+        hint.max(local)
+    }
+
+    /// Check if the text typed by the user confirms an action on the pod with the given ID,
+    /// ignoring surrounding whitespace
+    pub fn matches_typed(typed: &str, id: &str) -> bool {
+        typed.trim() == id
+    }
+}
+
+impl Context {
+    /// Get the confirmation to ask for before stopping or restarting the given pod
+    pub fn confirm_level(&self, pod: &CachedPod) -> CachedPodConfirmLevel {
+        let local = self
+            .server_of(pod)
+            .map(|server| server.clients.settings.read().confirm_level)
+            .unwrap_or_default();
+
+        CachedPodConfirmLevel::resolve(*pod.data.confirm_level.read(), local)
+    }
+}
+
+impl fmt::Display for CachedPodConfirmLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "Never",
+            Self::Confirm => "Ask",
+            Self::TypeToConfirm => "Type Pod ID",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_uses_the_stricter_level() {
+        use CachedPodConfirmLevel::*;
+        let expected = [
+            //Hint: None, Confirm, TypeToConfirm for each local setting
+            [None, Confirm, TypeToConfirm],
+            [Confirm, Confirm, TypeToConfirm],
+            [TypeToConfirm, TypeToConfirm, TypeToConfirm],
+        ];
+
+        for (local, row) in CachedPodConfirmLevel::ALL.into_iter().zip(expected) {
+            for (hint, level) in CachedPodConfirmLevel::ALL.into_iter().zip(row) {
+                assert_eq!(CachedPodConfirmLevel::resolve(hint, local), level, "hint {:?}, local {:?}", hint, local);
+            }
+        }
+    }
+
+    #[test]
+    fn typed_id_ignores_surrounding_whitespace() {
+        assert!(CachedPodConfirmLevel::matches_typed("web", "web"));
+        assert!(CachedPodConfirmLevel::matches_typed("  web\n", "web"));
+        assert!(!CachedPodConfirmLevel::matches_typed("we b", "web"));
+        assert!(!CachedPodConfirmLevel::matches_typed("Web", "web"));
+        assert!(!CachedPodConfirmLevel::matches_typed("", "web"));
+        assert!(!CachedPodConfirmLevel::matches_typed("web2", "web"));
+    }
+
+    #[test]
+    fn hints_map_to_levels() {
+        assert_eq!(CachedPodConfirmLevel::from_proto(deimosproto::PodConfirmLevel::None), CachedPodConfirmLevel::None);
+        assert_eq!(CachedPodConfirmLevel::from_proto(deimosproto::PodConfirmLevel::Confirm), CachedPodConfirmLevel::Confirm);
+        assert_eq!(CachedPodConfirmLevel::from_proto(deimosproto::PodConfirmLevel::Typed), CachedPodConfirmLevel::TypeToConfirm);
+    }
+}
@@ -0,0 +1,154 @@
+//! Ordering and concurrency limits for outgoing API requests. Mutations of a single pod are made
+//! in the order they were requested, and pod list synchronizations discard the state of pods that
+//! were mutated while the pod list was being queried so that stale responses cannot overwrite
+//! newer state
+
+use std::{collections::HashMap, future::Future, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, PoisonError}};
+
+use super::{pod::PodRef, NotifyMutation};
+
+/// Coordinates all outgoing API requests made by the [Context](super::Context)
+#[derive(Debug, Default)]
+pub struct RequestCoordinator {
+    /// Number of requests currently in flight, never exceeding the configured limit
+    pub in_flight: NotifyMutation<usize>,
+    /// Number of mutations that are queued or in flight across all pods
+    mutations: NotifyMutation<usize>,
+    queues: std::sync::Mutex<HashMap<PodRef, Arc<PodRequestQueue>>>,
+}
+
+/// Mutation state of a single pod
+#[derive(Debug, Default)]
+struct PodRequestQueue {
+    /// Held while a mutation is in flight, the lock is fair so mutations are made in the order
+    /// they were queued
+    lock: tokio::sync::Mutex<()>,
+    /// Incremented whenever a mutation of the pod is queued
+    generation: AtomicU64,
+    /// Number of mutations of the pod that are queued or in flight
+    pending: AtomicUsize,
+}
+
+/// Mutation generation of every pod at the time a pod list query was made, or `None` if a
+/// mutation was pending for the pod at that time
+#[derive(Debug)]
+pub struct RequestSnapshot(HashMap<PodRef, Option<u64>>);
+
+/// Decrements a counter when dropped, so that cancelled requests are still accounted for
+struct CountGuard<'a>(&'a NotifyMutation<usize>);
+
+/// Marks a pod's mutation as no longer pending when dropped
+struct PendingGuard<'a> {
+    queue: &'a PodRequestQueue,
+    _count: CountGuard<'a>,
+}
+
+impl RequestCoordinator {
+    /// Run a request that does not mutate any pod once fewer than the given number of requests
+    /// are in flight
+    pub async fn request<F: Future>(&self, limit: usize, request: F) -> F::Output {
+        let _permit = self.permit(limit).await;
+        request.await
+    }
+
+    /// Run a request mutating the given pod after all previously queued mutations of the pod
+    /// have completed and fewer than the given number of requests are in flight
+    pub async fn mutate<F: Future>(&self, pod: &PodRef, limit: usize, request: F) -> F::Output {
+        let queue = self.queue(pod);
+
+        //Marked pending before the generation changes so that a concurrent snapshot observes
+        //either the pending mutation or the new generation
+        queue.pending.fetch_add(1, Ordering::AcqRel);
+        queue.generation.fetch_add(1, Ordering::AcqRel);
+        self.mutations.modify(|count| *count += 1);
+        let _pending = PendingGuard { queue: &queue, _count: CountGuard(&self.mutations) };
+
+        let _order = queue.lock.lock().await;
+        let _permit = self.permit(limit).await;
+        request.await
+    }
+
+    /// Wait until no mutation of any pod is queued or in flight
+    pub async fn wait_mutations(&self) {
+        let mut sub = self.mutations.subscribe();
+        let _ = sub.wait_for(|count| *count == 0).await;
+    }
+
+    /// Record the mutation state of all pods before querying the pod list
+    pub fn snapshot(&self) -> RequestSnapshot {
+        let queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        RequestSnapshot(
+            queues
+                .iter()
+                .map(|(pod, queue)| {
+                    let generation = queue.generation.load(Ordering::Acquire);
+                    let pending = queue.pending.load(Ordering::Acquire) != 0;
+
+                    (pod.clone(), (!pending).then_some(generation))
+                })
+                .collect()
+        )
+    }
+
+    /// Check if the given pod was mutated, or had a mutation pending, since the given snapshot
+    /// was taken, in which case pod list data received after the snapshot is stale
+    pub fn is_stale(&self, snapshot: &RequestSnapshot, pod: &PodRef) -> bool {
+        let current = self
+            .queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(pod)
+            .map(|queue| queue.generation.load(Ordering::Acquire));
+
+        match snapshot.0.get(pod) {
+            Some(Some(generation)) => current != Some(*generation),
+            Some(None) => true,
+            None => current.is_some(),
+        }
+    }
+
+    /// Get the mutation queue of the given pod, creating it if it does not exist
+    fn queue(&self, pod: &PodRef) -> Arc<PodRequestQueue> {
+        self.queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(pod.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Wait until fewer than the given number of requests are in flight and reserve a slot
+    async fn permit(&self, limit: usize) -> CountGuard<'_> {
+        let limit = limit.max(1);
+        let mut sub = self.in_flight.subscribe();
+
+        loop {
+            let reserved = self.in_flight.0.send_if_modified(|count| {
+                let free = *count < limit;
+                if free {
+                    *count += 1;
+                }
+
+                free
+            });
+
+            if reserved {
+                return CountGuard(&self.in_flight)
+            }
+
+            let _ = sub.changed().await;
+        }
+    }
+}
+
+impl Drop for CountGuard<'_> {
+    fn drop(&mut self) {
+        self.0.modify(|count| *count = count.saturating_sub(1));
+    }
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,217 @@
+//! Selection of the directory that context state and cached pods are saved to. The platform's
+//! cache directory is used unless it cannot be written to, in which case the config directory
+//! and then a directory chosen by the user are tried before continuing without saving anything
+
+use std::{future::Future, path::{Path, PathBuf}};
+
+use super::Context;
+
+/// Directory chosen for saving context state, or the reason that none was chosen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheDirChoice {
+    /// Save to the given directory, which is checked before it is used
+    Directory(PathBuf),
+    /// Continue without saving settings, tokens, or cached pods
+    Ephemeral,
+    /// Exit the application without loading
+    Quit,
+}
+
+/// A directory that could not be used to save context state
+#[derive(Debug, thiserror::Error)]
+#[error("Cannot write to {}: {}", path.display(), err)]
+pub struct CacheDirProbeError {
+    pub path: PathBuf,
+    #[source]
+    pub err: std::io::Error,
+}
+
+impl Context {
+    /// File in the config directory naming a cache directory chosen by the user
+    pub const CACHE_OVERRIDE_FILE_NAME: &str = "cache-dir";
+
+    /// Select a writable directory to save context state to, trying a directory previously
+    /// chosen by the user, the platform's cache directory, and the config directory in order.
+    /// If none of them can be written to, `choose` is called with every failure until it returns
+    /// a writable directory or gives up, and a chosen directory is remembered for later launches
+    pub(super) async fn select_cache_dir<F, C>(choose: C) -> CacheDirChoice
+    where
+        C: FnMut(&[CacheDirProbeError]) -> F,
+        F: Future<Output = CacheDirChoice>,
+    {
+        let defaults = [
+            Self::cache_dir_override().await,
+            Some(dirs::cache_dir().map(|dir| dir.join(Self::CACHE_DIR_NAME)).unwrap_or_else(|| PathBuf::from("./deimos-cache"))),
+            dirs::config_dir().map(|dir| dir.join(Self::CACHE_DIR_NAME)),
+        ];
+
+        let (choice, chosen) = select_writable(defaults.into_iter().flatten(), choose).await;
+        if let (CacheDirChoice::Directory(ref dir), true) = (&choice, chosen) {
+            Self::save_cache_dir_override(dir).await;
+        }
+
+        choice
+    }
+
+    /// Get the path of the file naming a cache directory chosen by the user
+    fn cache_dir_override_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(Self::CACHE_DIR_NAME).join(Self::CACHE_OVERRIDE_FILE_NAME))
+    }
+
+    /// Read the cache directory chosen by the user in a previous launch, if any
+    async fn cache_dir_override() -> Option<PathBuf> {
+        let path = Self::cache_dir_override_path()?;
+        match tokio::fs::read_to_string(&path).await {
+            Ok(dir) if !dir.trim().is_empty() => Some(PathBuf::from(dir.trim())),
+            Ok(_) => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                tracing::warn!("Failed to read chosen cache directory from {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Remember the given cache directory chosen by the user for later launches
+    async fn save_cache_dir_override(dir: &Path) {
+        let Some(path) = Self::cache_dir_override_path() else {
+            tracing::warn!("No config directory to remember the chosen cache directory in");
+            return
+        };
+
+        if let Some(parent) = path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                tracing::warn!("Failed to create config directory {}: {}", parent.display(), e);
+            }
+        }
+
+        if let Err(e) = tokio::fs::write(&path, dir.as_os_str().as_encoded_bytes()).await {
+            tracing::warn!("Failed to remember chosen cache directory in {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Use the first writable directory of the given candidates, or call `choose` with every failure
+/// until it returns a writable directory or gives up. Returns the choice, and whether the
+/// directory was chosen by `choose`
+async fn select_writable<F, C>(candidates: impl IntoIterator<Item = PathBuf>, mut choose: C) -> (CacheDirChoice, bool)
+where
+    C: FnMut(&[CacheDirProbeError]) -> F,
+    F: Future<Output = CacheDirChoice>,
+{
+    let mut failures = Vec::new();
+    for dir in candidates {
+        match probe_writable(&dir).await {
+            Ok(()) => {
+                if !failures.is_empty() {
+                    tracing::warn!("Saving application state to fallback directory {}", dir.display());
+                }
+
+                return (CacheDirChoice::Directory(dir), false)
+            },
+            Err(e) => {
+                tracing::warn!("{}", e);
+                failures.push(e);
+            },
+        }
+    }
+
+    loop {
+        match choose(&failures).await {
+            CacheDirChoice::Directory(dir) => match probe_writable(&dir).await {
+                Ok(()) => return (CacheDirChoice::Directory(dir), true),
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    failures.push(e);
+                },
+            },
+            CacheDirChoice::Ephemeral => {
+                tracing::warn!("No writable directory was chosen, settings and tokens will not be saved");
+                return (CacheDirChoice::Ephemeral, false)
+            },
+            CacheDirChoice::Quit => return (CacheDirChoice::Quit, false),
+        }
+    }
+}
+
+/// Check that files can be created in the given directory, creating it if it does not exist
+async fn probe_writable(dir: &Path) -> Result<(), CacheDirProbeError> {
+    let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+    let result = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await
+    }.await;
+
+    result.map_err(|err| CacheDirProbeError { path: dir.to_owned(), err })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Get a directory that cannot be created, as its parent is a regular file
+    fn unwritable(root: &Path, name: &str) -> PathBuf {
+        let file = root.join(name);
+        std::fs::write(&file, b"").unwrap();
+        file.join("cache")
+    }
+
+    #[tokio::test]
+    async fn first_writable_candidate_is_used() {
+        let root = tempfile::tempdir().unwrap();
+        let candidates = [root.path().join("a"), root.path().join("b")];
+
+        let result = select_writable(candidates.clone(), |_| async { unreachable!("no directory needs to be chosen") }).await;
+        assert_eq!(result, (CacheDirChoice::Directory(candidates[0].clone()), false));
+        assert!(candidates[0].is_dir());
+    }
+
+    #[tokio::test]
+    async fn unwritable_candidates_fall_back() {
+        let root = tempfile::tempdir().unwrap();
+        let candidates = [unwritable(root.path(), "override"), unwritable(root.path(), "cache"), root.path().join("config")];
+
+        let result = select_writable(candidates.clone(), |_| async { unreachable!("no directory needs to be chosen") }).await;
+        assert_eq!(result, (CacheDirChoice::Directory(candidates[2].clone()), false));
+    }
+
+    #[tokio::test]
+    async fn user_is_asked_until_a_writable_directory_is_chosen() {
+        let root = tempfile::tempdir().unwrap();
+        let candidates = [unwritable(root.path(), "cache"), unwritable(root.path(), "config")];
+        let mut choices = vec![CacheDirChoice::Directory(root.path().join("chosen")), CacheDirChoice::Directory(unwritable(root.path(), "chosen-file"))];
+        let mut asked = Vec::new();
+
+        let result = select_writable(candidates.clone(), |failures| {
+            asked.push(failures.iter().map(|failure| failure.path.clone()).collect::<Vec<_>>());
+            let choice = choices.pop().unwrap();
+            async move { choice }
+        }).await;
+
+        assert_eq!(result, (CacheDirChoice::Directory(root.path().join("chosen")), true));
+        assert_eq!(asked, vec![
+            candidates.to_vec(),
+            vec![candidates[0].clone(), candidates[1].clone(), root.path().join("chosen-file").join("cache")],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn user_may_give_up() {
+        let root = tempfile::tempdir().unwrap();
+        for choice in [CacheDirChoice::Ephemeral, CacheDirChoice::Quit] {
+            let expected = choice.clone();
+            let result = select_writable([unwritable(root.path(), "cache")], move |_| {
+                let choice = choice.clone();
+                async move { choice }
+            }).await;
+            assert_eq!(result, (expected, false));
+        }
+    }
+
+    #[test]
+    fn load_can_run_on_the_runtime() {
+        fn assert_send<T: Send>(_: T) {}
+        assert_send(Context::load(|_| async { CacheDirChoice::Ephemeral }));
+    }
+}
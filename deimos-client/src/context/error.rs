@@ -0,0 +1,131 @@
+//! Record of errors returned by the server, kept so that the full status of a failure can be
+//! shown and copied when the friendly message shown in the UI is not enough to diagnose it
+
+use std::{collections::VecDeque, fmt::Write, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use tonic::metadata::KeyAndValueRef;
+
+use super::NotifyMutation;
+
+/// The most recent errors, newest first
+#[derive(Debug, Default, Clone)]
+pub struct ErrorLog(VecDeque<Arc<ErrorRecord>>);
+
+/// A single failed request and the raw status that the server or transport returned for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorRecord {
+    /// Time of the most recent occurrence
+    pub at: DateTime<Utc>,
+    /// Friendly description of the operation that failed
+    pub summary: String,
+    pub code: tonic::Code,
+    pub message: String,
+    /// Code and message of the error detail attached by the server, if any
+    pub detail: Option<(deimosproto::ErrorCode, String)>,
+    /// Printable metadata returned with the status
+    pub metadata: Vec<(String, String)>,
+    /// Number of identical errors that occurred in a row
+    pub count: usize,
+}
+
+impl ErrorLog {
+    /// Maximum number of errors kept in the log
+    pub const CAPACITY: usize = 20;
+
+    /// Iterate over all errors in the log, newest first
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<ErrorRecord>> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Add an error to the log, merging it with the newest error if they are identical so that
+    /// repeated failures while disconnected do not push out other errors
+    fn push(&mut self, record: ErrorRecord) -> Arc<ErrorRecord> {
+        if let Some(newest) = self.0.front_mut().filter(|newest| newest.same_error(&record)) {
+            *newest = Arc::new(ErrorRecord { count: newest.count + 1, ..record });
+            return newest.clone()
+        }
+
+        let record = Arc::new(record);
+        self.0.push_front(record.clone());
+        self.0.truncate(Self::CAPACITY);
+        record
+    }
+}
+
+impl NotifyMutation<ErrorLog> {
+    /// Record the given status returned for a failed operation, returning the record so that it
+    /// can be attached to the UI element showing the failure
+    pub fn record(&self, summary: impl Into<String>, status: &tonic::Status) -> Arc<ErrorRecord> {
+        let record = ErrorRecord::from_status(summary.into(), status);
+        tracing::warn!("{}: {}", record.summary, status);
+
+        let mut pushed = None;
+        self.modify(|log| pushed = Some(log.push(record)));
+        pushed.expect("Error log modification always pushes a record")
+    }
+}
+
+impl ErrorRecord {
+    /// Create a record of the given status, decoding its error detail and metadata
+    pub fn from_status(summary: String, status: &tonic::Status) -> Self {
+        let detail = deimosproto::ErrorDetail::from_status(status).map(|detail| (detail.code(), detail.message));
+        let metadata = status
+            .metadata()
+            .iter()
+            .map(|entry| match entry {
+                KeyAndValueRef::Ascii(key, value) => (
+                    key.to_string(),
+                    value.to_str().map(str::to_owned).unwrap_or_else(|_| String::from("<invalid>")),
+                ),
+                KeyAndValueRef::Binary(key, value) => (key.to_string(), format!("<{} bytes>", value.as_encoded_bytes().len())),
+            })
+            .collect();
+
+        Self {
+            at: Utc::now(),
+            summary,
+            code: status.code(),
+            message: status.message().to_owned(),
+            detail,
+            metadata,
+            count: 1,
+        }
+    }
+
+    /// Get the full description of the error as shown in its details pane and copied to the
+    /// clipboard
+    pub fn details(&self) -> String {
+        let mut details = format!("{}\nTime: {}\n", self.summary, self.at.to_rfc3339());
+        if self.count > 1 {
+            let _ = writeln!(details, "Occurrences: {}", self.count);
+        }
+
+        let _ = writeln!(details, "Status: {:?} ({})", self.code, self.code as i32);
+        let _ = writeln!(details, "Message: {}", self.message);
+        if let Some((code, ref message)) = self.detail {
+            let _ = writeln!(details, "Error code: {} ({})", code.as_str_name(), code as i32);
+            if *message != self.message {
+                let _ = writeln!(details, "Error message: {}", message);
+            }
+        }
+
+        if !self.metadata.is_empty() {
+            details.push_str("Metadata:\n");
+            for (key, value) in self.metadata.iter() {
+                let _ = writeln!(details, "  {}: {}", key, value);
+            }
+        }
+
+        details
+    }
+
+    /// Check if the given record describes the same failure as this one
+    fn same_error(&self, other: &Self) -> bool {
+        self.summary == other.summary && self.code == other.code && self.message == other.message && self.detail == other.detail
+    }
+}
@@ -0,0 +1,146 @@
+//! Explanation of why a disabled pod is not running, chosen from the most recent failure of its
+//! container and the time it was last stopped. Both are saved with the pod's cached metadata so
+//! that the explanation is shown again when the client restarts
+
+use chrono::{DateTime, Utc};
+
+use super::pod::{CachedPodFailure, CachedPodFailureKind};
+
+/// How and when a disabled pod's container last stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedPodExit {
+    pub kind: CachedPodExitKind,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachedPodExitKind {
+    /// The pod was disabled without its container failing
+    Stopped,
+    /// The container exited on its own with exit code 0
+    Exited,
+    /// The container exited on its own with a non-zero exit code, or an exit code that could
+    /// not be read
+    Crashed(Option<i64>),
+    /// The container was killed after running out of memory
+    OutOfMemory,
+    /// The container was killed outside of Deimos
+    Killed,
+    /// The container was created but could not be started
+    StartFailed,
+}
+
+/// How prominently the explanation of an exit is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachedPodExitSeverity {
+    Neutral,
+    Warning,
+    Error,
+}
+
+impl CachedPodExit {
+    /// Choose the explanation for a disabled pod, preferring the failure of its container since
+    /// failures are cleared when the pod is next enabled
+    pub fn select(failure: Option<&CachedPodFailure>, stopped_at: Option<DateTime<Utc>>) -> Option<Self> {
+        match failure {
+            Some(failure) => Some(Self {
+                kind: CachedPodExitKind::from(failure.kind),
+                at: failure.at,
+            }),
+            None => stopped_at.map(|at| Self { kind: CachedPodExitKind::Stopped, at }),
+        }
+    }
+}
+
+impl CachedPodExitKind {
+    /// Get the label shown in place of the pod's state
+    pub fn label(&self) -> String {
+        match self {
+            Self::Stopped => String::from("Stopped"),
+            Self::Exited => String::from("Exited"),
+            Self::Crashed(Some(code)) => format!("Crashed (exit {})", code),
+            Self::Crashed(None) => String::from("Crashed"),
+            Self::OutOfMemory => String::from("Killed: out of memory"),
+            Self::Killed => String::from("Killed"),
+            Self::StartFailed => String::from("Failed to start"),
+        }
+    }
+
+    pub const fn severity(&self) -> CachedPodExitSeverity {
+        match self {
+            Self::Stopped | Self::Exited => CachedPodExitSeverity::Neutral,
+            Self::Crashed(..) | Self::Killed => CachedPodExitSeverity::Warning,
+            Self::OutOfMemory | Self::StartFailed => CachedPodExitSeverity::Error,
+        }
+    }
+}
+
+impl From<CachedPodFailureKind> for CachedPodExitKind {
+    fn from(value: CachedPodFailureKind) -> Self {
+        match value {
+            CachedPodFailureKind::Exited(Some(0)) => Self::Exited,
+            CachedPodFailureKind::Exited(code) => Self::Crashed(code),
+            //Failures cached before their kind was recorded were all unexpected exits or failed
+            //starts, neither of which were clean
+            CachedPodFailureKind::Unknown => Self::Crashed(None),
+            CachedPodFailureKind::OutOfMemory => Self::OutOfMemory,
+            CachedPodFailureKind::Killed => Self::Killed,
+            CachedPodFailureKind::StartFailed => Self::StartFailed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn failure(kind: CachedPodFailureKind, at: DateTime<Utc>) -> CachedPodFailure {
+        CachedPodFailure { at, kind, reason: String::new(), output: String::new() }
+    }
+
+    #[test]
+    fn failures_are_preferred_over_stop_time() {
+        let failed = DateTime::<Utc>::from_timestamp(100, 0).unwrap();
+        let stopped = DateTime::<Utc>::from_timestamp(200, 0).unwrap();
+        let failure = failure(CachedPodFailureKind::OutOfMemory, failed);
+
+        assert_eq!(
+            CachedPodExit::select(Some(&failure), Some(stopped)),
+            Some(CachedPodExit { kind: CachedPodExitKind::OutOfMemory, at: failed }),
+        );
+        assert_eq!(
+            CachedPodExit::select(Some(&failure), None),
+            Some(CachedPodExit { kind: CachedPodExitKind::OutOfMemory, at: failed }),
+        );
+    }
+
+    #[test]
+    fn stopped_without_failure() {
+        let stopped = DateTime::<Utc>::from_timestamp(200, 0).unwrap();
+        assert_eq!(
+            CachedPodExit::select(None, Some(stopped)),
+            Some(CachedPodExit { kind: CachedPodExitKind::Stopped, at: stopped }),
+        );
+        assert_eq!(CachedPodExit::select(None, None), None);
+    }
+
+    #[test]
+    fn failure_kinds() {
+        let at = DateTime::<Utc>::from_timestamp(100, 0).unwrap();
+        for (kind, exit, severity) in [
+            (CachedPodFailureKind::Exited(Some(0)), CachedPodExitKind::Exited, CachedPodExitSeverity::Neutral),
+            (CachedPodFailureKind::Exited(Some(137)), CachedPodExitKind::Crashed(Some(137)), CachedPodExitSeverity::Warning),
+            (CachedPodFailureKind::Exited(None), CachedPodExitKind::Crashed(None), CachedPodExitSeverity::Warning),
+            (CachedPodFailureKind::Unknown, CachedPodExitKind::Crashed(None), CachedPodExitSeverity::Warning),
+            (CachedPodFailureKind::Killed, CachedPodExitKind::Killed, CachedPodExitSeverity::Warning),
+            (CachedPodFailureKind::OutOfMemory, CachedPodExitKind::OutOfMemory, CachedPodExitSeverity::Error),
+            (CachedPodFailureKind::StartFailed, CachedPodExitKind::StartFailed, CachedPodExitSeverity::Error),
+        ] {
+            let selected = CachedPodExit::select(Some(&failure(kind, at)), None).unwrap();
+            assert_eq!(selected.kind, exit, "{:?}", kind);
+            assert_eq!(selected.kind.severity(), severity, "{:?}", kind);
+        }
+
+        assert_eq!(CachedPodExitKind::Crashed(Some(1)).label(), "Crashed (exit 1)");
+    }
+}
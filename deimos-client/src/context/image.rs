@@ -0,0 +1,280 @@
+//! Resumable downloads of pod icons and banners from servers that support them. Received chunks
+//! are appended to a partial file named with the hash of the image being downloaded, kept next to
+//! the cached image, so that a download cut off by a lost connection or a closed client resumes
+//! from the bytes already held. Parts of an image that changed on the server are discarded, and
+//! completed downloads are checked against the hash the server gave before they are used
+
+use std::path::{Path, PathBuf};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use tokio::io::AsyncWriteExt;
+
+use super::{pod::{CachedPod, CachedPodImage}, server::ContextServer, Context};
+
+/// Partial download of an image, kept in the cache directory if there is one
+struct PartialImage {
+    path: Option<PathBuf>,
+    data: Vec<u8>,
+    /// Description of the image given with the first chunk of the download
+    image: Option<deimosproto::PodImage>,
+}
+
+impl Context {
+    /// Download the given image of a pod, resuming from a partial file of the same hash left by an
+    /// earlier download. The expected image is given by the server's response to `GetPodImages`
+    pub(super) async fn download_image(
+        &self,
+        server: &ContextServer,
+        pod: &CachedPod,
+        kind: deimosproto::PodImageKind,
+        file: &str,
+        expected: &deimosproto::PodImage,
+    ) -> Result<CachedPodImage, ImageDownloadError> {
+        let dir = self.cache_dir.as_ref().map(|cache_dir| pod.directory(cache_dir));
+        let mut part = PartialImage::open(dir.as_deref(), file, &expected.hash).await;
+        if part.data.len() as u64 > expected.size {
+            part.restart(dir.as_deref(), file, &expected.hash).await;
+        }
+
+        let request = deimosproto::PodImageDownloadRequest {
+            id: String::from(&pod.data.id),
+            kind: kind as i32,
+            hash: expected.hash.clone(),
+            offset: part.data.len() as u64,
+        };
+
+        let mut stream = {
+            let Some(ref mut api) = server.clients.podapi().await else {
+                return Err(ImageDownloadError::NotConnected)
+            };
+
+            server.requests.request(server.request_limit(), api.download_pod_image(request)).await?.into_inner()
+        };
+
+        while let Some(chunk) = stream.message().await? {
+            if part.receive(dir.as_deref(), file, chunk).await? {
+                tracing::debug!("{} of pod {} changed during download, restarting", file, pod.data.id);
+            }
+        }
+
+        CachedPodImage::from_proto(part.finish().await?).ok_or(ImageDownloadError::Format)
+    }
+}
+
+impl PartialImage {
+    /// Load the part of the image with the given hash held in the given directory, removing the
+    /// parts of any other version of the image
+    async fn open(dir: Option<&Path>, file: &str, hash: &str) -> Self {
+        let Some(dir) = dir else {
+            return Self { path: None, data: Vec::new(), image: None }
+        };
+
+        let path = Self::path(dir, file, hash);
+        let prefix = format!("{}.", file);
+        if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with(&prefix) && name.ends_with(".part") && entry.path() != path {
+                    let _ = tokio::fs::remove_file(entry.path()).await;
+                }
+            }
+        }
+
+        let data = tokio::fs::read(&path).await.unwrap_or_default();
+        if !data.is_empty() {
+            tracing::debug!("Resuming download of {} from {} bytes", path.display(), data.len());
+        }
+
+        Self { path: Some(path), data, image: None }
+    }
+
+    /// Get the path of the partial file of the image with the given hash
+    fn path(dir: &Path, file: &str, hash: &str) -> PathBuf {
+        dir.join(format!("{}.{}.part", file, hash))
+    }
+
+    /// Add a chunk received from the server to the part, returning `true` if the part held was
+    /// discarded first because the image changed on the server
+    async fn receive(&mut self, dir: Option<&Path>, file: &str, chunk: deimosproto::PodImageChunk) -> Result<bool, ImageDownloadError> {
+        let mut restarted = false;
+        if let Some(header) = chunk.image {
+            //The image changed since it was listed, so the part held is of the old image
+            if chunk.offset == 0 && !self.data.is_empty() {
+                self.restart(dir, file, &header.hash).await;
+                restarted = true;
+            }
+
+            self.image = Some(header);
+        }
+
+        if chunk.offset != self.data.len() as u64 {
+            return Err(ImageDownloadError::Offset { expected: self.data.len() as u64, received: chunk.offset })
+        }
+
+        self.append(&chunk.data).await;
+        Ok(restarted)
+    }
+
+    /// Check the completed download against the image described by the server, removing the
+    /// partial file and returning the image with its contents
+    async fn finish(&mut self) -> Result<deimosproto::PodImage, ImageDownloadError> {
+        let image = self.image.take().ok_or(ImageDownloadError::Incomplete)?;
+        let hash = format!("{:x}", Blake2b::<U32>::digest(&self.data));
+        let data = self.remove().await;
+        if data.len() as u64 != image.size || hash != image.hash {
+            return Err(ImageDownloadError::Hash { expected: image.hash, received: hash })
+        }
+
+        Ok(deimosproto::PodImage { data, ..image })
+    }
+
+    /// Discard the part held and begin holding the image with the given hash
+    async fn restart(&mut self, dir: Option<&Path>, file: &str, hash: &str) {
+        self.remove().await;
+        self.path = dir.map(|dir| Self::path(dir, file, hash));
+    }
+
+    /// Append received data to the part, saving it to the partial file.
+    /// Failing to save the data only prevents the download from being resumed
+    async fn append(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+        let Some(ref path) = self.path else { return };
+
+        let result = async {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?
+                .write_all(data)
+                .await
+        };
+
+        if let Err(e) = result.await {
+            tracing::warn!("Failed to save partial image {}: {}", path.display(), e);
+            self.path = None;
+        }
+    }
+
+    /// Remove the partial file, returning the data held
+    async fn remove(&mut self) -> Vec<u8> {
+        if let Some(path) = self.path.take() {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove partial image {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        std::mem::take(&mut self.data)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageDownloadError {
+    #[error("Not connected to the server")]
+    NotConnected,
+    #[error("{}", .0.message())]
+    Status(#[from] tonic::Status),
+    #[error("Received data at offset {received} when {expected} bytes are held")]
+    Offset { expected: u64, received: u64 },
+    #[error("The server ended the download without describing the image")]
+    Incomplete,
+    #[error("Downloaded image has hash {received} instead of {expected}")]
+    Hash { expected: String, received: String },
+    #[error("Downloaded image is empty or in an unknown format")]
+    Format,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(data: &[u8]) -> deimosproto::PodImage {
+        deimosproto::PodImage {
+            hash: format!("{:x}", Blake2b::<U32>::digest(data)),
+            data: Vec::new(),
+            format: deimosproto::PodImageFormat::Png as i32,
+            size: data.len() as u64,
+        }
+    }
+
+    /// Get the chunks that the server sends for the given image starting at the given offset
+    fn chunks(data: &[u8], offset: usize, size: usize) -> Vec<deimosproto::PodImageChunk> {
+        data[offset..]
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| deimosproto::PodImageChunk {
+                image: (i == 0).then(|| image(data)),
+                offset: (offset + i * size) as u64,
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+
+    /// Receive the given chunks without a cache directory
+    async fn receive(part: &mut PartialImage, chunks: Vec<deimosproto::PodImageChunk>) -> Result<bool, ImageDownloadError> {
+        let mut restarted = false;
+        for chunk in chunks {
+            restarted |= part.receive(None, "icon", chunk).await?;
+        }
+
+        Ok(restarted)
+    }
+
+    #[tokio::test]
+    async fn resumes_after_interrupt() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut part = PartialImage::open(None, "icon", "").await;
+
+        //The connection is lost after the first three chunks
+        let mut sent = chunks(&data, 0, 16);
+        sent.truncate(3);
+        assert!(!receive(&mut part, sent).await.unwrap());
+        assert_eq!(part.data.len(), 48);
+
+        //The held image is kept when resuming, only the new part arrives
+        let mut resumed = PartialImage { path: None, data: std::mem::take(&mut part.data), image: None };
+        assert!(!receive(&mut resumed, chunks(&data, 48, 16)).await.unwrap());
+        assert_eq!(resumed.finish().await.unwrap().data, data);
+    }
+
+    #[tokio::test]
+    async fn changed_image_restarts() {
+        let old = [1u8; 64];
+        let new = vec![2u8; 80];
+        let mut part = PartialImage { path: None, data: old[..32].to_vec(), image: None };
+
+        //The server starts from 0 as the image no longer has the hash of the part held
+        assert!(receive(&mut part, chunks(&new, 0, 16)).await.unwrap());
+        assert_eq!(part.finish().await.unwrap().data, new);
+    }
+
+    #[tokio::test]
+    async fn offset_gaps_are_rejected() {
+        let data = vec![3u8; 64];
+        let mut part = PartialImage { path: None, data: vec![3u8; 16], image: None };
+        let result = receive(&mut part, chunks(&data, 32, 16)).await;
+        assert!(matches!(result, Err(ImageDownloadError::Offset { expected: 16, received: 32 })));
+    }
+
+    #[tokio::test]
+    async fn corrupt_images_are_rejected() {
+        let data = vec![4u8; 64];
+        let mut sent = chunks(&data, 0, 16);
+        sent[1].data[0] = 0;
+
+        let mut part = PartialImage { path: None, data: Vec::new(), image: None };
+        receive(&mut part, sent).await.unwrap();
+        assert!(matches!(part.finish().await, Err(ImageDownloadError::Hash { .. })));
+        assert!(part.data.is_empty());
+
+        let mut part = PartialImage { path: None, data: Vec::new(), image: None };
+        assert!(matches!(part.finish().await, Err(ImageDownloadError::Incomplete)));
+    }
+}
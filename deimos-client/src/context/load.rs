@@ -1,99 +1,413 @@
-use std::path::{Path, PathBuf};
+//! Loading and saving of the context state kept in the cache directory. Server settings,
+//! authorization tokens, and queued actions are each stored in their own file and parsed
+//! independently, so that a file that cannot be read only resets its own section to the default.
+//! State saved by earlier versions is migrated once when it is first loaded: state saved in a
+//! single file is split into the separate files, and the settings and token of the single server
+//! that could be configured become the first entry of the server list
 
-use super::{client::ContextPersistent, Context};
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::{
+    client::{auth::{PersistentToken, PersistentTokenKind}, ContextPersistent, ContextSettings},
+    queue::QueuedAction,
+    server::{PersistentServer, ServerId},
+    Context,
+};
+
+/// Connection settings of a single server in the settings file
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct PersistentServerSettings {
+    id: ServerId,
+    #[serde(default)]
+    settings: ContextSettings,
+    #[serde(default)]
+    token_protect: PersistentTokenKind,
+}
+
+/// Contents of the settings file
+#[derive(Debug, Default, Clone, serde::Deserialize, serde::Serialize)]
+struct PersistentSettings {
+    #[serde(default)]
+    servers: Vec<PersistentServerSettings>,
+}
+
+/// Contents of the settings file saved before multiple servers were supported
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+struct LegacySettings {
+    #[serde(default)]
+    settings: ContextSettings,
+    #[serde(default)]
+    token_protect: PersistentTokenKind,
+}
+
+/// Contents of the token file, holding the protected token of each server that has one
+#[derive(Debug, Default, Clone, serde::Deserialize, serde::Serialize)]
+struct PersistentTokens {
+    #[serde(default)]
+    servers: HashMap<ServerId, PersistentToken>,
+}
+
+/// Unparsed contents of each state file, `None` for files that are missing or are not valid JSON
+#[derive(Debug, Default)]
+struct StateSections {
+    settings: Option<Value>,
+    tokens: Option<Value>,
+    queued: Option<Value>,
+}
 
 impl Context {
-    /// File located in the context's cache directory that stores serialized state
-    pub const STATE_FILE_NAME: &str = "state.json";
-
-    /// Load application context state from the local cache directory, or create a default one
-    pub fn load_state(cache_dir: &Path) -> Result<ContextPersistent, LoadStateError> {
-        let config_path = cache_dir.join(Self::STATE_FILE_NAME);
-        match std::fs::File::open(&config_path) {
-            Ok(rdr) => Ok(
-                serde_json::from_reader::<_, ContextPersistent>(rdr)
-                    .map_err(|e| LoadStateError {
-                    config_path: config_path.clone(),
-                    kind: LoadStateErrorKind::Parse(e),
-                })?,
-            ),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                tracing::warn!(
-                    "Config file {} not found: creating a default",
-                    config_path.display()
-                );
-
-                if !std::fs::exists(cache_dir).unwrap_or(false) {
-                    if let Err(e) = std::fs::create_dir(cache_dir) {
-                        tracing::warn!(
-                            "Failed to create config directory {}: {}",
-                            cache_dir.display(),
-                            e
-                        );
-                    }
-                }
+    /// File located in the context's cache directory that stores the settings of every server
+    pub const SETTINGS_FILE_NAME: &str = "settings.json";
+    /// File located in the context's cache directory that stores the protected token of every
+    /// server
+    pub const TOKEN_FILE_NAME: &str = "token.json";
+    /// File located in the context's cache directory that stores actions queued while disconnected
+    pub const QUEUE_FILE_NAME: &str = "queue.json";
+    /// File that all context state was stored in before each section was given its own file
+    pub const LEGACY_STATE_FILE_NAME: &str = "state.json";
+    /// Extension given to the legacy state file once it has been migrated
+    const MIGRATED_EXTENSION: &str = "json.migrated";
+    /// Key of the server list in the settings and token files, absent from files saved before
+    /// multiple servers were supported
+    const SERVERS_KEY: &str = "servers";
+
+    /// Load application context state from the local cache directory, using the default for
+    /// every section whose file is missing or cannot be parsed.
+    /// Sections saved in an older format are converted and written back immediately, so that a
+    /// token is never lost if the client exits before the state is next saved
+    pub async fn load_state(cache_dir: &Path) -> ContextPersistent {
+        let settings_path = cache_dir.join(Self::SETTINGS_FILE_NAME);
+        let token_path = cache_dir.join(Self::TOKEN_FILE_NAME);
+        let queue_path = cache_dir.join(Self::QUEUE_FILE_NAME);
+        let legacy_path = cache_dir.join(Self::LEGACY_STATE_FILE_NAME);
 
-                let config = ContextPersistent::default();
+        let settings_exists = tokio::fs::try_exists(&settings_path).await.unwrap_or(true);
+        let legacy = match !settings_exists && tokio::fs::try_exists(&legacy_path).await.unwrap_or(false) {
+            true => Self::read_legacy_state(&legacy_path).await,
+            false => None,
+        };
+
+        let from_legacy = legacy.is_some();
+        let sections = match legacy {
+            Some(sections) => sections,
+            None => StateSections {
+                settings: read_section(&settings_path).await,
+                tokens: read_section(&token_path).await,
+                queued: read_section(&queue_path).await,
+            },
+        };
+
+        let mut migrated = from_legacy;
+        let mut servers = match sections.settings {
+            Some(value) if value.get(Self::SERVERS_KEY).is_some() => parse_section::<PersistentSettings>(&settings_path, value).servers,
+            Some(value) => {
+                tracing::info!("Migrating settings of a single server to the server list");
+                migrated = true;
+                let legacy = parse_section::<LegacySettings>(&settings_path, value);
+                vec![PersistentServerSettings {
+                    id: ServerId::generate(),
+                    settings: legacy.settings,
+                    token_protect: legacy.token_protect,
+                }]
+            },
+            None => Vec::new(),
+        };
+
+        if servers.is_empty() {
+            tracing::warn!("No servers found in {}: creating a default", settings_path.display());
+            migrated |= !settings_exists;
+            servers.push(PersistentServerSettings {
+                id: ServerId::generate(),
+                settings: ContextSettings::default(),
+                token_protect: PersistentTokenKind::default(),
+            });
+        }
 
-                let file = std::fs::File::create(&config_path).map_err(|e| LoadStateError {
-                    config_path: config_path.clone(),
-                    kind: LoadStateErrorKind::FailedToCreateDefault(e),
-                })?;
+        let first = servers[0].id.clone();
+        let mut tokens = match sections.tokens {
+            Some(value) if value.get(Self::SERVERS_KEY).is_some() => parse_section::<PersistentTokens>(&token_path, value).servers,
+            Some(value) => {
+                migrated = true;
+                parse_section::<Option<PersistentToken>>(&token_path, value)
+                    .map(|token| HashMap::from([(first.clone(), token)]))
+                    .unwrap_or_default()
+            },
+            None => HashMap::new(),
+        };
 
-                serde_json::to_writer(file, &config).map_err(|e| LoadStateError {
-                    config_path: config_path.clone(),
-                    kind: LoadStateErrorKind::Serialize(e),
-                })?;
+        let mut queued = sections
+            .queued
+            .map(|value| parse_section::<Vec<QueuedAction>>(&queue_path, value))
+            .unwrap_or_default();
 
-                Ok(config)
+        //Actions queued before servers had IDs were queued for the server that was migrated
+        for action in queued.iter_mut().filter(|action| action.server.is_unassigned()) {
+            action.server = first.clone();
+            migrated = true;
+        }
+
+        if migrated {
+            let saved_tokens = PersistentTokens { servers: tokens.clone() };
+            let written = Self::save_sections(cache_dir, &PersistentSettings { servers: servers.clone() }, &saved_tokens, &queued).await;
+            if written && from_legacy {
+                let renamed = legacy_path.with_extension(Self::MIGRATED_EXTENSION);
+                if let Err(e) = tokio::fs::rename(&legacy_path, &renamed).await {
+                    tracing::warn!("Failed to rename migrated state file {}: {}", legacy_path.display(), e);
+                }
             }
-            Err(e) => Err(LoadStateError {
-                config_path,
-                kind: LoadStateErrorKind::FailedToOpen(e),
-            }),
         }
+
+        let servers = servers
+            .into_iter()
+            .map(|server| PersistentServer {
+                token: tokens.remove(&server.id),
+                id: server.id,
+                settings: server.settings,
+                token_protect: server.token_protect,
+            })
+            .collect();
+
+        ContextPersistent { servers, queued }
+    }
+
+    /// Split the state saved in the legacy state file into the sections stored in each file, so
+    /// that they are parsed and migrated in the same way as state saved in separate files.
+    /// Returns `None` if the legacy file could not be read at all
+    async fn read_legacy_state(legacy_path: &Path) -> Option<StateSections> {
+        tracing::info!("Migrating context state from {}", legacy_path.display());
+
+        let mut value = match read_section(legacy_path).await? {
+            Value::Object(map) => map,
+            _ => {
+                tracing::error!("Legacy state file {} does not contain an object", legacy_path.display());
+                return None
+            },
+        };
+
+        let settings = ["settings", "token_protect"]
+            .into_iter()
+            .filter_map(|key| value.remove(key).map(|section| (key.to_owned(), section)))
+            .collect::<serde_json::Map<_, _>>();
+
+        Some(StateSections {
+            settings: Some(Value::Object(settings)),
+            tokens: value.remove("token"),
+            queued: value.remove("queued"),
+        })
     }
 
-    /// Write all context state to the save file located in the cache directory
-    pub fn save_state(&self) {
-        let state_path = self.cache_dir.join(Self::STATE_FILE_NAME);
-
-        match std::fs::File::create(&state_path) {
-            Ok(w) => {
-                if let Err(e) = serde_json::to_writer::<_, ContextPersistent>(w, &self.clients.persistent()) {
-                    tracing::error!(
-                        "Failed to write context state to '{}': {}",
-                        state_path.display(),
-                        e
-                    );
+    /// Write all context state to the files located in the cache directory.
+    /// Servers whose current token could not be protected keep the token that was saved for them
+    /// earlier, so that it is not lost
+    pub async fn save_state(&self) {
+        let Some(ref cache_dir) = self.cache_dir else { return };
+        let servers = self.servers.read().clone();
+
+        let settings = PersistentSettings {
+            servers: servers
+                .iter()
+                .map(|server| PersistentServerSettings {
+                    id: server.id.clone(),
+                    settings: server.clients.settings.read().clone(),
+                    token_protect: *server.clients.token_protect.read(),
+                })
+                .collect(),
+        };
+
+        let mut tokens = PersistentTokens::default();
+        let mut kept = Vec::new();
+        for server in servers.iter() {
+            match server.clients.persistent_token() {
+                Ok(Some(token)) => {
+                    tokens.servers.insert(server.id.clone(), token);
+                },
+                Ok(None) => (),
+                Err(e) => {
+                    tracing::error!("Failed to protect persistent token of {}, keeping the saved token: {}", server.label(), e);
+                    kept.push(server.id.clone());
+                },
+            }
+        }
+
+        if !kept.is_empty() {
+            let path = cache_dir.join(Self::TOKEN_FILE_NAME);
+            if let Some(value) = read_section(&path).await.filter(|value| value.get(Self::SERVERS_KEY).is_some()) {
+                let mut saved = parse_section::<PersistentTokens>(&path, value);
+                for id in kept {
+                    if let Some(token) = saved.servers.remove(&id) {
+                        tokens.servers.insert(id, token);
+                    }
                 }
             }
-            Err(e) => {
-                tracing::error!(
-                    "Failed to create context state file '{}': {}",
-                    state_path.display(),
-                    e
-                );
+        }
+
+        let queued = self.queue.read().clone();
+        Self::save_sections(cache_dir, &settings, &tokens, &queued).await;
+    }
+
+    /// Write every section of the context state to its file, returning `true` if all files were
+    /// written
+    async fn save_sections(cache_dir: &Path, settings: &PersistentSettings, tokens: &PersistentTokens, queued: &[QueuedAction]) -> bool {
+        let written = [
+            save_section(&cache_dir.join(Self::SETTINGS_FILE_NAME), settings).await,
+            save_section(&cache_dir.join(Self::TOKEN_FILE_NAME), tokens).await,
+            save_section(&cache_dir.join(Self::QUEUE_FILE_NAME), &queued).await,
+        ];
+
+        let mut saved = true;
+        for result in written {
+            if let Err(e) = result {
+                tracing::error!("{}", e);
+                saved = false;
             }
         }
+
+        saved
+    }
+}
+
+/// Read a single file of the context state as JSON, returning `None` if it does not exist or
+/// cannot be read
+async fn read_section(path: &Path) -> Option<Value> {
+    match load_section::<Value>(path).await {
+        Ok(value) => value,
+        Err(e) => {
+            tracing::error!("{}: using the default", e);
+            None
+        }
     }
 }
 
+/// Read and parse a single section of the context state, returning `None` if its file does not
+/// exist
+async fn load_section<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, LoadStateError> {
+    match tokio::fs::read(path).await {
+        Ok(buf) => serde_json::from_slice::<T>(&buf)
+            .map(Some)
+            .map_err(|e| LoadStateError { path: path.to_owned(), kind: LoadStateErrorKind::Parse(e) }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(LoadStateError { path: path.to_owned(), kind: LoadStateErrorKind::FailedToOpen(e) }),
+    }
+}
+
+/// Serialize a single section of the context state and write it to the given file
+async fn save_section<T: Serialize + ?Sized>(path: &Path, section: &T) -> Result<(), LoadStateError> {
+    let buf = serde_json::to_vec(section)
+        .map_err(|e| LoadStateError { path: path.to_owned(), kind: LoadStateErrorKind::Serialize(e) })?;
+
+    tokio::fs::write(path, buf)
+        .await
+        .map_err(|e| LoadStateError { path: path.to_owned(), kind: LoadStateErrorKind::FailedToWrite(e) })
+}
+
+/// Parse a section of the context state that was read from the given file, using the default if
+/// it is invalid
+fn parse_section<T: DeserializeOwned + Default>(path: &Path, value: Value) -> T {
+    if value.is_null() {
+        return T::default()
+    }
+
+    serde_json::from_value(value).unwrap_or_else(|e| {
+        tracing::error!("{}: using the default", LoadStateError { path: path.to_owned(), kind: LoadStateErrorKind::Parse(e) });
+        T::default()
+    })
+}
+
 #[derive(Debug, thiserror::Error)]
-#[error("Loading config file '{config_path}': {kind}")]
+#[error("State file '{path}': {kind}")]
 pub struct LoadStateError {
-    pub config_path: PathBuf,
+    pub path: PathBuf,
     pub kind: LoadStateErrorKind,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoadStateErrorKind {
-    #[error("Failed to create default config file {0}")]
-    FailedToCreateDefault(#[source] std::io::Error),
-    #[error("Failed to open config file: {0}")]
+    #[error("Failed to write state file: {0}")]
+    FailedToWrite(#[source] std::io::Error),
+    #[error("Failed to open state file: {0}")]
     FailedToOpen(#[source] std::io::Error),
-    #[error("Failed to parse config file: {0}")]
+    #[error("Failed to parse state file: {0}")]
     Parse(#[source] serde_json::Error),
-    #[error("Failed to serialize config: {0}")]
+    #[error("Failed to serialize state: {0}")]
     Serialize(#[source] serde_json::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use std::{future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+    use super::{super::pod::{CachedPod, CachedPodState}, *};
+
+    /// Longest time that file operations may hold the runtime thread without yielding
+    const STALL_THRESHOLD: Duration = Duration::from_millis(50);
+
+    /// Run `work` alongside a heartbeat task on the same runtime thread, returning the longest
+    /// time that the heartbeat could not run because `work` blocked the thread
+    async fn longest_stall<F: Future>(work: F) -> (F::Output, Duration) {
+        let longest = Arc::new(Mutex::new(Duration::ZERO));
+        let heartbeat = tokio::spawn({
+            let longest = longest.clone();
+            async move {
+                let mut last = Instant::now();
+                loop {
+                    tokio::task::yield_now().await;
+                    let now = Instant::now();
+                    let mut longest = longest.lock().unwrap();
+                    *longest = (*longest).max(now - last);
+                    last = now;
+                }
+            }
+        });
+
+        //Let the heartbeat start before any work is done
+        tokio::task::yield_now().await;
+        let output = work.await;
+        heartbeat.abort();
+
+        let longest = *longest.lock().unwrap();
+        (output, longest)
+    }
+
+    #[tokio::test]
+    async fn watchdog_detects_blocking() {
+        let ((), stall) = longest_stall(async {
+            tokio::task::yield_now().await;
+            std::thread::sleep(STALL_THRESHOLD * 2);
+            tokio::task::yield_now().await;
+        })
+        .await;
+
+        assert!(stall >= STALL_THRESHOLD * 2, "stall of {:?} was not detected", stall);
+    }
+
+    #[tokio::test]
+    async fn state_and_cache_io_do_not_stall_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let server: ServerId = serde_json::from_value(serde_json::json!("server")).unwrap();
+        let pods = (0..500)
+            .map(|i| {
+                let data = serde_json::from_value(serde_json::json!({ "id": format!("pod{}", i), "name": "Pod", "up": CachedPodState::Disabled })).unwrap();
+                CachedPod { server: server.clone(), data }
+            })
+            .collect::<Vec<_>>();
+
+        let (loaded, stall) = longest_stall(async {
+            Context::load_state(dir.path()).await;
+            let settings = PersistentSettings::default();
+            assert!(Context::save_sections(dir.path(), &settings, &PersistentTokens::default(), &[]).await);
+            for pod in pods.iter() {
+                pod.save(dir.path()).await.unwrap();
+            }
+
+            Context::load_state(dir.path()).await;
+            Context::load_cached_pods(dir.path().to_owned(), std::slice::from_ref(&server)).await
+        })
+        .await;
+
+        assert_eq!(loaded.len(), pods.len());
+        assert!(stall < STALL_THRESHOLD, "file operations blocked the runtime thread for {:?}", stall);
+    }
+}
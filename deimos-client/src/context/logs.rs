@@ -0,0 +1,74 @@
+//! Output of pod containers streamed from the server, decoded as UTF-8 for display
+
+use super::{pod::CachedPod, Context};
+
+/// A stream of a pod container's output, decoded lossily so that invalid UTF-8 in the output
+/// is replaced rather than ending the stream
+#[derive(Debug)]
+pub struct PodLogStream {
+    stream: tonic::Streaming<deimosproto::PodLogChunk>,
+    /// Bytes of an incomplete UTF-8 sequence at the end of the last chunk
+    partial: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodLogOpenError {
+    #[error("Not connected to the server")]
+    NotConnected,
+    #[error("{}", .0.message())]
+    Rejected(#[from] tonic::Status),
+}
+
+impl Context {
+    /// Subscribe to the output of the given pod's container, starting with everything the
+    /// container has written since it was started
+    pub async fn subscribe_logs(&self, pod: &CachedPod) -> Result<PodLogStream, PodLogOpenError> {
+        let server = self.server_of(pod).ok_or(PodLogOpenError::NotConnected)?;
+        let mut api = server.clients.podapi().await.ok_or(PodLogOpenError::NotConnected)?;
+        let request = deimosproto::PodLogStreamRequest {
+            id: String::from(&pod.data.id),
+            follow: true,
+            ..Default::default()
+        };
+        match api.subscribe_pod_logs(request).await {
+            Ok(stream) => {
+                tracing::info!("Subscribed to logs of pod {}", pod.data.id);
+                Ok(PodLogStream {
+                    stream: stream.into_inner(),
+                    partial: Vec::new(),
+                })
+            },
+            Err(e) => {
+                server.clients.errors.record(format!("Failed to subscribe to logs of pod {}", pod.data.id), &e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl PodLogStream {
+    /// Wait for the next chunk of output, returning `None` once the server ends the stream
+    pub async fn next(&mut self) -> Result<Option<String>, tonic::Status> {
+        match self.stream.message().await? {
+            Some(chunk) => Ok(Some(self.decode(chunk.chunk))),
+            None if self.partial.is_empty() => Ok(None),
+            None => Ok(Some(String::from_utf8_lossy(&std::mem::take(&mut self.partial)).into_owned())),
+        }
+    }
+
+    /// Decode the given chunk, holding back an incomplete UTF-8 sequence at its end until the
+    /// rest of the sequence is received in the next chunk
+    fn decode(&mut self, chunk: Vec<u8>) -> String {
+        let mut bytes = std::mem::take(&mut self.partial);
+        bytes.extend_from_slice(&chunk);
+
+        let complete = bytes
+            .iter()
+            .rposition(|byte| byte & 0xC0 != 0x80)
+            .filter(|start| std::str::from_utf8(&bytes[*start..]).is_err_and(|e| e.error_len().is_none()))
+            .unwrap_or(bytes.len());
+
+        self.partial = bytes.split_off(complete);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
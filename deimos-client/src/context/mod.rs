@@ -1,12 +1,32 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::{HashMap, VecDeque}, path::PathBuf, sync::Arc, time::{Duration, Instant}};
 
-use client::{ContextClients, ContextPersistent};
+use client::{stream::{StreamGap, StreamHealth, StreamMode, StreamSequence}, ContextConnectionState, ContextPersistent};
+use dir::{CacheDirChoice, CacheDirProbeError};
+use deimos_client_lib::pod::PodEndpoint;
+use error::ErrorLog;
 use futures::StreamExt;
-use pod::{CachedPod, CachedPodData, CachedPodState};
+use pod::{CachedPod, CachedPodAlert, CachedPodAnnotation, CachedPodFailure, CachedPodRestartBreaker, CachedPodState, CachedPodTransition, PodKey};
+use queue::{ActionRecord, QueuedAction};
+use server::ContextServer;
+use summary::{HostSummary, PodCategory};
+use terminal::TerminalSession;
 
+mod image;
 mod load;
 pub mod client;
+pub mod confirm;
+pub mod coord;
+pub mod dir;
+pub mod error;
+pub mod exit;
+pub mod logs;
 pub mod pod;
+pub mod queue;
+pub mod server;
+pub mod stats;
+pub mod summary;
+pub mod sync;
+pub mod terminal;
 
 #[derive(Debug, Default)]
 pub struct NotifyMutation<T>(tokio::sync::watch::Sender<T>);
@@ -15,37 +35,73 @@ pub struct NotifyMutation<T>(tokio::sync::watch::Sender<T>);
 /// container cache.
 #[derive(Debug)]
 pub struct Context {
-    /// A map of all loaded containers, to be modified by gRPC notifications
-    pub pods: NotifyMutation<HashMap<String, Arc<CachedPod>>>,
-    /// Pod control and authorization API clients
-    pub clients: ContextClients,
-    /// Directory that all container data and context state will be saved to
-    cache_dir: PathBuf,
+    /// A map of all loaded containers of every server, to be modified by gRPC notifications
+    pub pods: NotifyMutation<HashMap<PodKey, Arc<CachedPod>>>,
+    /// Every server that pods are synchronized from in the order they were added, never empty
+    pub servers: NotifyMutation<Vec<Arc<ContextServer>>>,
+    /// Most recent errors returned by any server
+    pub errors: NotifyMutation<ErrorLog>,
+    /// Number of pods in each state, recomputed by [Context::summary_loop]
+    pub summary: NotifyMutation<HostSummary>,
+    /// Category of pods shown in the pod list, or `None` to show all pods
+    pub filter: NotifyMutation<Option<PodCategory>>,
+    /// Pod state changes requested while disconnected, applied by [Context::queue_loop] in the
+    /// order they were queued
+    pub queue: NotifyMutation<Vec<QueuedAction>>,
+    /// Results of the most recently applied or cancelled queued actions, oldest first
+    pub actions: NotifyMutation<VecDeque<ActionRecord>>,
+    /// Interactive terminal session that the user has opened in a pod, if any
+    pub terminal: NotifyMutation<Option<Arc<TerminalSession>>>,
+    /// Pod whose container output is shown in the log view, if any
+    pub logs: NotifyMutation<Option<Arc<CachedPod>>>,
+    /// Directory that all container data and context state will be saved to, or `None` if the
+    /// user chose to continue without saving when no directory could be written to
+    cache_dir: Option<PathBuf>,
 }
 
 impl Context {
     pub const CACHE_DIR_NAME: &str = "deimos";
 
-    /// Save all context state and new data received for containers to the local cache directory
-    pub fn save(&self) {
-        self.save_state();
-        self.save_cached_pods();
+    /// Save all context state and new data received for containers to the local cache directory.
+    /// All files have been written once the returned future completes, so it must be awaited
+    /// before the process exits
+    pub async fn save(&self) {
+        self.save_state().await;
+        self.save_cached_pods().await;
     }
     
-    /// Wait for pod status notifications and update the local cache with their statuses as
-    /// required
-    pub async fn pod_event_loop(&self) -> ! {            
-        let mut sub = self.clients.settings.subscribe();
-        let mut token_sub = self.clients.token.subscribe();
+    /// Wait for pod status notifications from every server and update the local cache with
+    /// their statuses as required
+    pub async fn pod_event_loop(&self) -> ! {
+        self.for_each_server(|server| async move { self.server_event_loop(&server).await }).await
+    }
+
+    /// Wait for pod status notifications from a single server.
+    /// Falls back to polling the pod list when streams are repeatedly terminated early, or when
+    /// polling is forced by the user's settings
+    async fn server_event_loop(&self, server: &ContextServer) -> ! {
+        let mut sub = server.clients.settings.subscribe();
+        let mut token_sub = server.clients.token.subscribe();
+        let mut health = StreamHealth::default();
+        let mut sequence = StreamSequence::default();
+        let mut probe = false;
         loop {
+            if !probe && server.clients.polling() {
+                probe = self.poll_pod_loop(server, &mut sub, &mut token_sub).await;
+                continue
+            }
+
+            probe = false;
+
             let stream = {
-                let mut api = self.clients.podapi().await;
+                let mut api = server.clients.podapi().await;
                 let Some(ref mut api) = api else {
                     let timeout = sub.borrow_and_update().connect_timeout;
 
                     tokio::select! {
                         _ = sub.changed() => {},
                         _ = token_sub.changed() => {},
+                        _ = server.clients.retry_requested() => {},
                         _ = tokio::time::sleep(timeout) => {},
                     };
                     continue
@@ -59,133 +115,433 @@ impl Context {
                 Err(e) => {
                     if e.code() != tonic::Code::DeadlineExceeded {
                         let timeout = {
-                            let settings = self.clients.settings.read();
+                            let settings = server.clients.settings.read();
                             settings.connect_timeout
                         };
                         
                         tokio::select! {
                             _ = sub.changed() => {},
                             _ = token_sub.changed() => {},
+                            _ = server.clients.retry_requested() => {},
                             _ = tokio::time::sleep(timeout) => {},
                         };
                     }
-                    tracing::warn!("Failed to subscribe to pod status stream: {}", e);
+                    server.clients.errors.record(format!("Failed to subscribe to pod status stream of {}", server.label()), &e);
                     continue
                 }
             };
             
-            while let Some(event) = stream.next().await {
-                let event = match event {
-                    Ok(ev) => ev,
-                    Err(e) => {
-                        tracing::warn!("Error when receiving pod status stream: {}", e);
-                        break
+            sequence.opened();
+            let established = tokio::time::sleep(StreamHealth::ESTABLISHED);
+            tokio::pin!(established);
+            let mut is_established = false;
+
+            loop {
+                let event = tokio::select! {
+                    event = stream.next() => event,
+                    _ = &mut established, if !is_established => {
+                        is_established = true;
+                        health.established();
+                        if *server.clients.streaming_unreliable.read() {
+                            tracing::info!("Pod status stream of {} is established, switching back from polling", server.label());
+                            server.clients.streaming_unreliable.set(false);
+                        }
+
+                        continue
                     }
                 };
 
-                let pod_state = {
+                let mut event = match event {
+                    Some(Ok(ev)) => ev,
+                    Some(Err(e)) => {
+                        server.clients.errors.record(format!("Pod status stream of {} failed", server.label()), &e);
+                        break
+                    },
+                    None => break,
+                };
+
+                //The state of every pod is queried again after a gap, as the missed notifications
+                //may have been the last change to a pod's state
+                match sequence.received(event.epoch, event.sequence) {
+                    Some(StreamGap::Missed(missed)) => {
+                        tracing::warn!("Missed {} pod status notifications from {}, polling pod states", missed, server.label());
+                        self.poll_pod_list(server).await;
+                    },
+                    Some(StreamGap::Restarted) => {
+                        tracing::info!("Server {} restarted since the last pod status notification, polling pod states", server.label());
+                        //The server may have been upgraded with different capabilities
+                        self.refresh_server_info(server).await;
+                        self.poll_pod_list(server).await;
+                    },
+                    None => (),
+                }
+
+                let pod = {
                     let read = self.pods.read();
-                    read.get(&event.id).map(|pod| pod.data.up.clone())
+                    read.get(&Self::pod_key(server, &event.id)).cloned()
                 };
 
-                match pod_state {
-                    Some(up) => {
-                        tracing::trace!("Got pod status notification for {} - {:?}", event.id, event.state());
-                        up.set(CachedPodState::from(event.state()));
+                //Every notification carries the pod's next scheduled transition, firing alerts, and
+                //archived flag, including those sent when they change without a change of state
+                if let Some(ref pod) = pod {
+                    if *pod.data.archived.read() != event.archived {
+                        pod.data.archived.set(event.archived);
+                    }
+
+                    let next = event.next_transition.take().and_then(CachedPodTransition::from_proto);
+                    if *pod.data.next_transition.read() != next {
+                        pod.data.next_transition.set(next);
+                    }
+
+                    let alerts = CachedPodAlert::from_protos(std::mem::take(&mut event.alerts));
+                    if *pod.data.alerts.read() != alerts {
+                        for alert in alerts.iter().filter(|alert| !pod.data.alerts.read().contains(alert)) {
+                            tracing::warn!("Alert for pod {} fired: {}", event.id, alert.rule);
+                        }
+                        pod.data.alerts.set(alerts);
+                    }
+                }
+
+                match pod {
+                    Some(pod) if event.annotation.is_some() => {
+                        if let Some(annotation) = event.annotation.and_then(CachedPodAnnotation::from_proto) {
+                            tracing::trace!("Got annotation from pod {}: {}", event.id, annotation.message);
+                            pod.data.record_annotation(annotation);
+                        }
+                    },
+                    Some(pod) => {
+                        tracing::trace!("Got pod status notification for {} - {:?} ({:?})", event.id, event.state(), event.cause());
+                        pod.data.set_state(CachedPodState::from(event.state()));
+                        match event.state() {
+                            deimosproto::PodState::Disabled => {
+                                pod.data.enabled_until.set(None);
+                                pod.data.last_error.set(event.failure.take().and_then(CachedPodFailure::from_proto));
+                                pod.data.restart_breaker.set(event.restart_breaker.take().and_then(CachedPodRestartBreaker::from_proto));
+                                //Servers that do not report the stop time send notifications as
+                                //soon as the container is removed
+                                let stopped_at = chrono::DateTime::<chrono::Utc>::from_timestamp(event.stopped_at, 0)
+                                    .filter(|_| event.stopped_at != 0)
+                                    .unwrap_or_else(chrono::Utc::now);
+                                pod.data.stopped_at.set(Some(stopped_at));
+                            },
+                            deimosproto::PodState::Enabled => {
+                                if pod.data.last_error.read().is_some() {
+                                    pod.data.last_error.set(None);
+                                }
+                                if pod.data.restart_breaker.read().is_some() {
+                                    pod.data.restart_breaker.set(None);
+                                }
+                            },
+                            _ => (),
+                        }
+
+                        //Published ports are only resolved by the server once the container starts
+                        match event.state() {
+                            deimosproto::PodState::Enabled | deimosproto::PodState::Degraded => self.refresh_endpoints(server, &pod).await,
+                            deimosproto::PodState::Transit => (),
+                            _ => if !pod.data.endpoints.read().is_empty() {
+                                pod.data.endpoints.set(Vec::new());
+                            },
+                        }
                     },
                     None => {
                         tracing::warn!("Got pod status notification for unknown container {}", event.id);
                     }
                 }
             }
+
+            if !is_established && health.terminated(Instant::now()) && !*server.clients.streaming_unreliable.read() {
+                tracing::warn!("Pod status streams of {} are repeatedly terminated early, switching to polling", server.label());
+                server.clients.streaming_unreliable.set(true);
+            }
+        }
+    }
+
+    /// Poll the pod list at the configured interval until settings change or, when the stream
+    /// mode is automatic, until streaming should be attempted again.
+    /// Returns `true` if a stream should be opened to probe whether streaming has recovered
+    async fn poll_pod_loop(
+        &self,
+        server: &ContextServer,
+        sub: &mut tokio::sync::watch::Receiver<client::ContextSettings>,
+        token_sub: &mut tokio::sync::watch::Receiver<client::auth::TokenStatus>,
+    ) -> bool {
+        sub.borrow_and_update();
+        let probe = tokio::time::sleep(StreamHealth::PROBE_INTERVAL);
+        tokio::pin!(probe);
+
+        loop {
+            self.poll_pod_list(server).await;
+
+            let (mode, interval) = {
+                let settings = server.clients.settings.read();
+                (settings.stream_mode, settings.poll_interval)
+            };
+
+            tokio::select! {
+                _ = sub.changed() => return false,
+                _ = &mut probe, if mode == StreamMode::Auto => return true,
+                _ = token_sub.changed() => {},
+                _ = server.clients.retry_requested() => {},
+                _ = tokio::time::sleep(interval) => {},
+            }
         }
     }
     
     /// Attempt to update the status of the given pod
     pub async fn update(&self, pod: &CachedPod, up: CachedPodState) {
-        let Some(ref mut api) = self.clients.podapi().await else { return };
+        self.update_for(pod, up, None).await
+    }
+
+    /// Attempt to enable the given pod, requesting that the server disable it again after the
+    /// given duration
+    pub async fn enable_for(&self, pod: &CachedPod, duration: Duration) {
+        self.update_for(pod, CachedPodState::Enabled, Some(duration)).await
+    }
+
+    /// Attempt to update the status of the given pod, with an optional time limit when enabling.
+    /// The pod is shown in transit from the time the update is requested until the server
+    /// reports its new state, or until the request fails
+    async fn update_for(&self, pod: &CachedPod, up: CachedPodState, duration: Option<Duration>) {
+        let Some(server) = self.server_of(pod) else { return };
+        pod.data.begin_request(up);
+        let accepted = self.request_update(&server, pod, up, duration).await;
+        pod.data.end_request(accepted);
+    }
+
+    /// Request that the server update the given pod's state, returning `true` if the server
+    /// accepted the request.
+    /// Updates are made after any other mutation of the same pod that is already queued.
+    /// If the server cannot be reached and the user has opted in, the update is queued until the
+    /// connection is restored. Timed enables are never queued as the time limit would begin at an
+    /// unpredictable time
+    async fn request_update(&self, server: &ContextServer, pod: &CachedPod, up: CachedPodState, duration: Option<Duration>) -> bool {
+        let queueable = duration.is_none() && server.queues_offline_actions();
+        if queueable && *server.clients.conn.read() == ContextConnectionState::Error {
+            self.queue_action(pod, up);
+            return false
+        }
+
+        let Some(ref mut api) = server.clients.podapi().await else { return false };
         
         let request = deimosproto::UpdatePodRequest {
-            id: pod.data.id.clone(),
+            id: String::from(&pod.data.id),
             method: deimosproto::PodState::from(up) as i32,
+            duration_seconds: duration.map(|duration| duration.as_secs()).unwrap_or_default(),
         };
 
-        match api.update_pod(request).await {
+        let result = server
+            .requests
+            .mutate(&pod.data.id, server.request_limit(), api.update_pod(request))
+            .await;
+
+        match result {
             Ok(_) => {
                 tracing::trace!("Successfully updated pod {} state to {:?}", pod.data.id, up);
+                //The pod's state was set directly, superseding any queued change
+                self.unqueue(&pod.key());
+                let until = duration
+                    .and_then(|duration| chrono::TimeDelta::from_std(duration).ok())
+                    .and_then(|duration| chrono::Utc::now().checked_add_signed(duration));
+                pod.data.enabled_until.set(until);
+                if pod.data.update_error.read().is_some() {
+                    pod.data.update_error.set(None);
+                }
+
+                true
+            },
+            Err(e) if queueable && Self::is_unreachable(&e) => {
+                self.queue_action(pod, up);
+                false
             },
             Err(e) => {
-                pod.data.up.notify();
-                tracing::warn!("Failed to update pod {} state: {}", pod.data.id, e);
+                let error = server.clients.errors.record(format!("Failed to update pod {} state", pod.data.id), &e);
+                pod.data.update_error.set(Some(error));
+                match deimosproto::ErrorDetail::from_status(&e).map(|detail| detail.code()) {
+                    Some(deimosproto::ErrorCode::PodNotFound) => {
+                        tracing::warn!("Pod {} no longer exists on the server, removing it from the cache", pod.data.id);
+                        self.pods.modify(|pods| { pods.remove(&pod.key()); });
+                    },
+                    Some(deimosproto::ErrorCode::PodImageMissing) => {
+                        tracing::warn!("Pod {} cannot be enabled until its image is pulled on the server", pod.data.id);
+                        pod.data.image_missing.set(true);
+                    },
+                    _ => (),
+                }
+
+                false
             }
         }
     }
     
-    /// Query the server for a list of containers and update our local cache in response
-    pub async fn synchronize(&self) {
-        let Some(ref mut api) = self.clients.podapi().await else { return };
-        let brief = match api.query_pods(deimosproto::QueryPodsRequest {}).await {
-            Ok(r) => r.into_inner(),
+    /// Attempt to restart the given pod, showing it in transit until the server accepts the
+    /// request. Restarts are never queued while the server is unreachable
+    pub async fn restart(&self, pod: &CachedPod) {
+        let Some(server) = self.server_of(pod) else { return };
+        let Some(ref mut api) = server.clients.podapi().await else { return };
+
+        pod.data.begin_request(CachedPodState::Enabled);
+        let request = deimosproto::RestartPodRequest {
+            id: String::from(&pod.data.id),
+        };
+
+        let result = server
+            .requests
+            .mutate(&pod.data.id, server.request_limit(), api.restart_pod(request))
+            .await;
+
+        let accepted = match result {
+            Ok(_) => {
+                tracing::trace!("Requested restart of pod {}", pod.data.id);
+                if pod.data.update_error.read().is_some() {
+                    pod.data.update_error.set(None);
+                }
+
+                true
+            },
             Err(e) => {
-                tracing::warn!("Failed to query pods from server: {}", e);
-                return
+                let error = server.clients.errors.record(format!("Failed to restart pod {}", pod.data.id), &e);
+                pod.data.update_error.set(Some(error));
+                false
             }
         };
 
-        self.pods.modify(|pods| {
-            for pod in brief.pods {
-                match pods.get_mut(&pod.id) {
-                    Some(exist) => {
-                        exist.data.up.set(pod.state().into());
-                        exist.data.name.set(pod.title);
-                    },
-                    None => {
-                        tracing::trace!("Received new pod {} from server", pod.id);
-                        let data = CachedPodData {
-                            up: NotifyMutation::new(CachedPodState::from(pod.state())),
-                            id: pod.id,
-                            name: NotifyMutation::new(pod.title),
-                        };
+        pod.data.end_request(accepted);
+    }
 
-                        let pod = CachedPod {
-                            data,
-                        };
+    /// Query the addresses that the given pod's published ports can be reached at from the server
+    async fn refresh_endpoints(&self, server: &ContextServer, pod: &CachedPod) {
+        let Some(ref mut api) = server.clients.podapi().await else { return };
 
-                        pods.insert(pod.data.id.clone(), Arc::new(pod));
-                    }
+        let request = deimosproto::PodDetailsRequest { id: String::from(&pod.data.id) };
+        match server.requests.request(server.request_limit(), api.get_pod_details(request)).await {
+            Ok(details) => {
+                let endpoints = details
+                    .into_inner()
+                    .brief
+                    .map(|brief| brief.endpoints.into_iter().filter_map(PodEndpoint::from_proto).collect())
+                    .unwrap_or_default();
+
+                pod.data.endpoints.set(endpoints);
+            },
+            Err(e) => {
+                server.clients.errors.record(format!("Failed to get endpoints of pod {}", pod.data.id), &e);
+            },
+        }
+    }
+
+    /// Skip the next transition planned by the given pod's schedule, or restore it if it has
+    /// already been skipped
+    pub async fn skip_scheduled(&self, pod: &CachedPod, skip: bool) {
+        let Some(server) = self.server_of(pod) else { return };
+        let Some(ref mut api) = server.clients.podapi().await else { return };
+
+        let request = deimosproto::OverrideScheduleRequest {
+            id: String::from(&pod.data.id),
+            skip_next: skip,
+        };
+
+        let result = server
+            .requests
+            .mutate(&pod.data.id, server.request_limit(), api.override_schedule(request))
+            .await;
+
+        match result {
+            Ok(response) => {
+                let next = response.into_inner().next_transition.and_then(CachedPodTransition::from_proto);
+                tracing::trace!("Overrode schedule of pod {}, next transition is {:?}", pod.data.id, next);
+                pod.data.next_transition.set(next);
+            },
+            Err(e) => match deimosproto::ErrorDetail::from_status(&e).map(|detail| detail.code()) {
+                Some(deimosproto::ErrorCode::PodNotScheduled) => {
+                    tracing::trace!("Pod {} is no longer scheduled", pod.data.id);
+                    pod.data.next_transition.set(None);
+                },
+                _ => {
+                    pod.data.next_transition.notify();
+                    server.clients.errors.record(format!("Failed to override schedule of pod {}", pod.data.id), &e);
                 }
             }
-        });
+        }
     }
 
-    /// Load all context state from the local cache directory and begin connection attempts to the
-    /// gRPC server with the loaded settings
-    pub async fn load() -> Self {
-        let cache_dir = match dirs::cache_dir() {
-            Some(dir) => dir.join(Self::CACHE_DIR_NAME),
-            None => PathBuf::from("./deimos-cache"),
+    /// Load all context state and cached pods from the local cache directory without contacting
+    /// the server, so that the last known pods can be shown before any connection is made.
+    /// Connections are started separately by [init](Self::init).
+    /// If no cache directory can be written to, `choose` is called with the directories that
+    /// were tried to select another directory or continue without saving. Returns `None` if the
+    /// user chose to quit instead
+    pub async fn load<F, C>(choose: C) -> Option<Self>
+    where
+        C: FnMut(&[CacheDirProbeError]) -> F,
+        F: std::future::Future<Output = CacheDirChoice>,
+    {
+        let cache_dir = match Self::select_cache_dir(choose).await {
+            CacheDirChoice::Directory(dir) => Some(dir),
+            CacheDirChoice::Ephemeral => None,
+            CacheDirChoice::Quit => return None,
         };
 
-        let persistent = match Self::load_state(&cache_dir) {
-            Ok(state) => state,
-            Err(e) => {
-                tracing::error!("Failed to load application state: {e}");
-                ContextPersistent::default()
-            }
+        let (persistent, cached) = match cache_dir {
+            Some(ref dir) => {
+                let persistent = Self::load_state(dir).await;
+                let servers = persistent.servers.iter().map(|server| server.id.clone()).collect::<Vec<_>>();
+                (persistent, Self::load_cached_pods(dir.clone(), &servers).await)
+            },
+            None => (ContextPersistent::default(), HashMap::default()),
         };
-        
-        let pods = NotifyMutation::new(HashMap::default());
-        let clients = ContextClients::new(persistent).await;
 
-        Self {
-            pods,
-            clients,
+        let errors = NotifyMutation::new(ErrorLog::default());
+        let servers = persistent
+            .servers
+            .into_iter()
+            .map(|server| Arc::new(ContextServer::new(server, errors.clone())))
+            .collect();
+
+        Some(Self {
+            pods: NotifyMutation::new(cached),
+            servers: NotifyMutation::new(servers),
+            errors,
+            summary: NotifyMutation::new(HostSummary::default()),
+            filter: NotifyMutation::new(None),
+            queue: NotifyMutation::new(persistent.queued),
+            actions: NotifyMutation::new(VecDeque::new()),
+            terminal: NotifyMutation::new(None),
+            logs: NotifyMutation::new(None),
             cache_dir,
-        }
+        })
     }
 
+    /// Create the API client of every server with the loaded settings, called once the cached
+    /// pods have been drawn so that the first frame never waits on the network
     pub async fn init(&self) {
-        self.pods.set(Self::load_cached_pods(self.cache_dir.clone()).await);
+        let servers = self.servers.read().clone();
+        for server in servers {
+            server.clients.connect().await;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Context {
+    /// Create a context with a single server that is never connected and no cache directory
+    pub(crate) fn test() -> (Self, Arc<ContextServer>) {
+        let errors = NotifyMutation::new(ErrorLog::default());
+        let server = Arc::new(ContextServer::new(server::PersistentServer::default(), errors.clone()));
+        let context = Self {
+            pods: NotifyMutation::new(HashMap::new()),
+            servers: NotifyMutation::new(vec![server.clone()]),
+            errors,
+            summary: NotifyMutation::new(HostSummary::default()),
+            filter: NotifyMutation::new(None),
+            queue: NotifyMutation::new(Vec::new()),
+            actions: NotifyMutation::new(VecDeque::new()),
+            terminal: NotifyMutation::new(None),
+            logs: NotifyMutation::new(None),
+            cache_dir: None,
+        };
+
+        (context, server)
     }
 }
 
@@ -1,22 +1,177 @@
 use std::{
-    collections::HashMap, io::Write, path::{Path, PathBuf}, sync::Arc
+    borrow::Borrow, collections::{BTreeMap, HashMap}, fmt, path::{Path, PathBuf}, sync::Arc
 };
 
-use super::{Context, NotifyMutation};
+use chrono::{DateTime, Utc};
+use deimos_client_lib::pod::PodEndpoint;
+
+use super::{confirm::CachedPodConfirmLevel, error::ErrorRecord, server::ServerId, Context, NotifyMutation};
+
+/// ID of a pod as assigned by the server, used to key all per-pod client state
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct PodRef(Arc<str>);
+
+/// Key of a pod in the [Context]'s pod map, as pods on different servers may share an ID
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PodKey {
+    pub server: ServerId,
+    pub pod: PodRef,
+}
 
 /// Data received from a server about a single container, cached locally.
 /// Contains iced handles for resources used to display the container.
 #[derive(Debug, Clone)]
 pub struct CachedPod {
+    /// Server that the pod was received from
+    pub server: ServerId,
     pub data: CachedPodData,
 }
 
 /// Data to be serialized in a local cache file for a container
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CachedPodData {
-    pub id: String,
+    pub id: PodRef,
     pub name: NotifyMutation<String>,
+    /// State of the pod last reported by the server
     pub up: NotifyMutation<CachedPodState>,
+    /// Target state of an update requested by the user that the server has not yet reported a
+    /// new state for, during which the pod is shown in transit
+    #[serde(skip)]
+    pub requested: NotifyMutation<Option<CachedPodState>>,
+    /// Set while the update request for [requested](Self::requested) awaits the server's
+    /// response, during which only a notification of the requested state ends the transition
+    #[serde(skip)]
+    pub(super) request_in_flight: NotifyMutation<bool>,
+    /// Next transition planned by the pod's schedule on the server
+    #[serde(default)]
+    pub next_transition: NotifyMutation<Option<CachedPodTransition>>,
+    /// Time that the server will automatically disable the pod at, if it was enabled for a
+    /// limited duration
+    #[serde(default)]
+    pub enabled_until: NotifyMutation<Option<DateTime<Utc>>>,
+    /// Set if the pod has been archived on the server and cannot be enabled
+    #[serde(default)]
+    pub archived: NotifyMutation<bool>,
+    /// Set if the pod's image has not been pulled on the server, preventing it from being enabled
+    #[serde(default)]
+    pub image_missing: NotifyMutation<bool>,
+    /// Set if the server allows interactive terminal sessions in the pod
+    #[serde(default)]
+    pub attachable: NotifyMutation<bool>,
+    /// Confirmation that the server asks for before the pod is stopped or restarted
+    #[serde(default)]
+    pub confirm_level: NotifyMutation<CachedPodConfirmLevel>,
+    /// Most recent failure of the pod's container since it was last enabled
+    #[serde(default)]
+    pub last_error: NotifyMutation<Option<CachedPodFailure>>,
+    /// Time that the pod's container was last stopped, whether it was disabled or failed
+    #[serde(default)]
+    pub stopped_at: NotifyMutation<Option<DateTime<Utc>>>,
+    /// Set while the server's restart policy has stopped restarting the pod after its container
+    /// failed too often
+    #[serde(default)]
+    pub restart_breaker: NotifyMutation<Option<CachedPodRestartBreaker>>,
+    /// Error returned by the most recent request to update the pod's state, if it failed
+    #[serde(skip)]
+    pub update_error: NotifyMutation<Option<Arc<ErrorRecord>>>,
+    /// Annotations reported from inside the pod's container since the client connected, oldest
+    /// first
+    #[serde(skip)]
+    pub annotations: NotifyMutation<Vec<CachedPodAnnotation>>,
+    /// Resource usage alerts of the pod that are firing on the server
+    #[serde(skip)]
+    pub alerts: NotifyMutation<Vec<CachedPodAlert>>,
+    /// Addresses that the pod's published ports can be reached at while it is enabled
+    #[serde(skip)]
+    pub endpoints: NotifyMutation<Vec<PodEndpoint>>,
+    /// Image shown next to the pod, if the server gives one
+    #[serde(default)]
+    pub icon: NotifyMutation<Option<CachedPodImage>>,
+    /// Wide image shown with the pod's details, if the server gives one
+    #[serde(default)]
+    pub banner: NotifyMutation<Option<CachedPodImage>>,
+}
+
+/// An icon or banner image of a pod. The contents are saved next to the pod's cached metadata
+/// and loaded with it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedPodImage {
+    /// Digest of the image contents given by the server, sent back to skip downloading an
+    /// unchanged image
+    pub hash: String,
+    pub format: CachedPodImageFormat,
+    #[serde(skip)]
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CachedPodImageFormat {
+    Png,
+    Jpeg,
+    Svg,
+    Gif,
+}
+
+/// An event reported by the application running inside a pod's container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedPodAnnotation {
+    pub at: DateTime<Utc>,
+    pub level: deimosproto::PodAnnotationLevel,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+}
+
+/// A resource usage alert that is firing for a pod on the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedPodAlert {
+    /// Human-readable description of the alert rule
+    pub rule: String,
+    pub since: DateTime<Utc>,
+}
+
+/// Unexpected exit or start failure of a pod's container, with the container's final output
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedPodFailure {
+    pub at: DateTime<Utc>,
+    #[serde(default)]
+    pub kind: CachedPodFailureKind,
+    pub reason: String,
+    /// End of the container's output, empty if the server could not read it
+    pub output: String,
+}
+
+/// How a pod's container failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CachedPodFailureKind {
+    /// Reported by a server that does not distinguish failures, or cached before the kind of
+    /// failure was recorded
+    #[default]
+    Unknown,
+    /// The container exited on its own, with its exit code if the server could read it
+    Exited(Option<i64>),
+    OutOfMemory,
+    Killed,
+    StartFailed,
+}
+
+/// Restarts of a pod stopped by the server's restart policy after its container failed too often
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedPodRestartBreaker {
+    /// Number of failures counted within the restart policy's window
+    pub failures: u32,
+    pub opened: DateTime<Utc>,
+    /// Time that the server will make a single probe restart at, if it will make one
+    pub probe_at: Option<DateTime<Utc>>,
+    pub last_error: String,
+}
+
+/// A transition planned by a pod's schedule on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedPodTransition {
+    pub action: CachedPodState,
+    pub at: DateTime<Utc>,
+    pub skipped: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -25,21 +180,35 @@ pub enum CachedPodState {
     Transit,
     Paused,
     Enabled,
+    /// Enabled, but failing its health check
+    Degraded,
+}
+
+impl CachedPodState {
+    /// Check if a pod in this state has completed a transition to the given state, treating a
+    /// degraded pod as enabled
+    pub fn satisfies(self, target: CachedPodState) -> bool {
+        self == target || (self == Self::Degraded && target == Self::Enabled)
+    }
 }
 
 impl Context {
     /// Save all cached pod state to the local cache directory
-    pub fn save_cached_pods(&self) {
-        for container in self.pods.read().values() {
-            if let Err(e) = container.save(&self.cache_dir) {
+    pub async fn save_cached_pods(&self) {
+        let Some(ref cache_dir) = self.cache_dir else { return };
+        let pods = self.pods.read().values().cloned().collect::<Vec<_>>();
+        for container in pods {
+            if let Err(e) = container.save(cache_dir).await {
                 tracing::error!("Failed to save container {}: {}", container.data.id, e);
             }
         }
     }
 
-    /// Attempt to load all pods from the given local cache directory
-    pub(super) async fn load_cached_pods(cache_dir: PathBuf) -> HashMap<String, Arc<CachedPod>> {
-        if !cache_dir.exists() {
+    /// Attempt to load the pods of every given server from the local cache directory.
+    /// Pods cached before multiple servers were supported are moved into the directory of the
+    /// first server, as they were received from the server that it was migrated from
+    pub(super) async fn load_cached_pods(cache_dir: PathBuf, servers: &[ServerId]) -> HashMap<PodKey, Arc<CachedPod>> {
+        if !tokio::fs::try_exists(&cache_dir).await.unwrap_or(false) {
             if let Err(e) = tokio::fs::create_dir(&cache_dir).await {
                 tracing::error!(
                     "Failed to create cache directory '{}': {}",
@@ -49,12 +218,69 @@ impl Context {
             }
         }
 
-        let mut iter = match tokio::fs::read_dir(&cache_dir).await {
+        let servers_dir = cache_dir.join(Self::SERVERS_DIR_NAME);
+        if !tokio::fs::try_exists(&servers_dir).await.unwrap_or(true) {
+            if let Some(first) = servers.first() {
+                Self::migrate_cached_pods(&cache_dir, &Self::server_dir(&cache_dir, first)).await;
+            }
+        }
+
+        let mut pods = HashMap::default();
+        for server in servers {
+            pods.extend(Self::load_server_pods(server, &Self::server_dir(&cache_dir, server)).await);
+        }
+
+        pods
+    }
+
+    /// Move every cached pod directory located directly in the cache directory into the given
+    /// server's directory
+    async fn migrate_cached_pods(cache_dir: &Path, server_dir: &Path) {
+        if let Err(e) = tokio::fs::create_dir_all(server_dir).await {
+            tracing::error!("Failed to create cached pods directory {}: {}", server_dir.display(), e);
+            return
+        }
+
+        let mut iter = match tokio::fs::read_dir(cache_dir).await {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!("Failed to read cache directory {}: {}", cache_dir.display(), e);
+                return
+            }
+        };
+
+        loop {
+            let entry = match iter.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("Failed to get entry from directory {}: {}", cache_dir.display(), e);
+                    continue
+                }
+            };
+
+            let path = entry.path();
+            if entry.file_name() == Self::SERVERS_DIR_NAME || !tokio::fs::try_exists(path.join(CachedPod::METADATA_FILE)).await.unwrap_or(false) {
+                continue
+            }
+
+            let moved = server_dir.join(entry.file_name());
+            match tokio::fs::rename(&path, &moved).await {
+                Ok(()) => tracing::info!("Moved cached pod {} to {}", path.display(), moved.display()),
+                Err(e) => tracing::error!("Failed to move cached pod {} to {}: {}", path.display(), moved.display(), e),
+            }
+        }
+    }
+
+    /// Load the cached pods of a single server from the server's directory
+    async fn load_server_pods(server: &ServerId, dir: &Path) -> HashMap<PodKey, Arc<CachedPod>> {
+        let mut iter = match tokio::fs::read_dir(dir).await {
             Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::default(),
             Err(e) => {
                 tracing::error!(
                     "Failed to read cached pods directory {}: {}",
-                    cache_dir.display(),
+                    dir.display(),
                     e
                 );
                 return HashMap::default();
@@ -70,7 +296,7 @@ impl Context {
                 Err(e) => {
                     tracing::warn!(
                         "Failed to get entry from directory {}: {}",
-                        cache_dir.display(),
+                        dir.display(),
                         e
                     );
                     continue;
@@ -95,8 +321,8 @@ impl Context {
                         }
                     };
 
-                    let full = CachedPod::load(meta, &path).await;
-                    pods.insert(full.data.id.clone(), Arc::new(full));
+                    let full = CachedPod::load(server.clone(), meta, &path).await;
+                    pods.insert(full.key(), Arc::new(full));
                 }
                 Ok(_) => (),
                 Err(e) => {
@@ -128,39 +354,51 @@ impl CachedPodData {
     }
 
     /// Write cached container metadata to a local cache directory
-    fn save(&self, directory: &Path) -> Result<(), CachedPodSaveError> {
+    async fn save(&self, directory: &Path) -> Result<(), CachedPodSaveError> {
         let meta_path = directory.join(CachedPod::METADATA_FILE);
 
-        let mut file = std::fs::File::create(&meta_path).map_err(|err| CachedPodSaveError::IO {
-            path: meta_path.clone(),
-            err,
-        })?;
-
         let bytes = serde_json::to_vec(self)?;
-        file.write_all(&bytes)
+        tokio::fs::write(&meta_path, bytes)
+            .await
             .map_err(|err| CachedPodSaveError::IO {
                 path: meta_path,
                 err,
-            })?;
-
-        Ok(())
+            })
     }
 }
 
 impl CachedPod {
     const METADATA_FILE: &str = "meta.json";
+    pub(super) const ICON_FILE: &str = "icon";
+    pub(super) const BANNER_FILE: &str = "banner";
 
     /// Load a cached container from a local cache directory
-    async fn load(data: CachedPodData, directory: &Path) -> Self {
+    async fn load(server: ServerId, data: CachedPodData, directory: &Path) -> Self {
         tracing::trace!("Loading cached container from {}", directory.display());
 
-        Self { data }
+        //Images that cannot be read are dropped so that they are downloaded again
+        for (image, file) in [(&data.icon, Self::ICON_FILE), (&data.banner, Self::BANNER_FILE)] {
+            let Some(mut cached) = image.read().clone() else { continue };
+            let path = directory.join(file);
+            match tokio::fs::read(&path).await {
+                Ok(contents) => {
+                    cached.data = Arc::from(contents);
+                    image.set(Some(cached));
+                },
+                Err(e) => {
+                    tracing::warn!("Failed to load cached image {}: {}", path.display(), e);
+                    image.set(None);
+                },
+            }
+        }
+
+        Self { server, data }
     }
 
     /// Save all state to the filesystem, creating cache directories as required
-    fn save(&self, cache_dir: &Path) -> Result<(), CachedPodSaveError> {
+    pub(super) async fn save(&self, cache_dir: &Path) -> Result<(), CachedPodSaveError> {
         let dir = self.directory(cache_dir);
-        if let Err(e) = std::fs::create_dir(&dir) {
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
             if e.kind() != std::io::ErrorKind::AlreadyExists {
                 tracing::warn!(
                     "Failed to create directory '{}' for pod {}: {}",
@@ -172,14 +410,92 @@ impl CachedPod {
         }
 
         tracing::trace!("Saving pod {} to {}", self.data.id, dir.display());
-        self.data.save(&dir)?;
+        self.data.save(&dir).await?;
 
         Ok(())
     }
 
     /// Get the directory that cache files for this container should be placed into
-    fn directory(&self, cache_dir: &Path) -> PathBuf {
-        cache_dir.join(&self.data.id)
+    pub(super) fn directory(&self, cache_dir: &Path) -> PathBuf {
+        Context::server_dir(cache_dir, &self.server).join(self.data.id.as_str())
+    }
+
+    /// Get the key of this pod in the [Context]'s pod map
+    pub fn key(&self) -> PodKey {
+        PodKey { server: self.server.clone(), pod: self.data.id.clone() }
+    }
+}
+
+/// Keep values bound to cached pods, such as the widgets displaying them, in step with the
+/// given pods. Values of removed pods are dropped, and values of pods that were removed and added
+/// again under the same key are replaced, as they are bound to the old cached pod. Values are
+/// created with `bind` for pods that have none, while pods that were only renamed keep theirs
+pub fn rebind_pods<B>(
+    bound: &mut BTreeMap<PodKey, B>,
+    pods: &HashMap<PodKey, Arc<CachedPod>>,
+    pod_of: impl Fn(&B) -> &Arc<CachedPod>,
+    mut bind: impl FnMut(&Arc<CachedPod>) -> B,
+) {
+    bound.retain(|key, value| pods.get(key).is_some_and(|pod| Arc::ptr_eq(pod, pod_of(value))));
+    for (key, pod) in pods {
+        bound.entry(key.clone()).or_insert_with(|| bind(pod));
+    }
+}
+
+impl PodRef {
+    /// Get the ID as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for PodRef {
+    fn from(value: String) -> Self {
+        Self(Arc::from(value))
+    }
+}
+
+impl From<&PodRef> for String {
+    fn from(value: &PodRef) -> Self {
+        value.0.to_string()
+    }
+}
+
+impl Borrow<str> for PodRef {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PodKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.server, self.pod)
+    }
+}
+
+impl fmt::Display for PodRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl CachedPodImage {
+    /// Convert an image received from the server, returning `None` if the server did not send
+    /// the image's contents or its format is not known
+    pub fn from_proto(image: deimosproto::PodImage) -> Option<Self> {
+        let format = match deimosproto::PodImageFormat::try_from(image.format).ok()? {
+            deimosproto::PodImageFormat::Png => CachedPodImageFormat::Png,
+            deimosproto::PodImageFormat::Jpeg => CachedPodImageFormat::Jpeg,
+            deimosproto::PodImageFormat::Svg => CachedPodImageFormat::Svg,
+            deimosproto::PodImageFormat::Gif => CachedPodImageFormat::Gif,
+            deimosproto::PodImageFormat::Unknown => return None,
+        };
+
+        (!image.data.is_empty()).then(|| Self {
+            hash: image.hash,
+            format,
+            data: Arc::from(image.data),
+        })
     }
 }
 
@@ -190,8 +506,139 @@ impl From<deimosproto::PodState> for CachedPodState {
             deimosproto::PodState::Transit => Self::Transit,
             deimosproto::PodState::Paused => Self::Paused,
             deimosproto::PodState::Enabled => Self::Enabled,
+            deimosproto::PodState::Degraded => Self::Degraded,
+        }
+    }
+}
+
+impl CachedPodData {
+    /// Maximum number of annotations kept for each pod
+    const ANNOTATION_HISTORY: usize = 20;
+
+    /// Get the state that the pod is shown in, which is [CachedPodState::Transit] while an
+    /// update requested by the user is in flight
+    pub fn shown_state(&self) -> CachedPodState {
+        match *self.requested.read() {
+            Some(_) => CachedPodState::Transit,
+            None => *self.up.read(),
+        }
+    }
+
+    /// Set the state of the pod reported by the server. The transition of a requested update
+    /// ends when the pod reaches the requested state, or when any settled state is reported
+    /// after the server responded to the request, as the pod may have been changed since
+    pub fn set_state(&self, up: CachedPodState) {
+        self.up.set(up);
+        let target = *self.requested.read();
+        let settled = !*self.request_in_flight.read() && up != CachedPodState::Transit;
+        if target.is_some_and(|target| up.satisfies(target) || settled) {
+            self.requested.set(None);
+        }
+    }
+
+    /// Show the pod in transit to the given state until the update request completes
+    pub(super) fn begin_request(&self, target: CachedPodState) {
+        self.request_in_flight.set(true);
+        self.requested.set(Some(target));
+    }
+
+    /// End the transition shown for an update request once it has completed. Accepted updates
+    /// stay in transit until the server reports the pod's new state, while failed updates
+    /// immediately show the state last reported by the server
+    pub(super) fn end_request(&self, accepted: bool) {
+        self.request_in_flight.set(false);
+        let target = *self.requested.read();
+        if target.is_some_and(|target| !accepted || self.up.read().satisfies(target)) {
+            self.requested.set(None);
         }
     }
+
+    /// Record an annotation received from the server, discarding the oldest annotation if too
+    /// many are kept
+    pub fn record_annotation(&self, annotation: CachedPodAnnotation) {
+        self.annotations.modify(|annotations| {
+            if annotations.len() >= Self::ANNOTATION_HISTORY {
+                annotations.remove(0);
+            }
+
+            annotations.push(annotation);
+        });
+    }
+}
+
+impl CachedPodAnnotation {
+    /// Decode an annotation received from the server
+    pub fn from_proto(proto: deimosproto::PodAnnotation) -> Option<Self> {
+        let at = DateTime::<Utc>::from_timestamp(proto.at, 0)?;
+        let level = proto.level();
+        let mut fields = proto.fields.into_iter().collect::<Vec<_>>();
+        fields.sort();
+
+        Some(Self {
+            at,
+            level,
+            message: proto.message,
+            fields,
+        })
+    }
+}
+
+impl CachedPodFailure {
+    /// Decode a container failure received from the server
+    pub fn from_proto(proto: deimosproto::PodFailure) -> Option<Self> {
+        let kind = match proto.kind() {
+            deimosproto::PodFailureKind::Unknown => CachedPodFailureKind::Unknown,
+            deimosproto::PodFailureKind::Exited => CachedPodFailureKind::Exited(proto.exit_code),
+            deimosproto::PodFailureKind::OutOfMemory => CachedPodFailureKind::OutOfMemory,
+            deimosproto::PodFailureKind::Killed => CachedPodFailureKind::Killed,
+            deimosproto::PodFailureKind::StartFailed => CachedPodFailureKind::StartFailed,
+        };
+
+        Some(Self {
+            at: DateTime::<Utc>::from_timestamp(proto.at, 0)?,
+            kind,
+            reason: proto.reason,
+            output: proto.output,
+        })
+    }
+}
+
+impl CachedPodRestartBreaker {
+    /// Decode a restart breaker received from the server
+    pub fn from_proto(proto: deimosproto::PodRestartBreaker) -> Option<Self> {
+        Some(Self {
+            failures: proto.failures,
+            opened: DateTime::<Utc>::from_timestamp(proto.opened, 0)?,
+            probe_at: DateTime::<Utc>::from_timestamp(proto.probe_at, 0).filter(|_| proto.probe_at != 0),
+            last_error: proto.last_error,
+        })
+    }
+}
+
+impl CachedPodTransition {
+    /// Decode a scheduled transition received from the server
+    pub fn from_proto(proto: deimosproto::PodScheduledTransition) -> Option<Self> {
+        Some(Self {
+            action: proto.action().into(),
+            at: DateTime::<Utc>::from_timestamp(proto.at, 0)?,
+            skipped: proto.skipped,
+        })
+    }
+}
+
+impl CachedPodAlert {
+    /// Decode a firing alert received from the server
+    pub fn from_proto(proto: deimosproto::PodAlert) -> Option<Self> {
+        Some(Self {
+            since: DateTime::<Utc>::from_timestamp(proto.since, 0)?,
+            rule: proto.rule,
+        })
+    }
+
+    /// Decode all firing alerts received from the server, skipping those with invalid times
+    pub fn from_protos(protos: Vec<deimosproto::PodAlert>) -> Vec<Self> {
+        protos.into_iter().filter_map(Self::from_proto).collect()
+    }
 }
 
 impl From<CachedPodState> for deimosproto::PodState {
@@ -201,6 +648,7 @@ impl From<CachedPodState> for deimosproto::PodState {
             CachedPodState::Disabled => deimosproto::PodState::Disabled,
             CachedPodState::Paused => deimosproto::PodState::Paused,
             CachedPodState::Enabled => deimosproto::PodState::Enabled,
+            CachedPodState::Degraded => deimosproto::PodState::Degraded,
         }
     }
 }
@@ -229,3 +677,110 @@ pub enum CachedPodSaveError {
     #[error("Failed to serialize pod state: {0}")]
     Encode(#[from] serde_json::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(up: CachedPodState) -> CachedPodData {
+        serde_json::from_value(serde_json::json!({ "id": "pod", "name": "Pod", "up": up })).unwrap()
+    }
+
+    #[test]
+    fn notifications_in_flight_only_end_at_target() {
+        let pod = pod(CachedPodState::Disabled);
+        pod.begin_request(CachedPodState::Enabled);
+
+        for up in [CachedPodState::Transit, CachedPodState::Disabled, CachedPodState::Paused] {
+            pod.set_state(up);
+            assert_eq!(*pod.requested.read(), Some(CachedPodState::Enabled), "ended by {:?}", up);
+            assert_eq!(pod.shown_state(), CachedPodState::Transit);
+        }
+
+        pod.set_state(CachedPodState::Degraded);
+        assert_eq!(*pod.requested.read(), None);
+        pod.end_request(true);
+        assert_eq!(pod.shown_state(), CachedPodState::Degraded);
+    }
+
+    #[test]
+    fn accepted_request_waits_for_notification() {
+        let pod = pod(CachedPodState::Enabled);
+        pod.begin_request(CachedPodState::Disabled);
+        pod.end_request(true);
+        assert_eq!(pod.shown_state(), CachedPodState::Transit);
+
+        pod.set_state(CachedPodState::Transit);
+        assert_eq!(*pod.requested.read(), Some(CachedPodState::Disabled));
+
+        //Once the server responded, any settled state is authoritative
+        pod.set_state(CachedPodState::Enabled);
+        assert_eq!(*pod.requested.read(), None);
+        assert_eq!(pod.shown_state(), CachedPodState::Enabled);
+    }
+
+    #[test]
+    fn notification_before_response_ends_request() {
+        let pod = pod(CachedPodState::Enabled);
+        pod.begin_request(CachedPodState::Disabled);
+        pod.set_state(CachedPodState::Disabled);
+        assert_eq!(*pod.requested.read(), None);
+
+        pod.end_request(true);
+        assert_eq!(pod.shown_state(), CachedPodState::Disabled);
+    }
+
+    #[test]
+    fn failed_request_ends_immediately() {
+        let pod = pod(CachedPodState::Disabled);
+        pod.begin_request(CachedPodState::Enabled);
+        pod.end_request(false);
+        assert_eq!(*pod.requested.read(), None);
+        assert_eq!(pod.shown_state(), CachedPodState::Disabled);
+    }
+
+    #[test]
+    fn degraded_satisfies_enabled() {
+        assert!(CachedPodState::Degraded.satisfies(CachedPodState::Enabled));
+        assert!(CachedPodState::Enabled.satisfies(CachedPodState::Enabled));
+        assert!(!CachedPodState::Enabled.satisfies(CachedPodState::Degraded));
+        assert!(!CachedPodState::Transit.satisfies(CachedPodState::Disabled));
+    }
+
+    fn cached(id: &str) -> Arc<CachedPod> {
+        let data = serde_json::from_value(serde_json::json!({ "id": id, "name": id, "up": CachedPodState::Disabled })).unwrap();
+        Arc::new(CachedPod { server: serde_json::from_value(serde_json::json!("server")).unwrap(), data })
+    }
+
+    fn rebind(bound: &mut BTreeMap<PodKey, Arc<CachedPod>>, pods: &[&Arc<CachedPod>]) -> usize {
+        let pods = pods.iter().map(|pod| (pod.key(), Arc::clone(pod))).collect::<HashMap<_, _>>();
+        let mut created = 0;
+        rebind_pods(bound, &pods, |value| value, |pod| {
+            created += 1;
+            pod.clone()
+        });
+        created
+    }
+
+    #[test]
+    fn rebinding_follows_pods() {
+        let (a, b) = (cached("a"), cached("b"));
+        let mut bound = BTreeMap::new();
+        assert_eq!(rebind(&mut bound, &[&a, &b]), 2);
+
+        //Renamed pods keep their values
+        a.data.name.set("Renamed".to_owned());
+        assert_eq!(rebind(&mut bound, &[&a, &b]), 0);
+        assert!(Arc::ptr_eq(&bound[&a.key()], &a));
+
+        //Removed pods drop their values
+        assert_eq!(rebind(&mut bound, &[&a]), 0);
+        assert_eq!(bound.len(), 1);
+        assert!(!bound.contains_key(&b.key()));
+
+        //Pods added again under the same key are bound to the new cached pod
+        let readded = cached("a");
+        assert_eq!(rebind(&mut bound, &[&readded]), 1);
+        assert!(Arc::ptr_eq(&bound[&a.key()], &readded));
+    }
+}
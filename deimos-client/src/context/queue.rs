@@ -0,0 +1,450 @@
+//! Pod state changes requested while the server cannot be reached, held until the connection is
+//! restored when enabled in the user's settings. Queued actions are only applied if the pod is
+//! still in the state it was in when the action was queued, so changes made on the server in the
+//! meantime are reported as conflicts rather than overwritten
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use super::{client::ContextConnectionState, pod::{CachedPod, CachedPodState, PodKey, PodRef}, server::{ContextServer, ServerId}, Context};
+
+/// A change of a pod's state requested while disconnected
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct QueuedAction {
+    /// Server that the pod belongs to, unassigned for actions queued before multiple servers
+    /// were supported until they are loaded
+    #[serde(default)]
+    pub server: ServerId,
+    pub pod: PodRef,
+    /// Name of the pod when the action was queued, shown if the pod is later removed
+    pub name: String,
+    /// Last known state of the pod when the action was queued
+    pub from: CachedPodState,
+    pub target: CachedPodState,
+    pub queued_at: DateTime<Utc>,
+}
+
+/// Result of a queued action, kept in the [Context]'s action history
+#[derive(Debug, Clone)]
+pub struct ActionRecord {
+    pub action: QueuedAction,
+    pub outcome: ActionOutcome,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionOutcome {
+    Applied,
+    /// The pod was already in the target state once synchronized with the server
+    AlreadySatisfied,
+    /// The action was cancelled by the user or by disabling queued actions
+    Cancelled,
+    /// The action was not applied because the pod changed on the server
+    Conflict(ActionConflict),
+    /// The server rejected the action with the given message
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionConflict {
+    PodRemoved,
+    Archived,
+    ImageMissing,
+    /// The pod's state on the server differs from its state when the action was queued
+    StateChanged(CachedPodState),
+}
+
+impl Context {
+    /// Maximum number of queued action results kept in the action history
+    const ACTION_HISTORY: usize = 20;
+
+    /// Queue a change of the given pod's state to be applied once the connection is restored.
+    /// An action already queued for the pod is replaced, keeping the state that the pod was in
+    /// when it was first queued
+    pub(super) fn queue_action(&self, pod: &CachedPod, target: CachedPodState) {
+        let from = *pod.data.up.read();
+        let name = pod.data.name.read().clone();
+
+        self.queue.modify(|queue| {
+            let from = match queue.iter().position(|action| action.server == pod.server && action.pod == pod.data.id) {
+                Some(idx) => queue.remove(idx).from,
+                None => from,
+            };
+
+            if from != target {
+                tracing::info!("Queued change of pod {} state to {:?} until the server is reachable", pod.data.id, target);
+                queue.push(QueuedAction {
+                    server: pod.server.clone(),
+                    pod: pod.data.id.clone(),
+                    name,
+                    from,
+                    target,
+                    queued_at: Utc::now(),
+                });
+            }
+        });
+    }
+
+    /// Cancel the action queued for the given pod, recording the cancellation in the action
+    /// history
+    pub fn cancel_queued(&self, pod: &PodKey) {
+        if let Some(action) = self.unqueue(pod) {
+            self.record_action(action, ActionOutcome::Cancelled);
+        }
+    }
+
+    /// Remove the action queued for the given pod without recording it
+    pub(super) fn unqueue(&self, pod: &PodKey) -> Option<QueuedAction> {
+        let mut removed = None;
+        self.queue.0.send_if_modified(|queue| {
+            removed = queue
+                .iter()
+                .position(|action| action.server == pod.server && action.pod == pod.pod)
+                .map(|idx| queue.remove(idx));
+
+            removed.is_some()
+        });
+
+        removed
+    }
+
+    /// Apply the queued actions of each server whenever the connection to it is restored, and
+    /// cancel them when queueing is disabled in the server's settings
+    pub async fn queue_loop(&self) -> ! {
+        self.for_each_server(|server| async move { self.server_queue_loop(&server).await }).await
+    }
+
+    /// Apply or cancel the queued actions of a single server
+    async fn server_queue_loop(&self, server: &ContextServer) -> ! {
+        let mut conn_sub = server.clients.conn.subscribe();
+        let mut queue_sub = self.queue.subscribe();
+        let mut settings_sub = server.clients.settings.subscribe();
+        loop {
+            settings_sub.borrow_and_update();
+            let connected = *conn_sub.borrow_and_update() == ContextConnectionState::Connected;
+            let queued = queue_sub.borrow_and_update().iter().any(|action| action.server == server.id);
+
+            if queued && !server.queues_offline_actions() {
+                let mut cancelled = Vec::new();
+                self.queue.modify(|queue| {
+                    let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(queue).into_iter().partition(|action| action.server == server.id);
+                    cancelled = removed;
+                    *queue = kept;
+                });
+
+                tracing::info!("Queued actions were disabled for {}, cancelling {} queued actions", server.label(), cancelled.len());
+                for action in cancelled {
+                    self.record_action(action, ActionOutcome::Cancelled);
+                }
+            } else if queued && connected {
+                self.apply_queued(server).await;
+            }
+
+            //Changes made to the queue while applying it should not trigger another pass
+            queue_sub.borrow_and_update();
+
+            let changed = tokio::select! {
+                result = conn_sub.changed() => result,
+                result = queue_sub.changed() => result,
+                result = settings_sub.changed() => result,
+            };
+
+            if changed.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Synchronize with the server and apply each queued action in the order it was queued,
+    /// stopping if the connection is lost again
+    async fn apply_queued(&self, server: &ContextServer) {
+        let actions = self
+            .queue
+            .read()
+            .iter()
+            .filter(|action| action.server == server.id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        tracing::info!("Connection to {} restored, applying {} queued actions", server.label(), actions.len());
+        self.synchronize_server(server).await;
+        if *server.clients.conn.read() != ContextConnectionState::Connected {
+            tracing::warn!("Failed to synchronize before applying queued actions, they will be retried when reconnected");
+            return
+        }
+
+        for action in actions {
+            //The action may have been cancelled or replaced while earlier actions were applied
+            if !self.queue.read().contains(&action) {
+                continue
+            }
+
+            let Some(outcome) = self.apply_action(server, &action).await else {
+                tracing::warn!("Lost connection to {} while applying queued actions, the rest remain queued", server.label());
+                return
+            };
+
+            self.queue.modify(|queue| queue.retain(|queued| *queued != action));
+            self.record_action(action, outcome);
+        }
+    }
+
+    /// Apply a single queued action if the pod is still in the state it was queued from.
+    /// Returns `None` if the server could not be reached and the action should stay queued
+    async fn apply_action(&self, server: &ContextServer, action: &QueuedAction) -> Option<ActionOutcome> {
+        let pod = self.pods.read().get(&action.key()).cloned();
+        let Some(pod) = pod else {
+            return Some(ActionOutcome::Conflict(ActionConflict::PodRemoved))
+        };
+
+        let current = *pod.data.up.read();
+        if current.satisfies(action.target) {
+            return Some(ActionOutcome::AlreadySatisfied)
+        }
+
+        //Degraded pods are still enabled, so a change in health is not a conflict
+        if !current.satisfies(action.from) && !action.from.satisfies(current) {
+            return Some(ActionOutcome::Conflict(ActionConflict::StateChanged(current)))
+        }
+
+        if action.target == CachedPodState::Enabled {
+            if *pod.data.archived.read() {
+                return Some(ActionOutcome::Conflict(ActionConflict::Archived))
+            }
+
+            if *pod.data.image_missing.read() {
+                return Some(ActionOutcome::Conflict(ActionConflict::ImageMissing))
+            }
+        }
+
+        let mut api = server.clients.podapi().await?;
+        let request = deimosproto::UpdatePodRequest {
+            id: String::from(&pod.data.id),
+            method: deimosproto::PodState::from(action.target) as i32,
+            duration_seconds: 0,
+        };
+
+        pod.data.begin_request(action.target);
+        let result = server
+            .requests
+            .mutate(&pod.data.id, server.request_limit(), api.update_pod(request))
+            .await;
+
+        pod.data.end_request(result.is_ok());
+        match result {
+            Ok(_) => {
+                pod.data.enabled_until.set(None);
+                if pod.data.update_error.read().is_some() {
+                    pod.data.update_error.set(None);
+                }
+
+                Some(ActionOutcome::Applied)
+            },
+            Err(e) if Self::is_unreachable(&e) => None,
+            Err(e) => Some(match deimosproto::ErrorDetail::from_status(&e).map(|detail| detail.code()) {
+                Some(deimosproto::ErrorCode::PodNotFound) => {
+                    self.pods.modify(|pods| { pods.remove(&pod.key()); });
+                    ActionOutcome::Conflict(ActionConflict::PodRemoved)
+                },
+                Some(deimosproto::ErrorCode::PodArchived) => {
+                    pod.data.archived.set(true);
+                    ActionOutcome::Conflict(ActionConflict::Archived)
+                },
+                Some(deimosproto::ErrorCode::PodImageMissing) => {
+                    pod.data.image_missing.set(true);
+                    ActionOutcome::Conflict(ActionConflict::ImageMissing)
+                },
+                _ => {
+                    let error = server.clients.errors.record(format!("Failed to apply queued change of pod {} state", pod.data.id), &e);
+                    pod.data.update_error.set(Some(error));
+                    ActionOutcome::Failed(e.message().to_owned())
+                },
+            }),
+        }
+    }
+
+    /// Check if the given request failure was caused by the server being unreachable, meaning
+    /// that the request may be queued and retried
+    pub(super) fn is_unreachable(status: &tonic::Status) -> bool {
+        matches!(status.code(), tonic::Code::Unavailable | tonic::Code::DeadlineExceeded)
+    }
+
+    /// Add the outcome of a queued action to the action history
+    fn record_action(&self, action: QueuedAction, outcome: ActionOutcome) {
+        match outcome {
+            ActionOutcome::Applied | ActionOutcome::AlreadySatisfied | ActionOutcome::Cancelled => tracing::info!(
+                "Queued change of pod {} state to {:?}: {}",
+                action.pod,
+                action.target,
+                outcome,
+            ),
+            _ => tracing::warn!(
+                "Queued change of pod {} state to {:?} was not applied: {}",
+                action.pod,
+                action.target,
+                outcome,
+            ),
+        }
+
+        self.actions.modify(|actions| {
+            if actions.len() >= Self::ACTION_HISTORY {
+                actions.pop_front();
+            }
+
+            actions.push_back(ActionRecord { action, outcome, at: Utc::now() });
+        });
+    }
+}
+
+impl QueuedAction {
+    /// Get the key of the action's pod in the [Context]'s pod map
+    pub fn key(&self) -> PodKey {
+        PodKey { server: self.server.clone(), pod: self.pod.clone() }
+    }
+}
+
+impl ContextServer {
+    /// Check if the user has opted in to queueing pod state changes while the server cannot be
+    /// reached
+    pub fn queues_offline_actions(&self) -> bool {
+        self.clients.settings.read().queue_offline_actions
+    }
+}
+
+impl ActionOutcome {
+    /// Check if the action was not applied because of a conflict or failure that the user should
+    /// be made aware of
+    pub const fn is_problem(&self) -> bool {
+        matches!(self, Self::Conflict(_) | Self::Failed(_))
+    }
+}
+
+impl fmt::Display for QueuedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self.target {
+            CachedPodState::Enabled | CachedPodState::Degraded => "Start",
+            CachedPodState::Paused => "Pause",
+            CachedPodState::Disabled | CachedPodState::Transit => "Stop",
+        };
+
+        write!(f, "{} {}", verb, self.name)
+    }
+}
+
+impl fmt::Display for ActionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Applied => f.write_str("applied"),
+            Self::AlreadySatisfied => f.write_str("already in the requested state"),
+            Self::Cancelled => f.write_str("cancelled"),
+            Self::Conflict(ActionConflict::PodRemoved) => f.write_str("the pod was removed from the server"),
+            Self::Conflict(ActionConflict::Archived) => f.write_str("the pod was archived"),
+            Self::Conflict(ActionConflict::ImageMissing) => f.write_str("the pod's image is not pulled on the server"),
+            Self::Conflict(ActionConflict::StateChanged(state)) => write!(f, "the pod's state changed to {:?} on the server", state),
+            Self::Failed(message) => write!(f, "failed: {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn add_pod(ctx: &Context, server: &ContextServer, id: &str, up: CachedPodState) -> Arc<CachedPod> {
+        let data = serde_json::from_value(serde_json::json!({ "id": id, "name": id, "up": up })).unwrap();
+        let pod = Arc::new(CachedPod { server: server.id.clone(), data });
+        ctx.pods.modify(|pods| { pods.insert(pod.key(), pod.clone()); });
+        pod
+    }
+
+    #[test]
+    fn requeued_action_keeps_original_state() {
+        let (ctx, server) = Context::test();
+        let pod = add_pod(&ctx, &server, "pod", CachedPodState::Disabled);
+
+        ctx.queue_action(&pod, CachedPodState::Enabled);
+        pod.data.up.set(CachedPodState::Transit);
+        ctx.queue_action(&pod, CachedPodState::Paused);
+        assert_eq!(ctx.queue.read().len(), 1);
+        assert_eq!(ctx.queue.read()[0].from, CachedPodState::Disabled);
+        assert_eq!(ctx.queue.read()[0].target, CachedPodState::Paused);
+
+        //Queueing the state that the pod started in cancels the action
+        ctx.queue_action(&pod, CachedPodState::Disabled);
+        assert!(ctx.queue.read().is_empty());
+    }
+
+    #[test]
+    fn cancelled_actions_are_recorded() {
+        let (ctx, server) = Context::test();
+        for i in 0..Context::ACTION_HISTORY + 5 {
+            let pod = add_pod(&ctx, &server, &format!("pod{}", i), CachedPodState::Disabled);
+            ctx.queue_action(&pod, CachedPodState::Enabled);
+            ctx.cancel_queued(&pod.key());
+        }
+
+        let actions = ctx.actions.read();
+        assert!(ctx.queue.read().is_empty());
+        assert_eq!(actions.len(), Context::ACTION_HISTORY);
+        assert_eq!(actions.front().unwrap().action.pod.as_str(), "pod5");
+        assert!(actions.iter().all(|record| record.outcome == ActionOutcome::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn changed_pods_conflict() {
+        let (ctx, server) = Context::test();
+        let stopped = add_pod(&ctx, &server, "stopped", CachedPodState::Disabled);
+        let archived = add_pod(&ctx, &server, "archived", CachedPodState::Disabled);
+        let missing = add_pod(&ctx, &server, "missing", CachedPodState::Disabled);
+        let degraded = add_pod(&ctx, &server, "degraded", CachedPodState::Disabled);
+        let removed = add_pod(&ctx, &server, "removed", CachedPodState::Disabled);
+
+        for pod in [&stopped, &archived, &missing, &degraded, &removed] {
+            ctx.queue_action(pod, CachedPodState::Enabled);
+        }
+        let queued = ctx.queue.read().clone();
+
+        stopped.data.up.set(CachedPodState::Paused);
+        archived.data.archived.set(true);
+        missing.data.image_missing.set(true);
+        degraded.data.up.set(CachedPodState::Degraded);
+        ctx.pods.modify(|pods| { pods.remove(&removed.key()); });
+
+        let mut outcomes = Vec::new();
+        for action in queued.iter() {
+            outcomes.push(ctx.apply_action(&server, action).await);
+        }
+
+        assert_eq!(
+            outcomes,
+            [
+                Some(ActionOutcome::Conflict(ActionConflict::StateChanged(CachedPodState::Paused))),
+                Some(ActionOutcome::Conflict(ActionConflict::Archived)),
+                Some(ActionOutcome::Conflict(ActionConflict::ImageMissing)),
+                Some(ActionOutcome::AlreadySatisfied),
+                Some(ActionOutcome::Conflict(ActionConflict::PodRemoved)),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn unconflicted_action_waits_for_connection() {
+        let (ctx, server) = Context::test();
+        let pod = add_pod(&ctx, &server, "pod", CachedPodState::Disabled);
+        ctx.queue_action(&pod, CachedPodState::Enabled);
+
+        let action = ctx.queue.read()[0].clone();
+        assert_eq!(ctx.apply_action(&server, &action).await, None);
+        assert_eq!(*pod.data.requested.read(), None);
+    }
+
+    #[test]
+    fn only_connection_failures_are_queued() {
+        assert!(Context::is_unreachable(&tonic::Status::unavailable("down")));
+        assert!(Context::is_unreachable(&tonic::Status::deadline_exceeded("slow")));
+        assert!(!Context::is_unreachable(&tonic::Status::permission_denied("no")));
+    }
+}
@@ -0,0 +1,253 @@
+//! Servers that pods are synchronized from. Each server has its own connection settings, token,
+//! connection state, and synchronization progress, and the pods of every server are kept together
+//! in the [Context]'s pod map keyed by the server's ID so that pods with the same ID on different
+//! servers are kept apart
+
+use std::{fmt, future::Future, path::{Path, PathBuf}, sync::{Arc, PoisonError}};
+
+use chrono::{DateTime, Utc};
+use deimosproto::capability::Capability;
+use tokio::sync::Notify;
+
+use super::{
+    client::{auth::{PersistentToken, PersistentTokenKind}, ContextClients, ContextSettings},
+    coord::RequestCoordinator,
+    error::ErrorLog,
+    pod::{CachedPod, PodKey},
+    sync::{ServerInfo, SyncProgress},
+    Context,
+    NotifyMutation,
+};
+
+/// ID assigned to a server by the client when it is added, used to key all per-server state
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct ServerId(Arc<str>);
+
+/// Connection, authorization, and synchronization state of a single server
+#[derive(Debug)]
+pub struct ContextServer {
+    pub id: ServerId,
+    /// Pod control and authorization API clients
+    pub clients: ContextClients,
+    /// Start time and previous termination of the server, if it has been queried
+    pub info: NotifyMutation<Option<ServerInfo>>,
+    /// Progress of the current pod synchronization with the server
+    pub sync: NotifyMutation<SyncProgress>,
+    /// Time that the pod list was last received from the server in this session
+    pub synchronized: NotifyMutation<Option<DateTime<Utc>>>,
+    /// Orders outgoing requests to the server and limits the number in flight
+    pub requests: RequestCoordinator,
+    /// Notifier used to cancel an ongoing synchronization when a new one is started
+    sync_cancel: std::sync::Mutex<Arc<Notify>>,
+}
+
+/// Connection settings and token of a single server, saved in the cache directory
+#[derive(Debug, Clone)]
+pub struct PersistentServer {
+    pub id: ServerId,
+    pub settings: ContextSettings,
+    pub token_protect: PersistentTokenKind,
+    pub token: Option<PersistentToken>,
+}
+
+impl ServerId {
+    /// Create a new ID from the current time, distinct from every ID created before it
+    pub fn generate() -> Self {
+        Self(Arc::from(format!("{:x}", Utc::now().timestamp_micros())))
+    }
+
+    /// Get the ID as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check if this is the empty ID given to actions queued before servers were assigned IDs
+    pub fn is_unassigned(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for ServerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl ContextServer {
+    /// Create the state of a server from its saved settings and token. No API client is created
+    /// until [ContextClients::connect] is called
+    pub fn new(persistent: PersistentServer, errors: NotifyMutation<ErrorLog>) -> Self {
+        let PersistentServer { id, settings, token_protect, token } = persistent;
+        Self {
+            id,
+            clients: ContextClients::new(settings, token_protect, token, errors),
+            info: NotifyMutation::new(None),
+            sync: NotifyMutation::new(SyncProgress::Idle),
+            synchronized: NotifyMutation::new(None),
+            requests: RequestCoordinator::default(),
+            sync_cancel: Default::default(),
+        }
+    }
+
+    /// Get the name shown for the server, which is the host and port of its URI if it has one
+    pub fn label(&self) -> String {
+        let settings = self.clients.settings.read();
+        match settings.server_uri.authority() {
+            Some(authority) => authority.to_string(),
+            None => settings.server_uri.to_string(),
+        }
+    }
+
+    /// Check if the server supports the given optional feature. Servers are assumed to support
+    /// every feature until their info is received
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.info.read().is_none_or(|info| info.capabilities.contains(capability))
+    }
+
+    /// Get the maximum number of requests that may be in flight at once from the current settings
+    pub(super) fn request_limit(&self) -> usize {
+        self.clients.settings.read().max_concurrent_requests
+    }
+
+    /// Replace the notifier used to cancel the synchronization in progress, cancelling it
+    fn begin_synchronize(&self) -> Arc<Notify> {
+        let cancel = Arc::new(Notify::new());
+        let mut current = self.sync_cancel.lock().unwrap_or_else(PoisonError::into_inner);
+        current.notify_waiters();
+        *current = cancel.clone();
+        cancel
+    }
+
+    /// Run a synchronization of the server, starting a new one first so that any synchronization
+    /// already in progress is cancelled. Returns `None` if this synchronization was itself
+    /// cancelled before it completed
+    pub(super) async fn synchronizing<F: Future>(&self, sync: F) -> Option<F::Output> {
+        let cancel = self.begin_synchronize();
+        tokio::select! {
+            _ = cancel.notified() => None,
+            output = sync => Some(output),
+        }
+    }
+
+    /// Cancel the synchronization in progress, if any
+    pub fn cancel_synchronize(&self) {
+        self.sync_cancel.lock().unwrap_or_else(PoisonError::into_inner).notify_waiters();
+        self.sync.set(SyncProgress::Idle);
+    }
+}
+
+impl Default for PersistentServer {
+    fn default() -> Self {
+        Self {
+            id: ServerId::generate(),
+            settings: ContextSettings::default(),
+            token_protect: PersistentTokenKind::default(),
+            token: None,
+        }
+    }
+}
+
+impl Context {
+    /// Directory located in the context's cache directory containing a directory of cached pods
+    /// for each server
+    pub const SERVERS_DIR_NAME: &str = "servers";
+
+    /// Get the server with the given ID, or `None` if it has been removed
+    pub fn server(&self, id: &ServerId) -> Option<Arc<ContextServer>> {
+        self.servers.read().iter().find(|server| server.id == *id).cloned()
+    }
+
+    /// Get the server that the given pod was received from, or `None` if it has been removed
+    pub fn server_of(&self, pod: &CachedPod) -> Option<Arc<ContextServer>> {
+        self.server(&pod.server)
+    }
+
+    /// Add a server with the given settings and create its API client, returning the server.
+    /// Its pods are received once the event loop picks up the new server
+    pub async fn add_server(&self, settings: ContextSettings) -> Arc<ContextServer> {
+        let persistent = PersistentServer { settings, ..Default::default() };
+        let server = Arc::new(ContextServer::new(persistent, self.errors.clone()));
+        server.clients.connect().await;
+
+        tracing::info!("Added server {} ({})", server.label(), server.id);
+        self.servers.modify(|servers| servers.push(server.clone()));
+        server
+    }
+
+    /// Remove the server with the given ID along with its pods, queued actions, and cached data.
+    /// The last remaining server cannot be removed
+    pub async fn remove_server(&self, id: &ServerId) {
+        let mut removed = None;
+        self.servers.0.send_if_modified(|servers| {
+            if servers.len() <= 1 {
+                return false
+            }
+
+            removed = servers
+                .iter()
+                .position(|server| server.id == *id)
+                .map(|idx| servers.remove(idx));
+
+            removed.is_some()
+        });
+
+        let Some(server) = removed else {
+            tracing::warn!("Not removing server {} as it is the last server or does not exist", id);
+            return
+        };
+
+        tracing::info!("Removing server {} ({})", server.label(), server.id);
+        server.cancel_synchronize();
+
+        self.pods.modify(|pods| pods.retain(|key, _| key.server != *id));
+        self.queue.modify(|queue| queue.retain(|action| action.server != *id));
+        if self.terminal.read().as_ref().is_some_and(|session| session.pod.server == *id) {
+            self.close_terminal();
+        }
+        if self.logs.read().as_ref().is_some_and(|pod| pod.server == *id) {
+            self.logs.set(None);
+        }
+
+        if let Some(ref cache_dir) = self.cache_dir {
+            let dir = Self::server_dir(cache_dir, id);
+            if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to delete cached pods of removed server {}: {}", dir.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Run the given loop for every server concurrently, restarting the loops whenever a server
+    /// is added or removed
+    pub(super) async fn for_each_server<'a, F, R>(&'a self, run: F) -> !
+    where
+        F: Fn(Arc<ContextServer>) -> R,
+        R: Future + 'a,
+    {
+        let mut sub = self.servers.subscribe();
+        loop {
+            let servers = sub.borrow_and_update().clone();
+            let empty = servers.is_empty();
+            let loops = futures::future::join_all(servers.into_iter().map(&run));
+
+            tokio::select! {
+                _ = loops, if !empty => {},
+                result = sub.changed() => if result.is_err() {
+                    std::future::pending::<()>().await;
+                },
+            }
+        }
+    }
+
+    /// Get the key of the given pod received from the given server
+    pub(super) fn pod_key(server: &ContextServer, pod: &str) -> PodKey {
+        PodKey { server: server.id.clone(), pod: pod.to_owned().into() }
+    }
+
+    /// Get the directory that the cached pods of the given server are saved to
+    pub(super) fn server_dir(cache_dir: &Path, id: &ServerId) -> PathBuf {
+        cache_dir.join(Self::SERVERS_DIR_NAME).join(id.as_str())
+    }
+}
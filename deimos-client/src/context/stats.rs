@@ -0,0 +1,130 @@
+//! Resource usage of enabled pods streamed from the server while the user is viewing a pod
+
+use deimosproto::capability::Capability;
+
+use super::{pod::{CachedPod, CachedPodState}, Context};
+
+/// A single reading of the resource usage of a pod's container
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachedPodStats {
+    /// CPU usage in percent of a single core, if the server has taken enough readings to know it
+    pub cpu: Option<f64>,
+    pub memory_used: u64,
+    pub memory_limit: u64,
+    pub network_rx: u64,
+    pub network_tx: u64,
+}
+
+impl Context {
+    /// Stream the resource usage of the given pod, passing each reading to `emit` and `None`
+    /// whenever no reading is available.
+    /// Streams are only opened while the pod is enabled, and are reopened after failures in the
+    /// same way as the pod status stream: when connection settings or the token change, when the
+    /// user requests a retry, or after the connection timeout
+    pub async fn stream_pod_stats(&self, pod: &CachedPod, mut emit: impl FnMut(Option<CachedPodStats>)) -> ! {
+        //Pods of removed servers have no statistics to show
+        let Some(server) = self.server_of(pod) else {
+            emit(None);
+            loop {
+                std::future::pending::<()>().await
+            }
+        };
+
+        let mut sub = server.clients.settings.subscribe();
+        let mut token_sub = server.clients.token.subscribe();
+        let mut up_sub = pod.data.up.subscribe();
+        let mut info_sub = server.info.subscribe();
+
+        loop {
+            emit(None);
+
+            if !up_sub.borrow_and_update().satisfies(CachedPodState::Enabled) {
+                wait_changed(&mut up_sub).await;
+                continue
+            }
+
+            info_sub.borrow_and_update();
+            if !server.supports(Capability::PodStats) {
+                tokio::select! {
+                    _ = wait_changed(&mut up_sub) => {},
+                    _ = wait_changed(&mut info_sub) => {},
+                };
+                continue
+            }
+
+            let stream = {
+                let mut api = server.clients.podapi().await;
+                match api {
+                    Some(ref mut api) => {
+                        let request = deimosproto::PodStatsStreamRequest { id: String::from(&pod.data.id) };
+                        Some(api.subscribe_pod_stats(request).await)
+                    },
+                    None => None,
+                }
+            };
+
+            let timeout = server.clients.settings.read().connect_timeout;
+            let mut stream = match stream {
+                Some(Ok(stream)) => stream.into_inner(),
+                //The pod stopped before the server received the request, wait for it to be enabled
+                Some(Err(e)) if e.code() == tonic::Code::FailedPrecondition => {
+                    wait_changed(&mut up_sub).await;
+                    continue
+                },
+                failed => {
+                    if let Some(Err(e)) = failed {
+                        server.clients.errors.record(format!("Failed to subscribe to statistics of pod {}", pod.data.id), &e);
+                    }
+
+                    tokio::select! {
+                        _ = sub.changed() => {},
+                        _ = token_sub.changed() => {},
+                        _ = server.clients.retry_requested() => {},
+                        _ = wait_changed(&mut up_sub) => {},
+                        _ = tokio::time::sleep(timeout) => {},
+                    };
+                    continue
+                },
+            };
+
+            loop {
+                match stream.message().await {
+                    Ok(Some(stats)) => emit(Some(CachedPodStats::from(stats))),
+                    Ok(None) => break,
+                    Err(e) => {
+                        server.clients.errors.record(format!("Statistics stream of pod {} failed", pod.data.id), &e);
+                        break
+                    },
+                }
+            }
+
+            //The server ends the stream when the container stops, which is reported separately
+            emit(None);
+            tokio::select! {
+                _ = sub.changed() => {},
+                _ = token_sub.changed() => {},
+                _ = wait_changed(&mut up_sub) => {},
+                _ = tokio::time::sleep(timeout) => {},
+            };
+        }
+    }
+}
+
+/// Wait until the given value changes, or forever if it can no longer change
+async fn wait_changed<T>(sub: &mut tokio::sync::watch::Receiver<T>) {
+    if sub.changed().await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
+
+impl From<deimosproto::PodStats> for CachedPodStats {
+    fn from(value: deimosproto::PodStats) -> Self {
+        Self {
+            cpu: value.cpu_percent,
+            memory_used: value.memory_used,
+            memory_limit: value.memory_limit,
+            network_rx: value.network_rx_bytes,
+            network_tx: value.network_tx_bytes,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{fmt, time::Duration};
+
+use futures::{stream::BoxStream, StreamExt};
+
+use super::{pod::{CachedPodData, CachedPodState}, Context};
+
+/// Aggregate state of all pods on the server, shown in the overview header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostSummary {
+    pub enabled: usize,
+    pub disabled: usize,
+    pub paused: usize,
+    pub updating: usize,
+    pub failed: usize,
+}
+
+/// Category that each pod is counted under in a [HostSummary], also used to filter the pod list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodCategory {
+    Enabled,
+    Disabled,
+    Paused,
+    /// The pod is transitioning between states
+    Updating,
+    /// The pod's last update request failed or it cannot be enabled
+    Failed,
+}
+
+impl HostSummary {
+    /// Count the number of pods in each category, ignoring archived pods
+    pub fn count<'a>(pods: impl IntoIterator<Item = &'a CachedPodData>) -> Self {
+        pods
+            .into_iter()
+            .filter_map(PodCategory::of)
+            .fold(Self::default(), |mut summary, category| {
+                *summary.get_mut(category) += 1;
+                summary
+            })
+    }
+
+    /// Get the number of pods in the given category
+    pub const fn get(&self, category: PodCategory) -> usize {
+        match category {
+            PodCategory::Enabled => self.enabled,
+            PodCategory::Disabled => self.disabled,
+            PodCategory::Paused => self.paused,
+            PodCategory::Updating => self.updating,
+            PodCategory::Failed => self.failed,
+        }
+    }
+
+    fn get_mut(&mut self, category: PodCategory) -> &mut usize {
+        match category {
+            PodCategory::Enabled => &mut self.enabled,
+            PodCategory::Disabled => &mut self.disabled,
+            PodCategory::Paused => &mut self.paused,
+            PodCategory::Updating => &mut self.updating,
+            PodCategory::Failed => &mut self.failed,
+        }
+    }
+}
+
+impl PodCategory {
+    pub const ALL: [Self; 5] = [Self::Enabled, Self::Disabled, Self::Paused, Self::Failed, Self::Updating];
+
+    /// Get the category that the given pod is counted under, or `None` if the pod is archived.
+    /// Failures take precedence over the pod's state
+    pub fn of(pod: &CachedPodData) -> Option<Self> {
+        if *pod.archived.read() {
+            return None
+        }
+
+        if pod.update_error.read().is_some() || *pod.image_missing.read() {
+            return Some(Self::Failed)
+        }
+
+        Some(match *pod.up.read() {
+            CachedPodState::Enabled | CachedPodState::Degraded => Self::Enabled,
+            CachedPodState::Disabled => Self::Disabled,
+            CachedPodState::Paused => Self::Paused,
+            CachedPodState::Transit => Self::Updating,
+        })
+    }
+}
+
+impl fmt::Display for PodCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Enabled => "enabled",
+            Self::Disabled => "disabled",
+            Self::Paused => "paused",
+            Self::Updating => "updating",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+impl CachedPodData {
+    /// Get a stream yielding whenever any state that determines the pod's [PodCategory] changes
+    fn category_changes(&self) -> BoxStream<'static, ()> {
+        fn changes<T: Send + Sync + 'static>(rx: tokio::sync::watch::Receiver<T>) -> BoxStream<'static, ()> {
+            futures::stream::unfold(rx, |mut rx| async move {
+                rx.changed().await.ok().map(|_| ((), rx))
+            })
+            .boxed()
+        }
+
+        futures::stream::select_all([
+            changes(self.up.subscribe()),
+            changes(self.archived.subscribe()),
+            changes(self.image_missing.subscribe()),
+            changes(self.update_error.subscribe()),
+        ])
+        .boxed()
+    }
+}
+
+impl Context {
+    /// Time to wait after a change before recomputing the summary, so that bursts of state
+    /// changes during synchronization are coalesced
+    const SUMMARY_DEBOUNCE: Duration = Duration::from_millis(250);
+
+    /// Recompute the [HostSummary] whenever the pod map or the state of any pod changes.
+    /// Changes to every pod are merged into a single stream that is rebuilt when the pod map
+    /// changes
+    pub async fn summary_loop(&self) -> ! {
+        let mut pods_sub = self.pods.subscribe();
+        loop {
+            let pods = pods_sub.borrow_and_update().values().cloned().collect::<Vec<_>>();
+            let mut changes = futures::stream::select_all(pods.iter().map(|pod| pod.data.category_changes()));
+
+            loop {
+                self.summary.set(HostSummary::count(pods.iter().map(|pod| &pod.data)));
+
+                let rebuild = tokio::select! {
+                    result = pods_sub.changed() => match result {
+                        Ok(()) => true,
+                        Err(_) => std::future::pending().await,
+                    },
+                    Some(()) = changes.next() => false,
+                };
+
+                tokio::time::sleep(Self::SUMMARY_DEBOUNCE).await;
+                if rebuild {
+                    break
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::{collections::HashSet, fmt, future::Future, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use deimos_client_lib::pod::PodEndpoint;
+use deimosproto::capability::{Capability, CapabilitySet};
+use futures::StreamExt;
+
+use super::{confirm::CachedPodConfirmLevel, coord::RequestSnapshot, pod::{CachedPod, CachedPodAlert, CachedPodData, CachedPodFailure, CachedPodImage, CachedPodRestartBreaker, CachedPodSaveError, CachedPodState, CachedPodTransition, PodRef}, server::ContextServer, Context, NotifyMutation};
+
+/// Progress of a pod synchronization with the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncProgress {
+    Idle,
+    /// The pod list has been applied and per-pod synchronization is ongoing
+    Running {
+        done: usize,
+        total: usize,
+    },
+}
+
+/// Start time of the server, how its previous run was terminated, and the optional features it
+/// supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub started: DateTime<Utc>,
+    pub previous_termination: deimosproto::DaemonTermination,
+    pub capabilities: CapabilitySet,
+}
+
+impl ServerInfo {
+    /// Check if the server restarted without shutting down cleanly before its current run
+    pub fn restarted_unexpectedly(&self) -> bool {
+        matches!(
+            self.previous_termination,
+            deimosproto::DaemonTermination::Crash | deimosproto::DaemonTermination::HostReboot,
+        )
+    }
+}
+
+impl Context {
+    /// Maximum number of per-pod synchronization steps to run at once
+    const SYNC_CONCURRENCY: usize = 4;
+
+    /// Synchronize the pods of every server concurrently
+    pub async fn synchronize(&self) {
+        let servers = self.servers.read().clone();
+        futures::future::join_all(servers.iter().map(|server| self.synchronize_server(server))).await;
+    }
+
+    /// Query the given server for a list of containers and update our local cache in response,
+    /// then synchronize each pod individually.
+    /// Starting a synchronization cancels any synchronization of the same server that is already
+    /// in progress.
+    pub async fn synchronize_server(&self, server: &ContextServer) {
+        match server.synchronizing(self.synchronize_all(server)).await {
+            Some(()) => server.sync.set(SyncProgress::Idle),
+            None => tracing::trace!("Pod synchronization of {} cancelled", server.label()),
+        }
+    }
+
+    async fn synchronize_all(&self, server: &ContextServer) {
+        //A pod list queried while a mutation is in flight may not reflect the mutation
+        server.requests.wait_mutations().await;
+        let snapshot = server.requests.snapshot();
+
+        self.refresh_server_info(server).await;
+        let brief = {
+            let Some(ref mut api) = server.clients.podapi().await else { return };
+            let limit = server.request_limit();
+            match server.requests.request(limit, api.query_pods(deimosproto::QueryPodsRequest { include_archived: true })).await {
+                Ok(r) => r.into_inner(),
+                Err(e) => {
+                    server.clients.errors.record(format!("Failed to query pods from {}", server.label()), &e);
+                    return
+                }
+            }
+        };
+
+        let synced = self.apply_pod_list(server, brief.pods, &snapshot);
+        server.synchronized.set(Some(Utc::now()));
+
+        synchronize_each(synced, Self::SYNC_CONCURRENCY, &server.sync, |pod| async move {
+            self.synchronize_pod(server, &pod).await
+        })
+        .await;
+    }
+
+    /// Query the start time and capabilities of the given server, which change when the server
+    /// is restarted or upgraded
+    pub(super) async fn refresh_server_info(&self, server: &ContextServer) {
+        let Some(ref mut api) = server.clients.podapi().await else { return };
+        match server.requests.request(server.request_limit(), api.get_server_info(deimosproto::ServerInfoRequest {})).await {
+            Ok(info) => {
+                let info = info.into_inner();
+                if let Some(started) = DateTime::<Utc>::from_timestamp(info.started, 0) {
+                    let info = ServerInfo {
+                        started,
+                        previous_termination: info.previous_termination(),
+                        capabilities: CapabilitySet::from_advertised(&info.capabilities),
+                    };
+
+                    //Only notify when the server has restarted since it was last seen
+                    if *server.info.read() != Some(info) {
+                        server.info.set(Some(info));
+                    }
+                }
+            },
+            Err(e) => {
+                server.clients.errors.record(format!("Failed to get server info of {}", server.label()), &e);
+            }
+        }
+    }
+
+    /// Apply the state of all pods in a pod list received from the server to the local cache,
+    /// returning every pod that was present in the list.
+    /// Cached pods of the server that are missing from the list were removed from the server, and
+    /// are removed from the cache.
+    /// The state of pods that were mutated after the given snapshot was taken is left unchanged,
+    /// as the response to the mutation is newer than the pod list
+    fn apply_pod_list(&self, server: &ContextServer, pods: Vec<deimosproto::PodBrief>, snapshot: &RequestSnapshot) -> Vec<Arc<CachedPod>> {
+        let mut synced = Vec::with_capacity(pods.len());
+
+        self.pods.modify(|cached| {
+            for mut pod in pods {
+                let image_missing = pod.blocked() == deimosproto::PodBlock::ImageMissing;
+                let confirm_level = CachedPodConfirmLevel::from_proto(pod.confirm_level());
+                let last_error = pod.last_error.take().and_then(CachedPodFailure::from_proto);
+                let restart_breaker = pod.restart_breaker.take().and_then(CachedPodRestartBreaker::from_proto);
+                let stopped_at = DateTime::<Utc>::from_timestamp(pod.stopped_at, 0).filter(|_| pod.stopped_at != 0);
+                let endpoints = pod.endpoints.iter().cloned().filter_map(PodEndpoint::from_proto).collect::<Vec<_>>();
+                let alerts = CachedPodAlert::from_protos(std::mem::take(&mut pod.alerts));
+                match cached.get_mut(&Self::pod_key(server, &pod.id)) {
+                    Some(exist) if server.requests.is_stale(snapshot, &exist.data.id) => {
+                        tracing::trace!("Ignoring stale state of pod {} as it was mutated during synchronization", pod.id);
+                        synced.push(exist.clone());
+                    },
+                    Some(exist) => {
+                        exist.data.set_state(pod.state().into());
+                        exist.data.name.set(pod.title);
+                        exist.data.next_transition.set(pod.next_transition.and_then(CachedPodTransition::from_proto));
+                        exist.data.enabled_until.set(DateTime::<Utc>::from_timestamp(pod.enabled_until, 0).filter(|_| pod.enabled_until != 0));
+                        exist.data.archived.set(pod.archived);
+                        exist.data.image_missing.set(image_missing);
+                        if *exist.data.attachable.read() != pod.attachable {
+                            exist.data.attachable.set(pod.attachable);
+                        }
+                        if *exist.data.confirm_level.read() != confirm_level {
+                            exist.data.confirm_level.set(confirm_level);
+                        }
+                        if *exist.data.last_error.read() != last_error {
+                            exist.data.last_error.set(last_error);
+                        }
+                        if *exist.data.restart_breaker.read() != restart_breaker {
+                            exist.data.restart_breaker.set(restart_breaker);
+                        }
+                        //Servers restarted since the pod was stopped no longer know when it stopped
+                        if stopped_at.is_some() && *exist.data.stopped_at.read() != stopped_at {
+                            exist.data.stopped_at.set(stopped_at);
+                        }
+                        if *exist.data.endpoints.read() != endpoints {
+                            exist.data.endpoints.set(endpoints);
+                        }
+                        if *exist.data.alerts.read() != alerts {
+                            exist.data.alerts.set(alerts);
+                        }
+                        synced.push(exist.clone());
+                    },
+                    None => {
+                        tracing::trace!("Received new pod {} from {}", pod.id, server.label());
+                        let data = CachedPodData {
+                            up: NotifyMutation::new(CachedPodState::from(pod.state())),
+                            requested: NotifyMutation::new(None),
+                            request_in_flight: NotifyMutation::new(false),
+                            next_transition: NotifyMutation::new(pod.next_transition.and_then(CachedPodTransition::from_proto)),
+                            enabled_until: NotifyMutation::new(DateTime::<Utc>::from_timestamp(pod.enabled_until, 0).filter(|_| pod.enabled_until != 0)),
+                            archived: NotifyMutation::new(pod.archived),
+                            image_missing: NotifyMutation::new(image_missing),
+                            attachable: NotifyMutation::new(pod.attachable),
+                            confirm_level: NotifyMutation::new(confirm_level),
+                            last_error: NotifyMutation::new(last_error),
+                            stopped_at: NotifyMutation::new(stopped_at),
+                            restart_breaker: NotifyMutation::new(restart_breaker),
+                            update_error: NotifyMutation::new(None),
+                            annotations: NotifyMutation::new(Vec::new()),
+                            alerts: NotifyMutation::new(alerts),
+                            endpoints: NotifyMutation::new(endpoints),
+                            icon: NotifyMutation::new(None),
+                            banner: NotifyMutation::new(None),
+                            id: PodRef::from(pod.id),
+                            name: NotifyMutation::new(pod.title),
+                        };
+
+                        let pod = Arc::new(CachedPod {
+                            server: server.id.clone(),
+                            data,
+                        });
+
+                        cached.insert(pod.key(), pod.clone());
+                        synced.push(pod);
+                    }
+                }
+            }
+
+            let listed = synced.iter().map(|pod| pod.key()).collect::<HashSet<_>>();
+            cached.retain(|key, pod| {
+                let keep = key.server != server.id || listed.contains(key) || server.requests.is_stale(snapshot, &pod.data.id);
+                if !keep {
+                    tracing::info!("Pod {} was removed from {}, removing it from the cache", pod.data.id, server.label());
+                }
+
+                keep
+            });
+        });
+
+        synced
+    }
+
+    /// Query the pod list from the server and update the state of all cached pods, without
+    /// synchronizing each pod individually. Used in place of the status stream when polling
+    pub(super) async fn poll_pod_list(&self, server: &ContextServer) {
+        let snapshot = server.requests.snapshot();
+        let brief = {
+            let Some(ref mut api) = server.clients.podapi().await else { return };
+            let request = api.query_pods(deimosproto::QueryPodsRequest { include_archived: true });
+            match server.requests.request(server.request_limit(), request).await {
+                Ok(r) => r.into_inner(),
+                Err(e) => {
+                    server.clients.errors.record(format!("Failed to poll pods from {}", server.label()), &e);
+                    return
+                }
+            }
+        };
+
+        self.apply_pod_list(server, brief.pods, &snapshot);
+    }
+
+    /// Synchronize state for a single pod after the pod list has been updated.
+    /// Failures are isolated to the given pod.
+    async fn synchronize_pod(&self, server: &ContextServer, pod: &CachedPod) -> Result<(), PodSyncError> {
+        //The pod is saved even if its images could not be fetched, as its state is already updated
+        let images = self.synchronize_images(server, pod).await;
+        if let Some(ref cache_dir) = self.cache_dir {
+            pod.save(cache_dir).await?;
+        }
+
+        images
+    }
+
+    /// Download the icon and banner of the given pod if they changed since they were cached,
+    /// saving them to the pod's cache directory. Failing to download or write a single image is
+    /// logged without failing the synchronization of the pod
+    async fn synchronize_images(&self, server: &ContextServer, pod: &CachedPod) -> Result<(), PodSyncError> {
+        if !server.supports(Capability::PodImages) {
+            return Ok(())
+        }
+
+        //Servers that support resumable downloads only list the images to be downloaded
+        let download = server.supports(Capability::PodImageDownload);
+        let held = |image: &NotifyMutation<Option<CachedPodImage>>| image.read().as_ref().map(|image| image.hash.clone()).unwrap_or_default();
+        let request = deimosproto::PodImagesRequest {
+            id: String::from(&pod.data.id),
+            icon_hash: held(&pod.data.icon),
+            banner_hash: held(&pod.data.banner),
+            omit_data: download,
+        };
+
+        let response = {
+            let Some(ref mut api) = server.clients.podapi().await else { return Ok(()) };
+            match server.requests.request(server.request_limit(), api.get_pod_images(request)).await {
+                Ok(r) => r.into_inner(),
+                //Servers from before images were served do not implement the call
+                Err(e) if e.code() == tonic::Code::Unimplemented => return Ok(()),
+                Err(e) => return Err(PodSyncError::Images(e)),
+            }
+        };
+
+        let dir = self.cache_dir.as_ref().map(|cache_dir| pod.directory(cache_dir));
+        let images = [
+            (&pod.data.icon, response.icon, deimosproto::PodImageKind::Icon, CachedPod::ICON_FILE),
+            (&pod.data.banner, response.banner, deimosproto::PodImageKind::Banner, CachedPod::BANNER_FILE),
+        ];
+
+        for (cached, received, kind, file) in images {
+            let path = dir.as_ref().map(|dir| dir.join(file));
+            let image = match received {
+                Some(received) if cached.read().as_ref().is_some_and(|held| held.hash == received.hash) => continue,
+                Some(received) if download => match self.download_image(server, pod, kind, file, &received).await {
+                    Ok(image) => Some(image),
+                    Err(e) => {
+                        tracing::warn!("Failed to download {} of pod {}: {}", file, pod.data.id, e);
+                        continue
+                    },
+                },
+                Some(received) => match CachedPodImage::from_proto(received) {
+                    Some(image) => Some(image),
+                    None => {
+                        tracing::warn!("Ignoring {} of pod {} as it is empty or in an unknown format", file, pod.data.id);
+                        continue
+                    },
+                },
+                None if cached.read().is_none() => continue,
+                None => None,
+            };
+
+            if let Some(path) = path {
+                let result = match image {
+                    Some(ref image) => match path.parent() {
+                        Some(parent) => tokio::fs::create_dir_all(parent).await,
+                        None => Ok(()),
+                    }
+                    .and(tokio::fs::write(&path, &image.data).await),
+                    None => tokio::fs::remove_file(&path).await,
+                };
+
+                if let Err(e) = result {
+                    tracing::warn!("Failed to update cached image {}: {}", path.display(), e);
+                }
+            }
+
+            cached.set(image);
+        }
+
+        Ok(())
+    }
+}
+
+/// Failure to synchronize a single pod after the pod list was applied
+#[derive(Debug, thiserror::Error)]
+pub enum PodSyncError {
+    #[error("Failed to get images: {}", .0.message())]
+    Images(tonic::Status),
+    #[error(transparent)]
+    Save(#[from] CachedPodSaveError),
+}
+
+/// Run the given synchronization step for every pod with at most `concurrency` steps in flight,
+/// updating `progress` as each step completes. A failed step is logged and left to be retried
+/// on the next synchronization without affecting the other pods. Returns the number of failed
+/// steps
+async fn synchronize_each<F, Fut, E>(pods: Vec<Arc<CachedPod>>, concurrency: usize, progress: &NotifyMutation<SyncProgress>, step: F) -> usize
+where
+    F: Fn(Arc<CachedPod>) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: fmt::Display,
+{
+    let total = pods.len();
+    progress.set(SyncProgress::Running { done: 0, total });
+
+    let mut results = futures::stream::iter(pods)
+        .map(|pod| {
+            let result = step(pod.clone());
+            async move { (pod, result.await) }
+        })
+        .buffer_unordered(concurrency)
+        .enumerate();
+
+    let mut failed = 0;
+    while let Some((i, (pod, result))) = results.next().await {
+        if let Err(e) = result {
+            tracing::warn!("Failed to synchronize pod {}, it will be retried on the next synchronization: {}", pod.data.id, e);
+            failed += 1;
+        }
+
+        progress.set(SyncProgress::Running { done: i + 1, total });
+    }
+
+    failed
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use tokio::sync::Notify;
+
+    use super::{super::server::PersistentServer, *};
+
+    fn pods(ids: &[&str]) -> Vec<Arc<CachedPod>> {
+        ids.iter()
+            .map(|id| {
+                let data = serde_json::from_value(serde_json::json!({ "id": id, "name": id, "up": CachedPodState::Disabled })).unwrap();
+                Arc::new(CachedPod { server: serde_json::from_value(serde_json::json!("server")).unwrap(), data })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn slow_pod_does_not_block_others() {
+        let progress = NotifyMutation::new(SyncProgress::Idle);
+        let release = Notify::new();
+        let done = Mutex::new(Vec::new());
+
+        let sync = synchronize_each(pods(&["slow", "a", "b"]), 2, &progress, |pod| {
+            let (release, done) = (&release, &done);
+            async move {
+                if pod.data.id.as_str() == "slow" {
+                    release.notified().await;
+                }
+                done.lock().unwrap().push(pod.data.id.as_str().to_owned());
+                Ok::<_, String>(())
+            }
+        });
+
+        let drive = async {
+            while done.lock().unwrap().len() < 2 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(*progress.read(), SyncProgress::Running { done: 2, total: 3 });
+            release.notify_one();
+        };
+
+        let (failed, ()) = tokio::join!(sync, drive);
+        assert_eq!(failed, 0);
+        assert_eq!(*done.lock().unwrap(), ["a", "b", "slow"]);
+        assert_eq!(*progress.read(), SyncProgress::Running { done: 3, total: 3 });
+    }
+
+    #[tokio::test]
+    async fn failing_pod_is_isolated() {
+        let progress = NotifyMutation::new(SyncProgress::Idle);
+        let done = Mutex::new(Vec::new());
+
+        let failed = synchronize_each(pods(&["a", "failing", "b"]), 1, &progress, |pod| {
+            let done = &done;
+            async move {
+                if pod.data.id.as_str() == "failing" {
+                    return Err("unavailable")
+                }
+                done.lock().unwrap().push(pod.data.id.as_str().to_owned());
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(failed, 1);
+        assert_eq!(*done.lock().unwrap(), ["a", "b"]);
+        assert_eq!(*progress.read(), SyncProgress::Running { done: 3, total: 3 });
+    }
+
+    #[tokio::test]
+    async fn new_synchronization_cancels_previous() {
+        let server = ContextServer::new(PersistentServer::default(), NotifyMutation::default());
+
+        let mut first = std::pin::pin!(server.synchronizing(std::future::pending::<()>()));
+        assert!(futures::poll!(first.as_mut()).is_pending());
+
+        assert_eq!(server.synchronizing(async { 1 }).await, Some(1));
+        assert_eq!(first.await, None);
+
+        let mut cancelled = std::pin::pin!(server.synchronizing(std::future::pending::<()>()));
+        assert!(futures::poll!(cancelled.as_mut()).is_pending());
+        server.cancel_synchronize();
+        assert_eq!(cancelled.await, None);
+    }
+
+    fn brief(ids: &[&str]) -> Vec<deimosproto::PodBrief> {
+        ids.iter()
+            .map(|id| deimosproto::PodBrief { id: id.to_string(), title: id.to_string(), ..Default::default() })
+            .collect()
+    }
+
+    #[test]
+    fn pods_missing_from_list_are_removed() {
+        let (ctx, server) = Context::test();
+        let other = pods(&["b"]).remove(0);
+        ctx.pods.modify(|pods| { pods.insert(other.key(), other.clone()); });
+
+        let synced = ctx.apply_pod_list(&server, brief(&["a", "b", "c"]), &server.requests.snapshot());
+        assert_eq!(synced.len(), 3);
+        assert_eq!(ctx.pods.read().len(), 4);
+
+        let synced = ctx.apply_pod_list(&server, brief(&["a"]), &server.requests.snapshot());
+        assert_eq!(synced.len(), 1);
+        let mut remaining = ctx.pods.read().keys().cloned().collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, [Context::pod_key(&server, "a"), other.key()]);
+    }
+}
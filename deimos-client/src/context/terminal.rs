@@ -0,0 +1,583 @@
+//! Interactive terminal sessions in pods that allow them, displayed with a minimal terminal model.
+//! The model handles carriage returns, backspaces, cursor movement within a line, line and screen
+//! erasure, and ANSI colors, which is enough for shells and line-oriented programs. Full-screen
+//! programs relying on the rest of the VT100 control sequences are not displayed correctly
+
+use std::{collections::VecDeque, fmt, sync::Arc};
+
+use deimosproto::capability::Capability;
+use futures::channel::mpsc;
+
+use super::{pod::{CachedPod, PodKey}, Context, NotifyMutation};
+
+/// An interactive terminal session opened in a pod by the user
+#[derive(Debug)]
+pub struct TerminalSession {
+    pub pod: PodKey,
+    /// Name of the pod when the session was opened
+    pub name: String,
+    /// Contents of the terminal, updated as output is received from the server
+    pub screen: NotifyMutation<TerminalScreen>,
+    /// Set once the session has ended
+    pub ended: NotifyMutation<Option<TerminalEnd>>,
+    input: mpsc::UnboundedSender<deimosproto::PodAttachInput>,
+    /// Task receiving output from the server, aborted to cancel the session
+    task: tokio::task::AbortHandle,
+}
+
+/// Size of a terminal in character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub columns: u16,
+    pub rows: u16,
+}
+
+/// Reason that a terminal session ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerminalEnd {
+    /// The session's process exited with the given code
+    Exited(i64),
+    IdleTimeout,
+    SessionTimeout,
+    /// The pod's container stopped while the session was open
+    ContainerLost,
+    /// The session was closed by the user
+    Closed,
+    /// The connection to the server failed with the given message
+    Failed(String),
+}
+
+/// Contents of a terminal, with lines that have scrolled off of the screen kept as scrollback
+#[derive(Debug, Clone)]
+pub struct TerminalScreen {
+    /// All lines of the terminal, the last [TerminalSize::rows] of which are on the screen
+    lines: VecDeque<Vec<TerminalCell>>,
+    /// Index into `lines` and column of the cursor
+    cursor: (usize, usize),
+    size: TerminalSize,
+    /// Color given to characters as they are written
+    color: TerminalColor,
+    parser: TerminalParser,
+    /// Bytes of an incomplete UTF-8 sequence at the end of the last output
+    partial: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalCell {
+    pub ch: char,
+    pub color: TerminalColor,
+}
+
+/// Foreground color of a terminal cell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TerminalColor {
+    #[default]
+    Default,
+    /// One of the 16 standard and bright ANSI colors
+    Ansi(u8),
+}
+
+/// State of the parser for escape sequences written to a terminal
+#[derive(Debug, Clone, Default)]
+enum TerminalParser {
+    #[default]
+    Ground,
+    Escape,
+    /// Character set designation, whose final character is ignored
+    Charset,
+    /// Control sequence with the parameters received so far
+    Csi(String),
+    /// Operating system command such as setting the window title, ignored until its terminator
+    Osc { escape: bool },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TerminalOpenError {
+    #[error("Not connected to the server")]
+    NotConnected,
+    #[error("The server does not allow terminal sessions")]
+    Unsupported,
+    #[error("{}", .0.message())]
+    Rejected(#[from] tonic::Status),
+}
+
+impl Context {
+    /// Open a terminal session in the given pod, replacing any session that is already open
+    pub async fn open_terminal(&self, pod: &CachedPod, size: TerminalSize) -> Result<Arc<TerminalSession>, TerminalOpenError> {
+        self.close_terminal();
+
+        let server = self.server_of(pod).ok_or(TerminalOpenError::NotConnected)?;
+        if !server.supports(Capability::Attach) {
+            return Err(TerminalOpenError::Unsupported)
+        }
+
+        let mut api = server.clients.podapi().await.ok_or(TerminalOpenError::NotConnected)?;
+        let (input, rx) = mpsc::unbounded();
+        let _ = input.unbounded_send(deimosproto::PodAttachInput {
+            id: String::from(&pod.data.id),
+            stdin: Vec::new(),
+            size: Some(size.into()),
+        });
+
+        let stream = api.attach_pod_exec(rx).await?.into_inner();
+        tracing::info!("Opened terminal session in pod {}", pod.data.id);
+
+        let screen = NotifyMutation::new(TerminalScreen::new(size));
+        let ended = NotifyMutation::new(None);
+        let task = tokio::task::spawn(TerminalSession::output_task(stream, screen.clone(), ended.clone()));
+
+        let session = Arc::new(TerminalSession {
+            pod: pod.key(),
+            name: pod.data.name.read().clone(),
+            screen,
+            ended,
+            input,
+            task: task.abort_handle(),
+        });
+
+        self.terminal.set(Some(session.clone()));
+        Ok(session)
+    }
+
+    /// Close the open terminal session if there is one
+    pub fn close_terminal(&self) {
+        let mut closed = None;
+        self.terminal.0.send_if_modified(|session| {
+            closed = session.take();
+            closed.is_some()
+        });
+
+        if let Some(session) = closed {
+            session.close();
+        }
+    }
+}
+
+impl TerminalSession {
+    /// Send the given bytes to the session's process as if they were typed
+    pub fn write(&self, bytes: &[u8]) {
+        if self.ended.read().is_none() {
+            let _ = self.input.unbounded_send(deimosproto::PodAttachInput { stdin: bytes.to_vec(), ..Default::default() });
+        }
+    }
+
+    /// Resize the terminal, notifying the server if the size has changed
+    pub fn resize(&self, size: TerminalSize) {
+        let mut changed = false;
+        self.screen.0.send_if_modified(|screen| {
+            changed = screen.size != size;
+            screen.resize(size);
+            changed
+        });
+
+        if changed && self.ended.read().is_none() {
+            let _ = self.input.unbounded_send(deimosproto::PodAttachInput { size: Some(size.into()), ..Default::default() });
+        }
+    }
+
+    /// End the session, closing the stream to the server so that the session's process receives
+    /// the end of its input
+    pub fn close(&self) {
+        self.task.abort();
+        self.input.close_channel();
+        if self.ended.read().is_none() {
+            tracing::info!("Closed terminal session in pod {}", self.pod);
+            self.ended.set(Some(TerminalEnd::Closed));
+        }
+    }
+
+    /// Apply output received from the server to the screen until the session ends
+    async fn output_task(
+        mut stream: tonic::Streaming<deimosproto::PodAttachOutput>,
+        screen: NotifyMutation<TerminalScreen>,
+        ended: NotifyMutation<Option<TerminalEnd>>,
+    ) {
+        let end = loop {
+            match stream.message().await {
+                Ok(Some(output)) => {
+                    if !output.stdout.is_empty() || !output.stderr.is_empty() {
+                        screen.modify(|screen| {
+                            screen.feed(&output.stdout);
+                            screen.feed(&output.stderr);
+                        });
+                    }
+
+                    if let Some(exit) = output.exit {
+                        break TerminalEnd::from(exit)
+                    }
+                },
+                Ok(None) => break TerminalEnd::Failed(String::from("The server closed the session")),
+                Err(e) => break TerminalEnd::Failed(e.message().to_owned()),
+            }
+        };
+
+        tracing::info!("Terminal session ended: {}", end);
+        ended.set(Some(end));
+    }
+}
+
+impl Drop for TerminalSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl TerminalSize {
+    /// Size used until the terminal widget has been laid out
+    pub const DEFAULT: Self = Self { columns: 80, rows: 24 };
+}
+
+impl TerminalScreen {
+    /// Maximum number of lines kept, including those on the screen
+    pub const SCROLLBACK: usize = 2000;
+
+    /// Maximum length of a control sequence's parameters before it is discarded
+    const MAX_PARAMS: usize = 32;
+
+    pub fn new(size: TerminalSize) -> Self {
+        Self {
+            lines: VecDeque::from([Vec::new()]),
+            cursor: (0, 0),
+            size,
+            color: TerminalColor::Default,
+            parser: TerminalParser::Ground,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Get every line of the terminal, oldest first
+    pub fn lines(&self) -> impl Iterator<Item = &[TerminalCell]> {
+        self.lines.iter().map(Vec::as_slice)
+    }
+
+    /// Get the line and column of the cursor
+    pub const fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub const fn size(&self) -> TerminalSize {
+        self.size
+    }
+
+    /// Change the size used to wrap lines and position the cursor. Lines that were already
+    /// written are not rewrapped
+    pub fn resize(&mut self, size: TerminalSize) {
+        self.size = size;
+        self.cursor.1 = self.cursor.1.min(self.last_column());
+    }
+
+    /// Write output of the session's process to the terminal
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let mut buf = std::mem::take(&mut self.partial);
+        buf.extend_from_slice(bytes);
+
+        let mut rest = buf.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    text.chars().for_each(|ch| self.put(ch));
+                    break
+                },
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    std::str::from_utf8(valid).unwrap_or_default().chars().for_each(|ch| self.put(ch));
+                    match e.error_len() {
+                        Some(len) => {
+                            self.put(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        },
+                        None => {
+                            self.partial = after.to_vec();
+                            break
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        match std::mem::take(&mut self.parser) {
+            TerminalParser::Ground => match ch {
+                '\x1b' => self.parser = TerminalParser::Escape,
+                '\r' => self.cursor.1 = 0,
+                '\n' | '\x0b' | '\x0c' => self.line_feed(),
+                '\x08' => self.cursor.1 = self.cursor.1.saturating_sub(1),
+                '\t' => self.cursor.1 = ((self.cursor.1 / 8 + 1) * 8).min(self.last_column()),
+                ch if ch.is_control() => (),
+                ch => self.print(ch),
+            },
+            TerminalParser::Escape => match ch {
+                '[' => self.parser = TerminalParser::Csi(String::new()),
+                ']' => self.parser = TerminalParser::Osc { escape: false },
+                '(' | ')' => self.parser = TerminalParser::Charset,
+                _ => (),
+            },
+            TerminalParser::Charset => (),
+            TerminalParser::Csi(mut params) => match ch {
+                '\x40'..='\x7e' => self.control(&params, ch),
+                ch if ch.is_control() || params.len() >= Self::MAX_PARAMS => (),
+                ch => {
+                    params.push(ch);
+                    self.parser = TerminalParser::Csi(params);
+                }
+            },
+            TerminalParser::Osc { escape } => match ch {
+                '\x07' => (),
+                '\\' if escape => (),
+                ch => self.parser = TerminalParser::Osc { escape: ch == '\x1b' },
+            },
+        }
+    }
+
+    /// Write a printable character at the cursor, wrapping to the next line at the edge of the
+    /// screen
+    fn print(&mut self, ch: char) {
+        if self.cursor.1 >= self.size.columns as usize {
+            self.cursor.1 = 0;
+            self.line_feed();
+        }
+
+        let (line, column) = self.cursor;
+        let cell = TerminalCell { ch, color: self.color };
+        let line = &mut self.lines[line];
+        match line.get_mut(column) {
+            Some(existing) => *existing = cell,
+            None => {
+                line.resize(column, TerminalCell::BLANK);
+                line.push(cell);
+            }
+        }
+
+        self.cursor.1 += 1;
+    }
+
+    /// Move the cursor down a line, adding a line if it is on the last line and discarding the
+    /// oldest line once the scrollback is full
+    fn line_feed(&mut self) {
+        self.cursor.0 += 1;
+        if self.cursor.0 == self.lines.len() {
+            self.lines.push_back(Vec::new());
+        }
+
+        if self.lines.len() > Self::SCROLLBACK {
+            self.lines.pop_front();
+            self.cursor.0 -= 1;
+        }
+    }
+
+    /// Apply a control sequence with the given parameters and final character
+    fn control(&mut self, params: &str, action: char) {
+        let args = params
+            .trim_start_matches('?')
+            .split(';')
+            .map(|arg| arg.parse::<usize>().ok())
+            .collect::<Vec<_>>();
+        let count = |idx: usize| args.get(idx).copied().flatten().filter(|n| *n != 0).unwrap_or(1);
+        let mode = args.first().copied().flatten().unwrap_or(0);
+
+        match action {
+            'm' => self.select_graphic(&args),
+            'A' => self.cursor.0 = self.cursor.0.saturating_sub(count(0)).max(self.screen_top()),
+            'B' => self.cursor.0 = (self.cursor.0 + count(0)).min(self.lines.len() - 1),
+            'C' => self.cursor.1 = (self.cursor.1 + count(0)).min(self.last_column()),
+            'D' => self.cursor.1 = self.cursor.1.saturating_sub(count(0)),
+            'G' => self.cursor.1 = (count(0) - 1).min(self.last_column()),
+            'H' | 'f' => {
+                let line = self.screen_top() + (count(0) - 1).min(self.size.rows.saturating_sub(1) as usize);
+                while self.lines.len() <= line {
+                    self.lines.push_back(Vec::new());
+                }
+
+                self.cursor = (line, (count(1) - 1).min(self.last_column()));
+            },
+            'K' => self.erase_line(mode),
+            'J' => self.erase_display(mode),
+            _ => (),
+        }
+    }
+
+    /// Apply a Select Graphic Rendition sequence, of which only foreground colors are displayed
+    fn select_graphic(&mut self, args: &[Option<usize>]) {
+        let mut args = args.iter().map(|arg| arg.unwrap_or(0));
+        while let Some(arg) = args.next() {
+            match arg {
+                0 | 39 => self.color = TerminalColor::Default,
+                30..=37 => self.color = TerminalColor::Ansi((arg - 30) as u8),
+                90..=97 => self.color = TerminalColor::Ansi((arg - 90 + 8) as u8),
+                38 => match args.next() {
+                    Some(5) => self.color = args
+                        .next()
+                        .filter(|color| *color < 16)
+                        .map(|color| TerminalColor::Ansi(color as u8))
+                        .unwrap_or_default(),
+                    Some(2) => { args.nth(2); },
+                    _ => (),
+                },
+                //Background colors are not displayed, but their arguments must be skipped
+                48 => match args.next() {
+                    Some(5) => { args.next(); },
+                    Some(2) => { args.nth(2); },
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let (line, column) = self.cursor;
+        let line = &mut self.lines[line];
+        match mode {
+            0 => line.truncate(column),
+            1 => line.iter_mut().take(column + 1).for_each(|cell| *cell = TerminalCell::BLANK),
+            _ => line.clear(),
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let top = self.screen_top();
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.lines.truncate(self.cursor.0 + 1);
+            },
+            1 => {
+                self.lines.range_mut(top..self.cursor.0).for_each(Vec::clear);
+                self.erase_line(1);
+            },
+            2 => self.lines.range_mut(top..).for_each(Vec::clear),
+            _ => {
+                self.lines.drain(..top);
+                self.cursor.0 = self.cursor.0.saturating_sub(top);
+            }
+        }
+    }
+
+    /// Get the index of the first line on the screen
+    fn screen_top(&self) -> usize {
+        self.lines.len().saturating_sub(self.size.rows.max(1) as usize)
+    }
+
+    fn last_column(&self) -> usize {
+        (self.size.columns as usize).saturating_sub(1)
+    }
+}
+
+impl TerminalCell {
+    pub const BLANK: Self = Self { ch: ' ', color: TerminalColor::Default };
+}
+
+impl From<TerminalSize> for deimosproto::PodAttachSize {
+    fn from(value: TerminalSize) -> Self {
+        Self {
+            columns: value.columns as u32,
+            rows: value.rows as u32,
+        }
+    }
+}
+
+impl From<deimosproto::PodAttachExit> for TerminalEnd {
+    fn from(value: deimosproto::PodAttachExit) -> Self {
+        match value.reason() {
+            deimosproto::PodAttachEnd::Exited => Self::Exited(value.exit_code),
+            deimosproto::PodAttachEnd::IdleTimeout => Self::IdleTimeout,
+            deimosproto::PodAttachEnd::SessionTimeout => Self::SessionTimeout,
+            deimosproto::PodAttachEnd::ContainerLost => Self::ContainerLost,
+        }
+    }
+}
+
+impl fmt::Display for TerminalEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "Process exited with code {}", code),
+            Self::IdleTimeout => f.write_str("Closed after no input was received"),
+            Self::SessionTimeout => f.write_str("Reached the maximum session length"),
+            Self::ContainerLost => f.write_str("The pod's container stopped"),
+            Self::Closed => f.write_str("Closed"),
+            Self::Failed(message) => write!(f, "Connection failed: {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn screen(columns: u16, rows: u16) -> TerminalScreen {
+        TerminalScreen::new(TerminalSize { columns, rows })
+    }
+
+    fn text(screen: &TerminalScreen) -> Vec<String> {
+        screen.lines().map(|line| line.iter().map(|cell| cell.ch).collect()).collect()
+    }
+
+    #[test]
+    fn carriage_return_overwrites_line() {
+        let mut screen = screen(20, 5);
+        screen.feed(b"progress 10%\rprogress 100%\r\nab\x08c\n");
+        assert_eq!(text(&screen), ["progress 100%", "ac", ""]);
+        assert_eq!(screen.cursor(), (2, 2));
+    }
+
+    #[test]
+    fn long_lines_wrap() {
+        let mut screen = screen(4, 5);
+        screen.feed(b"abcdefghij");
+        assert_eq!(text(&screen), ["abcd", "efgh", "ij"]);
+        assert_eq!(screen.cursor(), (2, 2));
+    }
+
+    #[test]
+    fn split_utf8_is_joined() {
+        let mut screen = screen(20, 5);
+        let bytes = "héllo".as_bytes();
+        screen.feed(&bytes[..2]);
+        assert_eq!(text(&screen), ["h"]);
+        screen.feed(&bytes[2..]);
+        screen.feed(b"\xff!");
+        assert_eq!(text(&screen), ["héllo\u{fffd}!"]);
+    }
+
+    #[test]
+    fn colors_are_applied() {
+        let mut screen = screen(20, 5);
+        screen.feed(b"\x1b[31ma\x1b[1;92mb\x1b[38;5;4mc\x1b[48;5;1;33md\x1b[0me");
+        let colors = screen.lines().next().unwrap().iter().map(|cell| cell.color).collect::<Vec<_>>();
+        assert_eq!(
+            colors,
+            [TerminalColor::Ansi(1), TerminalColor::Ansi(10), TerminalColor::Ansi(4), TerminalColor::Ansi(3), TerminalColor::Default],
+        );
+    }
+
+    #[test]
+    fn cursor_movement_and_erasure() {
+        let mut screen = screen(10, 3);
+        screen.feed(b"one\r\ntwo\r\nthree\x1b[2;2Hx\x1b[K");
+        assert_eq!(text(&screen), ["one", "tx", "three"]);
+
+        screen.feed(b"\x1b[1;1H\x1b[2J");
+        assert_eq!(text(&screen), ["", "", ""]);
+        assert_eq!(screen.cursor(), (0, 0));
+    }
+
+    #[test]
+    fn title_sequences_are_hidden() {
+        let mut screen = screen(20, 5);
+        screen.feed(b"\x1b]0;user@pod\x07$ \x1b]2;title\x1b\\ls");
+        assert_eq!(text(&screen), ["$ ls"]);
+    }
+
+    #[test]
+    fn scrollback_is_bounded() {
+        let mut screen = screen(10, 5);
+        for i in 0..TerminalScreen::SCROLLBACK + 10 {
+            screen.feed(format!("{}\r\n", i).as_bytes());
+        }
+
+        assert_eq!(screen.lines().count(), TerminalScreen::SCROLLBACK);
+        assert_eq!(screen.cursor().0, TerminalScreen::SCROLLBACK - 1);
+        assert_eq!(text(&screen)[0], "11");
+    }
+}
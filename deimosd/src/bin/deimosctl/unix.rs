@@ -1,16 +1,28 @@
-use std::{path::PathBuf, process::ExitCode, time::Duration};
+use std::{future::Future, io::{IsTerminal, Stdout}, path::PathBuf, process::ExitCode, time::Duration};
 
 use clap::{Parser, Subcommand};
-use crossterm::{style::{Attribute, Color, ContentStyle, Print, ResetColor, SetAttribute, SetForegroundColor, StyledContent, Stylize}, ExecutableCommand};
-use futures::{future::BoxFuture, FutureExt};
+use deimosproto::limit::MessageSizeLimit;
+use crossterm::{cursor::MoveTo, event::{Event, KeyCode, KeyEventKind, KeyModifiers}, style::{Attribute, Color, ContentStyle, Print, ResetColor, SetAttribute, SetForegroundColor, StyledContent, Stylize}, terminal::{Clear, ClearType}, ExecutableCommand};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
 use hyper_util::rt::TokioIo;
-use tokio::net::UnixStream;
+use tokio::{io::AsyncWriteExt, net::UnixStream};
 use tonic::transport::{Channel, Uri};
 use tower::Service;
+use zeroize::Zeroizing;
+
+mod agent;
 
 #[derive(Debug,)]
 pub struct UnixSocketConnector(PathBuf);
 
+/// Outcome of a single check made by `deimosctl doctor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DoctorStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
 struct TonicTransportErrorFormat<E>(E);
 struct TonicStatusErrorFormat(tonic::Status);
 
@@ -18,10 +30,22 @@ pub async fn main() -> std::io::Result<ExitCode> {
     let args = DeimosCtlArgs::parse();
     let mut stdout = std::io::stdout();
 
-    let channel = match Channel::from_static("http://localhost:1")
-        .connect_timeout(Duration::from_secs(args.timeout))
-        .connect_with_connector(UnixSocketConnector(args.bind))
-        .await {
+    //Setting a PIN only produces a hash for the config file and does not contact the daemon
+    if let DeimosCommand::SetPin(_) = args.cmd {
+        return set_pin(&mut stdout)
+    }
+
+    //Failing to connect is reported as one of the checks
+    if let DeimosCommand::Doctor(_) = args.cmd {
+        return doctor(&mut stdout, args.bind, args.timeout).await
+    }
+
+    //The agent manages its own connection so that it can reconnect when the daemon restarts
+    if let DeimosCommand::Agent(cmd) = args.cmd {
+        return agent::agent(cmd, args.bind, args.timeout).await
+    }
+
+    let channel = match connect(args.bind, args.timeout).await {
         Ok(c) => c,
         Err(e) => return stdout
             .execute(SetForegroundColor(Color::Red))?
@@ -30,29 +54,111 @@ pub async fn main() -> std::io::Result<ExitCode> {
             .map(|_| ExitCode::FAILURE)
     };
 
-    let mut client = deimosproto::internal_client::InternalClient::new(channel);
+    let limit = MessageSizeLimit::DEFAULT.bytes();
+    let mut client = deimosproto::internal_client::InternalClient::new(channel)
+        .max_decoding_message_size(limit)
+        .max_encoding_message_size(limit);
     match args.cmd {
         DeimosCommand::Approve(approve) => {
-            let request = deimosproto::ApproveRequest {
-                username: approve.username.clone()
-            };
+            let print_token = approve.print_token || approve.qr;
+            if print_token {
+                let warning = format!(
+                    "The token for {} will be printed once and grants {} to anyone holding it. Deliver it over a trusted channel.\n",
+                    approve.username.clone().bold(),
+                    match approve.pods.is_empty() && approve.groups.is_empty() {
+                        true => "full control of every pod",
+                        false => "control of the pods in its scope",
+                    },
+                );
+
+                if !confirm(&mut stdout, &warning, approve.yes)? {
+                    return stdout
+                        .execute(SetForegroundColor(Color::Red))?
+                        .execute(Print("Token request was not approved\n"))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::FAILURE)
+                }
+            }
+
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::ApproveRequest {
+                    username: approve.username.clone(),
+                    pin,
+                    pods: approve.pods.clone(),
+                    groups: approve.groups.clone(),
+                    print_token,
+                    attach: approve.attach,
+                };
+
+                async move { client.approve(request).await }
+            }).await?;
+
+            match response {
+                Ok(response) => {
+                    stdout
+                        .execute(SetForegroundColor(Color::Green))?
+                        .execute(Print(format_args!("Approved token request for {}\n", approve.username.bold())))?
+                        .execute(ResetColor)?;
 
-            match client.approve(request).await {
+                    let response = response.into_inner();
+                    match response.token {
+                        Some(token) => print_token_delivery(&mut stdout, &token, response.unused_deadline, approve.qr).map(|_| ExitCode::SUCCESS),
+                        None if print_token => stdout
+                            .execute(SetForegroundColor(Color::Red))?
+                            .execute(Print("The daemon did not return the issued token\n"))?
+                            .execute(ResetColor)
+                            .map(|_| ExitCode::FAILURE),
+                        None => Ok(ExitCode::SUCCESS),
+                    }
+                },
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to approve token request for {}: {}\n", approve.username.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::Deny(deny) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::DenyRequest {
+                    username: deny.username.clone(),
+                    reason: deny.reason.clone().unwrap_or_default(),
+                    pin,
+                };
+
+                async move { client.deny(request).await }
+            }).await?;
+
+            match response {
                 Ok(_) => stdout
-                    .execute(SetForegroundColor(Color::Green))?
-                    .execute(Print(format_args!("Approved token request for {}\n", approve.username.bold())))?
+                    .execute(SetForegroundColor(Color::Yellow))?
+                    .execute(Print(format_args!("Denied token request for {}\n", deny.username.bold())))?
                     .execute(ResetColor)
                     .map(|_| ExitCode::SUCCESS),
                 Err(e) => stdout
                     .execute(SetForegroundColor(Color::Red))?
-                    .execute(Print(format_args!("Failed to approve token request for {}: {}\n", approve.username.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(Print(format_args!("Failed to deny token request for {}: {}\n", deny.username.bold(), TonicStatusErrorFormat(e))))?
                     .execute(ResetColor)
                     .map(|_| ExitCode::FAILURE)
             }
         },
-        DeimosCommand::List(..) => {
+        DeimosCommand::List(list) => {
+            let mut events = match list.watch {
+                true => match client.watch_pending(deimosproto::WatchPendingRequest {}).await {
+                    Ok(v) => Some(v.into_inner()),
+                    Err(e) => return stdout
+                        .execute(SetForegroundColor(Color::Red))?
+                        .execute(Print(format_args!("Failed to watch token requests: {}\n", TonicStatusErrorFormat(e))))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::FAILURE)
+                },
+                false => None,
+            };
+
             let pending = client.get_pending(deimosproto::GetPendingRequest {}).await;
-            let pending = match pending {
+            let mut pending = match pending {
                 Ok(v) => v.into_inner().pending,
                 Err(e) => return stdout
                     .execute(SetForegroundColor(Color::Red))?
@@ -61,44 +167,377 @@ pub async fn main() -> std::io::Result<ExitCode> {
                     .map(|_| ExitCode::FAILURE)
             };
 
-            const USERNAME_HEADER: &str = "username";
-            const DATETIME_HEADER: &str = "date";
-            const REQIADDR_HEADER: &str = "address";
+            let redraw = stdout.is_terminal();
+            if redraw && events.is_some() {
+                stdout
+                    .execute(Clear(ClearType::All))?
+                    .execute(MoveTo(0, 0))?;
+            }
+
+            print_pending(&mut stdout, &pending)?;
+
+            let Some(ref mut events) = events else {
+                return Ok(ExitCode::SUCCESS)
+            };
+
+            loop {
+                let event = tokio::select! {
+                    _ = tokio::signal::ctrl_c() => break Ok(ExitCode::SUCCESS),
+                    event = events.next() => event,
+                };
+
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    Some(Err(e)) => break stdout
+                        .execute(SetForegroundColor(Color::Red))?
+                        .execute(Print(format_args!("Token request stream failed: {}\n", TonicStatusErrorFormat(e))))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::FAILURE),
+                    None => break stdout
+                        .execute(SetForegroundColor(Color::Red))?
+                        .execute(Print("Token request stream closed by daemon\n"))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::FAILURE),
+                };
+
+                let Some(request) = event.request.clone() else { continue };
+                pending.retain(|p| p.username != request.username);
+
+                let (verb, color) = match event.kind() {
+                    deimosproto::PendingTokenEventKind::Added => ("Requested", Color::Reset),
+                    deimosproto::PendingTokenEventKind::Approved => ("Approved", Color::Green),
+                    deimosproto::PendingTokenEventKind::Denied => ("Denied", Color::Red),
+                    deimosproto::PendingTokenEventKind::Expired => ("Expired", Color::DarkGrey),
+                    deimosproto::PendingTokenEventKind::Revoked => ("Revoked unused", Color::Yellow),
+                };
+
+                //Revoked tokens have no requester address
+                let from = match request.requester_address.is_empty() {
+                    true => String::new(),
+                    false => format!(" from {}", request.requester_address),
+                };
+
+                if event.kind() == deimosproto::PendingTokenEventKind::Added {
+                    pending.push(request.clone());
+                }
+
+                match redraw {
+                    true => {
+                        stdout
+                            .execute(Clear(ClearType::All))?
+                            .execute(MoveTo(0, 0))?;
+                        print_pending(&mut stdout, &pending)?;
+                        stdout
+                            .execute(Print("\n"))?
+                            .execute(SetForegroundColor(color))?
+                            .execute(Print(format_args!("{} token for {}{}\n", verb, request.username.bold(), from)))?
+                            .execute(ResetColor)?;
+                    },
+                    false => {
+                        stdout
+                            .execute(Print(format_args!("{} token for {}{}\n", verb, request.username, from)))?;
+                    }
+                }
+            }
+        },
+        DeimosCommand::Tokens(_) => {
+            let tokens = match client.get_tokens(deimosproto::GetTokensRequest {}).await {
+                Ok(v) => v.into_inner().tokens,
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve tokens: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
+
+            print_tokens(&mut stdout, &tokens).map(|_| ExitCode::SUCCESS)
+        },
+        DeimosCommand::Revoke(revoke) => {
+            let (username, fingerprint) = match revoke.fingerprint {
+                true => (String::new(), revoke.token.clone()),
+                false => (revoke.token.clone(), String::new()),
+            };
+
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::RevokeTokenRequest {
+                    username: username.clone(),
+                    fingerprint: fingerprint.clone(),
+                    pin,
+                };
+
+                async move { client.revoke_token(request).await }
+            }).await?;
+
+            match response {
+                Ok(response) => {
+                    let user = response.into_inner().token.map(|token| token.username).unwrap_or(revoke.token);
+                    stdout
+                        .execute(SetForegroundColor(Color::Yellow))?
+                        .execute(Print(format_args!("Revoked token for {}\n", user.bold())))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::SUCCESS)
+                },
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to revoke token {}: {}\n", revoke.token.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::LogLevel(LogLevelCommand { target: Some(target), level: Some(level), persist }) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::SetLogLevelRequest {
+                    target: target.clone(),
+                    level: level.clone(),
+                    persist,
+                    pin,
+                };
+
+                async move { client.set_log_level(request).await }
+            }).await?;
+
+            match response {
+                Ok(_) => stdout
+                    .execute(SetForegroundColor(Color::Green))?
+                    .execute(Print(format_args!("Set log level for {} to {}\n", target.bold(), level)))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::SUCCESS),
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to set log level for {}: {}\n", target.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::LogLevel(LogLevelCommand { target, .. }) => {
+            let targets = match client.get_log_levels(deimosproto::GetLogLevelsRequest {}).await {
+                Ok(v) => v.into_inner().targets,
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve log levels: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
 
-            //Width is constrained to 12 characters due to format string
-            const DATETIME_WIDTH: usize = 12;
+            const TARGET_HEADER: &str = "target";
+            const LEVEL_HEADER: &str = "level";
 
-            let strings = pending.into_iter().map(|i| (
-                i.username,
-                chrono::DateTime::from_timestamp(i.requested_dt, 0).unwrap_or_default().format("%b %d, %Y"),
-                i.requester_address.to_string()
-            )).collect::<Vec<_>>();
+            let targets = targets
+                .into_iter()
+                .filter(|t| target.as_ref().map(|target| *target == t.target).unwrap_or(true))
+                .collect::<Vec<_>>();
 
-            let max_username = strings.iter().map(|(username, _, _)| username.len()).max().unwrap_or_default();
-            let uname_width = max_username.max(USERNAME_HEADER.len());
-            
-            let max_addr = strings.iter().map(|(_, _, addr)| addr.len()).max().unwrap_or_default();
-            let addr_width = max_addr.max(REQIADDR_HEADER.len());
+            let target_width = targets.iter().map(|t| t.target.len()).max().unwrap_or_default().max(TARGET_HEADER.len());
 
             stdout
                 .execute(SetAttribute(Attribute::Bold))?
-                .execute(Print(format_args!("{0:^1$}  {2:^3$}  {4:^5$}\n", USERNAME_HEADER, uname_width, DATETIME_HEADER, DATETIME_WIDTH, REQIADDR_HEADER, addr_width)))?
+                .execute(Print(format_args!("{0:<1$}  {2}\n", TARGET_HEADER, target_width, LEVEL_HEADER)))?
                 .execute(SetAttribute(Attribute::NoBold))?;
-            
-            for (username, datetime, addr) in strings {
+
+            for t in targets {
                 stdout
-                    .execute(Print(format_args!("{0:^1$}  {2:^3$}  {4:^5$}\n", username, uname_width, datetime, DATETIME_WIDTH, addr, addr_width)))?;
+                    .execute(Print(format_args!("{0:<1$}  {2}\n", t.target, target_width, t.level)))?;
             }
 
             Ok(ExitCode::SUCCESS)
-        }
+        },
+        DeimosCommand::Logs(LogsCommand { cmd: LogsSubcommand::Export(export) }) => export_logs(&mut client, &mut stdout, export).await,
+        DeimosCommand::Share(share) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::SetPodSharingRequest {
+                    id: share.id.clone(),
+                    enabled: !share.revoke,
+                    regenerate: share.regenerate,
+                    pin,
+                };
+
+                async move { client.set_pod_sharing(request).await }
+            }).await?;
+
+            match response {
+                Ok(_) if share.revoke => stdout
+                    .execute(SetForegroundColor(Color::Green))?
+                    .execute(Print(format_args!("Revoked share link for {}\n", share.id.bold())))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::SUCCESS),
+                Ok(response) => stdout
+                    .execute(SetForegroundColor(Color::Green))?
+                    .execute(Print(format_args!("Status of {} is shared at {}\n", share.id.bold(), response.into_inner().path)))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::SUCCESS),
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to update sharing for {}: {}\n", share.id.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::ConfigHistory(history) => {
+            let request = deimosproto::GetPodConfigHistoryRequest { id: history.id.clone() };
+            let versions = match client.get_pod_config_history(request).await {
+                Ok(v) => v.into_inner().versions,
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve config history for {}: {}\n", history.id.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
+
+            print_config_history(&mut stdout, &versions).map(|_| ExitCode::SUCCESS)
+        },
+        DeimosCommand::ConfigRollback(rollback) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::RollbackPodConfigRequest {
+                    id: rollback.id.clone(),
+                    version: rollback.version.clone(),
+                    pin,
+                };
+
+                async move { client.rollback_pod_config(request).await }
+            }).await?;
+
+            match response {
+                Ok(_) => stdout
+                    .execute(SetForegroundColor(Color::Green))?
+                    .execute(Print(format_args!(
+                        "Restored and applied config of {} version {}\n",
+                        rollback.id.bold(),
+                        rollback.version,
+                    )))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::SUCCESS),
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to roll back config of {}: {}\n", rollback.id.bold(), TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::Archive(archive) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                let request = deimosproto::ArchivePodRequest {
+                    id: archive.id.clone(),
+                    archived: !archive.restore,
+                    pin,
+                };
+
+                async move { client.archive_pod(request).await }
+            }).await?;
+
+            match response {
+                Ok(_) => stdout
+                    .execute(SetForegroundColor(Color::Green))?
+                    .execute(Print(format_args!("{} {}\n", if archive.restore { "Unarchived" } else { "Archived" }, archive.id.bold())))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::SUCCESS),
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!(
+                        "Failed to {} {}: {}\n",
+                        if archive.restore { "unarchive" } else { "archive" },
+                        archive.id.bold(),
+                        TonicStatusErrorFormat(e),
+                    )))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::PruneImages(prune) => {
+            let response = match prune.dry_run {
+                true => client.prune_images(deimosproto::PruneImagesRequest { dry_run: true, pin: String::new() }).await,
+                false => with_pin(&mut stdout, |pin| {
+                    let mut client = client.clone();
+                    async move { client.prune_images(deimosproto::PruneImagesRequest { dry_run: false, pin }).await }
+                }).await?,
+            };
+
+            match response {
+                Ok(response) => print_pruned_images(&mut stdout, &response.into_inner(), prune.dry_run).map(|_| ExitCode::SUCCESS),
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to prune images: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::Env(env) => env_overrides(&mut client, &mut stdout, env).await,
+        DeimosCommand::Pods(pods) => {
+            let response = match client.get_pod_containers(deimosproto::GetPodContainersRequest {}).await {
+                Ok(v) => v.into_inner(),
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve pods: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
+
+            match pods.docker {
+                true => print_pod_containers(&mut stdout, &response),
+                false => print_pods(&mut stdout, &response.pods),
+            }.map(|_| ExitCode::SUCCESS)
+        },
+        DeimosCommand::Reload(_) => {
+            let response = with_pin(&mut stdout, |pin| {
+                let mut client = client.clone();
+                async move { client.reload_pods(deimosproto::ReloadPodsRequest { pin }).await }
+            }).await?;
+
+            match response {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    print_reload(&mut stdout, &response)?;
+                    Ok(if response.errors.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+                },
+                Err(e) => stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to reload pods: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            }
+        },
+        DeimosCommand::Memory(_) => {
+            let response = match client.get_memory_stats(deimosproto::GetMemoryStatsRequest {}).await {
+                Ok(v) => v.into_inner(),
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve memory statistics: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
+
+            print_memory_stats(&mut stdout, &response).map(|_| ExitCode::SUCCESS)
+        },
+        DeimosCommand::Upnp(_) => {
+            let response = match client.get_upnp_status(deimosproto::GetUpnpStatusRequest {}).await {
+                Ok(v) => v.into_inner(),
+                Err(e) => return stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to retrieve UPnP status: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)
+                    .map(|_| ExitCode::FAILURE)
+            };
+
+            print_upnp_status(&mut stdout, &response).map(|_| ExitCode::SUCCESS)
+        },
+        DeimosCommand::Status(status) => pod_status(&mut client, &mut stdout, status).await,
+        DeimosCommand::Enable(update) => update_pod(&mut client, &mut stdout, update, deimosproto::PodState::Enabled, args.timeout).await,
+        DeimosCommand::Disable(update) => update_pod(&mut client, &mut stdout, update, deimosproto::PodState::Disabled, args.timeout).await,
+        DeimosCommand::Pause(update) => update_pod(&mut client, &mut stdout, update, deimosproto::PodState::Paused, args.timeout).await,
+        DeimosCommand::SetPin(_) => unreachable!("set-pin is handled before connecting"),
+        DeimosCommand::Doctor(_) => unreachable!("doctor is handled before connecting"),
+        DeimosCommand::Agent(_) => unreachable!("agent is handled before connecting"),
     }
 }
 
 #[derive(Parser)]
 #[command(about = "")]
 struct DeimosCtlArgs {
-    #[arg(long, help="Connection timeout in seconds", default_value="5")]
+    #[arg(long, help="Connection timeout in seconds, also the time allowed for enable, disable, and pause to finish", default_value="5")]
     timeout: u64,
     #[arg(short, long, default_value="/tmp/deimos/api")]
     bind: PathBuf,
@@ -110,8 +549,52 @@ struct DeimosCtlArgs {
 enum DeimosCommand {
     #[command(name = "approve")]
     Approve(ApproveCommand),
+    #[command(name = "deny")]
+    Deny(DenyCommand),
     #[command(name = "list")]
     List(ListCommand),
+    #[command(name = "tokens")]
+    Tokens(TokensCommand),
+    #[command(name = "revoke")]
+    Revoke(RevokeCommand),
+    #[command(name = "log-level")]
+    LogLevel(LogLevelCommand),
+    #[command(name = "logs")]
+    Logs(LogsCommand),
+    #[command(name = "share")]
+    Share(ShareCommand),
+    #[command(name = "set-pin")]
+    SetPin(SetPinCommand),
+    #[command(name = "config-history")]
+    ConfigHistory(ConfigHistoryCommand),
+    #[command(name = "config-rollback")]
+    ConfigRollback(ConfigRollbackCommand),
+    #[command(name = "archive")]
+    Archive(ArchiveCommand),
+    #[command(name = "prune-images")]
+    PruneImages(PruneImagesCommand),
+    #[command(name = "env")]
+    Env(EnvCommand),
+    #[command(name = "pods")]
+    Pods(PodsCommand),
+    #[command(name = "reload")]
+    Reload(ReloadCommand),
+    #[command(name = "memory")]
+    Memory(MemoryCommand),
+    #[command(name = "upnp")]
+    Upnp(UpnpCommand),
+    #[command(name = "status")]
+    Status(StatusCommand),
+    #[command(name = "enable", about = "Enable a pod and wait for its container to start")]
+    Enable(PodUpdateCommand),
+    #[command(name = "disable", about = "Disable a pod and wait for its container to be removed")]
+    Disable(PodUpdateCommand),
+    #[command(name = "pause", about = "Pause a pod's container and wait for it to be paused")]
+    Pause(PodUpdateCommand),
+    #[command(name = "doctor")]
+    Doctor(DoctorCommand),
+    #[command(name = "agent")]
+    Agent(AgentCommand),
 }
 
 #[derive(Parser)]
@@ -119,50 +602,1533 @@ enum DeimosCommand {
 struct ApproveCommand {
     #[arg(help = "Username of the requested token")]
     username: String,
+    #[arg(long, value_delimiter = ',', help = "Comma-separated IDs of pods that the token may access")]
+    pods: Vec<String>,
+    #[arg(long, value_delimiter = ',', help = "Comma-separated groups whose pods the token may access, combined with --pods. All pods may be accessed if neither is given")]
+    groups: Vec<String>,
+    #[arg(long, help = "Allow the token to open interactive terminal sessions in the pods it may access that allow them")]
+    attach: bool,
+    #[arg(long, help = "Print the issued token so that it can be delivered to the client by another channel")]
+    print_token: bool,
+    #[arg(long, help = "Print the issued token as a QR code, implies --print-token")]
+    qr: bool,
+    #[arg(short, long, help = "Print the token without asking for confirmation, required when standard input is not a terminal")]
+    yes: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "Deny a pending token request with the given username")]
+struct DenyCommand {
+    #[arg(help = "Username of the requested token")]
+    username: String,
+    #[arg(long, help = "Reason shown to the client that requested the token")]
+    reason: Option<String>,
 }
 
 #[derive(Parser)]
 #[command(about = "List the currently pending token requests")]
-struct ListCommand {}
+struct ListCommand {
+    #[arg(short, long, help = "Keep listening and update the list as requests are added or resolved")]
+    watch: bool,
+}
 
-impl Service<Uri> for UnixSocketConnector {
-    type Response = TokioIo<UnixStream>;
-    type Error = std::io::Error;
+#[derive(Parser)]
+#[command(about = "List approved tokens and when they were first used")]
+struct TokensCommand {}
 
-    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+#[derive(Parser)]
+#[command(about = "Revoke an approved token so that it can no longer be used")]
+struct RevokeCommand {
+    #[arg(help = "Username of the token, or its fingerprint if --fingerprint is given")]
+    token: String,
+    #[arg(long, help = "Select the token by the fingerprint shown by the tokens command")]
+    fingerprint: bool,
+}
 
-    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
-        std::task::Poll::Ready(Ok(()))
-    }
+#[derive(Parser)]
+#[command(about = "List the effective log levels, or set the log level of a target until the daemon restarts")]
+struct LogLevelCommand {
+    #[arg(help = "Tracing target to show or set the log level of")]
+    target: Option<String>,
+    #[arg(help = "Level to set - one of off, error, warn, info, debug, or trace", requires = "target")]
+    level: Option<String>,
+    #[arg(long, help = "Write the new level to the [log] section of the daemon's config file", requires = "level")]
+    persist: bool,
+}
 
-    fn call(&mut self, _: Uri) -> Self::Future {
-        UnixStream::connect(self.0.clone()).map(|stream| stream.map(TokioIo::new)).boxed()
-    }
+#[derive(Parser)]
+#[command(about = "Share a read-only status page for a pod on the status server, or revoke its link")]
+struct ShareCommand {
+    #[arg(help = "ID of the pod to share")]
+    id: String,
+    #[arg(long, help = "Stop sharing the pod's status", conflicts_with = "regenerate")]
+    revoke: bool,
+    #[arg(long, help = "Replace the pod's share link so that the old link stops working")]
+    regenerate: bool,
 }
 
+#[derive(Parser)]
+#[command(about = "Create a hash of a new internal PIN to protect privileged commands, to be added to the daemon's config file")]
+struct SetPinCommand {}
 
-impl<E: std::error::Error> std::fmt::Display for TonicTransportErrorFormat<E> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.0.source() {
-            Some(source) => std::fmt::Display::fmt(source, f),
-            None => std::fmt::Display::fmt(&self.0, f),
-        }
+#[derive(Parser)]
+#[command(about = "List the saved versions of a pod's config file")]
+struct ConfigHistoryCommand {
+    #[arg(help = "ID of the pod to list config versions for")]
+    id: String,
+}
+
+#[derive(Parser)]
+#[command(about = "Restore a saved version of a pod's config file and apply it, re-creating the pod's container if it is running")]
+struct ConfigRollbackCommand {
+    #[arg(help = "ID of the pod to roll back")]
+    id: String,
+    #[arg(help = "Name of the version to restore, as shown by config-history")]
+    version: String,
+}
+
+#[derive(Parser)]
+#[command(about = "Archive a disabled pod, hiding it from clients and preventing it from being enabled, or unarchive it")]
+struct ArchiveCommand {
+    #[arg(help = "ID of the pod to archive")]
+    id: String,
+    #[arg(long, help = "Unarchive the pod so that it can be enabled again")]
+    restore: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "Remove images that pods used before their image was updated, keeping images that are still tagged")]
+struct PruneImagesCommand {
+    #[arg(long, help = "List the images that would be removed without removing them")]
+    dry_run: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "List, set, or remove environment variables overriding those in a pod's config file, applied when the pod is next restarted")]
+struct EnvCommand {
+    #[arg(help = "ID of the pod to manage overrides for")]
+    id: String,
+    #[arg(long, global = true, help = "Replace the overrides even if they were changed by someone else since they were read")]
+    force: bool,
+    #[arg(short, long, global = true, help = "Reapply the change without asking if the overrides were changed by someone else, required when standard input is not a terminal")]
+    yes: bool,
+    #[command(subcommand)]
+    cmd: Option<EnvSubcommand>,
+}
+
+#[derive(Parser)]
+struct PodUpdateCommand {
+    #[arg(help = "ID of the pod to update")]
+    id: String,
+}
+
+#[derive(Parser)]
+#[command(about = "List the ports forwarded with UPnP, with the status of each lease and when the gateway last accepted it")]
+struct UpnpCommand {}
+
+#[derive(Parser)]
+#[command(about = "Show the current state, container, and uptime of every loaded pod")]
+struct StatusCommand {
+    #[arg(short, long, help = "Keep querying the daemon every 2 seconds and redraw the table in place")]
+    watch: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "Check that the daemon's socket accepts connections, that the daemon responds, and that its version matches deimosctl. Run `deimosd doctor` to check the daemon's own deployment")]
+struct DoctorCommand {}
+
+#[derive(Parser)]
+#[command(about = "Keep running and prompt to approve or deny each token request as it arrives, with a desktop notification if a notification service is available")]
+struct AgentCommand {
+    #[arg(long, help = "Prompt in the terminal even if desktop notifications are available")]
+    terminal: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "List the loaded pods with their titles and states")]
+struct PodsCommand {
+    #[arg(long, help = "Show the containers Docker reports for each pod, and containers left behind by pods that are no longer loaded")]
+    docker: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "Load the pod directory again, adding new pods, removing pods whose directory was removed, and applying changed configs when each pod is next enabled")]
+struct ReloadCommand {}
+
+#[derive(Parser)]
+#[command(about = "Show the approximate memory held by the annotations, environment overrides, and other collections kept for each pod")]
+struct MemoryCommand {}
+
+#[derive(Subcommand)]
+enum EnvSubcommand {
+    #[command(name = "set")]
+    Set(EnvSetCommand),
+    #[command(name = "unset")]
+    Unset(EnvUnsetCommand),
+}
+
+#[derive(Parser)]
+#[command(about = "Set an environment variable override, replacing any existing value")]
+struct EnvSetCommand {
+    #[arg(help = "Name of the variable")]
+    key: String,
+    #[arg(help = "Value of the variable, read from the terminal without echoing it if not given")]
+    value: Option<String>,
+    #[arg(long, help = "Never show the value again, even to deimosctl")]
+    secret: bool,
+}
+
+#[derive(Parser)]
+#[command(about = "Remove an environment variable override, restoring the value from the pod's config file if any")]
+struct EnvUnsetCommand {
+    #[arg(help = "Name of the variable")]
+    key: String,
+}
+
+#[derive(Parser)]
+#[command(about = "Manage pod logs")]
+struct LogsCommand {
+    #[command(subcommand)]
+    cmd: LogsSubcommand,
+}
+
+#[derive(Subcommand)]
+enum LogsSubcommand {
+    #[command(name = "export")]
+    Export(LogsExportCommand),
+}
+
+#[derive(Parser)]
+#[command(about = "Download the logs of one or more pods as a zstd-compressed tar archive")]
+struct LogsExportCommand {
+    #[arg(required = true, help = "IDs of the pods to export logs for")]
+    ids: Vec<String>,
+    #[arg(long, value_parser = parse_time, help = "Earliest log line to include, as an RFC 3339 timestamp or a duration ago such as 30m, 12h, or 7d")]
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    #[arg(long, value_parser = parse_time, help = "Latest log line to include, in the same format as --since")]
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    #[arg(short, long, default_value = "logs.tar.zst", help = "Path to write the archive to")]
+    output: PathBuf,
+}
+
+/// Parse an RFC 3339 timestamp, or a number of minutes, hours, or days before now
+fn parse_time(arg: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(arg) {
+        return Ok(time.to_utc())
     }
+
+    let (count, unit) = arg.split_at(arg.len().saturating_sub(1));
+    let count = count.parse::<i64>().map_err(|_| format!("'{}' is not an RFC 3339 timestamp or a duration such as 12h", arg))?;
+    let ago = match unit {
+        "m" => chrono::TimeDelta::try_minutes(count),
+        "h" => chrono::TimeDelta::try_hours(count),
+        "d" => chrono::TimeDelta::try_days(count),
+        _ => return Err(format!("Unknown duration unit '{}', expected one of m, h, or d", unit)),
+    };
+
+    ago
+        .and_then(|ago| chrono::Utc::now().checked_sub_signed(ago))
+        .ok_or_else(|| format!("Duration '{}' is out of range", arg))
 }
 
-impl std::fmt::Display for TonicStatusErrorFormat {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match std::error::Error::source(&self.0) {
-            Some(source) => match source.downcast_ref::<tonic::transport::Error>() {
-                Some(transport) => TonicTransportErrorFormat(transport).fmt(f),
-                None => write!(f, "{}: {}", self.0, source),
+/// Update the state of a pod, waiting up to `timeout` seconds for the update to finish and
+/// printing the pod's state afterwards. The IDs of all pods are listed if the pod does not exist
+async fn update_pod(
+    client: &mut deimosproto::internal_client::InternalClient<Channel>,
+    stdout: &mut Stdout,
+    update: PodUpdateCommand,
+    method: deimosproto::PodState,
+    timeout: u64,
+) -> std::io::Result<ExitCode> {
+    let response = with_pin(stdout, |pin| {
+        let mut client = client.clone();
+        let request = deimosproto::InternalUpdatePodRequest {
+            id: update.id.clone(),
+            method: method as i32,
+            duration_seconds: 0,
+            pin,
+        };
+
+        async move {
+            match tokio::time::timeout(Duration::from_secs(timeout), client.update_pod(request)).await {
+                Ok(response) => response,
+                Err(_) => Err(tonic::Status::deadline_exceeded("Timed out waiting for the update to finish")),
+            }
+        }
+    }).await?;
+
+    let e = match response {
+        Ok(response) => {
+            let state = response.into_inner().state;
+            return stdout
+                .execute(Print(format_args!("Pod {} is now ", update.id.as_str().bold())))?
+                .execute(SetForegroundColor(pod_state_color(state)))?
+                .execute(Print(format_args!("{}\n", pod_state_name(state))))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::SUCCESS)
+        },
+        Err(e) => e,
+    };
+
+    if e.code() == tonic::Code::DeadlineExceeded {
+        return stdout
+            .execute(SetForegroundColor(Color::Yellow))?
+            .execute(Print(format_args!(
+                "Pod {} did not finish updating within {} seconds, the update continues in the background\n",
+                update.id,
+                timeout,
+            )))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::FAILURE)
+    }
+
+    let not_found = deimosproto::ErrorDetail::from_status(&e).is_some_and(|detail| detail.code() == deimosproto::ErrorCode::PodNotFound);
+    stdout
+        .execute(SetForegroundColor(Color::Red))?
+        .execute(Print(format_args!("Failed to update pod {}: {}\n", update.id, TonicStatusErrorFormat(e))))?
+        .execute(ResetColor)?;
+
+    if not_found {
+        if let Ok(response) = client.get_pod_status(deimosproto::GetPodStatusRequest {}).await {
+            let ids = response.into_inner().pods.into_iter().map(|pod| pod.id).collect::<Vec<_>>();
+            match ids.is_empty() {
+                true => stdout.execute(Print("No pods are loaded\n"))?,
+                false => stdout.execute(Print(format_args!("Loaded pods: {}\n", ids.join(", "))))?,
+            };
+        }
+    }
+
+    Ok(ExitCode::FAILURE)
+}
+
+/// Print the status of every pod, querying the daemon again and redrawing the table every 2
+/// seconds until interrupted if watching
+async fn pod_status(
+    client: &mut deimosproto::internal_client::InternalClient<Channel>,
+    stdout: &mut Stdout,
+    status: StatusCommand,
+) -> std::io::Result<ExitCode> {
+    const INTERVAL: Duration = Duration::from_secs(2);
+
+    let redraw = status.watch && stdout.is_terminal();
+    loop {
+        let pods = match client.get_pod_status(deimosproto::GetPodStatusRequest {}).await {
+            Ok(v) => v.into_inner().pods,
+            Err(e) => return stdout
+                .execute(SetForegroundColor(Color::Red))?
+                .execute(Print(format_args!("Failed to retrieve pod status: {}\n", TonicStatusErrorFormat(e))))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::FAILURE)
+        };
+
+        if redraw {
+            stdout
+                .execute(Clear(ClearType::All))?
+                .execute(MoveTo(0, 0))?;
+        }
+
+        print_pod_status(stdout, &pods)?;
+        if !status.watch {
+            return Ok(ExitCode::SUCCESS)
+        }
+
+        if redraw {
+            stdout
+                .execute(SetForegroundColor(Color::DarkGrey))?
+                .execute(Print(format_args!("\nUpdated {}, press Ctrl+C to stop\n", chrono::Local::now().format("%H:%M:%S"))))?
+                .execute(ResetColor)?;
+        }
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(ExitCode::SUCCESS),
+            _ = tokio::time::sleep(INTERVAL) => {},
+        }
+    }
+}
+
+/// Check the connection to the daemon, stopping at the first check that fails as each later check
+/// depends on it
+async fn doctor(stdout: &mut Stdout, bind: PathBuf, timeout: u64) -> std::io::Result<ExitCode> {
+    use std::os::unix::fs::FileTypeExt;
+
+    const SLOW_RESPONSE: Duration = Duration::from_secs(1);
+    const BIND_REMEDY: &str = "Start deimosd, or pass --bind with the internal_bind path from the daemon's config";
+
+    match std::fs::metadata(&bind) {
+        Ok(meta) if meta.file_type().is_socket() => print_check(stdout, DoctorStatus::Pass, "socket", format_args!("Found socket {}", bind.display()), None)?,
+        Ok(_) => return print_check(stdout, DoctorStatus::Fail, "socket", format_args!("{} is not a socket", bind.display()), Some(BIND_REMEDY)),
+        Err(e) => return print_check(stdout, DoctorStatus::Fail, "socket", format_args!("Cannot access socket {}: {}", bind.display(), e), Some(BIND_REMEDY)),
+    };
+
+    //Connecting directly gives the IO error without the transport error wrapping it
+    match tokio::time::timeout(Duration::from_secs(timeout), UnixStream::connect(&bind)).await {
+        Ok(Ok(_)) => print_check(stdout, DoctorStatus::Pass, "connect", format_args!("Connected to {}", bind.display()), None)?,
+        Ok(Err(e)) => {
+            let remedy = match e.kind() {
+                std::io::ErrorKind::PermissionDenied => "Run deimosctl as a user that may write to the socket, such as the daemon's user",
+                _ => "Start deimosd, the socket may have been left behind by a daemon that is no longer running",
+            };
+
+            return print_check(stdout, DoctorStatus::Fail, "connect", format_args!("Failed to connect to {}: {}", bind.display(), e), Some(remedy))
+        },
+        Err(_) => return print_check(stdout, DoctorStatus::Fail, "connect", format_args!("Timed out connecting to {}", bind.display()), Some("Check the daemon's logs, it may be stuck")),
+    };
+
+    let channel = match connect(bind, timeout).await {
+        Ok(channel) => channel,
+        Err(e) => return print_check(stdout, DoctorStatus::Fail, "connect", TonicTransportErrorFormat(e), Some("Check the daemon's logs")),
+    };
+
+    let mut client = deimosproto::internal_client::InternalClient::new(channel);
+    let started = std::time::Instant::now();
+    let response = tokio::time::timeout(Duration::from_secs(timeout), client.get_daemon_info(deimosproto::GetDaemonInfoRequest {})).await;
+    let elapsed = started.elapsed();
+
+    let info = match response {
+        Ok(Ok(info)) => info.into_inner(),
+        Ok(Err(e)) if e.code() == tonic::Code::Unimplemented => {
+            print_check(stdout, DoctorStatus::Pass, "respond", format_args!("Daemon responded in {}ms", elapsed.as_millis()), None)?;
+            print_check(stdout, DoctorStatus::Warn, "version", "Daemon is too old to report its version", Some("Update deimosd to the same version as deimosctl"))?;
+            return Ok(ExitCode::SUCCESS)
+        },
+        Ok(Err(e)) => return print_check(stdout, DoctorStatus::Fail, "respond", TonicStatusErrorFormat(e), Some("Check the daemon's logs")),
+        Err(_) => return print_check(
+            stdout,
+            DoctorStatus::Fail,
+            "respond",
+            format_args!("Daemon did not respond within {} seconds", timeout),
+            Some("Check the daemon's logs, it may be stuck or overloaded"),
+        ),
+    };
+
+    match elapsed > SLOW_RESPONSE {
+        true => print_check(
+            stdout,
+            DoctorStatus::Warn,
+            "respond",
+            format_args!("Daemon took {}ms to respond", elapsed.as_millis()),
+            Some("Check the daemon's logs and the load of its host"),
+        )?,
+        false => print_check(stdout, DoctorStatus::Pass, "respond", format_args!("Daemon responded in {}ms", elapsed.as_millis()), None)?,
+    };
+
+    let version = env!("CARGO_PKG_VERSION");
+    match info.version == version {
+        true => print_check(stdout, DoctorStatus::Pass, "version", format_args!("deimosctl and deimosd are both version {}", version), None)?,
+        false => print_check(
+            stdout,
+            DoctorStatus::Warn,
+            "version",
+            format_args!("deimosctl {} does not match deimosd {}", version, info.version),
+            Some("Install the same version of deimosctl and deimosd"),
+        )?,
+    };
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Download a log archive from the daemon and write it to the output path, removing the partial
+/// file if the download fails or is interrupted
+async fn export_logs(
+    client: &mut deimosproto::internal_client::InternalClient<Channel>,
+    stdout: &mut Stdout,
+    export: LogsExportCommand,
+) -> std::io::Result<ExitCode> {
+    let response = with_pin(stdout, |pin| {
+        let mut client = client.clone();
+        let request = deimosproto::DownloadLogsRequest {
+            ids: export.ids.clone(),
+            since: export.since.map(|t| t.timestamp()).unwrap_or_default(),
+            until: export.until.map(|t| t.timestamp()).unwrap_or_default(),
+            pin,
+        };
+
+        async move { client.download_logs(request).await }
+    }).await?;
+
+    let mut chunks = match response {
+        Ok(stream) => stream.into_inner(),
+        Err(e) => return stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("Failed to export logs: {}\n", TonicStatusErrorFormat(e))))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::FAILURE)
+    };
+
+    let mut file = tokio::fs::File::create(&export.output).await?;
+    let progress = stdout.is_terminal();
+    let mut total = 0;
+    let mut written = 0;
+
+    let result = loop {
+        let chunk = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break Err(String::from("Interrupted")),
+            chunk = chunks.next() => chunk,
+        };
+
+        match chunk {
+            Some(Ok(chunk)) => {
+                if chunk.archive_size != 0 {
+                    total = chunk.archive_size;
+                }
+
+                if let Err(e) = file.write_all(&chunk.chunk).await {
+                    break Err(format!("Failed to write {}: {}", export.output.display(), e))
+                }
+
+                written += chunk.chunk.len() as u64;
+                if progress && total != 0 {
+                    stdout
+                        .execute(Print(format_args!("\rDownloaded {} of {} bytes ({}%)", written, total, written * 100 / total)))?;
+                }
+            },
+            Some(Err(e)) => break Err(format!("Log archive stream failed: {}", TonicStatusErrorFormat(e))),
+            None => break file.flush().await.map_err(|e| format!("Failed to write {}: {}", export.output.display(), e)),
+        }
+    };
+
+    if progress && total != 0 {
+        stdout.execute(Print("\n"))?;
+    }
+
+    match result {
+        Ok(()) => stdout
+            .execute(SetForegroundColor(Color::Green))?
+            .execute(Print(format_args!("Wrote {} bytes of logs to {}\n", written, export.output.display().to_string().bold())))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::SUCCESS),
+        Err(e) => {
+            drop(file);
+            tokio::fs::remove_file(&export.output).await?;
+            stdout
+                .execute(SetForegroundColor(Color::Red))?
+                .execute(Print(format_args!("{}\n", e)))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::FAILURE)
+        }
+    }
+}
+
+/// A change to a single environment variable override
+enum EnvChange {
+    Set {
+        key: String,
+        value: Zeroizing<String>,
+        secret: bool,
+    },
+    Unset {
+        key: String,
+    },
+}
+
+impl EnvChange {
+    /// Apply the change to the given overrides, returning `false` if the variable to remove has
+    /// no override
+    fn apply(&self, overrides: &mut Vec<deimosproto::PodEnvOverride>) -> bool {
+        match self {
+            Self::Set { key, value, secret } => {
+                overrides.retain(|var| var.key != *key);
+                overrides.push(deimosproto::PodEnvOverride {
+                    key: key.clone(),
+                    value: value.to_string(),
+                    secret: *secret,
+                    keep: false,
+                });
+
+                true
+            },
+            Self::Unset { key } => {
+                let count = overrides.len();
+                overrides.retain(|var| var.key != *key);
+                overrides.len() != count
+            },
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Self::Set { key, .. } | Self::Unset { key } => key,
+        }
+    }
+}
+
+/// List the environment variable overrides of a pod, or set or remove a single override while
+/// keeping all others. If the overrides are changed by someone else before the change is made,
+/// the other change is shown and the change may be applied on top of it or discarded
+async fn env_overrides(
+    client: &mut deimosproto::internal_client::InternalClient<Channel>,
+    stdout: &mut Stdout,
+    env: EnvCommand,
+) -> std::io::Result<ExitCode> {
+    let Some(mut current) = get_env_overrides(client, stdout, &env.id).await? else {
+        return Ok(ExitCode::FAILURE)
+    };
+
+    let (change, verb) = match env.cmd {
+        None => {
+            print_env_overrides(stdout, &current.overrides)?;
+            if current.pending {
+                stdout.execute(Print("Overrides have changed since the pod was started, restart it to apply them\n".yellow()))?;
+            }
+
+            return Ok(ExitCode::SUCCESS)
+        },
+        Some(EnvSubcommand::Set(set)) => {
+            let value = match set.value {
+                Some(value) => Zeroizing::new(value),
+                None => prompt_pin(stdout, &format!("Value of {}: ", set.key))?,
+            };
+
+            (EnvChange::Set { key: set.key, value, secret: set.secret }, "Set")
+        },
+        Some(EnvSubcommand::Unset(unset)) => (EnvChange::Unset { key: unset.key }, "Removed"),
+    };
+
+    loop {
+        //Existing variables are sent back without their values so that secrets are kept
+        let mut overrides = current
+            .overrides
+            .iter()
+            .cloned()
+            .map(|var| deimosproto::PodEnvOverride { value: String::new(), keep: true, ..var })
+            .collect::<Vec<_>>();
+
+        if !change.apply(&mut overrides) {
+            return stdout
+                .execute(SetForegroundColor(Color::Red))?
+                .execute(Print(format_args!("{} has no override for {}\n", env.id.bold(), change.key())))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::FAILURE)
+        }
+
+        let response = with_pin(stdout, |pin| {
+            let mut client = client.clone();
+            let request = deimosproto::SetPodEnvOverridesRequest {
+                id: env.id.clone(),
+                overrides: overrides.clone(),
+                pin,
+                expected_version: current.version,
+                force: env.force,
+            };
+
+            async move { client.set_pod_env_overrides(request).await }
+        }).await?;
+
+        match response {
+            Ok(response) => return stdout
+                .execute(SetForegroundColor(Color::Green))?
+                .execute(Print(format_args!(
+                    "{} {} for {}, {}\n",
+                    verb,
+                    change.key().bold(),
+                    env.id.bold(),
+                    if response.into_inner().pending { "restart the pod to apply it" } else { "applied when the pod is next enabled" },
+                )))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::SUCCESS),
+            Err(e) if deimosproto::ErrorDetail::from_status(&e).is_some_and(|detail| detail.code() == deimosproto::ErrorCode::StateConflict) => {
+                let Some(latest) = get_env_overrides(client, stdout, &env.id).await? else {
+                    return Ok(ExitCode::FAILURE)
+                };
+
+                print_env_conflict(stdout, &env.id, &current, &latest)?;
+                let question = format!("Apply the change to {} on top of these changes?\n", change.key());
+                if !confirm(stdout, &question, env.yes)? {
+                    return stdout
+                        .execute(SetForegroundColor(Color::Red))?
+                        .execute(Print(format_args!("Discarded the change to {}\n", change.key().bold())))?
+                        .execute(ResetColor)
+                        .map(|_| ExitCode::FAILURE)
+                }
+
+                current = latest;
+            },
+            Err(e) => return stdout
+                .execute(SetForegroundColor(Color::Red))?
+                .execute(Print(format_args!("Failed to update environment overrides of {}: {}\n", env.id.bold(), TonicStatusErrorFormat(e))))?
+                .execute(ResetColor)
+                .map(|_| ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Get the environment variable overrides of a pod, printing an error if they cannot be retrieved
+async fn get_env_overrides(
+    client: &mut deimosproto::internal_client::InternalClient<Channel>,
+    stdout: &mut Stdout,
+    id: &str,
+) -> std::io::Result<Option<deimosproto::GetPodEnvOverridesResponse>> {
+    let request = deimosproto::GetPodEnvOverridesRequest { id: id.to_owned() };
+    match client.get_pod_env_overrides(request).await {
+        Ok(v) => Ok(Some(v.into_inner())),
+        Err(e) => stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("Failed to retrieve environment overrides of {}: {}\n", id.bold(), TonicStatusErrorFormat(e))))?
+            .execute(ResetColor)
+            .map(|_| None)
+    }
+}
+
+/// Minimum number of characters in a new internal PIN
+const MIN_PIN_LEN: usize = 4;
+
+/// Make a request that may be protected by the internal PIN, first without a PIN and then again
+/// with a PIN read from the terminal if the daemon requires one. The PIN is never stored
+async fn with_pin<T, F, R>(stdout: &mut Stdout, mut call: F) -> std::io::Result<Result<tonic::Response<T>, tonic::Status>>
+where
+    F: FnMut(String) -> R,
+    R: Future<Output = Result<tonic::Response<T>, tonic::Status>> {
+    match call(String::new()).await {
+        Err(e) if deimosproto::ErrorDetail::from_status(&e).is_some_and(|detail| detail.code() == deimosproto::ErrorCode::PinRequired) => {
+            let pin = prompt_pin(stdout, "Internal PIN: ")?;
+            Ok(call(pin.to_string()).await)
+        },
+        result => Ok(result),
+    }
+}
+
+/// Prompt for a new internal PIN twice and print its hash in the format expected by the
+/// `internal_pin_hash` key of the daemon's `[api]` config section
+fn set_pin(stdout: &mut Stdout) -> std::io::Result<ExitCode> {
+    let pin = prompt_pin(stdout, "New internal PIN: ")?;
+    if pin.chars().count() < MIN_PIN_LEN {
+        return stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("PIN must be at least {} characters\n", MIN_PIN_LEN)))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::FAILURE)
+    }
+
+    let confirm = prompt_pin(stdout, "Confirm internal PIN: ")?;
+    if *pin != *confirm {
+        return stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print("PINs do not match\n"))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::FAILURE)
+    }
+
+    let hash = deimosproto::pin::InternalPinHash::new(&pin, rand::random());
+    stdout
+        .execute(Print("Add the following to the [api] section of the daemon's config file and restart the daemon:\n\n"))?
+        .execute(Print(format_args!("internal_pin_hash = \"{}\"\n", hash)))
+        .map(|_| ExitCode::SUCCESS)
+}
+
+/// Show the given warning and ask the user to confirm, returning `true` without asking if `yes`
+/// is set. Confirmation cannot be given when standard input is not a terminal
+fn confirm(stdout: &mut Stdout, warning: &str, yes: bool) -> std::io::Result<bool> {
+    if yes {
+        return Ok(true)
+    }
+
+    if !std::io::stdin().is_terminal() {
+        stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print("Standard input is not a terminal, pass --yes to continue without confirmation\n"))?
+            .execute(ResetColor)?;
+        return Ok(false)
+    }
+
+    stdout
+        .execute(SetForegroundColor(Color::Yellow))?
+        .execute(Print(warning))?
+        .execute(ResetColor)?
+        .execute(Print("Continue? [y/N] "))?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Print an issued token for delivery to the client that requested it, optionally as a QR code
+fn print_token_delivery(stdout: &mut Stdout, token: &deimosproto::Token, unused_deadline: i64, qr: bool) -> std::io::Result<()> {
+    let format = |secs: i64| chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M");
+
+    let key = deimosproto::auth::DeimosTokenKey::from_bytes(token.key.clone());
+    let encoded = Zeroizing::new(key.to_base64());
+
+    stdout
+        .execute(Print(format_args!("\n{:<8} {}\n", "user", token.name)))?
+        .execute(Print(format_args!("{:<8} {}\n", "issued", format(token.issued))))?;
+    if unused_deadline != 0 {
+        stdout.execute(Print(format_args!("{:<8} {} if never used\n", "expires", format(unused_deadline))))?;
+    }
+
+    stdout.execute(Print(format_args!("{:<8} {}\n", "token", encoded.as_str().bold())))?;
+
+    if qr {
+        match qrcode::QrCode::new(encoded.as_bytes()) {
+            Ok(code) => {
+                let rendered = code
+                    .render::<qrcode::render::unicode::Dense1x2>()
+                    .dark_color(qrcode::render::unicode::Dense1x2::Light)
+                    .light_color(qrcode::render::unicode::Dense1x2::Dark)
+                    .build();
+                stdout.execute(Print(format_args!("\n{}\n", rendered)))?;
+            },
+            Err(e) => {
+                stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to render token as a QR code: {}\n", e)))?
+                    .execute(ResetColor)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a PIN without echoing it, falling back to a line of standard input when it is not a
+/// terminal
+fn prompt_pin(stdout: &mut Stdout, prompt: &str) -> std::io::Result<Zeroizing<String>> {
+    let mut pin = Zeroizing::new(String::new());
+
+    if !std::io::stdin().is_terminal() {
+        std::io::stdin().read_line(&mut pin)?;
+        let len = pin.trim_end_matches(['\r', '\n']).len();
+        pin.truncate(len);
+        return Ok(pin)
+    }
+
+    stdout.execute(Print(prompt))?;
+    crossterm::terminal::enable_raw_mode()?;
+
+    let result = loop {
+        let key = match crossterm::event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e),
+        };
+
+        match key.code {
+            KeyCode::Enter => break Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                break Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "PIN entry interrupted"))
+            },
+            KeyCode::Backspace => {
+                pin.pop();
+            },
+            KeyCode::Char(c) => pin.push(c),
+            _ => (),
+        }
+    };
+
+    crossterm::terminal::disable_raw_mode()?;
+    stdout.execute(Print("\n"))?;
+
+    result.map(|_| pin)
+}
+
+/// Print a table of the given saved config versions, marking the version currently loaded
+fn print_config_history(stdout: &mut Stdout, versions: &[deimosproto::PodConfigVersion]) -> std::io::Result<()> {
+    const VERSION_HEADER: &str = "version";
+    const SAVED_HEADER: &str = "saved";
+    const HASH_HEADER: &str = "hash";
+
+    let version_width = versions.iter().map(|v| v.name.len()).max().unwrap_or_default().max(VERSION_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("{0:<1$}  {2:<20}  {3}\n", VERSION_HEADER, version_width, SAVED_HEADER, HASH_HEADER)))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for version in versions {
+        let saved = chrono::DateTime::from_timestamp(version.saved, 0)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S");
+
+        stdout
+            .execute(Print(format_args!("{0:<1$}  {2:<20}  {3}", version.name, version_width, saved, version.hash)))?;
+        if version.current {
+            stdout.execute(Print(" (current)".green()))?;
+        }
+        stdout.execute(Print("\n"))?;
+    }
+
+    Ok(())
+}
+
+/// Print the result of a check made by `deimosctl doctor` with its remedy, returning a failing exit
+/// code if the check failed
+fn print_check(stdout: &mut Stdout, status: DoctorStatus, name: &str, message: impl std::fmt::Display, remedy: Option<&str>) -> std::io::Result<ExitCode> {
+    let (label, color) = match status {
+        DoctorStatus::Pass => ("PASS", Color::Green),
+        DoctorStatus::Warn => ("WARN", Color::Yellow),
+        DoctorStatus::Fail => ("FAIL", Color::Red),
+    };
+
+    stdout
+        .execute(SetForegroundColor(color))?
+        .execute(Print(format_args!("[{}]", label)))?
+        .execute(ResetColor)?
+        .execute(Print(format_args!(" {:<8} {}\n", name, message)))?;
+
+    if let Some(remedy) = remedy {
+        stdout
+            .execute(SetForegroundColor(Color::DarkGrey))?
+            .execute(Print(format_args!("{:>16}{}\n", "-> ", remedy)))?
+            .execute(ResetColor)?;
+    }
+
+    Ok(match status {
+        DoctorStatus::Fail => ExitCode::FAILURE,
+        DoctorStatus::Pass | DoctorStatus::Warn => ExitCode::SUCCESS,
+    })
+}
+
+/// Get a lowercase name for the given pod state
+fn pod_state_name(state: i32) -> &'static str {
+    match deimosproto::PodState::try_from(state) {
+        Ok(deimosproto::PodState::Disabled) => "disabled",
+        Ok(deimosproto::PodState::Paused) => "paused",
+        Ok(deimosproto::PodState::Enabled) => "enabled",
+        Ok(deimosproto::PodState::Degraded) => "degraded",
+        Ok(deimosproto::PodState::Transit) => "transit",
+        Err(_) => "unknown",
+    }
+}
+
+/// Get the color that the given pod state is printed in
+fn pod_state_color(state: i32) -> Color {
+    match deimosproto::PodState::try_from(state) {
+        Ok(deimosproto::PodState::Enabled) => Color::Green,
+        Ok(deimosproto::PodState::Degraded) => Color::Red,
+        Ok(deimosproto::PodState::Paused | deimosproto::PodState::Transit) => Color::Yellow,
+        Ok(deimosproto::PodState::Disabled) | Err(_) => Color::DarkGrey,
+    }
+}
+
+/// Print a table of the given pods
+fn print_pods(stdout: &mut Stdout, pods: &[deimosproto::PodContainers]) -> std::io::Result<()> {
+    const ID_HEADER: &str = "pod";
+    const TITLE_HEADER: &str = "title";
+    const STATE_HEADER: &str = "state";
+
+    let id_width = pods.iter().map(|p| p.id.len()).max().unwrap_or_default().max(ID_HEADER.len());
+    let title_width = pods.iter().map(|p| p.title.chars().count()).max().unwrap_or_default().max(TITLE_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("{0:<1$}  {2:<3$}  {4}\n", ID_HEADER, id_width, TITLE_HEADER, title_width, STATE_HEADER)))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for pod in pods {
+        stdout
+            .execute(Print(format_args!("{0:<1$}  {2:<3$}  {4}\n", pod.id, id_width, pod.title, title_width, pod_state_name(pod.state))))?;
+    }
+
+    Ok(())
+}
+
+/// Print a table of the state, container, and uptime of the given pods, colored by state
+fn print_pod_status(stdout: &mut Stdout, pods: &[deimosproto::PodStatusEntry]) -> std::io::Result<()> {
+    const ID_HEADER: &str = "pod";
+    const TITLE_HEADER: &str = "title";
+    const STATE_HEADER: &str = "state";
+    const CONTAINER_HEADER: &str = "container";
+    const UPTIME_HEADER: &str = "uptime";
+    const EVENTS_HEADER: &str = "events coalesced/deferred";
+
+    let id_width = pods.iter().map(|p| p.id.len()).max().unwrap_or_default().max(ID_HEADER.len());
+    let title_width = pods.iter().map(|p| p.title.chars().count()).max().unwrap_or_default().max(TITLE_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "{0:<1$}  {2:<3$}  {4:<8}  {5:<12}  {6:<8}  {7}\n",
+            ID_HEADER,
+            id_width,
+            TITLE_HEADER,
+            title_width,
+            STATE_HEADER,
+            CONTAINER_HEADER,
+            UPTIME_HEADER,
+            EVENTS_HEADER,
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for pod in pods {
+        let container = match pod.container_id.is_empty() {
+            true => "-",
+            false => &pod.container_id[..pod.container_id.len().min(12)],
+        };
+
+        let uptime = pod.uptime_seconds.map(format_uptime).unwrap_or_else(|| String::from("-"));
+
+        stdout
+            .execute(Print(format_args!("{0:<1$}  {2:<3$}  ", pod.id, id_width, pod.title, title_width)))?
+            .execute(SetForegroundColor(pod_state_color(pod.state)))?
+            .execute(Print(format_args!("{:<8}", pod_state_name(pod.state))))?
+            .execute(ResetColor)?
+            .execute(Print(format_args!("  {:<12}  {:<8}  {}/{}\n", container, uptime, pod.events_coalesced, pod.actions_deferred)))?;
+    }
+
+    Ok(())
+}
+
+/// Format a number of seconds using its two largest units, such as "2d 4h" or "5m 12s"
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", minutes, secs),
+        (0, _, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Print a table of the memory held by each pod's collections, followed by the total
+/// Print a table of the ports forwarded with UPnP, colored by the status of their leases
+fn print_upnp_status(stdout: &mut Stdout, response: &deimosproto::GetUpnpStatusResponse) -> std::io::Result<()> {
+    const PORT_HEADER: &str = "port";
+    const HOST_HEADER: &str = "host";
+    const PROTOCOL_HEADER: &str = "protocol";
+    const OWNER_HEADER: &str = "forwarded for";
+    const STATUS_HEADER: &str = "status";
+    const RENEWED_HEADER: &str = "renewed";
+
+    let format = |secs: i64| chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M");
+
+    match (response.gateway_found, response.external_ip.is_empty()) {
+        (false, _) => stdout.execute(Print("Gateway is not responding, searching for it again\n".red()))?,
+        (true, true) => stdout.execute(Print("Gateway found\n"))?,
+        (true, false) => stdout.execute(Print(format_args!("Gateway found with external IP {}\n", response.external_ip)))?,
+    };
+
+    if response.leases.is_empty() {
+        return stdout.execute(Print("No ports are forwarded\n")).map(|_| ())
+    }
+
+    let owners = response
+        .leases
+        .iter()
+        .map(|lease| {
+            let mut owners = lease.pods.clone();
+            if lease.api {
+                owners.insert(0, String::from("public API"));
+            }
+
+            owners.join(", ")
+        })
+        .collect::<Vec<_>>();
+    let owner_width = owners.iter().map(|owner| owner.chars().count()).max().unwrap_or_default().max(OWNER_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "\n{0:<5}  {1:<5}  {2:<8}  {3:<4$}  {5:<8}  {6:<16}\n",
+            PORT_HEADER,
+            HOST_HEADER,
+            PROTOCOL_HEADER,
+            OWNER_HEADER,
+            owner_width,
+            STATUS_HEADER,
+            RENEWED_HEADER,
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for (lease, owner) in response.leases.iter().zip(owners) {
+        stdout.execute(Print(format_args!(
+            "{0:<5}  {1:<5}  {2:<8}  {3:<4$}  ",
+            lease.port,
+            lease.host_port,
+            lease.protocol,
+            owner,
+            owner_width,
+        )))?;
+        match lease.status() {
+            deimosproto::UpnpLeaseStatus::Pending => stdout.execute(Print(format!("{:<8}", "pending").dim()))?,
+            deimosproto::UpnpLeaseStatus::Active => stdout.execute(Print(format!("{:<8}", "active").green()))?,
+            deimosproto::UpnpLeaseStatus::Failed => stdout.execute(Print(format!("{:<8}", "failed").red().bold()))?,
+        };
+
+        match lease.renewed {
+            0 => stdout.execute(Print(format!("  {:<16}", "never").dim()))?,
+            at => stdout.execute(Print(format_args!("  {:<16}", format(at))))?,
+        };
+
+        if !lease.error.is_empty() {
+            stdout.execute(Print(format!("  {}", lease.error).red()))?;
+        }
+
+        stdout.execute(Print("\n"))?;
+    }
+
+    Ok(())
+}
+
+fn print_memory_stats(stdout: &mut Stdout, response: &deimosproto::GetMemoryStatsResponse) -> std::io::Result<()> {
+    const ID_HEADER: &str = "pod";
+    const ANNOTATIONS_HEADER: &str = "annotations";
+    const EVICTED_HEADER: &str = "evicted";
+    const ENV_HEADER: &str = "env";
+    const VOLUMES_HEADER: &str = "volumes";
+    const TOTAL_HEADER: &str = "total";
+
+    let id_width = response.pods.iter().map(|p| p.id.len()).max().unwrap_or_default().max(ID_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "{0:<1$}  {2:<20}  {3:>8}  {4:>10}  {5:>10}  {6:>10}\n",
+            ID_HEADER,
+            id_width,
+            ANNOTATIONS_HEADER,
+            EVICTED_HEADER,
+            ENV_HEADER,
+            VOLUMES_HEADER,
+            TOTAL_HEADER,
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for pod in response.pods.iter() {
+        let annotations = format!("{}/{} {}", pod.annotations, pod.annotation_capacity, format_bytes(pod.annotation_bytes));
+        stdout
+            .execute(Print(format_args!(
+                "{0:<1$}  {2:<20}  {3:>8}  {4:>10}  {5:>10}  {6:>10}\n",
+                pod.id,
+                id_width,
+                annotations,
+                pod.annotations_evicted,
+                format_bytes(pod.env_bytes),
+                format_bytes(pod.image_volume_bytes),
+                format_bytes(pod.total_bytes),
+            )))?;
+    }
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("Total: {}\n", format_bytes(response.total_bytes))))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    Ok(())
+}
+
+/// Print the images removed by a prune, or that would be removed by a dry run
+fn print_pruned_images(stdout: &mut Stdout, response: &deimosproto::PruneImagesResponse, dry_run: bool) -> std::io::Result<()> {
+    const ID_HEADER: &str = "image";
+    const SIZE_HEADER: &str = "size";
+    const PODS_HEADER: &str = "pods";
+    /// Length of a short image ID as printed by the Docker CLI, after the `sha256:` prefix
+    const SHORT_ID_LEN: usize = 12;
+
+    if response.images.is_empty() {
+        return stdout
+            .execute(Print("No images to prune\n"))
+            .map(|_| ())
+    }
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("{:<12}  {:>10}  {}\n", ID_HEADER, SIZE_HEADER, PODS_HEADER)))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for image in response.images.iter() {
+        let id = image.id.strip_prefix("sha256:").unwrap_or(&image.id);
+        stdout.execute(Print(format_args!(
+            "{:<12}  {:>10}  {}\n",
+            &id[..id.len().min(SHORT_ID_LEN)],
+            format_bytes(image.size),
+            image.pods.join(", "),
+        )))?;
+    }
+
+    let total = response.images.iter().map(|image| image.size).sum::<u64>();
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "{} {} images, up to {}\n",
+            if dry_run { "Would remove" } else { "Removed" },
+            response.images.len(),
+            format_bytes(total),
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    Ok(())
+}
+
+/// Format a number of bytes with a binary unit
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = None;
+    for next in UNITS {
+        if value < 1024. {
+            break
+        }
+
+        value /= 1024.;
+        unit = Some(next);
+    }
+
+    match unit {
+        Some(unit) => format!("{:.1}{}", value, unit),
+        None => format!("{}B", bytes),
+    }
+}
+
+/// Print the pods changed by a reload of the pod directory, and the entries that failed to load
+fn print_reload(stdout: &mut Stdout, response: &deimosproto::ReloadPodsResponse) -> std::io::Result<()> {
+    let changes = [
+        ("Added", &response.added, Color::Green),
+        ("Removed", &response.removed, Color::Yellow),
+        ("Updated", &response.updated, Color::Cyan),
+    ];
+
+    for (label, ids, color) in changes {
+        for id in ids {
+            stdout
+                .execute(SetForegroundColor(color))?
+                .execute(Print(format_args!("{:<8}", label)))?
+                .execute(ResetColor)?
+                .execute(Print(format_args!("{}\n", id.as_str().bold())))?;
+        }
+    }
+
+    for error in response.errors.iter() {
+        stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("{:<8}", "Failed")))?
+            .execute(ResetColor)?
+            .execute(Print(format_args!("{}: {}\n", error.path, error.message)))?;
+    }
+
+    if changes.iter().all(|(_, ids, _)| ids.is_empty()) && response.errors.is_empty() {
+        stdout.execute(Print("No pods changed\n"))?;
+    }
+
+    Ok(())
+}
+
+/// Print a table of the given pods with each container Docker reports for them, followed by
+/// the containers labelled for pods that are no longer loaded
+fn print_pod_containers(stdout: &mut Stdout, response: &deimosproto::GetPodContainersResponse) -> std::io::Result<()> {
+    const ID_HEADER: &str = "pod";
+    const STATE_HEADER: &str = "state";
+    const CONTAINER_HEADER: &str = "container";
+    const STATUS_HEADER: &str = "status";
+    const CREATED_HEADER: &str = "created";
+    const IMAGE_HEADER: &str = "image";
+
+    let containers = response
+        .pods
+        .iter()
+        .flat_map(|pod| pod.containers.iter())
+        .chain(response.orphans.iter())
+        .collect::<Vec<_>>();
+
+    let id_width = response
+        .pods
+        .iter()
+        .map(|p| p.id.len())
+        .chain(response.orphans.iter().map(|c| c.pod.len()))
+        .max()
+        .unwrap_or_default()
+        .max(ID_HEADER.len());
+    let name_width = containers.iter().map(|c| c.name.len()).max().unwrap_or_default().max(CONTAINER_HEADER.len());
+    let status_width = containers.iter().map(|c| c.status.len()).max().unwrap_or_default().max(STATUS_HEADER.len());
+
+    let created = |secs: i64| chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M");
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "{0:<1$}  {2:<8}  {3:<4$}  {5:<6$}  {7:<16}  {8}\n",
+            ID_HEADER,
+            id_width,
+            STATE_HEADER,
+            CONTAINER_HEADER,
+            name_width,
+            STATUS_HEADER,
+            status_width,
+            CREATED_HEADER,
+            IMAGE_HEADER,
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    let print_container = |stdout: &mut Stdout, container: &deimosproto::DockerContainer| -> std::io::Result<()> {
+        stdout
+            .execute(Print(format_args!(
+                "{0:<1$}  {2:<3$}  {4:<16}  {5}\n",
+                container.name,
+                name_width,
+                container.status,
+                status_width,
+                created(container.created),
+                container.image_digest,
+            )))?;
+        Ok(())
+    };
+
+    for pod in response.pods.iter() {
+        stdout.execute(Print(format_args!("{0:<1$}  {2:<8}  ", pod.id, id_width, pod_state_name(pod.state))))?;
+        let mut containers = pod.containers.iter();
+        match containers.next() {
+            Some(container) => print_container(stdout, container)?,
+            None => {
+                stdout.execute(Print(format_args!("{}\n", "no container".dim())))?;
+            },
+        }
+
+        for container in containers {
+            stdout.execute(Print(format_args!("{0:<1$}  {2:<8}  ", "", id_width, "")))?;
+            print_container(stdout, container)?;
+        }
+    }
+
+    for container in response.orphans.iter() {
+        stdout
+            .execute(Print(format_args!("{0:<1$}  ", container.pod, id_width)))?
+            .execute(Print(format!("{:<8}", "orphaned").yellow()))?
+            .execute(Print("  "))?;
+        print_container(stdout, container)?;
+    }
+
+    Ok(())
+}
+
+/// Print a table of the given environment variable overrides, hiding the values of secrets
+fn print_env_overrides(stdout: &mut Stdout, overrides: &[deimosproto::PodEnvOverride]) -> std::io::Result<()> {
+    const KEY_HEADER: &str = "variable";
+    const VALUE_HEADER: &str = "value";
+
+    let key_width = overrides.iter().map(|v| v.key.len()).max().unwrap_or_default().max(KEY_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("{0:<1$}  {2}\n", KEY_HEADER, key_width, VALUE_HEADER)))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for var in overrides {
+        stdout.execute(Print(format_args!("{0:<1$}  ", var.key, key_width)))?;
+        match var.secret {
+            true => stdout.execute(Print("(secret)".dim()))?,
+            false => stdout.execute(Print(&var.value))?,
+        };
+        stdout.execute(Print("\n"))?;
+    }
+
+    Ok(())
+}
+
+/// Print who changed a pod's overrides since they were read and the variables that they added,
+/// removed, or changed
+fn print_env_conflict(
+    stdout: &mut Stdout,
+    id: &str,
+    read: &deimosproto::GetPodEnvOverridesResponse,
+    latest: &deimosproto::GetPodEnvOverridesResponse,
+) -> std::io::Result<()> {
+    let modified = chrono::DateTime::from_timestamp(latest.modified, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S");
+    let by = match latest.modified_by.is_empty() {
+        true => String::from("another user"),
+        false => latest.modified_by.clone(),
+    };
+
+    stdout
+        .execute(SetForegroundColor(Color::Yellow))?
+        .execute(Print(format_args!("Overrides of {} were modified by {} at {} while this change was being made:\n", id.bold(), by, modified)))?
+        .execute(ResetColor)?;
+
+    let value = |var: &deimosproto::PodEnvOverride| match var.secret {
+        true => String::from("(secret)"),
+        false => var.value.clone(),
+    };
+
+    let mut keys = read.overrides.iter().chain(latest.overrides.iter()).map(|var| var.key.as_str()).collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+
+    for key in keys {
+        let before = read.overrides.iter().find(|var| var.key == key);
+        let after = latest.overrides.iter().find(|var| var.key == key);
+        let (marker, color, shown) = match (before, after) {
+            (None, Some(after)) => ("+", Color::Green, value(after)),
+            (Some(before), None) => ("-", Color::Red, value(before)),
+            //Secret values are never returned, so a changed secret cannot be detected
+            (Some(before), Some(after)) if before != after => ("~", Color::Yellow, value(after)),
+            _ => continue,
+        };
+
+        stdout
+            .execute(SetForegroundColor(color))?
+            .execute(Print(format_args!("  {} {}={}\n", marker, key, shown)))?
+            .execute(ResetColor)?;
+    }
+
+    Ok(())
+}
+
+/// Print a table of the given approved tokens, highlighting tokens that have never been used
+fn print_tokens(stdout: &mut Stdout, tokens: &[deimosproto::ApiTokenInfo]) -> std::io::Result<()> {
+    const USERNAME_HEADER: &str = "username";
+    const ISSUED_HEADER: &str = "issued";
+    const USED_HEADER: &str = "first used";
+    const FINGERPRINT_HEADER: &str = "fingerprint";
+    const SCOPE_HEADER: &str = "scope";
+
+    let format = |secs: i64| chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M");
+
+    let uname_width = tokens.iter().map(|t| t.username.len()).max().unwrap_or_default().max(USERNAME_HEADER.len());
+    let fingerprint_width = tokens.iter().map(|t| t.fingerprint.len()).max().unwrap_or_default().max(FINGERPRINT_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!(
+            "{0:<1$}  {2:<3$}  {4:<16}  {5:<16}  {6}\n",
+            USERNAME_HEADER,
+            uname_width,
+            FINGERPRINT_HEADER,
+            fingerprint_width,
+            ISSUED_HEADER,
+            USED_HEADER,
+            SCOPE_HEADER,
+        )))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+
+    for token in tokens {
+        stdout.execute(Print(format_args!(
+            "{0:<1$}  {2:<3$}  {4:<16}  ",
+            token.username,
+            uname_width,
+            token.fingerprint,
+            fingerprint_width,
+            format(token.issued),
+        )))?;
+        match (token.never_used, token.first_used) {
+            (true, _) => stdout.execute(Print(format!("{:<16}", "never used").yellow().bold()))?,
+            (false, 0) => stdout.execute(Print(format!("{:<16}", "unknown").dim()))?,
+            (false, at) => stdout.execute(Print(format_args!("{:<16}", format(at))))?,
+        };
+
+        stdout.execute(Print("  "))?;
+        match (token.pods.is_empty(), token.groups.is_empty()) {
+            (true, true) => stdout.execute(Print("all pods".dim()))?,
+            _ => {
+                let scope = token
+                    .pods
+                    .iter()
+                    .cloned()
+                    .chain(token.groups.iter().map(|group| format!("group:{}", group)))
+                    .collect::<Vec<_>>();
+                stdout.execute(Print(scope.join(", ")))?
+            },
+        };
+        if token.attach {
+            stdout.execute(Print(", terminal".yellow()))?;
+        }
+        stdout.execute(Print("\n"))?;
+    }
+
+    Ok(())
+}
+
+/// Print a table of the given pending token requests
+fn print_pending(stdout: &mut Stdout, pending: &[deimosproto::PendingTokenRequest]) -> std::io::Result<()> {
+    const USERNAME_HEADER: &str = "username";
+    const DATETIME_HEADER: &str = "date";
+    const REQIADDR_HEADER: &str = "address";
+
+    //Width is constrained to 12 characters due to format string
+    const DATETIME_WIDTH: usize = 12;
+
+    let strings = pending.iter().map(|i| (
+        &i.username,
+        chrono::DateTime::from_timestamp(i.requested_dt, 0).unwrap_or_default().format("%b %d, %Y"),
+        &i.requester_address,
+    )).collect::<Vec<_>>();
+
+    let max_username = strings.iter().map(|(username, _, _)| username.len()).max().unwrap_or_default();
+    let uname_width = max_username.max(USERNAME_HEADER.len());
+    
+    let max_addr = strings.iter().map(|(_, _, addr)| addr.len()).max().unwrap_or_default();
+    let addr_width = max_addr.max(REQIADDR_HEADER.len());
+
+    stdout
+        .execute(SetAttribute(Attribute::Bold))?
+        .execute(Print(format_args!("{0:^1$}  {2:^3$}  {4:^5$}\n", USERNAME_HEADER, uname_width, DATETIME_HEADER, DATETIME_WIDTH, REQIADDR_HEADER, addr_width)))?
+        .execute(SetAttribute(Attribute::NoBold))?;
+    
+    for (username, datetime, addr) in strings {
+        stdout
+            .execute(Print(format_args!("{0:^1$}  {2:^3$}  {4:^5$}\n", username, uname_width, datetime, DATETIME_WIDTH, addr, addr_width)))?;
+    }
+
+    Ok(())
+}
+
+/// Create a channel to the internal API over the socket at the given path
+async fn connect(bind: PathBuf, timeout: u64) -> Result<Channel, tonic::transport::Error> {
+    Channel::from_static("http://localhost:1")
+        .connect_timeout(Duration::from_secs(timeout))
+        .connect_with_connector(UnixSocketConnector(bind))
+        .await
+}
+
+impl Service<Uri> for UnixSocketConnector {
+    type Response = TokioIo<UnixStream>;
+    type Error = std::io::Error;
+
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        UnixStream::connect(self.0.clone()).map(|stream| stream.map(TokioIo::new)).boxed()
+    }
+}
+
+
+impl<E: std::error::Error> std::fmt::Display for TonicTransportErrorFormat<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.source() {
+            Some(source) => std::fmt::Display::fmt(source, f),
+            None => std::fmt::Display::fmt(&self.0, f),
+        }
+    }
+}
+
+impl std::fmt::Display for TonicStatusErrorFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match std::error::Error::source(&self.0) {
+            Some(source) => match source.downcast_ref::<tonic::transport::Error>() {
+                Some(transport) => TonicTransportErrorFormat(transport).fmt(f),
+                None => write!(f, "{}: {}", self.0, source),
+            },
+            None => match deimosproto::ErrorDetail::from_status(&self.0) {
+                Some(detail) => write!(
+                    f,
+                    "{}: {}",
+                    StyledContent::new(ContentStyle::new().dim(), detail.code().as_str_name()),
+                    detail.message
+                ),
+                None => write!(
+                    f,
+                    "{}: {}",
+                    StyledContent::new(ContentStyle::new().dim(), self.0.code()),
+                    self.0.message()
+                ),
             },
-            None => write!(
-                f,
-                "{}: {}",
-                StyledContent::new(ContentStyle::new().dim(), self.0.code()),
-                self.0.message()
-            ),
         }
     }
 }
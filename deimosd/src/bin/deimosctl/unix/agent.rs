@@ -0,0 +1,416 @@
+//! Companion mode for a daemon running on the user's own machine, prompting to approve or deny
+//! each token request as it arrives. Prompts are shown as desktop notifications with actions
+//! when a notification service is available and read from the terminal otherwise.
+//! The agent reconnects whenever the daemon restarts, and each request is only prompted for once
+//! even if it is still pending after reconnecting
+
+use std::{collections::{HashMap, HashSet}, io::IsTerminal, path::PathBuf, process::{ExitCode, Stdio}, sync::Arc, time::Duration};
+
+use crossterm::{style::{Color, Print, ResetColor, SetForegroundColor, Stylize}, ExecutableCommand};
+use deimosproto::{internal_client::InternalClient, limit::MessageSizeLimit};
+use futures::StreamExt;
+use tokio::{io::{AsyncBufReadExt, BufReader, Lines, Stdin}, process::Command, signal::unix::{signal, SignalKind}, sync::Mutex, task::AbortHandle};
+use tonic::transport::Channel;
+
+use super::{connect, with_pin, AgentCommand, TonicStatusErrorFormat, TonicTransportErrorFormat};
+
+/// How the agent asks the user to approve a token request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AgentPrompter {
+    /// A desktop notification with actions, shown with `notify-send`
+    Desktop,
+    /// A line read from the terminal
+    Terminal,
+}
+
+/// Answer given by the user to a prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AgentDecision {
+    Approve,
+    Deny,
+    /// The prompt was closed without an answer, leaving the request pending
+    Dismiss,
+}
+
+/// State of the agent's connection to the daemon
+enum AgentState {
+    /// Waiting to connect after the given number of failed attempts
+    Connecting { attempts: u32 },
+    /// Connected and watching for changes to the pending token requests
+    Watching {
+        client: InternalClient<Channel>,
+        events: Box<tonic::Streaming<deimosproto::PendingTokenEvent>>,
+    },
+}
+
+/// Username and request time identifying a token request, so that a new request from the same
+/// user is prompted for again
+type AgentRequestKey = (String, i64);
+
+/// Lines of standard input shared by every terminal prompt, and held while a prompt or PIN entry
+/// is using the terminal so that prompts are shown one at a time
+type AgentTerminal = Arc<Mutex<Lines<BufReader<Stdin>>>>;
+
+struct Agent {
+    bind: PathBuf,
+    timeout: u64,
+    prompter: AgentPrompter,
+    /// Prompt tasks for every request that was pending when last seen, whether or not the prompt
+    /// has been answered
+    shown: HashMap<AgentRequestKey, AbortHandle>,
+    terminal: AgentTerminal,
+}
+
+/// Run the agent until it receives SIGTERM or SIGINT
+pub async fn agent(cmd: AgentCommand, bind: PathBuf, timeout: u64) -> std::io::Result<ExitCode> {
+    let mut stdout = std::io::stdout();
+    let prompter = match cmd.terminal {
+        true => AgentPrompter::Terminal,
+        false => AgentPrompter::detect().await,
+    };
+
+    if prompter == AgentPrompter::Terminal && !std::io::stdin().is_terminal() {
+        return stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print("No notification service with actions is available and standard input is not a terminal\n"))?
+            .execute(ResetColor)
+            .map(|_| ExitCode::FAILURE)
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut agent = Agent {
+        bind,
+        timeout,
+        prompter,
+        shown: HashMap::new(),
+        terminal: Arc::new(Mutex::new(BufReader::new(tokio::io::stdin()).lines())),
+    };
+
+    stdout.execute(Print(format_args!(
+        "Prompting for token requests {}\n",
+        match prompter {
+            AgentPrompter::Desktop => "with desktop notifications",
+            AgentPrompter::Terminal => "in the terminal",
+        }
+    )))?;
+
+    let mut state = AgentState::Connecting { attempts: 0 };
+    loop {
+        state = match state {
+            AgentState::Connecting { attempts } => tokio::select! {
+                _ = terminate.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+                state = agent.connect(attempts) => state?,
+            },
+            AgentState::Watching { client, mut events } => {
+                let event = tokio::select! {
+                    _ = terminate.recv() => break,
+                    _ = tokio::signal::ctrl_c() => break,
+                    event = events.next() => event,
+                };
+
+                match event {
+                    Some(Ok(event)) => {
+                        agent.handle(&client, event)?;
+                        AgentState::Watching { client, events }
+                    },
+                    Some(Err(e)) => {
+                        stdout
+                            .execute(SetForegroundColor(Color::Yellow))?
+                            .execute(Print(format_args!("Token request stream failed, reconnecting: {}\n", TonicStatusErrorFormat(e))))?
+                            .execute(ResetColor)?;
+                        AgentState::Connecting { attempts: 0 }
+                    },
+                    None => {
+                        stdout
+                            .execute(SetForegroundColor(Color::Yellow))?
+                            .execute(Print("Token request stream closed by daemon, reconnecting\n"))?
+                            .execute(ResetColor)?;
+                        AgentState::Connecting { attempts: 0 }
+                    },
+                }
+            },
+        };
+    }
+
+    agent.stop();
+    Ok(ExitCode::SUCCESS)
+}
+
+impl Agent {
+    /// Longest time waited between attempts to connect to the daemon
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+    /// Attempt to connect to the daemon and prompt for every request that is already pending,
+    /// waiting longer before each attempt after the first failure
+    async fn connect(&mut self, attempts: u32) -> std::io::Result<AgentState> {
+        let mut stdout = std::io::stdout();
+        if attempts > 0 {
+            let delay = Duration::from_secs(1 << attempts.min(5)).min(Self::MAX_RETRY_DELAY);
+            tokio::time::sleep(delay).await;
+        }
+
+        let channel = match connect(self.bind.clone(), self.timeout).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                //Only the first failure is reported so that waiting for the daemon to start does
+                //not fill the terminal
+                if attempts == 0 {
+                    stdout
+                        .execute(SetForegroundColor(Color::Yellow))?
+                        .execute(Print(format_args!("Waiting for the daemon at {}: {}\n", self.bind.display(), TonicTransportErrorFormat(e))))?
+                        .execute(ResetColor)?;
+                }
+
+                return Ok(AgentState::Connecting { attempts: attempts + 1 })
+            }
+        };
+
+        let limit = MessageSizeLimit::DEFAULT.bytes();
+        let mut client = InternalClient::new(channel)
+            .max_decoding_message_size(limit)
+            .max_encoding_message_size(limit);
+
+        //The stream is opened before the pending requests are listed so that no request added in
+        //between is missed
+        let result = match client.watch_pending(deimosproto::WatchPendingRequest {}).await {
+            Ok(events) => client
+                .get_pending(deimosproto::GetPendingRequest {})
+                .await
+                .map(|pending| (Box::new(events.into_inner()), pending.into_inner().pending)),
+            Err(e) => Err(e),
+        };
+
+        let (events, pending) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                stdout
+                    .execute(SetForegroundColor(Color::Red))?
+                    .execute(Print(format_args!("Failed to watch token requests: {}\n", TonicStatusErrorFormat(e))))?
+                    .execute(ResetColor)?;
+                return Ok(AgentState::Connecting { attempts: attempts + 1 })
+            }
+        };
+
+        stdout
+            .execute(SetForegroundColor(Color::Green))?
+            .execute(Print(format_args!("Connected to the daemon, {} pending token requests\n", pending.len())))?
+            .execute(ResetColor)?;
+
+        //Requests are not kept by the daemon across restarts, so prompts for requests that are no
+        //longer pending are closed
+        let keys = pending.iter().map(Self::key).collect::<HashSet<_>>();
+        self.shown.retain(|key, task| {
+            let keep = keys.contains(key);
+            if !keep {
+                task.abort();
+            }
+
+            keep
+        });
+
+        for request in pending {
+            self.show(&client, request);
+        }
+
+        Ok(AgentState::Watching { client, events })
+    }
+
+    /// Prompt for requests as they are added, and close prompts for requests that were answered
+    /// elsewhere or expired
+    fn handle(&mut self, client: &InternalClient<Channel>, event: deimosproto::PendingTokenEvent) -> std::io::Result<()> {
+        let Some(request) = event.request.clone() else { return Ok(()) };
+
+        let verb = match event.kind() {
+            deimosproto::PendingTokenEventKind::Added => "Requested",
+            deimosproto::PendingTokenEventKind::Approved => "Approved",
+            deimosproto::PendingTokenEventKind::Denied => "Denied",
+            deimosproto::PendingTokenEventKind::Expired => "Expired",
+            deimosproto::PendingTokenEventKind::Revoked => "Revoked unused",
+        };
+
+        std::io::stdout().execute(Print(format_args!("{} token for {}\n", verb, request.username)))?;
+
+        match event.kind() {
+            deimosproto::PendingTokenEventKind::Added => self.show(client, request),
+            _ => if let Some(task) = self.shown.remove(&Self::key(&request)) {
+                task.abort();
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Start a prompt for the given request unless one was already shown for it
+    fn show(&mut self, client: &InternalClient<Channel>, request: deimosproto::PendingTokenRequest) {
+        let key = Self::key(&request);
+        if self.shown.contains_key(&key) {
+            return
+        }
+
+        let prompter = self.prompter;
+        let client = client.clone();
+        let terminal = self.terminal.clone();
+        let task = tokio::task::spawn(async move {
+            //Failing to write to standard output leaves nothing to report the failure to
+            let _ = prompt(prompter, client, request, terminal).await;
+        });
+
+        self.shown.insert(key, task.abort_handle());
+    }
+
+    /// Close every open prompt, killing any notification process that is still waiting
+    fn stop(&mut self) {
+        for (_, task) in self.shown.drain() {
+            task.abort();
+        }
+    }
+
+    fn key(request: &deimosproto::PendingTokenRequest) -> AgentRequestKey {
+        (request.username.clone(), request.requested_dt)
+    }
+}
+
+impl AgentPrompter {
+    /// Use desktop notifications if a session bus is available and the installed `notify-send`
+    /// supports actions
+    async fn detect() -> Self {
+        if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() {
+            return Self::Terminal
+        }
+
+        let help = Command::new("notify-send")
+            .arg("--help")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .await;
+
+        match help {
+            Ok(output) if String::from_utf8_lossy(&output.stdout).contains("--action") => Self::Desktop,
+            _ => Self::Terminal,
+        }
+    }
+}
+
+/// Ask the user to approve or deny the given request, and send their answer to the daemon
+async fn prompt(
+    prompter: AgentPrompter,
+    client: InternalClient<Channel>,
+    request: deimosproto::PendingTokenRequest,
+    terminal: AgentTerminal,
+) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    let decision = match prompter {
+        AgentPrompter::Desktop => notify(&request).await,
+        AgentPrompter::Terminal => {
+            let mut lines = terminal.lock().await;
+            ask(&mut lines, &request).await
+        },
+    };
+
+    let _terminal = terminal.lock().await;
+    let decision = match decision {
+        Ok(decision) => decision,
+        Err(e) => return stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("Failed to prompt for token request from {}: {}\n", request.username, e)))?
+            .execute(ResetColor)
+            .map(|_| ())
+    };
+
+    let response = match decision {
+        AgentDecision::Approve => with_pin(&mut stdout, |pin| {
+            let mut client = client.clone();
+            let request = deimosproto::ApproveRequest {
+                username: request.username.clone(),
+                pin,
+                pods: Vec::new(),
+                groups: Vec::new(),
+                print_token: false,
+                attach: false,
+            };
+
+            async move { client.approve(request).await.map(|response| response.map(|_| ())) }
+        }).await?,
+        AgentDecision::Deny => with_pin(&mut stdout, |pin| {
+            let mut client = client.clone();
+            let request = deimosproto::DenyRequest {
+                username: request.username.clone(),
+                reason: String::new(),
+                pin,
+            };
+
+            async move { client.deny(request).await.map(|response| response.map(|_| ())) }
+        }).await?,
+        AgentDecision::Dismiss => return stdout
+            .execute(Print(format_args!("Dismissed token request for {}, it is still pending\n", request.username)))
+            .map(|_| ()),
+    };
+
+    //Successful answers are reported by the event that the daemon sends for the request
+    match response {
+        Ok(_) => Ok(()),
+        Err(e) => stdout
+            .execute(SetForegroundColor(Color::Red))?
+            .execute(Print(format_args!("Failed to answer token request for {}: {}\n", request.username, TonicStatusErrorFormat(e))))?
+            .execute(ResetColor)
+            .map(|_| ()),
+    }
+}
+
+/// Show a desktop notification for the given request and wait for one of its actions to be
+/// chosen. The notification process is killed if the prompt is closed first
+async fn notify(request: &deimosproto::PendingTokenRequest) -> std::io::Result<AgentDecision> {
+    let output = Command::new("notify-send")
+        .arg("--app-name=deimosctl")
+        .arg("--urgency=critical")
+        .arg("--wait")
+        .arg("--action=approve=Approve")
+        .arg("--action=deny=Deny")
+        .arg(format!("Token request from {}", request.username))
+        .arg(describe(request))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("notify-send exited with {}", output.status)))
+    }
+
+    Ok(match String::from_utf8_lossy(&output.stdout).trim() {
+        "approve" => AgentDecision::Approve,
+        "deny" => AgentDecision::Deny,
+        _ => AgentDecision::Dismiss,
+    })
+}
+
+/// Ask for an answer to the given request on the terminal, dismissing the prompt if standard
+/// input is closed
+async fn ask(lines: &mut Lines<BufReader<Stdin>>, request: &deimosproto::PendingTokenRequest) -> std::io::Result<AgentDecision> {
+    std::io::stdout()
+        .execute(Print(format_args!("\n{}\n", describe(request))))?
+        .execute(Print(format_args!("Approve token for {}? [a]pprove, [d]eny, or [i]gnore: ", request.username.clone().bold())))?;
+
+    let answer = lines.next_line().await?.unwrap_or_default();
+    Ok(match answer.trim().to_ascii_lowercase().as_str() {
+        "a" | "approve" => AgentDecision::Approve,
+        "d" | "deny" => AgentDecision::Deny,
+        _ => AgentDecision::Dismiss,
+    })
+}
+
+/// Describe where and when the given request was made, and the access an approved token grants
+fn describe(request: &deimosproto::PendingTokenRequest) -> String {
+    let at = chrono::DateTime::from_timestamp(request.requested_dt, 0)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M");
+
+    match request.requester_address.is_empty() {
+        true => format!("Requested at {}, approving grants full control of every pod", at),
+        false => format!("Requested from {} at {}, approving grants full control of every pod", request.requester_address, at),
+    }
+}
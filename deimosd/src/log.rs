@@ -0,0 +1,464 @@
+//! Runtime-adjustable log filtering for the daemon, allowing log levels to be changed without a
+//! restart via signals or the internal API, and the output that logs are written to.
+//! Logs are written as compact text to standard output until the config file is loaded, after
+//! which they may be written as JSON or to a log file that is rotated by size
+
+use std::{collections::BTreeMap, ffi::OsString, fs::{File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, str::FromStr, sync::Mutex};
+
+use tracing::level_filters::{LevelFilter, ParseLevelFilterError};
+use tracing_subscriber::{filter::Targets, layer::{Layered, SubscriberExt}, reload, util::SubscriberInitExt, Layer, Registry};
+
+use crate::pod::resources::PodMemorySize;
+
+/// User-provided configuration for log filtering and output
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Map of tracing targets to the maximum level logged for them, overriding the defaults
+    #[serde(default)]
+    pub targets: BTreeMap<String, String>,
+    /// Maximum level logged for targets not given in `targets` or the defaults, which are not
+    /// logged at all if not given
+    #[serde(default)]
+    pub level: Option<String>,
+    /// Format that log lines are written in
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Path of a file to write logs to instead of standard output, with the time of each line
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// Size that the log file may grow to before it is moved aside and a new file is started
+    #[serde(default = "LogConfig::default_max_size")]
+    pub max_size: PodMemorySize,
+    /// Number of previous log files kept when the log file is rotated, named by appending `.1`,
+    /// `.2`, and so on to the log file's path
+    #[serde(default = "LogConfig::default_files")]
+    pub files: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Compact human-readable lines, colored when written to standard output
+    #[default]
+    Text,
+    /// A JSON object for each event, for log collectors
+    Json,
+}
+
+/// Layer writing formatted events, replaced once the config file has been loaded
+type LogOutput = Box<dyn Layer<Layered<reload::Layer<Targets, Registry>, Registry>> + Send + Sync>;
+
+/// Handle to the global subscriber's filter that allows log levels to be modified at runtime
+pub struct LogHandle {
+    reload: reload::Handle<Targets, Registry>,
+    output: reload::Handle<LogOutput, Layered<reload::Layer<Targets, Registry>, Registry>>,
+    state: Mutex<LogFilterState>,
+    /// Path to the config file that log levels may be persisted to
+    config_path: PathBuf,
+}
+
+struct LogFilterState {
+    /// Levels set from the defaults and config file at startup
+    configured: BTreeMap<String, LevelFilter>,
+    /// Levels currently applied when not overridden by [LogFilterState::trace]
+    current: BTreeMap<String, LevelFilter>,
+    /// Level of targets that are not given a level
+    default: Option<LevelFilter>,
+    /// Set when all targets have been switched to TRACE by a signal
+    trace: bool,
+}
+
+/// Log file that is moved aside and replaced with an empty file when it grows too large
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    files: usize,
+    file: File,
+    /// Size of the current log file
+    written: u64,
+}
+
+impl LogConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_size() -> PodMemorySize {
+        PodMemorySize::from_mib(16)
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_files() -> usize {
+        5
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            targets: BTreeMap::new(),
+            level: None,
+            format: LogFormat::default(),
+            file: None,
+            max_size: Self::default_max_size(),
+            files: Self::default_files(),
+        }
+    }
+}
+
+impl LogHandle {
+    /// Install the global tracing subscriber with the default log levels and return a handle that
+    /// can be used to modify them later
+    pub fn init(config_path: impl Into<PathBuf>) -> Self {
+        let (subscriber, handle) = Self::new(config_path.into());
+        subscriber.init();
+        handle
+    }
+
+    /// Create the subscriber with the default log levels and a handle that modifies it
+    fn new(config_path: PathBuf) -> (impl tracing::Subscriber + Send + Sync, Self) {
+        let configured = BTreeMap::from([
+            (String::from("bollard"), LevelFilter::ERROR),
+            (String::from("deimosd"), LevelFilter::TRACE),
+            (String::from("deimosproto"), LevelFilter::TRACE),
+            (String::from("tonic"), LevelFilter::INFO),
+        ]);
+
+        let (filter, reload) = reload::Layer::new(Self::filter(&configured, None));
+        let (output, output_reload) = reload::Layer::new(Self::output(LogFormat::Text, None));
+
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(output);
+
+        let handle = Self {
+            reload,
+            output: output_reload,
+            state: Mutex::new(LogFilterState {
+                current: configured.clone(),
+                configured,
+                default: None,
+                trace: false,
+            }),
+            config_path,
+        };
+
+        (subscriber, handle)
+    }
+
+    /// Apply the log levels given in the config file on top of the defaults, and switch to the
+    /// configured output. If the log file cannot be opened, logs continue to be written to
+    /// standard output
+    pub fn configure(&self, config: &LogConfig) -> Result<(), LogLevelError> {
+        {
+            let mut state = self.lock();
+            for (target, level) in config.targets.iter() {
+                let level = LevelFilter::from_str(level)?;
+                state.configured.insert(target.clone(), level);
+            }
+
+            state.default = config.level.as_deref().map(LevelFilter::from_str).transpose()?;
+            state.current = state.configured.clone();
+            self.apply(&state)?;
+        }
+
+        let file = config.file.as_ref().and_then(|path| match LogFile::open(path, config.max_size.bytes(), config.files) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::warn!("Failed to open log file {}, logging to standard output instead: {}", path.display(), e);
+                None
+            }
+        });
+
+        if let Some(path) = config.file.as_ref().filter(|_| file.is_some()) {
+            tracing::info!("Writing logs to {}", path.display());
+        }
+
+        self.output.reload(Self::output(config.format, file)).map_err(Into::into)
+    }
+
+    /// Switch all targets between their configured levels and TRACE
+    pub fn toggle_trace(&self) -> Result<(), LogLevelError> {
+        let mut state = self.lock();
+        state.trace = !state.trace;
+        if !state.trace {
+            state.current = state.configured.clone();
+        }
+
+        self.apply(&state)?;
+        tracing::info!(
+            "Log levels switched to {}",
+            if state.trace { "TRACE for all targets" } else { "configured levels" }
+        );
+
+        Ok(())
+    }
+
+    /// Set the maximum level logged for the given target, optionally writing the change to the
+    /// config file. The config file is written first, so the level is left unchanged if it
+    /// cannot be persisted
+    pub fn set(&self, target: String, level: &str, persist: bool) -> Result<(), LogLevelError> {
+        let level = LevelFilter::from_str(level)?;
+        let mut state = self.lock();
+        if persist {
+            self.persist(&target, level)?;
+            state.configured.insert(target.clone(), level);
+        }
+
+        let previous = state.current.insert(target.clone(), level);
+        let trace = std::mem::replace(&mut state.trace, false);
+        if let Err(e) = self.apply(&state) {
+            match previous {
+                Some(level) => state.current.insert(target, level),
+                None => state.current.remove(&target),
+            };
+
+            state.trace = trace;
+            return Err(e)
+        }
+
+        tracing::info!("Set log level for target '{}' to {}", target, level);
+        Ok(())
+    }
+
+    /// Get the effective log level of all filtered targets
+    pub fn targets(&self) -> Vec<(String, LevelFilter)> {
+        let state = self.lock();
+        state
+            .current
+            .iter()
+            .map(|(target, level)| (target.clone(), if state.trace { LevelFilter::TRACE } else { *level }))
+            .collect()
+    }
+
+    /// Write the level of the given target to the `[log]` section of the config file, if the
+    /// section exists
+    fn persist(&self, target: &str, level: LevelFilter) -> Result<(), LogLevelError> {
+        let config_str = std::fs::read_to_string(&self.config_path)
+            .map_err(|err| LogLevelError::ConfigRead { path: self.config_path.clone(), err })?;
+
+        std::fs::write(&self.config_path, Self::edit_config(&config_str, target, level)?)
+            .map_err(|err| LogLevelError::ConfigWrite { path: self.config_path.clone(), err })
+    }
+
+    /// Set the level of one target in the `log.targets` table of the given config file contents,
+    /// leaving the rest of the file including its comments and formatting unchanged
+    fn edit_config(config_str: &str, target: &str, level: LevelFilter) -> Result<String, LogLevelError> {
+        let mut config = config_str.parse::<toml_edit::DocumentMut>()?;
+        let log = config
+            .get_mut("log")
+            .and_then(toml_edit::Item::as_table_like_mut)
+            .ok_or(LogLevelError::NoLogSection)?;
+
+        if !log.contains_key("targets") {
+            log.insert("targets", toml_edit::table());
+        }
+
+        let targets = log
+            .get_mut("targets")
+            .and_then(toml_edit::Item::as_table_like_mut)
+            .ok_or(LogLevelError::TargetsNotTable)?;
+
+        targets.insert(target, toml_edit::value(level.to_string().to_lowercase()));
+        Ok(config.to_string())
+    }
+
+    fn apply(&self, state: &LogFilterState) -> Result<(), LogLevelError> {
+        let filter = match state.trace {
+            true => Targets::new().with_targets(state.current.keys().map(|target| (target.clone(), LevelFilter::TRACE))),
+            false => Self::filter(&state.current, state.default),
+        };
+
+        self.reload.reload(filter).map_err(Into::into)
+    }
+
+    fn filter(levels: &BTreeMap<String, LevelFilter>, default: Option<LevelFilter>) -> Targets {
+        let targets = Targets::new().with_targets(levels.iter().map(|(target, level)| (target.clone(), *level)));
+        match default {
+            Some(default) => targets.with_default(default),
+            None => targets,
+        }
+    }
+
+    /// Create the layer writing events in the given format to a log file, or to standard output
+    /// without times if no file is given as the service manager records the time of each line
+    fn output(format: LogFormat, file: Option<LogFile>) -> LogOutput {
+        let layer = tracing_subscriber::fmt::layer();
+        match (format, file) {
+            (LogFormat::Text, None) => layer.compact().with_ansi(true).without_time().boxed(),
+            (LogFormat::Text, Some(file)) => layer.compact().with_ansi(false).with_writer(Mutex::new(file)).boxed(),
+            (LogFormat::Json, None) => layer.json().without_time().boxed(),
+            (LogFormat::Json, Some(file)) => layer.json().with_writer(Mutex::new(file)).boxed(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LogFilterState> {
+        self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl LogFile {
+    /// Open the log file for appending, creating its directory if it does not exist
+    fn open(path: &Path, max_size: u64, files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            files,
+            file,
+            written,
+        })
+    }
+
+    /// Move the current log file aside, discarding the oldest log file, and start a new file.
+    /// The current log file is truncated if no previous log files are kept
+    fn rotate(&mut self) -> io::Result<()> {
+        for idx in (1..self.files).rev() {
+            rename_existing(&rotated(&self.path, idx), &rotated(&self.path, idx + 1))?;
+        }
+
+        if self.files > 0 {
+            rename_existing(&self.path, &rotated(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Get the path of the given previous log file, where `1` is the most recent
+fn rotated(path: &Path, idx: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{}", idx));
+    PathBuf::from(path)
+}
+
+/// Rename a file, ignoring files that do not exist
+fn rename_existing(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogLevelError {
+    #[error("Invalid log level: {0}")]
+    Parse(#[from] ParseLevelFilterError),
+    #[error("Failed to reload log filter: {0}")]
+    Reload(#[from] reload::Error),
+    #[error("Config file has no [log] section to persist log levels to")]
+    NoLogSection,
+    #[error("Failed to read config file {}: {}", path.display(), err)]
+    ConfigRead {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    #[error("Failed to parse config file: {0}")]
+    ConfigParse(#[from] toml_edit::TomlError),
+    #[error("The targets key of the config file's [log] section is not a table")]
+    TargetsNotTable,
+    #[error("Failed to write config file {}: {}", path.display(), err)]
+    ConfigWrite {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Daemon config
+[docker]
+host = \"unix:///var/run/docker.sock\"
+
+[log]
+# Quieter logs for Docker
+targets = { bollard = \"error\" } # keep this
+level = \"warn\"
+";
+
+    #[test]
+    fn edit_changes_only_the_target() {
+        let edited = LogHandle::edit_config(CONFIG, "deimosd::pod", LevelFilter::DEBUG).unwrap();
+        assert!(edited.contains("\"deimosd::pod\" = \"debug\" } # keep this\n"));
+
+        //Every line other than the edited table is left as it was
+        let unchanged = |config: &str| config.lines().filter(|line| !line.starts_with("targets")).map(str::to_owned).collect::<Vec<_>>();
+        assert_eq!(unchanged(&edited), unchanged(CONFIG));
+
+        let edited = LogHandle::edit_config(&edited, "bollard", LevelFilter::INFO).unwrap();
+        assert!(edited.contains("bollard = \"info\""));
+        assert!(edited.contains("# Quieter logs for Docker"));
+        assert!(edited.contains("# keep this"));
+        assert!(edited.contains("level = \"warn\""));
+    }
+
+    #[test]
+    fn edit_adds_targets_table() {
+        let config = "[log]\n# Only warnings\nlevel = \"warn\"\n";
+        let edited = LogHandle::edit_config(config, "tonic", LevelFilter::OFF).unwrap();
+        assert_eq!(edited, "[log]\n# Only warnings\nlevel = \"warn\"\n\n[log.targets]\ntonic = \"off\"\n");
+    }
+
+    #[test]
+    fn edit_requires_log_section() {
+        assert!(matches!(LogHandle::edit_config("[docker]\n", "tonic", LevelFilter::OFF), Err(LogLevelError::NoLogSection)));
+        assert!(matches!(LogHandle::edit_config("[log]\ntargets = 1\n", "tonic", LevelFilter::OFF), Err(LogLevelError::TargetsNotTable)));
+        assert!(matches!(LogHandle::edit_config("[log", "tonic", LevelFilter::OFF), Err(LogLevelError::ConfigParse(..))));
+    }
+
+    fn level(handle: &LogHandle, target: &str) -> Option<LevelFilter> {
+        handle.targets().into_iter().find(|(name, _)| name == target).map(|(_, level)| level)
+    }
+
+    #[test]
+    fn failed_persist_leaves_level_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_subscriber, handle) = LogHandle::new(dir.path().join("missing.toml"));
+
+        assert!(matches!(handle.set(String::from("tonic"), "debug", true), Err(LogLevelError::ConfigRead { .. })));
+        assert_eq!(level(&handle, "tonic"), Some(LevelFilter::INFO));
+
+        handle.set(String::from("tonic"), "debug", false).unwrap();
+        assert_eq!(level(&handle, "tonic"), Some(LevelFilter::DEBUG));
+    }
+
+    #[test]
+    fn persisted_level_is_written_and_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deimos.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let (_subscriber, handle) = LogHandle::new(path.clone());
+
+        handle.set(String::from("tonic"), "trace", true).unwrap();
+        assert_eq!(level(&handle, "tonic"), Some(LevelFilter::TRACE));
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("tonic = \"trace\""));
+        assert!(written.starts_with("# Daemon config\n"));
+        assert!(!written.contains("deimosd ="));
+    }
+}
@@ -1,13 +1,12 @@
 #![deny(unused_must_use)]
 
-use std::process::ExitCode;
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{prelude::*, util::SubscriberInitExt, FmtSubscriber};
+use std::{path::Path, process::ExitCode};
 
-use deimosproto::util;
-use serde::Deserialize;
-use server::{Deimos, DeimosConfig};
+use log::LogHandle;
+use pod::{docker::record::replay_events, validate::run_validate};
+use server::{doctor::run_doctor, Deimos, DeimosConfig};
 
+mod log;
 mod pod;
 mod server;
 
@@ -15,44 +14,43 @@ const CONFIG_PATH: &str = "./deimos.toml";
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    let filter = tracing_subscriber::filter::Targets::new()
-        .with_target("bollard", LevelFilter::ERROR)
-        .with_target("deimosd", LevelFilter::TRACE)
-        .with_target("deimosproto", LevelFilter::TRACE)
-        .with_target("tonic", LevelFilter::INFO);
-
-    let subscriber = FmtSubscriber::builder()
-        .compact()
-        .with_max_level(LevelFilter::TRACE)
-        .with_ansi(true)
-        .without_time()
-        .finish();
-
-    subscriber.with(filter).init();
-
-    let config_buf = match util::load_check_permissions(CONFIG_PATH).await {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("Failed to open config file {CONFIG_PATH}: {e}");
-            return ExitCode::FAILURE;
-        }
-    };
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("doctor") => return run_doctor(Path::new(CONFIG_PATH)).await,
+        Some("validate") => return match args.next() {
+            Some(dir) => run_validate(Path::new(&dir)).await,
+            None => {
+                eprintln!("Usage: deimosd validate <dir>");
+                ExitCode::FAILURE
+            }
+        },
+        //Undocumented mode used to check changes to event handling against recorded Docker events
+        Some("replay-events") => return match args.next() {
+            Some(path) => replay_events(Path::new(&path)),
+            None => {
+                eprintln!("Usage: deimosd replay-events <file>");
+                ExitCode::FAILURE
+            }
+        },
+        _ => (),
+    }
 
-    let Ok(config_str) = String::from_utf8(config_buf) else {
-        tracing::error!("Cannot decode config file '{}' as UTF-8", CONFIG_PATH);
-        return ExitCode::FAILURE;
-    };
+    let log = LogHandle::init(CONFIG_PATH);
 
-    let toml_de = toml::Deserializer::new(&config_str);
-    let conf = match DeimosConfig::deserialize(toml_de) {
+    let conf = match DeimosConfig::load(Path::new(CONFIG_PATH)).await {
         Ok(v) => v,
         Err(e) => {
-            tracing::error!("Failed to parse config file at {CONFIG_PATH}: {e}");
+            tracing::error!("{e}");
             return ExitCode::FAILURE;
         }
     };
 
-    match Deimos::run(conf).await {
+    if let Err(e) = log.configure(&conf.log) {
+        tracing::error!("Failed to apply log configuration from {CONFIG_PATH}: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    match Deimos::run(conf, log).await {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             tracing::error!("{e}");
@@ -0,0 +1,284 @@
+//! Evaluation of pod resource usage alert rules against periodically sampled usage
+
+use std::{fmt, sync::PoisonError};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::{config::{PodAlertConfig, PodAlertMetric}, Pod};
+
+/// Resource usage of a pod's container sampled at a single point in time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PodUsage {
+    /// CPU usage in percent of a single core
+    pub cpu: Option<f64>,
+    /// Memory usage in percent of the container's memory limit
+    pub memory: Option<f64>,
+    /// Size of the container's writable layer in percent of the pod's disk limit
+    pub disk: Option<f64>,
+}
+
+/// State of a single alert rule for a pod
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PodAlertState {
+    /// Usage is below the threshold
+    #[default]
+    Normal,
+    /// Usage has been at or above the threshold since the given time, but not for long enough to
+    /// fire the alert
+    Pending(DateTime<Utc>),
+    /// The alert fired at the given time and usage has not yet dropped below the clear threshold
+    Firing(DateTime<Utc>),
+}
+
+/// A change in an alert's state that should be reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodAlertTransition {
+    Fired,
+    Cleared,
+}
+
+impl PodAlertConfig {
+    /// Get the usage below which a firing alert is cleared
+    pub fn clear_threshold(&self) -> f64 {
+        self.clear.unwrap_or(self.threshold - 10.).min(self.threshold)
+    }
+
+    /// Advance the given alert state with a usage value observed at the given time, returning
+    /// the transition that occurred if the alert fired or cleared
+    pub fn evaluate(&self, state: &mut PodAlertState, value: f64, now: DateTime<Utc>) -> Option<PodAlertTransition> {
+        let sustained = TimeDelta::try_seconds(self.sustained.try_into().unwrap_or(i64::MAX)).unwrap_or(TimeDelta::MAX);
+
+        match *state {
+            PodAlertState::Normal | PodAlertState::Pending(..) if value < self.threshold => {
+                *state = PodAlertState::Normal;
+                None
+            },
+            PodAlertState::Normal => {
+                *state = PodAlertState::Pending(now);
+                self.evaluate(state, value, now)
+            },
+            PodAlertState::Pending(since) => match now - since >= sustained {
+                true => {
+                    *state = PodAlertState::Firing(now);
+                    Some(PodAlertTransition::Fired)
+                },
+                false => None,
+            },
+            PodAlertState::Firing(..) => match value < self.clear_threshold() {
+                true => {
+                    *state = PodAlertState::Normal;
+                    Some(PodAlertTransition::Cleared)
+                },
+                false => None,
+            },
+        }
+    }
+}
+
+impl PodUsage {
+    /// Get the value of the given metric, if it could be measured
+    pub const fn get(&self, metric: PodAlertMetric) -> Option<f64> {
+        match metric {
+            PodAlertMetric::Cpu => self.cpu,
+            PodAlertMetric::Memory => self.memory,
+            PodAlertMetric::Disk => self.disk,
+        }
+    }
+}
+
+impl Pod {
+    /// Get all alert rules of this pod that are currently firing, with the time they fired at
+    pub fn active_alerts(&self) -> Vec<(PodAlertConfig, DateTime<Utc>)> {
+        let states = self.alerts.lock().unwrap_or_else(PoisonError::into_inner);
+        self
+            .config()
+            .alerts
+            .iter()
+            .zip(states.iter())
+            .filter_map(|(rule, state)| match *state {
+                PodAlertState::Firing(since) => Some((rule.clone(), since)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Evaluate all alert rules of this pod against the given usage sample, returning the rules
+    /// that fired or cleared. Subscribers are notified if any did, as notifications carry the
+    /// alerts that are firing
+    pub fn observe_usage(&self, usage: &PodUsage, now: DateTime<Utc>) -> Vec<(PodAlertConfig, PodAlertTransition)> {
+        let transitions = {
+            let mut states = self.alerts.lock().unwrap_or_else(PoisonError::into_inner);
+            self
+                .config()
+                .alerts
+                .iter()
+                .zip(states.iter_mut())
+                .filter_map(|(rule, state)| {
+                    let value = usage.get(rule.metric)?;
+                    Some((rule.clone(), rule.evaluate(state, value, now)?))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if !transitions.is_empty() {
+            self.state().touch();
+        }
+
+        transitions
+    }
+
+    /// Return all alert rules of this pod to their normal state, as alerts are only evaluated
+    /// while the pod is enabled. Returns the rules that were firing, notifying subscribers if
+    /// there were any
+    pub fn reset_alerts(&self) -> Vec<PodAlertConfig> {
+        let cleared = {
+            let mut states = self.alerts.lock().unwrap_or_else(PoisonError::into_inner);
+            self
+                .config()
+                .alerts
+                .iter()
+                .zip(states.iter_mut())
+                .filter_map(|(rule, state)| {
+                    let firing = matches!(state, PodAlertState::Firing(..));
+                    *state = PodAlertState::Normal;
+                    firing.then(|| rule.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if !cleared.is_empty() {
+            self.state().touch();
+        }
+
+        cleared
+    }
+}
+
+impl fmt::Display for PodAlertConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} usage at or above {}%", self.metric, self.threshold)?;
+        match self.sustained {
+            0 => Ok(()),
+            secs => write!(f, " for {}s", secs),
+        }
+    }
+}
+
+impl fmt::Display for PodAlertMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cpu => "CPU",
+            Self::Memory => "memory",
+            Self::Disk => "disk",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use crate::pod::PodState;
+
+    use super::*;
+
+    fn rule(threshold: f64, clear: Option<f64>, sustained: u64) -> PodAlertConfig {
+        PodAlertConfig { metric: PodAlertMetric::Cpu, threshold, clear, sustained }
+    }
+
+    #[test]
+    fn fires_after_sustained_usage() {
+        let rule = rule(80., None, 60);
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let mut state = PodAlertState::Normal;
+
+        assert_eq!(rule.evaluate(&mut state, 79.9, at(0)), None);
+        assert_eq!(state, PodAlertState::Normal);
+
+        assert_eq!(rule.evaluate(&mut state, 80., at(10)), None);
+        assert_eq!(state, PodAlertState::Pending(at(10)));
+        assert_eq!(rule.evaluate(&mut state, 95., at(69)), None);
+        assert_eq!(state, PodAlertState::Pending(at(10)));
+
+        assert_eq!(rule.evaluate(&mut state, 85., at(70)), Some(PodAlertTransition::Fired));
+        assert_eq!(state, PodAlertState::Firing(at(70)));
+        assert_eq!(rule.evaluate(&mut state, 99., at(100)), None);
+    }
+
+    #[test]
+    fn dip_restarts_pending_timer() {
+        let rule = rule(80., None, 60);
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let mut state = PodAlertState::Normal;
+
+        assert_eq!(rule.evaluate(&mut state, 90., at(0)), None);
+        assert_eq!(rule.evaluate(&mut state, 50., at(30)), None);
+        assert_eq!(state, PodAlertState::Normal);
+
+        assert_eq!(rule.evaluate(&mut state, 90., at(40)), None);
+        assert_eq!(rule.evaluate(&mut state, 90., at(90)), None);
+        assert_eq!(rule.evaluate(&mut state, 90., at(100)), Some(PodAlertTransition::Fired));
+    }
+
+    #[test]
+    fn unsustained_rule_fires_immediately() {
+        let rule = rule(80., None, 0);
+        let now = Utc::now();
+        let mut state = PodAlertState::Normal;
+
+        assert_eq!(rule.evaluate(&mut state, 80., now), Some(PodAlertTransition::Fired));
+        assert_eq!(state, PodAlertState::Firing(now));
+    }
+
+    #[test]
+    fn clears_below_clear_threshold() {
+        let now = Utc::now();
+        let rule = rule(80., None, 0);
+        assert_eq!(rule.clear_threshold(), 70.);
+
+        let mut state = PodAlertState::Firing(now);
+        assert_eq!(rule.evaluate(&mut state, 75., now), None);
+        assert_eq!(rule.evaluate(&mut state, 70., now), None);
+        assert_eq!(state, PodAlertState::Firing(now));
+        assert_eq!(rule.evaluate(&mut state, 69.9, now), Some(PodAlertTransition::Cleared));
+        assert_eq!(state, PodAlertState::Normal);
+    }
+
+    #[test]
+    fn clear_threshold_is_clamped() {
+        assert_eq!(rule(80., Some(50.), 0).clear_threshold(), 50.);
+        assert_eq!(rule(80., Some(90.), 0).clear_threshold(), 80.);
+
+        let now = Utc::now();
+        let rule = rule(80., Some(90.), 0);
+        let mut state = PodAlertState::Firing(now);
+        assert_eq!(rule.evaluate(&mut state, 85., now), None);
+        assert_eq!(rule.evaluate(&mut state, 79., now), Some(PodAlertTransition::Cleared));
+    }
+
+    #[tokio::test]
+    async fn transitions_notify_subscribers() {
+        let config = "id = \"alerting\"\nname = \"Alerting\"\n[[alerts]]\nmetric = \"cpu\"\nthreshold = 80.0\n[docker]\nimage = \"alpine:3\"\n";
+        let (_dir, pod) = Pod::test(config).await;
+        let mut rx = pod.state().subscribe();
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        let now = Utc::now();
+        let high = PodUsage { cpu: Some(90.), ..Default::default() };
+        let fired = pod.observe_usage(&high, now);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].1, PodAlertTransition::Fired);
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+        assert_eq!(pod.active_alerts().len(), 1);
+
+        assert!(pod.observe_usage(&high, now).is_empty());
+        assert!(pod.observe_usage(&PodUsage::default(), now).is_empty());
+
+        assert_eq!(pod.reset_alerts().len(), 1);
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+        assert!(pod.active_alerts().is_empty());
+        assert!(pod.reset_alerts().is_empty());
+    }
+}
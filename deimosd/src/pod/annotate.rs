@@ -0,0 +1,318 @@
+//! Annotations reported by the application running inside a pod's container, such as a world
+//! save completing. When a pod's `annotations` option is set, a unix socket is bind-mounted into
+//! the container that accepts one JSON object per line
+
+use std::{collections::BTreeMap, path::PathBuf, sync::{Arc, PoisonError}, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use super::{id::DeimosId, Pod, PodManager};
+
+/// An annotation accepted from a pod's container
+#[derive(Debug, Clone)]
+pub struct PodAnnotation {
+    /// Time that the annotation was received at
+    pub at: DateTime<Utc>,
+    pub level: PodAnnotationLevel,
+    pub message: String,
+    /// Additional key/value pairs provided by the application
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum PodAnnotationLevel {
+    #[default]
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+}
+
+/// A single line written to the annotation socket by the containerized application
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PodAnnotationLine {
+    #[serde(default)]
+    level: PodAnnotationLevel,
+    message: String,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+}
+
+/// Sender used to forward accepted annotations to status stream subscribers
+pub type PodAnnotationSender = tokio::sync::broadcast::Sender<(DeimosId, Arc<PodAnnotation>)>;
+
+/// Handle to the annotation socket of an enabled pod, stopping the listener and removing the
+/// socket once all clones are dropped
+#[derive(Clone)]
+pub struct PodAnnotationListener {
+    _guard: Arc<DropGuard>,
+}
+
+/// Number of annotations accepted from a pod in the current rate limiting window, shared by all
+/// connections to the pod's socket
+#[derive(Debug)]
+struct PodAnnotationLimiter {
+    window: std::sync::Mutex<(Instant, u32)>,
+}
+
+impl PodAnnotationListener {
+    /// Directory in the container that the annotation socket's directory is mounted at
+    pub const CONTAINER_DIR: &str = "/run/deimos";
+    /// Name of the directory in the pod's directory containing the socket
+    const HOST_DIR: &str = ".annotate";
+    const SOCKET_NAME: &str = "annotate.sock";
+    /// Maximum length of a single line in bytes, connections sending longer lines are closed
+    const MAX_LINE: usize = 4096;
+    /// Maximum number of connections to a pod's socket that may be open at once
+    const MAX_CONNECTIONS: usize = 4;
+
+    /// Create the annotation socket for the given pod and begin accepting connections
+    #[cfg(unix)]
+    fn bind(pod: Arc<Pod>, tx: PodAnnotationSender) -> Result<Self, std::io::Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = pod.annotation_dir();
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join(Self::SOCKET_NAME);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        //The containerized application usually runs as a different user than the daemon
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666))?;
+        tracing::trace!("Created annotation socket {} for pod {}", path.display(), pod.id());
+
+        let cancel = CancellationToken::new();
+        tokio::task::spawn(Self::accept(listener, path, pod, tx, cancel.clone()));
+
+        Ok(Self { _guard: Arc::new(cancel.drop_guard()) })
+    }
+
+    /// Accept connections to the annotation socket until cancelled, then remove the socket
+    #[cfg(unix)]
+    async fn accept(listener: tokio::net::UnixListener, path: PathBuf, pod: Arc<Pod>, tx: PodAnnotationSender, cancel: CancellationToken) {
+        let connections = Arc::new(tokio::sync::Semaphore::new(Self::MAX_CONNECTIONS));
+        let limiter = Arc::new(PodAnnotationLimiter::new());
+
+        loop {
+            let stream = tokio::select! {
+                _ = cancel.cancelled() => break,
+                result = listener.accept() => match result {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept connection to annotation socket of pod {}: {}", pod.id(), e);
+                        continue
+                    }
+                }
+            };
+
+            let Ok(permit) = connections.clone().try_acquire_owned() else {
+                tracing::debug!("Rejecting annotation connection for pod {} as too many connections are open", pod.id());
+                continue
+            };
+
+            let pod = pod.clone();
+            let tx = tx.clone();
+            let limiter = limiter.clone();
+            let cancel = cancel.clone();
+            tokio::task::spawn(async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {},
+                    result = Self::read(stream, &pod, &tx, &limiter) => if let Err(e) = result {
+                        tracing::debug!("Closed annotation connection for pod {}: {}", pod.id(), e);
+                    },
+                }
+
+                drop(permit);
+            });
+        }
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::warn!("Failed to remove annotation socket {}: {}", path.display(), e);
+        }
+    }
+
+    /// Read annotations from a single connection until it is closed, returning an error if the
+    /// connection sends a line that is too long
+    async fn read<R: AsyncRead + Unpin>(stream: R, pod: &Pod, tx: &PodAnnotationSender, limiter: &PodAnnotationLimiter) -> Result<(), PodAnnotationError> {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(Self::MAX_LINE as u64 + 1)
+                .read_until(b'\n', &mut line)
+                .await?;
+
+            if read == 0 {
+                return Ok(())
+            }
+
+            if line.len() > Self::MAX_LINE {
+                return Err(PodAnnotationError::LineTooLong)
+            }
+
+            //Invalid lines count towards the rate limit so that junk cannot flood the logs
+            if !limiter.allow() {
+                continue
+            }
+
+            match PodAnnotation::parse(&line) {
+                Ok(annotation) => pod.record_annotation(annotation, tx),
+                Err(e) => tracing::debug!("Rejected annotation from pod {}: {}", pod.id(), e),
+            }
+        }
+    }
+}
+
+impl PodAnnotation {
+    /// Maximum length of an annotation's message in bytes
+    const MAX_MESSAGE: usize = 512;
+    /// Maximum number of key/value pairs attached to an annotation
+    const MAX_FIELDS: usize = 16;
+    /// Maximum length in bytes of a single key or value
+    const MAX_FIELD_LEN: usize = 256;
+
+    /// Parse and validate a single line received from a pod's container
+    fn parse(line: &[u8]) -> Result<Self, PodAnnotationError> {
+        let line = serde_json::from_slice::<PodAnnotationLine>(line)?;
+        let message = line.message.trim();
+
+        if message.is_empty() {
+            return Err(PodAnnotationError::EmptyMessage)
+        }
+
+        if message.len() > Self::MAX_MESSAGE {
+            return Err(PodAnnotationError::MessageTooLong)
+        }
+
+        if line.fields.len() > Self::MAX_FIELDS {
+            return Err(PodAnnotationError::TooManyFields)
+        }
+
+        if line.fields.iter().any(|(k, v)| k.len() > Self::MAX_FIELD_LEN || v.len() > Self::MAX_FIELD_LEN) {
+            return Err(PodAnnotationError::FieldTooLong)
+        }
+
+        let is_control = |s: &str| s.chars().any(char::is_control);
+        if is_control(message) || line.fields.iter().any(|(k, v)| is_control(k) || is_control(v)) {
+            return Err(PodAnnotationError::ControlCharacter)
+        }
+
+        Ok(Self {
+            at: Utc::now(),
+            level: line.level,
+            message: message.to_owned(),
+            fields: line.fields,
+        })
+    }
+}
+
+impl PodAnnotationLimiter {
+    /// Maximum number of lines accepted from a pod in each window
+    const LIMIT: u32 = 30;
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        Self {
+            window: std::sync::Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Record a line received from the pod, returning `false` if it exceeds the rate limit
+    fn allow(&self) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        if now.duration_since(window.0) >= Self::WINDOW {
+            *window = (now, 0);
+        }
+
+        window.1 += 1;
+        window.1 <= Self::LIMIT
+    }
+}
+
+impl Pod {
+    /// Get the directory on the host containing the pod's annotation socket
+    pub fn annotation_dir(&self) -> PathBuf {
+        self.dir.join(PodAnnotationListener::HOST_DIR)
+    }
+
+    /// Get the most recent annotations reported by the pod, oldest first
+    pub fn annotations(&self) -> Vec<Arc<PodAnnotation>> {
+        self.annotations.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+    }
+
+    /// Record an annotation reported by the pod and forward it to status subscribers
+    fn record_annotation(&self, annotation: PodAnnotation, tx: &PodAnnotationSender) {
+        tracing::info!("Pod {} reported: {}", self.id(), annotation.message);
+
+        let annotation = Arc::new(annotation);
+        {
+            let mut annotations = self.annotations.lock().unwrap_or_else(PoisonError::into_inner);
+            annotations.push(annotation.clone());
+        }
+
+        let _ = tx.send((self.id(), annotation));
+    }
+}
+
+impl PodManager {
+    /// Create the annotation socket for the given pod if annotations are enabled in its config.
+    /// Failing to create the socket is logged and does not prevent the pod from being enabled
+    pub(super) fn listen_annotations(&self, pod: &Arc<Pod>) -> Option<PodAnnotationListener> {
+        if !pod.config().annotations {
+            return None
+        }
+
+        #[cfg(unix)]
+        {
+            match PodAnnotationListener::bind(pod.clone(), self.annotations.clone()) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    tracing::warn!("Failed to create annotation socket for pod {}: {}", pod.id(), e);
+                    None
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tracing::warn!("Annotations are enabled for pod {} but are only supported on unix", pod.id());
+            None
+        }
+    }
+
+    /// Subscribe to annotations reported by all pods
+    pub fn subscribe_annotations(&self) -> tokio::sync::broadcast::Receiver<(DeimosId, Arc<PodAnnotation>)> {
+        self.annotations.subscribe()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodAnnotationError {
+    #[error("Failed to read from socket: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line exceeds the maximum length of {} bytes", PodAnnotationListener::MAX_LINE)]
+    LineTooLong,
+    #[error("Invalid annotation: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Annotation message is empty")]
+    EmptyMessage,
+    #[error("Annotation message exceeds the maximum length of {} bytes", PodAnnotation::MAX_MESSAGE)]
+    MessageTooLong,
+    #[error("Annotation has more than {} fields", PodAnnotation::MAX_FIELDS)]
+    TooManyFields,
+    #[error("Annotation field exceeds the maximum length of {} bytes", PodAnnotation::MAX_FIELD_LEN)]
+    FieldTooLong,
+    #[error("Annotation contains control characters")]
+    ControlCharacter,
+}
@@ -0,0 +1,102 @@
+//! Archival of retired pods, which are hidden from clients by default and cannot be enabled
+//! while their configuration, history, and pod directory are kept
+
+use std::sync::atomic::Ordering;
+
+use super::{Pod, PodManager, PodStateKnown};
+
+impl Pod {
+    /// Check if this pod has been archived
+    pub fn archived(&self) -> bool {
+        self.archived.load(Ordering::Acquire)
+    }
+
+    /// Archive or unarchive this pod, failing if the pod is not disabled. Returns `true` if the
+    /// flag changed, in which case subscribers are notified and the flag must be saved.
+    /// The pod's state is locked while the flag is changed so that it cannot be enabled
+    /// concurrently
+    pub async fn set_archived(&self, archived: bool) -> Result<bool, PodArchiveError> {
+        let lock = self.state().read().await;
+        if archived && !matches!(*lock, PodStateKnown::Disabled) {
+            return Err(PodArchiveError::NotDisabled)
+        }
+
+        match self.archived.swap(archived, Ordering::AcqRel) == archived {
+            true => {
+                tracing::debug!("Pod {} is already {}", self.id(), if archived { "archived" } else { "unarchived" });
+                Ok(false)
+            },
+            false => {
+                tracing::info!("{} pod {}", if archived { "Archived" } else { "Unarchived" }, self.id());
+                self.state().touch();
+                Ok(true)
+            },
+        }
+    }
+}
+
+impl PodManager {
+
+    /// Restore the archived flag of the pods with the given IDs from persistent state
+    pub(super) fn restore_archived(&self, archived: impl IntoIterator<Item = String>) {
+        for id in archived {
+            match self.loaded().get(id.as_str()) {
+                Some(pod) => pod.archived.store(true, Ordering::Release),
+                None => tracing::warn!("Archived pod {} no longer has a pod directory, dropping it", id),
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodArchiveError {
+    #[error("Pod must be disabled before it can be archived")]
+    NotDisabled,
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use futures::StreamExt;
+
+    use crate::{pod::{id::DockerId, state::PodEnable, PodState}, server::upnp::{UpnpLease, UpnpLeaseOwner}};
+
+    use super::*;
+
+    const CONFIG: &str = "id = \"retired\"\nname = \"Retired\"\n[docker]\nimage = \"alpine:3\"\n";
+
+    #[tokio::test]
+    async fn changes_notify_subscribers() {
+        let (_dir, pod) = Pod::test(CONFIG).await;
+        let mut rx = pod.state().subscribe();
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        assert!(pod.set_archived(true).await.unwrap());
+        assert!(pod.archived());
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        assert!(!pod.set_archived(true).await.unwrap());
+        assert!(pod.set_archived(false).await.unwrap());
+        assert!(!pod.archived());
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+    }
+
+    #[tokio::test]
+    async fn enabled_pods_cannot_be_archived() {
+        let (_dir, pod) = Pod::test(CONFIG).await;
+        let mut lock = pod.state().transact().await;
+        lock.set(PodStateKnown::Enabled(PodEnable {
+            docker_id: DockerId::from(String::from("container")),
+            upnp_lease: UpnpLease::test(UpnpLeaseOwner::Pod(pod.id())),
+            annotations: None,
+            started: Utc::now(),
+            restarted: None,
+            degraded: false,
+        }));
+        drop(lock);
+
+        assert!(matches!(pod.set_archived(true).await, Err(PodArchiveError::NotDisabled)));
+        assert!(!pod.archived());
+        assert!(!pod.set_archived(false).await.unwrap());
+    }
+}
@@ -0,0 +1,254 @@
+//! Icon and banner images of pods, read from the pod's directory when a client requests them.
+//! Raster images larger than clients draw them or than the served size limit are downscaled and
+//! re-encoded before they are served. The served image is kept until the file's size or
+//! modification time changes, so that replaced images are picked up without reloading the pod
+//! and large files are not decoded for every request
+
+use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::{Arc, PoisonError}, time::SystemTime};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageError, ImageFormat, ImageReader, Limits};
+use tokio::io::AsyncReadExt;
+
+use super::Pod;
+
+/// Image configured for a pod
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PodArtworkKind {
+    Icon,
+    Banner,
+}
+
+/// Image formats that clients are able to draw
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodArtworkFormat {
+    Png,
+    Jpeg,
+    Svg,
+    Gif,
+}
+
+/// Contents of a pod's image file
+#[derive(Debug, Clone)]
+pub struct PodArtwork {
+    /// Hexadecimal digest of the file contents, used by clients to skip downloading images they
+    /// already hold
+    pub hash: String,
+    pub format: PodArtworkFormat,
+    pub data: Vec<u8>,
+}
+
+/// Images most recently served for a pod, with the files that they were read from
+pub(super) type PodArtworkCache = HashMap<PodArtworkKind, (PodArtworkSource, Arc<PodArtwork>)>;
+
+/// Identity of an image file when it was read, used to detect that it was replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct PodArtworkSource {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Pod {
+    /// Maximum size in bytes of an image served to clients, after raster images are downscaled
+    pub const MAX_ARTWORK_SIZE: u64 = 512 * 1024;
+
+    /// Maximum size in bytes of an image file read from the pod directory
+    pub const MAX_ARTWORK_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+    /// Read the given image of the pod from its directory, downscaling it if needed.
+    /// Returns `None` if the pod does not configure the image
+    pub async fn artwork(&self, kind: PodArtworkKind) -> Result<Option<Arc<PodArtwork>>, PodArtworkError> {
+        let config = self.config();
+        let path = match kind {
+            PodArtworkKind::Icon => config.icon.as_ref(),
+            PodArtworkKind::Banner => config.banner.as_ref(),
+        };
+
+        let Some(path) = path.map(|path| self.dir.join(path)) else {
+            return Ok(None)
+        };
+
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|err| PodArtworkError::Read { path: path.clone(), err })?;
+
+        //The file is identified before it is read, so that a file replaced while it is being read
+        //is read again by the next request
+        let metadata = file
+            .metadata()
+            .await
+            .map_err(|err| PodArtworkError::Read { path: path.clone(), err })?;
+
+        let source = PodArtworkSource {
+            path: path.clone(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        };
+
+        if let Some((_, artwork)) = self.artwork.lock().unwrap_or_else(PoisonError::into_inner).get(&kind).filter(|(cached, _)| *cached == source) {
+            return Ok(Some(artwork.clone()))
+        }
+
+        //Read one byte beyond the limit to detect files that exceed it without reading them whole
+        let mut data = Vec::new();
+        file
+            .take(Self::MAX_ARTWORK_FILE_SIZE + 1)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|err| PodArtworkError::Read { path: path.clone(), err })?;
+
+        if data.len() as u64 > Self::MAX_ARTWORK_FILE_SIZE {
+            return Err(PodArtworkError::TooLarge { path, limit: Self::MAX_ARTWORK_FILE_SIZE })
+        }
+
+        let format = PodArtworkFormat::detect(&data).ok_or_else(|| PodArtworkError::UnsupportedFormat(path.clone()))?;
+        let (format, data) = match format.raster() {
+            Some(raster) => tokio::task::spawn_blocking(move || kind.fit(raster, data))
+                .await
+                .unwrap_or_else(|e| Err(ImageError::IoError(std::io::Error::other(e))))
+                .map_err(|err| PodArtworkError::Decode { path: path.clone(), err })?,
+            None => (format, data),
+        };
+
+        if data.len() as u64 > Self::MAX_ARTWORK_SIZE {
+            return Err(PodArtworkError::TooLarge { path, limit: Self::MAX_ARTWORK_SIZE })
+        }
+
+        let hash = format!("{:x}", Blake2b::<U32>::digest(&data));
+        let artwork = Arc::new(PodArtwork { hash, format, data });
+        self.artwork.lock().unwrap_or_else(PoisonError::into_inner).insert(kind, (source, artwork.clone()));
+
+        Ok(Some(artwork))
+    }
+}
+
+impl PodArtworkKind {
+    /// Quality of JPEG images re-encoded after they are downscaled
+    const JPEG_QUALITY: u8 = 85;
+
+    /// Get the largest width and height that clients draw the image at
+    const fn max_dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::Icon => (256, 256),
+            Self::Banner => (1920, 640),
+        }
+    }
+
+    /// Downscale a raster image to fit within the largest size that clients draw it at,
+    /// returning it unchanged if it already fits and is within the served size limit.
+    /// Downscaled JPEG images are re-encoded as JPEG and all others as PNG, so animated GIFs
+    /// larger than the limits are reduced to their first frame
+    fn fit(self, format: ImageFormat, data: Vec<u8>) -> Result<(PodArtworkFormat, Vec<u8>), ImageError> {
+        let reader = || {
+            let mut reader = ImageReader::with_format(Cursor::new(&data), format);
+            let mut limits = Limits::default();
+            limits.max_alloc = Some(256 * 1024 * 1024);
+            reader.limits(limits);
+            reader
+        };
+
+        let (max_width, max_height) = self.max_dimensions();
+        let (width, height) = reader().into_dimensions()?;
+        let fits = width <= max_width && height <= max_height;
+        if fits && data.len() as u64 <= Pod::MAX_ARTWORK_SIZE {
+            return Ok((PodArtworkFormat::from_raster(format), data))
+        }
+
+        let mut image = reader().decode()?;
+        if !fits {
+            image = image.resize(max_width, max_height, FilterType::Lanczos3);
+        }
+
+        let mut out = Cursor::new(Vec::new());
+        let format = match format {
+            ImageFormat::Jpeg => {
+                DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(JpegEncoder::new_with_quality(&mut out, Self::JPEG_QUALITY))?;
+                PodArtworkFormat::Jpeg
+            },
+            _ => {
+                image.write_to(&mut out, ImageFormat::Png)?;
+                PodArtworkFormat::Png
+            },
+        };
+
+        Ok((format, out.into_inner()))
+    }
+}
+
+impl PodArtworkFormat {
+    /// Detect the format of an image from the start of its contents
+    fn detect(data: &[u8]) -> Option<Self> {
+        const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+        const JPEG_SIGNATURE: &[u8] = b"\xFF\xD8\xFF";
+        const GIF_SIGNATURE: &[u8] = b"GIF8";
+
+        if data.starts_with(PNG_SIGNATURE) {
+            return Some(Self::Png)
+        }
+
+        if data.starts_with(JPEG_SIGNATURE) {
+            return Some(Self::Jpeg)
+        }
+
+        if data.starts_with(GIF_SIGNATURE) {
+            return Some(Self::Gif)
+        }
+
+        let text = std::str::from_utf8(data).ok()?;
+        let start = text.trim_start();
+        ((start.starts_with("<?xml") || start.starts_with("<svg")) && text.contains("<svg")).then_some(Self::Svg)
+    }
+
+    /// Get the format to decode the image with, or `None` if it is not a raster image
+    const fn raster(&self) -> Option<ImageFormat> {
+        match self {
+            Self::Png => Some(ImageFormat::Png),
+            Self::Jpeg => Some(ImageFormat::Jpeg),
+            Self::Gif => Some(ImageFormat::Gif),
+            Self::Svg => None,
+        }
+    }
+
+    /// Get the format of a raster image decoded with the given format
+    fn from_raster(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Jpeg => Self::Jpeg,
+            ImageFormat::Gif => Self::Gif,
+            _ => Self::Png,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodArtworkError {
+    #[error("Failed to read image {}: {}", path.display(), err)]
+    Read { path: PathBuf, err: std::io::Error },
+    #[error("Image {} exceeds the limit of {} bytes", path.display(), limit)]
+    TooLarge { path: PathBuf, limit: u64 },
+    #[error("Image {} is not a PNG, JPEG, GIF, or SVG image", .0.display())]
+    UnsupportedFormat(PathBuf),
+    #[error("Failed to downscale image {}: {}", path.display(), err)]
+    Decode { path: PathBuf, err: ImageError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn changed_files_are_read_again() {
+        let (dir, pod) = Pod::test("id = \"x\"\nname = \"X\"\nicon = \"icon.svg\"\n[docker]\nimage = \"alpine:3\"\n").await;
+        let path = dir.path().join("icon.svg");
+        tokio::fs::write(&path, "<svg></svg>").await.unwrap();
+
+        let first = pod.artwork(PodArtworkKind::Icon).await.unwrap().unwrap();
+        let cached = pod.artwork(PodArtworkKind::Icon).await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &cached));
+
+        tokio::fs::write(&path, "<svg><rect/></svg>").await.unwrap();
+        let changed = pod.artwork(PodArtworkKind::Icon).await.unwrap().unwrap();
+        assert_ne!(changed.hash, first.hash);
+        assert_eq!(changed.data, b"<svg><rect/></svg>");
+    }
+}
@@ -0,0 +1,213 @@
+//! Collections kept for each pod that grow while the daemon runs, capped at a fixed number of
+//! entries by discarding the oldest entries. The caps of all such collections are defined here
+//! so that the memory held by each pod stays bounded, and the approximate memory held by each
+//! pod can be reported through the internal API
+
+use std::{collections::VecDeque, mem::size_of, path::PathBuf, sync::{Arc, PoisonError}};
+
+use super::{annotate::PodAnnotation, env::{PodEnvOverride, PodEnvOverrides}, Pod, PodManager};
+
+/// Maximum number of entries kept in each growable per-pod collection
+pub struct PodCollectionCaps;
+
+impl PodCollectionCaps {
+    /// Most recent annotations reported from inside the pod's container
+    pub const ANNOTATIONS: usize = 100;
+    /// Volumes declared by the pod's image
+    pub const IMAGE_VOLUMES: usize = 64;
+}
+
+/// A queue holding at most a fixed number of entries, discarding the oldest entry when a new
+/// entry is pushed while full
+#[derive(Debug, Clone)]
+pub struct BoundedDeque<T> {
+    entries: VecDeque<T>,
+    capacity: usize,
+    /// Number of entries discarded to make room for newer entries
+    evicted: u64,
+}
+
+/// Approximate amount of heap memory owned by a value, excluding the size of the value itself
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+/// Approximate memory held by the growable collections of a single pod
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PodMemoryUsage {
+    pub annotations: usize,
+    /// Maximum number of annotations retained
+    pub annotation_capacity: usize,
+    /// Size in bytes of the retained annotations
+    pub annotation_bytes: usize,
+    /// Number of annotations discarded since the daemon started
+    pub annotations_evicted: u64,
+    /// Size in bytes of the current and applied environment variable overrides
+    pub env_bytes: usize,
+    /// Size in bytes of the volumes recorded from the pod's image
+    pub image_volume_bytes: usize,
+}
+
+impl<T> BoundedDeque<T> {
+    /// Create an empty queue holding at most `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+            evicted: 0,
+        }
+    }
+
+    /// Create a queue from the last `capacity` entries of the given iterator
+    pub fn from_iter_capped(capacity: usize, iter: impl IntoIterator<Item = T>) -> Self {
+        let mut this = Self::new(capacity);
+        for entry in iter {
+            this.push(entry);
+        }
+
+        this
+    }
+
+    /// Add an entry to the back of the queue, returning the oldest entry if it was discarded to
+    /// make room
+    pub fn push(&mut self, entry: T) -> Option<T> {
+        if self.capacity == 0 {
+            self.evicted += 1;
+            return Some(entry)
+        }
+
+        let evicted = match self.entries.len() >= self.capacity {
+            true => {
+                self.evicted += 1;
+                self.entries.pop_front()
+            },
+            false => None,
+        };
+
+        self.entries.push_back(entry);
+        evicted
+    }
+
+    /// Iterate over the entries of the queue, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the number of entries that have been discarded to make room for newer entries
+    pub const fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+impl<T: HeapSize> BoundedDeque<T> {
+    /// Get the approximate memory used by the entries of the queue in bytes
+    pub fn size(&self) -> usize {
+        self.entries.capacity() * size_of::<T>() + self.entries.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl Pod {
+    /// Get the approximate memory held by this pod's growable collections
+    pub fn memory_usage(&self) -> PodMemoryUsage {
+        let (annotations, annotation_capacity, annotation_bytes, annotations_evicted) = {
+            let annotations = self.annotations.lock().unwrap_or_else(PoisonError::into_inner);
+            (annotations.len(), annotations.capacity(), annotations.size(), annotations.evicted())
+        };
+
+        let env_bytes = self.env.lock().unwrap_or_else(PoisonError::into_inner).heap_size()
+            + self
+                .env_applied
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_ref()
+                .map(HeapSize::heap_size)
+                .unwrap_or_default();
+
+        let image_volume_bytes = self
+            .image_volumes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(BoundedDeque::size)
+            .unwrap_or_default();
+
+        PodMemoryUsage {
+            annotations,
+            annotation_capacity,
+            annotation_bytes,
+            annotations_evicted,
+            env_bytes,
+            image_volume_bytes,
+        }
+    }
+}
+
+impl PodManager {
+    /// Get the approximate memory held by the growable collections of every loaded pod
+    pub fn memory_usage(&self) -> Vec<(Arc<Pod>, PodMemoryUsage)> {
+        let mut pods = self
+            .pods()
+            .into_iter()
+            .map(|pod| {
+                let usage = pod.memory_usage();
+                (pod, usage)
+            })
+            .collect::<Vec<_>>();
+
+        pods.sort_unstable_by(|(a, _), (b, _)| str::cmp(&a.id(), &b.id()));
+        pods
+    }
+}
+
+impl PodMemoryUsage {
+    /// Get the combined size in bytes of all collections
+    pub const fn total_bytes(&self) -> usize {
+        self.annotation_bytes + self.env_bytes + self.image_volume_bytes
+    }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl HeapSize for PathBuf {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + T::heap_size(self)
+    }
+}
+
+impl HeapSize for PodAnnotation {
+    fn heap_size(&self) -> usize {
+        self.message.heap_size()
+            + self
+                .fields
+                .iter()
+                .map(|(key, value)| size_of::<(String, String)>() + key.heap_size() + value.heap_size())
+                .sum::<usize>()
+    }
+}
+
+impl HeapSize for PodEnvOverrides {
+    fn heap_size(&self) -> usize {
+        self
+            .iter()
+            .map(|(key, value)| size_of::<(String, PodEnvOverride)>() + key.heap_size() + value.value.heap_size())
+            .sum()
+    }
+}
@@ -1,6 +1,8 @@
 use std::{path::PathBuf, sync::Arc};
 
-use super::id::DeimosId;
+use chrono::NaiveTime;
+
+use super::{id::DeimosId, quota::PodDiskLimit, resources::{PodMemorySize, PodMemorySwap}};
 
 /// Top-level configuration for a Pod, parsed from TOML files
 #[derive(Debug, serde::Deserialize)]
@@ -12,6 +14,137 @@ pub struct PodConfig {
     pub name: Arc<str>,
     /// Configuration for the Docker container
     pub docker: PodDockerConfig,
+    /// Group that this pod belongs to, allowing API tokens scoped to the group to access it
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Daily schedule of automatic transitions
+    #[serde(default)]
+    pub schedule: PodScheduleConfig,
+    /// Rules raising alerts when resource usage stays high while the pod is enabled
+    #[serde(default)]
+    pub alerts: Vec<PodAlertConfig>,
+    /// Mount a socket into the container that the containerized application can write
+    /// annotations to, recorded with the pod's history and forwarded to clients
+    #[serde(default)]
+    pub annotations: bool,
+    /// Allow clients to open interactive terminal sessions in the pod's container while it is
+    /// enabled, disallowed if not given
+    #[serde(default)]
+    pub attach: Option<PodAttachConfig>,
+    /// IDs of pods that this pod depends on, which must be loaded and may not depend on this pod
+    #[serde(default)]
+    pub depends_on: Vec<DeimosId>,
+    /// Restart this pod while it is enabled when one of the pods it depends on is restarted
+    #[serde(default)]
+    pub restart_with_dependencies: bool,
+    /// Probe run periodically while the pod is enabled, marking the pod as degraded when it fails
+    #[serde(default)]
+    pub healthcheck: Option<PodHealthcheckConfig>,
+    /// Path of an image shown next to the pod in clients, relative to the pod's directory
+    #[serde(default)]
+    pub icon: Option<PathBuf>,
+    /// Path of a wide image shown with the pod's details in clients, relative to the pod's
+    /// directory
+    #[serde(default)]
+    pub banner: Option<PathBuf>,
+    /// Least confirmation that clients ask for before stopping or restarting the pod
+    #[serde(default)]
+    pub confirm: PodConfirmLevel,
+}
+
+/// Confirmation asked for by clients before a pod is stopped or restarted. Clients may ask for
+/// more confirmation than configured, but never less
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum PodConfirmLevel {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Ask the user to confirm the action
+    #[serde(rename = "confirm")]
+    Confirm,
+    /// Ask the user to type the pod's ID to confirm the action
+    #[serde(rename = "type-to-confirm")]
+    TypeToConfirm,
+}
+
+/// Settings for interactive terminal sessions opened in a pod's container
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodAttachConfig {
+    /// Command run for each session, defaulting to `/bin/sh`
+    #[serde(default, deserialize_with = "deserialize_args")]
+    pub command: Option<Vec<String>>,
+    /// Number of seconds without input from the client after which a session is closed
+    #[serde(default = "PodAttachConfig::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Maximum number of seconds that a session may remain open
+    #[serde(default = "PodAttachConfig::default_max_duration")]
+    pub max_duration: u64,
+}
+
+/// Settings for the health check of a pod, which must give exactly one of a command or a port
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodHealthcheckConfig {
+    /// Command run in the pod's container, passing if it exits with a code of 0
+    #[serde(default, deserialize_with = "deserialize_args")]
+    pub command: Option<Vec<String>>,
+    /// Port of the pod's container that must accept TCP connections
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Number of seconds between each probe
+    #[serde(default = "PodHealthcheckConfig::default_interval")]
+    pub interval: u64,
+    /// Number of seconds after which a probe that has not finished fails
+    #[serde(default = "PodHealthcheckConfig::default_timeout")]
+    pub timeout: u64,
+    /// Number of consecutive failed probes after which the pod is degraded
+    #[serde(default = "PodHealthcheckConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+/// A rule that raises an alert when a pod's resource usage stays at or above a threshold
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodAlertConfig {
+    /// Resource usage measurement that the rule applies to
+    pub metric: PodAlertMetric,
+    /// Usage in percent at or above which the alert fires
+    pub threshold: f64,
+    /// Usage in percent below which a firing alert is cleared, defaulting to 10 percentage points
+    /// below the threshold. Clamped to the threshold if it is higher
+    #[serde(default)]
+    pub clear: Option<f64>,
+    /// Number of seconds that usage must remain at or above the threshold before the alert fires
+    #[serde(default)]
+    pub sustained: u64,
+}
+
+/// Resource usage measurements that alerts can be raised for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum PodAlertMetric {
+    /// CPU usage in percent of a single core, as reported by `docker stats`
+    #[serde(rename = "cpu")]
+    Cpu,
+    /// Memory usage excluding the page cache, in percent of the container's memory limit
+    #[serde(rename = "memory")]
+    Memory,
+    /// Size of the container's writable layer, in percent of the pod's disk limit
+    #[serde(rename = "disk")]
+    Disk,
+}
+
+/// Daily schedule used to automatically enable and disable a pod, with times given in the
+/// server's local time zone
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodScheduleConfig {
+    /// Time of day to enable the pod at
+    #[serde(default)]
+    pub start: Option<NaiveTime>,
+    /// Time of day to disable the pod at
+    #[serde(default)]
+    pub stop: Option<NaiveTime>,
 }
 
 /// Configuration to be passed to Docker when  starting this container
@@ -20,9 +153,10 @@ pub struct PodConfig {
 pub struct PodDockerConfig {
     /// Docker image used to create the Docker container
     pub image: String,
-    /// Time to wait in seconds before forcefully killing the container
-    #[serde(default = "PodDockerConfig::default_stop_timeout")]
-    pub stop_timeout: u32,
+    /// Time to wait in seconds for the container to exit when stopped before forcefully killing
+    /// it, also accepted as `stop_timeout` for configs written before it was renamed
+    #[serde(default = "PodDockerConfig::default_stop_timeout", alias = "stop_timeout")]
+    pub stop_timeout_seconds: u32,
     /// List of volumes to mount inside the container
     #[serde(default)]
     pub volume: Vec<PodDockerMountConfig>,
@@ -35,8 +169,126 @@ pub struct PodDockerConfig {
     /// List of capabilities to add to the process
     #[serde(default)]
     pub cap_add: Vec<String>,
+    /// Entrypoint overriding the image's default, an empty array clears the image's entrypoint
+    #[serde(default, deserialize_with = "deserialize_args")]
+    pub entrypoint: Option<Vec<String>>,
+    /// Arguments overriding the image's default command
+    #[serde(default, deserialize_with = "deserialize_args")]
+    pub command: Option<Vec<String>>,
+    /// Working directory of the container's process, overriding the image's default
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// Maximum size of the container's writable layer, only applied if the Docker storage driver
+    /// can enforce it
+    #[serde(default)]
+    pub disk_limit: Option<PodDiskLimit>,
+    /// Maximum amount of memory the container may use, such as `"2g"`
+    #[serde(default)]
+    pub memory_limit: Option<PodMemorySize>,
+    /// Maximum amount of memory and swap combined that the container may use, or `"unlimited"`.
+    /// Requires `memory_limit`
+    #[serde(default)]
+    pub memory_swap: Option<PodMemorySwap>,
+    /// Weight of the container's share of CPU time relative to other containers when the CPU is
+    /// contended, Docker's default is 1024
+    #[serde(default)]
+    pub cpu_shares: Option<u32>,
+    /// Maximum CPU time the container may use in billionths of a CPU, e.g. `1500000000` for one
+    /// and a half CPUs
+    #[serde(default)]
+    pub nano_cpus: Option<u64>,
+    /// Maximum number of processes and threads that may run in the container
+    #[serde(default)]
+    pub pids_limit: Option<u32>,
+    /// Policy for re-enabling the pod when its container dies unexpectedly, given as a policy
+    /// name or a table of settings. The pod is left disabled if not given
+    #[serde(default)]
+    pub restart: PodRestartConfig,
+    /// Pull the image from its registry if it is not present locally when the pod is loaded or
+    /// enabled
+    #[serde(default)]
+    pub pull: bool,
+    /// When the image is pulled, `"always"` pulls it before every container is created so that a
+    /// newer image for the tag is used, and implies `pull`
+    #[serde(default)]
+    pub pull_policy: PodPullPolicy,
+    /// HTTP service of the container, listed in the service registry file while the pod is
+    /// enabled so that a reverse proxy can route requests to it
+    #[serde(default)]
+    pub http: Option<PodDockerHttpConfig>,
+}
+
+/// HTTP service served by a pod's container
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodDockerHttpConfig {
+    /// Host name that a reverse proxy should route to the pod
+    pub host: String,
+    /// Port of the container that the service listens on
+    pub port: u16,
+}
+
+/// When the image of a pod is pulled from its registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum PodPullPolicy {
+    /// Pull the image only if it is not present locally
+    #[default]
+    #[serde(rename = "missing")]
+    Missing,
+    /// Pull the image before every container is created, falling back to the local image if the
+    /// pull fails
+    #[serde(rename = "always")]
+    Always,
+}
+
+/// Restart policy of a pod, with the backoff and limit applied to consecutive restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PodRestartConfig {
+    pub policy: PodRestartPolicy,
+    /// Number of restarts attempted within the window before the pod is left disabled
+    pub max_attempts: u32,
+    /// Number of seconds to wait before the first restart, doubled for each consecutive restart
+    pub backoff_seconds: u64,
+    /// Number of seconds that the container must stay up for before consecutive restarts are no
+    /// longer counted
+    pub stable_seconds: u64,
+    /// Number of seconds that failures are counted towards the maximum number of attempts for
+    pub window_seconds: u64,
+    /// Number of seconds after restarts stop that a single probe restart is made, or `None` to
+    /// leave the pod disabled until it is enabled again
+    pub cooloff_seconds: Option<u64>,
 }
 
+/// Table form of [PodRestartConfig]
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PodRestartTable {
+    policy: PodRestartPolicy,
+    #[serde(default = "PodRestartConfig::default_max_attempts")]
+    max_attempts: u32,
+    #[serde(default = "PodRestartConfig::default_backoff_seconds")]
+    backoff_seconds: u64,
+    #[serde(default = "PodRestartConfig::default_stable_seconds")]
+    stable_seconds: u64,
+    #[serde(default = "PodRestartConfig::default_window_seconds")]
+    window_seconds: u64,
+    #[serde(default)]
+    cooloff_seconds: Option<u64>,
+}
+
+/// Exits of a pod's container that cause the pod to be restarted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum PodRestartPolicy {
+    #[default]
+    #[serde(rename = "no")]
+    Never,
+    /// Restart whenever the container dies while the pod is enabled
+    #[serde(rename = "always")]
+    Always,
+    /// Restart if the container exits with a nonzero code or is killed by Docker
+    #[serde(rename = "on-failure")]
+    OnFailure,
+}
 
 /// Configuration for a local volume mounted to a Docker container
 #[derive(Debug, serde::Deserialize)]
@@ -50,14 +302,22 @@ pub struct PodDockerMountConfig {
 #[derive(Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PodDockerPortConfig {
+    /// Port exposed by the container
     pub expose: u16,
+    /// Port on the host that the container port is published to, the same as the container port
+    /// if not given
+    #[serde(default)]
+    pub host: Option<u16>,
+    /// Port opened on the gateway when forwarded with UPnP, the same as the host port if not given
+    #[serde(default)]
+    pub external: Option<u16>,
     pub protocol: PodDockerPortProtocol,
     #[serde(default)]
     pub upnp: bool,
 }
 
 /// Selectable protocol for forwarded port
-#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
 pub enum PodDockerPortProtocol {
     #[serde(rename = "udp")]
     Udp,
@@ -65,11 +325,33 @@ pub enum PodDockerPortProtocol {
     Tcp,
 }
 
-/// Configuration for an environment variable to be set in the container
-#[derive(Debug, serde::Deserialize)]
+/// Configuration for an environment variable to be set in the container, given as a table with
+/// exactly one of `value`, `value_from_file`, or `from_host`
+#[derive(Debug)]
 pub struct PodDockerEnvConfig {
     pub key: String,
-    pub value: String,
+    pub value: PodDockerEnvValue,
+}
+
+/// Source of an environment variable's value. Values not given in the config are read each time
+/// a container is created for the pod, so that changing them only requires a restart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PodDockerEnvValue {
+    Literal(String),
+    /// Path of a file containing the value, relative to the pod directory and confined to it
+    File(PathBuf),
+    /// Name of a variable in the daemon's environment whose value is copied
+    Host(String),
+}
+
+/// Table form of [PodDockerEnvConfig]
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PodDockerEnvTable {
+    key: String,
+    value: Option<String>,
+    value_from_file: Option<PathBuf>,
+    from_host: Option<String>,
 }
 
 /// Configuration for the pod manager including state to connect to the local Docker server and
@@ -79,6 +361,65 @@ pub struct PodDockerEnvConfig {
 pub struct PodManagerConfig {
     pub containerdir: PathBuf,
     pub docker: Option<DockerConnectionConfig>,
+    /// Maximum number of previous config file versions kept for each pod, or 0 to disable
+    /// config history
+    #[serde(default = "PodManagerConfig::default_config_history")]
+    pub config_history: usize,
+    /// Removal of images that pods used before their image was updated
+    #[serde(default)]
+    pub image_prune: PodImagePruneConfig,
+    /// Minimum number of seconds between restarts of the dependents of a single pod, so that a
+    /// pod that repeatedly fails does not repeatedly restart the pods depending on it
+    #[serde(default = "PodManagerConfig::default_dependency_restart_cooldown")]
+    pub dependency_restart_cooldown: u64,
+    /// Maximum number of pods that will be loaded, pods beyond the limit are rejected in order of
+    /// their IDs
+    #[serde(default = "PodManagerConfig::default_max_pods")]
+    pub max_pods: usize,
+    /// Maximum number of ports a single pod may publish, pods publishing more are rejected
+    #[serde(default = "PodManagerConfig::default_max_ports_per_pod")]
+    pub max_ports_per_pod: usize,
+    /// Maximum number of distinct ports forwarded with UPnP for all enabled pods, enabling a pod
+    /// that would exceed it fails
+    #[serde(default = "PodManagerConfig::default_max_total_forwarded_ports")]
+    pub max_total_forwarded_ports: usize,
+    /// Number of pod annotations and container starts buffered for slow subscribers before the
+    /// oldest are dropped
+    #[serde(default = "PodManagerConfig::default_event_capacity")]
+    pub event_capacity: usize,
+    /// Periodically sample the resource usage of enabled pods to evaluate their alert rules,
+    /// alert rules are never evaluated if disabled
+    #[serde(default = "PodManagerConfig::default_usage_sampling")]
+    pub usage_sampling: bool,
+    /// Enable the pods that were enabled when the daemon last stopped once the daemon starts
+    #[serde(default)]
+    pub restore_on_start: bool,
+    /// Take over containers left running by a previous run of the daemon when it starts,
+    /// removing stopped containers and containers of pods that are no longer loaded
+    #[serde(default = "PodManagerConfig::default_adopt_orphans")]
+    pub adopt_orphans: bool,
+    /// Time in milliseconds that Docker events for a container are collected after the last
+    /// event before they are handled together as a single event
+    #[serde(default = "PodManagerConfig::default_event_debounce_ms")]
+    pub event_debounce_ms: u64,
+    /// Minimum time in seconds between corrective actions taken for a single pod in response to
+    /// Docker events, events received during the cooldown are handled together once it ends
+    #[serde(default = "PodManagerConfig::default_event_cooldown_seconds")]
+    pub event_cooldown_seconds: u64,
+}
+
+/// Configuration for removing old images of pods that are no longer referenced
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodImagePruneConfig {
+    /// Number of previous images of each pod that are kept so that an image update can be
+    /// reverted
+    #[serde(default = "PodImagePruneConfig::default_retain")]
+    pub retain: usize,
+    /// Interval in seconds between automatic prunes, images are only pruned on request if not
+    /// given
+    #[serde(default)]
+    pub interval: Option<u64>,
 }
 
 /// Configuration governing how the server will connect to the Docker API
@@ -99,6 +440,74 @@ pub enum DockerConnectionType {
     Local,
 }
 
+impl PodManagerConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_config_history() -> usize {
+        10
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_dependency_restart_cooldown() -> u64 {
+        60
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_pods() -> usize {
+        256
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_ports_per_pod() -> usize {
+        32
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_total_forwarded_ports() -> usize {
+        64
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_event_capacity() -> usize {
+        64
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_usage_sampling() -> bool {
+        true
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_adopt_orphans() -> bool {
+        true
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_event_debounce_ms() -> u64 {
+        250
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_event_cooldown_seconds() -> u64 {
+        5
+    }
+}
+
+impl PodImagePruneConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_retain() -> usize {
+        1
+    }
+}
+
+impl Default for PodImagePruneConfig {
+    fn default() -> Self {
+        Self {
+            retain: Self::default_retain(),
+            interval: None,
+        }
+    }
+}
+
 impl DockerConnectionConfig {
     /// Helper function for serde deserializer defaults
     pub const fn default_timeout() -> u64 {
@@ -106,6 +515,18 @@ impl DockerConnectionConfig {
     }
 }
 
+impl PodDockerPortConfig {
+    /// Get the port on the host that the container port is published to
+    pub fn host_port(&self) -> u16 {
+        self.host.unwrap_or(self.expose)
+    }
+
+    /// Get the port opened on the gateway if the port is forwarded with UPnP
+    pub fn external_port(&self) -> u16 {
+        self.external.unwrap_or_else(|| self.host_port())
+    }
+}
+
 impl PodDockerPortProtocol {
     /// Get the string to use when specifying the protocol to the Docker API
     pub const fn docker_name(&self) -> &'static str {
@@ -125,9 +546,260 @@ impl From<PodDockerPortProtocol> for igd_next::PortMappingProtocol {
     }
 }
 
+impl PodAttachConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_idle_timeout() -> u64 {
+        60 * 10
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_duration() -> u64 {
+        60 * 60
+    }
+}
+
+impl PodHealthcheckConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_interval() -> u64 {
+        30
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_timeout() -> u64 {
+        10
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_failure_threshold() -> u32 {
+        3
+    }
+}
+
 impl PodDockerConfig {
     /// Helper function for providing a default timeout when serde does not find one specified
     pub const fn default_stop_timeout() -> u32 {
-        60
+        30
+    }
+
+    /// Check if the image should be pulled when it is not present locally
+    pub fn pulls_missing(&self) -> bool {
+        self.pull || self.pull_policy == PodPullPolicy::Always
+    }
+}
+
+impl PodRestartConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_max_attempts() -> u32 {
+        5
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_backoff_seconds() -> u64 {
+        10
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_stable_seconds() -> u64 {
+        60 * 5
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_window_seconds() -> u64 {
+        60 * 60
+    }
+
+    /// Create a configuration with the given policy and default settings
+    pub const fn with_policy(policy: PodRestartPolicy) -> Self {
+        Self {
+            policy,
+            max_attempts: Self::default_max_attempts(),
+            backoff_seconds: Self::default_backoff_seconds(),
+            stable_seconds: Self::default_stable_seconds(),
+            window_seconds: Self::default_window_seconds(),
+            cooloff_seconds: None,
+        }
+    }
+}
+
+impl Default for PodRestartConfig {
+    fn default() -> Self {
+        Self::with_policy(PodRestartPolicy::Never)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PodRestartConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RestartVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RestartVisitor {
+            type Value = PodRestartConfig;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a restart policy of \"no\", \"always\", or \"on-failure\", or a table with a policy")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                let policy = <PodRestartPolicy as serde::Deserialize>::deserialize(serde::de::value::StrDeserializer::<E>::new(v))?;
+                Ok(PodRestartConfig::with_policy(policy))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let table = <PodRestartTable as serde::Deserialize>::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(PodRestartConfig {
+                    policy: table.policy,
+                    max_attempts: table.max_attempts,
+                    backoff_seconds: table.backoff_seconds,
+                    stable_seconds: table.stable_seconds,
+                    window_seconds: table.window_seconds,
+                    cooloff_seconds: table.cooloff_seconds,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(RestartVisitor)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PodDockerEnvConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = <PodDockerEnvTable as serde::Deserialize>::deserialize(deserializer)?;
+        let value = match (table.value, table.value_from_file, table.from_host) {
+            (Some(value), None, None) => PodDockerEnvValue::Literal(value),
+            (None, Some(path), None) => PodDockerEnvValue::File(path),
+            (None, None, Some(name)) => PodDockerEnvValue::Host(name),
+            (None, None, None) => return Err(serde::de::Error::custom(format_args!(
+                "environment variable '{}' has no value - set one of 'value', 'value_from_file', or 'from_host'",
+                table.key,
+            ))),
+            _ => return Err(serde::de::Error::custom(format_args!(
+                "environment variable '{}' sets more than one of 'value', 'value_from_file', and 'from_host' - set exactly one",
+                table.key,
+            ))),
+        };
+
+        Ok(Self { key: table.key, value })
+    }
+}
+
+/// Deserialize an entrypoint or command given as an array of arguments, rejecting a single string
+/// with a message suggesting how to split it instead of serde's generic type error
+fn deserialize_args<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    struct ArgsVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for ArgsVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("an array of arguments")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            let split = v
+                .split_whitespace()
+                .map(|arg| format!("\"{}\"", arg.escape_default()))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            Err(E::custom(format_args!(
+                "expected an array of arguments but found the string \"{}\" - arguments are not split by a shell, write [{}] instead",
+                v.escape_default(),
+                split,
+            )))
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut args = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(arg) = seq.next_element()? {
+                args.push(arg);
+            }
+
+            Ok(args)
+        }
+    }
+
+    deserializer.deserialize_any(ArgsVisitor).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: &str = "id = \"x\"\nname = \"X\"\n[docker]\nimage = \"alpine:3\"\n";
+
+    fn parse(extra: &str) -> Result<PodConfig, toml::de::Error> {
+        toml::from_str(&format!("{}{}", BASE, extra))
+    }
+
+    #[test]
+    fn args_given_as_string() {
+        let config = parse("command = [\"sh\", \"-c\", \"echo hi\"]\n").unwrap();
+        assert_eq!(config.docker.command.unwrap(), ["sh", "-c", "echo hi"]);
+
+        let err = parse("command = \"sh -c run\"\n").unwrap_err();
+        assert!(err.message().contains("[\"sh\", \"-c\", \"run\"]"), "{}", err);
+    }
+
+    #[test]
+    fn restart_policy_forms() {
+        let config = parse("").unwrap();
+        assert_eq!(config.docker.restart, PodRestartConfig::default());
+
+        let config = parse("restart = \"on-failure\"\n").unwrap();
+        assert_eq!(config.docker.restart, PodRestartConfig::with_policy(PodRestartPolicy::OnFailure));
+
+        let config = parse("[docker.restart]\npolicy = \"always\"\nmax_attempts = 2\n").unwrap();
+        assert_eq!(config.docker.restart.policy, PodRestartPolicy::Always);
+        assert_eq!(config.docker.restart.max_attempts, 2);
+        assert_eq!(config.docker.restart.backoff_seconds, PodRestartConfig::default_backoff_seconds());
+
+        assert!(parse("restart = \"sometimes\"\n").is_err());
+    }
+
+    #[test]
+    fn stop_timeout_alias() {
+        let config = parse("stop_timeout = 5\n").unwrap();
+        assert_eq!(config.docker.stop_timeout_seconds, 5);
+
+        let config = parse("").unwrap();
+        assert_eq!(config.docker.stop_timeout_seconds, PodDockerConfig::default_stop_timeout());
+    }
+
+    #[test]
+    fn port_defaults() {
+        let config = parse(
+            "[[docker.port]]\nexpose = 80\nprotocol = \"tcp\"\n\
+            [[docker.port]]\nexpose = 80\nhost = 8080\nprotocol = \"tcp\"\n\
+            [[docker.port]]\nexpose = 80\nhost = 8080\nexternal = 443\nprotocol = \"tcp\"\nupnp = true\n",
+        ).unwrap();
+
+        let ports = config
+            .docker
+            .port
+            .iter()
+            .map(|port| (port.host_port(), port.external_port()))
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [(80, 80), (8080, 8080), (8080, 443)]);
+    }
+
+    #[test]
+    fn env_value_sources() {
+        let config = parse(
+            "[[docker.env]]\nkey = \"A\"\nvalue = \"1\"\n\
+            [[docker.env]]\nkey = \"B\"\nvalue_from_file = \"secret\"\n\
+            [[docker.env]]\nkey = \"C\"\nfrom_host = \"HOME\"\n",
+        ).unwrap();
+
+        let values = config.docker.env.into_iter().map(|env| env.value).collect::<Vec<_>>();
+        assert_eq!(values, [
+            PodDockerEnvValue::Literal("1".to_owned()),
+            PodDockerEnvValue::File(PathBuf::from("secret")),
+            PodDockerEnvValue::Host("HOME".to_owned()),
+        ]);
+
+        let err = parse("[[docker.env]]\nkey = \"A\"\n").unwrap_err();
+        assert!(err.message().contains("has no value"), "{}", err);
+
+        let err = parse("[[docker.env]]\nkey = \"A\"\nvalue = \"1\"\nfrom_host = \"HOME\"\n").unwrap_err();
+        assert!(err.message().contains("more than one"), "{}", err);
     }
 }
@@ -0,0 +1,196 @@
+//! Dependencies between pods, used to restart pods that opt in with `restart_with_dependencies`
+//! when a pod they depend on is restarted. A dependency counts as restarted once its container
+//! has been created and started again, and its dependents are only restarted if their container
+//! was started before it
+
+use std::{collections::{HashMap, HashSet}, sync::{Arc, PoisonError}, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+
+use super::{config::PodConfig, id::DeimosId, Pod, PodManager, PodState, PodStateKnown};
+
+/// Broadcasts the IDs of pods when their container is created and started, with the start time
+pub type PodStartSender = tokio::sync::broadcast::Sender<(DeimosId, DateTime<Utc>)>;
+
+/// Recent dependent restarts, used to limit how often and how deep restarts cascade
+#[derive(Debug, Default)]
+pub struct PodDependencyRestarts {
+    /// Time that the dependents of each pod were last restarted
+    last: HashMap<DeimosId, Instant>,
+    /// Number of dependency restarts that led to the restart of each pod in progress
+    depth: HashMap<DeimosId, usize>,
+}
+
+impl PodManager {
+    /// Maximum number of restarts that may cascade from a single restarted pod. Dependency
+    /// cycles are rejected when pods are loaded, this bounds a cascade if one is missed
+    const MAX_RESTART_DEPTH: usize = 8;
+
+    /// Check that every pod's dependencies are in the given set of pod configurations and that no
+    /// pods depend on each other in a cycle
+    pub(super) fn check_dependencies(pods: &HashMap<DeimosId, Arc<PodConfig>>) -> Result<(), PodDependencyError> {
+        for (id, config) in pods {
+            if let Some(dependency) = config.depends_on.iter().find(|id| !pods.contains_key(*id)) {
+                return Err(PodDependencyError::Unknown { pod: id.clone(), dependency: dependency.clone() })
+            }
+        }
+
+        let mut checked = HashSet::new();
+        for id in pods.keys() {
+            let mut path = Vec::new();
+            Self::check_dependency_cycle(pods, id.clone(), &mut path, &mut checked)?;
+        }
+
+        Ok(())
+    }
+
+    /// Follow the dependencies of the given pod depth-first, failing if a pod already on the
+    /// path is reached again
+    fn check_dependency_cycle(
+        pods: &HashMap<DeimosId, Arc<PodConfig>>,
+        id: DeimosId,
+        path: &mut Vec<DeimosId>,
+        checked: &mut HashSet<DeimosId>,
+    ) -> Result<(), PodDependencyError> {
+        if let Some(start) = path.iter().position(|exist| *exist == id) {
+            return Err(PodDependencyError::Cycle(path.split_off(start)))
+        }
+
+        if checked.contains(&id) {
+            return Ok(())
+        }
+
+        path.push(id.clone());
+        if let Some(config) = pods.get(&id) {
+            for dependency in config.depends_on.iter() {
+                Self::check_dependency_cycle(pods, dependency.clone(), path, checked)?;
+            }
+        }
+
+        path.pop();
+        checked.insert(id);
+        Ok(())
+    }
+
+    /// Subscribe to the creation and start of pod containers
+    pub fn subscribe_starts(&self) -> tokio::sync::broadcast::Receiver<(DeimosId, DateTime<Utc>)> {
+        self.starts.subscribe()
+    }
+
+    /// Get the pods that should be restarted after the given pod's container was started: enabled
+    /// pods that depend on it with `restart_with_dependencies` set. Returns no pods if the
+    /// dependents of the pod were restarted within the cooldown or the restart has cascaded too
+    /// deep. Each returned pod must be checked with [dependent_outdated](Self::dependent_outdated)
+    /// before it is restarted
+    pub fn take_dependent_restarts(&self, dependency: &DeimosId) -> Vec<Arc<Pod>> {
+        let mut restarts = self.restarts.lock().unwrap_or_else(PoisonError::into_inner);
+        let depth = restarts.depth.remove(dependency).unwrap_or_default();
+
+        let dependents = self
+            .pods()
+            .into_iter()
+            .filter(|pod| pod.config().restart_with_dependencies && pod.config().depends_on.contains(dependency))
+            .filter(|pod| !pod.archived() && matches!(pod.state().current(), PodState::Enabled | PodState::Degraded))
+            .collect::<Vec<_>>();
+
+        if dependents.is_empty() {
+            return Vec::new()
+        }
+
+        if depth >= Self::MAX_RESTART_DEPTH {
+            tracing::warn!("Not restarting dependents of {} as {} restarts have already cascaded to it", dependency, depth);
+            return Vec::new()
+        }
+
+        let cooldown = Duration::from_secs(self.config.dependency_restart_cooldown);
+        let now = Instant::now();
+        if let Some(last) = restarts.last.get(dependency).filter(|last| now.duration_since(**last) < cooldown) {
+            tracing::warn!(
+                "Not restarting dependents of {} as they were restarted {}s ago",
+                dependency,
+                now.duration_since(*last).as_secs(),
+            );
+            return Vec::new()
+        }
+
+        restarts.last.insert(dependency.clone(), now);
+        dependents
+            .into_iter()
+            .inspect(|pod| {
+                restarts.depth.insert(pod.id(), depth + 1);
+            })
+            .collect()
+    }
+
+    /// Check if the given state of a dependent is a container that was started before its
+    /// dependency's container was started at the given time, and so should be restarted
+    pub fn dependent_outdated(state: &PodStateKnown, dependency_started: DateTime<Utc>) -> bool {
+        matches!(state, PodStateKnown::Enabled(enabled) if enabled.started < dependency_started)
+    }
+
+    /// Forget the cascade depth of a dependent whose restart did not start its container
+    pub fn abandon_dependent_restart(&self, pod: &Pod) {
+        self.restarts.lock().unwrap_or_else(PoisonError::into_inner).depth.remove(&pod.id());
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodDependencyError {
+    #[error("Pod {pod} depends on pod {dependency}, which is not loaded")]
+    Unknown {
+        pod: DeimosId,
+        dependency: DeimosId,
+    },
+    #[error(
+        "Pods {} depend on each other in a cycle",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(" -> ")
+    )]
+    Cycle(Vec<DeimosId>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pods(deps: &[(&str, &[&str])]) -> HashMap<DeimosId, Arc<PodConfig>> {
+        deps.iter()
+            .map(|(id, depends_on)| {
+                let config = toml::from_str::<PodConfig>(&format!(
+                    "id = \"{}\"\nname = \"{}\"\ndepends_on = {:?}\n[docker]\nimage = \"alpine:3\"\n",
+                    id, id, depends_on,
+                ))
+                .unwrap();
+                (config.id.clone(), Arc::new(config))
+            })
+            .collect()
+    }
+
+    fn cycle(deps: &[(&str, &[&str])]) -> Vec<String> {
+        match PodManager::check_dependencies(&pods(deps)) {
+            Err(PodDependencyError::Cycle(path)) => path.iter().map(DeimosId::owned).collect(),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn shared_dependencies_are_not_cycles() {
+        let deps: &[(&str, &[&str])] = &[("app", &["db", "cache"]), ("cache", &["db"]), ("db", &[]), ("worker", &["db"])];
+        PodManager::check_dependencies(&pods(deps)).unwrap();
+    }
+
+    #[test]
+    fn unknown_dependency_is_rejected() {
+        let err = PodManager::check_dependencies(&pods(&[("app", &["db"])])).unwrap_err();
+        assert!(matches!(err, PodDependencyError::Unknown { ref pod, ref dependency } if pod.owned() == "app" && dependency.owned() == "db"));
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_pods() {
+        assert_eq!(cycle(&[("a", &["a"])]), ["a"]);
+
+        //Only the pods in the cycle are reported, not the path leading to it
+        let mut found = cycle(&[("a", &["b"]), ("b", &["c"]), ("c", &["b"])]);
+        found.sort();
+        assert_eq!(found, ["b", "c"]);
+    }
+}
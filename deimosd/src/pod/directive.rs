@@ -0,0 +1,86 @@
+//! Last state that each pod was commanded into, persisted so that pods enabled before the daemon
+//! stopped or the host rebooted can be enabled again when the daemon starts. Disabling pods when
+//! the daemon shuts down does not change their directive
+
+use std::{collections::HashMap, sync::{Arc, PoisonError}};
+
+use super::{Pod, PodManager};
+
+/// State that a pod was last successfully commanded into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PodDirective {
+    #[serde(rename = "enabled")]
+    Enabled,
+    #[serde(rename = "paused")]
+    Paused,
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+impl Pod {
+    /// Get the state that this pod was last commanded into, if it has been commanded since it
+    /// was first loaded
+    pub fn directive(&self) -> Option<PodDirective> {
+        *self.directive.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PodManager {
+    /// Record the state that the given pod was commanded into, notifying waiters if it changed
+    pub(super) fn set_directive(&self, pod: &Pod, directive: PodDirective) {
+        let previous = pod.directive.lock().unwrap_or_else(PoisonError::into_inner).replace(directive);
+        if previous != Some(directive) {
+            self.directives.notify_one();
+        }
+    }
+
+    /// Wait until the directive of a pod changes
+    pub async fn directives_changed(&self) {
+        self.directives.notified().await
+    }
+
+    /// Check if pods that were enabled when the daemon stopped should be enabled when it starts
+    pub const fn restores_on_start(&self) -> bool {
+        self.config.restore_on_start
+    }
+
+    /// Get the pods that were last commanded to be enabled and may be enabled again
+    pub fn restorable(&self) -> Vec<Arc<Pod>> {
+        let mut pods = self
+            .pods()
+            .into_iter()
+            .filter(|pod| pod.directive() == Some(PodDirective::Enabled) && !pod.archived())
+            .filter(|pod| {
+                //Restarting the daemon must not resume a pod's restart storm
+                let open = pod.restart_breaker().is_open();
+                if open {
+                    tracing::warn!("Not restoring pod {} as its restart policy stopped restarting it", pod.id());
+                }
+
+                !open
+            })
+            .collect::<Vec<_>>();
+
+        pods.sort_unstable_by(|a, b| str::cmp(&a.id(), &b.id()));
+        pods
+    }
+
+    /// Get the directives of all pods that have been commanded
+    pub(super) fn directives(&self) -> HashMap<String, PodDirective> {
+        self
+            .loaded()
+            .iter()
+            .filter_map(|(id, pod)| Some((id.owned(), pod.directive()?)))
+            .collect()
+    }
+
+    /// Restore the directives of pods from persistent state
+    pub(super) fn restore_directives(&self, directives: HashMap<String, PodDirective>) {
+        for (id, directive) in directives {
+            match self.loaded().get(id.as_str()) {
+                Some(pod) => *pod.directive.lock().unwrap_or_else(PoisonError::into_inner) = Some(directive),
+                None => tracing::warn!("Discarding last directive of unknown pod {}", id),
+            }
+        }
+    }
+}
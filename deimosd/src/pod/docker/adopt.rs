@@ -0,0 +1,175 @@
+//! Adoption of containers left behind by a previous run of the daemon that did not shut down
+//! cleanly. Running and paused containers of loaded pods are taken over as the pod's container,
+//! while stopped containers and containers of pods that are no longer loaded are removed so that
+//! they do not conflict with containers created later
+
+use std::{collections::HashMap, sync::Arc};
+
+use bollard::{container::{InspectContainerOptions, ListContainersOptions, RemoveContainerOptions}, secret::ContainerSummary};
+use chrono::{DateTime, Utc};
+
+use super::containers::POD_LABEL;
+use crate::{pod::{id::DockerId, state::{PodEnable, PodPaused}, Pod, PodManager, PodStateKnown}, server::upnp::UpnpError};
+
+/// State of a container that can be adopted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdoptableState {
+    Running,
+    Paused,
+}
+
+impl PodManager {
+    /// Adopt or remove the containers labelled with the ID of a pod that Docker still reports, if
+    /// enabled in the config. UPnP leases are requested for adopted pods, so the UPnP task must
+    /// already be running
+    pub async fn adopt_containers(&self) {
+        if !self.config.adopt_orphans {
+            return
+        }
+
+        let options = ListContainersOptions::<String> {
+            all: true,
+            filters: HashMap::from([(String::from("label"), vec![String::from(POD_LABEL)])]),
+            ..Default::default()
+        };
+
+        let containers = match self.docker.list_containers(Some(options)).await {
+            Ok(containers) => containers,
+            Err(e) => {
+                tracing::error!("Failed to list containers left by a previous run of the daemon: {}", e);
+                return
+            }
+        };
+
+        let mut owned = HashMap::<String, Vec<ContainerSummary>>::new();
+        for container in containers {
+            let Some(pod) = container.labels.as_ref().and_then(|labels| labels.get(POD_LABEL)).cloned() else {
+                continue
+            };
+
+            owned.entry(pod).or_default().push(container);
+        }
+
+        for (id, mut containers) in owned {
+            let Some(pod) = self.get(&id) else {
+                for container in containers.iter().filter_map(|container| container.id.as_deref()) {
+                    tracing::info!("Removing container {} of pod {} which is no longer loaded", container, id);
+                    self.remove_leftover(container).await;
+                }
+
+                continue
+            };
+
+            //Running containers are preferred, only one container may be adopted for each pod
+            containers.sort_by_key(|container| match adoptable(container) {
+                Some(AdoptableState::Running) => 0,
+                Some(AdoptableState::Paused) => 1,
+                None => 2,
+            });
+
+            let mut containers = containers.into_iter();
+            if let Some(container) = containers.next() {
+                match (container.id.clone(), adoptable(&container)) {
+                    (Some(docker_id), Some(state)) => {
+                        let docker_id = DockerId::from(docker_id);
+                        match self.adopt(&pod, docker_id.clone(), state).await {
+                            Ok(()) => tracing::info!("Adopted container {} of pod {} left by a previous run", docker_id, pod.id()),
+                            Err(e) => {
+                                tracing::error!("Failed to adopt container {} of pod {}, removing it: {}", docker_id, pod.id(), e);
+                                self.remove_leftover(&docker_id).await;
+                            }
+                        }
+                    },
+                    (Some(docker_id), None) => {
+                        tracing::info!("Removing stopped container {} of pod {}", docker_id, pod.id());
+                        self.remove_leftover(&docker_id).await;
+                    },
+                    (None, _) => (),
+                }
+            }
+
+            for container in containers.filter_map(|container| container.id) {
+                tracing::info!("Removing extra container {} of pod {}", container, pod.id());
+                self.remove_leftover(&container).await;
+            }
+        }
+    }
+
+    /// Take over the given container as the container of the pod, setting the pod's state to
+    /// match the container's
+    async fn adopt(&self, pod: &Arc<Pod>, docker_id: DockerId, state: AdoptableState) -> Result<(), UpnpError> {
+        let mut lock = pod.state().transact().await;
+        let upnp_lease = self.request_leases(pod).await?;
+        let annotations = self.listen_annotations(pod);
+        let started = self.container_started(&docker_id).await.unwrap_or_else(Utc::now);
+        match state {
+            AdoptableState::Paused => lock.set(PodStateKnown::Paused(PodPaused { docker_id: docker_id.clone(), upnp_lease, annotations, started })),
+            AdoptableState::Running => {
+                let network = self.inspect_network(pod, &docker_id).await;
+                pod.set_network(Some(Arc::new(network)));
+
+                lock.set(PodStateKnown::Enabled(PodEnable {
+                    docker_id: docker_id.clone(),
+                    upnp_lease,
+                    annotations,
+                    started,
+                    restarted: None,
+                    degraded: false,
+                }));
+            },
+        }
+
+        self.reverse_lookup.insert(docker_id, pod.clone());
+        Ok(())
+    }
+
+    /// Get the time that the given container was last started at
+    async fn container_started(&self, container: &DockerId) -> Option<DateTime<Utc>> {
+        let inspect = self.docker.inspect_container(container, None::<InspectContainerOptions>).await.ok()?;
+        let started = inspect.state?.started_at?;
+        DateTime::parse_from_rfc3339(&started).ok().map(|started| started.with_timezone(&Utc))
+    }
+
+    /// Forcefully remove a container that cannot be adopted
+    async fn remove_leftover(&self, container: &str) {
+        let options = RemoveContainerOptions { force: true, ..Default::default() };
+        if let Err(e) = self.docker.remove_container(container, Some(options)).await {
+            tracing::error!("Failed to remove container {}: {}", container, e);
+        }
+    }
+}
+
+/// Get the state of the given container if it can be adopted
+fn adoptable(container: &ContainerSummary) -> Option<AdoptableState> {
+    match container.state.as_deref()? {
+        "running" | "restarting" => Some(AdoptableState::Running),
+        "paused" => Some(AdoptableState::Paused),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container(state: Option<&str>) -> ContainerSummary {
+        ContainerSummary { state: state.map(str::to_owned), ..Default::default() }
+    }
+
+    #[test]
+    fn only_live_containers_are_adoptable() {
+        let expected = [
+            (Some("running"), Some(AdoptableState::Running)),
+            (Some("restarting"), Some(AdoptableState::Running)),
+            (Some("paused"), Some(AdoptableState::Paused)),
+            (Some("exited"), None),
+            (Some("created"), None),
+            (Some("dead"), None),
+            (None, None),
+        ];
+
+        for (state, adopted) in expected {
+            assert_eq!(adoptable(&container(state)), adopted, "{:?}", state);
+        }
+    }
+}
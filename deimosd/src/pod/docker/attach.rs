@@ -0,0 +1,235 @@
+//! Interactive terminal sessions in an enabled pod's container, run as Docker exec processes with
+//! a TTY attached. Docker cannot stop an exec process, so sessions that are closed while their
+//! process is still running kill it with a `kill` process run in the same container
+
+use std::{pin::Pin, time::Duration};
+
+use bollard::{container::LogOutput, exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults}, Docker};
+use futures::{stream::BoxStream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::pod::{config::PodAttachConfig, id::DockerId, Pod, PodManager, PodStateKnown};
+
+/// A process running in a pod's container with a TTY, attached to by a single client
+pub struct PodAttachSession {
+    docker: Docker,
+    /// Container that the process runs in
+    container: DockerId,
+    exec_id: String,
+    /// Time without input from the client after which the session is closed, from the pod's
+    /// config when the session was opened
+    pub idle_timeout: Duration,
+    /// Maximum time that the session may remain open, from the pod's config when the session was
+    /// opened
+    pub max_duration: Duration,
+    /// Output written to the TTY by the process, ending when the process exits
+    pub output: BoxStream<'static, Result<LogOutput, bollard::errors::Error>>,
+    input: Pin<Box<dyn AsyncWrite + Send>>,
+}
+
+impl PodManager {
+    /// Default command run in a pod's container when its attach config does not give one
+    const ATTACH_SHELL: &'static str = "/bin/sh";
+
+    /// Start the command configured for the given pod in its container with a TTY of the given
+    /// size, failing if the pod does not allow terminal sessions or is not enabled
+    pub async fn attach(&self, pod: &Pod, columns: u16, rows: u16) -> Result<PodAttachSession, PodAttachError> {
+        let pod_config = pod.config();
+        let config = pod_config.attach.as_ref().ok_or(PodAttachError::NotAllowed)?;
+        let docker_id = {
+            let lock = pod.state().read().await;
+            match *lock {
+                PodStateKnown::Enabled(ref run) => run.docker_id.clone(),
+                _ => return Err(PodAttachError::NotEnabled),
+            }
+        };
+
+        let exec = self
+            .docker
+            .create_exec(
+                &docker_id,
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    tty: Some(true),
+                    env: Some(vec![String::from("TERM=vt100")]),
+                    cmd: Some(Self::attach_command(config)),
+                    ..Default::default()
+                }
+            )
+            .await?;
+
+        let started = self
+            .docker
+            .start_exec(&exec.id, Some(StartExecOptions { detach: false, tty: true, output_capacity: None }))
+            .await?;
+
+        let StartExecResults::Attached { output, input } = started else {
+            return Err(PodAttachError::Detached)
+        };
+
+        let mut session = PodAttachSession {
+            docker: self.docker.clone(),
+            container: docker_id,
+            exec_id: exec.id,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            max_duration: Duration::from_secs(config.max_duration),
+            output: output.boxed(),
+            input,
+        };
+
+        //The TTY cannot be sized until the process has started
+        if let Err(e) = session.resize(columns, rows).await {
+            tracing::warn!("Failed to set initial terminal size of session in pod {}: {}", pod.id(), e);
+        }
+
+        tracing::info!("Started terminal session {} in pod {}", session.exec_id, pod.id());
+        Ok(session)
+    }
+
+    fn attach_command(config: &PodAttachConfig) -> Vec<String> {
+        match config.command {
+            Some(ref command) if !command.is_empty() => command.clone(),
+            _ => vec![String::from(Self::ATTACH_SHELL)],
+        }
+    }
+}
+
+impl PodAttachSession {
+    /// Write the given bytes to the TTY of the session's process
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        self.input.write_all(bytes).await?;
+        self.input.flush().await
+    }
+
+    /// Change the size of the session's TTY
+    pub async fn resize(&mut self, columns: u16, rows: u16) -> Result<(), bollard::errors::Error> {
+        self.docker
+            .resize_exec(&self.exec_id, ResizeExecOptions { width: columns.max(1), height: rows.max(1) })
+            .await
+    }
+
+    /// Get the exit code of the session's process, or `None` if it is still running
+    pub async fn exit_code(&mut self) -> Result<Option<i64>, bollard::errors::Error> {
+        let inspect = self.docker.inspect_exec(&self.exec_id).await?;
+        Ok(
+            match inspect.running {
+                Some(true) => None,
+                _ => inspect.exit_code,
+            }
+        )
+    }
+
+    /// Close the process's standard input and kill the process if it is still running, so that
+    /// a process ignoring the end of its input does not outlive the session
+    pub async fn close(mut self) {
+        if let Err(e) = self.input.shutdown().await {
+            tracing::trace!("Failed to close input of terminal session {}: {}", self.exec_id, e);
+        }
+
+        if let Err(e) = self.kill().await {
+            tracing::warn!("Failed to kill process of terminal session {}: {}", self.exec_id, e);
+        }
+    }
+
+    /// Kill the session's process if it is still running. Docker reports the process's PID in
+    /// the Docker host's PID namespace, which is translated to its PID in the container with the
+    /// host's `/proc` and given to a `kill` process run in the container as root. This requires
+    /// the daemon to share the PID namespace of the Docker host, and the container to provide
+    /// `kill`. Processes started by the killed process lose their terminal and are sent `SIGHUP`
+    async fn kill(&mut self) -> Result<(), PodAttachKillError> {
+        let inspect = self.docker.inspect_exec(&self.exec_id).await?;
+        if inspect.running != Some(true) {
+            return Ok(())
+        }
+
+        let pid = inspect.pid.filter(|pid| *pid > 0).ok_or(PodAttachKillError::NoPid)?;
+        let status = tokio::fs::read_to_string(format!("/proc/{}/status", pid))
+            .await
+            .map_err(PodAttachKillError::Namespace)?;
+        let pid = container_pid(&status).ok_or(PodAttachKillError::NoNamespacePid)?;
+
+        let exec = self
+            .docker
+            .create_exec(
+                &self.container,
+                CreateExecOptions {
+                    user: Some(String::from("0")),
+                    cmd: Some(vec![String::from("kill"), String::from("-KILL"), pid.to_string()]),
+                    ..Default::default()
+                }
+            )
+            .await?;
+
+        self
+            .docker
+            .start_exec(&exec.id, Some(StartExecOptions { detach: true, tty: false, output_capacity: None }))
+            .await?;
+
+        tracing::info!("Killed process {} of terminal session {}", pid, self.exec_id);
+        Ok(())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.exec_id
+    }
+}
+
+/// Get the PID of a process in its innermost PID namespace from the contents of its
+/// `/proc/<pid>/status` file, or `None` if the kernel does not report namespaced PIDs
+fn container_pid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?
+        .split_whitespace()
+        .next_back()?
+        .parse()
+        .ok()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodAttachKillError {
+    #[error("Docker did not report the process's PID")]
+    NoPid,
+    #[error("Failed to read the process's status from the host: {0}")]
+    Namespace(#[source] std::io::Error),
+    #[error("The kernel does not report the process's PID in the container")]
+    NoNamespacePid,
+    #[error("Docker API error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodAttachError {
+    #[error("Terminal sessions are not allowed for this pod")]
+    NotAllowed,
+    #[error("Pod is not enabled")]
+    NotEnabled,
+    #[error("Docker started the session's process detached from its input and output")]
+    Detached,
+    #[error("Docker API error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn container_pid_is_innermost_namespace() {
+        let status = "Name:\tsh\nState:\tS (sleeping)\nPid:\t48213\nNSpid:\t48213\t17\nNSpgid:\t48213\t17\n";
+        assert_eq!(container_pid(status), Some(17));
+    }
+
+    #[test]
+    fn container_pid_of_host_process() {
+        assert_eq!(container_pid("Pid:\t812\nNSpid:\t812\n"), Some(812));
+    }
+
+    #[test]
+    fn container_pid_needs_namespaced_pids() {
+        assert_eq!(container_pid("Name:\tsh\nPid:\t48213\n"), None);
+        assert_eq!(container_pid("NSpid:\n"), None);
+    }
+}
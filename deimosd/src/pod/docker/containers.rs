@@ -0,0 +1,196 @@
+//! Labels identifying the pod that owns each container, and the join of loaded pods with the
+//! containers Docker reports so that the host can be inspected without the client
+
+use std::{collections::HashMap, sync::Arc};
+
+use bollard::{container::ListContainersOptions, secret::ContainerSummary};
+
+use crate::pod::{id::{DeimosId, DockerId}, Pod, PodManager, PodState};
+
+/// Label holding the ID of the pod that a container was created for
+pub const POD_LABEL: &str = "deimos.pod";
+/// Label holding the title of the pod that a container was created for
+pub const TITLE_LABEL: &str = "deimos.title";
+
+/// Maximum length of the pod title included in container names
+const NAME_TITLE_LENGTH: usize = 32;
+
+/// A loaded pod and the containers created for it that Docker still reports
+#[derive(Debug)]
+pub struct PodContainers {
+    pub id: DeimosId,
+    pub title: String,
+    pub state: PodState,
+    pub containers: Vec<PodContainerInfo>,
+}
+
+/// A container as reported by Docker
+#[derive(Debug, Clone, Default)]
+pub struct PodContainerInfo {
+    pub id: String,
+    pub name: String,
+    /// ID of the pod from the container's labels, empty if the container has none
+    pub pod: String,
+    pub state: String,
+    pub status: String,
+    /// Time the container was created at, in seconds since the Unix epoch
+    pub created: i64,
+    pub image: String,
+    /// Repository digest of the container's image, or the image ID if it has no digest
+    pub image_digest: String,
+}
+
+/// Loaded pods joined with their containers, and containers labelled for pods that are not loaded
+#[derive(Debug, Default)]
+pub struct PodContainerReport {
+    pub pods: Vec<PodContainers>,
+    pub orphans: Vec<PodContainerInfo>,
+}
+
+impl PodManager {
+    /// List all containers known to Docker and join them with the loaded pods
+    pub async fn pod_containers(&self) -> Result<PodContainerReport, bollard::errors::Error> {
+        let containers = self
+            .docker
+            .list_containers(Some(ListContainersOptions::<String> { all: true, ..Default::default() }))
+            .await?;
+
+        let mut digests = HashMap::<String, String>::new();
+        for image in containers.iter().filter_map(|container| container.image_id.as_ref()) {
+            if digests.contains_key(image) {
+                continue
+            }
+
+            let digest = match self.docker.inspect_image(image).await {
+                Ok(inspect) => inspect.repo_digests.and_then(|digests| digests.into_iter().next()),
+                Err(e) => {
+                    tracing::trace!("Failed to inspect image {} of container: {}", image, e);
+                    None
+                }
+            };
+
+            digests.insert(image.clone(), digest.unwrap_or_else(|| image.clone()));
+        }
+
+        let containers = containers
+            .into_iter()
+            .map(|container| container_info(container, &digests))
+            .collect::<Vec<_>>();
+
+        let mut pods = self.pods();
+        pods.sort_unstable_by(|a, b| str::cmp(&a.id(), &b.id()));
+
+        let owners = self
+            .reverse_lookup
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().id()))
+            .collect::<HashMap<_, _>>();
+
+        Ok(join_containers(&pods, &owners, containers))
+    }
+}
+
+/// Join the given containers to the pods that own them, found by the pod label of the container
+/// or by the pod that the daemon created the container for. Containers labelled for a pod that
+/// is not loaded are reported as orphans, containers with no owner at all are ignored
+fn join_containers(pods: &[Arc<Pod>], owners: &HashMap<DockerId, DeimosId>, containers: Vec<PodContainerInfo>) -> PodContainerReport {
+    let mut report = PodContainerReport {
+        pods: pods
+            .iter()
+            .map(|pod| PodContainers {
+                id: pod.id(),
+                title: pod.title().to_string(),
+                state: pod.state().current(),
+                containers: Vec::new(),
+            })
+            .collect(),
+        orphans: Vec::new(),
+    };
+
+    for container in containers {
+        let owner = owners
+            .get(container.id.as_str())
+            .map(|id| &**id)
+            .or_else(|| (!container.pod.is_empty()).then_some(container.pod.as_str()));
+
+        let Some(owner) = owner else {
+            continue
+        };
+
+        match report.pods.iter_mut().find(|pod| &*pod.id == owner) {
+            Some(pod) => pod.containers.push(container),
+            None => report.orphans.push(container),
+        }
+    }
+
+    report
+}
+
+/// Convert a container reported by Docker, looking up its image digest in the given map
+fn container_info(container: ContainerSummary, digests: &HashMap<String, String>) -> PodContainerInfo {
+    let pod = container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(POD_LABEL))
+        .cloned()
+        .unwrap_or_default();
+
+    //Docker reports names with a leading slash
+    let name = container
+        .names
+        .iter()
+        .flatten()
+        .next()
+        .map(|name| name.trim_start_matches('/').to_owned())
+        .unwrap_or_default();
+
+    let image_digest = container
+        .image_id
+        .as_ref()
+        .and_then(|image| digests.get(image))
+        .cloned()
+        .unwrap_or_default();
+
+    PodContainerInfo {
+        id: container.id.unwrap_or_default(),
+        name,
+        pod,
+        state: container.state.unwrap_or_default(),
+        status: container.status.unwrap_or_default(),
+        created: container.created.unwrap_or_default(),
+        image: container.image.unwrap_or_default(),
+        image_digest,
+    }
+}
+
+/// Get the labels applied to the container created for the given pod
+pub(super) fn container_labels(pod: &Pod) -> HashMap<String, String> {
+    HashMap::from([
+        (String::from(POD_LABEL), pod.id().owned()),
+        (String::from(TITLE_LABEL), pod.title().to_string()),
+    ])
+}
+
+/// Get the name of the container created for the given pod, made of the pod's ID and its title
+/// reduced to the characters Docker allows in names
+pub(super) fn container_name(pod: &Pod) -> String {
+    let mut slug = String::with_capacity(NAME_TITLE_LENGTH);
+    for c in pod.title().chars() {
+        if slug.len() >= NAME_TITLE_LENGTH {
+            break
+        }
+
+        match c.is_ascii_alphanumeric() {
+            true => slug.push(c.to_ascii_lowercase()),
+            false => if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            },
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    match slug.is_empty() {
+        true => pod.id().owned(),
+        false => format!("{}-{}", &*pod.id(), slug),
+    }
+}
@@ -0,0 +1,242 @@
+//! Debouncing of the events received from Docker for each container. A container that crash loops
+//! or is controlled with the Docker CLI emits events in rapid succession, and handling each event
+//! separately would transition the pod repeatedly. Events received for a container in quick
+//! succession are instead collected into a burst that is handled as a single event, and bursts
+//! that may lead to a corrective action are held back while a corrective action taken recently
+//! for the same pod is cooling down.
+//! The debouncer does not read the clock itself, the current time is passed to each method so
+//! that it behaves the same for any sequence of events and times
+
+use std::{collections::HashMap, sync::{Arc, PoisonError}, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+
+use super::events::DockerPodEvent;
+use crate::pod::{id::DeimosId, Pod};
+
+/// Collects the events received for each container until they are due to be handled
+pub struct DockerEventDebouncer {
+    /// Time after the last event of a burst that the burst is handled if no other events arrive
+    window: Duration,
+    /// Minimum time between corrective actions taken for a single pod
+    cooldown: Duration,
+    /// Events waiting to be handled, by the ID of the container they were received for
+    pending: HashMap<String, DockerEventBurst>,
+    /// Time that the cooldown of each pod that recently took a corrective action ends at
+    cooldowns: HashMap<DeimosId, Instant>,
+}
+
+/// Events received for a single container in quick succession, handled together
+pub struct DockerEventBurst {
+    pub pod: Arc<Pod>,
+    pub container: String,
+    /// Actions received and the time that Docker emitted each, oldest first
+    pub events: Vec<(String, DateTime<Utc>)>,
+    /// Set if the burst was held back by the cooldown of a previous corrective action
+    pub deferred: bool,
+    /// Time that the first event of the burst was received
+    first: Instant,
+    /// Time that the most recent event of the burst was received
+    last: Instant,
+}
+
+/// Number of events for a pod that were handled together with another event, and the number of
+/// corrective actions that were delayed by the cooldown
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PodEventCounts {
+    pub coalesced: u64,
+    pub deferred: u64,
+}
+
+impl DockerEventDebouncer {
+    /// Number of windows after the first event of a burst that the burst is handled even if
+    /// events keep arriving, so that a container emitting events constantly is still handled
+    const MAX_WINDOWS: u32 = 8;
+
+    pub fn new(window: Duration, cooldown: Duration) -> Self {
+        Self {
+            window,
+            cooldown,
+            pending: HashMap::new(),
+            cooldowns: HashMap::new(),
+        }
+    }
+
+    /// Add an event received at the given time to the burst of its container
+    pub fn push(&mut self, event: DockerPodEvent, now: Instant) {
+        let DockerPodEvent { pod, container, action, at } = event;
+        match self.pending.get_mut(&container) {
+            Some(burst) => {
+                burst.events.push((action, at));
+                burst.last = now;
+            },
+            None => {
+                let burst = DockerEventBurst {
+                    pod,
+                    container: container.clone(),
+                    events: vec![(action, at)],
+                    deferred: false,
+                    first: now,
+                    last: now,
+                };
+
+                self.pending.insert(container, burst);
+            },
+        }
+    }
+
+    /// Record that a corrective action was taken for the given pod at the given time, holding back
+    /// its next bursts until the cooldown ends
+    pub fn acted(&mut self, pod: DeimosId, now: Instant) {
+        self.cooldowns.insert(pod, now + self.cooldown);
+    }
+
+    /// Get the earliest time that a pending burst will be due, or `None` if no events are pending
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|burst| self.due(burst).0).min()
+    }
+
+    /// Remove and return every burst that is due at the given time, oldest first
+    pub fn take_due(&mut self, now: Instant) -> Vec<DockerEventBurst> {
+        let due = self
+            .pending
+            .iter()
+            .filter_map(|(container, burst)| {
+                let (at, deferred) = self.due(burst);
+                (at <= now).then(|| (container.clone(), deferred))
+            })
+            .collect::<Vec<_>>();
+
+        let mut bursts = due
+            .into_iter()
+            .filter_map(|(container, deferred)| {
+                let mut burst = self.pending.remove(&container)?;
+                burst.deferred = deferred;
+                Some(burst)
+            })
+            .collect::<Vec<_>>();
+
+        self.cooldowns.retain(|_, until| *until > now);
+        bursts.sort_unstable_by_key(|burst| burst.first);
+        bursts
+    }
+
+    /// Get the time that the given burst is due, and if it is due later than it would be without
+    /// the cooldown of its pod
+    fn due(&self, burst: &DockerEventBurst) -> (Instant, bool) {
+        let quiet = (burst.last + self.window).min(burst.first + self.window * Self::MAX_WINDOWS);
+        match self.cooldowns.get(&burst.pod.id()) {
+            Some(until) if *until > quiet => (*until, true),
+            _ => (quiet, false),
+        }
+    }
+}
+
+impl Pod {
+    /// Get the number of events for this pod that were coalesced or deferred since it was loaded
+    pub fn event_counts(&self) -> PodEventCounts {
+        *self.event_counts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count the events of a burst that were handled together with another event, and whether a
+    /// corrective action was deferred by the cooldown
+    pub(super) fn count_burst(&self, events: usize, deferred: bool) {
+        let mut counts = self.event_counts.lock().unwrap_or_else(PoisonError::into_inner);
+        counts.coalesced += events.saturating_sub(1) as u64;
+        counts.deferred += u64::from(deferred);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    async fn test_pod(id: &str) -> (tempfile::TempDir, Arc<Pod>) {
+        let (dir, pod) = Pod::test(&format!("id = \"{}\"\nname = \"{}\"\n[docker]\nimage = \"alpine:3\"\n", id, id)).await;
+        (dir, Arc::new(pod))
+    }
+
+    fn event(pod: &Arc<Pod>, container: &str, action: &str) -> DockerPodEvent {
+        DockerPodEvent { pod: pod.clone(), container: container.to_owned(), action: action.to_owned(), at: Utc::now() }
+    }
+
+    fn actions(burst: &DockerEventBurst) -> Vec<&str> {
+        burst.events.iter().map(|(action, _)| action.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn burst_is_handled_once_quiet() {
+        let (_dir, pod) = test_pod("pod").await;
+        let mut debouncer = DockerEventDebouncer::new(5 * SECOND, 30 * SECOND);
+        let start = Instant::now();
+        assert_eq!(debouncer.next_deadline(), None);
+
+        for (i, action) in ["kill", "die", "start"].into_iter().enumerate() {
+            debouncer.push(event(&pod, "c1", action), start + SECOND * i as u32);
+        }
+
+        assert_eq!(debouncer.next_deadline(), Some(start + 7 * SECOND));
+        assert!(debouncer.take_due(start + 6 * SECOND).is_empty());
+
+        let due = debouncer.take_due(start + 7 * SECOND);
+        assert_eq!(due.len(), 1);
+        assert_eq!(actions(&due[0]), ["kill", "die", "start"]);
+        assert!(!due[0].deferred);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn constant_events_are_handled_after_max_windows() {
+        let (_dir, pod) = test_pod("pod").await;
+        let mut debouncer = DockerEventDebouncer::new(SECOND, 30 * SECOND);
+        let start = Instant::now();
+
+        for i in 0..40 {
+            debouncer.push(event(&pod, "c1", "die"), start + SECOND / 2 * i);
+        }
+
+        assert_eq!(debouncer.next_deadline(), Some(start + SECOND * DockerEventDebouncer::MAX_WINDOWS));
+    }
+
+    #[tokio::test]
+    async fn cooldown_defers_bursts_of_same_pod() {
+        let (_dir, pod) = test_pod("pod").await;
+        let (_other_dir, other) = test_pod("other").await;
+        let mut debouncer = DockerEventDebouncer::new(SECOND, 30 * SECOND);
+        let start = Instant::now();
+
+        debouncer.acted(pod.id(), start);
+        debouncer.push(event(&pod, "c1", "die"), start + SECOND);
+        debouncer.push(event(&other, "c2", "die"), start + SECOND);
+
+        let due = debouncer.take_due(start + 2 * SECOND);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].container, "c2");
+
+        assert_eq!(debouncer.next_deadline(), Some(start + 30 * SECOND));
+        let due = debouncer.take_due(start + 30 * SECOND);
+        assert_eq!(due.len(), 1);
+        assert!(due[0].deferred);
+
+        //Bursts after the cooldown ended are handled without delay
+        debouncer.push(event(&pod, "c1", "die"), start + 31 * SECOND);
+        let due = debouncer.take_due(start + 32 * SECOND);
+        assert!(!due[0].deferred);
+    }
+
+    #[tokio::test]
+    async fn bursts_are_returned_oldest_first() {
+        let (_dir, pod) = test_pod("pod").await;
+        let mut debouncer = DockerEventDebouncer::new(5 * SECOND, 30 * SECOND);
+        let start = Instant::now();
+
+        debouncer.push(event(&pod, "new", "start"), start + 2 * SECOND);
+        debouncer.push(event(&pod, "old", "die"), start);
+
+        let due = debouncer.take_due(start + 10 * SECOND);
+        assert_eq!(due.iter().map(|burst| burst.container.as_str()).collect::<Vec<_>>(), ["old", "new"]);
+    }
+}
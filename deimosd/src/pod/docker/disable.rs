@@ -1,53 +1,181 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
+use chrono::Utc;
 use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::sync::Semaphore;
 
-use crate::pod::{id::DockerId, state::PodStateWriteHandle, Pod, PodManager, PodStateKnown};
+use crate::pod::{directive::PodDirective, id::DockerId, state::PodStateWriteHandle, Pod, PodManager, PodState, PodStateKnown};
 
+/// Number of pods that were stopped when the daemon shut down, by how they were stopped
+#[derive(Debug, Default)]
+pub struct PodShutdownSummary {
+    /// Pods whose containers stopped gracefully
+    pub stopped: usize,
+    /// Pods whose containers were removed forcefully
+    pub forced: usize,
+    /// Pods whose containers could not be removed
+    pub failed: usize,
+}
+
+/// How a pod was stopped when the daemon shut down
+#[derive(Debug)]
+enum PodShutdownOutcome {
+    Stopped,
+    Forced,
+    Failed(String),
+}
+
+impl PodShutdownSummary {
+    fn record(&mut self, pod: &Pod, outcome: PodShutdownOutcome) {
+        match outcome {
+            PodShutdownOutcome::Stopped => self.stopped += 1,
+            PodShutdownOutcome::Forced => self.forced += 1,
+            PodShutdownOutcome::Failed(e) => {
+                tracing::error!("Failed to stop pod {} on shutdown: {}", pod.id(), e);
+                self.failed += 1;
+            }
+        }
+    }
+}
 
 impl PodManager {
-    /// Fully disable all pods
-    pub async fn disable_all(&self) -> Vec<PodDisableError> {
-        tracing::trace!("Disabling all enabled pods");
-
-        let mut tasks = self
-            .pods
-            .values()
-            .cloned()
-            .map(|pod| async move { self.disable(pod.clone(), pod.state().transact().await).await })
-            .collect::<FuturesUnordered<_>>();
-
-        let mut errors = Vec::new();
-        while let Some(result) = tasks.next().await {
-            if let Err(e) = result {
-                errors.push(e);
+    /// Time allowed beyond a pod's stop timeout for Docker to kill and remove its container when
+    /// all pods are disabled
+    const SHUTDOWN_MARGIN: Duration = Duration::from_secs(5);
+    /// Time allowed to forcefully remove the containers of pods left running once the shutdown
+    /// deadline passes
+    const FORCE_REMOVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Disable all pods, stopping at most `parallelism` pods at a time. Pods that do not stop
+    /// within their stop timeout, or before the given timeout for the whole shutdown passes,
+    /// have their containers removed forcefully
+    pub async fn disable_all(&self, parallelism: usize, timeout: Duration) -> PodShutdownSummary {
+        let pods = self
+            .pods()
+            .into_iter()
+            .filter(|pod| pod.state().current() != PodState::Disabled)
+            .collect::<Vec<_>>();
+
+        tracing::info!("Disabling {} pods, {} at a time", pods.len(), parallelism.max(1));
+
+        let permits = Semaphore::new(parallelism.max(1));
+        let mut summary = PodShutdownSummary::default();
+        let mut remaining = pods.iter().map(|pod| pod.id()).collect::<HashSet<_>>();
+
+        {
+            let mut tasks = pods
+                .iter()
+                .map(|pod| {
+                    let permits = &permits;
+                    async move {
+                        //The semaphore is never closed
+                        let _permit = permits.acquire().await;
+                        (pod, self.shutdown_pod(pod).await)
+                    }
+                })
+                .collect::<FuturesUnordered<_>>();
+
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                match tokio::time::timeout_at(deadline, tasks.next()).await {
+                    Ok(Some((pod, outcome))) => {
+                        remaining.remove(&pod.id());
+                        summary.record(pod, outcome);
+                    },
+                    Ok(None) => break,
+                    Err(_) => {
+                        tracing::warn!(
+                            "{} pods did not stop within the shutdown timeout of {} seconds",
+                            remaining.len(),
+                            timeout.as_secs(),
+                        );
+                        break
+                    }
+                }
             }
+
+            //Dropping the tasks cancels pods still stopping, releasing their state locks
         }
 
-        errors
+        let remaining = pods.iter().filter(|pod| remaining.contains(&pod.id())).collect::<Vec<_>>();
+        let forced = futures::future::join_all(remaining.iter().map(|pod| async move {
+            tracing::warn!("Removing container of pod {} forcefully as the daemon is shutting down", pod.id());
+            let outcome = match tokio::time::timeout(Self::FORCE_REMOVE_TIMEOUT, self.force_disable(pod)).await {
+                Ok(Ok(())) => PodShutdownOutcome::Forced,
+                Ok(Err(e)) => PodShutdownOutcome::Failed(e.to_string()),
+                Err(_) => PodShutdownOutcome::Failed(String::from("Docker did not respond to forced removal")),
+            };
+
+            (*pod, outcome)
+        }));
+
+        for (pod, outcome) in forced.await {
+            summary.record(pod, outcome);
+        }
+
+        summary
+    }
+
+    /// Disable a pod when the daemon is shutting down, removing its container forcefully if it
+    /// does not stop within its stop timeout. The pod's directive is kept so that it can be
+    /// restored when the daemon starts
+    async fn shutdown_pod(&self, pod: &Arc<Pod>) -> PodShutdownOutcome {
+        let timeout = Duration::from_secs(pod.config().docker.stop_timeout_seconds.into()) + Self::SHUTDOWN_MARGIN;
+        let mut lock = pod.state().transact().await;
+        pod.cancel_restart();
+        let result = tokio::time::timeout(timeout, self.disable_container(pod, &mut lock)).await;
+        //Forced removal takes the state lock again
+        drop(lock);
+
+        match result {
+            Ok(Ok(())) => PodShutdownOutcome::Stopped,
+            Ok(Err(e)) => PodShutdownOutcome::Failed(e.to_string()),
+            Err(_) => {
+                tracing::warn!(
+                    "Pod {} did not stop within {} seconds, removing its container forcefully",
+                    pod.id(),
+                    timeout.as_secs(),
+                );
+
+                match self.force_disable(pod).await {
+                    Ok(()) => PodShutdownOutcome::Forced,
+                    Err(e) => PodShutdownOutcome::Failed(e.to_string()),
+                }
+            }
+        }
     }
 
     /// Top-level operation to disable the given pod.
     /// Gracefully, then forcefully stops and removes the Docker container as required.
+    /// Cancels any restart of the pod waiting to be made by its restart policy.
     pub async fn disable(&self, pod: Arc<Pod>, mut lock: PodStateWriteHandle<'_>) -> Result<(), PodDisableError> {
+        pod.cancel_restart();
+        self.disable_container(&pod, &mut lock).await?;
+        self.set_directive(&pod, PodDirective::Disabled);
+        Ok(())
+    }
+
+    /// Stop and remove the container of the given pod, leaving it disabled without cancelling a
+    /// pending restart
+    pub(super) async fn disable_container(&self, pod: &Pod, lock: &mut PodStateWriteHandle<'_>) -> Result<(), PodDisableError> {
         let docker_id = match lock.state() {
             PodStateKnown::Disabled => return Ok(()),
             PodStateKnown::Paused(ref paused) => paused.docker_id.clone(),
             PodStateKnown::Enabled(ref running) => {
-                self.stop_container(&pod, &running.docker_id, pod.config().docker.stop_timeout)
+                self.stop_container(pod, &running.docker_id, pod.config().docker.stop_timeout_seconds)
                     .await?;
                 running.docker_id.clone()
             }
         };
 
-        if let Err(e) = self.destroy_container(&pod, &docker_id, false).await {
+        if let Err(e) = self.destroy_container(pod, &docker_id, false).await {
             tracing::error!(
                 "Failed to destroy container {} for {}, attempting forcefully: {}",
                 docker_id,
                 pod.id(),
                 e
             );
-            if let Err(e) = self.destroy_container(&pod, &docker_id, true).await {
+            if let Err(e) = self.destroy_container(pod, &docker_id, true).await {
                 tracing::error!(
                     "Failed to destroy container for {} forcefully: {}",
                     pod.id(),
@@ -56,10 +184,36 @@ impl PodManager {
             }
         }
 
-        lock.set(PodStateKnown::Disabled);
+        Self::set_disabled(pod, lock);
         Ok(())
     }
 
+    /// Forcefully remove the container of the given pod without waiting for it to stop
+    async fn force_disable(&self, pod: &Pod) -> Result<(), PodDisableError> {
+        let mut lock = pod.state().transact().await;
+        let docker_id = match lock.state() {
+            PodStateKnown::Disabled => return Ok(()),
+            PodStateKnown::Paused(ref paused) => paused.docker_id.clone(),
+            PodStateKnown::Enabled(ref running) => running.docker_id.clone(),
+        };
+
+        self.destroy_container(pod, &docker_id, true).await?;
+        Self::set_disabled(pod, &mut lock);
+        Ok(())
+    }
+
+    /// Record that the container of the given pod has been removed.
+    /// The stop time is recorded first so that it is sent with the state notification
+    fn set_disabled(pod: &Pod, lock: &mut PodStateWriteHandle<'_>) {
+        pod.set_stopped_at(Utc::now());
+        lock.set(PodStateKnown::Disabled);
+        pod.set_network(None);
+
+        for rule in pod.reset_alerts() {
+            tracing::info!("Alert for pod {} on {} cleared as the pod was disabled", pod.id(), rule);
+        }
+    }
+
     async fn stop_container(&self, pod: &Pod, container: &DockerId, t: u32) -> Result<(), PodDisableError> {
         tracing::trace!("Beginning graceful shutdown of container {} for {}", container, pod.id());
         self.docker
@@ -1,44 +1,69 @@
 use std::{collections::HashMap, sync::Arc};
 
 use bollard::secret::PortBinding;
+use chrono::Utc;
 
-use crate::{pod::{config::PodDockerConfig, id::{DeimosId, DockerId}, state::{PodEnable, PodStateWriteHandle}, Pod, PodManager, PodStateKnown}, server::upnp::UpnpLeaseData};
+use super::{containers::{container_labels, container_name}, failure::PodFailureKind};
+use crate::{pod::{annotate::PodAnnotationListener, directive::PodDirective, config::{PodDockerConfig, PodPullPolicy}, env::{docker_env, PodEnvValueError}, id::{DeimosId, DockerId}, image::{PodBlock, PodImagePullError}, state::{PodEnable, PodStateWriteHandle}, Pod, PodManager, PodStateCause, PodStateKnown}, server::upnp::{UpnpError, UpnpLease, UpnpLeaseData, UpnpLeaseOwner}};
 
 impl PodManager {
     /// Top-level operation to enable the given pod.
     /// Creates and starts Docker container as required based on the current state of the pod.
     /// If the pod is already enabled, this is a no-op.
+    /// Archived pods and pods whose image has not been pulled cannot be enabled, unless the pod's
+    /// config allows the image to be pulled.
     pub async fn enable(&self, pod: Arc<Pod>, mut lock: PodStateWriteHandle<'_>) -> Result<(), PodEnableError> {
-        let leases = pod
-            .config()
-            .docker
-            .port
-            .iter()
-            .filter(|port| port.upnp)
-            .map(|port| 
-                UpnpLeaseData {
-                    name: format!("deimos.{}", <DeimosId as std::borrow::Borrow<str>>::borrow(&pod.id())),
-                    port: port.expose,
-                    protocol: port.protocol.into()
-                }
-            )
-            .collect::<Vec<_>>();
+        if pod.archived() {
+            return Err(PodEnableError::Archived)
+        }
 
-        let (upnp_lease, docker_id) = match lock.state() {
-            PodStateKnown::Enabled(..) => return Ok(()),
+        if matches!(lock.state(), PodStateKnown::Disabled) {
+            pod.apply_pending_config();
+        }
+
+        //The image may have been pulled since the pod was blocked, and is pulled again before
+        //each container is created with the always pull policy
+        let pull = pod.config().docker.pull_policy == PodPullPolicy::Always && matches!(lock.state(), PodStateKnown::Disabled);
+        if pod.blocked().is_some() || pull {
+            self.prepare_image(&pod).await?;
+        }
+
+        //Conflicts forwarded with UPnP already fail loading, but the container must never be
+        //started with them regardless of how it was loaded
+        self.check_pod_ports(&pod)?;
+        if matches!(lock.state(), PodStateKnown::Disabled) {
+            self.check_host_ports(&pod)?;
+        }
+
+        let (upnp_lease, annotations, docker_id, started) = match lock.state() {
+            PodStateKnown::Enabled(..) => {
+                self.set_directive(&pod, PodDirective::Enabled);
+                return Ok(())
+            },
             PodStateKnown::Paused(ref paused) => {
-                let leases = self.upnp.request(leases).await?;
                 self.resume_container(&pod, &paused.docker_id).await?;
-                (leases, paused.docker_id.clone())
+                (paused.upnp_lease.clone(), paused.annotations.clone(), paused.docker_id.clone(), paused.started)
             },
             PodStateKnown::Disabled => {
-                let leases = self.upnp.request(leases).await?;
-                let container = self.create_container(pod.clone()).await?;
+                let leases = self.request_leases(&pod).await?;
+                //The socket's directory must exist before the container is created for it to be
+                //mounted
+                let annotations = self.listen_annotations(&pod);
+                self.lint_image_volumes(&pod);
+                let container = match self.create_container(pod.clone()).await {
+                    //The image may have been removed since it was last checked
+                    Err(PodEnableError::ImageMissing(..)) if pod.config().docker.pulls_missing() => {
+                        self.prepare_image(&pod).await?;
+                        self.create_container(pod.clone()).await?
+                    },
+                    result => result?,
+                };
                 if let Err(e) = self.start_container(&pod, &container).await {
                     tracing::warn!(
                         "Container for pod {} failed to start, destroying it",
                         pod.id()
                     );
+                    self.record_failure(&pod, &container, PodFailureKind::StartFailed, format!("Container failed to start: {}", e)).await;
                     if let Err(e) = self.destroy_container(&pod, &container, true).await {
                         tracing::error!("Failsafe destroy failed for pod {}: {}", pod.id(), e);
                     }
@@ -46,28 +71,91 @@ impl PodManager {
                     return Err(e);
                 }
 
-                (leases, container)
+                (leases, annotations, container, Utc::now())
             }
         };
 
-        lock.set(PodStateKnown::Enabled(PodEnable { docker_id, upnp_lease }));
+        let network = self.inspect_network(&pod, &docker_id).await;
+        pod.set_network(Some(Arc::new(network)));
+        pod.set_failure(None);
+
+        let created = matches!(lock.state(), PodStateKnown::Disabled);
+        lock.set(PodStateKnown::Enabled(PodEnable { docker_id, upnp_lease, annotations, started, restarted: None, degraded: false }));
 
+        //Resuming a paused container does not restart the application inside of it
+        if created {
+            let _ = self.starts.send((pod.id(), started));
+        }
+
+        //Only an explicit enable resets restarts stopped by the restart policy
+        if pod.cause() == PodStateCause::Requested {
+            pod.close_restart_breaker();
+        }
+
+        self.set_directive(&pod, PodDirective::Enabled);
         Ok(())
     }
     
+    /// Request UPnP leases for the ports of the pod that are forwarded with UPnP
+    pub(super) async fn request_leases(&self, pod: &Pod) -> Result<UpnpLease, UpnpError> {
+        let leases = pod
+            .config()
+            .docker
+            .port
+            .iter()
+            .filter(|port| port.upnp)
+            .map(|port| 
+                UpnpLeaseData {
+                    name: format!("deimos.{}", <DeimosId as std::borrow::Borrow<str>>::borrow(&pod.id())),
+                    port: port.external_port(),
+                    host_port: port.host_port(),
+                    protocol: port.protocol.into()
+                }
+            )
+            .collect::<Vec<_>>();
+
+        self.upnp.request(UpnpLeaseOwner::Pod(pod.id()), leases, Some(self.config.max_total_forwarded_ports)).await
+    }
+
+    /// Check that the pod's image is present, pulling it if the pod's config allows it
+    async fn prepare_image(&self, pod: &Pod) -> Result<(), PodEnableError> {
+        match self.check_or_pull_image(pod).await {
+            Ok(Some(PodBlock::ImageMissing)) => Err(PodEnableError::ImageMissing(pod.config().docker.image.clone())),
+            Ok(None) => Ok(()),
+            Err(PodImagePullError::Pull { image, err }) => Err(PodEnableError::ImagePull { image, err }),
+            Err(e) => {
+                tracing::warn!("Failed to check image of pod {}: {}", pod.id(), e);
+                Ok(())
+            },
+        }
+    }
+
     async fn create_container(&self, pod: Arc<Pod>) -> Result<DockerId, PodEnableError> {
-        let config = docker_config(&pod.config().docker);
+        let env = pod.env_overrides();
+        let vars = docker_env(&pod.dir, &pod.config().docker, &env).await?;
+        let mut config = docker_config(&pod.config().docker, vars, pod.config().annotations.then(|| pod.annotation_dir()).as_deref());
+        if let Some(ref mut host) = config.host_config {
+            host.storage_opt = self.storage_opt(pod.config().docker.disk_limit);
+        }
+        config.labels = Some(container_labels(&pod));
+
         let create_response = self
             .docker
             .create_container(
                 Some(bollard::container::CreateContainerOptions {
-                    name: pod.id().owned(),
+                    name: container_name(&pod),
                     platform: None,
                 }),
                 config,
             )
             .await
-            .map_err(PodEnableError::CreateContainer)?;
+            .map_err(|e| match e {
+                bollard::errors::Error::DockerResponseServerError { status_code: 404, .. } => {
+                    pod.set_blocked(Some(PodBlock::ImageMissing));
+                    PodEnableError::ImageMissing(pod.config().docker.image.clone())
+                },
+                e => PodEnableError::CreateContainer(e),
+            })?;
 
         for warn in create_response.warnings {
             tracing::warn!("creating container for pod {}: {}", pod.id(), warn);
@@ -75,6 +163,7 @@ impl PodManager {
 
         let docker_id = DockerId::from(create_response.id);
         tracing::trace!("Created container {} for {}", docker_id, pod.id());
+        pod.set_env_applied(env);
 
         self.reverse_lookup.insert(docker_id.clone(), pod);
 
@@ -104,8 +193,9 @@ impl PodManager {
 }
 
 /// Convert a [Pod](super::Pod)'s parsed [PodDockerConfig] to a type that can be used in the Docker
-/// API
-pub(super) fn docker_config(config: &PodDockerConfig) -> bollard::container::Config<String> {
+/// API with the given environment variable overrides, mounting the given annotation socket
+/// directory if annotations are enabled
+pub(super) fn docker_config(config: &PodDockerConfig, env: Option<Vec<String>>, annotations: Option<&std::path::Path>) -> bollard::container::Config<String> {
     let image = Some(config.image.clone());
 
     let exposed_ports = (!config.port.is_empty()).then(|| {
@@ -129,7 +219,7 @@ pub(super) fn docker_config(config: &PodDockerConfig) -> bollard::container::Con
                 let key = format!("{}/{}", conf.expose, conf.protocol.docker_name());
                 let value = Some(vec![
                     PortBinding {
-                        host_port: Some(conf.expose.to_string()),
+                        host_port: Some(conf.host_port().to_string()),
                         host_ip: None,
                     }
                 ]);
@@ -139,46 +229,54 @@ pub(super) fn docker_config(config: &PodDockerConfig) -> bollard::container::Con
             .collect()
     });
 
-    let env = (!config.env.is_empty()).then(|| {
-        config
-            .env
-            .iter()
-            .map(|var| format!("{}={}", var.key, var.value))
-            .collect()
-    });
-
-    let binds = (!config.volume.is_empty()).then(|| {
-        config
-            .volume
-            .iter()
-            .map(|volume| format!("{}:{}", volume.local.display(), volume.container.display()))
-            .collect()
-    });
+    let binds = config
+        .volume
+        .iter()
+        .map(|volume| format!("{}:{}", volume.local.display(), volume.container.display()))
+        .chain(annotations.map(|dir| format!("{}:{}", dir.display(), PodAnnotationListener::CONTAINER_DIR)))
+        .collect::<Vec<_>>();
+    let binds = (!binds.is_empty()).then_some(binds);
 
     let cap_add = (!config.cap_add.is_empty()).then_some(config.cap_add.clone());
 
-    let host_config = Some(bollard::models::HostConfig {
+    let mut host_config = bollard::models::HostConfig {
         binds,
         port_bindings,
         cap_add,
         ..Default::default()
-    });
+    };
+    config.apply_resources(&mut host_config);
 
     bollard::container::Config {
         image,
         exposed_ports,
         env,
-        host_config,
+        entrypoint: config.entrypoint.clone(),
+        cmd: config.command.clone(),
+        working_dir: config.working_dir.clone(),
+        host_config: Some(host_config),
         ..Default::default()
     }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum PodEnableError {
+    #[error("Pod is archived and must be unarchived before it can be enabled")]
+    Archived,
+    #[error("Image '{0}' is not present locally and must be pulled before the pod can be enabled")]
+    ImageMissing(String),
+    #[error("Failed to pull image '{image}': {err}")]
+    ImagePull { image: String, #[source] err: bollard::errors::Error },
     #[error("Failed to create Docker container: {0}")]
     CreateContainer(#[source] bollard::errors::Error),
     #[error("Failed to start Docker container: {0}")]
     StartContainer(#[source] bollard::errors::Error),
     #[error("Failed to acquire UPnP lease: {0}")]
     Upnp(#[from] crate::server::upnp::UpnpError),
+    #[error("{0}")]
+    PortConflict(#[from] crate::pod::reserved::PodPortConflict),
+    #[error("{0}")]
+    HostPortConflict(#[from] crate::pod::reserved::PodHostPortConflict),
+    #[error("{0}")]
+    Env(#[from] PodEnvValueError),
 }
@@ -1,9 +1,11 @@
-use std::{collections::HashMap, sync::Arc, task::Poll};
+use std::{collections::HashMap, sync::Arc, task::Poll, time::Duration};
 
 use bollard::{secret::EventMessageTypeEnum, system::EventsOptions, Docker};
+use chrono::{DateTime, Utc};
 use futures::{stream::BoxStream, Stream, StreamExt};
 
-use crate::pod::{Pod, PodManager, PodStateKnown, ReversePodLookup};
+use super::{debounce::{DockerEventBurst, DockerEventDebouncer}, failure::{exit_reason, PodFailureKind}, record::{DockerEventRecordKind, DockerEventRecorder}};
+use crate::pod::{state::{PodEnable, PodPaused}, Pod, PodManager, PodStateKnown, ReversePodLookup};
 
 /// A stream that maps events received from the local Docker server to their corresponding pods.
 /// Also handles resubscribing to the docker stream in case it is dropped for some reason.
@@ -11,65 +13,204 @@ pub struct DockerEventStream {
     inner: BoxStream<'static, Result<bollard::secret::EventMessage, bollard::errors::Error>>,
     docker: Docker,
     reverse: ReversePodLookup,
+    recorder: DockerEventRecorder,
+}
+
+/// An event received from Docker for the container of a pod
+pub struct DockerPodEvent {
+    pub pod: Arc<Pod>,
+    /// ID of the container that the event was received for
+    pub container: String,
+    pub action: String,
+    /// Time that Docker emitted the event at
+    pub at: DateTime<Utc>,
+}
+
+/// The parts of a pod's state that determine how an event received for its container is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PodEventState {
+    Disabled,
+    Paused,
+    Enabled {
+        /// Time that the container was last restarted in place
+        restarted: Option<DateTime<Utc>>,
+    },
+}
+
+/// Action taken by the pod manager in response to an event received for a pod's container
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PodEventResponse {
+    /// The event does not change the pod's state
+    Ignore,
+    /// The event was emitted before the container was restarted in place, and is ignored
+    BeforeRestart,
+    /// An enabled container was paused outside of Deimos, and the pod is marked paused
+    Paused,
+    /// A paused container was resumed outside of Deimos, and the pod is marked enabled. Recordings
+    /// made before this was renamed call it `repause`
+    #[serde(alias = "repause")]
+    Resumed,
+    /// An enabled container was killed outside of Deimos, and the pod is disabled
+    Killed,
+    /// An enabled container was stopped outside of Deimos, and the pod is enabled again
+    Reenable,
+    /// The container ran out of memory, and the pod is disabled and restarted if it was enabled
+    OutOfMemory,
+    /// The container exited, and the pod is disabled and restarted if it was enabled
+    Died,
 }
 
 impl PodManager {
     /// Process all Docker container events in a loop to monitor uncommanded pod state changes
-    pub fn eventloop(&self) -> impl Stream<Item = (Arc<Pod>, String)> {
-        DockerEventStream::new(self.docker.clone(), self.reverse_lookup.clone())
+    pub fn eventloop(&self) -> impl Stream<Item = DockerPodEvent> {
+        DockerEventStream::new(self.docker.clone(), self.reverse_lookup.clone(), self.events.clone())
+    }
+
+    /// Create a debouncer for the events of the [eventloop](Self::eventloop) stream using the
+    /// window and cooldown given in the config
+    pub fn event_debouncer(&self) -> DockerEventDebouncer {
+        DockerEventDebouncer::new(
+            Duration::from_millis(self.config.event_debounce_ms),
+            Duration::from_secs(self.config.event_cooldown_seconds),
+        )
     }
     
-    /// Handle an event received from the [eventloop](Self::eventloop) stream
-    pub async fn handle_event(&self, pod: Arc<Pod>, action: String) {
-        tracing::trace!("Pod {} got event '{}'", pod.id(), action);
+    /// Handle a burst of events received for a pod's container as a single event, returning
+    /// `true` if a corrective action was taken for the pod
+    pub async fn handle_events(&self, burst: DockerEventBurst) -> bool {
+        let DockerEventBurst { pod, container, events, deferred, .. } = burst;
+        tracing::trace!("Pod {} got {} events for container {}: {:?}", pod.id(), events.len(), container, events);
+
         let lock = pod.state().read().await;
+        let state = PodEventState::from(&*lock);
+        let (response, action, at) = PodEventResponse::coalesce(state, events.iter());
+        let action = action.to_owned();
+        pod.count_burst(events.len(), deferred && response.corrective());
 
-        match action.as_str() {
-            "unpause" => if let PodStateKnown::Paused(ref paused) = *lock {
-                tracing::warn!("Paused pod {} got unpause event unexpectedly", pod.id());
-                match self.docker.pause_container(&paused.docker_id).await {
-                    Ok(..) => {},
-                    Err(e) => {
-                        tracing::warn!("Failed to re-pause container {} after unexpected resume: {}", pod.id(), e);
-                        let lock = pod.state().upgrade(lock);
-                        let _ = self.disable(pod.clone(), lock).await;
-                    }
-                }
+        match (response, &*lock) {
+            (PodEventResponse::BeforeRestart, _) => {
+                tracing::trace!("Ignoring event '{}' for pod {} from before its container was restarted", action, pod.id());
+            },
+            (PodEventResponse::Paused, PodStateKnown::Enabled(..)) | (PodEventResponse::Resumed, PodStateKnown::Paused(..)) => {
+                let lock = pod.state().upgrade(lock);
+                self.reconcile_pause(&pod, lock).await;
             },
-            "kill" => if let PodStateKnown::Enabled(..) = *lock {
+            (PodEventResponse::Killed, PodStateKnown::Enabled(enabled)) => {
                 tracing::warn!("Enabled pod {} got kill event unexpectedly", pod.id());
+                self.record_failure(&pod, &enabled.docker_id, PodFailureKind::Killed, String::from("Container was killed outside of Deimos")).await;
                 let lock = pod.state().upgrade(lock);
                 let _ = self.disable(pod.clone(), lock).await;
             },
-            "stop" => if let PodStateKnown::Enabled(..) = *lock {
+            (PodEventResponse::Reenable, _) => {
                 tracing::warn!("Enabled pod {} got stop request unexpectedly", pod.id());
                 let lock = pod.state().upgrade(lock);
                 let _ = self.enable(pod.clone(), lock).await;
             },
-            "oom" => if let PodStateKnown::Paused(..) | PodStateKnown::Enabled(..) = *lock {
+            (PodEventResponse::OutOfMemory, PodStateKnown::Paused(PodPaused { docker_id, .. }) | PodStateKnown::Enabled(PodEnable { docker_id, .. })) => {
                 tracing::warn!("Running pod {} got OOM", pod.id());
+                self.record_failure(&pod, docker_id, PodFailureKind::OutOfMemory, String::from("Container ran out of memory")).await;
+                let uptime = match *lock {
+                    PodStateKnown::Enabled(ref enabled) => Some(Utc::now() - enabled.started),
+                    _ => None,
+                };
+
+                let mut lock = pod.state().upgrade(lock);
+                let _ = self.disable_container(&pod, &mut lock).await;
+                if let Some(uptime) = uptime {
+                    self.restart_after_death(pod.clone(), lock, None, uptime).await;
+                }
+            },
+            (PodEventResponse::Died, PodStateKnown::Paused(paused)) => {
+                tracing::info!("Paused container {} died unexpectedly", pod.id());
+                let code = self.exit_code(&paused.docker_id).await;
+                self.record_failure(&pod, &paused.docker_id, PodFailureKind::Exited(code), exit_reason(code)).await;
                 let lock = pod.state().upgrade(lock);
                 let _ = self.disable(pod.clone(), lock).await;
             },
-            "die" => match *lock {
-                PodStateKnown::Disabled => {
+            (PodEventResponse::Died, PodStateKnown::Enabled(enabled)) => {
+                tracing::warn!("Running container {} died unexpectedly", pod.id());
+                let code = self.exit_code(&enabled.docker_id).await;
+                self.record_failure(&pod, &enabled.docker_id, PodFailureKind::Exited(code), exit_reason(code)).await;
+                let uptime = Utc::now() - enabled.started;
 
-                },
-                PodStateKnown::Paused(..) => {
-                    tracing::info!("Paused container {} died unexpectedly", pod.id());
-                    let lock = pod.state().upgrade(lock);
-                    let _ = self.disable(pod.clone(), lock).await;
-                },
-                PodStateKnown::Enabled(..) => {
-                    tracing::warn!("Running container {} died unexpectedly", pod.id());
-                    let lock = pod.state().upgrade(lock);
-                    let _ = self.disable(pod.clone(), lock).await;
-                }
+                //The pending restart is only replaced while the write handle is held, so a
+                //disable requested after the container died is never missed
+                let mut lock = pod.state().upgrade(lock);
+                let _ = self.disable_container(&pod, &mut lock).await;
+                self.restart_after_death(pod.clone(), lock, code, uptime).await;
             },
             _ => {},
         }
+
+        self.events.record(|| DockerEventRecordKind::Handled {
+            pod: pod.id().to_string(),
+            action,
+            at,
+            state,
+            response,
+            after: pod.state().current(),
+        });
+
+        response.corrective()
+    }
+}
+
+impl PodEventResponse {
+    /// Choose the response to an event with the given action received at the given time, based
+    /// only on the state of the pod when the event is handled
+    pub fn decide(state: PodEventState, action: &str, at: DateTime<Utc>) -> Self {
+        //Restarting a container in place stops it before it is started again
+        if let PodEventState::Enabled { restarted: Some(restarted) } = state {
+            if at < restarted && matches!(action, "kill" | "stop" | "oom" | "die") {
+                return Self::BeforeRestart
+            }
+        }
+
+        match (action, state) {
+            ("pause", PodEventState::Enabled { .. }) => Self::Paused,
+            ("unpause", PodEventState::Paused) => Self::Resumed,
+            ("kill", PodEventState::Enabled { .. }) => Self::Killed,
+            ("stop", PodEventState::Enabled { .. }) => Self::Reenable,
+            ("oom", PodEventState::Paused | PodEventState::Enabled { .. }) => Self::OutOfMemory,
+            ("die", PodEventState::Paused | PodEventState::Enabled { .. }) => Self::Died,
+            _ => Self::Ignore,
+        }
     }
+}
 
+impl PodEventResponse {
+    /// Choose the response to a burst of events as if they were handled one after another. Only
+    /// the first event that leads to a corrective action is acted on, as the action changes the
+    /// pod's state. Returns the response with the event it was chosen for, which is the last
+    /// event if none lead to a corrective action
+    pub fn coalesce<'a>(state: PodEventState, events: impl IntoIterator<Item = &'a (String, DateTime<Utc>)>) -> (Self, &'a str, DateTime<Utc>) {
+        let mut chosen = (Self::Ignore, "", Utc::now());
+        for (action, at) in events {
+            chosen = (Self::decide(state, action, *at), action.as_str(), *at);
+            if chosen.0.corrective() {
+                break
+            }
+        }
+
+        chosen
+    }
+
+    /// Check if the pod manager changes the pod's state or container in response
+    pub const fn corrective(&self) -> bool {
+        !matches!(self, Self::Ignore | Self::BeforeRestart)
+    }
+}
+
+impl From<&PodStateKnown> for PodEventState {
+    fn from(value: &PodStateKnown) -> Self {
+        match value {
+            PodStateKnown::Disabled => Self::Disabled,
+            PodStateKnown::Paused(..) => Self::Paused,
+            PodStateKnown::Enabled(enabled) => Self::Enabled { restarted: enabled.restarted },
+        }
+    }
 }
 
 impl DockerEventStream {
@@ -89,47 +230,66 @@ impl DockerEventStream {
     }
     
     /// Create a new stream that will subscribe to container events from the given Docker instance,
-    /// and map them to local pods using the provided reverse lookup table
-    pub fn new(docker: Docker, reverse: ReversePodLookup) -> Self {
+    /// and map them to local pods using the provided reverse lookup table. Received events are
+    /// passed to the given recorder along with the pod they were mapped to
+    pub fn new(docker: Docker, reverse: ReversePodLookup, recorder: DockerEventRecorder) -> Self {
         Self {
             inner: Self::subscribe(&docker),
             docker,
             reverse,
+            recorder,
         }
     }
 }
 
 impl Stream for DockerEventStream {
-    type Item = (Arc<Pod>, String);
+    type Item = DockerPodEvent;
 
     fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
             let next = futures::ready!(self.inner.as_mut().poll_next(cx));
             match next {
                 Some(event) => match event {
-                    Ok(ev) => match ev.typ {
-                        Some(EventMessageTypeEnum::CONTAINER) => {
-                            let Some(actor) = ev.actor else {
-                                tracing::warn!("Received container event with no actor");
-                                continue;
-                            };
-
-                            let Some(id) = actor.id else {
-                                tracing::warn!("Received container event with no actor ID");
-                                continue;
-                            };
-
-                            let Some(action) = ev.action else {
-                                tracing::warn!("Received container event with no action");
-                                continue;
-                            };
-
-                            if let Some(pod) = self.reverse.get(id.as_str()) {
-                                break Poll::Ready(Some((pod.clone(), action)))
+                    Ok(ev) => {
+                        self.recorder.record(|| DockerEventRecordKind::Event {
+                            pod: ev
+                                .actor
+                                .as_ref()
+                                .and_then(|actor| actor.id.as_deref())
+                                .and_then(|id| self.reverse.get(id))
+                                .map(|pod| pod.id().to_string()),
+                            event: ev.clone(),
+                        });
+
+                        match ev.typ {
+                            Some(EventMessageTypeEnum::CONTAINER) => {
+                                let Some(actor) = ev.actor else {
+                                    tracing::warn!("Received container event with no actor");
+                                    continue;
+                                };
+
+                                let Some(id) = actor.id else {
+                                    tracing::warn!("Received container event with no actor ID");
+                                    continue;
+                                };
+
+                                let Some(action) = ev.action else {
+                                    tracing::warn!("Received container event with no action");
+                                    continue;
+                                };
+
+                                let at = ev
+                                    .time_nano
+                                    .map(DateTime::from_timestamp_nanos)
+                                    .unwrap_or_else(Utc::now);
+
+                                if let Some(pod) = self.reverse.get(id.as_str()) {
+                                    break Poll::Ready(Some(DockerPodEvent { pod: pod.clone(), container: id, action, at }))
+                                }
+                            },
+                            _ => {
+                                tracing::warn!("Got unwanted Docker event {:?}", ev.typ);
                             }
-                        },
-                        _ => {
-                            tracing::warn!("Got unwanted Docker event {:?}", ev.typ);
                         }
                     },
                     Err(e) => {
@@ -143,3 +303,87 @@ impl Stream for DockerEventStream {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeDelta;
+
+    use crate::{pod::id::DockerId, server::upnp::{UpnpLease, UpnpLeaseOwner}};
+
+    use super::*;
+
+    const ACTIONS: [&str; 7] = ["pause", "unpause", "kill", "stop", "oom", "die", "start"];
+
+    #[test]
+    fn decide_matrix() {
+        use PodEventResponse::*;
+
+        let at = Utc::now();
+        let enabled = PodEventState::Enabled { restarted: None };
+        let expected = [
+            (PodEventState::Disabled, [Ignore, Ignore, Ignore, Ignore, Ignore, Ignore, Ignore]),
+            (PodEventState::Paused, [Ignore, Resumed, Ignore, Ignore, OutOfMemory, Died, Ignore]),
+            (enabled, [Paused, Ignore, Killed, Reenable, OutOfMemory, Died, Ignore]),
+        ];
+
+        for (state, responses) in expected {
+            for (action, response) in ACTIONS.into_iter().zip(responses) {
+                assert_eq!(PodEventResponse::decide(state, action, at), response, "'{}' while {:?}", action, state);
+            }
+        }
+    }
+
+    #[test]
+    fn events_before_restart_are_ignored() {
+        use PodEventResponse::*;
+
+        let restarted = Utc::now();
+        let state = PodEventState::Enabled { restarted: Some(restarted) };
+        let before = restarted - TimeDelta::seconds(1);
+        let after = restarted + TimeDelta::seconds(1);
+
+        let expected = [Paused, Ignore, BeforeRestart, BeforeRestart, BeforeRestart, BeforeRestart, Ignore];
+        for (action, response) in ACTIONS.into_iter().zip(expected) {
+            assert_eq!(PodEventResponse::decide(state, action, before), response, "'{}' before restart", action);
+        }
+
+        assert_eq!(PodEventResponse::decide(state, "die", after), Died);
+        assert_eq!(PodEventResponse::decide(state, "stop", after), Reenable);
+    }
+
+    #[test]
+    fn coalesce_acts_on_first_corrective_event() {
+        let at = Utc::now();
+        let events = [
+            (String::from("start"), at),
+            (String::from("kill"), at + TimeDelta::seconds(1)),
+            (String::from("die"), at + TimeDelta::seconds(2)),
+        ];
+
+        let state = PodEventState::Enabled { restarted: None };
+        assert_eq!(PodEventResponse::coalesce(state, events.iter()), (PodEventResponse::Killed, "kill", at + TimeDelta::seconds(1)));
+        assert_eq!(PodEventResponse::coalesce(PodEventState::Disabled, events.iter()), (PodEventResponse::Ignore, "die", at + TimeDelta::seconds(2)));
+        assert_eq!(PodEventResponse::coalesce(PodEventState::Paused, events.iter()), (PodEventResponse::Died, "die", at + TimeDelta::seconds(2)));
+    }
+
+    #[tokio::test]
+    async fn event_state_from_pod_state() {
+        let restarted = Some(Utc::now());
+        let started = Utc::now();
+        let docker_id = DockerId::from(String::from("container"));
+        let upnp_lease = UpnpLease::test(UpnpLeaseOwner::Api);
+
+        let paused = PodStateKnown::Paused(PodPaused { docker_id: docker_id.clone(), upnp_lease: upnp_lease.clone(), annotations: None, started });
+        let enabled = PodStateKnown::Enabled(PodEnable { docker_id, upnp_lease, annotations: None, started, restarted, degraded: false });
+
+        assert_eq!(PodEventState::from(&PodStateKnown::Disabled), PodEventState::Disabled);
+        assert_eq!(PodEventState::from(&paused), PodEventState::Paused);
+        assert_eq!(PodEventState::from(&enabled), PodEventState::Enabled { restarted });
+    }
+
+    #[test]
+    fn recorded_repause_loads_as_resumed() {
+        assert_eq!(serde_json::from_str::<PodEventResponse>("\"repause\"").unwrap(), PodEventResponse::Resumed);
+        assert_eq!(serde_json::to_string(&PodEventResponse::Resumed).unwrap(), "\"resumed\"");
+    }
+}
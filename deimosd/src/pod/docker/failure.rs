@@ -0,0 +1,156 @@
+//! Output captured from a pod's container when it exits unexpectedly or fails to start. The
+//! container is removed when the pod is disabled, taking its logs with it, so the output must be
+//! captured before the container is destroyed
+
+use std::sync::{Arc, PoisonError};
+
+use bollard::container::LogsOptions;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+
+use crate::pod::{id::DockerId, Pod, PodManager};
+
+/// The most recent failure of a pod's container
+#[derive(Debug)]
+pub struct PodFailure {
+    pub at: DateTime<Utc>,
+    pub kind: PodFailureKind,
+    pub reason: String,
+    /// Last output written by the container, or `None` if it could not be read
+    pub output: Option<String>,
+}
+
+/// How a pod's container failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodFailureKind {
+    /// The container exited on its own, with its exit code if it could be inspected
+    Exited(Option<i64>),
+    /// The container was killed after running out of memory
+    OutOfMemory,
+    /// The container was killed outside of Deimos
+    Killed,
+    /// The container was created but could not be started
+    StartFailed,
+}
+
+impl Pod {
+    /// Get the most recent failure of this pod's container since it was last enabled
+    pub fn failure(&self) -> Option<Arc<PodFailure>> {
+        self.failure.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub(super) fn set_failure(&self, failure: Option<PodFailure>) {
+        *self.failure.lock().unwrap_or_else(PoisonError::into_inner) = failure.map(Arc::new);
+    }
+
+    /// Get the time that this pod's container was last stopped and removed, whether it was
+    /// disabled or failed
+    pub fn stopped_at(&self) -> Option<DateTime<Utc>> {
+        *self.stopped_at.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn set_stopped_at(&self, at: DateTime<Utc>) {
+        *self.stopped_at.lock().unwrap_or_else(PoisonError::into_inner) = Some(at);
+    }
+}
+
+impl PodManager {
+    /// Maximum size in bytes of the output kept from a failed container
+    const FAILURE_OUTPUT_LIMIT: usize = 4 * 1024;
+    /// Number of lines read from the end of a failed container's logs
+    const FAILURE_OUTPUT_LINES: usize = 100;
+
+    /// Record a failure of the given container for the given pod, capturing the container's most
+    /// recent output. Must be called before the container is destroyed
+    pub(super) async fn record_failure(&self, pod: &Pod, container: &DockerId, kind: PodFailureKind, reason: String) {
+        let output = match self.failure_output(container).await {
+            Ok(output) => Some(output),
+            Err(e) => {
+                tracing::warn!("Failed to capture output of failed container for pod {}: {}", pod.id(), e);
+                None
+            }
+        };
+
+        tracing::debug!("Captured failure of pod {}: {}", pod.id(), reason);
+        pod.set_failure(Some(PodFailure { at: Utc::now(), kind, reason, output }));
+    }
+
+    /// Get the exit code of the given container, or `None` if it cannot be inspected
+    pub(super) async fn exit_code(&self, container: &DockerId) -> Option<i64> {
+        match self.docker.inspect_container(container, None).await {
+            Ok(inspect) => inspect.state.and_then(|state| state.exit_code),
+            Err(e) => {
+                tracing::trace!("Failed to inspect exited container {}: {}", container, e);
+                None
+            }
+        }
+    }
+
+    /// Read the end of the given container's logs, bounded to the output limit
+    async fn failure_output(&self, container: &DockerId) -> Result<String, bollard::errors::Error> {
+        let mut stream = self.docker.logs(
+            container,
+            Some(
+                LogsOptions::<String> {
+                    stdout: true,
+                    stderr: true,
+                    follow: false,
+                    tail: Self::FAILURE_OUTPUT_LINES.to_string(),
+                    ..Default::default()
+                }
+            )
+        );
+
+        let mut output = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            output.extend_from_slice(&chunk?.into_bytes());
+
+            //Only the end of the output is kept, with room for escape sequences that are removed
+            if output.len() > Self::FAILURE_OUTPUT_LIMIT * 2 {
+                output.drain(..output.len() - Self::FAILURE_OUTPUT_LIMIT * 2);
+            }
+        }
+
+        Ok(sanitize_output(&output, Self::FAILURE_OUTPUT_LIMIT))
+    }
+}
+
+/// Get a description of why a container exited, including its exit code if it is known
+pub(super) fn exit_reason(code: Option<i64>) -> String {
+    match code {
+        Some(code) => format!("Container exited unexpectedly with code {}", code),
+        None => String::from("Container exited unexpectedly"),
+    }
+}
+
+/// Get at most `limit` bytes of printable text from the end of the given output, removing
+/// terminal escape sequences and control characters other than newlines and tabs
+fn sanitize_output(output: &[u8], limit: usize) -> String {
+    let text = String::from_utf8_lossy(output);
+    let mut clean = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            //Control sequences end at a character from '@' to '~', other escapes are followed
+            //by a single character
+            '\x1b' => match chars.next_if_eq(&'[') {
+                Some(..) => while chars.next().is_some_and(|c| !('@'..='~').contains(&c)) {},
+                None => {
+                    chars.next();
+                },
+            },
+            '\n' | '\t' => clean.push(c),
+            c if c.is_control() => (),
+            c => clean.push(c),
+        }
+    }
+
+    let trimmed = clean.trim_end();
+    let mut start = trimmed.len().saturating_sub(limit);
+    while !trimmed.is_char_boundary(start) {
+        start += 1;
+    }
+
+    trimmed[start..].to_owned()
+}
@@ -2,10 +2,24 @@ use std::{sync::Arc, task::Poll};
 
 use bollard::container::LogsOptions;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures::{stream::BoxStream, Stream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::pod::{id::DeimosId, Pod, PodManager, PodStateKnown};
 
+/// Selection of a container's output to stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PodLogOptions {
+    /// Number of lines to send from the end of the output already written, or all output if
+    /// `None`
+    pub tail_lines: Option<u32>,
+    /// Keep the stream open and send output as it is written
+    pub follow: bool,
+    /// Only send output written after the given time
+    pub since: Option<DateTime<Utc>>,
+}
+
 /// A streamer forwarding a Docker container's logs
 #[pin_project::pin_project]
 pub struct PodLogStream {
@@ -15,8 +29,9 @@ pub struct PodLogStream {
 }
 
 impl PodManager {
-    /// Subscribe to logs from the given pod
-    pub async fn subscribe_logs(&self, pod: Arc<Pod>) -> Result<PodLogStream, PodSubscribeLogsError> {
+    /// Subscribe to logs from the given pod, ending the stream once the selected output has been
+    /// sent unless the options request that it is followed
+    pub async fn subscribe_logs(&self, pod: Arc<Pod>, options: PodLogOptions) -> Result<PodLogStream, PodSubscribeLogsError> {
         let lock = pod.state().read().await;
         match *lock {
             PodStateKnown::Enabled(ref run) => Ok(
@@ -26,20 +41,80 @@ impl PodManager {
                         .docker
                         .logs(
                             &run.docker_id, 
-                            Some(
-                                LogsOptions::<&'static str> {
-                                    stdout: true,
-                                    stderr: true,
-                                    follow: true,
-                                    ..Default::default()
-                                }
-                            )
+                            Some(options.into())
                         )
                 )
             ),
             _ => Err(PodSubscribeLogsError::NotEnabled),
         }
     }
+
+    /// Write all logs written by the given pod's container in the given time range to `out`,
+    /// failing if more than `limit` bytes would be written. Returns the number of bytes written.
+    /// Each line is prefixed with the RFC 3339 timestamp it was written at.
+    pub async fn collect_logs<W: AsyncWrite + Unpin>(
+        &self,
+        pod: &Pod,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        limit: usize,
+        out: &mut W,
+    ) -> Result<usize, PodCollectLogsError> {
+        let docker_id = {
+            let lock = pod.state().read().await;
+            match *lock {
+                PodStateKnown::Enabled(ref run) => run.docker_id.clone(),
+                PodStateKnown::Paused(ref paused) => paused.docker_id.clone(),
+                PodStateKnown::Disabled => return Err(PodCollectLogsError::NoContainer),
+            }
+        };
+
+        let mut stream = self
+            .docker
+            .logs(
+                &docker_id,
+                Some(
+                    LogsOptions::<&'static str> {
+                        stdout: true,
+                        stderr: true,
+                        follow: false,
+                        timestamps: true,
+                        since: since.map(|t| t.timestamp()).unwrap_or_default(),
+                        until: until.map(|t| t.timestamp()).unwrap_or_default(),
+                        ..Default::default()
+                    }
+                )
+            );
+
+        let mut written = 0;
+        while let Some(output) = stream.next().await {
+            let bytes = output?.into_bytes();
+            if written + bytes.len() > limit {
+                return Err(PodCollectLogsError::LimitExceeded { limit })
+            }
+
+            out.write_all(&bytes).await?;
+            written += bytes.len();
+        }
+
+        Ok(written)
+    }
+}
+
+impl From<PodLogOptions> for LogsOptions<String> {
+    fn from(value: PodLogOptions) -> Self {
+        Self {
+            stdout: true,
+            stderr: true,
+            follow: value.follow,
+            since: value.since.map(|since| since.timestamp()).unwrap_or_default(),
+            tail: match value.tail_lines {
+                Some(lines) => lines.to_string(),
+                None => String::from("all"),
+            },
+            ..Default::default()
+        }
+    }
 }
 
 impl PodLogStream {
@@ -90,3 +165,17 @@ pub enum PodSubscribeLogsError {
     #[error("Container is not enabled")]
     NotEnabled,
 }
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodCollectLogsError {
+    #[error("Pod has no container to collect logs from")]
+    NoContainer,
+    #[error("Logs exceed the limit of {} bytes", limit)]
+    LimitExceeded {
+        limit: usize,
+    },
+    #[error("Docker API error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error("Failed to write logs: {0}")]
+    Write(#[from] std::io::Error),
+}
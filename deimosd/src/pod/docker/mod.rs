@@ -1,5 +1,14 @@
+mod adopt;
+pub mod attach;
+pub mod containers;
+pub mod debounce;
 mod disable;
 mod enable;
 mod pause;
+pub mod restart;
 pub mod events;
+pub mod failure;
 pub mod logs;
+pub mod network;
+pub mod record;
+pub mod stats;
@@ -0,0 +1,124 @@
+//! Addresses and published ports of an enabled pod's container, resolved by inspecting the
+//! container after it is started so that clients can be given a reachable endpoint
+
+use std::{net::IpAddr, sync::{Arc, PoisonError}};
+
+use bollard::{container::InspectContainerOptions, secret::NetworkSettings};
+
+use crate::pod::{config::PodDockerPortProtocol, id::DockerId, Pod, PodManager};
+
+/// Network state of a pod's running container
+#[derive(Debug, Clone, Default)]
+pub struct PodNetwork {
+    /// Address of the container on its Docker network
+    pub container_ip: Option<IpAddr>,
+    /// Published ports in the order they are given in the pod's config
+    pub ports: Vec<PodPublishedPort>,
+}
+
+/// A container port published on the host
+#[derive(Debug, Clone, Copy)]
+pub struct PodPublishedPort {
+    pub protocol: PodDockerPortProtocol,
+    /// Port exposed by the container
+    pub container_port: u16,
+    /// Port on the host that Docker bound the container port to
+    pub host_port: u16,
+    /// Port opened on the gateway that is forwarded to the host port if the port is forwarded with
+    /// UPnP
+    pub external_port: u16,
+    /// If the port is forwarded through the gateway with UPnP
+    pub upnp: bool,
+}
+
+/// Best-known address that a published port can be reached at
+#[derive(Debug, Clone, Copy)]
+pub struct PodEndpoint {
+    pub protocol: PodDockerPortProtocol,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Set if the address is the gateway's external address rather than the server's address
+    /// on the local network
+    pub external: bool,
+}
+
+impl Pod {
+    /// Get the network state of the pod's container if it is enabled
+    pub fn network(&self) -> Option<Arc<PodNetwork>> {
+        self.network.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Set or clear the network state when the pod's container is started or stopped
+    pub(in crate::pod) fn set_network(&self, network: Option<Arc<PodNetwork>>) {
+        *self.network.lock().unwrap_or_else(PoisonError::into_inner) = network;
+    }
+}
+
+impl PodManager {
+    /// Inspect the given container to resolve its address and the host ports that its ports were
+    /// published on. Ports that Docker does not report a binding for fall back to the port given
+    /// in the pod's config
+    pub(super) async fn inspect_network(&self, pod: &Pod, container: &DockerId) -> PodNetwork {
+        let settings = match self.docker.inspect_container(container, None::<InspectContainerOptions>).await {
+            Ok(response) => response.network_settings.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("Failed to inspect network of container {} for {}: {}", container, pod.id(), e);
+                NetworkSettings::default()
+            }
+        };
+
+        //The top-level address is only set for the default bridge network
+        let container_ip = settings
+            .ip_address
+            .iter()
+            .chain(settings.networks.iter().flat_map(|networks| networks.values()).filter_map(|network| network.ip_address.as_ref()))
+            .find_map(|ip| ip.parse::<IpAddr>().ok());
+
+        let ports = pod
+            .config()
+            .docker
+            .port
+            .iter()
+            .map(|conf| {
+                let key = format!("{}/{}", conf.expose, conf.protocol.docker_name());
+                let host_port = settings
+                    .ports
+                    .as_ref()
+                    .and_then(|ports| ports.get(&key))
+                    .and_then(Option::as_ref)
+                    .and_then(|bindings| bindings.iter().find_map(|binding| binding.host_port.as_deref()?.parse().ok()))
+                    .unwrap_or(conf.host_port());
+
+                PodPublishedPort {
+                    protocol: conf.protocol,
+                    container_port: conf.expose,
+                    host_port,
+                    external_port: conf.external_port(),
+                    upnp: conf.upnp,
+                }
+            })
+            .collect();
+
+        PodNetwork { container_ip, ports }
+    }
+
+    /// Get the best-known reachable address of each of the given pod's published ports, using the
+    /// gateway's external address for ports forwarded with UPnP and the server's local address
+    /// otherwise. Empty if the pod is not enabled
+    pub fn endpoints(&self, pod: &Pod) -> Vec<PodEndpoint> {
+        let Some(network) = pod.network() else {
+            return Vec::new()
+        };
+
+        network
+            .ports
+            .iter()
+            .map(|port| {
+                match port.upnp.then(|| self.upnp.external_ip(port.external_port, port.protocol.into())).flatten() {
+                    Some(address) => PodEndpoint { protocol: port.protocol, address, port: port.external_port, external: true },
+                    None => PodEndpoint { protocol: port.protocol, address: self.upnp.local_ip(), port: port.host_port, external: false },
+                }
+            })
+            .collect()
+    }
+}
@@ -1,14 +1,25 @@
+//! Pausing and resuming of pod containers. Only enabled pods may be paused, and paused pods keep
+//! their container, UPnP lease, and annotation socket so that enabling them again unpauses the
+//! container in place rather than creating a new one.
+//! Containers paused or resumed with the Docker CLI are reconciled into the pod's state once
+//! Docker confirms the container's new state
+
 use std::sync::Arc;
 
-use crate::pod::{state::{PodPaused, PodStateWriteHandle}, Pod, PodManager, PodStateKnown};
+use bollard::container::InspectContainerOptions;
+
+use crate::pod::{directive::PodDirective, id::DockerId, state::{PodEnable, PodPaused, PodStateWriteHandle}, Pod, PodManager, PodStateCause, PodStateKnown};
 
 impl PodManager {
-    /// Pause the given container if it is enabled and running, or no-op
+    /// Pause the given container if it is enabled and running, or no-op if it is already paused
     pub async fn pause(&self, pod: Arc<Pod>, mut lock: PodStateWriteHandle<'_>) -> Result<(), PausePodResult> {
-        match lock.state() {
+        match lock.state().clone() {
             PodStateKnown::Disabled => Err(PausePodResult::PodDisabled),
-            PodStateKnown::Paused(..) => Ok(()),
-            PodStateKnown::Enabled(ref run) => {
+            PodStateKnown::Paused(..) => {
+                self.set_directive(&pod, PodDirective::Paused);
+                Ok(())
+            },
+            PodStateKnown::Enabled(run) => {
                 self.docker
                     .pause_container(&run.docker_id)
                     .await
@@ -16,14 +27,63 @@ impl PodManager {
 
                 tracing::trace!("Paused container {} for {}", run.docker_id, pod.id());
 
-                lock.set(PodStateKnown::Paused(PodPaused {
-                    docker_id: run.docker_id.clone(),
-                }));
+                lock.set(PodStateKnown::Paused(PodPaused::from(run)));
+                pod.set_network(None);
+                self.set_directive(&pod, PodDirective::Paused);
 
                 Ok(())
             }
         }
     }
+
+    /// Update the state of a pod after its container received a pause or unpause event that the
+    /// pod's state does not account for, as when the container is paused with the Docker CLI.
+    /// The pod is left unchanged if Docker no longer reports the container in the state the event
+    /// implies, as when the event was handled after the pod was resumed or paused again
+    pub(super) async fn reconcile_pause(&self, pod: &Pod, mut lock: PodStateWriteHandle<'_>) {
+        match lock.state().clone() {
+            PodStateKnown::Enabled(run) if self.container_paused(&run.docker_id).await == Some(true) => {
+                tracing::warn!("Container {} of pod {} was paused outside of Deimos", run.docker_id, pod.id());
+                lock.set_cause(PodStateCause::Requested);
+                lock.set(PodStateKnown::Paused(PodPaused::from(run)));
+                pod.set_network(None);
+                self.set_directive(pod, PodDirective::Paused);
+            },
+            PodStateKnown::Paused(paused) if self.container_paused(&paused.docker_id).await == Some(false) => {
+                tracing::warn!("Container {} of pod {} was resumed outside of Deimos", paused.docker_id, pod.id());
+                let network = self.inspect_network(pod, &paused.docker_id).await;
+                pod.set_network(Some(Arc::new(network)));
+                lock.set_cause(PodStateCause::Requested);
+                lock.set(PodStateKnown::Enabled(PodEnable {
+                    docker_id: paused.docker_id,
+                    upnp_lease: paused.upnp_lease,
+                    annotations: paused.annotations,
+                    started: paused.started,
+                    restarted: None,
+                    degraded: false,
+                }));
+                self.set_directive(pod, PodDirective::Enabled);
+            },
+            _ => tracing::trace!("Container of pod {} is no longer in the state implied by its last pause event", pod.id()),
+        }
+    }
+
+    /// Check if Docker reports the given container as paused, or `None` if it cannot be inspected
+    async fn container_paused(&self, container: &DockerId) -> Option<bool> {
+        let inspect = self.docker.inspect_container(container, None::<InspectContainerOptions>).await.ok()?;
+        inspect.state?.paused
+    }
+}
+
+impl From<PodEnable> for PodPaused {
+    fn from(value: PodEnable) -> Self {
+        Self {
+            docker_id: value.docker_id,
+            upnp_lease: value.upnp_lease,
+            annotations: value.annotations,
+            started: value.started,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -33,3 +93,48 @@ pub enum PausePodResult {
     #[error("Pause API call failed: {0}")]
     Docker(#[source] bollard::errors::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeDelta, Utc};
+    use futures::StreamExt;
+
+    use crate::{pod::{id::DockerId, state::PodState}, server::upnp::{UpnpLease, UpnpLeaseOwner}};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn paused_keeps_start_time() {
+        let started = Utc::now() - TimeDelta::hours(1);
+        let paused = PodPaused::from(PodEnable {
+            docker_id: DockerId::from(String::from("container")),
+            upnp_lease: UpnpLease::test(UpnpLeaseOwner::Api),
+            annotations: None,
+            started,
+            restarted: Some(started),
+            degraded: true,
+        });
+
+        assert_eq!(paused.started, started);
+    }
+
+    #[tokio::test]
+    async fn cause_is_set_with_state() {
+        let (_dir, pod) = Pod::test("id = \"paused\"\nname = \"Paused\"\n[docker]\nimage = \"alpine:3\"\n").await;
+        let mut rx = pod.state().subscribe();
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        let mut lock = pod.state().transact().await;
+        lock.set_cause(PodStateCause::Requested);
+        lock.set(PodStateKnown::Paused(PodPaused {
+            docker_id: DockerId::from(String::from("container")),
+            upnp_lease: UpnpLease::test(UpnpLeaseOwner::Pod(pod.id())),
+            annotations: None,
+            started: Utc::now(),
+        }));
+        drop(lock);
+
+        assert_eq!(rx.next().await, Some(PodState::Paused));
+        assert_eq!(pod.cause(), PodStateCause::Requested);
+    }
+}
@@ -0,0 +1,388 @@
+//! Recording of the events received from Docker, the pod that each event was mapped to, and the
+//! response chosen by the pod manager, enabled by the `[debug]` section of the config.
+//! Records are written as lines of JSON by a background task so that recording never delays
+//! event handling, and recordings can be replayed with `deimosd replay-events <file>` to check
+//! that event handling still makes the same decisions
+
+use std::{
+    ffi::OsString, io::{self, BufRead}, path::{Path, PathBuf}, process::ExitCode, sync::{atomic::{AtomicU64, Ordering}, Arc}
+};
+
+use bollard::secret::EventMessage;
+use chrono::{DateTime, Utc};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncWriteExt, BufWriter}, sync::mpsc};
+
+use super::events::{PodEventResponse, PodEventState};
+use crate::pod::{resources::PodMemorySize, PodState};
+
+/// Options for recording Docker events to a file
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DockerEventRecordConfig {
+    /// Path of the file to record events to, recording is disabled if not given
+    #[serde(default)]
+    pub record_events: Option<PathBuf>,
+    /// Size that the recording may grow to before it is moved aside and a new file is started
+    #[serde(default = "DockerEventRecordConfig::default_record_max_size")]
+    pub record_max_size: PodMemorySize,
+    /// Number of previous recordings kept when the recording is rotated, named by appending `.1`,
+    /// `.2`, and so on to the recording's path
+    #[serde(default = "DockerEventRecordConfig::default_record_files")]
+    pub record_files: usize,
+}
+
+/// A single line of an event recording
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DockerEventRecord {
+    /// Time that the record was created
+    pub recorded: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: DockerEventRecordKind,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DockerEventRecordKind {
+    /// An event as received from Docker, with the ID of the pod its container belongs to
+    Event {
+        event: EventMessage,
+        pod: Option<String>,
+    },
+    /// An event handled for a pod, with the state that the response was chosen from and the
+    /// state of the pod after the response was carried out
+    Handled {
+        pod: String,
+        action: String,
+        at: DateTime<Utc>,
+        state: PodEventState,
+        response: PodEventResponse,
+        after: PodState,
+    },
+    /// Records discarded because the recording could not be written quickly enough
+    Dropped {
+        count: u64,
+    },
+}
+
+/// Handle used to submit records to the task writing the recording, which does nothing if
+/// recording is disabled
+#[derive(Clone, Default)]
+pub struct DockerEventRecorder {
+    tx: Option<mpsc::Sender<DockerEventRecord>>,
+    /// Number of records discarded since the last batch was written
+    dropped: Arc<AtomicU64>,
+}
+
+/// Task appending records to the recording file, rotating it when it grows too large
+struct DockerEventWriter {
+    path: PathBuf,
+    max_size: u64,
+    files: usize,
+    file: Option<BufWriter<File>>,
+    /// Size of the current recording file
+    written: u64,
+}
+
+impl DockerEventRecordConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_record_max_size() -> PodMemorySize {
+        PodMemorySize::from_mib(16)
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_record_files() -> usize {
+        3
+    }
+}
+
+impl Default for DockerEventRecordConfig {
+    fn default() -> Self {
+        Self {
+            record_events: None,
+            record_max_size: Self::default_record_max_size(),
+            record_files: Self::default_record_files(),
+        }
+    }
+}
+
+impl DockerEventRecorder {
+    /// Maximum number of records waiting to be written before new records are discarded
+    const CAPACITY: usize = 1024;
+
+    /// Start the task writing records to the file given in the config, returning a recorder that
+    /// does nothing if no file is configured
+    pub fn spawn(config: &DockerEventRecordConfig) -> Self {
+        let Some(ref path) = config.record_events else {
+            return Self::default()
+        };
+
+        tracing::info!("Recording Docker events to {}", path.display());
+
+        let (tx, rx) = mpsc::channel(Self::CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = DockerEventWriter {
+            path: path.clone(),
+            max_size: config.record_max_size.bytes(),
+            files: config.record_files,
+            file: None,
+            written: 0,
+        };
+
+        tokio::task::spawn(writer.run(rx, dropped.clone()));
+        Self { tx: Some(tx), dropped }
+    }
+
+    /// Submit a record if recording is enabled, discarding it if the writer has fallen behind.
+    /// The record is only created if it will be submitted
+    pub fn record(&self, kind: impl FnOnce() -> DockerEventRecordKind) {
+        let Some(ref tx) = self.tx else {
+            return
+        };
+
+        let record = DockerEventRecord { recorded: Utc::now(), kind: kind() };
+        if tx.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl DockerEventWriter {
+    /// Write records in batches until every recorder is dropped, noting the number of records
+    /// that were discarded before each batch
+    async fn run(mut self, mut rx: mpsc::Receiver<DockerEventRecord>, dropped: Arc<AtomicU64>) {
+        let mut batch = Vec::with_capacity(DockerEventRecorder::CAPACITY);
+        let mut failing = false;
+
+        while rx.recv_many(&mut batch, DockerEventRecorder::CAPACITY).await > 0 {
+            let count = dropped.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                tracing::warn!("Discarded {} Docker event records because the recording fell behind", count);
+                batch.insert(0, DockerEventRecord { recorded: Utc::now(), kind: DockerEventRecordKind::Dropped { count } });
+            }
+
+            match self.write(&batch).await {
+                Ok(()) => failing = false,
+                Err(e) => {
+                    if !failing {
+                        tracing::error!("Failed to write Docker event recording to {}: {}", self.path.display(), e);
+                    }
+
+                    self.file = None;
+                    failing = true;
+                }
+            }
+
+            batch.clear();
+        }
+    }
+
+    /// Append the given records to the recording, flushing them once all are written
+    async fn write(&mut self, records: &[DockerEventRecord]) -> io::Result<()> {
+        for record in records {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+
+            let file = match self.file {
+                Some(ref mut file) if self.written + line.len() as u64 <= self.max_size || self.written == 0 => file,
+                Some(_) => self.rotate().await?,
+                None => self.open().await?,
+            };
+
+            file.write_all(&line).await?;
+            self.written += line.len() as u64;
+        }
+
+        if let Some(ref mut file) = self.file {
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Open the recording for appending, rotating it first if it is already full
+    async fn open(&mut self) -> io::Result<&mut BufWriter<File>> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        self.written = file.metadata().await?.len();
+        if self.written >= self.max_size {
+            return self.rotate().await
+        }
+
+        Ok(self.file.insert(BufWriter::new(file)))
+    }
+
+    /// Move the current recording aside, discarding the oldest recording, and start a new file.
+    /// The current recording is truncated if no previous recordings are kept
+    async fn rotate(&mut self) -> io::Result<&mut BufWriter<File>> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+        }
+
+        for idx in (1..self.files).rev() {
+            rename_existing(&rotated(&self.path, idx), &rotated(&self.path, idx + 1)).await?;
+        }
+
+        if self.files > 0 {
+            rename_existing(&self.path, &rotated(&self.path, 1)).await?;
+        }
+
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path).await?;
+        self.written = 0;
+        Ok(self.file.insert(BufWriter::new(file)))
+    }
+}
+
+/// Get the path of the given previous recording, where `1` is the most recent
+fn rotated(path: &Path, idx: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{}", idx));
+    PathBuf::from(path)
+}
+
+/// Rename a file, ignoring files that do not exist
+async fn rename_existing(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Print the timeline of the given recording, choosing the response to each handled event again
+/// and reporting every event where the response differs from the recorded response
+pub fn replay_events(path: &Path) -> ExitCode {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to open event recording {}: {}", path.display(), e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let mut handled = 0usize;
+    let mut mismatched = 0usize;
+    let mut invalid = 0usize;
+
+    for (idx, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to read event recording {}: {}", path.display(), e);
+                return ExitCode::FAILURE
+            }
+        };
+
+        let record = match serde_json::from_str::<DockerEventRecord>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("{}:{}: invalid record: {}", path.display(), idx + 1, e);
+                invalid += 1;
+                continue
+            }
+        };
+
+        let recorded = record.recorded.format("%Y-%m-%d %H:%M:%S%.3f");
+        match record.kind {
+            DockerEventRecordKind::Event { event, pod } => {
+                let container = event
+                    .actor
+                    .and_then(|actor| actor.id)
+                    .map(|id| id.chars().take(12).collect::<String>())
+                    .unwrap_or_else(|| String::from("-"));
+
+                println!(
+                    "{}  event    {:<12} {:<10} -> {}",
+                    recorded,
+                    container,
+                    event.action.as_deref().unwrap_or("-"),
+                    pod.as_deref().unwrap_or("(no pod)"),
+                );
+            },
+            DockerEventRecordKind::Handled { pod, action, at, state, response, after } => {
+                handled += 1;
+                let replayed = PodEventResponse::decide(state, &action, at);
+                println!("{}  handled  {:<12} {:<10} {:?} -> {:?}, now {:?}", recorded, pod, action, state, response, after);
+
+                if replayed != response {
+                    mismatched += 1;
+                    println!("    MISMATCH: replay chose {:?} instead of {:?}", replayed, response);
+                }
+            },
+            DockerEventRecordKind::Dropped { count } => {
+                println!("{}  dropped  {} records", recorded, count);
+            },
+        }
+    }
+
+    println!("Replayed {} handled events: {} mismatched, {} invalid records", handled, mismatched, invalid);
+    match mismatched == 0 && invalid == 0 {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dropped(count: u64) -> DockerEventRecord {
+        DockerEventRecord { recorded: Utc::now(), kind: DockerEventRecordKind::Dropped { count } }
+    }
+
+    fn handled(action: &str, response: PodEventResponse) -> String {
+        let kind = DockerEventRecordKind::Handled {
+            pod: String::from("pod"),
+            action: action.to_owned(),
+            at: Utc::now(),
+            state: PodEventState::Enabled { restarted: None },
+            response,
+            after: PodState::Disabled,
+        };
+
+        serde_json::to_string(&DockerEventRecord { recorded: Utc::now(), kind }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_recordings_are_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let line = serde_json::to_vec(&dropped(1)).unwrap().len() as u64 + 1;
+        let mut writer = DockerEventWriter { path: path.clone(), max_size: line * 2, files: 2, file: None, written: 0 };
+
+        for _ in 0..4 {
+            writer.write(&[dropped(1), dropped(1)]).await.unwrap();
+        }
+
+        for path in [&path, &rotated(&path, 1), &rotated(&path, 2)] {
+            assert_eq!(std::fs::metadata(path).unwrap().len(), line * 2, "{}", path.display());
+        }
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[tokio::test]
+    async fn full_recording_is_rotated_when_opened() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        std::fs::write(&path, vec![b'x'; 64]).unwrap();
+
+        let mut writer = DockerEventWriter { path: path.clone(), max_size: 64, files: 1, file: None, written: 0 };
+        writer.write(&[dropped(1)]).await.unwrap();
+
+        assert_eq!(std::fs::read(rotated(&path, 1)).unwrap(), vec![b'x'; 64]);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("dropped"));
+    }
+
+    #[test]
+    fn replay_reports_mismatched_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+
+        std::fs::write(&path, format!("{}\n\n", handled("die", PodEventResponse::Died))).unwrap();
+        assert_eq!(replay_events(&path), ExitCode::SUCCESS);
+
+        std::fs::write(&path, format!("{}\n", handled("die", PodEventResponse::Ignore))).unwrap();
+        assert_eq!(replay_events(&path), ExitCode::FAILURE);
+
+        std::fs::write(&path, "not a record\n").unwrap();
+        assert_eq!(replay_events(&path), ExitCode::FAILURE);
+    }
+}
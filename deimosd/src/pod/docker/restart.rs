@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use bollard::container::RestartContainerOptions;
+use chrono::Utc;
+
+use crate::pod::{docker::{disable::PodDisableError, enable::PodEnableError}, state::{PodEnable, PodStateWriteHandle}, Pod, PodManager, PodStateKnown};
+
+impl PodManager {
+    /// Top-level operation to restart the given pod in a single transaction.
+    /// The container of an enabled pod is restarted in place so that its UPnP leases are kept,
+    /// while the container of a paused pod is re-created. Disabled pods are enabled, as are
+    /// enabled pods whose container no longer exists
+    pub async fn restart(&self, pod: Arc<Pod>, mut lock: PodStateWriteHandle<'_>) -> Result<(), PodRestartError> {
+        pod.cancel_restart();
+
+        let enabled = match lock.state() {
+            PodStateKnown::Enabled(ref enabled) => enabled.clone(),
+            PodStateKnown::Paused(..) => {
+                self.disable_container(&pod, &mut lock).await?;
+                return Ok(self.enable(pod, lock).await?)
+            },
+            PodStateKnown::Disabled => return Ok(self.enable(pod, lock).await?),
+        };
+
+        tracing::trace!("Restarting container {} for {}", enabled.docker_id, pod.id());
+        let options = RestartContainerOptions { t: pod.config().docker.stop_timeout_seconds as isize };
+        match self.docker.restart_container(&enabled.docker_id, Some(options)).await {
+            Ok(()) => (),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {
+                tracing::warn!("Container {} of pod {} no longer exists, re-creating it", enabled.docker_id, pod.id());
+                self.reverse_lookup.remove(&enabled.docker_id);
+                pod.set_network(None);
+
+                //The leases of the old container must be released before they are requested again
+                drop(enabled);
+                lock.set(PodStateKnown::Disabled);
+                return Ok(self.enable(pod, lock).await?)
+            },
+            Err(e) => return Err(PodRestartError::Restart(e)),
+        }
+
+        //Events for the container stopping during the restart are ignored as they occurred
+        //before it finished
+        let started = Utc::now();
+        let network = self.inspect_network(&pod, &enabled.docker_id).await;
+        pod.set_network(Some(Arc::new(network)));
+        pod.set_failure(None);
+
+        lock.set(PodStateKnown::Enabled(PodEnable { started, restarted: Some(started), degraded: false, ..enabled }));
+        let _ = self.starts.send((pod.id(), started));
+
+        Ok(())
+    }
+
+    /// Remove the container of an enabled or paused pod and create it again, applying any config
+    /// staged for the pod. Disabled pods are left disabled
+    pub async fn recreate(&self, pod: Arc<Pod>, mut lock: PodStateWriteHandle<'_>) -> Result<(), PodRestartError> {
+        if matches!(lock.state(), PodStateKnown::Disabled) {
+            return Ok(())
+        }
+
+        pod.cancel_restart();
+        self.disable_container(&pod, &mut lock).await?;
+        Ok(self.enable(pod, lock).await?)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodRestartError {
+    #[error("Failed to restart Docker container: {0}")]
+    Restart(#[source] bollard::errors::Error),
+    #[error("{0}")]
+    Disable(#[from] PodDisableError),
+    #[error("{0}")]
+    Enable(#[from] PodEnableError),
+}
@@ -0,0 +1,243 @@
+use std::time::{Duration, Instant};
+
+use bollard::container::{MemoryStatsStats, Stats, StatsOptions};
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::pod::{alert::PodUsage, Pod, PodManager, PodStateKnown};
+
+/// Resource usage of a pod's container sent to clients watching its statistics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PodStatsSample {
+    /// CPU usage in percent of a single core, unknown until two readings have been taken
+    pub cpu: Option<f64>,
+    /// Memory used in bytes, excluding the page cache
+    pub memory_used: u64,
+    /// Memory available to the container in bytes
+    pub memory_limit: u64,
+    /// Total bytes received over all of the container's networks
+    pub network_rx: u64,
+    /// Total bytes sent over all of the container's networks
+    pub network_tx: u64,
+}
+
+impl PodManager {
+    /// Check if the resource usage of pods is sampled to evaluate their alert rules
+    pub fn usage_sampling(&self) -> bool {
+        self.config.usage_sampling
+    }
+
+    /// Sample the resource usage of the given pod's container, returning `None` if the pod is
+    /// not enabled
+    pub async fn sample_usage(&self, pod: &Pod) -> Result<Option<PodUsage>, bollard::errors::Error> {
+        let docker_id = {
+            let lock = pod.state().read().await;
+            match *lock {
+                PodStateKnown::Enabled(ref run) => run.docker_id.clone(),
+                _ => return Ok(None),
+            }
+        };
+
+        //A single non-streaming sample includes the previous CPU reading needed to compute usage
+        let stats = self
+            .docker
+            .stats(&docker_id, Some(StatsOptions { stream: false, one_shot: false }))
+            .next()
+            .await
+            .transpose()?;
+
+        let Some(mut usage) = stats.as_ref().map(PodUsage::from) else {
+            return Ok(None)
+        };
+
+        //Failing to measure the writable layer should not discard the CPU and memory sample
+        if let Some(limit) = pod.config().docker.disk_limit {
+            match self.sample_disk(&docker_id, limit).await {
+                Ok(disk) => usage.disk = disk,
+                Err(e) => tracing::warn!("Failed to sample disk usage of pod {}: {}", pod.id(), e),
+            }
+        }
+
+        Ok(Some(usage))
+    }
+
+    /// Minimum time between statistics sent for a pod, Docker's readings in between are dropped
+    const STATS_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// Stream the resource usage of the given pod's container until the container stops
+    pub async fn subscribe_stats(&self, pod: &Pod) -> Result<BoxStream<'static, PodStatsSample>, PodSubscribeStatsError> {
+        let docker_id = {
+            let lock = pod.state().read().await;
+            match *lock {
+                PodStateKnown::Enabled(ref run) => run.docker_id.clone(),
+                _ => return Err(PodSubscribeStatsError::NotEnabled),
+            }
+        };
+
+        let id = pod.id();
+        let mut last = None::<Instant>;
+        let stream = self
+            .docker
+            .stats(&docker_id, Some(StatsOptions { stream: true, one_shot: false }))
+            .filter_map(move |stats| {
+                let sample = match stats {
+                    Ok(stats) => {
+                        let now = Instant::now();
+                        match last.is_some_and(|last| now.duration_since(last) < Self::STATS_INTERVAL) {
+                            true => None,
+                            false => {
+                                last = Some(now);
+                                Some(PodStatsSample::from(&stats))
+                            },
+                        }
+                    },
+                    Err(e) => {
+                        tracing::warn!("Statistics stream of pod {} failed: {}", id, e);
+                        None
+                    },
+                };
+
+                futures::future::ready(sample)
+            })
+            .boxed();
+
+        Ok(stream)
+    }
+}
+
+/// Get the CPU usage in percent of a single core between the two readings of the given stats
+fn cpu_percent(stats: &Stats) -> Option<f64> {
+    let cpu_delta = stats.cpu_stats.cpu_usage.total_usage.saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+    let system_delta = stats
+        .cpu_stats
+        .system_cpu_usage
+        .zip(stats.precpu_stats.system_cpu_usage)
+        .map(|(now, pre)| now.saturating_sub(pre))
+        .filter(|delta| *delta != 0);
+    let cpus = stats
+        .cpu_stats
+        .online_cpus
+        .or_else(|| stats.cpu_stats.cpu_usage.percpu_usage.as_ref().map(|cpus| cpus.len() as u64))
+        .unwrap_or(1);
+
+    system_delta.map(|system| cpu_delta as f64 / system as f64 * cpus as f64 * 100.)
+}
+
+/// Get the memory used by the container in bytes, excluding the page cache that the kernel can
+/// reclaim
+fn memory_used(stats: &Stats) -> Option<u64> {
+    let cache = match stats.memory_stats.stats {
+        Some(MemoryStatsStats::V1(v1)) => v1.total_inactive_file,
+        Some(MemoryStatsStats::V2(v2)) => v2.inactive_file,
+        None => 0,
+    };
+
+    stats.memory_stats.usage.map(|usage| usage.saturating_sub(cache))
+}
+
+impl From<&Stats> for PodStatsSample {
+    fn from(stats: &Stats) -> Self {
+        let (network_rx, network_tx) = stats
+            .networks
+            .iter()
+            .flat_map(|networks| networks.values())
+            .fold((0u64, 0u64), |(rx, tx), network| (rx.saturating_add(network.rx_bytes), tx.saturating_add(network.tx_bytes)));
+
+        Self {
+            cpu: cpu_percent(stats),
+            memory_used: memory_used(stats).unwrap_or_default(),
+            memory_limit: stats.memory_stats.limit.unwrap_or_default(),
+            network_rx,
+            network_tx,
+        }
+    }
+}
+
+impl From<&Stats> for PodUsage {
+    fn from(stats: &Stats) -> Self {
+        let memory = memory_used(stats)
+            .zip(stats.memory_stats.limit.filter(|limit| *limit != 0))
+            .map(|(used, limit)| used as f64 / limit as f64 * 100.);
+
+        Self { cpu: cpu_percent(stats), memory, disk: None }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodSubscribeStatsError {
+    #[error("Pod is not enabled")]
+    NotEnabled,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cpu(total: u64, system: Option<u64>, online: Option<u64>) -> serde_json::Value {
+        json!({
+            "cpu_usage": { "total_usage": total, "usage_in_usermode": 0, "usage_in_kernelmode": 0, "percpu_usage": [0, 0, 0, 0] },
+            "system_cpu_usage": system,
+            "online_cpus": online,
+            "throttling_data": { "periods": 0, "throttled_periods": 0, "throttled_time": 0 },
+        })
+    }
+
+    fn network(rx: u64, tx: u64) -> serde_json::Value {
+        json!({
+            "rx_bytes": rx, "rx_packets": 0, "rx_errors": 0, "rx_dropped": 0,
+            "tx_bytes": tx, "tx_packets": 0, "tx_errors": 0, "tx_dropped": 0,
+        })
+    }
+
+    fn stats(precpu: serde_json::Value, cpu: serde_json::Value, memory: serde_json::Value) -> Stats {
+        serde_json::from_value(json!({
+            "read": "2024-01-01T00:00:01Z",
+            "preread": "2024-01-01T00:00:00Z",
+            "num_procs": 0,
+            "pids_stats": {},
+            "networks": { "eth0": network(100, 10), "eth1": network(50, 5) },
+            "memory_stats": memory,
+            "blkio_stats": {},
+            "cpu_stats": cpu,
+            "precpu_stats": precpu,
+            "storage_stats": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cpu_is_scaled_by_online_cores() {
+        let stats = stats(cpu(200, Some(1000), Some(2)), cpu(400, Some(2000), Some(2)), json!({}));
+        assert_eq!(cpu_percent(&stats), Some(40.));
+
+        //Daemons that do not report online cores are read from the per-core usage
+        let stats = self::stats(cpu(200, Some(1000), None), cpu(400, Some(2000), None), json!({}));
+        assert_eq!(cpu_percent(&stats), Some(80.));
+    }
+
+    #[test]
+    fn cpu_is_unknown_without_previous_reading() {
+        let first = stats(cpu(0, None, None), cpu(400, Some(2000), Some(2)), json!({}));
+        assert_eq!(cpu_percent(&first), None);
+
+        let idle = stats(cpu(400, Some(2000), Some(2)), cpu(400, Some(2000), Some(2)), json!({}));
+        assert_eq!(cpu_percent(&idle), None);
+    }
+
+    #[test]
+    fn sample_totals_networks_and_memory() {
+        let stats = stats(cpu(0, None, None), cpu(0, None, None), json!({ "usage": 256, "limit": 1024 }));
+        let sample = PodStatsSample::from(&stats);
+        assert_eq!((sample.network_rx, sample.network_tx), (150, 15));
+        assert_eq!((sample.memory_used, sample.memory_limit), (256, 1024));
+
+        assert_eq!(PodUsage::from(&stats).memory, Some(25.));
+    }
+
+    #[test]
+    fn memory_is_unknown_without_limit() {
+        let stats = stats(cpu(0, None, None), cpu(0, None, None), json!({ "usage": 256, "limit": 0 }));
+        assert_eq!(PodUsage::from(&stats).memory, None);
+    }
+}
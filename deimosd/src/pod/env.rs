@@ -0,0 +1,384 @@
+//! Environment variables set for pods through the internal API, kept in the daemon's save file
+//! rather than the pod's config file. Overrides replace variables of the same name from the
+//! config and take effect when the pod's container is next created.
+//! Each change increments the version of a pod's overrides, and changes based on an older
+//! version are rejected unless forced so that concurrent edits do not silently discard each other
+
+use std::{collections::{BTreeMap, HashMap, HashSet}, path::{Component, Path, PathBuf}, sync::PoisonError};
+
+use chrono::{DateTime, Utc};
+
+use super::{config::{PodDockerConfig, PodDockerEnvValue}, Pod, PodManager, PodState};
+
+/// Map of variable names to the values set for them
+pub type PodEnvOverrides = BTreeMap<String, PodEnvOverride>;
+
+/// Value of a single environment variable override
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PodEnvOverride {
+    pub value: String,
+    /// Secret values can be set but are never returned by the API
+    #[serde(default)]
+    pub secret: bool,
+}
+
+/// Version of a pod's overrides and the last change made to them
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PodEnvRevision {
+    /// Number of changes made to the overrides
+    pub version: u64,
+    /// Time of the last change
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    /// Local user that made the last change, if known
+    #[serde(default)]
+    pub modified_by: Option<String>,
+}
+
+/// A variable in a new set of overrides for a pod
+#[derive(Debug)]
+pub struct PodEnvOverrideUpdate {
+    pub key: String,
+    /// New value of the variable, or `None` to keep its current value so that secrets can be
+    /// kept without being read back
+    pub value: Option<String>,
+    pub secret: bool,
+}
+
+impl Pod {
+    /// Get the environment variable overrides set for this pod
+    pub fn env_overrides(&self) -> PodEnvOverrides {
+        self.env.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Get the environment variable overrides set for this pod along with their version
+    pub fn env_overrides_revision(&self) -> (PodEnvOverrides, PodEnvRevision) {
+        let env = self.env.lock().unwrap_or_else(PoisonError::into_inner);
+        (env.clone(), self.env_revision.lock().unwrap_or_else(PoisonError::into_inner).clone())
+    }
+
+    /// Check if the pod's container was created with different overrides than are currently
+    /// set, and must be restarted to apply them
+    pub fn env_pending(&self) -> bool {
+        let applied = self.env_applied.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.state().current() {
+            PodState::Disabled => false,
+            _ => applied.as_ref().is_some_and(|applied| *applied != *self.env.lock().unwrap_or_else(PoisonError::into_inner)),
+        }
+    }
+
+    /// Record the overrides that the pod's container was created with
+    pub(super) fn set_env_applied(&self, overrides: PodEnvOverrides) {
+        *self.env_applied.lock().unwrap_or_else(PoisonError::into_inner) = Some(overrides);
+    }
+}
+
+impl PodManager {
+    /// Maximum number of overrides that may be set for a single pod
+    pub const MAX_ENV_OVERRIDES: usize = 64;
+    /// Maximum length in bytes of an override's variable name
+    pub const MAX_ENV_KEY_LEN: usize = 256;
+    /// Maximum length in bytes of an override's value
+    pub const MAX_ENV_VALUE_LEN: usize = 8 * 1024;
+
+    /// Replace the environment variable overrides of the given pod, applied when its container
+    /// is next created. The change is rejected if the overrides are no longer at the `expected`
+    /// version, and is applied regardless of the current version if `expected` is `None`.
+    /// Only the names of changed variables are logged
+    pub fn set_env_overrides(
+        &self,
+        pod: &Pod,
+        updates: Vec<PodEnvOverrideUpdate>,
+        expected: Option<u64>,
+        editor: Option<String>,
+    ) -> Result<PodEnvRevision, PodEnvOverrideError> {
+        if updates.len() > Self::MAX_ENV_OVERRIDES {
+            return Err(PodEnvOverrideError::TooMany { count: updates.len(), limit: Self::MAX_ENV_OVERRIDES })
+        }
+
+        let mut env = pod.env.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut revision = pod.env_revision.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(expected) = expected.filter(|expected| *expected != revision.version) {
+            return Err(PodEnvOverrideError::Conflict { expected, current: revision.clone() })
+        }
+
+        let mut overrides = PodEnvOverrides::new();
+        for update in updates {
+            validate_env_key(&update.key)?;
+            if overrides.contains_key(&update.key) {
+                return Err(PodEnvOverrideError::Duplicate(update.key))
+            }
+
+            let value = match update.value {
+                Some(value) => value,
+                None => match env.get(&update.key) {
+                    Some(exist) => exist.value.clone(),
+                    None => return Err(PodEnvOverrideError::MissingValue(update.key)),
+                },
+            };
+
+            if value.len() > Self::MAX_ENV_VALUE_LEN {
+                return Err(PodEnvOverrideError::ValueTooLarge { key: update.key, limit: Self::MAX_ENV_VALUE_LEN })
+            }
+
+            if value.contains('\0') {
+                return Err(PodEnvOverrideError::NulValue(update.key))
+            }
+
+            overrides.insert(update.key, PodEnvOverride { value, secret: update.secret });
+        }
+
+        let changed = env
+            .keys()
+            .chain(overrides.keys())
+            .filter(|key| env.get(*key) != overrides.get(*key))
+            .collect::<HashSet<_>>();
+
+        match changed.is_empty() {
+            true => {
+                tracing::debug!("Environment overrides of pod {} are unchanged", pod.id());
+                return Ok(revision.clone())
+            },
+            false => {
+                let mut changed = changed.into_iter().map(String::as_str).collect::<Vec<_>>();
+                changed.sort_unstable();
+                tracing::info!(
+                    "Changed environment overrides of pod {} to version {}{}: {}",
+                    pod.id(),
+                    revision.version + 1,
+                    editor.as_ref().map(|editor| format!(" by {}", editor)).unwrap_or_default(),
+                    changed.join(", "),
+                );
+            }
+        }
+
+        *env = overrides;
+        *revision = PodEnvRevision {
+            version: revision.version + 1,
+            modified: Some(Utc::now()),
+            modified_by: editor,
+        };
+
+        Ok(revision.clone())
+    }
+
+    /// Get the environment variable overrides of all pods to be written to the save file
+    pub(super) fn env_overrides(&self) -> HashMap<String, PodEnvOverrides> {
+        self
+            .loaded()
+            .iter()
+            .map(|(id, pod)| (id.owned(), pod.env_overrides()))
+            .filter(|(_, overrides)| !overrides.is_empty())
+            .collect()
+    }
+
+    /// Get the versions of the environment variable overrides of all changed pods to be written
+    /// to the save file
+    pub(super) fn env_revisions(&self) -> HashMap<String, PodEnvRevision> {
+        self
+            .loaded()
+            .iter()
+            .map(|(id, pod)| (id.owned(), pod.env_revision.lock().unwrap_or_else(PoisonError::into_inner).clone()))
+            .filter(|(_, revision)| revision.version > 0)
+            .collect()
+    }
+
+    /// Restore environment variable overrides from persistent state
+    pub(super) fn restore_env_overrides(&self, persistent: HashMap<String, PodEnvOverrides>) {
+        for (id, overrides) in persistent {
+            match self.loaded().get(id.as_str()) {
+                Some(pod) => *pod.env.lock().unwrap_or_else(PoisonError::into_inner) = overrides,
+                None => tracing::warn!("Discarding environment overrides for unknown pod {}", id),
+            }
+        }
+    }
+
+    /// Restore the versions of environment variable overrides from persistent state. Pods
+    /// without a saved version, such as those saved by older versions of the daemon, start at
+    /// version 0
+    pub(super) fn restore_env_revisions(&self, persistent: HashMap<String, PodEnvRevision>) {
+        for (id, revision) in persistent {
+            if let Some(pod) = self.loaded().get(id.as_str()) {
+                *pod.env_revision.lock().unwrap_or_else(PoisonError::into_inner) = revision;
+            }
+        }
+    }
+}
+
+/// Get the environment of a pod's container from its config and the given overrides, with
+/// overrides replacing config variables of the same name.
+/// Values from files are read relative to the given pod directory and values from the daemon's
+/// environment are copied, failing if any cannot be read or a file path leaves the pod directory
+pub(super) async fn docker_env(dir: &Path, config: &PodDockerConfig, overrides: &PodEnvOverrides) -> Result<Option<Vec<String>>, PodEnvValueError> {
+    let mut env = Vec::with_capacity(config.env.len() + overrides.len());
+    for var in config.env.iter().filter(|var| !overrides.contains_key(&var.key)) {
+        let value = match var.value {
+            PodDockerEnvValue::Literal(ref value) => value.clone(),
+            PodDockerEnvValue::File(ref path) => {
+                if !env_path_confined(path) {
+                    return Err(PodEnvValueError::Outside { key: var.key.clone(), path: path.clone() })
+                }
+
+                let path = dir.join(path);
+                let contents = deimosproto::util::load_check_permissions(&path)
+                    .await
+                    .map_err(|err| PodEnvValueError::Read { key: var.key.clone(), path: path.clone(), err })?;
+
+                let mut value = String::from_utf8(contents).map_err(|_| PodEnvValueError::Utf8 { key: var.key.clone(), path })?;
+                //Editors and `echo` end files with a newline that is not part of the secret
+                let len = value.trim_end_matches(['\r', '\n']).len();
+                value.truncate(len);
+                value
+            },
+            PodDockerEnvValue::Host(ref name) => std::env::var(name).map_err(|err| PodEnvValueError::Host { key: var.key.clone(), name: name.clone(), err })?,
+        };
+
+        env.push(format!("{}={}", var.key, value));
+    }
+
+    env.extend(overrides.iter().map(|(key, var)| format!("{}={}", key, var.value)));
+    Ok((!env.is_empty()).then_some(env))
+}
+
+/// Check that a `value_from_file` path stays inside the pod directory when joined to it, as it is
+/// relative and names no parent directory. The check is lexical, so symbolic links inside the
+/// pod directory are still followed
+pub(super) fn env_path_confined(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Check that the given variable name is a letter or underscore followed by letters, digits, and
+/// underscores, as accepted by POSIX shells
+fn validate_env_key(key: &str) -> Result<(), PodEnvOverrideError> {
+    let mut chars = key.chars();
+    let valid = key.len() <= PodManager::MAX_ENV_KEY_LEN
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(PodEnvOverrideError::InvalidKey(key.to_owned())),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodEnvOverrideError {
+    #[error(
+        "Invalid variable name '{0}', names must start with a letter or underscore followed by letters, digits, or underscores and be at most {} bytes",
+        PodManager::MAX_ENV_KEY_LEN
+    )]
+    InvalidKey(String),
+    #[error("Variable {0} is given more than once")]
+    Duplicate(String),
+    #[error("No value was given for new variable {0}")]
+    MissingValue(String),
+    #[error("Value of variable {key} exceeds the limit of {limit} bytes")]
+    ValueTooLarge {
+        key: String,
+        limit: usize,
+    },
+    #[error("Value of variable {0} contains a NUL character")]
+    NulValue(String),
+    #[error("{count} overrides were given, exceeding the limit of {limit}")]
+    TooMany {
+        count: usize,
+        limit: usize,
+    },
+    #[error("Overrides were changed to version {} since version {expected} was read", current.version)]
+    Conflict {
+        expected: u64,
+        current: PodEnvRevision,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodEnvValueError {
+    #[error("Failed to read value of variable {key} from {}: {err}", path.display())]
+    Read { key: String, path: PathBuf, err: std::io::Error },
+    #[error("Value of variable {key} is read from {}, which is not inside the pod directory", path.display())]
+    Outside { key: String, path: PathBuf },
+    #[error("Value of variable {key} in {} is not valid UTF-8", path.display())]
+    Utf8 { key: String, path: PathBuf },
+    #[error("Failed to copy variable {name} from the daemon's environment to {key}: {err}")]
+    Host { key: String, name: String, err: std::env::VarError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(env: &str) -> PodDockerConfig {
+        toml::from_str(&format!("image = \"alpine:3\"\n{}", env)).unwrap()
+    }
+
+    fn overrides(vars: &[(&str, &str)]) -> PodEnvOverrides {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), PodEnvOverride { value: value.to_string(), secret: false }))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn values_are_read_from_each_source() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("secrets")).unwrap();
+        std::fs::write(dir.path().join("secrets/token"), "line one\nline two\r\n\n").unwrap();
+        std::env::set_var("DEIMOSD_TEST_ENV_HOST", "from host");
+
+        let config = config(
+            "[[env]]\nkey = \"LITERAL\"\nvalue = \"literal\\n\"\n\
+            [[env]]\nkey = \"FILE\"\nvalue_from_file = \"secrets/token\"\n\
+            [[env]]\nkey = \"HOST\"\nfrom_host = \"DEIMOSD_TEST_ENV_HOST\"\n",
+        );
+        let env = docker_env(dir.path(), &config, &PodEnvOverrides::new()).await.unwrap().unwrap();
+
+        //Only trailing newlines of files are removed
+        assert_eq!(env, ["LITERAL=literal\n", "FILE=line one\nline two", "HOST=from host"]);
+    }
+
+    #[tokio::test]
+    async fn overrides_replace_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(
+            "[[env]]\nkey = \"A\"\nvalue = \"config\"\n\
+            [[env]]\nkey = \"B\"\nvalue_from_file = \"missing\"\n",
+        );
+
+        //Overridden variables are not read, so a missing file does not fail
+        let env = docker_env(dir.path(), &config, &overrides(&[("B", "override"), ("C", "new")])).await.unwrap().unwrap();
+        assert_eq!(env, ["A=config", "B=override", "C=new"]);
+
+        assert_eq!(docker_env(dir.path(), &self::config(""), &PodEnvOverrides::new()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn unreadable_values_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("binary"), [0xff, 0xfe]).unwrap();
+
+        let missing = docker_env(dir.path(), &config("[[env]]\nkey = \"A\"\nvalue_from_file = \"missing\"\n"), &PodEnvOverrides::new()).await;
+        assert!(matches!(missing, Err(PodEnvValueError::Read { ref key, .. }) if key == "A"));
+
+        let binary = docker_env(dir.path(), &config("[[env]]\nkey = \"A\"\nvalue_from_file = \"binary\"\n"), &PodEnvOverrides::new()).await;
+        assert!(matches!(binary, Err(PodEnvValueError::Utf8 { .. })));
+
+        let host = docker_env(dir.path(), &config("[[env]]\nkey = \"A\"\nfrom_host = \"DEIMOSD_TEST_ENV_UNSET\"\n"), &PodEnvOverrides::new()).await;
+        assert!(matches!(host, Err(PodEnvValueError::Host { ref name, .. }) if name == "DEIMOSD_TEST_ENV_UNSET"));
+    }
+
+    #[tokio::test]
+    async fn files_outside_pod_directory_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("pod");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(root.path().join("outside"), "secret").unwrap();
+
+        let escape = format!("[[env]]\nkey = \"A\"\nvalue_from_file = \"{}\"\n", root.path().join("outside").display());
+        for env in ["[[env]]\nkey = \"A\"\nvalue_from_file = \"../outside\"\n", escape.as_str()] {
+            let result = docker_env(&dir, &config(env), &PodEnvOverrides::new()).await;
+            assert!(matches!(result, Err(PodEnvValueError::Outside { .. })), "{}", env);
+        }
+
+        assert!(env_path_confined(Path::new("./secrets/token")));
+        assert!(!env_path_confined(Path::new("secrets/../../token")));
+    }
+}
@@ -0,0 +1,152 @@
+//! Health checks of enabled pods, made by running a command in the pod's container or connecting
+//! to one of its ports. A pod is degraded after failing a number of consecutive probes, and is
+//! healthy again as soon as a probe passes
+
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use futures::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use super::{config::PodHealthcheckConfig, id::DockerId, Pod, PodManager, PodStateKnown};
+
+impl PodManager {
+    /// Probe the given pod at the interval set by its health check for as long as the daemon
+    /// runs, skipping probes while the pod is not enabled. Returns immediately if the pod has no
+    /// health check
+    pub async fn health_check_task(&self, pod: Arc<Pod>, cancel: CancellationToken) {
+        let pod_config = pod.config();
+        let Some(config) = pod_config.healthcheck.as_ref() else {
+            return
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        //The first tick completes immediately, containers are given an interval to start up
+        interval.tick().await;
+
+        let mut failures = 0u32;
+        let mut container = None;
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            let (docker_id, started) = match *pod.state().read().await {
+                PodStateKnown::Enabled(ref enabled) => (enabled.docker_id.clone(), enabled.started),
+                _ => {
+                    container = None;
+                    continue
+                },
+            };
+
+            //Failures of a container that has since been restarted are not counted
+            if container != Some(started) {
+                container = Some(started);
+                failures = 0;
+            }
+
+            let result = tokio::time::timeout(Duration::from_secs(config.timeout), self.probe_health(&pod, config, &docker_id))
+                .await
+                .unwrap_or(Err(PodHealthProbeError::Timeout(config.timeout)));
+
+            match result {
+                Ok(()) => {
+                    failures = 0;
+                    if pod.state().set_degraded(started, false).await {
+                        tracing::info!("Pod {} passed its health check and is no longer degraded", pod.id());
+                    }
+                },
+                Err(e) => {
+                    failures = failures.saturating_add(1);
+                    tracing::debug!(
+                        "Health check of pod {} failed ({}/{}): {}",
+                        pod.id(),
+                        failures,
+                        config.failure_threshold,
+                        e,
+                    );
+
+                    if failures >= config.failure_threshold && pod.state().set_degraded(started, true).await {
+                        tracing::warn!("Pod {} is degraded after failing {} health checks: {}", pod.id(), failures, e);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Run a single probe of the given pod's health check against its container
+    async fn probe_health(&self, pod: &Pod, config: &PodHealthcheckConfig, container: &DockerId) -> Result<(), PodHealthProbeError> {
+        if let Some(ref command) = config.command {
+            return self.probe_command(container, command.clone()).await
+        }
+
+        let Some(port) = config.port else {
+            return Ok(())
+        };
+
+        //Published ports can be reached on the host even if the container's address is unknown
+        let network = pod.network().ok_or(PodHealthProbeError::NoAddress(port))?;
+        let addr = match network.container_ip {
+            Some(ip) => SocketAddr::new(ip, port),
+            None => network
+                .ports
+                .iter()
+                .find(|published| published.container_port == port)
+                .map(|published| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), published.host_port))
+                .ok_or(PodHealthProbeError::NoAddress(port))?,
+        };
+
+        tokio::net::TcpStream::connect(addr)
+            .await
+            .map(drop)
+            .map_err(|err| PodHealthProbeError::Connect { addr, err })
+    }
+
+    /// Run the given command in the container, passing if it exits with a code of 0
+    async fn probe_command(&self, container: &DockerId, command: Vec<String>) -> Result<(), PodHealthProbeError> {
+        let exec = self
+            .docker
+            .create_exec(
+                container,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(command),
+                    ..Default::default()
+                }
+            )
+            .await?;
+
+        //Output is discarded, but must be read until the command exits
+        let started = self
+            .docker
+            .start_exec(&exec.id, Some(StartExecOptions { detach: false, tty: false, output_capacity: None }))
+            .await?;
+
+        if let StartExecResults::Attached { mut output, .. } = started {
+            while output.next().await.is_some() {}
+        }
+
+        match self.docker.inspect_exec(&exec.id).await?.exit_code {
+            Some(0) => Ok(()),
+            code => Err(PodHealthProbeError::ExitCode(code)),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodHealthProbeError {
+    #[error("Docker API error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error("Command exited with code {}", .0.map(|code| code.to_string()).unwrap_or_else(|| String::from("unknown")))]
+    ExitCode(Option<i64>),
+    #[error("No address to reach port {0} of the container at")]
+    NoAddress(u16),
+    #[error("Failed to connect to {addr}: {err}")]
+    Connect { addr: SocketAddr, err: std::io::Error },
+    #[error("Probe did not finish within {0} seconds")]
+    Timeout(u64),
+}
@@ -0,0 +1,390 @@
+//! Versioned history of pod config files, kept as timestamped copies in a `.history` directory
+//! next to each pod's config file
+
+use std::{path::PathBuf, sync::{Arc, PoisonError}};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use chrono::{DateTime, NaiveDateTime, SubsecRound, TimeDelta, Utc};
+
+use super::{docker::restart::PodRestartError, Pod, PodManager, PodStateCause, PodStateKnown};
+
+/// Digest of the contents of a pod config file
+pub type PodConfigHash = [u8; 32];
+
+/// A previous version of a pod's config file saved in its history
+#[derive(Debug, Clone)]
+pub struct PodConfigVersion {
+    /// Name used to identify this version, the stem of its file name
+    pub name: String,
+    /// Time that the version was first loaded by the daemon
+    pub saved: DateTime<Utc>,
+    /// Digest of the config file contents
+    pub hash: PodConfigHash,
+}
+
+impl PodManager {
+    /// Roll back the config of the given pod to a saved version and apply it. A disabled pod uses
+    /// the restored config immediately, and the container of an enabled or paused pod is
+    /// re-created with it
+    pub async fn rollback_config(&self, pod: Arc<Pod>, name: &str) -> Result<PodConfigVersion, PodConfigRollbackError> {
+        let mut lock = pod.state().transact().await;
+        let version = pod.rollback_config(name, self.config.config_history).await?;
+
+        match lock.state() {
+            PodStateKnown::Disabled => pod.apply_pending_config(),
+            _ => {
+                tracing::info!("Re-creating container of pod {} with its restored config", pod.id());
+                lock.set_cause(PodStateCause::Requested);
+                self
+                    .recreate(pod.clone(), lock)
+                    .await
+                    .map_err(|err| PodConfigRollbackError::Recreate { version: version.name.clone(), err })?;
+            },
+        }
+
+        Ok(version)
+    }
+}
+
+impl Pod {
+    /// Name of the directory in the pod directory containing saved config versions
+    const HISTORY_DIR: &str = ".history";
+    /// Format of the timestamp at the start of version file names, with milliseconds so that
+    /// versions saved within the same second are still ordered
+    const VERSION_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%3fZ";
+    /// Format of the timestamp of versions saved by earlier releases
+    const LEGACY_VERSION_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+    /// Get the digest of the config file contents this pod was most recently loaded from, which
+    /// may not be applied until a container is next created for the pod
+    pub fn config_hash(&self) -> PodConfigHash {
+        *self.config_hash.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Compute the digest of the given config file contents
+    pub(super) fn hash_config(content: &[u8]) -> PodConfigHash {
+        Blake2b::<U32>::digest(content).into()
+    }
+
+    /// Get all saved versions of this pod's config file, newest first
+    pub async fn config_history(&self) -> Result<Vec<PodConfigVersion>, PodConfigHistoryError> {
+        let dir = self.history_dir();
+        let mut iter = match tokio::fs::read_dir(&dir).await {
+            Ok(iter) => iter,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(PodConfigHistoryError::Read { path: dir, err }),
+        };
+
+        let mut versions = Vec::new();
+        while let Some(entry) = iter
+            .next_entry()
+            .await
+            .map_err(|err| PodConfigHistoryError::Read { path: dir.clone(), err })? {
+            let path = entry.path();
+            let Some(saved) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split_once('-'))
+                .and_then(|(time, _)| {
+                    NaiveDateTime::parse_from_str(time, Self::VERSION_TIME_FORMAT)
+                        .or_else(|_| NaiveDateTime::parse_from_str(time, Self::LEGACY_VERSION_TIME_FORMAT))
+                        .ok()
+                }) else {
+                tracing::warn!("Ignoring unrecognized file {} in config history of pod {}", path.display(), self.id());
+                continue
+            };
+
+            let content = tokio::fs::read(&path)
+                .await
+                .map_err(|err| PodConfigHistoryError::Read { path: path.clone(), err })?;
+
+            versions.push(PodConfigVersion {
+                name: path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_owned(),
+                saved: saved.and_utc(),
+                hash: Self::hash_config(&content),
+            });
+        }
+
+        versions.sort_by(|a, b| b.saved.cmp(&a.saved).then_with(|| b.name.cmp(&a.name)));
+        Ok(versions)
+    }
+
+    /// Save the given config file contents to the history if they differ from the newest saved
+    /// version, removing the oldest versions beyond the given limit
+    pub(super) async fn record_config(&self, content: &str, limit: usize) -> Result<(), PodConfigHistoryError> {
+        if limit == 0 {
+            return Ok(())
+        }
+
+        let mut versions = self.config_history().await?;
+        let previous = versions.first().map(|version| version.hash);
+        let hash = self.config_hash();
+        if previous == Some(hash) {
+            return Ok(())
+        }
+
+        let dir = self.history_dir();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|err| PodConfigHistoryError::Write { path: dir.clone(), err })?;
+
+        //Versions are ordered by the millisecond they were saved in, so a version saved in the same
+        //millisecond as the newest is moved after it rather than ordered by its digest
+        let saved = Utc::now().trunc_subsecs(3);
+        let saved = match versions.first() {
+            Some(newest) if saved <= newest.saved => newest.saved + TimeDelta::milliseconds(1),
+            _ => saved,
+        };
+        let name = format!("{}-{}", saved.format(Self::VERSION_TIME_FORMAT), Self::short_hash(&hash));
+        let path = self.version_path(&name);
+        tokio::fs::write(&path, content)
+            .await
+            .map_err(|err| PodConfigHistoryError::Write { path, err })?;
+
+        match previous {
+            Some(previous) => tracing::info!(
+                "Config of pod {} changed from {} to {}",
+                self.id(),
+                Self::short_hash(&previous),
+                Self::short_hash(&hash),
+            ),
+            None => tracing::info!("Saved initial config version {} of pod {}", name, self.id()),
+        }
+
+        versions.insert(0, PodConfigVersion { name, saved, hash });
+        for version in versions.iter().skip(limit) {
+            let path = self.version_path(&version.name);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::warn!("Failed to remove old config version {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the given saved version back as this pod's config file, refusing to overwrite the
+    /// config file if it was modified since the pod was loaded. The restored config is staged as
+    /// a reload would stage it and its digest becomes the pod's config digest, then it is saved
+    /// to the history as the newest version
+    pub(super) async fn rollback_config(&self, name: &str, limit: usize) -> Result<PodConfigVersion, PodConfigHistoryError> {
+        let _guard = self.history_lock.lock().await;
+
+        let version = self
+            .config_history()
+            .await?
+            .into_iter()
+            .find(|version| version.name == name)
+            .ok_or_else(|| PodConfigHistoryError::UnknownVersion(name.to_owned()))?;
+
+        let current = tokio::fs::read(&self.config_path)
+            .await
+            .map_err(|err| PodConfigHistoryError::Read { path: self.config_path.clone(), err })?;
+        let hash = self.config_hash();
+        if Self::hash_config(&current) != hash {
+            return Err(PodConfigHistoryError::ConcurrentEdit { path: self.config_path.clone() })
+        }
+
+        let content = tokio::fs::read(self.version_path(&version.name))
+            .await
+            .map_err(|err| PodConfigHistoryError::Read { path: self.version_path(&version.name), err })?;
+        if Self::hash_config(&content) != version.hash {
+            return Err(PodConfigHistoryError::ConcurrentEdit { path: self.version_path(&version.name) })
+        }
+
+        let content = String::from_utf8(content)
+            .map_err(|_| PodConfigHistoryError::InvalidVersion { name: version.name.clone(), err: String::from("Config is not valid UTF-8") })?;
+        let config = Self::parse_config(&self.dir, &self.config_path, &content)
+            .await
+            .map_err(|e| PodConfigHistoryError::InvalidVersion { name: version.name.clone(), err: e.to_string() })?;
+
+        //Write to a temporary file first so that the config is never left partially written
+        let tmp = self.config_path.with_extension("toml.rollback");
+        tokio::fs::write(&tmp, &content)
+            .await
+            .map_err(|err| PodConfigHistoryError::Write { path: tmp.clone(), err })?;
+        tokio::fs::rename(&tmp, &self.config_path)
+            .await
+            .map_err(|err| PodConfigHistoryError::Write { path: self.config_path.clone(), err })?;
+
+        self.stage_config(Arc::new(config), version.hash);
+        tracing::info!(
+            "Rolled back config of pod {} from {} to version {}",
+            self.id(),
+            Self::short_hash(&hash),
+            version.name,
+        );
+
+        if let Err(e) = self.record_config(&content, limit).await {
+            tracing::error!("Failed to save restored config of pod {} to its history: {}", self.id(), e);
+        }
+
+        Ok(version)
+    }
+
+    /// Get a short hexadecimal representation of the given config digest
+    pub fn short_hash(hash: &PodConfigHash) -> String {
+        hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn history_dir(&self) -> PathBuf {
+        self.dir.join(Self::HISTORY_DIR)
+    }
+
+    fn version_path(&self, name: &str) -> PathBuf {
+        self.history_dir().join(name).with_extension("toml")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodConfigHistoryError {
+    #[error("Failed to read {}: {}", path.display(), err)]
+    Read {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    #[error("Failed to write {}: {}", path.display(), err)]
+    Write {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    #[error("No saved config version named {0}")]
+    UnknownVersion(String),
+    #[error("{} was modified since it was loaded by the daemon", path.display())]
+    ConcurrentEdit {
+        path: PathBuf,
+    },
+    #[error("Saved config version {name} can no longer be loaded: {err}")]
+    InvalidVersion {
+        name: String,
+        err: String,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodConfigRollbackError {
+    #[error("{0}")]
+    History(#[from] PodConfigHistoryError),
+    #[error("Restored config version {version} but failed to re-create the pod's container: {err}")]
+    Recreate {
+        version: String,
+        #[source]
+        err: PodRestartError,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{pod::{id::DockerId, state::PodEnable, PodState}, server::upnp::{UpnpLease, UpnpLeaseOwner}};
+
+    use super::*;
+
+    const LIMIT: usize = 3;
+
+    fn config(name: &str) -> String {
+        format!("id = \"web\"\nname = \"{}\"\n[docker]\nimage = \"alpine:3\"\n", name)
+    }
+
+    /// Write the given config to the pod directory and load it as the daemon would on startup or
+    /// reload, saving it to the history
+    async fn load(dir: &Path, content: &str) -> Pod {
+        tokio::fs::write(dir.join("pod.toml"), content).await.unwrap();
+        Pod::load(dir, LIMIT).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn history_keeps_the_newest_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "b", "c", "d", "e"] {
+            load(dir.path(), &config(name)).await;
+        }
+
+        let pod = load(dir.path(), &config("e")).await;
+        let history = pod.config_history().await.unwrap();
+        let hashes = history.iter().map(|version| version.hash).collect::<Vec<_>>();
+        let expected = ["e", "d", "c"].map(|name| Pod::hash_config(config(name).as_bytes()));
+        assert_eq!(hashes, expected);
+        assert_eq!(history[0].hash, pod.config_hash());
+
+        let files = std::fs::read_dir(dir.path().join(Pod::HISTORY_DIR)).unwrap().count();
+        assert_eq!(files, LIMIT);
+    }
+
+    #[tokio::test]
+    async fn legacy_version_names_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let pod = load(dir.path(), &config("a")).await;
+        tokio::fs::write(pod.version_path("20240101T000000Z-0011223344556677"), config("old")).await.unwrap();
+
+        let history = pod.config_history().await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].name, "20240101T000000Z-0011223344556677");
+        assert_eq!(history[1].saved, DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap());
+    }
+
+    #[tokio::test]
+    async fn rollback_of_enabled_pod_stages_config() {
+        let dir = tempfile::tempdir().unwrap();
+        load(dir.path(), &config("a")).await;
+        let pod = load(dir.path(), &config("b")).await;
+
+        let mut lock = pod.state().transact().await;
+        lock.set(PodStateKnown::Enabled(PodEnable {
+            docker_id: DockerId::from(String::from("container")),
+            upnp_lease: UpnpLease::test(UpnpLeaseOwner::Pod(pod.id())),
+            annotations: None,
+            started: Utc::now(),
+            restarted: None,
+            degraded: false,
+        }));
+        drop(lock);
+
+        let previous = pod.config_history().await.unwrap().pop().unwrap();
+        let restored = pod.rollback_config(&previous.name, LIMIT).await.unwrap();
+        assert_eq!(restored.hash, Pod::hash_config(config("a").as_bytes()));
+
+        assert_eq!(tokio::fs::read_to_string(dir.path().join("pod.toml")).await.unwrap(), config("a"));
+        assert_eq!(pod.config_hash(), restored.hash);
+        assert_eq!(&*pod.config().name, "b");
+        assert_eq!(pod.state().current(), PodState::Enabled);
+
+        let history = pod.config_history().await.unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].hash, restored.hash);
+
+        pod.apply_pending_config();
+        assert_eq!(&*pod.config().name, "a");
+
+        //The digest was updated with the file, so another rollback is not seen as a concurrent edit
+        pod.rollback_config(&history[1].name, LIMIT).await.unwrap();
+        assert_eq!(pod.config_hash(), Pod::hash_config(config("b").as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn rollback_refuses_edited_config() {
+        let dir = tempfile::tempdir().unwrap();
+        load(dir.path(), &config("a")).await;
+        let pod = load(dir.path(), &config("b")).await;
+        tokio::fs::write(dir.path().join("pod.toml"), config("edited")).await.unwrap();
+
+        let previous = pod.config_history().await.unwrap().pop().unwrap();
+        assert!(matches!(
+            pod.rollback_config(&previous.name, LIMIT).await,
+            Err(PodConfigHistoryError::ConcurrentEdit { .. }),
+        ));
+        assert_eq!(tokio::fs::read_to_string(dir.path().join("pod.toml")).await.unwrap(), config("edited"));
+        assert!(pod.pending_config.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rollback_refuses_invalid_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let pod = load(dir.path(), &config("a")).await;
+        let name = "20240101T000000000Z-0011223344556677";
+        tokio::fs::write(pod.version_path(name), "id = 1").await.unwrap();
+
+        assert!(matches!(pod.rollback_config(name, LIMIT).await, Err(PodConfigHistoryError::InvalidVersion { .. })));
+        assert_eq!(tokio::fs::read_to_string(dir.path().join("pod.toml")).await.unwrap(), config("a"));
+    }
+}
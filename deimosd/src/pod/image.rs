@@ -0,0 +1,302 @@
+//! Validation of the Docker image referenced by a pod's config. A reference that cannot be parsed
+//! prevents the pod from loading, while a valid image that has not been pulled to the local
+//! Docker daemon only blocks the pod from being enabled unless its config allows the daemon to
+//! pull it
+
+use std::{collections::HashMap, sync::PoisonError};
+
+use bollard::{image::CreateImageOptions, secret::ImageInspect};
+use futures::StreamExt;
+
+use super::{config::PodPullPolicy, Pod, PodManager};
+
+/// Reason that a loaded pod cannot currently be enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodBlock {
+    /// The pod's image is not present on the local Docker daemon
+    ImageMissing,
+}
+
+/// Check that the given string is a valid Docker image reference of the form
+/// `[registry[:port]/]repository[:tag][@digest]`
+pub fn validate_image_reference(image: &str) -> Result<(), ImageReferenceError> {
+    /// Maximum length of the registry and repository name
+    const MAX_NAME: usize = 255;
+    /// Maximum length of a tag
+    const MAX_TAG: usize = 128;
+
+    if image.is_empty() {
+        return Err(ImageReferenceError::Empty)
+    }
+
+    let (rest, digest) = match image.split_once('@') {
+        Some((rest, digest)) => (rest, Some(digest)),
+        None => (image, None),
+    };
+
+    //A colon after the last slash separates the tag, any other colon is a registry port
+    let (name, tag) = match rest.rfind(':') {
+        Some(colon) if !rest[colon..].contains('/') => (&rest[..colon], Some(&rest[colon + 1..])),
+        _ => (rest, None),
+    };
+
+    if name.len() > MAX_NAME {
+        return Err(ImageReferenceError::NameTooLong)
+    }
+
+    let mut components = name.split('/').peekable();
+    let first = components.next().unwrap_or_default();
+    let is_domain = components.peek().is_some() && (first.contains(['.', ':']) || first == "localhost");
+    if is_domain {
+        validate_domain(first)?;
+    } else {
+        validate_path_component(first)?;
+    }
+
+    for component in components {
+        validate_path_component(component)?;
+    }
+
+    if let Some(tag) = tag {
+        let valid = tag.len() <= MAX_TAG
+            && tag.chars().next().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+            && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+        if !valid {
+            return Err(ImageReferenceError::InvalidTag(tag.to_owned()))
+        }
+    }
+
+    if let Some(digest) = digest {
+        let valid = digest
+            .split_once(':')
+            .is_some_and(|(algorithm, hex)| {
+                algorithm.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+                    && algorithm.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '.'))
+                    && hex.len() >= 32
+                    && hex.chars().all(|c| c.is_ascii_hexdigit())
+            });
+
+        if !valid {
+            return Err(ImageReferenceError::InvalidDigest(digest.to_owned()))
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a registry host with an optional port
+fn validate_domain(domain: &str) -> Result<(), ImageReferenceError> {
+    let (host, port) = match domain.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (domain, None),
+    };
+
+    let valid_host = host.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+
+    let valid_port = port.is_none_or(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+
+    match valid_host && valid_port {
+        true => Ok(()),
+        false => Err(ImageReferenceError::InvalidRegistry(domain.to_owned())),
+    }
+}
+
+/// Check a single slash-separated component of a repository name, consisting of lowercase
+/// alphanumeric runs joined by `.`, `_`, `__`, or any number of `-`
+fn validate_path_component(component: &str) -> Result<(), ImageReferenceError> {
+    let invalid = || ImageReferenceError::InvalidComponent(component.to_owned());
+    let is_alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+    let mut chars = component.chars().peekable();
+    if !chars.peek().copied().is_some_and(is_alnum) {
+        return Err(invalid())
+    }
+
+    while let Some(c) = chars.next() {
+        if is_alnum(c) {
+            continue
+        }
+
+        match c {
+            '.' => (),
+            '_' => {
+                chars.next_if_eq(&'_');
+            },
+            '-' => while chars.next_if_eq(&'-').is_some() {},
+            _ => return Err(invalid()),
+        }
+
+        //Separators must be followed by another alphanumeric run
+        if !chars.peek().copied().is_some_and(is_alnum) {
+            return Err(invalid())
+        }
+    }
+
+    Ok(())
+}
+
+impl Pod {
+    /// Get the reason that this pod cannot be enabled, if any
+    pub fn blocked(&self) -> Option<PodBlock> {
+        *self.blocked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Set or clear the reason that this pod cannot be enabled
+    pub(super) fn set_blocked(&self, block: Option<PodBlock>) {
+        let mut blocked = self.blocked.lock().unwrap_or_else(PoisonError::into_inner);
+        if *blocked != block {
+            match block {
+                Some(PodBlock::ImageMissing) => tracing::warn!(
+                    "Image '{}' of pod {} is not present locally - pull it before enabling the pod",
+                    self.config().docker.image,
+                    self.id(),
+                ),
+                None => tracing::info!("Pod {} is no longer blocked", self.id()),
+            }
+        }
+
+        *blocked = block;
+    }
+}
+
+impl PodManager {
+    /// Check if the given pod's image is present on the local Docker daemon, updating and
+    /// returning the pod's blocked state
+    pub async fn check_image(&self, pod: &Pod) -> Result<Option<PodBlock>, bollard::errors::Error> {
+        let block = match self.inspect_pod_image(pod).await? {
+            Some(..) => None,
+            None => Some(PodBlock::ImageMissing),
+        };
+
+        pod.set_blocked(block);
+        Ok(block)
+    }
+
+    /// Inspect the image that the given pod's image reference currently resolves to, recording
+    /// its ID in the pod's image history. Returns `None` if the image is not present locally
+    pub(super) async fn inspect_pod_image(&self, pod: &Pod) -> Result<Option<ImageInspect>, bollard::errors::Error> {
+        match self.docker.inspect_image(&pod.config().docker.image).await {
+            Ok(image) => {
+                if let Some(ref id) = image.id {
+                    self.record_image(pod, id);
+                }
+
+                pod.set_image_volumes(&image);
+
+                Ok(Some(image))
+            },
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check if the given pod's image is present, pulling it first if the pod's config requires
+    /// it, and update and return the pod's blocked state.
+    /// Images are pulled when missing if `pull` is set, and every time with the `always` pull
+    /// policy, in which case the local image is still used if the pull fails
+    pub async fn check_or_pull_image(&self, pod: &Pod) -> Result<Option<PodBlock>, PodImagePullError> {
+        let config = &pod.config().docker;
+        if config.pull_policy == PodPullPolicy::Always {
+            let Err(e) = self.pull_image(pod).await else {
+                return self.check_image(pod).await.map_err(PodImagePullError::Inspect)
+            };
+
+            return match self.check_image(pod).await.map_err(PodImagePullError::Inspect)? {
+                None => {
+                    tracing::warn!("{}, using the local image for pod {}", e, pod.id());
+                    Ok(None)
+                },
+                Some(..) => Err(e),
+            }
+        }
+
+        match self.check_image(pod).await.map_err(PodImagePullError::Inspect)? {
+            Some(PodBlock::ImageMissing) if config.pull => {
+                self.pull_image(pod).await?;
+                self.check_image(pod).await.map_err(PodImagePullError::Inspect)
+            },
+            block => Ok(block),
+        }
+    }
+
+    /// Pull the given pod's image from its registry, logging the progress of each layer
+    async fn pull_image(&self, pod: &Pod) -> Result<(), PodImagePullError> {
+        let image = pull_reference(&pod.config().docker.image);
+        tracing::info!("Pulling image '{}' for pod {}", image, pod.id());
+
+        let mut pull = self.docker.create_image(
+            Some(CreateImageOptions { from_image: image.clone(), ..Default::default() }),
+            None,
+            None,
+        );
+
+        //Progress of each layer is reported many times, only changes to its status are logged
+        let mut layers = HashMap::<String, String>::new();
+        while let Some(info) = pull.next().await {
+            let info = info.map_err(|err| PodImagePullError::Pull { image: image.clone(), err })?;
+            let Some(status) = info.status else {
+                continue
+            };
+
+            match info.id {
+                Some(layer) => if layers.get(&layer) != Some(&status) {
+                    tracing::info!("Pulling image '{}': {} {}", image, layer, status);
+                    layers.insert(layer, status);
+                },
+                None => tracing::info!("Pulling image '{}': {}", image, status),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the images of all pods after loading, pulling them if allowed and logging failures
+    /// to reach the Docker daemon or registry
+    pub(super) async fn check_images(&self) {
+        for pod in self.pods() {
+            if let Err(e) = self.check_or_pull_image(&pod).await {
+                tracing::error!("Failed to check image of pod {}: {}", pod.id(), e);
+            }
+        }
+    }
+}
+
+/// Get the reference to pull for the given image, adding the `latest` tag to references with no
+/// tag or digest as Docker would otherwise pull every tag of the repository
+fn pull_reference(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match image.contains('@') || name.contains(':') {
+        true => image.to_owned(),
+        false => format!("{}:latest", image),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodImagePullError {
+    #[error("Failed to inspect image: {0}")]
+    Inspect(#[source] bollard::errors::Error),
+    #[error("Failed to pull image '{image}': {err}")]
+    Pull { image: String, #[source] err: bollard::errors::Error },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageReferenceError {
+    #[error("Image reference is empty")]
+    Empty,
+    #[error("Image name exceeds the maximum length of 255 characters")]
+    NameTooLong,
+    #[error("Invalid registry '{0}'")]
+    InvalidRegistry(String),
+    #[error("Invalid repository name component '{0}' - components must be lowercase alphanumeric runs separated by '.', '_', '__', or '-'")]
+    InvalidComponent(String),
+    #[error("Invalid tag '{0}'")]
+    InvalidTag(String),
+    #[error("Invalid digest '{0}'")]
+    InvalidDigest(String),
+}
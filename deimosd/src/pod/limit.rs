@@ -0,0 +1,70 @@
+//! Limits on the number of pods and the ports they publish, so that a misconfigured pod
+//! directory cannot overwhelm the daemon, the gateway's UPnP table, or clients. The limit on
+//! ports forwarded with UPnP is enforced when pods are enabled, as only enabled pods hold leases
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::{config::PodManagerConfig, id::DeimosId, Pod, PodManager};
+
+/// Configured limits and current usage, reported to clients
+#[derive(Debug, Clone, Copy)]
+pub struct PodLimitUsage {
+    pub pods: usize,
+    pub max_pods: usize,
+    /// Number of distinct ports currently forwarded with UPnP for enabled pods
+    pub forwarded_ports: usize,
+    pub max_forwarded_ports: usize,
+    pub max_ports_per_pod: usize,
+}
+
+impl PodManager {
+    /// Reject loaded pods that publish more ports than allowed, then reject pods beyond the
+    /// maximum pod count in order of their IDs so that the same pods are kept on every start
+    pub(super) fn enforce_limits(config: &PodManagerConfig, mut pods: HashMap<DeimosId, Arc<Pod>>) -> HashMap<DeimosId, Arc<Pod>> {
+        pods.retain(|id, pod| {
+            let ports = pod.config().docker.port.len();
+            let allowed = ports <= config.max_ports_per_pod;
+            if !allowed {
+                tracing::error!(
+                    "Not loading pod {} as it publishes {} ports, exceeding max_ports_per_pod of {}",
+                    id,
+                    ports,
+                    config.max_ports_per_pod,
+                );
+            }
+
+            allowed
+        });
+
+        if pods.len() > config.max_pods {
+            let mut ids = pods.keys().cloned().collect::<Vec<_>>();
+            ids.sort_unstable_by(|a, b| str::cmp(a, b));
+
+            let rejected = ids.split_off(config.max_pods);
+            for id in rejected.iter() {
+                tracing::error!("Not loading pod {} as max_pods of {} pods are already loaded", id, config.max_pods);
+                pods.remove(id);
+            }
+
+            tracing::error!(
+                "Rejected {} of {} pods exceeding max_pods of {}",
+                rejected.len(),
+                rejected.len() + pods.len(),
+                config.max_pods,
+            );
+        }
+
+        pods
+    }
+
+    /// Get the configured limits and the current usage of each
+    pub fn limits(&self) -> PodLimitUsage {
+        PodLimitUsage {
+            pods: self.loaded().len(),
+            max_pods: self.config.max_pods,
+            forwarded_ports: self.upnp.pod_forwarded_ports(),
+            max_forwarded_ports: self.config.max_total_forwarded_ports,
+            max_ports_per_pod: self.config.max_ports_per_pod,
+        }
+    }
+}
@@ -7,16 +7,47 @@ use dashmap::DashMap;
 use futures::{
     stream::SelectAll, StreamExt
 };
+use annotate::PodAnnotationSender;
+use config::PodConfig;
+use docker::record::DockerEventRecorder;
+use depend::{PodDependencyError, PodDependencyRestarts, PodStartSender};
+use quota::PodDiskQuotaSupport;
+use reload::PodReloadFailure;
+use prune::PodImageHistory;
+use reserved::{PodPortConflict, ReservedPort};
 use id::{DeimosId, DockerId};
+use tokio::sync::Notify;
 
 use crate::server::upnp::Upnp;
 
+pub mod alert;
+pub mod annotate;
+pub mod archive;
+pub mod artwork;
+pub mod bounded;
 pub mod docker;
 pub mod id;
 pub mod config;
+pub mod depend;
+pub mod directive;
+pub mod env;
+pub mod health;
+pub mod history;
+pub mod image;
+pub mod limit;
+pub mod prune;
+pub mod quota;
+pub mod reload;
+pub mod reserved;
+pub mod resources;
+pub mod restart;
+pub mod schedule;
 pub mod state;
+pub mod timed;
+pub mod validate;
+pub mod volume;
 
-pub use state::{Pod,  PodState, PodStateKnown};
+pub use state::{Pod,  PodState, PodStateCause, PodStateKnown};
 pub use config::{DockerConnectionConfig, DockerConnectionType, PodManagerConfig};
 
 /// Manager responsible for orchestrating Docker containers and watching for external events and
@@ -25,13 +56,36 @@ pub struct PodManager {
     config: PodManagerConfig,
     docker: Docker,
     upnp: Upnp,
-    pods: HashMap<DeimosId, Arc<Pod>>,
+    /// Loaded pods, replaced when the pod directory is reloaded
+    pods: std::sync::RwLock<HashMap<DeimosId, Arc<Pod>>>,
     reverse_lookup: ReversePodLookup,
+    /// Notified when the deadline of a timed enable is set or cleared
+    timed: Notify,
+    /// Forwards annotations reported from inside pod containers
+    annotations: PodAnnotationSender,
+    /// If the Docker storage driver can enforce pod disk limits
+    disk_quota: PodDiskQuotaSupport,
+    /// Ports used by the daemon's own listeners, which pods may not publish
+    reserved: Vec<ReservedPort>,
+    /// IDs of the images that each pod has used, newest first
+    images: std::sync::Mutex<PodImageHistory>,
+    /// Forwards the IDs of pods when their container is created and started
+    starts: PodStartSender,
+    /// Recent restarts of pods caused by a restart of their dependencies
+    restarts: std::sync::Mutex<PodDependencyRestarts>,
+    /// Held while the pod directory is being reloaded
+    reloading: tokio::sync::Mutex<()>,
+    /// Notified when pods are added or removed by a reload
+    reloaded: Notify,
+    /// Notified when the directive of a pod changes or its restart breaker opens
+    directives: Notify,
+    /// Records events received from Docker and how they were handled, if enabled
+    events: DockerEventRecorder,
 }
 
 type ReversePodLookup = Arc<DashMap<DockerId, Arc<Pod>>>;
 
-pub type PodStateStreamMapper = dyn FnMut(PodState) -> (DeimosId, PodState) + Send + Sync;
+pub type PodStateStreamMapper = dyn FnMut(PodState) -> (DeimosId, PodState, PodStateCause) + Send + Sync;
 pub type PodStateStream = SelectAll<
     futures::stream::Map<
         tokio_stream::wrappers::WatchStream<PodState>,
@@ -43,7 +97,57 @@ impl PodManager {
     /// Load a config TOML file from the given path, and use the options specified inside to
     /// create a connection to the local Docker server, then load all pods from the directory
     /// given.
-    pub async fn new(config: PodManagerConfig, upnp: Upnp) -> Result<Self, PodManagerInitError> {
+    /// Pods publishing one of the given reserved ports are reported, and loading fails if any of
+    /// these ports are forwarded with UPnP
+    pub async fn new(
+        config: PodManagerConfig,
+        upnp: Upnp,
+        reserved: Vec<ReservedPort>,
+        events: DockerEventRecorder,
+    ) -> Result<Self, PodManagerInitError> {
+        let docker = Self::connect_docker(&config).await?;
+        tracing::info!("Connected to Docker daemon {}", docker.client_version());
+        let disk_quota = PodDiskQuotaSupport::probe(&docker).await;
+
+        let (pods, _) = Self::load_containers(&config.containerdir, config.config_history).await?;
+        let pods = Self::enforce_limits(&config, pods);
+        if pods.is_empty() {
+            tracing::warn!("Starting pod manager with no pods configured");
+        }
+
+        let reverse_lookup = Arc::new(DashMap::with_capacity(pods.len()));
+        let event_capacity = config.event_capacity.max(1);
+
+        let this = Self {
+            config,
+            docker,
+            upnp,
+            pods: std::sync::RwLock::new(pods),
+            reverse_lookup,
+            timed: Notify::new(),
+            annotations: tokio::sync::broadcast::channel(event_capacity).0,
+            disk_quota,
+            reserved,
+            images: Default::default(),
+            starts: tokio::sync::broadcast::channel(event_capacity).0,
+            restarts: Default::default(),
+            reloading: Default::default(),
+            reloaded: Notify::new(),
+            directives: Notify::new(),
+            events,
+        };
+
+        this.check_reserved_ports()?;
+        Self::check_dependencies(&this.configs())?;
+        this.check_images().await;
+        this.check_disk_quotas();
+
+        Ok(this)
+    }
+
+    /// Connect to the Docker daemon given in the config, or the local default daemon if none is
+    /// given, and negotiate the API version to use with it
+    pub async fn connect_docker(config: &PodManagerConfig) -> Result<Docker, PodManagerInitError> {
         let docker = match config.docker {
             None => Docker::connect_with_local_defaults().map(|docker| {
                 docker.with_timeout(Duration::from_secs(
@@ -64,31 +168,16 @@ impl PodManager {
             },
         }?;
 
-        let docker = docker.negotiate_version().await?;
-        tracing::info!("Connected to Docker daemon {}", docker.client_version());
-
-        let pods = Self::load_containers(&config.containerdir).await?;
-        if pods.is_empty() {
-            tracing::warn!("Starting pod manager with no pods configured");
-        }
-
-        let reverse_lookup = Arc::new(DashMap::with_capacity(pods.len()));
-
-        Ok(Self {
-            config,
-            docker,
-            upnp,
-            pods,
-            reverse_lookup,
-        })
+        docker.negotiate_version().await.map_err(Into::into)
     }
 
     /// Get a stream of state changes made to containers, with their associated ID
     pub fn stream(&self) -> PodStateStream {
-        let iter = self.pods.values().cloned().map(|pod| {
+        let iter = self.pods().into_iter().map(|pod| {
             let id = pod.id();
+            let cause = pod.clone();
             pod.state().subscribe().map(Box::<PodStateStreamMapper>::from(
-                Box::new(move |state| (id.clone(), state)),
+                Box::new(move |state| (id.clone(), state, cause.cause())),
             ))
         });
 
@@ -97,15 +186,32 @@ impl PodManager {
 
     /// Get a reference to the pod with the given ID
     pub fn get(&self, id: &str) -> Option<Arc<Pod>> {
-        self.pods.get(id).cloned()
+        self.loaded().get(id).cloned()
+    }
+
+    /// Get all loaded pods, which do not change if pods are reloaded while they are in use
+    pub fn pods(&self) -> Vec<Arc<Pod>> {
+        self.loaded().values().cloned().collect()
+    }
+
+    /// Get the current configuration of every loaded pod
+    fn configs(&self) -> HashMap<DeimosId, Arc<PodConfig>> {
+        self.loaded().iter().map(|(id, pod)| (id.clone(), pod.config())).collect()
+    }
+
+    /// Lock the map of loaded pods for reading, the lock must not be held across an await
+    fn loaded(&self) -> std::sync::RwLockReadGuard<'_, HashMap<DeimosId, Arc<Pod>>> {
+        self.pods.read().unwrap_or_else(std::sync::PoisonError::into_inner)
     }
     
     /// Load all containers from directory entries in the given containers directory,
     /// logging errors and ignoring on failure
-    async fn load_containers(
+    pub async fn load_containers(
         dir: &Path,
-    ) -> Result<HashMap<DeimosId, Arc<Pod>>, PodManagerInitError> {
-        let mut pods = HashMap::new();
+        history: usize,
+    ) -> Result<(HashMap<DeimosId, Arc<Pod>>, Vec<PodReloadFailure>), PodManagerInitError> {
+        let mut pods = HashMap::<DeimosId, Arc<Pod>>::new();
+        let mut failures = Vec::new();
 
         let mut iter =
             tokio::fs::read_dir(dir)
@@ -132,12 +238,28 @@ impl PodManager {
             let path = entry.path();
 
             match entry.file_type().await {
-                Ok(ft) if ft.is_dir() => match Pod::load(&entry.path()).await {
-                    Ok(pod) => {
-                        pods.insert(pod.id(), Arc::new(pod));
-                    }
+                Ok(ft) if ft.is_dir() => match Pod::load(&entry.path(), history).await {
+                    Ok(pod) => match pods.get(&pod.id()) {
+                        Some(exist) => {
+                            tracing::error!(
+                                "Pod loaded from {} has the same ID {} as existing pod '{}' - ignoring it",
+                                path.display(),
+                                pod.id(),
+                                exist.title(),
+                            );
+
+                            failures.push(PodReloadFailure {
+                                message: format!("Pod ID {} is already used by pod '{}'", pod.id(), exist.title()),
+                                path,
+                            });
+                        },
+                        None => {
+                            pods.insert(pod.id(), Arc::new(pod));
+                        }
+                    },
                     Err(e) => {
                         tracing::error!("Failed to load container from {}: {}", path.display(), e);
+                        failures.push(PodReloadFailure { path, message: e.to_string() });
                     }
                 },
                 Ok(..) => {
@@ -152,25 +274,29 @@ impl PodManager {
                         path.display(),
                         e
                     );
+                    failures.push(PodReloadFailure { path, message: format!("Failed to get file type: {}", e) });
                 }
             }
         }
 
-        Ok(pods)
-    }
-
-    /// Get an immutable iterator over references to the managed pods
-    pub fn iter(&self) -> impl Iterator<Item = (&DeimosId, &Arc<Pod>)> {
-        self.pods.iter()
+        Ok((pods, failures))
     }
-}
 
-impl<'a> IntoIterator for &'a PodManager {
-    type Item = (&'a DeimosId, &'a Arc<Pod>);
-    type IntoIter = std::collections::hash_map::Iter<'a, DeimosId, Arc<Pod>>;
+    /// Check if the Docker daemon responds to a ping within a short timeout
+    pub async fn docker_reachable(&self) -> bool {
+        const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.pods.iter()
+        match tokio::time::timeout(PING_TIMEOUT, self.docker.ping()).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                tracing::debug!("Docker ping failed: {}", e);
+                false
+            },
+            Err(_) => {
+                tracing::debug!("Docker ping timed out after {:?}", PING_TIMEOUT);
+                false
+            }
+        }
     }
 }
 
@@ -180,4 +306,8 @@ pub enum PodManagerInitError {
     Docker(#[from] bollard::errors::Error),
     #[error("Failed to read entries from pod directory {}: {}", path.display(), err)]
     PodRead { path: PathBuf, err: std::io::Error },
+    #[error("{0}")]
+    PortConflict(#[from] PodPortConflict),
+    #[error("{0}")]
+    Dependency(#[from] PodDependencyError),
 }
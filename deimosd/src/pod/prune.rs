@@ -0,0 +1,269 @@
+//! Removal of images that pods used before their image was updated. The ID of every image that
+//! a pod's image reference resolves to is recorded, and only recorded images that are no longer
+//! referenced by any pod or container and have no remaining tags are removed, so images pulled
+//! and tagged by the user are never touched
+
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::PoisonError, time::Duration};
+
+use bollard::{container::ListContainersOptions, image::RemoveImageOptions};
+
+use super::{Pod, PodManager};
+
+/// Map of pod IDs to the IDs of images they have used, newest first
+pub type PodImageHistory = HashMap<String, Vec<String>>;
+
+/// An image removed by a prune, or that would be removed if the prune was not a dry run
+#[derive(Debug, Clone)]
+pub struct PrunedImage {
+    pub id: String,
+    /// IDs of the pods that used the image
+    pub pods: Vec<String>,
+    /// Size of the image in bytes, including layers that may be shared with other images
+    pub size: u64,
+}
+
+impl PodManager {
+    /// Remove every recorded image that is not referenced by a pod or container, keeping the
+    /// configured number of previous images for each pod. Images that still have a tag are
+    /// skipped. If `dry_run` is set, the images that would be removed are returned without
+    /// removing them
+    pub async fn prune_images(&self, dry_run: bool) -> Result<Vec<PrunedImage>, bollard::errors::Error> {
+        let mut referenced = HashSet::new();
+        for pod in self.pods() {
+            if let Some(id) = self.inspect_pod_image(&pod).await?.and_then(|image| image.id) {
+                referenced.insert(id);
+            }
+        }
+
+        let containers = self
+            .docker
+            .list_containers(Some(ListContainersOptions::<String> { all: true, ..Default::default() }))
+            .await?;
+        referenced.extend(containers.into_iter().filter_map(|container| container.image_id));
+
+        let candidates = self.prune_candidates(&referenced);
+        let mut pruned = Vec::with_capacity(candidates.len());
+
+        for (id, pods) in candidates {
+            let image = match self.docker.inspect_image(&id).await {
+                Ok(image) => image,
+                Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {
+                    tracing::trace!("Recorded image {} was already removed", id);
+                    self.forget_image(&id);
+                    continue
+                },
+                Err(e) => {
+                    tracing::warn!("Failed to inspect image {} for pruning: {}", id, e);
+                    continue
+                }
+            };
+
+            //A tagged image can still be used by name, and may have been tagged by the user
+            if is_tagged(image.repo_tags.as_deref()) {
+                tracing::trace!("Not pruning image {} as it is still tagged", id);
+                continue
+            }
+
+            if !dry_run {
+                let options = RemoveImageOptions { force: false, noprune: false };
+                if let Err(e) = self.docker.remove_image(&id, Some(options), None).await {
+                    tracing::warn!("Failed to remove unused image {}: {}", id, e);
+                    continue
+                }
+
+                tracing::info!("Removed image {} no longer used by pods {}", id, pods.join(", "));
+                self.forget_image(&id);
+            }
+
+            pruned.push(
+                PrunedImage {
+                    id,
+                    pods,
+                    size: image.size.unwrap_or_default().max(0) as u64,
+                }
+            );
+        }
+
+        Ok(pruned)
+    }
+
+    /// Get the interval between automatic prunes, if they are enabled
+    pub fn image_prune_interval(&self) -> Option<Duration> {
+        self.config.image_prune.interval.map(|secs| Duration::from_secs(secs.max(1)))
+    }
+
+    /// Get every recorded image that is not referenced and is not one of the most recent
+    /// previous images kept for a pod, with the IDs of the pods that used it
+    fn prune_candidates(&self, referenced: &HashSet<String>) -> BTreeMap<String, Vec<String>> {
+        let images = self.images.lock().unwrap_or_else(PoisonError::into_inner);
+        prune_candidates(&images, referenced, self.config.image_prune.retain)
+    }
+
+    /// Record that the given pod's image reference resolves to the image with the given ID
+    pub(super) fn record_image(&self, pod: &Pod, id: &str) {
+        let mut images = self.images.lock().unwrap_or_else(PoisonError::into_inner);
+        record_image(&mut images, pod.id().owned(), id);
+    }
+
+    /// Remove the image with the given ID from the history of every pod
+    fn forget_image(&self, id: &str) {
+        let mut images = self.images.lock().unwrap_or_else(PoisonError::into_inner);
+        forget_image(&mut images, id);
+    }
+
+    /// Get the image history of all pods to be written to the save file
+    pub(super) fn image_history(&self) -> PodImageHistory {
+        self.images.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Restore image history from persistent state, after the images that pods currently use
+    /// were recorded when they were loaded
+    pub(super) fn restore_image_history(&self, persistent: PodImageHistory) {
+        let mut images = self.images.lock().unwrap_or_else(PoisonError::into_inner);
+        restore_image_history(&mut images, persistent);
+    }
+}
+
+/// Get every image in the history that is not referenced and is not one of the `retain` most
+/// recent previous images of a pod, with the IDs of the pods that used it. Only images present in
+/// the history are ever returned
+fn prune_candidates(images: &PodImageHistory, referenced: &HashSet<String>, retain: usize) -> BTreeMap<String, Vec<String>> {
+    let mut retained = HashSet::new();
+    let mut candidates = BTreeMap::<String, Vec<String>>::new();
+
+    for (pod, history) in images.iter() {
+        let mut previous = history.iter().filter(|id| !referenced.contains(*id));
+        retained.extend(previous.by_ref().take(retain).cloned());
+        for id in previous {
+            candidates.entry(id.clone()).or_default().push(pod.clone());
+        }
+    }
+
+    candidates.retain(|id, _| !retained.contains(id));
+    for pods in candidates.values_mut() {
+        pods.sort();
+    }
+
+    candidates
+}
+
+/// Check if an image has any tag other than the placeholder Docker reports for untagged images
+fn is_tagged(tags: Option<&[String]>) -> bool {
+    tags.into_iter().flatten().any(|tag| tag != "<none>:<none>")
+}
+
+/// Move the image with the given ID to the front of a pod's history
+fn record_image(images: &mut PodImageHistory, pod: String, id: &str) {
+    let history = images.entry(pod).or_default();
+    if history.first().is_some_and(|newest| newest == id) {
+        return
+    }
+
+    history.retain(|exist| exist != id);
+    history.insert(0, id.to_owned());
+}
+
+/// Remove the image with the given ID from the history of every pod, dropping pods that are left
+/// with no history
+fn forget_image(images: &mut PodImageHistory, id: &str) {
+    images.retain(|_, history| {
+        history.retain(|exist| exist != id);
+        !history.is_empty()
+    });
+}
+
+/// Append persisted history after the images already recorded for each pod
+fn restore_image_history(images: &mut PodImageHistory, persistent: PodImageHistory) {
+    for (pod, previous) in persistent {
+        let history = images.entry(pod).or_default();
+        for id in previous {
+            if !history.contains(&id) {
+                history.push(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn history(entries: &[(&str, &[&str])]) -> PodImageHistory {
+        entries
+            .iter()
+            .map(|(pod, ids)| (pod.to_string(), ids.iter().map(|id| id.to_string()).collect()))
+            .collect()
+    }
+
+    fn referenced(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn unrecorded_images_are_never_candidates() {
+        let images = history(&[("web", &["sha256:b", "sha256:a"])]);
+        let candidates = prune_candidates(&images, &referenced(&["sha256:b"]), 0);
+        assert_eq!(candidates.keys().collect::<Vec<_>>(), ["sha256:a"]);
+
+        assert!(prune_candidates(&PodImageHistory::new(), &HashSet::new(), 0).is_empty());
+    }
+
+    #[test]
+    fn referenced_images_are_kept() {
+        let images = history(&[("web", &["sha256:c", "sha256:b", "sha256:a"])]);
+        let candidates = prune_candidates(&images, &referenced(&["sha256:c", "sha256:a"]), 0);
+        assert_eq!(candidates.keys().collect::<Vec<_>>(), ["sha256:b"]);
+    }
+
+    #[test]
+    fn newest_previous_images_are_retained() {
+        let images = history(&[("web", &["sha256:d", "sha256:c", "sha256:b", "sha256:a"])]);
+        let candidates = prune_candidates(&images, &referenced(&["sha256:d"]), 2);
+        assert_eq!(candidates.keys().collect::<Vec<_>>(), ["sha256:a"]);
+
+        assert!(prune_candidates(&images, &referenced(&["sha256:d"]), 3).is_empty());
+    }
+
+    #[test]
+    fn image_retained_by_one_pod_is_kept_for_all() {
+        let images = history(&[
+            ("web", &["sha256:c", "sha256:b"]),
+            ("db", &["sha256:d", "sha256:c", "sha256:b"]),
+        ]);
+
+        let candidates = prune_candidates(&images, &referenced(&["sha256:c", "sha256:d"]), 1);
+        assert!(candidates.is_empty());
+
+        let candidates = prune_candidates(&images, &referenced(&["sha256:d"]), 0);
+        assert_eq!(candidates["sha256:b"], ["db", "web"]);
+        assert_eq!(candidates["sha256:c"], ["db", "web"]);
+    }
+
+    #[test]
+    fn tagged_images_are_skipped() {
+        assert!(!is_tagged(None));
+        assert!(!is_tagged(Some(&[])));
+        assert!(!is_tagged(Some(&["<none>:<none>".to_owned()])));
+        assert!(is_tagged(Some(&["alpine:3".to_owned()])));
+        assert!(is_tagged(Some(&["<none>:<none>".to_owned(), "mine:latest".to_owned()])));
+    }
+
+    #[test]
+    fn history_records_newest_first() {
+        let mut images = PodImageHistory::new();
+        record_image(&mut images, "web".to_owned(), "sha256:a");
+        record_image(&mut images, "web".to_owned(), "sha256:b");
+        record_image(&mut images, "web".to_owned(), "sha256:b");
+        record_image(&mut images, "web".to_owned(), "sha256:a");
+        assert_eq!(images["web"], ["sha256:a", "sha256:b"]);
+
+        restore_image_history(&mut images, history(&[("web", &["sha256:b", "sha256:c"]), ("db", &["sha256:d"])]));
+        assert_eq!(images["web"], ["sha256:a", "sha256:b", "sha256:c"]);
+        assert_eq!(images["db"], ["sha256:d"]);
+
+        forget_image(&mut images, "sha256:d");
+        forget_image(&mut images, "sha256:b");
+        assert!(!images.contains_key("db"));
+        assert_eq!(images["web"], ["sha256:a", "sha256:c"]);
+    }
+}
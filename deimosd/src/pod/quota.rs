@@ -0,0 +1,230 @@
+//! Limits on the size of a pod's writable container layer. Docker only enforces the `size`
+//! storage option for some storage drivers and rejects it for others, so the daemon's storage
+//! driver is probed once at startup and the limit is omitted with a warning when it cannot be
+//! enforced
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use bollard::{container::InspectContainerOptions, Docker};
+
+use super::{config::{PodAlertConfig, PodAlertMetric, PodConfig}, id::DockerId, PodManager};
+
+/// Maximum size of a pod's writable container layer in bytes, parsed from a number of bytes or
+/// a string with a binary unit suffix such as `"10g"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PodDiskLimit(u64);
+
+/// Whether the Docker daemon's storage driver can enforce a [PodDiskLimit]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PodDiskQuotaSupport {
+    Supported,
+    /// The storage driver with the given name and backing filesystem cannot enforce size limits
+    Unsupported { driver: String, backing: Option<String> },
+    /// The storage driver could not be determined
+    Unknown,
+}
+
+impl PodDiskLimit {
+    /// Usage in percent of the limit at which an alert is raised if the pod has no other disk
+    /// alert rule
+    pub const ALERT_THRESHOLD: f64 = 90.;
+
+    pub const fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl PodDiskQuotaSupport {
+    /// Determine if the storage driver with the given name and backing filesystem, as reported
+    /// by `docker info`, can enforce the `size` storage option
+    pub fn of(driver: &str, backing: Option<&str>) -> Self {
+        let supported = match driver {
+            //Also requires the filesystem to be mounted with pquota, which is not reported
+            "overlay2" => backing == Some("xfs"),
+            "btrfs" | "zfs" | "devicemapper" | "windowsfilter" => true,
+            _ => false,
+        };
+
+        match supported {
+            true => Self::Supported,
+            false => Self::Unsupported { driver: driver.to_owned(), backing: backing.map(str::to_owned) },
+        }
+    }
+
+    /// Query the storage driver of the given Docker daemon
+    pub async fn probe(docker: &Docker) -> Self {
+        let info = match docker.info().await {
+            Ok(info) => info,
+            Err(e) => {
+                tracing::warn!("Failed to query Docker storage driver, pod disk limits will not be applied: {}", e);
+                return Self::Unknown
+            }
+        };
+
+        let Some(driver) = info.driver else {
+            return Self::Unknown
+        };
+
+        let backing = info
+            .driver_status
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|status| match status.as_slice() {
+                [key, value] if key == "Backing Filesystem" => Some(value.clone()),
+                _ => None,
+            });
+
+        Self::of(&driver, backing.as_deref())
+    }
+
+    pub const fn is_supported(&self) -> bool {
+        matches!(self, Self::Supported)
+    }
+}
+
+impl PodConfig {
+    /// Add an alert rule for the pod's disk usage approaching its limit if a limit is set and the
+    /// config does not already have a disk alert rule
+    pub(super) fn add_disk_alert(&mut self) {
+        if self.docker.disk_limit.is_none() || self.alerts.iter().any(|rule| rule.metric == PodAlertMetric::Disk) {
+            return
+        }
+
+        self.alerts.push(PodAlertConfig {
+            metric: PodAlertMetric::Disk,
+            threshold: PodDiskLimit::ALERT_THRESHOLD,
+            clear: None,
+            sustained: 0,
+        });
+    }
+}
+
+impl PodManager {
+    /// Warn about every pod with a disk limit that the Docker daemon cannot enforce
+    pub(super) fn check_disk_quotas(&self) {
+        let limited = self
+            .pods()
+            .into_iter()
+            .filter_map(|pod| Some((pod.config().docker.disk_limit?, pod)));
+
+        for (limit, pod) in limited {
+            match self.disk_quota {
+                PodDiskQuotaSupport::Supported => (),
+                PodDiskQuotaSupport::Unsupported { ref driver, ref backing } => tracing::warn!(
+                    "Pod {} sets a disk_limit of {} but the Docker storage driver {} on {} cannot enforce it - the limit will not be applied",
+                    pod.id(),
+                    limit,
+                    driver,
+                    backing.as_deref().unwrap_or("an unknown filesystem"),
+                ),
+                PodDiskQuotaSupport::Unknown => tracing::warn!(
+                    "Pod {} sets a disk_limit of {} but the Docker storage driver is unknown - the limit will not be applied",
+                    pod.id(),
+                    limit,
+                ),
+            }
+        }
+    }
+
+    /// Get the storage options applying the given disk limit if the storage driver supports it
+    pub(super) fn storage_opt(&self, limit: Option<PodDiskLimit>) -> Option<HashMap<String, String>> {
+        limit
+            .filter(|_| self.disk_quota.is_supported())
+            .map(|limit| HashMap::from([(String::from("size"), limit.bytes().to_string())]))
+    }
+
+    /// Get the size of the given container's writable layer in percent of the given limit
+    pub(super) async fn sample_disk(&self, container: &DockerId, limit: PodDiskLimit) -> Result<Option<f64>, bollard::errors::Error> {
+        let response = self
+            .docker
+            .inspect_container(container, Some(InspectContainerOptions { size: true }))
+            .await?;
+
+        Ok(
+            response
+                .size_rw
+                .filter(|_| limit.bytes() != 0)
+                .map(|size| size.max(0) as f64 / limit.bytes() as f64 * 100.)
+        )
+    }
+}
+
+impl FromStr for PodDiskLimit {
+    type Err = PodDiskLimitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let number = number.parse::<u64>().map_err(|_| PodDiskLimitParseError::InvalidNumber(s.to_owned()))?;
+        let shift = match unit.trim().to_ascii_lowercase().trim_end_matches(['b', 'i']) {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            _ => return Err(PodDiskLimitParseError::InvalidUnit(unit.to_owned())),
+        };
+
+        number
+            .checked_mul(1 << shift)
+            .filter(|bytes| *bytes != 0)
+            .map(Self)
+            .ok_or_else(|| PodDiskLimitParseError::OutOfRange(s.to_owned()))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PodDiskLimit {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LimitVisitor;
+
+        impl serde::de::Visitor<'_> for LimitVisitor {
+            type Value = PodDiskLimit;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes or a size such as \"10g\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                match v {
+                    0 => Err(E::custom(PodDiskLimitParseError::OutOfRange(v.to_string()))),
+                    v => Ok(PodDiskLimit(v)),
+                }
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u64::try_from(v)
+                    .map_err(|_| E::custom(PodDiskLimitParseError::OutOfRange(v.to_string())))
+                    .and_then(|v| self.visit_u64(v))
+            }
+        }
+
+        deserializer.deserialize_any(LimitVisitor)
+    }
+}
+
+impl fmt::Display for PodDiskLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(u32, &str); 4] = [(40, "TiB"), (30, "GiB"), (20, "MiB"), (10, "KiB")];
+
+        match UNITS.iter().find(|(shift, _)| self.0 >= 1 << shift && self.0.is_multiple_of(1 << shift)) {
+            Some((shift, unit)) => write!(f, "{}{}", self.0 >> shift, unit),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodDiskLimitParseError {
+    #[error("Invalid disk limit '{0}' - expected a number followed by an optional unit such as \"10g\"")]
+    InvalidNumber(String),
+    #[error("Unknown disk limit unit '{0}' - expected one of k, m, g, or t")]
+    InvalidUnit(String),
+    #[error("Disk limit '{0}' must be greater than zero and less than 16EiB")]
+    OutOfRange(String),
+}
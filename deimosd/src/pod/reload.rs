@@ -0,0 +1,182 @@
+//! Reloading of the pod directory while the daemon runs. Directories added since pods were loaded
+//! become new pods, and pods whose directory was removed are disabled and dropped. A pod whose
+//! config file changed keeps running with its current config, and the changed config is applied
+//! when a container is next created for it
+
+use std::{path::PathBuf, sync::{Arc, PoisonError}};
+
+use super::{alert::PodAlertState, config::PodConfig, depend::PodDependencyError, history::PodConfigHash, id::DeimosId, Pod, PodManager, PodManagerInitError, PodStateCause, PodStateKnown};
+
+/// Changes made to the loaded pods by reloading the pod directory
+#[derive(Debug, Default)]
+pub struct PodReloadSummary {
+    /// Pods loaded from directories added to the pod directory
+    pub added: Vec<DeimosId>,
+    /// Pods that were disabled and dropped as their directory was removed
+    pub removed: Vec<DeimosId>,
+    /// Pods with a changed config file, which is applied when each pod is next enabled
+    pub updated: Vec<DeimosId>,
+    /// Entries of the pod directory that could not be loaded or applied
+    pub errors: Vec<PodReloadFailure>,
+}
+
+/// An entry of the pod directory that could not be loaded, or a change that could not be applied.
+/// A pod previously loaded from the entry is left unchanged
+#[derive(Debug, Clone)]
+pub struct PodReloadFailure {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl Pod {
+    /// Stage a config loaded from the pod's changed config file, replacing any staged config
+    pub(super) fn stage_config(&self, config: Arc<PodConfig>, hash: PodConfigHash) {
+        *self.config_hash.lock().unwrap_or_else(PoisonError::into_inner) = hash;
+        *self.pending_config.lock().unwrap_or_else(PoisonError::into_inner) = Some(config);
+    }
+
+    /// Apply the config staged by a reload, if any, resetting the state of the pod's alert rules.
+    /// Must only be called while the pod is disabled and its state is locked
+    pub(super) fn apply_pending_config(&self) {
+        let Some(config) = self.pending_config.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+            return
+        };
+
+        *self.alerts.lock().unwrap_or_else(PoisonError::into_inner) = vec![PodAlertState::default(); config.alerts.len()];
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+        tracing::info!("Applied changed config of pod {}", self.id());
+    }
+}
+
+impl PodManager {
+    /// Load the pod directory again, adding and removing pods to match it and staging changed
+    /// configs of existing pods. Limits, port conflicts, and dependencies are checked as when
+    /// the daemon starts, and no pods are changed if the reloaded dependencies are invalid
+    pub async fn reload(&self) -> Result<PodReloadSummary, PodReloadError> {
+        let _reloading = self.reloading.lock().await;
+
+        let (mut loaded, errors) = Self::load_containers(&self.config.containerdir, self.config.config_history).await?;
+        let mut summary = PodReloadSummary { errors, ..Default::default() };
+
+        let mut removed = Vec::new();
+        let mut updated = Vec::new();
+        for pod in self.pods() {
+            match loaded.remove(&pod.id()) {
+                Some(reloaded) if reloaded.dir == pod.dir && reloaded.config_path == pod.config_path => {
+                    if reloaded.config_hash() != pod.config_hash() {
+                        updated.push((pod, reloaded));
+                    }
+                },
+                //A pod moved to another directory is replaced by the pod loaded from it
+                Some(reloaded) => {
+                    removed.push(pod);
+                    loaded.insert(reloaded.id(), reloaded);
+                },
+                None if summary.errors.iter().any(|failure| failure.path == pod.dir) => (),
+                None => removed.push(pod),
+            }
+        }
+
+        let mut added = loaded.into_values().collect::<Vec<_>>();
+        added.sort_unstable_by(|a, b| str::cmp(&a.id(), &b.id()));
+
+        updated.retain(|(_, reloaded)| self.check_reload_limits(reloaded, &mut summary.errors));
+        added.retain(|reloaded| self.check_reload_limits(reloaded, &mut summary.errors));
+
+        let kept = self.loaded().len() - removed.len();
+        for rejected in added.split_off(self.config.max_pods.saturating_sub(kept).min(added.len())) {
+            summary.errors.push(PodReloadFailure {
+                path: rejected.dir.clone(),
+                message: format!("Not loading pod {} as max_pods of {} pods are already loaded", rejected.id(), self.config.max_pods),
+            });
+        }
+
+        let mut configs = self.configs();
+        for pod in removed.iter() {
+            configs.remove(&pod.id());
+        }
+
+        for reloaded in updated.iter().map(|(_, reloaded)| reloaded).chain(added.iter()) {
+            configs.insert(reloaded.id(), reloaded.config());
+        }
+
+        Self::check_dependencies(&configs)?;
+
+        for pod in removed {
+            let mut lock = pod.state().transact().await;
+            lock.set_cause(PodStateCause::Requested);
+            match self.disable(pod.clone(), lock).await {
+                Ok(()) => {
+                    self.pods.write().unwrap_or_else(PoisonError::into_inner).remove(&pod.id());
+                    tracing::info!("Removed pod {} as its directory {} was removed", pod.id(), pod.dir.display());
+                    summary.removed.push(pod.id());
+                },
+                Err(e) => summary.errors.push(PodReloadFailure {
+                    path: pod.dir.clone(),
+                    message: format!("Failed to disable removed pod {}: {}", pod.id(), e),
+                }),
+            }
+        }
+
+        for (pod, reloaded) in updated {
+            let lock = pod.state().transact().await;
+            let _history = pod.history_lock.lock().await;
+            pod.stage_config(reloaded.config(), reloaded.config_hash());
+            match lock.state() {
+                PodStateKnown::Disabled => pod.apply_pending_config(),
+                _ => tracing::info!("Config of pod {} changed and will be applied when it is next enabled", pod.id()),
+            }
+
+            summary.updated.push(pod.id());
+        }
+
+        for pod in added {
+            self.pods.write().unwrap_or_else(PoisonError::into_inner).insert(pod.id(), pod.clone());
+            tracing::info!("Added pod {} from {}", pod.id(), pod.dir.display());
+            if let Err(e) = self.check_or_pull_image(&pod).await {
+                tracing::error!("Failed to check image of pod {}: {}", pod.id(), e);
+            }
+
+            summary.added.push(pod.id());
+        }
+
+        if !summary.added.is_empty() || !summary.removed.is_empty() {
+            self.reloaded.notify_waiters();
+        }
+
+        Ok(summary)
+    }
+
+    /// Wait until pods are added or removed by a reload
+    pub async fn reloaded(&self) {
+        self.reloaded.notified().await
+    }
+
+    /// Check a pod loaded by a reload against the port limit and the daemon's listeners,
+    /// recording a failure if it may not be loaded
+    fn check_reload_limits(&self, pod: &Pod, errors: &mut Vec<PodReloadFailure>) -> bool {
+        let ports = pod.config().docker.port.len();
+        let message = match ports <= self.config.max_ports_per_pod {
+            true => self.check_pod_ports(pod).err().map(|e| e.to_string()),
+            false => Some(format!("Pod {} publishes {} ports, exceeding max_ports_per_pod of {}", pod.id(), ports, self.config.max_ports_per_pod)),
+        };
+
+        match message {
+            Some(message) => {
+                tracing::error!("Not reloading pod from {}: {}", pod.dir.display(), message);
+                errors.push(PodReloadFailure { path: pod.dir.clone(), message });
+                false
+            },
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodReloadError {
+    #[error("{0}")]
+    Load(#[from] PodManagerInitError),
+    #[error("{0}")]
+    Dependency(#[from] PodDependencyError),
+}
+
@@ -0,0 +1,169 @@
+//! Host ports that the daemon's own listeners are bound to. A pod publishing one of these ports
+//! either fails to start or, when the port is forwarded with UPnP, points the gateway at the
+//! daemon's listener in place of the pod.
+//! Host ports published by pods that are already running are checked in the same way before a
+//! pod's container is created, as Docker would otherwise fail to start the container
+
+use std::fmt;
+
+use super::{config::PodDockerPortProtocol, id::DeimosId, Pod, PodManager, PodState};
+
+/// A TCP port used by one of the daemon's listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedPort {
+    pub port: u16,
+    pub listener: ReservedPortListener,
+    /// If the listener's port is forwarded with UPnP
+    pub upnp: bool,
+}
+
+/// Listener of the daemon that a port is reserved for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedPortListener {
+    Api,
+    Status,
+}
+
+/// A port published by a pod that is also used by one of the daemon's listeners
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Pod {pod} publishes TCP port {port}, which is also used by the daemon's {listener}{}",
+    if *upnp { " - forwarding it with UPnP would expose the daemon in place of the pod" } else { "" }
+)]
+pub struct PodPortConflict {
+    pub pod: DeimosId,
+    pub port: u16,
+    pub listener: ReservedPortListener,
+    /// If the pod's port or the listener's port is forwarded with UPnP, which makes the conflict
+    /// an error rather than a warning
+    pub upnp: bool,
+}
+
+/// A host port or UPnP external port published by a pod that is also used by another running pod
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Pod {pod} publishes {} {kind} port {port}, which is already used by pod {other}", protocol.docker_name().to_uppercase())]
+pub struct PodHostPortConflict {
+    pub pod: DeimosId,
+    pub other: DeimosId,
+    pub port: u16,
+    pub protocol: PodDockerPortProtocol,
+    pub kind: PodHostPortKind,
+}
+
+/// Kind of port that two pods conflict on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodHostPortKind {
+    Host,
+    /// Both pods forward the same port on the gateway with UPnP to different host ports
+    External,
+}
+
+impl PodManager {
+    /// Check the ports of every loaded pod against the daemon's listeners, logging conflicts that
+    /// are not forwarded with UPnP and failing on the first conflict that is
+    pub(super) fn check_reserved_ports(&self) -> Result<(), PodPortConflict> {
+        self
+            .loaded()
+            .values()
+            .try_for_each(|pod| self.check_pod_ports(pod))
+    }
+
+    /// Check the ports published by the given pod against the daemon's listeners, logging
+    /// conflicts that are not forwarded with UPnP and failing on the first conflict that is
+    pub(super) fn check_pod_ports(&self, pod: &Pod) -> Result<(), PodPortConflict> {
+        for conflict in self.port_conflicts(pod) {
+            match conflict.upnp {
+                true => return Err(conflict),
+                false => tracing::warn!("{}", conflict),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get every conflict between the ports published by the given pod and the daemon's listeners.
+    /// The daemon only listens on TCP, so UDP ports never conflict
+    fn port_conflicts(&self, pod: &Pod) -> Vec<PodPortConflict> {
+        let config = pod.config();
+        config
+            .docker
+            .port
+            .iter()
+            .filter(|port| matches!(port.protocol, PodDockerPortProtocol::Tcp))
+            .flat_map(|port| {
+                self
+                    .reserved
+                    .iter()
+                    .filter(move |reserved| reserved.port == port.host_port())
+                    .map(move |reserved| PodPortConflict {
+                        pod: pod.id(),
+                        port: port.host_port(),
+                        listener: reserved.listener,
+                        upnp: port.upnp || reserved.upnp,
+                    })
+            })
+            .collect()
+    }
+}
+
+impl PodManager {
+    /// Check the host ports of the given pod against the ports published by every other pod that
+    /// is not disabled, failing on the first port that is already in use.
+    /// Pods may share a port forwarded with UPnP only if they forward it to the same host port
+    pub(super) fn check_host_ports(&self, pod: &Pod) -> Result<(), PodHostPortConflict> {
+        let config = pod.config();
+        for other in self.loaded().values().filter(|other| other.id() != pod.id()) {
+            if other.state().current() == PodState::Disabled {
+                continue
+            }
+
+            let other_config = other.config();
+            for port in config.docker.port.iter() {
+                for used in other_config.docker.port.iter().filter(|used| used.protocol == port.protocol) {
+                    let kind = if used.host_port() == port.host_port() {
+                        PodHostPortKind::Host
+                    } else if port.upnp && used.upnp && used.external_port() == port.external_port() {
+                        PodHostPortKind::External
+                    } else {
+                        continue
+                    };
+
+                    return Err(PodHostPortConflict {
+                        pod: pod.id(),
+                        other: other.id(),
+                        port: match kind {
+                            PodHostPortKind::Host => port.host_port(),
+                            PodHostPortKind::External => port.external_port(),
+                        },
+                        protocol: port.protocol,
+                        kind,
+                    })
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for PodHostPortKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            match self {
+                Self::Host => "host",
+                Self::External => "UPnP external",
+            }
+        )
+    }
+}
+
+impl fmt::Display for ReservedPortListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            match self {
+                Self::Api => "public API",
+                Self::Status => "status page server",
+            }
+        )
+    }
+}
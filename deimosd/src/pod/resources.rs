@@ -0,0 +1,215 @@
+//! Limits on the memory, CPU time, and number of processes available to a pod's container,
+//! applied by Docker when the container is created
+
+use std::{fmt, str::FromStr};
+
+use bollard::models::HostConfig;
+
+use super::config::PodDockerConfig;
+
+/// Amount of memory in bytes, parsed from a number of bytes or a string with a binary unit suffix
+/// such as `"2g"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PodMemorySize(u64);
+
+/// Limit on the combined memory and swap usage of a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodMemorySwap {
+    Limited(PodMemorySize),
+    /// The container may use unlimited swap, given as `"unlimited"` or `-1`
+    Unlimited,
+}
+
+impl PodMemorySize {
+    /// Smallest memory limit that Docker accepts for a container
+    pub const MIN_LIMIT: Self = Self(6 << 20);
+
+    /// Create a size of the given number of mebibytes
+    pub const fn from_mib(mib: u64) -> Self {
+        Self(mib << 20)
+    }
+
+    pub const fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl PodDockerConfig {
+    /// Smallest relative CPU weight that Docker accepts
+    const MIN_CPU_SHARES: u32 = 2;
+
+    /// Check that the resource limits of the container are consistent with each other and
+    /// within the ranges that Docker accepts
+    pub fn validate_resources(&self) -> Result<(), PodResourceError> {
+        if let Some(memory) = self.memory_limit.filter(|memory| *memory < PodMemorySize::MIN_LIMIT) {
+            return Err(PodResourceError::MemoryTooSmall(memory))
+        }
+
+        if let Some(PodMemorySwap::Limited(swap)) = self.memory_swap {
+            match self.memory_limit {
+                None => return Err(PodResourceError::SwapWithoutMemory),
+                Some(memory) if swap < memory => return Err(PodResourceError::SwapBelowMemory { swap, memory }),
+                Some(_) => (),
+            }
+        }
+
+        if let Some(shares) = self.cpu_shares.filter(|shares| *shares < Self::MIN_CPU_SHARES) {
+            return Err(PodResourceError::CpuSharesTooSmall(shares))
+        }
+
+        if self.nano_cpus.is_some_and(|cpus| cpus == 0 || i64::try_from(cpus).is_err()) {
+            return Err(PodResourceError::NanoCpusOutOfRange)
+        }
+
+        if self.pids_limit == Some(0) {
+            return Err(PodResourceError::PidsLimitZero)
+        }
+
+        Ok(())
+    }
+
+    /// Set the resource limits of the given host config from the limits in this config
+    pub(super) fn apply_resources(&self, host: &mut HostConfig) {
+        host.memory = self.memory_limit.map(|memory| memory.bytes() as i64);
+        host.memory_swap = self.memory_swap.map(|swap| match swap {
+            PodMemorySwap::Limited(swap) => swap.bytes() as i64,
+            PodMemorySwap::Unlimited => -1,
+        });
+        host.cpu_shares = self.cpu_shares.map(i64::from);
+        host.nano_cpus = self.nano_cpus.map(|cpus| cpus as i64);
+        host.pids_limit = self.pids_limit.map(i64::from);
+    }
+}
+
+impl FromStr for PodMemorySize {
+    type Err = PodMemorySizeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let number = number.parse::<u64>().map_err(|_| PodMemorySizeParseError::InvalidNumber(s.to_owned()))?;
+        let shift = match unit.trim().to_ascii_lowercase().trim_end_matches(['b', 'i']) {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            _ => return Err(PodMemorySizeParseError::InvalidUnit(unit.to_owned())),
+        };
+
+        number
+            .checked_mul(1 << shift)
+            .filter(|bytes| i64::try_from(*bytes).is_ok())
+            .map(Self)
+            .ok_or_else(|| PodMemorySizeParseError::OutOfRange(s.to_owned()))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PodMemorySize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SizeVisitor;
+
+        impl serde::de::Visitor<'_> for SizeVisitor {
+            type Value = PodMemorySize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes or a size such as \"2g\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u64::try_from(v)
+                    .map(PodMemorySize)
+                    .map_err(|_| E::custom(PodMemorySizeParseError::OutOfRange(v.to_string())))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map_err(|_| E::custom(PodMemorySizeParseError::OutOfRange(v.to_string())))
+                    .and_then(|v| self.visit_i64(v))
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PodMemorySwap {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SwapVisitor;
+
+        impl serde::de::Visitor<'_> for SwapVisitor {
+            type Value = PodMemorySwap;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes, a size such as \"4g\", or \"unlimited\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                match v.trim() {
+                    "unlimited" | "-1" => Ok(PodMemorySwap::Unlimited),
+                    v => v.parse().map(PodMemorySwap::Limited).map_err(E::custom),
+                }
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                match v {
+                    -1 => Ok(PodMemorySwap::Unlimited),
+                    v => u64::try_from(v)
+                        .map(|v| PodMemorySwap::Limited(PodMemorySize(v)))
+                        .map_err(|_| E::custom(PodMemorySizeParseError::OutOfRange(v.to_string()))),
+                }
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map_err(|_| E::custom(PodMemorySizeParseError::OutOfRange(v.to_string())))
+                    .and_then(|v| self.visit_i64(v))
+            }
+        }
+
+        deserializer.deserialize_any(SwapVisitor)
+    }
+}
+
+impl fmt::Display for PodMemorySize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(u32, &str); 4] = [(40, "TiB"), (30, "GiB"), (20, "MiB"), (10, "KiB")];
+
+        match UNITS.iter().find(|(shift, _)| self.0 >= 1 << shift && self.0.is_multiple_of(1 << shift)) {
+            Some((shift, unit)) => write!(f, "{}{}", self.0 >> shift, unit),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodMemorySizeParseError {
+    #[error("Invalid memory size '{0}' - expected a number followed by an optional unit such as \"2g\"")]
+    InvalidNumber(String),
+    #[error("Unknown memory size unit '{0}' - expected one of k, m, g, or t")]
+    InvalidUnit(String),
+    #[error("Memory size '{0}' must be positive and less than 8EiB")]
+    OutOfRange(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodResourceError {
+    #[error("'memory_limit' of {0} is below the minimum of {min} that Docker accepts", min = PodMemorySize::MIN_LIMIT)]
+    MemoryTooSmall(PodMemorySize),
+    #[error("'memory_swap' requires 'memory_limit' to be set")]
+    SwapWithoutMemory,
+    #[error("'memory_swap' of {swap} limits memory and swap combined and may not be less than 'memory_limit' of {memory}")]
+    SwapBelowMemory { swap: PodMemorySize, memory: PodMemorySize },
+    #[error("'cpu_shares' of {0} is below the minimum of {min} that Docker accepts", min = PodDockerConfig::MIN_CPU_SHARES)]
+    CpuSharesTooSmall(u32),
+    #[error("'nano_cpus' must be greater than zero and less than 2^63")]
+    NanoCpusOutOfRange,
+    #[error("'pids_limit' must be greater than zero")]
+    PidsLimitZero,
+}
@@ -0,0 +1,274 @@
+//! Restart policies that re-enable pods when their container dies unexpectedly. Consecutive
+//! restarts are delayed with an exponential backoff, and each pod's restarts pass through a circuit
+//! breaker that opens once too many failures occur within the configured window, leaving the pod
+//! disabled until it is explicitly enabled or the breaker's cool-off expires, after which a single
+//! probe restart is made. Disabling a pod cancels any restart that is waiting, so a pod disabled
+//! through the API is never restarted
+
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, PoisonError}, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio_util::sync::CancellationToken;
+
+use super::{config::{PodRestartConfig, PodRestartPolicy}, state::PodStateWriteHandle, Pod, PodManager, PodStateCause, PodStateKnown};
+
+/// Failures of a pod's container counted by its restart policy, and the restart waiting to be made
+#[derive(Debug, Default)]
+pub struct PodRestartState {
+    breaker: PodRestartBreaker,
+    /// Cancelled when the pod is disabled, stopping the restart started when its container died
+    pending: Option<CancellationToken>,
+}
+
+/// Circuit breaker stopping a pod's restart policy once its container fails too often.
+/// All times are given by the caller so that transitions do not depend on the system clock
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PodRestartBreaker {
+    /// Times of the failures within the window, oldest first
+    failures: VecDeque<DateTime<Utc>>,
+    /// Time that the breaker opened at, if no more restarts are being made
+    opened: Option<DateTime<Utc>>,
+    /// Set while the breaker is half-open after a probe restart, during which any failure opens
+    /// it again
+    probing: bool,
+    /// Reason for the most recent failure
+    last_error: Option<String>,
+}
+
+impl PodRestartBreaker {
+    /// Record a failure at the given time, forgetting failures older than the window.
+    /// Returns `true` if the breaker is open, which it becomes once more than the maximum number
+    /// of restarts would be needed within the window or a probe restart fails
+    pub fn fail(&mut self, now: DateTime<Utc>, config: &PodRestartConfig, error: String) -> bool {
+        let window = TimeDelta::try_seconds(i64::try_from(config.window_seconds).unwrap_or(i64::MAX)).unwrap_or(TimeDelta::MAX);
+        while self.failures.front().is_some_and(|at| now.signed_duration_since(*at) >= window) {
+            self.failures.pop_front();
+        }
+
+        self.failures.push_back(now);
+        self.last_error = Some(error);
+
+        let exhausted = self.failures.len() > usize::try_from(config.max_attempts).unwrap_or(usize::MAX);
+        if self.opened.is_none() && (self.probing || exhausted) {
+            self.opened = Some(now);
+            self.probing = false;
+        }
+
+        self.is_open()
+    }
+
+    /// Check if the breaker is open, preventing the restart policy from restarting the pod
+    pub const fn is_open(&self) -> bool {
+        self.opened.is_some()
+    }
+
+    /// Get the number of failures counted within the window
+    pub fn failures(&self) -> usize {
+        self.failures.len()
+    }
+
+    /// Get the time that the breaker opened at, if it is open
+    pub const fn opened(&self) -> Option<DateTime<Utc>> {
+        self.opened
+    }
+
+    /// Get the reason for the most recent failure
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Get the time that a probe restart may be made at while the breaker is open, or `None` if
+    /// the breaker is closed or the config gives no cool-off
+    pub fn probe_at(&self, config: &PodRestartConfig) -> Option<DateTime<Utc>> {
+        let cooloff = TimeDelta::try_seconds(i64::try_from(config.cooloff_seconds?).ok()?)?;
+        self.opened?.checked_add_signed(cooloff)
+    }
+
+    /// Move the breaker to half-open if it is open and its cool-off expired at or before the
+    /// given time, returning `true` if a probe restart may be made
+    pub fn begin_probe(&mut self, now: DateTime<Utc>, config: &PodRestartConfig) -> bool {
+        match self.probe_at(config) {
+            Some(at) if at <= now => {
+                self.opened = None;
+                self.probing = true;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Forget all failures and close the breaker
+    pub fn close(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Pod {
+    /// Get the circuit breaker of the pod's restart policy
+    pub fn restart_breaker(&self) -> PodRestartBreaker {
+        self.restart.lock().unwrap_or_else(PoisonError::into_inner).breaker.clone()
+    }
+
+    /// Close the circuit breaker of the pod's restart policy, allowing the pod to be restarted
+    /// again when its container dies
+    pub(super) fn close_restart_breaker(&self) {
+        self.restart.lock().unwrap_or_else(PoisonError::into_inner).breaker.close();
+    }
+
+    /// Cancel the restart waiting to be made by the pod's restart policy, if any
+    pub(super) fn cancel_restart(&self) {
+        if let Some(pending) = self.restart.lock().unwrap_or_else(PoisonError::into_inner).pending.take() {
+            pending.cancel();
+        }
+    }
+
+    /// Replace the pending restart of the pod with a new one, returning the token cancelled when
+    /// the pod is disabled
+    fn begin_restart(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        let mut restart = self.restart.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = restart.pending.replace(token.clone()) {
+            previous.cancel();
+        }
+
+        token
+    }
+}
+
+impl PodManager {
+    /// Maximum delay between two restarts of a pod, regardless of the configured backoff
+    const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+    /// Get the restart breakers of all pods that have failed since they were last closed
+    pub(super) fn restart_breakers(&self) -> HashMap<String, PodRestartBreaker> {
+        self
+            .loaded()
+            .iter()
+            .map(|(id, pod)| (id.owned(), pod.restart_breaker()))
+            .filter(|(_, breaker)| *breaker != PodRestartBreaker::default())
+            .collect()
+    }
+
+    /// Record a failure in the given pod's circuit breaker, saving persistent state if it opened
+    fn fail_restart(&self, pod: &Pod, config: &PodRestartConfig, error: String) {
+        let mut restart = pod.restart.lock().unwrap_or_else(PoisonError::into_inner);
+        let was_open = restart.breaker.is_open();
+        if restart.breaker.fail(Utc::now(), config, error) && !was_open {
+            self.directives.notify_one();
+        }
+
+        drop(restart);
+        //Subscribers read the breaker with the disabled state sent before it was updated
+        pod.state().touch();
+    }
+
+    /// Restore the restart breakers of pods from persistent state
+    pub(super) fn restore_restart_breakers(&self, breakers: HashMap<String, PodRestartBreaker>) {
+        for (id, breaker) in breakers {
+            match self.loaded().get(id.as_str()) {
+                Some(pod) => pod.restart.lock().unwrap_or_else(PoisonError::into_inner).breaker = breaker,
+                None => tracing::warn!("Discarding restart breaker of unknown pod {}", id),
+            }
+        }
+    }
+
+    /// Re-enable the given pod according to its restart policy after its container died with the
+    /// given exit code, having been up for the given time.
+    /// The write handle must be held from when the pod was disabled after its container died, so
+    /// that a disable requested in the meantime cancels the restart
+    pub(super) async fn restart_after_death(&self, pod: Arc<Pod>, lock: PodStateWriteHandle<'_>, exit_code: Option<i64>, uptime: TimeDelta) {
+        let config = pod.config().docker.restart;
+        let restart = match config.policy {
+            PodRestartPolicy::Never => false,
+            PodRestartPolicy::Always => true,
+            PodRestartPolicy::OnFailure => exit_code != Some(0),
+        };
+
+        if !restart || !matches!(lock.state(), PodStateKnown::Disabled) {
+            return
+        }
+
+        //Containers that stayed up for the stable period close a half-open breaker
+        if uptime.num_seconds() >= i64::try_from(config.stable_seconds).unwrap_or(i64::MAX) {
+            pod.close_restart_breaker();
+        }
+
+        let reason = pod
+            .failure()
+            .map(|failure| failure.reason.clone())
+            .unwrap_or_else(|| String::from("Container died"));
+        self.fail_restart(&pod, &config, reason);
+
+        let cancel = pod.begin_restart();
+        drop(lock);
+
+        loop {
+            let breaker = pod.restart_breaker();
+            let delay = match breaker.probe_at(&config) {
+                _ if !breaker.is_open() => {
+                    let failures = u32::try_from(breaker.failures()).unwrap_or(u32::MAX);
+                    let backoff = Duration::from_secs(config.backoff_seconds)
+                        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+                        .min(Self::MAX_RESTART_BACKOFF);
+
+                    tracing::warn!(
+                        "Restarting pod {} in {}s, attempt {} of {}",
+                        pod.id(),
+                        backoff.as_secs(),
+                        failures,
+                        config.max_attempts,
+                    );
+
+                    backoff
+                },
+                Some(at) => {
+                    tracing::error!(
+                        "Stopped restarting pod {} after {} failures, making a probe restart at {}",
+                        pod.id(),
+                        breaker.failures(),
+                        at,
+                    );
+
+                    (at - Utc::now()).to_std().unwrap_or_default()
+                },
+                None => {
+                    tracing::error!(
+                        "Not restarting pod {} as it failed {} times within {}s, until it is enabled again",
+                        pod.id(),
+                        breaker.failures(),
+                        config.window_seconds,
+                    );
+                    return
+                },
+            };
+
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(delay) => (),
+            };
+
+            let mut lock = pod.state().transact().await;
+            if cancel.is_cancelled() || !matches!(lock.state(), PodStateKnown::Disabled) {
+                tracing::trace!("Restart of pod {} abandoned as its state changed", pod.id());
+                return
+            }
+
+            if breaker.is_open() {
+                let probing = pod.restart.lock().unwrap_or_else(PoisonError::into_inner).breaker.begin_probe(Utc::now(), &config);
+                if !probing {
+                    tracing::trace!("Probe restart of pod {} abandoned as its breaker changed", pod.id());
+                    return
+                }
+            }
+
+            lock.set_cause(PodStateCause::RestartPolicy);
+            match self.enable(pod.clone(), lock).await {
+                Ok(()) => return,
+                Err(e) => {
+                    tracing::error!("Failed to restart pod {}: {}", pod.id(), e);
+                    self.fail_restart(&pod, &config, e.to_string());
+                },
+            }
+        }
+    }
+}
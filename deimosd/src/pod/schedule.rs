@@ -0,0 +1,180 @@
+use std::sync::{Arc, PoisonError};
+
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, TimeZone, Utc};
+
+use super::{config::PodScheduleConfig, Pod, PodManager, PodState};
+
+/// A transition planned by a pod's schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PodScheduledTransition {
+    /// State that the pod will be transitioned to
+    pub action: PodState,
+    /// Time that the transition will occur at
+    pub at: DateTime<Utc>,
+}
+
+impl PodScheduleConfig {
+    /// Get the first transition in this schedule that occurs strictly after the given time
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<PodScheduledTransition> {
+        [(self.start, PodState::Enabled), (self.stop, PodState::Disabled)]
+            .into_iter()
+            .filter_map(|(time, action)| Some(PodScheduledTransition { action, at: Self::next_occurrence(time?, after)? }))
+            .min_by_key(|transition| transition.at)
+    }
+
+    /// Find the next time that the given local time of day occurs after the given time
+    fn next_occurrence(time: NaiveTime, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = after.with_timezone(&Local);
+        let mut date = local.date_naive();
+
+        //Search a few days ahead in case the time of day does not exist on a day due to DST
+        for _ in 0..3 {
+            if let Some(at) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+                let at = at.with_timezone(&Utc);
+                if at > after {
+                    return Some(at)
+                }
+            }
+
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+
+    /// Get the name of a UTC offset that schedule times are given in, like `UTC+02:00`
+    pub fn zone_name(offset: FixedOffset) -> String {
+        let seconds = offset.local_minus_utc();
+        if seconds == 0 {
+            return String::from("UTC")
+        }
+
+        let sign = if seconds < 0 { '-' } else { '+' };
+        let minutes = seconds.unsigned_abs() / 60;
+        format!("UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+impl Pod {
+    /// Get the next transition planned by this pod's schedule, and whether it will be skipped
+    pub fn next_transition(&self) -> Option<(PodScheduledTransition, bool)> {
+        let next = self.config().schedule.next_after(Utc::now())?;
+        let skip = *self.schedule_skip.lock().unwrap_or_else(PoisonError::into_inner);
+        Some((next, skip == Some(next.at)))
+    }
+
+    /// Skip the next transition planned by this pod's schedule, or cancel a previously requested
+    /// skip. Subscribers are notified so that every client sees the change
+    pub fn skip_next_transition(&self, skip: bool) -> Option<(PodScheduledTransition, bool)> {
+        let next = self.config().schedule.next_after(Utc::now())?;
+        *self.schedule_skip.lock().unwrap_or_else(PoisonError::into_inner) = skip.then_some(next.at);
+        self.state().touch();
+        Some((next, skip))
+    }
+
+    /// Check if the given transition was marked to be skipped, clearing the skip
+    fn take_skip(&self, transition: &PodScheduledTransition) -> bool {
+        let mut skip = self.schedule_skip.lock().unwrap_or_else(PoisonError::into_inner);
+        match *skip == Some(transition.at) {
+            true => {
+                *skip = None;
+                true
+            },
+            false => false,
+        }
+    }
+}
+
+impl PodManager {
+    /// Get all scheduled transitions that came due in the given time range and have not been
+    /// skipped. Subscribers of pods whose transitions are not made are notified, as their next
+    /// transition has changed without a change of state
+    pub fn due_transitions(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<(Arc<Pod>, PodScheduledTransition)> {
+        self
+            .loaded()
+            .values()
+            .filter(|pod| !pod.archived())
+            .filter_map(|pod| {
+                let transition = pod.config().schedule.next_after(since)?;
+                if transition.at > now {
+                    return None
+                }
+
+                //An explicit timed enable takes precedence over the schedule until it expires
+                if transition.action == PodState::Disabled && pod.enabled_until().is_some_and(|until| until > now) {
+                    tracing::info!("Ignoring scheduled disable of pod {} until its timed enable expires", pod.id());
+                    pod.state().touch();
+                    return None
+                }
+
+                match pod.take_skip(&transition) {
+                    true => {
+                        tracing::info!("Skipping scheduled transition of pod {} to {:?}", pod.id(), transition.action);
+                        pod.state().touch();
+                        None
+                    },
+                    false => Some((pod.clone(), transition)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveDate, TimeDelta};
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    fn local(hour: u32, min: u32) -> DateTime<Utc> {
+        let date = NaiveDate::from_ymd_opt(2026, 1, 15).unwrap();
+        Local.from_local_datetime(&date.and_time(time(hour, min))).earliest().unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn next_transition_is_strictly_after() {
+        let schedule = PodScheduleConfig { start: Some(time(8, 0)), stop: Some(time(18, 0)) };
+
+        let next = schedule.next_after(local(12, 0)).unwrap();
+        assert_eq!((next.action, next.at), (PodState::Disabled, local(18, 0)));
+
+        let next = schedule.next_after(local(18, 0)).unwrap();
+        assert_eq!((next.action, next.at), (PodState::Enabled, local(8, 0) + TimeDelta::days(1)));
+
+        let next = schedule.next_after(local(7, 59)).unwrap();
+        assert_eq!((next.action, next.at), (PodState::Enabled, local(8, 0)));
+
+        assert_eq!(PodScheduleConfig::default().next_after(local(12, 0)), None);
+    }
+
+    #[test]
+    fn zone_names() {
+        let offset = |secs| FixedOffset::east_opt(secs).unwrap();
+        assert_eq!(PodScheduleConfig::zone_name(offset(0)), "UTC");
+        assert_eq!(PodScheduleConfig::zone_name(offset(2 * 3600)), "UTC+02:00");
+        assert_eq!(PodScheduleConfig::zone_name(offset(-(5 * 3600 + 30 * 60))), "UTC-05:30");
+        assert_eq!(PodScheduleConfig::zone_name(offset(5 * 3600 + 45 * 60)), "UTC+05:45");
+    }
+
+    #[tokio::test]
+    async fn skipping_notifies_subscribers() {
+        let config = "id = \"scheduled\"\nname = \"Scheduled\"\n[schedule]\nstart = \"08:00:00\"\n[docker]\nimage = \"alpine:3\"\n";
+        let (_dir, pod) = Pod::test(config).await;
+        let mut rx = pod.state().subscribe();
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        let (next, skipped) = pod.skip_next_transition(true).unwrap();
+        assert!(skipped);
+        assert_eq!(pod.next_transition(), Some((next, true)));
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+
+        assert!(pod.take_skip(&next));
+        assert_eq!(pod.next_transition(), Some((next, false)));
+        assert!(!pod.take_skip(&next));
+    }
+}
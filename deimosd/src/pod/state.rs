@@ -1,8 +1,10 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use chrono::{DateTime, Utc};
 
 use crate::server::upnp::UpnpLease;
 
-use super::{config::PodConfig, id::{DeimosId, DockerId}};
+use super::{alert::PodAlertState, annotate::{PodAnnotation, PodAnnotationListener}, artwork::PodArtworkCache, bounded::{BoundedDeque, PodCollectionCaps}, config::PodConfig, directive::PodDirective, history::PodConfigHash, id::{DeimosId, DockerId}, image::PodBlock, docker::{debounce::PodEventCounts, failure::PodFailure, network::PodNetwork}, env::{PodEnvOverrides, PodEnvRevision}, restart::PodRestartState, validate::PodConfigInvalid};
 
 mod handle;
 
@@ -10,17 +12,84 @@ pub use handle::{PodStateHandle, PodStateWriteHandle};
 
 /// Represents a single pod with associated config and running Docker container if any exists
 pub struct Pod {
-    config: PodConfig,
+    id: DeimosId,
+    pub(super) config: std::sync::RwLock<Arc<PodConfig>>,
+    /// Config loaded after the pod's config file changed, applied when a container is next
+    /// created for the pod
+    pub(super) pending_config: std::sync::Mutex<Option<Arc<PodConfig>>>,
     state: PodStateHandle,
+    /// Directory that the pod was loaded from
+    pub(super) dir: PathBuf,
+    /// Path of the config file that the pod was loaded from
+    pub(super) config_path: PathBuf,
+    /// Digest of the config file contents when the pod was last loaded or reloaded
+    pub(super) config_hash: std::sync::Mutex<PodConfigHash>,
+    /// Held while the pod's config file is being rolled back
+    pub(super) history_lock: tokio::sync::Mutex<()>,
+    /// Time of a scheduled transition that should be skipped
+    pub(super) schedule_skip: std::sync::Mutex<Option<DateTime<Utc>>>,
+    /// Time that the pod should be automatically disabled at after a timed enable
+    pub(super) enabled_until: std::sync::Mutex<Option<DateTime<Utc>>>,
+    /// State of each of the alert rules in the pod's config
+    pub(super) alerts: std::sync::Mutex<Vec<PodAlertState>>,
+    /// Set when the pod is archived, preventing it from being enabled
+    pub(super) archived: std::sync::atomic::AtomicBool,
+    /// Most recent annotations reported from inside the pod's container
+    pub(super) annotations: std::sync::Mutex<BoundedDeque<Arc<PodAnnotation>>>,
+    /// Reason that the pod cannot currently be enabled, such as its image not being pulled
+    pub(super) blocked: std::sync::Mutex<Option<PodBlock>>,
+    /// Address and published ports of the pod's container while it is enabled
+    pub(super) network: std::sync::Mutex<Option<Arc<PodNetwork>>>,
+    /// Most recent failure of the pod's container since it was last enabled
+    pub(super) failure: std::sync::Mutex<Option<Arc<PodFailure>>>,
+    /// Time that the pod's container was last stopped and removed
+    pub(super) stopped_at: std::sync::Mutex<Option<DateTime<Utc>>>,
+    /// Consecutive restarts made by the pod's restart policy and the restart waiting to be made
+    pub(super) restart: std::sync::Mutex<PodRestartState>,
+    /// Environment variables set through the internal API, replacing those in the config
+    pub(super) env: std::sync::Mutex<PodEnvOverrides>,
+    /// Overrides that the pod's current container was created with
+    pub(super) env_applied: std::sync::Mutex<Option<PodEnvOverrides>>,
+    /// Version of the overrides, locked after `env` when both are held
+    pub(super) env_revision: std::sync::Mutex<PodEnvRevision>,
+    /// Volumes declared by the pod's image when it was last inspected
+    pub(super) image_volumes: std::sync::Mutex<Option<BoundedDeque<PathBuf>>>,
+    /// State that the pod was last commanded into
+    pub(super) directive: std::sync::Mutex<Option<PodDirective>>,
+    /// Docker events for the pod's containers that were coalesced or deferred
+    pub(super) event_counts: std::sync::Mutex<PodEventCounts>,
+    /// Icon and banner most recently served to clients
+    pub(super) artwork: std::sync::Mutex<PodArtworkCache>,
 }
 
 /// Current state of a pod - including if the state is currently unknown and being modified
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PodState {
     Disabled,
     Transit,
     Paused,
     Enabled,
+    /// Enabled, but failing its health check
+    Degraded,
+}
+
+/// Reason that a pod's state was changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PodStateCause {
+    /// The change was requested through the API or occurred outside of the daemon's control
+    #[default]
+    Requested,
+    /// The change was made by the pod's schedule
+    Scheduled,
+    /// The pod was disabled because its timed enable expired
+    TimedEnableExpired,
+    /// The pod was restarted because a pod it depends on was restarted
+    DependencyRestarted,
+    /// The pod was re-enabled by its restart policy after its container died
+    RestartPolicy,
+    /// The pod was enabled when the daemon started as it was enabled when the daemon stopped
+    Restored,
 }
 
 /// State of a pod with the guarantee that the state is always known
@@ -36,23 +105,37 @@ pub enum PodStateKnown {
 pub struct PodEnable {
     pub docker_id: DockerId,
     pub upnp_lease: UpnpLease,
+    /// Socket accepting annotations from the container, if enabled in the pod's config
+    pub annotations: Option<PodAnnotationListener>,
+    /// Time that the container was last started at, kept while the container is paused
+    pub started: DateTime<Utc>,
+    /// Time that the container last finished restarting in place at, before which events for the
+    /// container stopping were caused by the restart
+    pub restarted: Option<DateTime<Utc>>,
+    /// Set when the container has failed its health check too many times in a row
+    pub degraded: bool,
 }
 
-/// State maintained for a pod that has been paused and can be quickly restarted
+/// State maintained for a pod that has been paused and can be quickly resumed
 #[derive(Clone)]
 pub struct PodPaused {
     pub docker_id: DockerId,
+    /// Lease of the container's forwarded ports, kept so that they stay reachable when resumed
+    pub upnp_lease: UpnpLease,
+    pub annotations: Option<PodAnnotationListener>,
+    /// Time that the container was last started at, restored when the container is resumed
+    pub started: DateTime<Utc>,
 }
 
 impl Pod {
     /// Get the user-visible title for this container
-    pub fn title(&self) -> &str {
-        &self.config.name
+    pub fn title(&self) -> Arc<str> {
+        self.config().name.clone()
     }
 
     /// Get the ID used to refer to the container in API requests
     pub fn id(&self) -> DeimosId {
-        self.config.id.clone()
+        self.id.clone()
     }
     
     /// Get a handle to access this pod's state
@@ -60,24 +143,148 @@ impl Pod {
         &self.state
     }
 
-    /// Get immutable access to the pod's configuration data
-    pub fn config(&self) -> &PodConfig {
-        &self.config
+    /// Get the pod's current configuration data, which is replaced when a changed config file
+    /// is applied
+    pub fn config(&self) -> Arc<PodConfig> {
+        self.config.read().unwrap_or_else(std::sync::PoisonError::into_inner).clone()
+    }
+
+    /// Get the reason for the most recent state change
+    pub fn cause(&self) -> PodStateCause {
+        self.state.cause()
+    }
+
+    /// Config file name of a pod, located in the pod's directory
+    const CONFIG_FILENAME: &str = "pod.toml";
+    /// Config file name used by the legacy container manager, still accepted with a warning
+    const LEGACY_CONFIG_FILENAME: &str = "container.toml";
+
+    /// Check if the given directory contains a pod config file
+    pub(super) async fn has_config(dir: &Path) -> bool {
+        for name in [Self::CONFIG_FILENAME, Self::LEGACY_CONFIG_FILENAME] {
+            if matches!(tokio::fs::try_exists(dir.join(name)).await, Ok(true)) {
+                return true
+            }
+        }
+
+        false
     }
 
-    /// Load the pod from config files located in the given directory
-    pub(super) async fn load(dir: &Path) -> Result<Self, PodLoadError> {
-        const CONFIG_FILENAME: &str = "pod.toml";
+    /// Read, parse, and validate the config file located in the given directory.
+    /// Returns a tuple of (path of the config file, contents of the config file, parsed config)
+    pub(super) async fn read_config(dir: &Path) -> Result<(PathBuf, String, PodConfig), PodLoadError> {
+        let path = dir.join(Self::CONFIG_FILENAME);
+        let (path, legacy) = match tokio::fs::try_exists(&path).await {
+            Ok(false) if matches!(tokio::fs::try_exists(dir.join(Self::LEGACY_CONFIG_FILENAME)).await, Ok(true)) => {
+                (dir.join(Self::LEGACY_CONFIG_FILENAME), true)
+            },
+            _ => (path, false),
+        };
 
-        let path = dir.join(CONFIG_FILENAME);
         let config_str = tokio::fs::read_to_string(&path)
             .await
-            .map_err(|err| PodLoadError::ConfigRead { path, err })?;
+            .map_err(|err| PodLoadError::ConfigRead { path: path.clone(), err })?;
+
+        if legacy {
+            tracing::warn!(
+                "Pod config {} uses the deprecated file name '{}' - rename it to '{}'",
+                path.display(),
+                Self::LEGACY_CONFIG_FILENAME,
+                Self::CONFIG_FILENAME,
+            );
+        }
+
+        let config = Self::parse_config(dir, &path, &config_str).await?;
+        Ok((path, config_str, config))
+    }
+
+    /// Parse and validate the contents of the config file at the given path in a pod directory
+    pub(super) async fn parse_config(dir: &Path, path: &Path, config_str: &str) -> Result<PodConfig, PodLoadError> {
+        let mut table = toml::from_str::<toml::Table>(config_str)?;
+        if !table.contains_key("id") {
+            let id = dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| PodLoadError::MissingId { path: path.to_owned() })?;
+
+            tracing::warn!(
+                "Pod config {} has no 'id' field, deriving id '{}' from the directory name - add `id = \"{}\"` to the config as this will be refused in a future release",
+                path.display(),
+                id,
+                id,
+            );
+
+            table.insert(String::from("id"), toml::Value::String(id.to_owned()));
+        }
+
+        let mut config = toml::Value::Table(table).try_into::<PodConfig>()?;
+        config.add_disk_alert();
+
+        for shadow in config.validate().await? {
+            tracing::warn!(
+                "Volume mounted at {} in pod config {} hides part of the volume mounted at {}",
+                shadow.child.display(),
+                path.display(),
+                shadow.parent.display(),
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// Load the pod from config files located in the given directory, saving the config file to
+    /// the pod's config history if it changed
+    pub(super) async fn load(dir: &Path, history: usize) -> Result<Self, PodLoadError> {
+        let (path, config_str, config) = Self::read_config(dir).await?;
 
-        let config = toml::from_str(&config_str)?;
         let state = PodStateHandle::new(PodStateKnown::Disabled);
+        let alerts = std::sync::Mutex::new(vec![PodAlertState::default(); config.alerts.len()]);
+
+        let pod = Self {
+            id: config.id.clone(),
+            config: std::sync::RwLock::new(Arc::new(config)),
+            pending_config: Default::default(),
+            state,
+            dir: dir.to_owned(),
+            config_hash: std::sync::Mutex::new(Self::hash_config(config_str.as_bytes())),
+            config_path: path,
+            history_lock: Default::default(),
+            schedule_skip: Default::default(),
+            enabled_until: Default::default(),
+            alerts,
+            archived: Default::default(),
+            annotations: std::sync::Mutex::new(BoundedDeque::new(PodCollectionCaps::ANNOTATIONS)),
+            blocked: Default::default(),
+            network: Default::default(),
+            failure: Default::default(),
+            stopped_at: Default::default(),
+            restart: Default::default(),
+            env: Default::default(),
+            env_applied: Default::default(),
+            env_revision: Default::default(),
+            image_volumes: Default::default(),
+            directive: Default::default(),
+            event_counts: Default::default(),
+            artwork: Default::default(),
+        };
+
+        if let Err(e) = pod.record_config(&config_str, history).await {
+            tracing::error!("Failed to save config history of pod {}: {}", pod.id(), e);
+        }
 
-        Ok(Self { config, state })
+        Ok(pod)
+    }
+}
+
+#[cfg(test)]
+impl Pod {
+    /// Load a pod from a new temporary directory containing the given config file. The directory
+    /// is removed when the returned handle is dropped, so it must be kept while the pod is used
+    pub(crate) async fn test(config: &str) -> (tempfile::TempDir, Self) {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join(Self::CONFIG_FILENAME), config).await.unwrap();
+        let pod = Self::load(dir.path(), 0).await.unwrap();
+        (dir, pod)
     }
 }
 
@@ -86,6 +293,7 @@ impl From<&PodStateKnown> for PodState {
         match value {
             PodStateKnown::Disabled => PodState::Disabled,
             PodStateKnown::Paused(..) => PodState::Paused,
+            PodStateKnown::Enabled(PodEnable { degraded: true, .. }) => PodState::Degraded,
             PodStateKnown::Enabled(..) => PodState::Enabled,
         }
     }
@@ -97,4 +305,89 @@ pub enum PodLoadError {
     ConfigRead { path: PathBuf, err: std::io::Error },
     #[error("Failed to parse config file: {0}")]
     ConfigParse(#[from] toml::de::Error),
+    #[error("Config file {} has no 'id' and the directory name is not valid UTF-8 - add an `id` field to the config", path.display())]
+    MissingId { path: PathBuf },
+    #[error("Invalid config: {0}")]
+    Invalid(#[from] PodConfigInvalid),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = "name = \"X\"\n[docker]\nimage = \"alpine:3\"\n";
+
+    /// Create a pod directory with the given name containing the given config files
+    async fn pod_dir(parent: &Path, name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = parent.join(name);
+        tokio::fs::create_dir(&dir).await.unwrap();
+        for (file, contents) in files {
+            tokio::fs::write(dir.join(file), contents).await.unwrap();
+        }
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn legacy_config_file_is_read() {
+        let parent = tempfile::tempdir().unwrap();
+        let config = format!("id = \"legacy\"\n{}", CONFIG);
+        let dir = pod_dir(parent.path(), "legacy", &[(Pod::LEGACY_CONFIG_FILENAME, &config)]).await;
+
+        assert!(Pod::has_config(&dir).await);
+        let (path, contents, parsed) = Pod::read_config(&dir).await.unwrap();
+        assert_eq!(path, dir.join(Pod::LEGACY_CONFIG_FILENAME));
+        assert_eq!(contents, config);
+        assert_eq!(&*parsed.id, "legacy");
+    }
+
+    #[tokio::test]
+    async fn config_file_is_preferred_over_legacy() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = pod_dir(
+            parent.path(),
+            "both",
+            &[
+                (Pod::CONFIG_FILENAME, &format!("id = \"current\"\n{}", CONFIG)),
+                (Pod::LEGACY_CONFIG_FILENAME, &format!("id = \"legacy\"\n{}", CONFIG)),
+            ],
+        )
+        .await;
+
+        let (path, _, parsed) = Pod::read_config(&dir).await.unwrap();
+        assert_eq!(path, dir.join(Pod::CONFIG_FILENAME));
+        assert_eq!(&*parsed.id, "current");
+    }
+
+    #[tokio::test]
+    async fn missing_id_is_taken_from_directory() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = pod_dir(parent.path(), "from-dir", &[(Pod::LEGACY_CONFIG_FILENAME, CONFIG)]).await;
+
+        let (_, _, parsed) = Pod::read_config(&dir).await.unwrap();
+        assert_eq!(&*parsed.id, "from-dir");
+    }
+
+    #[tokio::test]
+    async fn directories_without_config() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = pod_dir(parent.path(), "empty", &[("other.toml", CONFIG)]).await;
+
+        assert!(!Pod::has_config(&dir).await);
+        assert!(matches!(Pod::read_config(&dir).await, Err(PodLoadError::ConfigRead { .. })));
+    }
+
+    #[tokio::test]
+    async fn duplicate_ids_are_rejected() {
+        let parent = tempfile::tempdir().unwrap();
+        let config = format!("id = \"same\"\n{}", CONFIG);
+        pod_dir(parent.path(), "a", &[(Pod::CONFIG_FILENAME, &config)]).await;
+        pod_dir(parent.path(), "b", &[(Pod::LEGACY_CONFIG_FILENAME, &config)]).await;
+
+        let (pods, failures) = crate::pod::PodManager::load_containers(parent.path(), 0).await.unwrap();
+        assert_eq!(pods.len(), 1);
+        assert!(pods.contains_key("same"));
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].message.contains("already used"));
+    }
 }
@@ -1,8 +1,9 @@
 use std::ops::Deref;
 
+use chrono::{DateTime, Utc};
 use tokio::sync::Mutex;
 
-use super::{PodState, PodStateKnown};
+use super::{PodState, PodStateCause, PodStateKnown};
 
 
 
@@ -14,6 +15,9 @@ use super::{PodState, PodStateKnown};
 pub struct PodStateHandle {
     lock: Mutex<PodStateKnown>,
     tx: tokio::sync::watch::Sender<PodState>,
+    /// Reason for the most recent state change, only changed while the state is locked so that
+    /// subscribers always read the cause of the state they were sent
+    cause: std::sync::Mutex<PodStateCause>,
 }
 
 /// A handle allowing mutations to the state of a [Pod].
@@ -21,6 +25,7 @@ pub struct PodStateHandle {
 pub struct PodStateWriteHandle<'a> {
     lock: tokio::sync::MutexGuard<'a, PodStateKnown>,
     tx: tokio::sync::watch::Sender<PodState>,
+    cause: &'a std::sync::Mutex<PodStateCause>,
 }
 
 /// A handle that ensures the pod's state will not be changed while held, but does not allow
@@ -35,7 +40,7 @@ impl PodStateHandle {
         let (tx, _) = tokio::sync::watch::channel(PodState::from(&state));
         let lock = Mutex::new(state);
 
-        Self { lock, tx }
+        Self { lock, tx, cause: Default::default() }
     }
     
     /// Subscribe to a stream of pod state changes
@@ -51,6 +56,7 @@ impl PodStateHandle {
         PodStateWriteHandle {
             lock,
             tx: self.tx.clone(),
+            cause: &self.cause,
         }
     }
     
@@ -59,13 +65,20 @@ impl PodStateHandle {
         PodStateReadHandle(self.lock.lock().await)
     }
     
+    /// Get a read-only lock for the state without waiting, returning `None` if a transaction is
+    /// happening concurrently to this call
+    pub fn try_read(&self) -> Option<PodStateReadHandle> {
+        self.lock.try_lock().ok().map(PodStateReadHandle)
+    }
+
     /// Upgrade a pod read handle to allow state mutations
-    pub fn upgrade<'a>(&self, read: PodStateReadHandle<'a>) -> PodStateWriteHandle<'a> {
+    pub fn upgrade<'a>(&'a self, read: PodStateReadHandle<'a>) -> PodStateWriteHandle<'a> {
         self.tx.send_replace(PodState::Transit);
 
         PodStateWriteHandle {
             lock: read.0,
             tx: self.tx.clone(),
+            cause: &self.cause,
         }
     }
 
@@ -78,6 +91,32 @@ impl PodStateHandle {
             .map(Into::into)
             .unwrap_or(PodState::Transit)
     }
+
+    /// Get the reason for the most recent state change
+    pub fn cause(&self) -> PodStateCause {
+        *self.cause.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Notify subscribers of the current state again without changing it, so that they read
+    /// details of the pod that changed after the state was sent
+    pub fn touch(&self) {
+        self.tx.send_modify(|_| ());
+    }
+
+    /// Mark the pod as failing or passing its health check if it is still enabled with the
+    /// container started at the given time, notifying subscribers without passing through
+    /// [PodState::Transit]. Returns `true` if the state was changed
+    pub async fn set_degraded(&self, started: DateTime<Utc>, degraded: bool) -> bool {
+        let mut lock = self.lock.lock().await;
+        match *lock {
+            PodStateKnown::Enabled(ref mut enabled) if enabled.started == started && enabled.degraded != degraded => {
+                enabled.degraded = degraded;
+                self.tx.send_replace(PodState::from(&*lock));
+                true
+            },
+            _ => false,
+        }
+    }
 }
 
 impl<'a> PodStateWriteHandle<'a> {
@@ -91,6 +130,11 @@ impl<'a> PodStateWriteHandle<'a> {
         self.tx.send_replace((&state).into());
         *self.lock = state;
     }
+
+    /// Record the reason for the next state change, to be reported with state notifications
+    pub fn set_cause(&mut self, cause: PodStateCause) {
+        *self.cause.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = cause;
+    }
 }
 
 impl<'a> Deref for PodStateReadHandle<'a> {
@@ -107,3 +151,30 @@ impl Drop for PodStateWriteHandle<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cause_is_read_with_the_state_it_caused() {
+        let handle = PodStateHandle::new(PodStateKnown::Disabled);
+        let mut rx = handle.subscribe();
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+        assert_eq!(handle.cause(), PodStateCause::Requested);
+
+        let mut lock = handle.transact().await;
+        lock.set_cause(PodStateCause::Scheduled);
+        assert_eq!(rx.next().await, Some(PodState::Transit));
+        lock.set(PodStateKnown::Disabled);
+        drop(lock);
+        assert_eq!(rx.next().await, Some(PodState::Disabled));
+        assert_eq!(handle.cause(), PodStateCause::Scheduled);
+
+        //Transactions that do not record a cause keep the previous one
+        drop(handle.transact().await);
+        assert_eq!(handle.cause(), PodStateCause::Scheduled);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::{BTreeSet, HashMap}, sync::{Arc, PoisonError}};
+
+use chrono::{DateTime, Utc};
+
+use super::{directive::PodDirective, env::{PodEnvOverrides, PodEnvRevision}, prune::PodImageHistory, restart::PodRestartBreaker, Pod, PodManager};
+
+/// Deadlines of timed enables, archived pods, the images pods have used, environment variable
+/// overrides, the last directive of each pod, and the restart breakers of pods that have failed,
+/// persisted so that they survive daemon restarts
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct PodManagerPersistent {
+    /// Map of pod IDs to the time their timed enable expires at
+    #[serde(default)]
+    enabled_until: HashMap<String, DateTime<Utc>>,
+    /// IDs of all archived pods
+    #[serde(default)]
+    archived: BTreeSet<String>,
+    /// IDs of the images that each pod has used, newest first
+    #[serde(default)]
+    images: PodImageHistory,
+    /// Environment variable overrides of each pod
+    #[serde(default)]
+    env: HashMap<String, PodEnvOverrides>,
+    /// Version of the environment variable overrides of each pod that has changed them
+    #[serde(default)]
+    env_revisions: HashMap<String, PodEnvRevision>,
+    /// State that each pod was last commanded into
+    #[serde(default)]
+    directives: HashMap<String, PodDirective>,
+    /// Restart breakers of pods whose containers have failed
+    #[serde(default)]
+    restart_breakers: HashMap<String, PodRestartBreaker>,
+}
+
+impl Pod {
+    /// Get the time that this pod will be automatically disabled at, if it was enabled for a
+    /// limited duration
+    pub fn enabled_until(&self) -> Option<DateTime<Utc>> {
+        *self.enabled_until.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PodManager {
+    /// Set or clear the time that the given pod will be automatically disabled at
+    pub fn set_enabled_until(&self, pod: &Pod, until: Option<DateTime<Utc>>) {
+        *pod.enabled_until.lock().unwrap_or_else(PoisonError::into_inner) = until;
+        self.timed.notify_one();
+    }
+
+    /// Wait until a timed enable deadline is set or cleared
+    pub async fn timed_changed(&self) {
+        self.timed.notified().await
+    }
+
+    /// Get the earliest deadline of all timed enables
+    pub fn next_timed_expiry(&self) -> Option<DateTime<Utc>> {
+        self
+            .loaded()
+            .values()
+            .filter_map(|pod| pod.enabled_until())
+            .min()
+    }
+
+    /// Get all pods with a timed enable, and their deadlines
+    pub fn timed(&self) -> Vec<(Arc<Pod>, DateTime<Utc>)> {
+        self
+            .loaded()
+            .values()
+            .filter_map(|pod| Some((pod.clone(), pod.enabled_until()?)))
+            .collect()
+    }
+
+    /// Clear and return all timed enables that expired at or before the given time
+    pub fn take_expired_timed(&self, now: DateTime<Utc>) -> Vec<Arc<Pod>> {
+        self
+            .loaded()
+            .values()
+            .filter(|pod| {
+                let mut until = pod.enabled_until.lock().unwrap_or_else(PoisonError::into_inner);
+                match *until {
+                    Some(at) if at <= now => {
+                        *until = None;
+                        true
+                    },
+                    _ => false,
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Get persistent state to be written to the save file
+    pub fn persistent(&self) -> PodManagerPersistent {
+        PodManagerPersistent {
+            enabled_until: self
+                .loaded()
+                .iter()
+                .filter_map(|(id, pod)| Some((id.owned(), pod.enabled_until()?)))
+                .collect(),
+            archived: self
+                .loaded()
+                .iter()
+                .filter(|(_, pod)| pod.archived())
+                .map(|(id, _)| id.owned())
+                .collect(),
+            images: self.image_history(),
+            env: self.env_overrides(),
+            env_revisions: self.env_revisions(),
+            directives: self.directives(),
+            restart_breakers: self.restart_breakers(),
+        }
+    }
+
+    /// Restore timed enable deadlines, archived pods, image history, directives, and restart
+    /// breakers from persistent state, discarding any timed enables that have expired
+    pub fn restore(&self, persistent: PodManagerPersistent) {
+        self.restore_archived(persistent.archived);
+        self.restore_image_history(persistent.images);
+        self.restore_env_overrides(persistent.env);
+        self.restore_env_revisions(persistent.env_revisions);
+        self.restore_directives(persistent.directives);
+        self.restore_restart_breakers(persistent.restart_breakers);
+
+        let now = Utc::now();
+        for (id, until) in persistent.enabled_until {
+            match self.loaded().get(id.as_str()) {
+                Some(pod) if until > now => self.set_enabled_until(pod, Some(until)),
+                Some(_) => tracing::info!("Timed enable of pod {} expired while the daemon was stopped", id),
+                None => tracing::warn!("Discarding timed enable for unknown pod {}", id),
+            }
+        }
+    }
+}
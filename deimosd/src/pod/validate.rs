@@ -0,0 +1,329 @@
+//! Validation of pod configs as they are loaded. Every check is made even after an earlier one
+//! fails, so that all problems with a config are reported together with the field each was found
+//! in, rather than surfacing one at a time or as a Docker API error when the pod is enabled.
+//! `deimosd validate <dir>` runs the same checks on a pod directory or a directory of pods without
+//! connecting to Docker or recording config history
+
+use std::{collections::HashMap, fmt, path::{Component, Path}, process::ExitCode};
+
+use super::{config::{PodConfig, PodDockerEnvValue, PodDockerPortProtocol}, env::env_path_confined, image::validate_image_reference, state::PodLoadError, volume::{validate_volumes, PodVolumeShadow}, Pod};
+
+/// A single problem found in a pod config
+#[derive(Debug, Clone)]
+pub struct PodConfigProblem {
+    /// Path of the field containing the problem, such as `docker.port[1].host`
+    pub field: String,
+    pub message: String,
+}
+
+/// Every problem found in a pod config, of which there is at least one
+#[derive(Debug, thiserror::Error)]
+pub struct PodConfigInvalid(pub Vec<PodConfigProblem>);
+
+impl PodConfig {
+    /// Check every field of the config, normalizing volume paths in place.
+    /// Returns the mounts that hide part of another mount, which are allowed but likely unintended
+    pub(super) async fn validate(&mut self) -> Result<Vec<PodVolumeShadow>, PodConfigInvalid> {
+        let mut problems = Vec::new();
+        let mut problem = |field: String, message: String| problems.push(PodConfigProblem { field, message });
+
+        if let Err(e) = validate_image_reference(&self.docker.image) {
+            problem(String::from("docker.image"), e.to_string());
+        }
+
+        //Docker resolves the working directory inside the container, so it must be absolute
+        if let Some(dir) = self.docker.working_dir.as_ref().filter(|dir| !dir.starts_with('/')) {
+            problem(String::from("docker.working_dir"), format!("'{}' must be an absolute path in the container", dir));
+        }
+
+        if let Some(ref healthcheck) = self.healthcheck {
+            if healthcheck.command.is_some() == healthcheck.port.is_some() {
+                problem(String::from("healthcheck"), String::from("Exactly one of 'command' or 'port' must be given"));
+            }
+
+            if healthcheck.interval == 0 || healthcheck.timeout == 0 || healthcheck.failure_threshold == 0 {
+                problem(String::from("healthcheck"), String::from("'interval', 'timeout', and 'failure_threshold' must be at least 1"));
+            }
+        }
+
+        //Images are served to clients, so they may not be read from outside the pod directory
+        for (field, image) in [("icon", self.icon.as_ref()), ("banner", self.banner.as_ref())] {
+            if let Some(image) = image.filter(|image| image.is_absolute() || image.components().any(|part| matches!(part, Component::ParentDir))) {
+                problem(String::from(field), format!("'{}' must be relative to the pod directory and may not leave it", image.display()));
+            }
+        }
+
+        //Docker creates missing directories for bind mounts, hiding a mistyped path until the
+        //container is found to be missing its data. Names without a separator are named volumes
+        for (i, volume) in self.docker.volume.iter().enumerate() {
+            if volume.local.is_absolute() && !tokio::fs::try_exists(&volume.local).await.unwrap_or(true) {
+                problem(format!("docker.volume[{}].local", i), format!("Host path '{}' does not exist", volume.local.display()));
+            }
+        }
+
+        //Mounts at a relative or differently spelled path silently write somewhere unexpected
+        let shadows = match validate_volumes(&mut self.docker.volume) {
+            Ok(shadows) => shadows,
+            Err(e) => {
+                problem(String::from("docker.volume"), e.to_string());
+                Vec::new()
+            },
+        };
+
+        let mut exposed = HashMap::<(u16, PodDockerPortProtocol), usize>::new();
+        let mut published = HashMap::<(u16, PodDockerPortProtocol), usize>::new();
+        let mut forwarded = HashMap::<(u16, PodDockerPortProtocol), usize>::new();
+        for (i, port) in self.docker.port.iter().enumerate() {
+            if let Some(first) = exposed.insert((port.expose, port.protocol), i) {
+                problem(format!("docker.port[{}].expose", i), format!("Container port {}/{} is already published by docker.port[{}]", port.expose, port.protocol.docker_name(), first));
+            }
+
+            if let Some(first) = published.insert((port.host_port(), port.protocol), i) {
+                problem(format!("docker.port[{}].host", i), format!("Host port {}/{} is already used by docker.port[{}]", port.host_port(), port.protocol.docker_name(), first));
+            }
+
+            if !port.upnp {
+                continue
+            }
+
+            if let Some(first) = forwarded.insert((port.external_port(), port.protocol), i) {
+                problem(format!("docker.port[{}].external", i), format!("External port {}/{} is already forwarded by docker.port[{}]", port.external_port(), port.protocol.docker_name(), first));
+            }
+        }
+
+        for (i, var) in self.docker.env.iter().enumerate() {
+            if var.key.is_empty() || var.key.contains(['=', '\0']) {
+                problem(format!("docker.env[{}].key", i), format!("Variable name '{}' must be non-empty and may not contain '=' or NUL", var.key));
+            }
+
+            //Values from other sources are read when the pod is enabled, so only their names are checked
+            match var.value {
+                PodDockerEnvValue::File(ref path) if path.as_os_str().is_empty() => {
+                    problem(format!("docker.env[{}].value_from_file", i), String::from("Path must name a file"));
+                },
+                PodDockerEnvValue::File(ref path) if !env_path_confined(path) => {
+                    problem(format!("docker.env[{}].value_from_file", i), format!("Path '{}' must be relative to the pod directory and may not contain '..'", path.display()));
+                },
+                PodDockerEnvValue::Host(ref name) if name.is_empty() || name.contains(['=', '\0']) => {
+                    problem(format!("docker.env[{}].from_host", i), format!("Variable name '{}' must be non-empty and may not contain '=' or NUL", name));
+                },
+                _ => (),
+            }
+        }
+
+        //Host names are placed in reverse proxy rules, which must not be able to inject matchers
+        if let Some(http) = self.docker.http.as_ref() {
+            if http.host.is_empty() || !http.host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-')) {
+                problem(String::from("docker.http.host"), format!("'{}' must be a host name made of letters, digits, '.', and '-'", http.host));
+            }
+        }
+
+        if let Err(e) = self.docker.validate_resources() {
+            problem(String::from("docker"), e.to_string());
+        }
+
+        match problems.is_empty() {
+            true => Ok(shadows),
+            false => Err(PodConfigInvalid(problems)),
+        }
+    }
+}
+
+/// Check the config of the pod in the given directory, or of every pod in it if it does not
+/// contain a pod config, printing every problem found. Fails if any config has a problem
+pub async fn run_validate(dir: &Path) -> ExitCode {
+    let dirs = match Pod::has_config(dir).await {
+        true => vec![dir.to_owned()],
+        false => {
+            let mut iter = match tokio::fs::read_dir(dir).await {
+                Ok(iter) => iter,
+                Err(e) => {
+                    eprintln!("Failed to read directory {}: {}", dir.display(), e);
+                    return ExitCode::FAILURE
+                },
+            };
+
+            let mut dirs = Vec::new();
+            while let Ok(Some(entry)) = iter.next_entry().await {
+                if entry.file_type().await.is_ok_and(|ft| ft.is_dir()) {
+                    dirs.push(entry.path());
+                }
+            }
+
+            dirs.sort_unstable();
+            dirs
+        },
+    };
+
+    if dirs.is_empty() {
+        eprintln!("No pods found in {}", dir.display());
+        return ExitCode::FAILURE
+    }
+
+    let mut failed = 0;
+    for dir in dirs.iter() {
+        match Pod::read_config(dir).await {
+            Ok((path, ..)) => println!("[PASS] {}", path.display()),
+            Err(PodLoadError::Invalid(PodConfigInvalid(problems))) => {
+                failed += 1;
+                println!("[FAIL] {}", dir.display());
+                for problem in problems {
+                    println!("{:>10}{}", "-> ", problem);
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                println!("[FAIL] {}", dir.display());
+                println!("{:>10}{}", "-> ", e);
+            },
+        }
+    }
+
+    println!("{}/{} pod configs are valid", dirs.len() - failed, dirs.len());
+    match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+impl fmt::Display for PodConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl fmt::Display for PodConfigInvalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str("; ")?;
+            }
+
+            write!(f, "{}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: &str = "id = \"x\"\nname = \"X\"\n";
+
+    async fn validate(config: &str) -> Result<Vec<PodVolumeShadow>, PodConfigInvalid> {
+        toml::from_str::<PodConfig>(config).unwrap().validate().await
+    }
+
+    /// Get the field of every problem found in the given config
+    async fn fields(config: &str) -> Vec<String> {
+        match validate(config).await {
+            Ok(..) => Vec::new(),
+            Err(PodConfigInvalid(problems)) => problems.into_iter().map(|problem| problem.field).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_config() {
+        let config = format!(
+            "{}icon = \"icon.png\"\n[docker]\nimage = \"alpine:3\"\nworking_dir = \"/app\"\n\
+            [[docker.port]]\nexpose = 80\nprotocol = \"tcp\"\nupnp = true\n\
+            [[docker.port]]\nexpose = 80\nprotocol = \"udp\"\n\
+            [[docker.env]]\nkey = \"A\"\nvalue = \"1\"\n",
+            BASE,
+        );
+        assert!(validate(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn every_problem_is_reported() {
+        let config = format!(
+            "{}icon = \"../icon.png\"\nbanner = \"/banner.png\"\n[healthcheck]\ninterval = 0\n\
+            [docker]\nimage = \"\"\nworking_dir = \"app\"\n",
+            BASE,
+        );
+        assert_eq!(fields(&config).await, ["docker.image", "docker.working_dir", "healthcheck", "healthcheck", "icon", "banner"]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_ports() {
+        let config = format!(
+            "{}[docker]\nimage = \"alpine:3\"\n\
+            [[docker.port]]\nexpose = 80\nprotocol = \"tcp\"\nupnp = true\n\
+            [[docker.port]]\nexpose = 80\nhost = 8080\nexternal = 80\nprotocol = \"tcp\"\nupnp = true\n\
+            [[docker.port]]\nexpose = 81\nhost = 8080\nprotocol = \"tcp\"\n",
+            BASE,
+        );
+        assert_eq!(fields(&config).await, ["docker.port[1].expose", "docker.port[1].external", "docker.port[2].host"]);
+    }
+
+    #[tokio::test]
+    async fn env_names() {
+        let config = format!(
+            "{}[docker]\nimage = \"alpine:3\"\n\
+            [[docker.env]]\nkey = \"\"\nvalue = \"1\"\n\
+            [[docker.env]]\nkey = \"A=B\"\nvalue = \"1\"\n\
+            [[docker.env]]\nkey = \"C\"\nfrom_host = \"\"\n\
+            [[docker.env]]\nkey = \"D\"\nvalue_from_file = \"\"\n\
+            [[docker.env]]\nkey = \"E\"\nvalue_from_file = \"../secret\"\n\
+            [[docker.env]]\nkey = \"F\"\nvalue_from_file = \"/etc/shadow\"\n\
+            [[docker.env]]\nkey = \"G\"\nvalue_from_file = \"./secrets/g\"\n",
+            BASE,
+        );
+        assert_eq!(
+            fields(&config).await,
+            [
+                "docker.env[0].key",
+                "docker.env[1].key",
+                "docker.env[2].from_host",
+                "docker.env[3].value_from_file",
+                "docker.env[4].value_from_file",
+                "docker.env[5].value_from_file",
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_volume_host_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "{}[docker]\nimage = \"alpine:3\"\n\
+            [[docker.volume]]\nlocal = \"{}\"\ncontainer = \"/data\"\n\
+            [[docker.volume]]\nlocal = \"{}\"\ncontainer = \"/missing\"\n\
+            [[docker.volume]]\nlocal = \"named\"\ncontainer = \"/named\"\n",
+            BASE,
+            dir.path().display(),
+            dir.path().join("missing").display(),
+        );
+        assert_eq!(fields(&config).await, ["docker.volume[1].local"]);
+    }
+
+    #[tokio::test]
+    async fn http_host_names() {
+        let config = |host: &str| format!("{}[docker]\nimage = \"alpine:3\"\nhttp = {{ host = \"{}\", port = 80 }}\n", BASE, host);
+        assert!(fields(&config("app.example-1.com")).await.is_empty());
+        assert_eq!(fields(&config("app.com, evil.com")).await, ["docker.http.host"]);
+        assert_eq!(fields(&config("")).await, ["docker.http.host"]);
+    }
+
+    #[test]
+    fn port_protocol_names() {
+        let config = toml::from_str::<PodConfig>(&format!(
+            "{}[docker]\nimage = \"alpine:3\"\n[[docker.port]]\nexpose = 1\nprotocol = \"udp\"\n[[docker.port]]\nexpose = 2\nprotocol = \"tcp\"\n",
+            BASE,
+        ))
+        .unwrap();
+        let protocols = config.docker.port.iter().map(|port| port.protocol).collect::<Vec<_>>();
+        assert_eq!(protocols, [PodDockerPortProtocol::Udp, PodDockerPortProtocol::Tcp]);
+    }
+
+    #[test]
+    fn problems_display_with_fields() {
+        let invalid = PodConfigInvalid(vec![
+            PodConfigProblem { field: String::from("a"), message: String::from("first") },
+            PodConfigProblem { field: String::from("b"), message: String::from("second") },
+        ]);
+        assert_eq!(invalid.to_string(), "a: first; b: second");
+    }
+}
@@ -0,0 +1,136 @@
+//! Validation of the volumes mounted into pod containers. Container paths are normalized when the
+//! pod is loaded so that two spellings of the same path are treated as one, and paths that Docker
+//! would resolve somewhere other than intended are refused. Volumes declared by the pod's image
+//! that no mount covers are reported when the pod is enabled, as Docker creates an anonymous
+//! volume for each of them that is not kept when the container is removed
+
+use std::{path::{Component, Path, PathBuf}, sync::PoisonError};
+
+use bollard::secret::ImageInspect;
+
+use super::{bounded::{BoundedDeque, PodCollectionCaps}, config::PodDockerMountConfig, Pod, PodManager};
+
+/// A mount whose container path is inside the container path of another mount, hiding the part
+/// of the other volume at that path
+#[derive(Debug, Clone)]
+pub struct PodVolumeShadow {
+    pub parent: PathBuf,
+    pub child: PathBuf,
+}
+
+/// Normalize the container paths of the given mounts in place, returning the mounts that shadow
+/// part of another mount
+pub fn validate_volumes(volumes: &mut [PodDockerMountConfig]) -> Result<Vec<PodVolumeShadow>, PodVolumeError> {
+    for volume in volumes.iter_mut() {
+        volume.container = normalize_container_path(&volume.container)?;
+    }
+
+    let mut shadows = Vec::new();
+    for (i, volume) in volumes.iter().enumerate() {
+        for other in volumes[i + 1..].iter() {
+            if volume.container == other.container {
+                return Err(PodVolumeError::Duplicate(volume.container.clone()))
+            }
+
+            let (parent, child) = match (volume.container.starts_with(&other.container), other.container.starts_with(&volume.container)) {
+                (true, _) => (&other.container, &volume.container),
+                (_, true) => (&volume.container, &other.container),
+                _ => continue,
+            };
+
+            shadows.push(PodVolumeShadow { parent: parent.clone(), child: child.clone() });
+        }
+    }
+
+    Ok(shadows)
+}
+
+/// Normalize a path inside the container by removing redundant separators and `.` segments,
+/// requiring it to be absolute and not contain `..` segments
+fn normalize_container_path(path: &Path) -> Result<PathBuf, PodVolumeError> {
+    if !path.has_root() {
+        return Err(PodVolumeError::Relative(path.to_owned()))
+    }
+
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => (),
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir | Component::Prefix(..) => return Err(PodVolumeError::ParentDir(path.to_owned())),
+        }
+    }
+
+    Ok(normalized)
+}
+
+impl Pod {
+    /// Record the volumes declared by the pod's image when it was last inspected
+    pub(super) fn set_image_volumes(&self, image: &ImageInspect) {
+        let volumes = image
+            .config
+            .as_ref()
+            .and_then(|config| config.volumes.as_ref())
+            .map(|volumes| volumes.keys().map(PathBuf::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        if volumes.len() > PodCollectionCaps::IMAGE_VOLUMES {
+            tracing::warn!(
+                "Image of pod {} declares {} volumes, only the last {} are checked against the pod's mounts",
+                self.id(),
+                volumes.len(),
+                PodCollectionCaps::IMAGE_VOLUMES,
+            );
+        }
+
+        let volumes = BoundedDeque::from_iter_capped(PodCollectionCaps::IMAGE_VOLUMES, volumes);
+
+        *self.image_volumes.lock().unwrap_or_else(PoisonError::into_inner) = Some(volumes);
+    }
+
+    /// Get the volumes declared by the pod's image that are not covered by one of the pod's
+    /// mounts, or `None` if the image has not been inspected
+    fn uncovered_image_volumes(&self) -> Option<Vec<PathBuf>> {
+        let config = self.config();
+        let declared = self.image_volumes.lock().unwrap_or_else(PoisonError::into_inner).clone()?;
+
+        Some(
+            declared
+                .iter()
+                .filter(|declared| {
+                    !config
+                        .docker
+                        .volume
+                        .iter()
+                        .any(|volume| declared.starts_with(&volume.container))
+                })
+                .cloned()
+                .collect()
+        )
+    }
+}
+
+impl PodManager {
+    /// Warn about volumes declared by the pod's image that the pod's config does not mount, using
+    /// the image metadata recorded when the image was last inspected
+    pub(super) fn lint_image_volumes(&self, pod: &Pod) {
+        for volume in pod.uncovered_image_volumes().into_iter().flatten() {
+            tracing::warn!(
+                "Image '{}' of pod {} declares a volume at {} that the pod does not mount - Docker will create an anonymous volume that is lost when the container is removed",
+                pod.config().docker.image,
+                pod.id(),
+                volume.display(),
+            );
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PodVolumeError {
+    #[error("Container path '{}' of volume must be absolute", .0.display())]
+    Relative(PathBuf),
+    #[error("Container path '{}' of volume may not contain '..'", .0.display())]
+    ParentDir(PathBuf),
+    #[error("Multiple volumes are mounted at container path '{}'", .0.display())]
+    Duplicate(PathBuf),
+}
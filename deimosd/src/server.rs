@@ -1,16 +1,29 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
 
 use api::{ApiConfig, ApiInitError, ApiPersistent, ApiState};
+use chrono::Utc;
+use footprint::DeimosFootprint;
+use lifecycle::DaemonLifecycle;
+use registry::RegistryConfig;
+use save::SaveFile;
 #[cfg(unix)]
 use tokio::signal::unix::SignalKind;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 use upnp::{Upnp, UpnpConfig};
 
-use crate::pod::{PodManager, PodManagerConfig, PodManagerInitError};
+use crate::{log::{LogConfig, LogHandle}, pod::{alert::PodAlertTransition, docker::record::{DockerEventRecordConfig, DockerEventRecorder}, timed::PodManagerPersistent, PodManager, PodManagerConfig, PodManagerInitError, PodState, PodStateCause}};
 
 
 mod api;
+pub mod doctor;
+pub mod footprint;
+pub mod lifecycle;
+pub mod registry;
+pub mod save;
+pub mod storage;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
 pub mod upnp;
 
 /// RPC server that listens for TCP connections and spawns tasks to serve clients
@@ -18,6 +31,18 @@ pub struct Deimos {
     pub pods: PodManager,
     upnp: Upnp,
     api: ApiState,
+    /// Handle used to adjust log levels at runtime
+    log: LogHandle,
+    /// Start time of this run and the termination reason of the previous run
+    lifecycle: DaemonLifecycle,
+    /// File that persistent state is saved to
+    save_file: SaveFile,
+    /// Service registry file written for reverse proxies, if enabled
+    registry: Option<RegistryConfig>,
+    /// Time allowed to stop all pods when the daemon shuts down
+    shutdown_timeout: Duration,
+    /// Maximum number of pods stopped at once when the daemon shuts down
+    shutdown_parallelism: usize,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -32,19 +57,77 @@ pub struct DeimosConfig {
     /// Configuration for the UPnP client
     #[serde(default)]
     pub upnp: UpnpConfig,
+    /// Log level filters applied on top of the defaults
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Profile of defaults applied to settings not given in the config file
+    #[serde(default)]
+    pub footprint: DeimosFootprint,
+    /// Service registry file listing the HTTP services of enabled pods for reverse proxies
+    #[serde(default)]
+    pub registry: Option<RegistryConfig>,
+    /// Time in seconds allowed to stop all pods when the daemon shuts down, after which the
+    /// containers of pods still running are removed forcefully
+    #[serde(default = "DeimosConfig::default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    /// Maximum number of pods stopped at once when the daemon shuts down
+    #[serde(default = "DeimosConfig::default_shutdown_parallelism")]
+    pub shutdown_parallelism: usize,
+    /// Options used to diagnose the daemon's handling of Docker events
+    #[serde(default)]
+    pub debug: DockerEventRecordConfig,
+}
+
+impl DeimosConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_shutdown_timeout_seconds() -> u64 {
+        90
+    }
+
+    /// Helper function for serde deserializer defaults
+    pub const fn default_shutdown_parallelism() -> usize {
+        4
+    }
+
+    /// Read and parse the config file at the given path, applying the defaults of the footprint
+    /// profile it selects before the settings are parsed
+    pub async fn load(path: &Path) -> Result<Self, DeimosConfigError> {
+        let buf = deimosproto::util::load_check_permissions(path)
+            .await
+            .map_err(|err| DeimosConfigError::Open { path: path.to_owned(), err })?;
+
+        let Ok(config) = String::from_utf8(buf) else {
+            return Err(DeimosConfigError::Utf8(path.to_owned()))
+        };
+
+        let mut table = toml::from_str::<toml::Table>(&config)
+            .map_err(|err| DeimosConfigError::Parse { path: path.to_owned(), err })?;
+
+        DeimosFootprint::apply(&mut table);
+        toml::Value::Table(table)
+            .try_into::<Self>()
+            .map_err(|err| DeimosConfigError::Parse { path: path.to_owned(), err })
+    }
 }
 
 /// Persistent state written to a save file specified in the config [DeimosConfig::save_path]
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct DeimosPersistent {
     api: ApiPersistent,
+    #[serde(default)]
+    pods: PodManagerPersistent,
 }
 
 impl Deimos {
     /// Create a new server instance, loading all required files from the configuration specified
     /// and creating a TCP listener for the control interface.
     /// Then run the server until an interrupt signal is received or a fatal error occurs
-    pub async fn run(config: DeimosConfig) -> Result<(), DeimosRunError> {
+    pub async fn run(config: DeimosConfig, log: LogHandle) -> Result<(), DeimosRunError> {
+        //Reading the mount table and resolving the persistent directories block
+        let config = tokio::task::spawn_blocking(move || config.check_storage().map(|()| config))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+
         let persistent = match std::fs::File::open(&config.save_path) {
             Ok(file) => serde_json::from_reader::<_, DeimosPersistent>(file)?,
             Err(e) => {
@@ -53,30 +136,71 @@ impl Deimos {
             }
         };
 
+        let lifecycle = DaemonLifecycle::load(&config.save_path);
+        match lifecycle.previous() {
+            previous if previous.unexpected() => tracing::warn!("Daemon restarted after {}", previous),
+            previous => tracing::info!("Daemon started after {}", previous),
+        }
+
+        if config.footprint != DeimosFootprint::Normal {
+            tracing::info!("Running with the {} footprint profile", config.footprint);
+        }
+
         let (upnp, upnp_rx) = Upnp::new(config.upnp).await?;
-        let api = ApiState::load(persistent.api, config.api, &upnp).await?;
-        let pods = PodManager::new(config.pod, upnp.clone()).await?;
+        let pods = PodManager::new(
+            config.pod,
+            upnp.clone(),
+            config.api.reserved_ports(),
+            DockerEventRecorder::spawn(&config.debug),
+        ).await?;
+        pods.restore(persistent.pods);
+        let api = ApiState::load(persistent.api, config.api, &upnp, &pods).await?;
         let this = Arc::new(
             Self {
                 pods,
                 api,
                 upnp,
+                log,
+                lifecycle,
+                save_file: SaveFile::new(config.save_path),
+                registry: config.registry,
+                shutdown_timeout: Duration::from_secs(config.shutdown_timeout_seconds),
+                shutdown_parallelism: config.shutdown_parallelism,
             }
         );
 
         let cancel = CancellationToken::new();
+        //The API is stopped separately so that it can be drained before pods are stopped
+        let api_cancel = CancellationToken::new();
         let upnp = tokio::task::spawn(this.clone().upnp_task(upnp_rx, cancel.clone()));
-        let api_server = tokio::task::spawn(this.clone().api_task(cancel.clone()));
+        //Containers are adopted before Docker events are handled or clients can connect
+        this.pods.adopt_containers().await;
+        let api_server = tokio::task::spawn(this.clone().api_task(api_cancel.clone()));
         let pods = tokio::task::spawn(this.clone().pod_task(cancel.clone()));
+        let schedule = tokio::task::spawn(this.clone().schedule_task(cancel.clone()));
+        let timed = tokio::task::spawn(this.clone().timed_task(cancel.clone()));
+        let alerts = tokio::task::spawn(this.clone().alert_task(cancel.clone()));
+        let heartbeat = tokio::task::spawn(this.clone().heartbeat_task(cancel.clone()));
+        let status = tokio::task::spawn(this.clone().status_task(cancel.clone()));
+        let tokens = tokio::task::spawn(this.clone().token_task(cancel.clone()));
+        let prune = tokio::task::spawn(this.clone().image_prune_task(cancel.clone()));
+        let dependencies = tokio::task::spawn(this.clone().dependency_task(cancel.clone()));
+        let health = tokio::task::spawn(this.clone().health_task(cancel.clone()));
+        let registry = tokio::task::spawn(this.clone().registry_task(cancel.clone()));
+        let directives = tokio::task::spawn(this.clone().directive_task(cancel.clone()));
+        #[cfg(all(unix, feature = "systemd"))]
+        let watchdog = tokio::task::spawn(this.clone().watchdog_task(cancel.clone()));
 
         #[cfg(unix)]
         {
-            let (mut int, mut term) = match (
+            let (mut int, mut term, mut usr1) = match (
                 tokio::signal::unix::signal(SignalKind::interrupt()),
                 tokio::signal::unix::signal(SignalKind::terminate()),
+                tokio::signal::unix::signal(SignalKind::user_defined1()),
             ) {
-                (Ok(int), Ok(term)) => (int, term),
-                (Err(e), _) | (_, Err(e)) => {
+                (Ok(int), Ok(term), Ok(usr1)) => (int, term, usr1),
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                    api_cancel.cancel();
                     cancel.cancel();
 
                     return Err(
@@ -85,56 +209,435 @@ impl Deimos {
                 }
             };
 
-            tokio::select! {
-                _ = int.recv() => {
-                    tracing::info!("Got SIGINT");
-                },
-                _ = term.recv() => {
-                    tracing::info!("Got SIGTERM");
-                },
-            };
+            loop {
+                tokio::select! {
+                    _ = int.recv() => {
+                        tracing::info!("Got SIGINT");
+                        break
+                    },
+                    _ = term.recv() => {
+                        tracing::info!("Got SIGTERM");
+                        break
+                    },
+                    _ = usr1.recv() => {
+                        tracing::info!("Got SIGUSR1");
+                        if let Err(e) = this.log.toggle_trace() {
+                            tracing::error!("Failed to toggle trace logging: {e}");
+                        }
+                    },
+                };
+            }
+
+            #[cfg(feature = "systemd")]
+            systemd::notify_stopping();
 
+            this.drain_api(api_server, api_cancel).await;
             cancel.cancel();
         }
 
+        #[cfg(all(unix, feature = "systemd"))]
+        let _ = watchdog.await;
+
+        let started = Instant::now();
         let _ = tokio::join! {
-            api_server,
             upnp,
             pods,
+            schedule,
+            timed,
+            alerts,
+            heartbeat,
+            status,
+            tokens,
+            prune,
+            dependencies,
+            health,
+            registry,
+            directives,
         };
+        tracing::info!("Stopped pods and background tasks in {:?}", started.elapsed());
 
-        let persistent = DeimosPersistent {
-            api: this.api.save()
-        };
+        let started = Instant::now();
+        let saved = this.save().await;
+        match saved {
+            Ok(()) => tracing::info!("Saved persistent state in {:?}", started.elapsed()),
+            Err(ref e) => tracing::error!("Failed to save persistent state on shutdown: {}", e),
+        }
+
+        if let Err(e) = this.lifecycle.shutdown() {
+            tracing::error!("Failed to mark clean shutdown in heartbeat file: {}", e);
+        }
+
+        //A failed save must end the daemon with an error so that supervisors report it
+        saved
+    }
+
+    /// Periodically expire token requests that have not been approved in time and revoke
+    /// approved tokens that have never been used, saving persistent state when tokens are used
+    /// for the first time or revoked
+    pub async fn token_task(self: Arc<Self>, cancel: CancellationToken) {
+        const TOKEN_INTERVAL: Duration = Duration::from_secs(60 * 10);
+        const REQUEST_INTERVAL: Duration = Duration::from_secs(10);
+
+        let mut interval = tokio::time::interval(TOKEN_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut requests = tokio::time::interval(REQUEST_INTERVAL);
+        requests.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-        serde_json::to_writer(
-            std::fs::File::create(&config.save_path)
-                .map_err(|err| DeimosRunError::SavePersistent { path: config.save_path.clone(), err })?,
-            &persistent
-        )?;
-        
-        Ok(())
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = requests.tick() => {
+                    self.api.auth.expire_requests(Utc::now()).await;
+                    continue
+                },
+                _ = interval.tick() => {},
+            };
+
+            self.api.auth.revoke_unused(Utc::now());
+
+            //Saves that failed are retried even if no tokens changed since
+            if self.api.auth.take_dirty() || self.save_file.unsaved() {
+                if let Err(e) = self.save().await {
+                    tracing::error!("Failed to save token state: {}", e);
+                }
+            }
+        }
     }
 
-    /// Monitor events received from the local Docker instance
+    /// Monitor events received from the local Docker instance, handling the events received for
+    /// each container in quick succession together
     pub async fn pod_task(self: Arc<Self>, cancel: CancellationToken) {
         let mut events = self.pods.eventloop();
-        
+        let mut debounce = self.pods.event_debouncer();
+        let (acted_tx, mut acted_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        loop {
+            let deadline = debounce.next_deadline();
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = events.next() => match event {
+                    Some(event) => debounce.push(event, tokio::time::Instant::now()),
+                    None => break,
+                },
+                Some(pod) = acted_rx.recv() => debounce.acted(pod, tokio::time::Instant::now()),
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {},
+            };
+
+            for burst in debounce.take_due(tokio::time::Instant::now()) {
+                let this = self.clone();
+                let acted = acted_tx.clone();
+                tokio::task::spawn(async move {
+                    let pod = burst.pod.id();
+                    if this.pods.handle_events(burst).await {
+                        let _ = acted.send(pod);
+                    }
+                });
+            }
+        }
+
+        let started = Instant::now();
+        let summary = self.pods.disable_all(self.shutdown_parallelism, self.shutdown_timeout).await;
+        tracing::info!(
+            "Stopped pods in {:?}: {} stopped gracefully, {} removed forcefully, {} failed",
+            started.elapsed(),
+            summary.stopped,
+            summary.forced,
+            summary.failed,
+        );
+    }
+
+    /// Perform scheduled pod transitions as they come due
+    pub async fn schedule_task(self: Arc<Self>, cancel: CancellationToken) {
+        const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+        let mut since = Utc::now();
+        let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            let now = Utc::now();
+            for (pod, transition) in self.pods.due_transitions(since, now) {
+                tracing::info!("Performing scheduled transition of pod {} to {:?}", pod.id(), transition.action);
+
+                let this = self.clone();
+                tokio::task::spawn(async move {
+                    let mut lock = pod.state().transact().await;
+                    lock.set_cause(PodStateCause::Scheduled);
+                    let result = match transition.action {
+                        PodState::Enabled => this.pods.enable(pod.clone(), lock).await.map_err(|e| e.to_string()),
+                        _ => this.pods.disable(pod.clone(), lock).await.map_err(|e| e.to_string()),
+                    };
+
+                    if let Err(e) = result {
+                        tracing::error!("Scheduled transition of pod {} failed: {}", pod.id(), e);
+                    }
+                });
+            }
+
+            since = now;
+        }
+    }
+
+    /// Periodically sample the resource usage of enabled pods with alert rules and report alerts
+    /// as they fire and clear
+    pub async fn alert_task(self: Arc<Self>, cancel: CancellationToken) {
+        const ALERT_INTERVAL: Duration = Duration::from_secs(30);
+
+        let pods = self
+            .pods
+            .pods()
+            .into_iter()
+            .filter(|pod| !pod.config().alerts.is_empty())
+            .collect::<Vec<_>>();
+
+        if pods.is_empty() {
+            return
+        }
+
+        if !self.pods.usage_sampling() {
+            tracing::warn!("Resource usage sampling is disabled, alert rules of {} pods will not be evaluated", pods.len());
+            return
+        }
+
+        let mut interval = tokio::time::interval(ALERT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            let samples = futures::future::join_all(pods.iter().map(|pod| self.pods.sample_usage(pod))).await;
+            let now = Utc::now();
+
+            for (pod, sample) in pods.iter().zip(samples) {
+                match sample {
+                    Ok(Some(usage)) => for (rule, transition) in pod.observe_usage(&usage, now) {
+                        match transition {
+                            PodAlertTransition::Fired => tracing::warn!("Alert for pod {} fired: {}", pod.id(), rule),
+                            PodAlertTransition::Cleared => tracing::info!("Alert for pod {} cleared: {}", pod.id(), rule),
+                        }
+                    },
+                    Ok(None) => for rule in pod.reset_alerts() {
+                        tracing::info!("Alert for pod {} on {} cleared as the pod is no longer enabled", pod.id(), rule);
+                    },
+                    Err(e) => tracing::warn!("Failed to sample resource usage of pod {}: {}", pod.id(), e),
+                }
+            }
+        }
+    }
+
+    /// Run the health checks of all pods that configure one, each in its own task
+    pub async fn health_task(self: Arc<Self>, cancel: CancellationToken) {
+        let checks = self
+            .pods
+            .pods()
+            .into_iter()
+            .filter(|pod| pod.config().healthcheck.is_some())
+            .map(|pod| {
+                let this = self.clone();
+                let cancel = cancel.clone();
+                tokio::task::spawn(async move { this.pods.health_check_task(pod, cancel).await })
+            })
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(checks).await;
+    }
+
+    /// Periodically remove images that pods no longer use if automatic pruning is configured,
+    /// saving the image history after images are removed
+    pub async fn image_prune_task(self: Arc<Self>, cancel: CancellationToken) {
+        let Some(period) = self.pods.image_prune_interval() else {
+            return
+        };
+
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        //The first tick completes immediately, images are not pruned as soon as the daemon starts
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            match self.pods.prune_images(false).await {
+                Ok(pruned) if pruned.is_empty() => tracing::trace!("No unused images to prune"),
+                Ok(pruned) => {
+                    tracing::info!(
+                        "Pruned {} unused images, reclaiming up to {} bytes",
+                        pruned.len(),
+                        pruned.iter().map(|image| image.size).sum::<u64>(),
+                    );
+
+                    if let Err(e) = self.save().await {
+                        tracing::error!("Failed to save image history after pruning: {}", e);
+                    }
+                },
+                Err(e) => tracing::error!("Failed to prune unused images: {}", e),
+            }
+        }
+    }
+
+    /// Restart pods with `restart_with_dependencies` set when the container of a pod they depend
+    /// on is created and started again
+    pub async fn dependency_task(self: Arc<Self>, cancel: CancellationToken) {
+        let mut starts = self.pods.subscribe_starts();
+
+        loop {
+            let (dependency, started) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                start = starts.recv() => match start {
+                    Ok(start) => start,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Missed {} pod starts when checking for dependent restarts", skipped);
+                        continue
+                    },
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+            };
+
+            for pod in self.pods.take_dependent_restarts(&dependency) {
+                let this = self.clone();
+                let dependency = dependency.clone();
+                tokio::task::spawn(async move {
+                    let lock = pod.state().read().await;
+                    if !PodManager::dependent_outdated(&lock, started) {
+                        this.pods.abandon_dependent_restart(&pod);
+                        return
+                    }
+
+                    tracing::info!("Restarting pod {} as dependency {} restarted", pod.id(), dependency);
+                    let mut lock = pod.state().upgrade(lock);
+                    lock.set_cause(PodStateCause::DependencyRestarted);
+                    let result = match this.pods.disable(pod.clone(), lock).await {
+                        Ok(()) => this.pods.enable(pod.clone(), pod.state().transact().await).await.map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+
+                    if let Err(e) = result {
+                        this.pods.abandon_dependent_restart(&pod);
+                        tracing::error!("Failed to restart pod {} after dependency {} restarted: {}", pod.id(), dependency, e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Save persistent state whenever a pod is commanded into a new state or its restart policy
+    /// stops restarting it, so that the pods to restore are known even if the host stops without
+    /// the daemon shutting down
+    pub async fn directive_task(self: Arc<Self>, cancel: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = self.pods.directives_changed() => {},
+            };
+
+            if let Err(e) = self.save().await {
+                tracing::error!("Failed to save pod directives: {}", e);
+            }
+        }
+    }
+
+    /// Enable the pods that were enabled when the daemon last stopped if the pod manager is
+    /// configured to restore them. Pods that fail to start are left disabled
+    pub async fn restore_task(self: Arc<Self>) {
+        if !self.pods.restores_on_start() {
+            return
+        }
+
+        let pods = self.pods.restorable();
+        if pods.is_empty() {
+            return
+        }
+
+        tracing::info!("Restoring {} pods that were enabled when the daemon stopped", pods.len());
+        let results = futures::future::join_all(pods.iter().map(|pod| async {
+            let mut lock = pod.state().transact().await;
+            lock.set_cause(PodStateCause::Restored);
+            self.pods.enable(pod.clone(), lock).await
+        }))
+        .await;
+
+        let mut restored = 0;
+        for (pod, result) in pods.iter().zip(results) {
+            match result {
+                Ok(()) => restored += 1,
+                Err(e) => tracing::error!("Failed to restore pod {}, leaving it disabled: {}", pod.id(), e),
+            }
+        }
+
+        tracing::info!("Restored {}/{} pods", restored, pods.len());
+    }
+
+    /// Disable pods when their timed enable expires, resuming timed enables that were restored
+    /// from the save file
+    pub async fn timed_task(self: Arc<Self>, cancel: CancellationToken) {
+        for (pod, until) in self.pods.timed() {
+            tracing::info!("Resuming timed enable of pod {} until {}", pod.id(), until);
 
-        while let Some((pod, action)) = tokio::select! {
-            _ = cancel.cancelled() => None,
-            v = events.next() => v,
-        } {
             let this = self.clone();
             tokio::task::spawn(async move {
-                this.pods.handle_event(pod, action).await;
+                let mut lock = pod.state().transact().await;
+                lock.set_cause(PodStateCause::Requested);
+                if let Err(e) = this.pods.enable(pod.clone(), lock).await {
+                    tracing::error!("Failed to resume timed enable of pod {}: {}", pod.id(), e);
+                }
             });
         }
 
-        self.pods.disable_all().await;
+        loop {
+            let sleep = match self.pods.next_timed_expiry() {
+                Some(at) => (at - Utc::now()).to_std().unwrap_or_default(),
+                None => Duration::MAX,
+            };
+
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = self.pods.timed_changed() => continue,
+                _ = tokio::time::sleep(sleep) => {},
+            };
+
+            for pod in self.pods.take_expired_timed(Utc::now()) {
+                tracing::info!("Timed enable of pod {} expired", pod.id());
+
+                let this = self.clone();
+                tokio::task::spawn(async move {
+                    let mut lock = pod.state().transact().await;
+                    lock.set_cause(PodStateCause::TimedEnableExpired);
+                    if let Err(e) = this.pods.disable(pod.clone(), lock).await {
+                        tracing::error!("Failed to disable pod {} after its timed enable expired: {}", pod.id(), e);
+                    }
+                });
+            }
+        }
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DeimosConfigError {
+    #[error("Failed to open config file {}: {}", path.display(), err)]
+    Open {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    #[error("Cannot decode config file '{}' as UTF-8", .0.display())]
+    Utf8(PathBuf),
+    #[error("Failed to parse config file at {}: {}", path.display(), err)]
+    Parse {
+        path: PathBuf,
+        err: toml::de::Error,
+    },
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum DeimosRunError {
     #[error("Failed to create save file {}: {}", path.display(), err)]
@@ -152,4 +655,9 @@ pub enum DeimosRunError {
     Upnp(#[from] upnp::UpnpInitError),
     #[error("Failed to subscribe to signals: {0}")]
     Signal(#[source] std::io::Error),
+    #[error("Save file {} is on read-only filesystem {}", path.display(), mount)]
+    ReadOnlySavePath {
+        path: PathBuf,
+        mount: String,
+    },
 }
@@ -0,0 +1,401 @@
+//! Interactive terminal sessions opened by clients in pods that allow them, with tokens that are
+//! allowed to open them. Each session is driven by a task forwarding client input and TTY output,
+//! which closes the session when either side disconnects or the pod's idle or absolute session
+//! timeout expires
+
+use std::{sync::Arc, time::Duration};
+
+use bollard::container::LogOutput;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use tokio::{sync::mpsc, time::Instant};
+use tonic::async_trait;
+
+use deimosproto as proto;
+
+use crate::{pod::{docker::attach::{PodAttachError, PodAttachSession}, id::DeimosId}, server::Deimos};
+
+use super::auth::ApiTokenScope;
+
+impl Deimos {
+    /// Size of a session's TTY used when the client does not give one
+    const ATTACH_DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+    /// Open a terminal session in the pod selected by the first message of the given input
+    /// stream, returning the stream of output sent to the client
+    pub(super) async fn attach_session(
+        self: Arc<Self>,
+        scope: &ApiTokenScope,
+        mut input: tonic::Streaming<proto::PodAttachInput>,
+    ) -> Result<BoxStream<'static, Result<proto::PodAttachOutput, tonic::Status>>, tonic::Status> {
+        self.api.drain.check()?;
+        let first = input.message().await?.ok_or_else(|| {
+            proto::ErrorDetail::new(proto::ErrorCode::UnknownError, "Input stream closed before selecting a pod to attach to")
+                .into_status(tonic::Code::InvalidArgument)
+        })?;
+
+        let pod = self.lookup_scoped_pod(scope, first.id)?;
+        let id = pod.id();
+        if !scope.attach() {
+            return Err(
+                proto::ErrorDetail::new(proto::ErrorCode::AttachNotAllowed, "Token is not allowed to open terminal sessions")
+                    .with_pod(id.owned())
+                    .into_status(tonic::Code::PermissionDenied)
+            )
+        }
+
+        let (columns, rows) = first.size.map(attach_size).unwrap_or(Self::ATTACH_DEFAULT_SIZE);
+
+        let mut session = self.pods.attach(&pod, columns, rows).await.map_err(|e| {
+            let (code, status) = match e {
+                PodAttachError::NotAllowed => (proto::ErrorCode::AttachNotAllowed, tonic::Code::PermissionDenied),
+                PodAttachError::NotEnabled => (proto::ErrorCode::PodNotEnabled, tonic::Code::FailedPrecondition),
+                PodAttachError::Detached | PodAttachError::Docker(_) => (proto::ErrorCode::InternalError, tonic::Code::Internal),
+            };
+
+            proto::ErrorDetail::new(code, format!("Failed to open terminal session in pod {}: {}", id, e))
+                .with_pod(id.owned())
+                .into_status(status)
+        })?;
+
+        if !first.stdin.is_empty() {
+            if let Err(e) = session.write(&first.stdin).await {
+                tracing::warn!("Failed to write input to terminal session {} in pod {}: {}", session.id(), id, e);
+            }
+        }
+
+        //Timeouts are those of the config that allowed the session, even if the config is reloaded
+        let (idle, max) = (session.idle_timeout, session.max_duration);
+        let (tx, rx) = mpsc::channel(16);
+        tokio::task::spawn(Self::attach_task(session, input, tx, id, idle, max));
+
+        Ok(self.api.drain.stream(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    /// Forward input and output of the given session until it ends, then send the reason that
+    /// it ended to the client if it is still connected
+    async fn attach_task<P: AttachProcess, I: Stream<Item = Result<proto::PodAttachInput, tonic::Status>> + Unpin>(
+        mut session: P,
+        mut input: I,
+        tx: mpsc::Sender<Result<proto::PodAttachOutput, tonic::Status>>,
+        id: DeimosId,
+        idle: Duration,
+        max: Duration,
+    ) {
+        let deadline = tokio::time::sleep(max);
+        let idle_deadline = tokio::time::sleep(idle);
+        tokio::pin!(deadline, idle_deadline);
+
+        let reason = loop {
+            tokio::select! {
+                _ = &mut deadline => break Some(proto::PodAttachEnd::SessionTimeout),
+                _ = &mut idle_deadline => break Some(proto::PodAttachEnd::IdleTimeout),
+                _ = tx.closed() => break None,
+                message = input.next() => match message {
+                    Some(Ok(message)) => {
+                        idle_deadline.as_mut().reset(Instant::now() + idle);
+                        if let Some(size) = message.size {
+                            let (columns, rows) = attach_size(size);
+                            if let Err(e) = session.resize(columns, rows).await {
+                                tracing::warn!("Failed to resize terminal session {} in pod {}: {}", session.id(), id, e);
+                            }
+                        }
+
+                        if !message.stdin.is_empty() {
+                            if let Err(e) = session.write(&message.stdin).await {
+                                tracing::warn!("Failed to write input to terminal session {} in pod {}: {}", session.id(), id, e);
+                                break Some(proto::PodAttachEnd::ContainerLost)
+                            }
+                        }
+                    },
+                    None => break None,
+                    Some(Err(e)) => {
+                        tracing::trace!("Input stream of terminal session {} in pod {} failed: {}", session.id(), id, e);
+                        break None
+                    }
+                },
+                output = session.next_output() => match output {
+                    Some(Ok(output)) => if let Some(output) = attach_output(output) {
+                        if tx.send(Ok(output)).await.is_err() {
+                            break None
+                        }
+                    },
+                    Some(Err(e)) => {
+                        tracing::warn!("Output stream of terminal session {} in pod {} failed: {}", session.id(), id, e);
+                        break Some(proto::PodAttachEnd::ContainerLost)
+                    },
+                    None => break Some(proto::PodAttachEnd::Exited),
+                }
+            }
+        };
+
+        match reason {
+            Some(reason) => {
+                let (reason, exit_code) = match reason {
+                    proto::PodAttachEnd::Exited => match session.exit_code().await {
+                        Ok(Some(code)) => (reason, code),
+                        Ok(None) => (proto::PodAttachEnd::ContainerLost, 0),
+                        Err(e) => {
+                            tracing::warn!("Failed to get exit code of terminal session {} in pod {}: {}", session.id(), id, e);
+                            (proto::PodAttachEnd::ContainerLost, 0)
+                        }
+                    },
+                    reason => (reason, 0),
+                };
+
+                tracing::info!("Terminal session {} in pod {} ended: {:?}", session.id(), id, reason);
+                let exit = proto::PodAttachOutput {
+                    exit: Some(proto::PodAttachExit { reason: reason as i32, exit_code }),
+                    ..Default::default()
+                };
+
+                let _ = tx.send(Ok(exit)).await;
+            },
+            None => tracing::info!("Client disconnected from terminal session {} in pod {}", session.id(), id),
+        }
+
+        session.close().await;
+    }
+}
+
+/// Process that a terminal session is attached to, implemented by [PodAttachSession] for
+/// processes run in pod containers
+#[async_trait]
+trait AttachProcess: Send + 'static {
+    fn id(&self) -> &str;
+
+    /// Write input to the process's TTY
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error>;
+
+    /// Change the size of the process's TTY
+    async fn resize(&mut self, columns: u16, rows: u16) -> Result<(), bollard::errors::Error>;
+
+    /// Wait for the next output written by the process, or `None` once it exits
+    async fn next_output(&mut self) -> Option<Result<LogOutput, bollard::errors::Error>>;
+
+    /// Get the exit code of the process, or `None` if it is still running
+    async fn exit_code(&mut self) -> Result<Option<i64>, bollard::errors::Error>;
+
+    /// End the session, stopping the process if it is still running
+    async fn close(self);
+}
+
+#[async_trait]
+impl AttachProcess for PodAttachSession {
+    fn id(&self) -> &str {
+        PodAttachSession::id(self)
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        PodAttachSession::write(self, bytes).await
+    }
+
+    async fn resize(&mut self, columns: u16, rows: u16) -> Result<(), bollard::errors::Error> {
+        PodAttachSession::resize(self, columns, rows).await
+    }
+
+    async fn next_output(&mut self) -> Option<Result<LogOutput, bollard::errors::Error>> {
+        self.output.next().await
+    }
+
+    async fn exit_code(&mut self) -> Result<Option<i64>, bollard::errors::Error> {
+        PodAttachSession::exit_code(self).await
+    }
+
+    async fn close(self) {
+        PodAttachSession::close(self).await
+    }
+}
+
+/// Get the size of a TTY in columns and rows from the size given by a client
+fn attach_size(size: proto::PodAttachSize) -> (u16, u16) {
+    let clamp = |value: u32| u16::try_from(value).unwrap_or(u16::MAX).max(1);
+    (clamp(size.columns), clamp(size.rows))
+}
+
+/// Get the message sent to the client for output of a session, or `None` for echoed input
+fn attach_output(output: LogOutput) -> Option<proto::PodAttachOutput> {
+    match output {
+        LogOutput::StdErr { message } => Some(proto::PodAttachOutput { stderr: message.to_vec(), ..Default::default() }),
+        LogOutput::StdOut { message } | LogOutput::Console { message } => Some(proto::PodAttachOutput { stdout: message.to_vec(), ..Default::default() }),
+        LogOutput::StdIn { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::*;
+
+    const IDLE: Duration = Duration::from_secs(60);
+    const MAX: Duration = Duration::from_secs(600);
+
+    /// What a [FakeProcess] was asked to do
+    #[derive(Debug, Default)]
+    struct FakeLog {
+        input: Vec<u8>,
+        sizes: Vec<(u16, u16)>,
+        closed: bool,
+    }
+
+    /// Process whose output is sent by the test, exiting with the given code once the sender is
+    /// dropped
+    struct FakeProcess {
+        output: mpsc::UnboundedReceiver<LogOutput>,
+        exit_code: i64,
+        log: Arc<Mutex<FakeLog>>,
+    }
+
+    #[async_trait]
+    impl AttachProcess for FakeProcess {
+        fn id(&self) -> &str {
+            "fake"
+        }
+
+        async fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+            self.log.lock().unwrap().input.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        async fn resize(&mut self, columns: u16, rows: u16) -> Result<(), bollard::errors::Error> {
+            self.log.lock().unwrap().sizes.push((columns, rows));
+            Ok(())
+        }
+
+        async fn next_output(&mut self) -> Option<Result<LogOutput, bollard::errors::Error>> {
+            self.output.recv().await.map(Ok)
+        }
+
+        async fn exit_code(&mut self) -> Result<Option<i64>, bollard::errors::Error> {
+            Ok(Some(self.exit_code))
+        }
+
+        async fn close(self) {
+            self.log.lock().unwrap().closed = true;
+        }
+    }
+
+    /// A session being driven by [Deimos::attach_task], with handles to act as the client and
+    /// the process
+    struct Session {
+        input: Option<mpsc::UnboundedSender<Result<proto::PodAttachInput, tonic::Status>>>,
+        output: Option<mpsc::UnboundedSender<LogOutput>>,
+        client: mpsc::Receiver<Result<proto::PodAttachOutput, tonic::Status>>,
+        log: Arc<Mutex<FakeLog>>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    fn session(exit_code: i64) -> Session {
+        let (input, input_rx) = mpsc::unbounded_channel();
+        let (output, output_rx) = mpsc::unbounded_channel();
+        let (tx, client) = mpsc::channel(16);
+        let log = Arc::new(Mutex::new(FakeLog::default()));
+        let process = FakeProcess { output: output_rx, exit_code, log: log.clone() };
+        let id = serde_json::from_value(serde_json::Value::from("web")).unwrap();
+        let task = tokio::task::spawn(Deimos::attach_task(process, UnboundedReceiverStream::new(input_rx), tx, id, IDLE, MAX));
+
+        Session { input: Some(input), output: Some(output), client, log, task }
+    }
+
+    impl Session {
+        fn send(&self, message: proto::PodAttachInput) {
+            self.input.as_ref().unwrap().send(Ok(message)).unwrap();
+        }
+
+        /// Wait for the session to end, returning the reason and exit code sent to the client
+        async fn end(mut self) -> (Option<(proto::PodAttachEnd, i64)>, Arc<Mutex<FakeLog>>) {
+            self.task.await.unwrap();
+            let mut exit = None;
+            while let Some(Ok(output)) = self.client.recv().await {
+                if let Some(end) = output.exit {
+                    exit = Some((end.reason(), end.exit_code));
+                }
+            }
+
+            (exit, self.log)
+        }
+    }
+
+    fn stdin(bytes: &[u8]) -> proto::PodAttachInput {
+        proto::PodAttachInput { stdin: bytes.to_vec(), ..Default::default() }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_session_times_out() {
+        let session = session(0);
+        let started = Instant::now();
+        let (exit, log) = session.end().await;
+
+        assert_eq!(exit, Some((proto::PodAttachEnd::IdleTimeout, 0)));
+        assert_eq!(started.elapsed(), IDLE);
+        assert!(log.lock().unwrap().closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn input_defers_idle_timeout_until_session_timeout() {
+        let session = session(0);
+        let input = session.input.clone().unwrap();
+        tokio::task::spawn(async move {
+            while input.send(Ok(stdin(b"."))).is_ok() {
+                tokio::time::sleep(IDLE / 2).await;
+            }
+        });
+
+        let started = Instant::now();
+        let (exit, log) = session.end().await;
+
+        assert_eq!(exit, Some((proto::PodAttachEnd::SessionTimeout, 0)));
+        assert_eq!(started.elapsed(), MAX);
+        assert!(log.lock().unwrap().closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exit_code_is_reported_when_process_exits() {
+        let mut session = session(3);
+        session.output.take();
+        let (exit, log) = session.end().await;
+
+        assert_eq!(exit, Some((proto::PodAttachEnd::Exited, 3)));
+        assert!(log.lock().unwrap().closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_disconnect_closes_session() {
+        let mut session = session(0);
+        session.input.take();
+        let (exit, log) = session.end().await;
+
+        assert_eq!(exit, None);
+        assert!(log.lock().unwrap().closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn input_and_output_are_forwarded() {
+        let mut session = session(0);
+        session.send(proto::PodAttachInput {
+            stdin: b"ls\n".to_vec(),
+            size: Some(proto::PodAttachSize { columns: 120, rows: 0 }),
+            ..Default::default()
+        });
+
+        let output = session.output.as_ref().unwrap();
+        output.send(LogOutput::StdIn { message: "ls\n".into() }).unwrap();
+        output.send(LogOutput::Console { message: "file\n".into() }).unwrap();
+        output.send(LogOutput::StdErr { message: "warning\n".into() }).unwrap();
+
+        let first = session.client.recv().await.unwrap().unwrap();
+        let second = session.client.recv().await.unwrap().unwrap();
+        assert_eq!(first.stdout, b"file\n");
+        assert_eq!(second.stderr, b"warning\n");
+
+        session.output.take();
+        let (exit, log) = session.end().await;
+        assert_eq!(exit, Some((proto::PodAttachEnd::Exited, 0)));
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.input, b"ls\n");
+        assert_eq!(log.sizes, [(120, 1)]);
+    }
+}
@@ -3,9 +3,14 @@
 
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tonic::async_trait;
 
-use crate::server::Deimos;
+use crate::{log::LogLevelError, pod::{archive::PodArchiveError, docker::containers::PodContainerInfo, env::{PodEnvOverrideError, PodEnvOverrideUpdate}, history::{PodConfigHistoryError, PodConfigRollbackError, PodConfigVersion}, id::DeimosId, reload::PodReloadError, Pod, PodState, PodStateKnown}, server::{upnp::{UpnpLeaseOwner, UpnpLeaseStatus}, Deimos}};
+
+use super::{ApiTokenRevokeError, ApiTokenScope, ApiTokenSelector};
 
 #[async_trait]
 impl deimosproto::internal_server::Internal for Deimos {
@@ -26,28 +31,652 @@ impl deimosproto::internal_server::Internal for Deimos {
 
     async fn approve(self: Arc<Self>, req: tonic::Request<deimosproto::ApproveRequest>)
         -> Result<tonic::Response<deimosproto::ApproveResponse>, tonic::Status> {
-        let user = req.into_inner().username;
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("approve", &req.pin).await?;
+        let user = req.username;
+        let scope = ApiTokenScope::new(req.pods, req.groups)
+            .map_err(|e| {
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InvalidArgument, e.to_string())
+                    .into_status(tonic::Code::InvalidArgument)
+            })?
+            .with_attach(req.attach);
+        let print_token = req.print_token;
 
-        let pending = self.api.auth.pending.remove(&*user);
+        let pending = self.api.auth.pending.take(&user);
 
         match pending {
-            Some((user, pend)) => {
-                tracing::info!("Approved token request for '{}'", user);
-
-                self
-                    .api
-                    .auth
-                    .approve(pend)
-                    .await
-                    .map(|_| tonic::Response::new(deimosproto::ApproveResponse {}))
-                    .map_err(|e| tonic::Status::internal(e.to_string()))
+            Some(pend) => {
+                match scope.unrestricted() {
+                    true => tracing::info!("Approved token request for '{}'", user),
+                    false => tracing::info!(
+                        "Approved token request for '{}' limited to pods {:?} and groups {:?}",
+                        user,
+                        scope.pods(),
+                        scope.groups(),
+                    ),
+                }
+
+                if scope.attach() {
+                    tracing::warn!("Token for '{}' may open terminal sessions in pods that allow them", user);
+                }
+
+                let token = self.api.auth.approve(pend, scope).await;
+                if token.is_ok() {
+                    if let Err(e) = self.save().await {
+                        tracing::error!("Failed to save approved token for '{}': {}", user, e);
+                    }
+                }
+
+                token
+                    .map(|token| {
+                        if !print_token {
+                            return tonic::Response::new(deimosproto::ApproveResponse::default())
+                        }
+
+                        tracing::warn!(
+                            "Token for '{}' ({}) was returned to the internal API to be delivered out of band",
+                            user,
+                            token.key().fingerprint(),
+                        );
+
+                        let grace = chrono::TimeDelta::from_std(self.api.auth.config.unused_token_grace).unwrap_or(chrono::TimeDelta::MAX);
+                        tonic::Response::new(deimosproto::ApproveResponse {
+                            unused_deadline: token.issued().checked_add_signed(grace).map(|at| at.timestamp()).unwrap_or_default(),
+                            token: Some(token.proto()),
+                        })
+                    })
+                    .map_err(|e| {
+                        deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, e.to_string())
+                            .into_status(tonic::Code::Internal)
+                    })
             },
             None => {
                 Err(
-                    tonic::Status::not_found(format!("Request with username {} not found", user))
+                    deimosproto::ErrorDetail::new(deimosproto::ErrorCode::RequestNotFound, format!("Request with username {} not found", user))
+                        .into_status(tonic::Code::NotFound)
                 )
             }
         }
     }
+
+    async fn deny(self: Arc<Self>, req: tonic::Request<deimosproto::DenyRequest>)
+        -> Result<tonic::Response<deimosproto::DenyResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("deny", &req.pin).await?;
+        let user = req.username;
+
+        match self.api.auth.pending.resolve(&user, deimosproto::PendingTokenEventKind::Denied) {
+            Some(pend) => {
+                let reason = match req.reason.trim() {
+                    "" => String::from("Token request was denied by the server operator"),
+                    reason => reason.to_owned(),
+                };
+
+                tracing::info!("Denied token request for '{}': {}", user, reason);
+                pend.deny(reason).await;
+                Ok(tonic::Response::new(deimosproto::DenyResponse {}))
+            },
+            None => {
+                Err(
+                    deimosproto::ErrorDetail::new(deimosproto::ErrorCode::RequestNotFound, format!("Request with username {} not found", user))
+                        .into_status(tonic::Code::NotFound)
+                )
+            }
+        }
+    }
+
+    async fn get_tokens(self: Arc<Self>, _req: tonic::Request<deimosproto::GetTokensRequest>)
+        -> Result<tonic::Response<deimosproto::GetTokensResponse>, tonic::Status> {
+        let mut tokens = self.api.auth.tokens();
+        tokens.sort_by_key(|token| token.issued);
+
+        Ok(
+            tonic::Response::new(deimosproto::GetTokensResponse { tokens })
+        )
+    }
+
+    async fn revoke_token(self: Arc<Self>, req: tonic::Request<deimosproto::RevokeTokenRequest>)
+        -> Result<tonic::Response<deimosproto::RevokeTokenResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("revoke", &req.pin).await?;
+
+        let selector = match (req.username.as_str(), req.fingerprint.as_str()) {
+            (user, "") if !user.is_empty() => ApiTokenSelector::User(user),
+            ("", fingerprint) if !fingerprint.is_empty() => ApiTokenSelector::Fingerprint(fingerprint),
+            _ => return Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::UnknownError, "Exactly one of username or fingerprint must be given")
+                    .into_status(tonic::Code::InvalidArgument)
+            ),
+        };
+
+        let token = self.api.auth.revoke(selector).map_err(|e| match e {
+            ApiTokenRevokeError::NotFound => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenNotFound, e.to_string())
+                .into_status(tonic::Code::NotFound),
+            ApiTokenRevokeError::Ambiguous(..) => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenAmbiguous, e.to_string())
+                .into_status(tonic::Code::FailedPrecondition),
+        })?;
+
+        if let Err(e) = self.save().await {
+            tracing::error!("Failed to save revocation of token for '{}': {}", token.user(), e);
+        }
+
+        Ok(tonic::Response::new(deimosproto::RevokeTokenResponse { token: Some(token.info()) }))
+    }
+
+    type WatchPendingStream = BoxStream<'static, Result<deimosproto::PendingTokenEvent, tonic::Status>>;
+
+    async fn watch_pending(self: Arc<Self>, _req: tonic::Request<deimosproto::WatchPendingRequest>)
+        -> Result<tonic::Response<Self::WatchPendingStream>, tonic::Status> {
+        let stream = BroadcastStream::new(self.api.auth.pending.subscribe())
+            .map(|event: Result<_, BroadcastStreamRecvError>| {
+                event.map_err(|e| {
+                    deimosproto::ErrorDetail::new(deimosproto::ErrorCode::EventsLagged, e.to_string())
+                        .into_status(tonic::Code::DataLoss)
+                })
+            });
+
+        Ok(tonic::Response::new(self.api.drain.stream(stream)))
+    }
+
+    async fn get_log_levels(self: Arc<Self>, _req: tonic::Request<deimosproto::GetLogLevelsRequest>)
+        -> Result<tonic::Response<deimosproto::GetLogLevelsResponse>, tonic::Status> {
+        let targets = self
+            .log
+            .targets()
+            .into_iter()
+            .map(|(target, level)| deimosproto::LogTarget { target, level: level.to_string() })
+            .collect();
+
+        Ok(
+            tonic::Response::new(deimosproto::GetLogLevelsResponse { targets })
+        )
+    }
+
+    type DownloadLogsStream = BoxStream<'static, Result<deimosproto::LogArchiveChunk, tonic::Status>>;
+
+    async fn download_logs(self: Arc<Self>, req: tonic::Request<deimosproto::DownloadLogsRequest>)
+        -> Result<tonic::Response<Self::DownloadLogsStream>, tonic::Status> {
+        let chunk_size = self.chunk_size(req.metadata());
+        let req = req.into_inner();
+        self.check_pin("log download", &req.pin).await?;
+        let timestamp = |secs: i64| match secs {
+            0 => Ok(None),
+            secs => DateTime::from_timestamp(secs, 0).map(Some).ok_or_else(|| {
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InvalidArgument, format!("Invalid timestamp {}", secs))
+                    .into_status(tonic::Code::InvalidArgument)
+            }),
+        };
+
+        let (since, until) = (timestamp(req.since)?, timestamp(req.until)?);
+        let archive = self.export_logs(req.ids, since, until).await?;
+        tracing::info!("Exporting log archive of {} bytes", archive.size());
+
+        archive
+            .stream(chunk_size)
+            .await
+            .map(tonic::Response::new)
+            .map_err(|e| {
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, format!("Failed to open log archive: {}", e))
+                    .into_status(tonic::Code::Internal)
+            })
+    }
+
+    async fn set_pod_sharing(self: Arc<Self>, req: tonic::Request<deimosproto::SetPodSharingRequest>)
+        -> Result<tonic::Response<deimosproto::SetPodSharingResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("share", &req.pin).await?;
+        let pod = self.lookup_pod(req.id)?;
+
+        if !req.enabled {
+            if self.api.sharing.revoke(&pod.id()) {
+                tracing::info!("Revoked share link for pod {}", pod.id());
+            }
+
+            return Ok(tonic::Response::new(deimosproto::SetPodSharingResponse { path: String::new() }))
+        }
+
+        if self.api.config.status.is_none() {
+            return Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::SharingDisabled, "No [api.status] server is configured to serve shared pods")
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+            )
+        }
+
+        let slug = self.api.sharing.share(pod.id(), req.regenerate);
+        tracing::info!("Sharing status of pod {}", pod.id());
+
+        Ok(tonic::Response::new(deimosproto::SetPodSharingResponse { path: format!("/s/{}", slug) }))
+    }
+
+    async fn get_pod_config_history(self: Arc<Self>, req: tonic::Request<deimosproto::GetPodConfigHistoryRequest>)
+        -> Result<tonic::Response<deimosproto::GetPodConfigHistoryResponse>, tonic::Status> {
+        let pod = self.lookup_pod(req.into_inner().id)?;
+        let versions = pod
+            .config_history()
+            .await
+            .map_err(|e| config_history_status(&pod, e))?
+            .into_iter()
+            .map(|version| config_version(&pod, version))
+            .collect();
+
+        Ok(tonic::Response::new(deimosproto::GetPodConfigHistoryResponse { versions }))
+    }
+
+    async fn rollback_pod_config(self: Arc<Self>, req: tonic::Request<deimosproto::RollbackPodConfigRequest>)
+        -> Result<tonic::Response<deimosproto::RollbackPodConfigResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("config rollback", &req.pin).await?;
+        let pod = self.lookup_pod(req.id)?;
+
+        let version = self
+            .pods
+            .rollback_config(pod.clone(), &req.version)
+            .await
+            .map_err(|e| match e {
+                PodConfigRollbackError::History(e) => config_history_status(&pod, e),
+                e => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, e.to_string())
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::Internal),
+            })?;
+
+        Ok(tonic::Response::new(deimosproto::RollbackPodConfigResponse { version: Some(config_version(&pod, version)) }))
+    }
+
+    async fn archive_pod(self: Arc<Self>, req: tonic::Request<deimosproto::ArchivePodRequest>)
+        -> Result<tonic::Response<deimosproto::ArchivePodResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin(if req.archived { "archive" } else { "unarchive" }, &req.pin).await?;
+        let pod = self.lookup_pod(req.id)?;
+
+        let changed = pod
+            .set_archived(req.archived)
+            .await
+            .map_err(|e| match e {
+                PodArchiveError::NotDisabled => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::PodNotDisabled, e.to_string())
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::FailedPrecondition),
+            })?;
+
+        if changed {
+            if let Err(e) = self.save().await {
+                tracing::error!("Failed to save archived flag of pod {}: {}", pod.id(), e);
+            }
+        }
+
+        Ok(tonic::Response::new(deimosproto::ArchivePodResponse {}))
+    }
+
+    async fn prune_images(self: Arc<Self>, req: tonic::Request<deimosproto::PruneImagesRequest>)
+        -> Result<tonic::Response<deimosproto::PruneImagesResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        if !req.dry_run {
+            self.check_pin("image prune", &req.pin).await?;
+        }
+
+        let pruned = self
+            .pods
+            .prune_images(req.dry_run)
+            .await
+            .map_err(|e| {
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, format!("Failed to prune images: {}", e))
+                    .into_status(tonic::Code::Internal)
+            })?;
+
+        if !req.dry_run && !pruned.is_empty() {
+            if let Err(e) = self.save().await {
+                tracing::error!("Failed to save image history after pruning: {}", e);
+            }
+        }
+
+        let images = pruned
+            .into_iter()
+            .map(|image| deimosproto::PrunedImage {
+                id: image.id,
+                pods: image.pods,
+                size: image.size,
+            })
+            .collect();
+
+        Ok(tonic::Response::new(deimosproto::PruneImagesResponse { images }))
+    }
+
+    async fn get_pod_env_overrides(self: Arc<Self>, req: tonic::Request<deimosproto::GetPodEnvOverridesRequest>)
+        -> Result<tonic::Response<deimosproto::GetPodEnvOverridesResponse>, tonic::Status> {
+        let pod = self.lookup_pod(req.into_inner().id)?;
+        let (overrides, revision) = pod.env_overrides_revision();
+        let overrides = overrides
+            .into_iter()
+            .map(|(key, var)| deimosproto::PodEnvOverride {
+                key,
+                value: if var.secret { String::new() } else { var.value },
+                secret: var.secret,
+                keep: false,
+            })
+            .collect();
+
+        Ok(tonic::Response::new(deimosproto::GetPodEnvOverridesResponse {
+            overrides,
+            pending: pod.env_pending(),
+            version: revision.version,
+            modified: revision.modified.map(|at| at.timestamp()).unwrap_or_default(),
+            modified_by: revision.modified_by.unwrap_or_default(),
+        }))
+    }
+
+    async fn set_pod_env_overrides(self: Arc<Self>, req: tonic::Request<deimosproto::SetPodEnvOverridesRequest>)
+        -> Result<tonic::Response<deimosproto::SetPodEnvOverridesResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let editor = local_user(&req);
+        let req = req.into_inner();
+        self.check_pin("environment override", &req.pin).await?;
+        let pod = self.lookup_pod(req.id)?;
+
+        let updates = req
+            .overrides
+            .into_iter()
+            .map(|var| PodEnvOverrideUpdate {
+                key: var.key,
+                value: (!var.keep).then_some(var.value),
+                secret: var.secret,
+            })
+            .collect();
+
+        let expected = (!req.force).then_some(req.expected_version);
+        let revision = self
+            .pods
+            .set_env_overrides(&pod, updates, expected, editor)
+            .map_err(|e| match e {
+                PodEnvOverrideError::Conflict { ref current, .. } => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::StateConflict, e.to_string())
+                    .with_pod(pod.id().owned())
+                    .with_current_version(current.version)
+                    .into_status(tonic::Code::Aborted),
+                _ => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InvalidEnvOverride, e.to_string())
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::InvalidArgument),
+            })?;
+
+        if let Err(e) = self.save().await {
+            tracing::error!("Failed to save environment overrides of pod {}: {}", pod.id(), e);
+        }
+
+        Ok(tonic::Response::new(deimosproto::SetPodEnvOverridesResponse { pending: pod.env_pending(), version: revision.version }))
+    }
+
+    async fn get_pod_containers(self: Arc<Self>, _req: tonic::Request<deimosproto::GetPodContainersRequest>)
+        -> Result<tonic::Response<deimosproto::GetPodContainersResponse>, tonic::Status> {
+        let report = self
+            .pods
+            .pod_containers()
+            .await
+            .map_err(|e| {
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, format!("Failed to list Docker containers: {}", e))
+                    .into_status(tonic::Code::Internal)
+            })?;
+
+        let pods = report
+            .pods
+            .into_iter()
+            .map(|pod| deimosproto::PodContainers {
+                id: pod.id.owned(),
+                title: pod.title,
+                state: deimosproto::PodState::from(pod.state) as i32,
+                containers: pod.containers.into_iter().map(docker_container).collect(),
+            })
+            .collect();
+
+        Ok(tonic::Response::new(deimosproto::GetPodContainersResponse {
+            pods,
+            orphans: report.orphans.into_iter().map(docker_container).collect(),
+        }))
+    }
+
+    async fn reload_pods(self: Arc<Self>, req: tonic::Request<deimosproto::ReloadPodsRequest>)
+        -> Result<tonic::Response<deimosproto::ReloadPodsResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        self.check_pin("reload", &req.into_inner().pin).await?;
+
+        let summary = self
+            .pods
+            .reload()
+            .await
+            .map_err(|e| match e {
+                PodReloadError::Dependency(..) => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InvalidPodDependency, e.to_string())
+                    .into_status(tonic::Code::FailedPrecondition),
+                PodReloadError::Load(..) => deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, e.to_string())
+                    .into_status(tonic::Code::Internal),
+            })?;
+
+        let ids = |ids: Vec<DeimosId>| ids.iter().map(DeimosId::owned).collect();
+        Ok(tonic::Response::new(deimosproto::ReloadPodsResponse {
+            added: ids(summary.added),
+            removed: ids(summary.removed),
+            updated: ids(summary.updated),
+            errors: summary
+                .errors
+                .into_iter()
+                .map(|failure| deimosproto::PodReloadError {
+                    path: failure.path.display().to_string(),
+                    message: failure.message,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_memory_stats(self: Arc<Self>, _req: tonic::Request<deimosproto::GetMemoryStatsRequest>)
+        -> Result<tonic::Response<deimosproto::GetMemoryStatsResponse>, tonic::Status> {
+        let pods = self
+            .pods
+            .memory_usage()
+            .into_iter()
+            .map(|(pod, usage)| deimosproto::PodMemoryStats {
+                id: pod.id().owned(),
+                annotations: usage.annotations as u32,
+                annotation_capacity: usage.annotation_capacity as u32,
+                annotations_evicted: usage.annotations_evicted,
+                annotation_bytes: usage.annotation_bytes as u64,
+                env_bytes: usage.env_bytes as u64,
+                image_volume_bytes: usage.image_volume_bytes as u64,
+                total_bytes: usage.total_bytes() as u64,
+            })
+            .collect::<Vec<_>>();
+
+        Ok(tonic::Response::new(deimosproto::GetMemoryStatsResponse {
+            total_bytes: pods.iter().map(|pod| pod.total_bytes).sum(),
+            pods,
+        }))
+    }
+
+    async fn get_pod_status(self: Arc<Self>, _req: tonic::Request<deimosproto::GetPodStatusRequest>)
+        -> Result<tonic::Response<deimosproto::GetPodStatusResponse>, tonic::Status> {
+        let now = Utc::now();
+        let mut pods = self
+            .pods
+            .pods()
+            .into_iter()
+            .map(|pod| {
+                let (state, container_id, uptime_seconds) = match pod.state().try_read() {
+                    Some(lock) => match *lock {
+                        PodStateKnown::Disabled => (PodState::Disabled, String::new(), None),
+                        PodStateKnown::Paused(ref paused) => (PodState::Paused, paused.docker_id.to_string(), None),
+                        PodStateKnown::Enabled(ref enabled) => (
+                            PodState::from(&*lock),
+                            enabled.docker_id.to_string(),
+                            Some((now - enabled.started).num_seconds().max(0) as u64),
+                        ),
+                    },
+                    None => (PodState::Transit, String::new(), None),
+                };
+
+                let events = pod.event_counts();
+                deimosproto::PodStatusEntry {
+                    id: pod.id().owned(),
+                    title: pod.title().to_string(),
+                    state: deimosproto::PodState::from(state) as i32,
+                    container_id,
+                    uptime_seconds,
+                    events_coalesced: events.coalesced,
+                    actions_deferred: events.deferred,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        pods.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        Ok(tonic::Response::new(deimosproto::GetPodStatusResponse { pods }))
+    }
+
+    async fn update_pod(self: Arc<Self>, req: tonic::Request<deimosproto::InternalUpdatePodRequest>)
+        -> Result<tonic::Response<deimosproto::InternalUpdatePodResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("pod update", &req.pin).await?;
+        let pod = self.lookup_pod(req.id)?;
+
+        //The update continues if the request is cancelled, as it does for the public API
+        let update = self.clone().begin_pod_update(pod.clone(), req.method, req.duration_seconds).await?;
+        let result = update.await.unwrap_or_else(|e| Err(format!("Update task failed: {}", e)));
+        match result {
+            Ok(()) => Ok(tonic::Response::new(deimosproto::InternalUpdatePodResponse {
+                state: deimosproto::PodState::from(pod.state().current()) as i32,
+            })),
+            Err(e) => Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, format!("Failed to update pod {}: {}", pod.id(), e))
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::Internal)
+            ),
+        }
+    }
+
+    async fn get_daemon_info(self: Arc<Self>, _req: tonic::Request<deimosproto::GetDaemonInfoRequest>)
+        -> Result<tonic::Response<deimosproto::GetDaemonInfoResponse>, tonic::Status> {
+        Ok(tonic::Response::new(deimosproto::GetDaemonInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            started: self.lifecycle.started().timestamp(),
+        }))
+    }
+
+    async fn get_upnp_status(self: Arc<Self>, _req: tonic::Request<deimosproto::GetUpnpStatusRequest>)
+        -> Result<tonic::Response<deimosproto::GetUpnpStatusResponse>, tonic::Status> {
+        let status = self.upnp.status().borrow().clone();
+        let leases = status
+            .leases
+            .into_iter()
+            .map(|lease| {
+                let (status, error) = match lease.status {
+                    UpnpLeaseStatus::Pending => (deimosproto::UpnpLeaseStatus::Pending, String::new()),
+                    UpnpLeaseStatus::Active => (deimosproto::UpnpLeaseStatus::Active, String::new()),
+                    UpnpLeaseStatus::Failed(error) => (deimosproto::UpnpLeaseStatus::Failed, error),
+                };
+
+                deimosproto::UpnpLeaseEntry {
+                    port: lease.data.port as u32,
+                    host_port: lease.data.host_port as u32,
+                    protocol: lease.data.protocol.to_string(),
+                    pods: lease
+                        .owners
+                        .iter()
+                        .filter_map(|owner| match owner {
+                            UpnpLeaseOwner::Pod(id) => Some(id.owned()),
+                            UpnpLeaseOwner::Api => None,
+                        })
+                        .collect(),
+                    api: lease.owners.contains(&UpnpLeaseOwner::Api),
+                    name: lease.data.name,
+                    status: status as i32,
+                    error,
+                    renewed: lease.renewed.map(|at| at.timestamp()).unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        Ok(tonic::Response::new(deimosproto::GetUpnpStatusResponse {
+            gateway_found: status.gateway_found,
+            external_ip: status.external_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            leases,
+        }))
+    }
+
+    async fn set_log_level(self: Arc<Self>, req: tonic::Request<deimosproto::SetLogLevelRequest>)
+        -> Result<tonic::Response<deimosproto::SetLogLevelResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let req = req.into_inner();
+        self.check_pin("log level", &req.pin).await?;
+
+        self
+            .log
+            .set(req.target, &req.level, req.persist)
+            .map(|_| tonic::Response::new(deimosproto::SetLogLevelResponse {}))
+            .map_err(|e| {
+                let (code, error) = match e {
+                    LogLevelError::Parse(..) => (tonic::Code::InvalidArgument, deimosproto::ErrorCode::InvalidLogLevel),
+                    LogLevelError::NoLogSection | LogLevelError::TargetsNotTable => (tonic::Code::FailedPrecondition, deimosproto::ErrorCode::NoLogSection),
+                    _ => (tonic::Code::Internal, deimosproto::ErrorCode::InternalError),
+                };
+
+                deimosproto::ErrorDetail::new(error, e.to_string()).into_status(code)
+            })
+    }
+}
+
+/// Describe the local user that made the given request over the internal socket, if the
+/// connection's peer credentials are available
+fn local_user<T>(req: &tonic::Request<T>) -> Option<String> {
+    #[cfg(unix)]
+    {
+        req
+            .extensions()
+            .get::<tonic::transport::server::UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+            .map(|cred| format!("uid {}", cred.uid()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = req;
+        None
+    }
 }
 
+/// Get a protobuf description of a container reported by Docker
+fn docker_container(container: PodContainerInfo) -> deimosproto::DockerContainer {
+    deimosproto::DockerContainer {
+        id: container.id,
+        name: container.name,
+        pod: container.pod,
+        state: container.state,
+        status: container.status,
+        created: container.created,
+        image: container.image,
+        image_digest: container.image_digest,
+    }
+}
+
+/// Get a protobuf description of a saved config version of the given pod
+fn config_version(pod: &Pod, version: PodConfigVersion) -> deimosproto::PodConfigVersion {
+    deimosproto::PodConfigVersion {
+        current: version.hash == pod.config_hash(),
+        hash: Pod::short_hash(&version.hash),
+        saved: version.saved.timestamp(),
+        name: version.name,
+    }
+}
+
+/// Convert an error accessing the config history of the given pod to a status
+fn config_history_status(pod: &Pod, err: PodConfigHistoryError) -> tonic::Status {
+    let (code, error) = match err {
+        PodConfigHistoryError::UnknownVersion(..) => (tonic::Code::NotFound, deimosproto::ErrorCode::ConfigVersionNotFound),
+        PodConfigHistoryError::ConcurrentEdit { .. } => (tonic::Code::Aborted, deimosproto::ErrorCode::ConfigModified),
+        PodConfigHistoryError::InvalidVersion { .. } => (tonic::Code::FailedPrecondition, deimosproto::ErrorCode::InvalidArgument),
+        _ => (tonic::Code::Internal, deimosproto::ErrorCode::InternalError),
+    };
+
+    deimosproto::ErrorDetail::new(error, err.to_string())
+        .with_pod(pod.id().owned())
+        .into_status(code)
+}
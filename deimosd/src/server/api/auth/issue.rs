@@ -1,10 +1,10 @@
 
-use std::{future::Future, net::IpAddr, sync::Arc};
+use std::{future::Future, net::IpAddr, sync::{atomic::Ordering, Arc}};
 
 use futures::Stream;
 use pin_project::pin_project;
 
-use super::{token::ApiTokenPendingFuture, ApiAuthorization, ApiToken, ApiTokenPending};
+use super::{scope::ApiTokenScope, token::ApiTokenPendingFuture, ApiAuthorization, ApiToken, ApiTokenPending};
 
 /// A stream used in the authorization API that will send either a denied message or the approved
 /// token to a client that has requested a token.
@@ -12,44 +12,62 @@ use super::{token::ApiTokenPendingFuture, ApiAuthorization, ApiToken, ApiTokenPe
 pub struct PendingTokenStream(#[pin] ApiTokenPendingFuture);
 
 impl ApiAuthorization {
-    /// Approve the given pending token request
-    pub async fn approve(&self, request: ApiTokenPending) -> Result<ApiToken, ApiTokenIssueError> {
-        let token = request.upgrade().await;
-        let base64 = token.key().to_base64();
-        match self.tokens.get(&base64) {
+    /// Approve the given pending token request taken from [PendingTokens::take], issuing a token
+    /// limited to the given scope. The client and subscribers are only told that the request was
+    /// approved once the token has been stored, and are told it was denied if it could not be
+    /// stored because its key collides with an existing token.
+    /// The store is marked as changed so that the token is saved even if the caller does not save
+    /// it immediately
+    ///
+    /// [PendingTokens::take]: super::pending::PendingTokens::take
+    pub async fn approve(&self, request: ApiTokenPending, scope: ApiTokenScope) -> Result<ApiToken, ApiTokenIssueError> {
+        let proto = request.proto();
+        let token = request.token(scope);
+        let collision = match self.tokens.entry(token.key().to_base64()) {
+            dashmap::Entry::Occupied(exist) => Some(exist.get().user().clone()),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(token.clone());
+                self.dirty.store(true, Ordering::Release);
+                None
+            },
+        };
+
+        match collision {
             Some(exist) => {
                 tracing::error!(
                     "Generated API token identical to existing: new for '{}' collides with token issued for '{}'",
                     token.user(),
-                    exist.user()
+                    exist,
                 );
 
-                Err(ApiTokenIssueError::KeyCollision { exist: exist.user().clone(), requested: token.user().clone() })
+                request.deny("The server failed to issue a token, request another").await;
+                self.pending.notify(deimosproto::PendingTokenEventKind::Denied, proto);
+                Err(ApiTokenIssueError::KeyCollision { exist, requested: token.user().clone() })
             },
             None => {
-                self.tokens.insert(base64, token.clone());
+                request.upgrade(token.clone()).await;
+                self.pending.notify(deimosproto::PendingTokenEventKind::Approved, proto);
                 Ok(token)
             }
         }
     }
     
-    /// Create a new pending token request for the given username
-    pub async fn create_request(&self, requester: IpAddr, user: Arc<str>) -> PendingTokenStream {
-        let (pending, rx) = ApiTokenPending::create(user.clone(), requester);
-
-        match self.valid_username(&user) {
-            Ok(_) => {
-                self.pending.insert(
-                    user.clone(),
-                    pending
-                );
-            }
-            Err(e) => {
-                pending.deny(e).await;
-            }
-        }
+    /// Create a new pending token request for the given username, rejecting the request if the
+    /// username is invalid or in use, or if the requesting address has exceeded its limits
+    pub fn create_request(&self, requester: IpAddr, user: Arc<str>) -> Result<PendingTokenStream, ApiTokenIssueError> {
+        self.valid_username(&user)?;
 
-        PendingTokenStream(rx)
+        let (pending, rx) = ApiTokenPending::create(user, requester);
+        self.pending.insert(pending, &self.config)?;
+        Ok(PendingTokenStream(rx))
+    }
+
+    /// Deny all token requests that have been pending for longer than the configured timeout
+    pub async fn expire_requests(&self, now: chrono::DateTime<chrono::Utc>) {
+        for expired in self.pending.expire(now, &self.config) {
+            tracing::info!("Token request for '{}' expired", expired.user());
+            expired.deny("Token request expired before it was approved").await;
+        }
     }
     
     /// Ensure the given username only contains displayable ASCII characters and that it does not
@@ -57,10 +75,7 @@ impl ApiAuthorization {
     fn valid_username(&self, user: &str) -> Result<(), ApiTokenIssueError> {
         match user.chars().all(|c| c.is_ascii_alphanumeric() || c.is_ascii_punctuation()) {
             true => match self.tokens.iter().all(|entry| **entry.value().user() != *user) {
-                true => match !self.pending.contains_key(user) {
-                    true => Ok(()),
-                    false => Err(ApiTokenIssueError::UsernameInUse(user.to_owned())),
-                },
+                true => Ok(()),
                 false => Err(ApiTokenIssueError::UsernameInUse(user.to_owned())),
             },
             false => Err(ApiTokenIssueError::InvalidUsername),
@@ -77,7 +92,10 @@ impl Stream for PendingTokenStream {
         std::task::Poll::Ready(Some(
             recv
                 .map(ApiToken::proto)
-                .map_err(tonic::Status::permission_denied)
+                .map_err(|reason| {
+                    deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenDenied, reason)
+                        .into_status(tonic::Code::PermissionDenied)
+                })
         ))
     }
 }
@@ -88,9 +106,62 @@ pub enum ApiTokenIssueError {
     UsernameInUse(String),
     #[error("Username is not ASCII or contains whitespace")]
     InvalidUsername,
+    #[error("A token request for username '{}' is already pending", .0)]
+    RequestPending(String),
+    #[error("{address} already has {limit} pending token requests")]
+    TooManyPending {
+        address: IpAddr,
+        limit: usize,
+    },
+    #[error("{address} requested a token too recently, try again in {remaining} seconds")]
+    Cooldown {
+        address: IpAddr,
+        remaining: i64,
+    },
     #[error("Generated duplicate keys - requested {} collides with existing {}", exist, requested)]
     KeyCollision {
         exist: Arc<str>,
         requested: Arc<str>,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn auth() -> ApiAuthorization {
+        ApiAuthorization::load(Default::default(), toml::from_str("").unwrap())
+    }
+
+    #[tokio::test]
+    async fn approval_is_announced_after_token_is_stored() {
+        let auth = auth();
+        let mut events = auth.pending.subscribe();
+        let mut stream = auth.create_request(IpAddr::from([127, 0, 0, 1]), Arc::from("alice")).unwrap();
+        assert_eq!(events.recv().await.unwrap().kind(), deimosproto::PendingTokenEventKind::Added);
+
+        let pending = auth.pending.take("alice").unwrap();
+        assert!(events.try_recv().is_err());
+
+        let token = auth.approve(pending, ApiTokenScope::new([], []).unwrap()).await.unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind(), deimosproto::PendingTokenEventKind::Approved);
+        assert_eq!(event.request.unwrap().username, "alice");
+        assert!(auth.tokens.contains_key(&token.key().to_base64()));
+        assert!(auth.take_dirty());
+
+        let delivered = stream.next().await.unwrap().unwrap();
+        assert_eq!(delivered, token.proto());
+    }
+
+    #[tokio::test]
+    async fn taken_request_is_no_longer_pending() {
+        let auth = auth();
+        let _stream = auth.create_request(IpAddr::from([127, 0, 0, 1]), Arc::from("alice")).unwrap();
+        assert!(auth.pending.take("alice").is_some());
+        assert!(auth.pending.take("alice").is_none());
+        assert_eq!(auth.pending.iter().count(), 0);
+    }
+}
@@ -1,17 +1,21 @@
-use std::{sync::Arc, time::Duration};
+use std::{sync::{atomic::AtomicBool, Arc}, time::Duration};
 
 use dashmap::DashMap;
 use deimosproto::auth::DeimosTokenKey;
+use pending::PendingTokens;
+use subtle::{Choice, ConstantTimeEq};
 use token::{ApiToken, ApiTokenPending};
 use tonic::service::Interceptor;
 
 mod grpc;
 mod issue;
+mod pending;
+mod scope;
 mod token;
-pub use issue::{ApiTokenIssueError, PendingTokenStream};
-
-
-type PendingTokensCollection = Arc<DashMap<Arc<str>, ApiTokenPending>>;
+mod usage;
+pub use issue::{ApiTokenIssueError};
+pub use scope::ApiTokenScope;
+pub use usage::{ApiTokenRevokeError, ApiTokenSelector};
 
 /// Authorization state for the gRPC API, tracking all issued tokens
 #[derive(Default, Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -22,7 +26,10 @@ pub struct ApiAuthorization {
     tokens: Arc<DashMap<String, ApiToken>>,
     /// Map of all token requests
     #[serde(skip)]
-    pending: PendingTokensCollection,
+    pending: Arc<PendingTokens>,
+    /// Set when tokens are used for the first time or revoked, so that they can be saved
+    #[serde(skip)]
+    dirty: Arc<AtomicBool>,
 }
 
 /// Persistent state loaded from and saved to save files, not meant to be editable by users
@@ -36,6 +43,15 @@ pub struct ApiAuthorizationConfig {
     /// How long to wait until a request is timed out
     #[serde(default="ApiAuthorizationConfig::default_token_timeout")]
     pub request_timeout: Duration,
+    /// How long an approved token may remain unused before it is revoked
+    #[serde(default="ApiAuthorizationConfig::default_unused_token_grace")]
+    pub unused_token_grace: Duration,
+    /// Maximum number of requests from a single address that may be pending at once
+    #[serde(default="ApiAuthorizationConfig::default_max_pending_per_address")]
+    pub max_pending_per_address: usize,
+    /// Minimum time between token requests from a single address
+    #[serde(default="ApiAuthorizationConfig::default_request_cooldown")]
+    pub request_cooldown: Duration,
 }
 
 impl ApiAuthorization {
@@ -48,26 +64,83 @@ impl ApiAuthorization {
     
     /// Load API authorization state from the given persistent data and user-provided configuration
     pub fn load(persistent: ApiAuthorizationPersistent, config: ApiAuthorizationConfig) -> Self {
+        persistent.tokens.iter_mut().for_each(|mut token| token.rehash());
+
         Self {
             config,
             tokens: persistent.tokens,
             pending: Default::default(),
+            dirty: Default::default(),
         }
     }
+
+    /// Get a protobuf description of every approved token
+    pub fn tokens(&self) -> Vec<deimosproto::ApiTokenInfo> {
+        self.tokens.iter().map(|token| token.info()).collect()
+    }
 }
 
 impl Interceptor for ApiAuthorization {
-    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
-        if true {
-            match request.metadata().get(DeimosTokenKey::HTTP_HEADER_NAME) {
-                Some(token) => match token.to_str().ok().and_then(|key| self.tokens.get(key)){
-                    Some(_) => Ok(request),
-                    None => Err(tonic::Status::unauthenticated("Invalid authorization token")),
-                },
-                None => Err(tonic::Status::unauthenticated(format!("No '{}' header located", DeimosTokenKey::HTTP_HEADER_NAME))),
-            }
-        } else {
-            Ok(request)
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let authorized = request
+            .metadata()
+            .get(DeimosTokenKey::HTTP_HEADER_NAME)
+            .map(|token| match token.as_bytes().len() <= Self::MAX_TOKEN_HEADER_LEN {
+                true => self.authorized(token.as_bytes()),
+                false => None,
+            });
+
+        match authorized {
+            Some(Some(scope)) => {
+                request.extensions_mut().insert(scope);
+                Ok(request)
+            },
+            Some(None) => Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenInvalid, "Invalid authorization token")
+                    .into_status(tonic::Code::Unauthenticated)
+            ),
+            None => Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenMissing, format!("No '{}' header located", DeimosTokenKey::HTTP_HEADER_NAME))
+                    .into_status(tonic::Code::Unauthenticated)
+            ),
+        }
+    }
+}
+
+impl ApiAuthorization {
+    /// Maximum length of a token header value that will be validated, well above the length of an
+    /// encoded key
+    const MAX_TOKEN_HEADER_LEN: usize = 256;
+
+    /// Check if the given header value is the key of an issued token, recording the token's
+    /// first use and returning the pods that the token may access
+    fn authorized(&self, header: &[u8]) -> Option<Arc<ApiTokenScope>> {
+        let key = self.validate(header)?.to_base64();
+        match self.record_use(&key, chrono::Utc::now()) {
+            true => self.tokens.get(&key).map(|token| token.scope().clone()),
+            false => None,
+        }
+    }
+
+    /// Get the key of the issued token matching the given header value, if any.
+    /// The digest of the presented key is compared against every issued token in constant time, and
+    /// malformed values are hashed and compared in the same way, so that timing does not reveal
+    /// how much of a valid key was presented or whether the value could be decoded
+    fn validate(&self, header: &[u8]) -> Option<DeimosTokenKey> {
+        let key = std::str::from_utf8(header)
+            .ok()
+            .and_then(|header| DeimosTokenKey::from_base64(header).ok());
+        let well_formed = Choice::from(key.is_some() as u8);
+        let digest = ApiToken::digest_key(key.as_ref().map(DeimosTokenKey::as_bytes).unwrap_or(header));
+
+        let found = self
+            .tokens
+            .iter()
+            .fold(Choice::from(0), |found, token| found | token.digest().ct_eq(&digest));
+
+        match bool::from(found & well_formed) {
+            true => key,
+            false => None,
         }
     }
 }
@@ -77,12 +150,136 @@ impl ApiAuthorizationConfig {
     pub const fn default_token_timeout() -> Duration {
         Duration::from_secs(60 * 30)
     }
+
+    pub const fn default_unused_token_grace() -> Duration {
+        Duration::from_secs(60 * 60 * 48)
+    }
+
+    pub const fn default_max_pending_per_address() -> usize {
+        3
+    }
+
+    pub const fn default_request_cooldown() -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 impl Default for ApiAuthorizationConfig {
     fn default() -> Self {
         Self {
             request_timeout: Self::default_token_timeout(),
+            unused_token_grace: Self::default_unused_token_grace(),
+            max_pending_per_address: Self::default_max_pending_per_address(),
+            request_cooldown: Self::default_request_cooldown(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Instant};
+
+    use super::*;
+
+    /// Create a token store holding the given number of tokens, returning the store and the header
+    /// value of its first token
+    fn auth(tokens: usize) -> (ApiAuthorization, String) {
+        let auth = ApiAuthorization::load(Default::default(), ApiAuthorizationConfig::default());
+        let keys = (0..tokens)
+            .map(|_| {
+                let (pending, _) = ApiTokenPending::create(Arc::from("alice"), IpAddr::from([127, 0, 0, 1]));
+                let token = pending.token(ApiTokenScope::new([], []).unwrap());
+                let key = token.key().to_base64();
+                auth.tokens.insert(key.clone(), token);
+                key
+            })
+            .collect::<Vec<_>>();
+
+        (auth, keys[0].clone())
+    }
+
+    /// Key of a token that was never issued by any store
+    fn unknown() -> String {
+        let (pending, _) = ApiTokenPending::create(Arc::from("mallory"), IpAddr::from([127, 0, 0, 1]));
+        pending.token(ApiTokenScope::new([], []).unwrap()).key().to_base64()
+    }
+
+    fn call(auth: &mut ApiAuthorization, header: &str) -> Result<tonic::Request<()>, Box<tonic::Status>> {
+        let mut request = tonic::Request::new(());
+        request.metadata_mut().insert(DeimosTokenKey::HTTP_HEADER_NAME, header.parse().unwrap());
+        auth.call(request).map_err(Box::new)
+    }
+
+    #[test]
+    fn valid_token_is_accepted() {
+        let (mut auth, key) = auth(3);
+        let request = call(&mut auth, &key).unwrap();
+        assert!(request.extensions().get::<Arc<ApiTokenScope>>().unwrap().unrestricted());
+        assert!(auth.take_dirty());
+    }
+
+    #[test]
+    fn every_rejection_is_the_same() {
+        let (mut auth, key) = auth(3);
+        let headers = [
+            unknown(),
+            "not a token".to_owned(),
+            key[..key.len() - 2].to_owned(),
+            key.repeat(ApiAuthorization::MAX_TOKEN_HEADER_LEN / key.len() + 1),
+        ];
+
+        let rejections = headers
+            .iter()
+            .map(|header| call(&mut auth, header).unwrap_err())
+            .map(|status| (status.code(), status.message().to_owned(), status.details().to_vec()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(rejections[0].0, tonic::Code::Unauthenticated);
+        assert!(rejections.iter().all(|rejection| *rejection == rejections[0]), "{:?}", rejections);
+        assert!(!auth.take_dirty());
+    }
+
+    #[test]
+    fn missing_header_is_reported() {
+        let (mut auth, _) = auth(1);
+        let status = auth.call(tonic::Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_ne!(status.message(), "Invalid authorization token");
+    }
+
+    /// Coarse check that validation time does not depend on whether or how a key is wrong. Each
+    /// kind of value is measured in turn so that noise affects them all alike
+    #[test]
+    fn validation_time_is_uncorrelated() {
+        const ROUNDS: usize = 200;
+        const BATCH: usize = 20;
+
+        let (auth, key) = auth(64);
+        let mut near = key.clone().into_bytes();
+        near[key.len() / 2] = if near[key.len() / 2] == b'A' { b'B' } else { b'A' };
+        let headers = [key.into_bytes(), unknown().into_bytes(), near, b"not a token".to_vec()];
+
+        let mut times = vec![Vec::with_capacity(ROUNDS); headers.len()];
+        for _ in 0..ROUNDS {
+            for (header, times) in headers.iter().zip(times.iter_mut()) {
+                let start = Instant::now();
+                for _ in 0..BATCH {
+                    std::hint::black_box(auth.validate(std::hint::black_box(header)));
+                }
+                times.push(start.elapsed());
+            }
+        }
+
+        let medians = times
+            .into_iter()
+            .map(|mut times| {
+                times.sort();
+                times[ROUNDS / 2]
+            })
+            .collect::<Vec<_>>();
+
+        let fastest = *medians.iter().min().unwrap();
+        let slowest = *medians.iter().max().unwrap();
+        assert!(slowest < fastest * 2, "validation times differ: {:?}", medians);
+    }
+}
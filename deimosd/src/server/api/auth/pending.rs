@@ -0,0 +1,215 @@
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex, PoisonError}};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+
+use super::{ApiAuthorizationConfig, ApiToken, ApiTokenIssueError, ApiTokenPending};
+
+/// Collection of all pending token requests, broadcasting an event to subscribers whenever a
+/// request is added or resolved
+#[derive(Debug)]
+pub struct PendingTokens {
+    map: DashMap<Arc<str>, ApiTokenPending>,
+    /// Time of the most recent request from each address, held while a request is admitted so
+    /// that concurrent requests cannot exceed the per-address limits
+    recent: Mutex<HashMap<IpAddr, DateTime<Utc>>>,
+    tx: broadcast::Sender<deimosproto::PendingTokenEvent>,
+}
+
+impl PendingTokens {
+    /// Maximum number of events buffered for each subscriber before they begin to lag
+    const EVENT_CAPACITY: usize = 64;
+
+    /// Add a new pending request, notifying subscribers. The request is rejected if a request
+    /// for the same username is already pending, if its address has too many pending requests,
+    /// or if its address made another request within the configured cooldown
+    pub fn insert(&self, pending: ApiTokenPending, config: &ApiAuthorizationConfig) -> Result<(), ApiTokenIssueError> {
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        let address = pending.requester();
+
+        let cooldown = TimeDelta::from_std(config.request_cooldown).unwrap_or(TimeDelta::MAX);
+        if let Some(last) = recent.get(&address).filter(|last| pending.requested_at().signed_duration_since(**last) < cooldown) {
+            let remaining = (*last + cooldown).signed_duration_since(pending.requested_at());
+            return Err(ApiTokenIssueError::Cooldown { address, remaining: remaining.num_seconds().max(1) })
+        }
+
+        let outstanding = self.map.iter().filter(|entry| entry.requester() == address).count();
+        if outstanding >= config.max_pending_per_address {
+            return Err(ApiTokenIssueError::TooManyPending { address, limit: config.max_pending_per_address })
+        }
+
+        let event = Self::event(deimosproto::PendingTokenEventKind::Added, &pending);
+        match self.map.entry(pending.user().clone()) {
+            dashmap::Entry::Occupied(_) => return Err(ApiTokenIssueError::RequestPending(pending.user().to_string())),
+            dashmap::Entry::Vacant(entry) => {
+                recent.insert(address, pending.requested_at());
+                entry.insert(pending);
+            },
+        }
+
+        let _ = self.tx.send(event);
+        Ok(())
+    }
+
+    /// Remove all requests made longer than the configured timeout before the given time,
+    /// notifying subscribers that they expired. Addresses that are no longer within their
+    /// cooldown are forgotten
+    pub fn expire(&self, now: DateTime<Utc>, config: &ApiAuthorizationConfig) -> Vec<ApiTokenPending> {
+        let timeout = TimeDelta::from_std(config.request_timeout).unwrap_or(TimeDelta::MAX);
+        let expired = |pending: &ApiTokenPending| now.signed_duration_since(pending.requested_at()) >= timeout;
+
+        //Keys are collected first as removing entries while iterating would deadlock
+        let users = self
+            .map
+            .iter()
+            .filter(|entry| expired(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let expired = users
+            .into_iter()
+            .filter_map(|user| self.map.remove_if(&user, |_, pending| expired(pending)))
+            .map(|(_, pending)| self.resolved(deimosproto::PendingTokenEventKind::Expired, pending))
+            .collect();
+
+        let cooldown = TimeDelta::from_std(config.request_cooldown).unwrap_or(TimeDelta::MAX);
+        self
+            .recent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, last| now.signed_duration_since(*last) < cooldown);
+
+        expired
+    }
+
+    /// Remove the pending request for the given username, notifying subscribers that it has been
+    /// resolved in the given way
+    pub fn resolve(&self, user: &str, kind: deimosproto::PendingTokenEventKind) -> Option<ApiTokenPending> {
+        self.map
+            .remove(user)
+            .map(|(_, pending)| self.resolved(kind, pending))
+    }
+
+    /// Remove the pending request for the given username without notifying subscribers, who must
+    /// be notified with [Self::notify] once the request has been resolved
+    pub fn take(&self, user: &str) -> Option<ApiTokenPending> {
+        self.map.remove(user).map(|(_, pending)| pending)
+    }
+
+    /// Notify subscribers that the given request taken with [Self::take] was resolved in the
+    /// given way
+    pub fn notify(&self, kind: deimosproto::PendingTokenEventKind, request: deimosproto::PendingTokenRequest) {
+        let _ = self.tx.send(deimosproto::PendingTokenEvent { kind: kind as i32, request: Some(request) });
+    }
+
+    /// Get an iterator over all pending requests
+    pub fn iter(&self) -> impl Iterator<Item = dashmap::mapref::multiple::RefMulti<'_, Arc<str>, ApiTokenPending>> {
+        self.map.iter()
+    }
+
+    /// Subscribe to events emitted when pending requests are added or resolved
+    pub fn subscribe(&self) -> broadcast::Receiver<deimosproto::PendingTokenEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Notify subscribers that the given approved token was revoked as it was never used
+    pub fn revoked(&self, token: &ApiToken) {
+        let _ = self.tx.send(deimosproto::PendingTokenEvent {
+            kind: deimosproto::PendingTokenEventKind::Revoked as i32,
+            request: Some(deimosproto::PendingTokenRequest {
+                username: token.user().to_string(),
+                requested_dt: token.issued().timestamp(),
+                requester_address: String::new(),
+            }),
+        });
+    }
+
+    fn resolved(&self, kind: deimosproto::PendingTokenEventKind, pending: ApiTokenPending) -> ApiTokenPending {
+        let _ = self.tx.send(Self::event(kind, &pending));
+        pending
+    }
+
+    fn event(kind: deimosproto::PendingTokenEventKind, pending: &ApiTokenPending) -> deimosproto::PendingTokenEvent {
+        deimosproto::PendingTokenEvent {
+            kind: kind as i32,
+            request: Some(pending.proto()),
+        }
+    }
+}
+
+impl Default for PendingTokens {
+    fn default() -> Self {
+        Self {
+            map: DashMap::new(),
+            recent: Default::default(),
+            tx: broadcast::channel(Self::EVENT_CAPACITY).0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    const FIRST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const SECOND: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    fn config(max_pending_per_address: usize, request_cooldown: Duration) -> ApiAuthorizationConfig {
+        ApiAuthorizationConfig { max_pending_per_address, request_cooldown, ..Default::default() }
+    }
+
+    fn insert(pending: &PendingTokens, user: &str, address: IpAddr, config: &ApiAuthorizationConfig) -> Result<(), ApiTokenIssueError> {
+        pending.insert(ApiTokenPending::create(Arc::from(user), address).0, config)
+    }
+
+    #[test]
+    fn requests_within_cooldown_are_rejected() {
+        let pending = PendingTokens::default();
+        let config = config(3, Duration::from_secs(60));
+
+        insert(&pending, "alice", FIRST, &config).unwrap();
+        assert!(matches!(insert(&pending, "bob", FIRST, &config), Err(ApiTokenIssueError::Cooldown { address: FIRST, .. })));
+        insert(&pending, "bob", SECOND, &config).unwrap();
+    }
+
+    #[test]
+    fn pending_requests_are_limited_per_address() {
+        let pending = PendingTokens::default();
+        let config = config(2, Duration::ZERO);
+
+        insert(&pending, "alice", FIRST, &config).unwrap();
+        insert(&pending, "bob", FIRST, &config).unwrap();
+        assert!(matches!(insert(&pending, "carol", FIRST, &config), Err(ApiTokenIssueError::TooManyPending { limit: 2, .. })));
+        assert!(matches!(insert(&pending, "alice", SECOND, &config), Err(ApiTokenIssueError::RequestPending(_))));
+
+        //Resolved requests no longer count towards the limit
+        pending.resolve("alice", deimosproto::PendingTokenEventKind::Denied).unwrap();
+        insert(&pending, "carol", FIRST, &config).unwrap();
+    }
+
+    #[test]
+    fn expired_requests_are_removed_and_announced() {
+        let pending = PendingTokens::default();
+        let config = ApiAuthorizationConfig { request_timeout: Duration::from_secs(30), ..config(3, Duration::from_secs(60)) };
+        let mut events = pending.subscribe();
+
+        insert(&pending, "alice", FIRST, &config).unwrap();
+        assert_eq!(events.try_recv().unwrap().kind(), deimosproto::PendingTokenEventKind::Added);
+
+        let now = Utc::now();
+        assert!(pending.expire(now, &config).is_empty());
+
+        let expired = pending.expire(now + TimeDelta::seconds(31), &config);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(pending.iter().count(), 0);
+        assert_eq!(events.try_recv().unwrap().kind(), deimosproto::PendingTokenEventKind::Expired);
+
+        //The address is still within its cooldown, which is only forgotten once it has passed
+        assert!(matches!(insert(&pending, "bob", FIRST, &config), Err(ApiTokenIssueError::Cooldown { .. })));
+        pending.expire(now + TimeDelta::seconds(61), &config);
+        insert(&pending, "bob", FIRST, &config).unwrap();
+    }
+}
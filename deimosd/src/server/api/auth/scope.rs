@@ -0,0 +1,233 @@
+//! Restriction of API tokens to a subset of pods, given as explicit pod IDs and as pod groups.
+//! Groups are resolved against each pod's current config whenever a request is made, so pods
+//! added to or removed from a group are covered or excluded without changing the token
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use crate::pod::Pod;
+
+/// Pods that requests authorized by a token may view and control. A pod is allowed if it is
+/// listed explicitly or belongs to one of the listed groups, and all pods are allowed if neither
+/// pods nor groups are listed. Terminal sessions may only be opened if allowed explicitly
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ApiTokenScope {
+    /// IDs of the pods that may be accessed
+    #[serde(default)]
+    pods: BTreeSet<String>,
+    /// Groups whose member pods may be accessed
+    #[serde(default)]
+    groups: BTreeSet<String>,
+    /// Allow interactive terminal sessions in the pods that may be accessed, which is never
+    /// implied by access to a pod as sessions run commands in its container
+    #[serde(default)]
+    attach: bool,
+}
+
+impl ApiTokenScope {
+    /// Create a scope allowing the given pods and the members of the given groups, ignoring
+    /// whitespace around each name. Empty names are rejected rather than ignored, as a scope
+    /// given only empty names would otherwise allow every pod
+    pub fn new(pods: impl IntoIterator<Item = String>, groups: impl IntoIterator<Item = String>) -> Result<Self, ApiTokenScopeError> {
+        Ok(Self {
+            pods: names(pods).ok_or(ApiTokenScopeError::EmptyPod)?,
+            groups: names(groups).ok_or(ApiTokenScopeError::EmptyGroup)?,
+            attach: false,
+        })
+    }
+
+    /// Set if interactive terminal sessions may be opened in the pods that may be accessed
+    pub const fn with_attach(mut self, attach: bool) -> Self {
+        self.attach = attach;
+        self
+    }
+
+    /// Get the scope of the token that authorized the given request. Requests that were not
+    /// authorized by a token are denied rather than given access to every pod
+    pub fn of<T>(request: &tonic::Request<T>) -> Result<Arc<Self>, tonic::Status> {
+        request.extensions().get::<Arc<Self>>().cloned().ok_or_else(|| {
+            deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenMissing, "Request was not authorized by a token")
+                .into_status(tonic::Code::Unauthenticated)
+        })
+    }
+
+    /// Get the scope of the token that authorized the given request, denying the request if the
+    /// token is limited to some pods. Calls that change how the daemon manages a pod rather than
+    /// the pod's state are reserved for administrators holding an unrestricted token
+    pub fn unrestricted_of<T>(request: &tonic::Request<T>, action: &str) -> Result<Arc<Self>, tonic::Status> {
+        let scope = Self::of(request)?;
+        match scope.unrestricted() {
+            true => Ok(scope),
+            false => Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::TokenScopeRestricted, format!("{} requires a token that is not limited to some pods", action))
+                    .into_status(tonic::Code::PermissionDenied)
+            ),
+        }
+    }
+
+    /// Check if this scope allows access to every pod
+    pub fn unrestricted(&self) -> bool {
+        self.pods.is_empty() && self.groups.is_empty()
+    }
+
+    /// Check if the given pod may be accessed, using the pod's current group
+    pub fn allows(&self, pod: &Pod) -> bool {
+        self.unrestricted()
+            || self.pods.contains(&*pod.id())
+            || pod.config().group.as_ref().is_some_and(|group| self.groups.contains(group))
+    }
+
+    /// Check if events for a pod may be sent given the pod if it is still loaded. Events for pods
+    /// that are no longer loaded are only sent to unrestricted scopes, as their group is unknown
+    pub fn allows_loaded(&self, pod: Option<&Pod>) -> bool {
+        self.unrestricted() || pod.is_some_and(|pod| self.allows(pod))
+    }
+
+    /// Get the IDs of pods allowed explicitly
+    pub const fn pods(&self) -> &BTreeSet<String> {
+        &self.pods
+    }
+
+    /// Get the groups whose member pods are allowed
+    pub const fn groups(&self) -> &BTreeSet<String> {
+        &self.groups
+    }
+
+    /// Check if interactive terminal sessions may be opened in the pods that may be accessed
+    pub const fn attach(&self) -> bool {
+        self.attach
+    }
+}
+
+/// Collect the given names without surrounding whitespace, or `None` if any name is empty
+fn names(names: impl IntoIterator<Item = String>) -> Option<BTreeSet<String>> {
+    names
+        .into_iter()
+        .map(|name| Some(name.trim().to_owned()).filter(|name| !name.is_empty()))
+        .collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiTokenScopeError {
+    #[error("Pod IDs that a token is limited to may not be empty")]
+    EmptyPod,
+    #[error("Groups that a token is limited to may not be empty")]
+    EmptyGroup,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scope(pods: &[&str], groups: &[&str]) -> ApiTokenScope {
+        ApiTokenScope::new(pods.iter().map(|pod| pod.to_string()), groups.iter().map(|group| group.to_string())).unwrap()
+    }
+
+    async fn pod(id: &str, group: Option<&str>) -> (tempfile::TempDir, Pod) {
+        let group = group.map(|group| format!("group = \"{}\"\n", group)).unwrap_or_default();
+        Pod::test(&format!("id = \"{}\"\nname = \"{}\"\n{}[docker]\nimage = \"alpine:3\"\n", id, id, group)).await
+    }
+
+    #[tokio::test]
+    async fn group_members_are_allowed() {
+        let (_dir, web) = pod("web", Some("media")).await;
+        let (_dir, db) = pod("db", Some("storage")).await;
+        let (_dir, lone) = pod("lone", None).await;
+
+        let scope = scope(&[], &["media"]);
+        assert!(scope.allows(&web));
+        assert!(!scope.allows(&db));
+        assert!(!scope.allows(&lone));
+    }
+
+    #[tokio::test]
+    async fn pods_and_groups_are_combined() {
+        let (_dir, web) = pod("web", Some("media")).await;
+        let (_dir, db) = pod("db", Some("storage")).await;
+        let (_dir, lone) = pod("lone", None).await;
+
+        let scope = scope(&["lone"], &["media"]);
+        assert!(scope.allows(&web));
+        assert!(!scope.allows(&db));
+        assert!(scope.allows(&lone));
+    }
+
+    #[tokio::test]
+    async fn unrestricted_allows_every_pod() {
+        let (_dir, web) = pod("web", Some("media")).await;
+        let (_dir, lone) = pod("lone", None).await;
+
+        let scope = scope(&[], &[]);
+        assert!(scope.unrestricted());
+        assert!(scope.allows(&web));
+        assert!(scope.allows(&lone));
+    }
+
+    #[tokio::test]
+    async fn unloaded_pods_are_only_in_unrestricted_scopes() {
+        let (_dir, web) = pod("web", Some("media")).await;
+
+        assert!(scope(&[], &[]).allows_loaded(None));
+        assert!(!scope(&["web"], &[]).allows_loaded(None));
+        assert!(scope(&["web"], &[]).allows_loaded(Some(&web)));
+        assert!(!scope(&[], &["storage"]).allows_loaded(Some(&web)));
+    }
+
+    #[test]
+    fn scoped_tokens_are_not_administrators() {
+        let request = |scope: Option<ApiTokenScope>| {
+            let mut request = tonic::Request::new(());
+            if let Some(scope) = scope {
+                request.extensions_mut().insert(Arc::new(scope));
+            }
+            ApiTokenScope::unrestricted_of(&request, "Overriding a schedule").err()
+        };
+
+        assert!(request(Some(scope(&[], &[]))).is_none());
+        for scope in [scope(&["web"], &[]), scope(&[], &["media"])] {
+            let status = request(Some(scope)).unwrap();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            let detail = deimosproto::ErrorDetail::from_status(&status).unwrap();
+            assert_eq!(detail.code(), deimosproto::ErrorCode::TokenScopeRestricted);
+        }
+        assert_eq!(request(None).unwrap().code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn names_are_trimmed() {
+        let scope = scope(&[" web "], &["media\t"]);
+        assert_eq!(scope.pods().iter().collect::<Vec<_>>(), ["web"]);
+        assert_eq!(scope.groups().iter().collect::<Vec<_>>(), ["media"]);
+    }
+
+    #[test]
+    fn empty_names_are_rejected() {
+        for empty in ["", " "] {
+            assert!(matches!(ApiTokenScope::new([], [String::from(empty)]), Err(ApiTokenScopeError::EmptyGroup)));
+            assert!(matches!(ApiTokenScope::new([String::from(empty)], []), Err(ApiTokenScopeError::EmptyPod)));
+            assert!(matches!(
+                ApiTokenScope::new([], [String::from("media"), String::from(empty)]),
+                Err(ApiTokenScopeError::EmptyGroup)
+            ));
+        }
+    }
+
+    #[test]
+    fn attach_is_only_allowed_explicitly() {
+        assert!(!scope(&[], &[]).attach());
+        assert!(scope(&[], &[]).with_attach(true).attach());
+
+        //Tokens saved before terminal sessions were scoped may not open them
+        let saved = serde_json::from_str::<ApiTokenScope>("{\"pods\": [], \"groups\": []}").unwrap();
+        assert!(!saved.attach());
+    }
+
+    #[test]
+    fn requests_without_a_token_are_denied() {
+        let status = ApiTokenScope::of(&tonic::Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut request = tonic::Request::new(());
+        request.extensions_mut().insert(Arc::new(scope(&["web"], &[])));
+        assert_eq!(*ApiTokenScope::of(&request).unwrap(), scope(&["web"], &[]));
+    }
+}
@@ -1,11 +1,14 @@
 use std::{future::Future, net::IpAddr, sync::Arc};
 
+use blake2::{digest::consts::U32, Blake2b, Digest};
 use chrono::{DateTime, Utc};
 use deimosproto::auth::DeimosTokenKey;
 use pin_project::pin_project;
 use rand::{rngs::OsRng, CryptoRng, Rng};
 use tokio::sync::mpsc;
 
+use super::scope::ApiTokenScope;
+
 
 /// An API token that has been created with a random key and approved sometime in the past
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize,)]
@@ -16,6 +19,29 @@ pub struct ApiToken {
     issued: DateTime<Utc>,
     /// Randomly generated token assigned by the server
     key: DeimosTokenKey,
+    /// Time that the token was first used to authorize a request
+    #[serde(default)]
+    pub(super) first_used: ApiTokenFirstUse,
+    /// Pods that requests authorized by this token may access, all pods if not given
+    #[serde(default)]
+    scope: Arc<ApiTokenScope>,
+    /// Digest of the key compared against presented keys, computed when the token is loaded
+    #[serde(skip)]
+    digest: ApiTokenDigest,
+}
+
+/// Digest of a token key, used to validate presented keys without comparing them to the raw key
+pub type ApiTokenDigest = [u8 ; 32];
+
+/// Record of when a token was first presented by a client
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ApiTokenFirstUse {
+    /// The token was saved before first use was recorded, and is assumed to have been used
+    #[default]
+    Untracked,
+    /// The token was approved but has never been presented
+    Never,
+    At(DateTime<Utc>),
 }
 
 /// Type representing a pending token request from a client, with data about the client and a
@@ -38,20 +64,40 @@ pub struct ApiTokenPending {
 pub struct ApiTokenPendingFuture(#[pin] mpsc::Receiver<Result<ApiToken, String>>);
 
 impl ApiToken {
-    /// Generate a new token from the given source of randomness and the given username
-    fn rand<R: Rng + CryptoRng>(mut rng: R, user: Arc<str>) -> Self {
+    /// Generate a new token from the given source of randomness and the given username, limited
+    /// to the given scope
+    fn rand<R: Rng + CryptoRng>(mut rng: R, user: Arc<str>, scope: ApiTokenScope) -> Self {
         let issued = Utc::now();
         let mut key = vec![0u8 ; 64];
         rng.fill_bytes(&mut key);
 
         let key = DeimosTokenKey::from_bytes(key);
+        let digest = Self::digest_key(key.as_bytes());
 
         Self {
             user,
             issued,
             key,
+            first_used: ApiTokenFirstUse::Never,
+            scope: Arc::new(scope),
+            digest,
         }
     }
+
+    /// Compute the digest of the given key bytes
+    pub fn digest_key(key: &[u8]) -> ApiTokenDigest {
+        Blake2b::<U32>::digest(key).into()
+    }
+
+    /// Recompute the digest of this token's key, required after the token is deserialized
+    pub fn rehash(&mut self) {
+        self.digest = Self::digest_key(self.key.as_bytes());
+    }
+
+    /// Get the digest of this token's key
+    pub const fn digest(&self) -> &ApiTokenDigest {
+        &self.digest
+    }
     
     /// Get a protocol buffer representation of the token
     pub fn proto(self) -> deimosproto::Token {
@@ -76,16 +122,46 @@ impl ApiToken {
     pub const fn issued(&self) -> DateTime<Utc> {
         self.issued
     }
+
+    /// Get the time that this token was first used at
+    pub const fn first_used(&self) -> ApiTokenFirstUse {
+        self.first_used
+    }
+
+    /// Get the pods that requests authorized by this token may access
+    pub const fn scope(&self) -> &Arc<ApiTokenScope> {
+        &self.scope
+    }
+
+    /// Get a protobuf description of this token without its key
+    pub fn info(&self) -> deimosproto::ApiTokenInfo {
+        deimosproto::ApiTokenInfo {
+            username: self.user.to_string(),
+            issued: self.issued.timestamp(),
+            first_used: match self.first_used {
+                ApiTokenFirstUse::At(at) => at.timestamp(),
+                _ => 0,
+            },
+            never_used: self.first_used == ApiTokenFirstUse::Never,
+            fingerprint: self.key.fingerprint(),
+            pods: self.scope.pods().iter().cloned().collect(),
+            groups: self.scope.groups().iter().cloned().collect(),
+            attach: self.scope.attach(),
+        }
+    }
 }
 
 impl ApiTokenPending {
-    /// Upgrade this API token request to a full API token, notifying the waiting client that the
-    /// request has been approved
-    pub async fn upgrade(self) -> ApiToken {
+    /// Generate a new API token for this request that may only access pods in the given scope,
+    /// without notifying the waiting client
+    pub fn token(&self, scope: ApiTokenScope) -> ApiToken {
+        ApiToken::rand(OsRng, self.user.clone(), scope)
+    }
+
+    /// Notify the waiting client that the request has been approved with the given token
+    pub async fn upgrade(self, token: ApiToken) {
         tracing::trace!("Upgrading token request for {}", self.requester);
-        let token = ApiToken::rand(OsRng, self.user);
-        let _ = self.resolve.send(Ok(token.clone())).await;
-        token
+        let _ = self.resolve.send(Ok(token)).await;
     }
     
     /// Deny the given token request, notifying the client waiting on the request 
@@ -111,6 +187,21 @@ impl ApiTokenPending {
         )
     }
     
+    /// Get the username that was requested
+    pub const fn user(&self) -> &Arc<str> {
+        &self.user
+    }
+
+    /// Get the address of the client that requested this token
+    pub const fn requester(&self) -> IpAddr {
+        self.requester
+    }
+
+    /// Get the date and time that this token was requested at
+    pub const fn requested_at(&self) -> DateTime<Utc> {
+        self.requested_at
+    }
+
     /// Get a protobuf representation of this token request
     pub fn proto(&self) -> deimosproto::PendingTokenRequest {
         deimosproto::PendingTokenRequest {
@@ -0,0 +1,156 @@
+//! Tracking of the first use of approved tokens, revoking tokens that were approved but never
+//! delivered to or used by the requesting device, and revocation of tokens by an administrator
+
+use std::sync::atomic::Ordering;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::{token::ApiTokenFirstUse, ApiAuthorization, ApiToken};
+
+/// Identifies a single approved token to be revoked
+#[derive(Debug, Clone, Copy)]
+pub enum ApiTokenSelector<'a> {
+    User(&'a str),
+    /// Fingerprint of the token's key as shown by [DeimosTokenKey::fingerprint](deimosproto::auth::DeimosTokenKey::fingerprint)
+    Fingerprint(&'a str),
+}
+
+impl ApiAuthorization {
+    /// Record that the token with the given base64 key was used at the given time, returning
+    /// `false` if the token no longer exists.
+    /// The token's entry is locked while its first use is recorded so that it cannot be revoked
+    /// between validation and the first use being recorded
+    pub(super) fn record_use(&self, key: &str, now: DateTime<Utc>) -> bool {
+        match self.tokens.get(key) {
+            Some(token) if token.first_used() != ApiTokenFirstUse::Never => return true,
+            Some(_) => (),
+            None => return false,
+        }
+
+        let Some(mut token) = self.tokens.get_mut(key) else {
+            return false
+        };
+
+        if token.first_used == ApiTokenFirstUse::Never {
+            tracing::info!("Token for '{}' was used for the first time", token.user());
+            token.first_used = ApiTokenFirstUse::At(now);
+            self.dirty.store(true, Ordering::Release);
+        }
+
+        true
+    }
+
+    /// Revoke all tokens that were approved longer than the configured grace period before the
+    /// given time and have never been used, notifying watchers of pending requests
+    pub fn revoke_unused(&self, now: DateTime<Utc>) -> Vec<ApiToken> {
+        let grace = TimeDelta::from_std(self.config.unused_token_grace).unwrap_or(TimeDelta::MAX);
+        let expired = |token: &ApiToken| {
+            token.first_used() == ApiTokenFirstUse::Never
+                && now.signed_duration_since(token.issued()) > grace
+        };
+
+        //Keys are collected first as removing entries while iterating would deadlock
+        let keys = self
+            .tokens
+            .iter()
+            .filter(|entry| expired(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let revoked = keys
+            .into_iter()
+            .filter_map(|key| self.tokens.remove_if(&key, |_, token| expired(token)))
+            .map(|(_, token)| token)
+            .collect::<Vec<_>>();
+
+        for token in &revoked {
+            tracing::warn!(
+                "Revoked token for '{}' approved at {} as it was never used",
+                token.user(),
+                token.issued(),
+            );
+
+            self.pending.revoked(token);
+        }
+
+        if !revoked.is_empty() {
+            self.dirty.store(true, Ordering::Release);
+        }
+
+        revoked
+    }
+
+    /// Revoke the single approved token matching the given selector, so that requests bearing its
+    /// key are rejected by the interceptor
+    pub fn revoke(&self, selector: ApiTokenSelector<'_>) -> Result<ApiToken, ApiTokenRevokeError> {
+        let matches = |token: &ApiToken| match selector {
+            ApiTokenSelector::User(user) => **token.user() == *user,
+            ApiTokenSelector::Fingerprint(fingerprint) => token.key().fingerprint().eq_ignore_ascii_case(fingerprint),
+        };
+
+        let keys = self
+            .tokens
+            .iter()
+            .filter(|entry| matches(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let key = match keys.as_slice() {
+            [key] => key,
+            [] => return Err(ApiTokenRevokeError::NotFound),
+            keys => return Err(ApiTokenRevokeError::Ambiguous(keys.len())),
+        };
+
+        let (_, token) = self.tokens.remove(key).ok_or(ApiTokenRevokeError::NotFound)?;
+        tracing::warn!("Revoked token for '{}' issued at {}", token.user(), token.issued());
+        self.dirty.store(true, Ordering::Release);
+        Ok(token)
+    }
+
+    /// Check if tokens have been used for the first time or revoked since the last call,
+    /// clearing the flag
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiTokenRevokeError {
+    #[error("No approved token matches")]
+    NotFound,
+    /// Only revoking by username can match more than one token, as fingerprints are unique
+    #[error("{0} approved tokens match, revoke by fingerprint with --fingerprint instead")]
+    Ambiguous(usize),
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, sync::Arc};
+
+    use super::{super::{token::ApiTokenPending, ApiAuthorizationConfig, ApiTokenScope}, *};
+
+    #[test]
+    fn duplicate_usernames_are_revoked_by_fingerprint() {
+        let auth = ApiAuthorization::load(Default::default(), ApiAuthorizationConfig::default());
+        let keys = (0..2)
+            .map(|_| {
+                let (pending, _) = ApiTokenPending::create(Arc::from("alice"), IpAddr::from([127, 0, 0, 1]));
+                let token = pending.token(ApiTokenScope::new([], []).unwrap());
+                let key = token.key().to_base64();
+                auth.tokens.insert(key.clone(), token);
+                key
+            })
+            .collect::<Vec<_>>();
+
+        assert!(matches!(auth.revoke(ApiTokenSelector::User("alice")), Err(ApiTokenRevokeError::Ambiguous(2))));
+        assert!(!auth.take_dirty());
+
+        let fingerprint = auth.tokens.get(&keys[0]).unwrap().value().key().fingerprint().to_uppercase();
+        let revoked = auth.revoke(ApiTokenSelector::Fingerprint(&fingerprint)).unwrap();
+        assert_eq!(revoked.key().to_base64(), keys[0]);
+        assert!(auth.take_dirty());
+
+        assert_eq!(auth.revoke(ApiTokenSelector::User("alice")).unwrap().key().to_base64(), keys[1]);
+        assert!(matches!(auth.revoke(ApiTokenSelector::User("alice")), Err(ApiTokenRevokeError::NotFound)));
+    }
+}
@@ -0,0 +1,126 @@
+//! Addresses that the public API listens on, given in the config as either a single address or
+//! a list of addresses so that dual-stack hosts can serve the API on IPv4 and IPv6 wildcard
+//! addresses at once
+
+use std::{fmt, net::SocketAddr};
+
+use tokio::net::TcpListener;
+
+/// One or more addresses that the public API is served on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiBind(Vec<SocketAddr>);
+
+impl ApiBind {
+    /// Maximum number of pending connections for each listener, matching the standard library
+    const BACKLOG: i32 = 128;
+
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Get each distinct port that is reachable from other hosts, ignoring loopback addresses
+    pub fn external_ports(&self) -> Vec<u16> {
+        let mut ports = self
+            .iter()
+            .filter(|addr| !addr.ip().is_loopback())
+            .map(|addr| addr.port())
+            .collect::<Vec<_>>();
+
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+
+    /// Bind a listener to every address, failing if any address cannot be bound. Each listener is
+    /// returned with the local address it was bound to.
+    /// IPv6 addresses only accept IPv6 connections when an IPv4 address with the same port is
+    /// also given, otherwise a wildcard IPv6 address would conflict with the IPv4 listener
+    pub fn listen(&self) -> Result<Vec<(SocketAddr, TcpListener)>, ApiBindError> {
+        self
+            .iter()
+            .map(|addr| {
+                let only_v6 = addr.is_ipv6() && self.iter().any(|other| other.is_ipv4() && other.port() == addr.port());
+                let listener = Self::listen_on(addr, only_v6).map_err(|err| ApiBindError { addr, err })?;
+                Ok((listener.local_addr().unwrap_or(addr), listener))
+            })
+            .collect()
+    }
+
+    fn listen_on(addr: SocketAddr, only_v6: bool) -> Result<TcpListener, std::io::Error> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if only_v6 {
+            socket.set_only_v6(true)?;
+        }
+
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+
+        socket.bind(&addr.into())?;
+        socket.listen(Self::BACKLOG)?;
+        socket.set_nonblocking(true)?;
+
+        TcpListener::from_std(socket.into())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ApiBind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BindVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BindVisitor {
+            type Value = ApiBind;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a socket address such as \"0.0.0.0:50051\" or a list of socket addresses")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v
+                    .parse()
+                    .map(|addr| ApiBind(vec![addr]))
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(v), &self))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut addrs = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(addr) = seq.next_element::<SocketAddr>()? {
+                    if addrs.contains(&addr) {
+                        return Err(<A::Error as serde::de::Error>::custom(format!("API bind address {} is given more than once", addr)))
+                    }
+
+                    addrs.push(addr);
+                }
+
+                match addrs.is_empty() {
+                    true => Err(<A::Error as serde::de::Error>::invalid_length(0, &self)),
+                    false => Ok(ApiBind(addrs)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(BindVisitor)
+    }
+}
+
+impl fmt::Display for ApiBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, addr) in self.0.iter().enumerate() {
+            if idx != 0 {
+                f.write_str(", ")?;
+            }
+
+            write!(f, "{}", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to bind public API to {addr}: {err}")]
+pub struct ApiBindError {
+    pub addr: SocketAddr,
+    pub err: std::io::Error,
+}
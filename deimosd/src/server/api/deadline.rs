@@ -0,0 +1,140 @@
+//! Deadlines of API calls, combining the deadline requested by the client in the `grpc-timeout`
+//! header with the server's maximum time for the called method. A call that does not complete in
+//! time is cancelled by dropping its handler, which stops any Docker requests it is waiting on,
+//! and answered with a `DEADLINE_EXCEEDED` status before the client gives up on it
+
+use std::{collections::HashMap, sync::Arc, task::Poll, time::Duration};
+
+use futures::future::BoxFuture;
+use tonic::{body::BoxBody, codegen::http};
+
+/// Layer that bounds the time taken to respond to each call by the client's deadline and the
+/// server's maximum for the method
+#[derive(Debug, Clone)]
+pub struct DeadlineLayer {
+    /// Maximum time for methods without their own maximum, unbounded if not given
+    default: Option<Duration>,
+    /// Maximum time of each method by method name
+    methods: Arc<HashMap<String, Duration>>,
+}
+
+/// Service produced by [DeadlineLayer]
+#[derive(Debug, Clone)]
+pub struct Deadline<S> {
+    inner: S,
+    layer: DeadlineLayer,
+}
+
+impl DeadlineLayer {
+    /// Header used by gRPC clients to send the deadline of a call
+    const TIMEOUT_HEADER: &str = "grpc-timeout";
+    /// Time subtracted from client deadlines so that the status reaches the client before it
+    /// abandons the call
+    const CLIENT_MARGIN: Duration = Duration::from_millis(250);
+
+    /// Create a layer with the given maximum time for all methods, and maximum times in seconds
+    /// that override it for the given method names
+    pub fn new(default: Option<Duration>, methods: &HashMap<String, u64>) -> Self {
+        Self {
+            default,
+            methods: Arc::new(
+                methods
+                    .iter()
+                    .map(|(method, secs)| (method.clone(), Duration::from_secs(*secs)))
+                    .collect()
+            ),
+        }
+    }
+
+    /// Get the time allowed for a call to the method at the given path with the given client
+    /// timeout, if it is bounded at all
+    fn timeout(&self, path: &str, client: Option<Duration>) -> Option<Duration> {
+        let method = path.rsplit('/').next().unwrap_or_default();
+        let server = self.methods.get(method).copied().or(self.default);
+        let client = client.map(|timeout| timeout.saturating_sub(Self::CLIENT_MARGIN));
+
+        match (server, client) {
+            (Some(server), Some(client)) => Some(server.min(client)),
+            (server, client) => server.or(client),
+        }
+    }
+
+    /// Parse the value of a `grpc-timeout` header, made of at most 8 digits followed by a unit
+    fn parse_timeout(value: &http::HeaderValue) -> Option<Duration> {
+        let value = value.to_str().ok()?;
+        if value.len() < 2 || value.len() > 9 {
+            return None
+        }
+
+        let (amount, unit) = value.split_at(value.len() - 1);
+        let amount = amount.parse::<u64>().ok()?;
+        match unit {
+            "H" => Some(Duration::from_secs(amount * 60 * 60)),
+            "M" => Some(Duration::from_secs(amount * 60)),
+            "S" => Some(Duration::from_secs(amount)),
+            "m" => Some(Duration::from_millis(amount)),
+            "u" => Some(Duration::from_micros(amount)),
+            "n" => Some(Duration::from_nanos(amount)),
+            _ => None,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for DeadlineLayer {
+    type Service = Deadline<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Deadline { inner, layer: self.clone() }
+    }
+}
+
+impl<S, B> tower::Service<http::Request<B>> for Deadline<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let client = match request.headers().get(DeadlineLayer::TIMEOUT_HEADER) {
+            Some(value) => {
+                let timeout = DeadlineLayer::parse_timeout(value);
+                if timeout.is_none() {
+                    tracing::debug!("Ignoring invalid {} header {:?}", DeadlineLayer::TIMEOUT_HEADER, value);
+                }
+
+                timeout
+            },
+            None => None,
+        };
+
+        let path = request.uri().path().to_owned();
+        let timeout = self.layer.timeout(&path, client);
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let Some(timeout) = timeout else {
+                return response.await
+            };
+
+            match tokio::time::timeout(timeout, response).await {
+                Ok(response) => response,
+                Err(_) => {
+                    tracing::debug!("Cancelled call to {} after its deadline of {:?}", path, timeout);
+                    let status = deimosproto::ErrorDetail::new(
+                        deimosproto::ErrorCode::DeadlineExceeded,
+                        format!("Call did not complete within its deadline of {}ms", timeout.as_millis()),
+                    )
+                    .into_status(tonic::Code::DeadlineExceeded);
+
+                    Ok(status.into_http())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! Draining of the API when the daemon shuts down. Once draining begins, mutating requests are
+//! rejected and long-lived streams are ended with a final status so that clients see a clean
+//! shutdown instead of a connection reset, allowing the servers to shut down gracefully before
+//! pods are stopped
+
+use futures::{stream::BoxStream, Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+/// Shared draining state of the public and internal APIs
+#[derive(Debug, Default)]
+pub struct ApiDrain {
+    /// Cancelled when the API begins draining
+    draining: CancellationToken,
+}
+
+impl ApiDrain {
+    /// Begin draining, rejecting mutating requests and ending all streams wrapped with
+    /// [ApiDrain::stream]
+    pub fn start(&self) {
+        self.draining.cancel();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    /// Reject a request that would change the state of the server once draining has begun
+    pub fn check(&self) -> Result<(), ApiDrainingError> {
+        match self.is_draining() {
+            true => Err(ApiDrainingError),
+            false => Ok(()),
+        }
+    }
+
+    /// Wrap a long-lived response stream so that it ends with an `UNAVAILABLE` status once
+    /// draining begins. Streams that end before draining are not given the final status
+    pub fn stream<S, T>(&self, stream: S) -> BoxStream<'static, Result<T, tonic::Status>>
+    where
+        S: Stream<Item = Result<T, tonic::Status>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let draining = self.draining.clone();
+        let goodbye = futures::stream::once(async move {
+            draining.is_cancelled().then(|| tonic::Status::from(ApiDrainingError)).map(Err)
+        })
+        .filter_map(futures::future::ready);
+
+        stream
+            .take_until(self.draining.clone().cancelled_owned())
+            .chain(goodbye)
+            .boxed()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Server is shutting down")]
+pub struct ApiDrainingError;
+
+impl From<ApiDrainingError> for tonic::Status {
+    fn from(value: ApiDrainingError) -> Self {
+        deimosproto::ErrorDetail::new(deimosproto::ErrorCode::ShuttingDown, value.to_string())
+            .into_status(tonic::Code::Unavailable)
+    }
+}
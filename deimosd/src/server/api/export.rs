@@ -0,0 +1,284 @@
+//! Assembly of compressed log archives exported through the internal API.
+//! Logs are written to files in a private temporary directory as they are received from Docker
+//! and then compressed into the archive, so that neither the logs nor the archive are held in
+//! memory or readable by other users of the host
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{pod::{docker::logs::PodCollectLogsError, id::DeimosId}, server::Deimos};
+
+/// A zstd-compressed tar archive of pod logs written to a temporary directory, which is removed
+/// when the archive is dropped
+#[derive(Debug)]
+pub struct LogArchive {
+    /// Directory only accessible by the daemon's user, containing the archive
+    dir: TempDir,
+    /// Size of the compressed archive in bytes
+    size: u64,
+}
+
+impl Deimos {
+    /// Collect the logs of all given pods in the given time range and compress them into an
+    /// archive, failing if the uncompressed logs exceed the configured limit
+    pub async fn export_logs(
+        &self,
+        ids: Vec<String>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<LogArchive, LogExportError> {
+        let limit = self.api.config.log_export_limit;
+        let mut remaining = limit;
+        let dir = tokio::task::spawn_blocking(LogArchive::private_dir).await??;
+        let mut entries = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            let pod = self.pods.get(&id).ok_or(LogExportError::UnknownPod(id))?;
+            let path = dir.path().join(format!("{}.log", &*pod.id()));
+            let mut file = tokio::io::BufWriter::new(
+                tokio::fs::OpenOptions::from(LogArchive::private_options()).open(&path).await?
+            );
+
+            let written = self
+                .pods
+                .collect_logs(&pod, since, until, remaining, &mut file)
+                .await
+                .map_err(|err| match err {
+                    PodCollectLogsError::LimitExceeded { .. } => LogExportError::LimitExceeded { limit },
+                    err => LogExportError::Collect { id: pod.id(), err },
+                })?;
+
+            file.flush().await?;
+            remaining -= written;
+            entries.push((pod.id(), path));
+        }
+
+        let archive = LogArchive { dir, size: 0 };
+
+        //The archive is owned by the blocking task so that the file is still removed if the
+        //request is cancelled while it is being written
+        Ok(tokio::task::spawn_blocking(move || archive.write(entries)).await??)
+    }
+}
+
+impl LogArchive {
+    /// Get the size of the compressed archive in bytes
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Create a temporary directory that only the daemon's user may access
+    fn private_dir() -> Result<TempDir, std::io::Error> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("deimos-logs-");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(0o700));
+        }
+
+        builder.tempdir()
+    }
+
+    /// Get the path of the archive in its directory
+    fn path(&self) -> PathBuf {
+        self.dir.path().join("logs.tar.zst")
+    }
+
+    /// Get the options used to create files in the archive's directory, which may only be read
+    /// by the daemon's user
+    fn private_options() -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options
+    }
+
+    /// Compress the log file of each pod into the archive, removing each log file once it has
+    /// been added
+    fn write(mut self, entries: Vec<(DeimosId, PathBuf)>) -> Result<Self, std::io::Error> {
+        let file = Self::private_options().open(self.path())?;
+        let mut tar = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+        let mtime = Utc::now().timestamp().max(0) as u64;
+
+        for (id, path) in entries {
+            let mut logs = std::fs::File::open(&path)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(logs.metadata()?.len());
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            tar.append_data(&mut header, format!("{}.log", &*id), &mut logs)?;
+
+            drop(logs);
+            std::fs::remove_file(&path)?;
+        }
+
+        tar.into_inner()?.finish()?.sync_all()?;
+        self.size = std::fs::metadata(self.path())?.len();
+
+        Ok(self)
+    }
+
+    /// Stream the archive in chunks of at most the given size, with the total archive size
+    /// attached to the first chunk.
+    /// The temporary file is removed once the stream is finished or dropped
+    pub async fn stream(self, chunk_size: usize) -> Result<BoxStream<'static, Result<deimosproto::LogArchiveChunk, tonic::Status>>, std::io::Error> {
+        let file = tokio::fs::File::open(self.path()).await?;
+
+        Ok(
+            futures::stream::unfold(Some((self, file, true)), move |state| async move {
+                let (archive, mut file, first) = state?;
+                let mut chunk = vec![0u8; chunk_size];
+                match file.read(&mut chunk).await {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        let response = deimosproto::LogArchiveChunk {
+                            archive_size: if first { archive.size } else { 0 },
+                            chunk,
+                        };
+
+                        Some((Ok(response), Some((archive, file, false))))
+                    },
+                    Err(e) => Some((
+                        Err(
+                            deimosproto::ErrorDetail::new(deimosproto::ErrorCode::InternalError, format!("Failed to read log archive: {}", e))
+                                .into_status(tonic::Code::Internal)
+                        ),
+                        None,
+                    )),
+                }
+            })
+            .boxed()
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogExportError {
+    #[error("No pod with ID {0}")]
+    UnknownPod(String),
+    #[error("Requested logs exceed the export limit of {} bytes", limit)]
+    LimitExceeded {
+        limit: usize,
+    },
+    #[error("Failed to collect logs for pod {}: {}", id, err)]
+    Collect {
+        id: DeimosId,
+        err: PodCollectLogsError,
+    },
+    #[error("Failed to write log archive: {0}")]
+    Write(#[from] std::io::Error),
+    #[error("Log archive task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+impl From<LogExportError> for tonic::Status {
+    fn from(value: LogExportError) -> Self {
+        let (code, error) = match value {
+            LogExportError::UnknownPod(..) => (tonic::Code::NotFound, deimosproto::ErrorCode::PodNotFound),
+            LogExportError::LimitExceeded { .. } => (tonic::Code::ResourceExhausted, deimosproto::ErrorCode::LogExportTooLarge),
+            LogExportError::Collect { err: PodCollectLogsError::NoContainer, .. } => (tonic::Code::FailedPrecondition, deimosproto::ErrorCode::PodLogsUnavailable),
+            _ => (tonic::Code::Internal, deimosproto::ErrorCode::InternalError),
+        };
+
+        let detail = deimosproto::ErrorDetail::new(error, value.to_string());
+        let detail = match value {
+            LogExportError::UnknownPod(id) => detail.with_pod(id),
+            LogExportError::Collect { id, .. } => detail.with_pod(id.owned()),
+            _ => detail,
+        };
+
+        detail.into_status(code)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    /// Create an empty archive in a new private directory, as [Deimos::export_logs] does
+    fn archive() -> LogArchive {
+        LogArchive { dir: LogArchive::private_dir().unwrap(), size: 0 }
+    }
+
+    /// Write the given logs to private files in the archive's directory
+    fn logs(archive: &LogArchive, logs: &[(&str, &[u8])]) -> Vec<(DeimosId, PathBuf)> {
+        logs
+            .iter()
+            .map(|(id, data)| {
+                let path = archive.dir.path().join(format!("{}.log", id));
+                std::io::Write::write_all(&mut LogArchive::private_options().open(&path).unwrap(), data).unwrap();
+                (serde_json::from_value(serde_json::Value::from(*id)).unwrap(), path)
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn archive_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let archive = archive();
+        let entries = logs(&archive, &[("web", b"line\n")]);
+        let archive = archive.write(entries).unwrap();
+
+        let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(archive.dir.path()), 0o700);
+        assert_eq!(mode(&archive.path()), 0o600);
+    }
+
+    #[test]
+    fn archive_contains_each_pod_log() {
+        let archive = archive();
+        let entries = logs(&archive, &[("web", b"first\nsecond\n"), ("db", b"")]);
+        let sources = entries.iter().map(|(_, path)| path.clone()).collect::<Vec<_>>();
+        let archive = archive.write(entries).unwrap();
+
+        assert_eq!(archive.size(), std::fs::metadata(archive.path()).unwrap().len());
+        assert!(sources.iter().all(|path| !path.exists()), "log files are removed once archived");
+
+        let file = std::fs::File::open(archive.path()).unwrap();
+        let mut tar = tar::Archive::new(zstd::Decoder::new(file).unwrap());
+        let mut found = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            found.push((entry.path().unwrap().display().to_string(), data));
+        }
+
+        assert_eq!(found, vec![(String::from("web.log"), b"first\nsecond\n".to_vec()), (String::from("db.log"), Vec::new())]);
+    }
+
+    #[tokio::test]
+    async fn stream_sends_archive_in_chunks_and_removes_it() {
+        let archive = archive();
+        let entries = logs(&archive, &[("web", &[b'x'; 4096])]);
+        let archive = archive.write(entries).unwrap();
+        let expected = std::fs::read(archive.path()).unwrap();
+        let dir = archive.dir.path().to_owned();
+
+        let chunks = archive.stream(16).await.unwrap().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(chunks[0].archive_size, expected.len() as u64);
+        assert!(chunks.iter().skip(1).all(|chunk| chunk.archive_size == 0));
+        assert!(chunks.iter().all(|chunk| chunk.chunk.len() <= 16));
+        assert_eq!(chunks.into_iter().flat_map(|chunk| chunk.chunk).collect::<Vec<_>>(), expected);
+        assert!(!dir.exists(), "directory is removed once the stream ends");
+    }
+}
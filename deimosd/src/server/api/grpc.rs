@@ -1,155 +1,797 @@
 //! Implementation of public authorization and pod control gRPC endpoints
 
 
-use std::sync::Arc;
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
 
 use bytes::Bytes;
-use futures::StreamExt;
+use chrono::{Local, NaiveTime, TimeDelta, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tonic::async_trait;
 
-use deimosproto as proto;
+use deimosproto::{self as proto, capability::Capability};
 
-use crate::{pod::{docker::logs::PodLogStream, id::DeimosId, PodState, PodStateStream}, server::Deimos};
+use crate::{pod::{annotate::PodAnnotation, artwork::{PodArtwork, PodArtworkFormat, PodArtworkKind}, config::{PodConfirmLevel, PodDockerConfig, PodScheduleConfig}, docker::{failure::{PodFailure, PodFailureKind}, logs::PodLogOptions, stats::PodStatsSample}, image::PodBlock, limit::PodLimitUsage, schedule::PodScheduledTransition, Pod, PodState, PodStateCause}, server::Deimos};
 
-use super::auth::PendingTokenStream;
+use super::auth::{ApiTokenIssueError, ApiTokenScope};
 
 
 #[async_trait]
 impl deimosproto::authserver::DeimosAuthorization for Deimos {
-    type RequestTokenStream = PendingTokenStream;
+    type RequestTokenStream = BoxStream<'static, Result<deimosproto::Token, tonic::Status>>;
 
     async fn request_token(self: Arc<Self>, request: tonic::Request<deimosproto::TokenRequest>) -> Result<tonic::Response<Self::RequestTokenStream>, tonic::Status> {
-        let requester = request.remote_addr().ok_or_else(|| tonic::Status::failed_precondition("Failed to get IP address of requester"))?;
+        self.api.drain.check()?;
+        let requester = request.remote_addr().ok_or_else(|| {
+            proto::ErrorDetail::new(proto::ErrorCode::RequesterAddressUnknown, "Failed to get IP address of requester")
+                .into_status(tonic::Code::FailedPrecondition)
+        })?;
         let username = Arc::from(request.into_inner().user);
-        Ok(tonic::Response::new(self.api.auth.create_request(requester.ip(), username).await))
+        let stream = self
+            .api
+            .auth
+            .create_request(requester.ip(), username)
+            .map_err(|e| {
+                let (code, error) = match e {
+                    ApiTokenIssueError::UsernameInUse(..) | ApiTokenIssueError::RequestPending(..) => (tonic::Code::AlreadyExists, proto::ErrorCode::UsernameInUse),
+                    ApiTokenIssueError::InvalidUsername => (tonic::Code::InvalidArgument, proto::ErrorCode::InvalidUsername),
+                    ApiTokenIssueError::TooManyPending { .. } | ApiTokenIssueError::Cooldown { .. } => {
+                        tracing::debug!("Rejected token request: {}", e);
+                        (tonic::Code::ResourceExhausted, proto::ErrorCode::TokenRequestsLimited)
+                    },
+                    ApiTokenIssueError::KeyCollision { .. } => (tonic::Code::Internal, proto::ErrorCode::InternalError),
+                };
+
+                proto::ErrorDetail::new(error, e.to_string()).into_status(code)
+            })?;
+
+        Ok(tonic::Response::new(self.api.drain.stream(stream)))
     }
 }
 
 #[async_trait]
 impl proto::server::DeimosService for Deimos {
+    async fn get_server_info(
+        self: Arc<Self>,
+        _: tonic::Request<proto::ServerInfoRequest>,
+    ) -> Result<tonic::Response<proto::ServerInfo>, tonic::Status> {
+        Ok(tonic::Response::new(proto::ServerInfo {
+            started: self.lifecycle.started().timestamp(),
+            previous_termination: proto::DaemonTermination::from(self.lifecycle.previous()) as i32,
+            addresses: self.api.bound.iter().map(ToString::to_string).collect(),
+            limits: Some(proto::PodLimits::from(self.pods.limits())),
+            capabilities: self.api.capabilities.advertise(),
+        }))
+    }
+
     async fn query_pods(
         self: Arc<Self>,
-        _: tonic::Request<proto::QueryPodsRequest>,
+        req: tonic::Request<proto::QueryPodsRequest>,
     ) -> Result<tonic::Response<proto::QueryPodsResponse>, tonic::Status> {
+        let scope = ApiTokenScope::of(&req)?;
+        let include_archived = req.into_inner().include_archived;
         let pods = self
             .pods
+            .pods()
             .iter()
-            .map(|(_, pod)| proto::PodBrief {
-                id: pod.id().owned(),
-                title: pod.title().to_owned(),
-                state: proto::PodState::from(pod.state().current()) as i32,
-            })
+            .filter(|pod| (include_archived || !pod.archived()) && scope.allows(pod))
+            .map(|pod| self.pod_brief(pod))
             .collect::<Vec<_>>();
 
         Ok(tonic::Response::new(proto::QueryPodsResponse { pods }))
     }
 
+    async fn get_pod_images(
+        self: Arc<Self>,
+        req: tonic::Request<proto::PodImagesRequest>,
+    ) -> Result<tonic::Response<proto::PodImagesResponse>, tonic::Status> {
+        self.api.require(Capability::PodImages)?;
+        let scope = ApiTokenScope::of(&req)?;
+        let req = req.into_inner();
+        let pod = self.lookup_scoped_pod(&scope, req.id)?;
+
+        Ok(tonic::Response::new(proto::PodImagesResponse {
+            icon: pod_image(&pod, PodArtworkKind::Icon, &req.icon_hash, req.omit_data).await,
+            banner: pod_image(&pod, PodArtworkKind::Banner, &req.banner_hash, req.omit_data).await,
+        }))
+    }
+
+    type DownloadPodImageStream = BoxStream<'static, Result<proto::PodImageChunk, tonic::Status>>;
+
+    async fn download_pod_image(
+        self: Arc<Self>,
+        req: tonic::Request<proto::PodImageDownloadRequest>,
+    ) -> Result<tonic::Response<Self::DownloadPodImageStream>, tonic::Status> {
+        self.api.require(Capability::PodImageDownload)?;
+        //Small chunks keep the progress of downloads over slow connections when they are cut off
+        let chunk_size = self.chunk_size(req.metadata()).clamp(1, Self::IMAGE_CHUNK_SIZE);
+        let scope = ApiTokenScope::of(&req)?;
+        let req = req.into_inner();
+        let kind = match req.kind() {
+            proto::PodImageKind::Icon => PodArtworkKind::Icon,
+            proto::PodImageKind::Banner => PodArtworkKind::Banner,
+        };
+
+        let pod = self.lookup_scoped_pod(&scope, req.id)?;
+        let unavailable = |message: String| {
+            proto::ErrorDetail::new(proto::ErrorCode::PodImageUnavailable, message)
+                .with_pod(pod.id().owned())
+                .into_status(tonic::Code::NotFound)
+        };
+
+        let artwork = match pod.artwork(kind).await {
+            Ok(Some(artwork)) => artwork,
+            Ok(None) => return Err(unavailable(format!("Pod {} has no {:?}", pod.id(), kind))),
+            Err(e) => {
+                tracing::warn!("Not sending {:?} of pod {}: {}", kind, pod.id(), e);
+                return Err(unavailable(e.to_string()))
+            },
+        };
+
+        tracing::trace!("Sending {:?} of pod {} from offset {} of {}", kind, pod.id(), req.offset, req.hash);
+        let chunks = image_chunks(artwork, &req.hash, req.offset, chunk_size).map(Ok);
+        Ok(tonic::Response::new(self.api.drain.stream(futures::stream::iter(chunks))))
+    }
+
+    async fn get_pod_details(
+        self: Arc<Self>,
+        req: tonic::Request<proto::PodDetailsRequest>,
+    ) -> Result<tonic::Response<proto::PodDetails>, tonic::Status> {
+        let scope = ApiTokenScope::of(&req)?;
+        let pod = self.lookup_scoped_pod(&scope, req.into_inner().id)?;
+        let schedule = &pod.config().schedule;
+        let format = |time: Option<NaiveTime>| time.map(|t| t.format("%H:%M").to_string()).unwrap_or_default();
+
+        Ok(tonic::Response::new(proto::PodDetails {
+            brief: Some(self.pod_brief(&pod)),
+            schedule: Some(proto::PodSchedule {
+                start: format(schedule.start),
+                stop: format(schedule.stop),
+                zone: PodScheduleConfig::zone_name(*Local::now().offset()),
+            }),
+            annotations: pod
+                .annotations()
+                .iter()
+                .map(|annotation| proto::PodAnnotation::from(&**annotation))
+                .collect(),
+            command: Some(pod_command(&pod.config().docker)),
+            container_address: pod
+                .network()
+                .and_then(|network| network.container_ip)
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+            env_overrides: pod
+                .env_overrides()
+                .into_iter()
+                .map(|(key, var)| proto::PodEnvOverrideKey { key, secret: var.secret })
+                .collect(),
+            env_pending: pod.env_pending(),
+        }))
+    }
+
+    async fn override_schedule(
+        self: Arc<Self>,
+        req: tonic::Request<proto::OverrideScheduleRequest>,
+    ) -> Result<tonic::Response<proto::OverrideScheduleResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let scope = ApiTokenScope::unrestricted_of(&req, "Overriding a pod's schedule")?;
+        let req = req.into_inner();
+        let pod = self.lookup_scoped_pod(&scope, req.id)?;
+
+        let next_transition = pod
+            .skip_next_transition(req.skip_next)
+            .ok_or_else(|| {
+                proto::ErrorDetail::new(proto::ErrorCode::PodNotScheduled, format!("Pod {} has no scheduled transitions", pod.id()))
+                    .with_pod(pod.id().owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+            })?;
+
+        tracing::info!(
+            "{} next scheduled transition of pod {} at {}",
+            if req.skip_next { "Skipping" } else { "Restoring" },
+            pod.id(),
+            next_transition.0.at,
+        );
+
+        Ok(tonic::Response::new(proto::OverrideScheduleResponse {
+            next_transition: Some(scheduled_transition(next_transition)),
+        }))
+    }
+
     async fn update_pod(
         self: Arc<Self>,
         req: tonic::Request<proto::UpdatePodRequest>,
     ) -> Result<tonic::Response<proto::UpdatePodResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let scope = ApiTokenScope::of(&req)?;
+        let req = req.into_inner();
+        let pod = self.lookup_scoped_pod(&scope, req.id)?;
+
+        self.begin_pod_update(pod, req.method, req.duration_seconds).await?;
+        Ok(tonic::Response::new(proto::UpdatePodResponse {}))
+    }
+
+    async fn restart_pod(
+        self: Arc<Self>,
+        req: tonic::Request<proto::RestartPodRequest>,
+    ) -> Result<tonic::Response<proto::RestartPodResponse>, tonic::Status> {
+        self.api.drain.check()?;
+        let scope = ApiTokenScope::of(&req)?;
+        let pod = self.lookup_scoped_pod(&scope, req.into_inner().id)?;
+        let id = pod.id();
+
+        if pod.archived() {
+            return Err(
+                proto::ErrorDetail::new(proto::ErrorCode::PodArchived, format!("Pod {} is archived and cannot be restarted", id))
+                    .with_pod(id.owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+            )
+        }
+
+        tokio::task::spawn(async move {
+            let mut lock = pod.state().transact().await;
+            lock.set_cause(PodStateCause::Requested);
+            if let Err(e) = self.pods.restart(pod.clone(), lock).await {
+                tracing::error!(
+                    "Failed to restart pod {} in response to API request: {}",
+                    id,
+                    e
+                );
+            }
+        });
+
+        Ok(tonic::Response::new(proto::RestartPodResponse {}))
+    }
+
+    type SubscribePodStatusStream = BoxStream<'static, Result<proto::PodStatusNotification, tonic::Status>>;
+
+    async fn subscribe_pod_status(
+        self: Arc<Self>,
+        req: tonic::Request<proto::PodStatusStreamRequest>,
+    ) -> Result<tonic::Response<Self::SubscribePodStatusStream>, tonic::Status> {
+        let scope = ApiTokenScope::of(&req)?;
+        let this = self.clone();
+        let filter = scope.clone();
+        let states = self
+            .pods
+            .stream()
+            .filter(move |(id, ..)| futures::future::ready(this.in_scope(&filter, id)));
+
+        let this = self.clone();
+        let states = states.map(move |(id, state, cause)| {
+            let pod = this.pods.get(&id);
+            let (failure, breaker, stopped_at) = match (state, &pod) {
+                (PodState::Disabled, Some(pod)) => (pod.failure(), restart_breaker(pod), pod.stopped_at()),
+                _ => (None, None, None),
+            };
+            let alerts = pod.as_deref().map(active_alerts).unwrap_or_default();
+            let archived = pod.as_ref().is_some_and(|pod| pod.archived());
+            let next_transition = pod
+                .filter(|pod| !pod.archived())
+                .and_then(|pod| pod.next_transition())
+                .map(scheduled_transition);
+
+            Ok(proto::PodStatusNotification {
+                id: id.owned(),
+                state: proto::PodState::from(state) as i32,
+                cause: proto::PodStateCause::from(cause) as i32,
+                annotation: None,
+                failure: failure.as_deref().map(proto::PodFailure::from),
+                restart_breaker: breaker,
+                next_transition,
+                alerts,
+                archived,
+                stopped_at: stopped_at.map(|at| at.timestamp()).unwrap_or_default(),
+                ..Default::default()
+            })
+        });
+
+        //Annotations dropped because the subscriber fell behind advance the sequence so that the
+        //client sees a gap and re-synchronizes
+        let sequence = Arc::new(AtomicU64::new(0));
+
+        //Annotations are forwarded with the pod's current state so that clients ignoring them
+        //still see an accurate state
+        let this = self.clone();
+        let lagged = sequence.clone();
+        let annotations = tokio_stream::wrappers::BroadcastStream::new(self.pods.subscribe_annotations())
+            .filter_map(move |annotation| {
+                let annotation = annotation.inspect_err(|BroadcastStreamRecvError::Lagged(skipped)| {
+                    tracing::debug!("Status subscriber fell behind, dropping {} annotations", skipped);
+                    lagged.fetch_add(*skipped, Ordering::Relaxed);
+                });
+
+                let notification = annotation.ok().and_then(|(id, annotation)| {
+                    let pod = this.pods.get(&id).filter(|pod| scope.allows(pod))?;
+                    Some(Ok(proto::PodStatusNotification {
+                        id: id.owned(),
+                        state: proto::PodState::from(pod.state().current()) as i32,
+                        cause: proto::PodStateCause::from(pod.cause()) as i32,
+                        annotation: Some(proto::PodAnnotation::from(&*annotation)),
+                        failure: None,
+                        next_transition: pod.next_transition().filter(|_| !pod.archived()).map(scheduled_transition),
+                        alerts: active_alerts(&pod),
+                        archived: pod.archived(),
+                        ..Default::default()
+                    }))
+                });
+
+                futures::future::ready(notification)
+            });
+
+        //Notifications are numbered as they leave the merged stream, so every subscriber has its
+        //own sequence regardless of which pods are in its scope
+        let epoch = self.lifecycle.epoch();
+        let notifications = futures::stream::select(states, annotations).map(move |notification| {
+            notification.map(|notification| proto::PodStatusNotification {
+                sequence: sequence.fetch_add(1, Ordering::Relaxed),
+                epoch,
+                ..notification
+            })
+        });
+
+        Ok(tonic::Response::new(self.api.drain.stream(notifications)))
+    }
+
+    type SubscribePodLogsStream = BoxStream<'static, Result<proto::PodLogChunk, tonic::Status>>;
+
+    async fn subscribe_pod_logs(self: Arc<Self>, req: tonic::Request<proto::PodLogStreamRequest>) -> Result<tonic::Response<Self::SubscribePodLogsStream>, tonic::Status> {
+        let chunk_size = self.chunk_size(req.metadata());
+        let scope = ApiTokenScope::of(&req)?;
         let req = req.into_inner();
-        let pod = self.lookup_pod(req.id)?;
+        let options = PodLogOptions::from(&req);
+        let pod = self.lookup_scoped_pod(&scope, req.id)?;
+        let id = pod.id();
+        tracing::trace!("Client subscribed to logs for {} with {:?}", id, options);
+
+        self
+            .pods
+            .subscribe_logs(pod, options)
+            .await
+            .map_err(|e| {
+                proto::ErrorDetail::new(proto::ErrorCode::PodLogsUnavailable, e.to_string())
+                    .with_pod(id.owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+            })
+            .map(|sub|
+                tonic::Response::new(
+                    self.api.drain.stream(
+                        sub.flat_map(move |bytes|
+                            futures::stream::iter(
+                                split_chunks(bytes, chunk_size).map(|chunk| Ok(proto::PodLogChunk { chunk: chunk.to_vec() }))
+                            )
+                        )
+                    )
+                )
+            )
+    }
+
+    type SubscribePodStatsStream = BoxStream<'static, Result<proto::PodStats, tonic::Status>>;
+
+    async fn subscribe_pod_stats(
+        self: Arc<Self>,
+        req: tonic::Request<proto::PodStatsStreamRequest>,
+    ) -> Result<tonic::Response<Self::SubscribePodStatsStream>, tonic::Status> {
+        self.api.require(Capability::PodStats)?;
+        let scope = ApiTokenScope::of(&req)?;
+        let pod = self.lookup_scoped_pod(&scope, req.into_inner().id)?;
+        let id = pod.id();
+
+        let stats = self.pods.subscribe_stats(&pod).await.map_err(|e| {
+            proto::ErrorDetail::new(proto::ErrorCode::PodNotEnabled, e.to_string())
+                .with_pod(id.owned())
+                .into_status(tonic::Code::FailedPrecondition)
+        })?;
+
+        tracing::trace!("Client subscribed to statistics of {}", id);
+        Ok(tonic::Response::new(self.api.drain.stream(stats.map(|sample| Ok(proto::PodStats::from(sample))))))
+    }
+
+    type AttachPodExecStream = BoxStream<'static, Result<proto::PodAttachOutput, tonic::Status>>;
+
+    async fn attach_pod_exec(
+        self: Arc<Self>,
+        req: tonic::Request<tonic::Streaming<proto::PodAttachInput>>,
+    ) -> Result<tonic::Response<Self::AttachPodExecStream>, tonic::Status> {
+        self.api.require(Capability::Attach)?;
+        let scope = ApiTokenScope::of(&req)?;
+        self.attach_session(&scope, req.into_inner()).await.map(tonic::Response::new)
+    }
+}
+
+impl Deimos {
+    /// Check an update of the given pod's state requested through the public or internal API,
+    /// and begin the update in a task that resolves once the pod has left transit
+    pub(super) async fn begin_pod_update(
+        self: Arc<Self>,
+        pod: Arc<Pod>,
+        method_value: i32,
+        duration_seconds: u64,
+    ) -> Result<JoinHandle<Result<(), String>>, tonic::Status> {
         let id = pod.id();
+        let method = proto::PodState::try_from(method_value);
+        if method == Ok(proto::PodState::Enabled) && pod.archived() {
+            return Err(
+                proto::ErrorDetail::new(proto::ErrorCode::PodArchived, format!("Pod {} is archived and cannot be enabled", id))
+                    .with_pod(id.owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+            )
+        }
+
+        //Check the image again so that pods can be enabled as soon as their image is pulled, pods
+        //that pull their own image do so when they are enabled
+        if method == Ok(proto::PodState::Enabled) && pod.blocked().is_some() && !pod.config().docker.pulls_missing() {
+            let block = self.pods.check_image(&pod).await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to check image of blocked pod {}: {}", id, e);
+                pod.blocked()
+            });
+
+            if block == Some(PodBlock::ImageMissing) {
+                return Err(
+                    proto::ErrorDetail::new(
+                        proto::ErrorCode::PodImageMissing,
+                        format!("Image '{}' of pod {} has not been pulled", pod.config().docker.image, id)
+                    )
+                    .with_pod(id.owned())
+                    .into_status(tonic::Code::FailedPrecondition)
+                )
+            }
+        }
 
-        match proto::PodState::try_from(req.method) {
+        let until = match duration_seconds {
+            0 => None,
+            _ if method != Ok(proto::PodState::Enabled) => {
+                return Err(
+                    proto::ErrorDetail::new(proto::ErrorCode::InvalidDuration, "A duration may only be given when enabling a pod")
+                        .with_pod(id.owned())
+                        .into_status(tonic::Code::InvalidArgument)
+                )
+            },
+            secs => {
+                let until = i64::try_from(secs)
+                    .ok()
+                    .and_then(TimeDelta::try_seconds)
+                    .and_then(|duration| Utc::now().checked_add_signed(duration));
+
+                match until {
+                    Some(until) => Some(until),
+                    None => return Err(
+                        proto::ErrorDetail::new(proto::ErrorCode::InvalidDuration, format!("Duration of {} seconds is out of range", secs))
+                            .with_pod(id.owned())
+                            .into_status(tonic::Code::InvalidArgument)
+                    ),
+                }
+            }
+        };
+
+        //Any explicit request replaces a previous timed enable
+        if method.is_ok_and(|method| !matches!(method, proto::PodState::Transit | proto::PodState::Degraded)) {
+            self.pods.set_enabled_until(&pod, until);
+        }
+
+        let update = match method {
             Ok(proto::PodState::Disabled) => tokio::task::spawn(async move {
-                let lock = pod.state().transact().await;
-                if let Err(e) = self.pods.disable(pod.clone(), lock).await {
+                let mut lock = pod.state().transact().await;
+                lock.set_cause(PodStateCause::Requested);
+                self.pods.disable(pod.clone(), lock).await.map_err(|e| {
                     tracing::error!(
                         "Failed to disable pod {} in response to API request: {}",
                         id,
                         e
                     );
-                }
+                    e.to_string()
+                })
             }),
             Ok(proto::PodState::Enabled) => tokio::task::spawn(async move {
-                let lock = pod.state().transact().await;
-                if let Err(e) = self.pods.enable(pod.clone(), lock).await {
+                let mut lock = pod.state().transact().await;
+                lock.set_cause(PodStateCause::Requested);
+                self.pods.enable(pod.clone(), lock).await.map_err(|e| {
                     tracing::error!(
                         "Failed to enable pod {} in response to API request: {}",
                         id,
                         e
                     );
-                }
+                    e.to_string()
+                })
             }),
             Ok(proto::PodState::Paused) => tokio::task::spawn(async move {
-                let lock = pod.state().transact().await;
-                if let Err(e) = self.pods.pause(pod.clone(), lock).await {
+                let mut lock = pod.state().transact().await;
+                lock.set_cause(PodStateCause::Requested);
+                self.pods.pause(pod.clone(), lock).await.map_err(|e| {
                     tracing::error!(
-                        "Failed to puase pod {} in response to API request: {}",
+                        "Failed to pause pod {} in response to API request: {}",
                         id,
                         e
                     );
-                }
+                    e.to_string()
+                })
             }),
             Ok(proto::PodState::Transit) => {
-                return Err(tonic::Status::invalid_argument(String::from(
-                    "Cannot set pod to reserved state Transit",
-                )))
-            }
+                return Err(
+                    proto::ErrorDetail::new(proto::ErrorCode::InvalidPodState, "Cannot set pod to reserved state Transit")
+                        .with_pod(id.owned())
+                        .into_status(tonic::Code::InvalidArgument)
+                )
+            },
+            Ok(proto::PodState::Degraded) => {
+                return Err(
+                    proto::ErrorDetail::new(proto::ErrorCode::InvalidPodState, "Pods are only degraded by failing their health check")
+                        .with_pod(id.owned())
+                        .into_status(tonic::Code::InvalidArgument)
+                )
+            },
             Err(_) => {
-                return Err(tonic::Status::invalid_argument(format!(
-                    "Unknown pod state enumeration value {}",
-                    req.method
-                )))
+                return Err(
+                    proto::ErrorDetail::new(proto::ErrorCode::InvalidPodState, format!("Unknown pod state enumeration value {}", method_value))
+                        .with_pod(id.owned())
+                        .into_status(tonic::Code::InvalidArgument)
+                )
             }
         };
 
-        Ok(tonic::Response::new(proto::UpdatePodResponse {}))
+        Ok(update)
     }
 
-    type SubscribePodStatusStream = futures::stream::Map<
-        PodStateStream,
-        Box<PodStatusApiMapper>,
-    >;
+    /// Get a brief protobuf description of the given pod
+    fn pod_brief(&self, pod: &Pod) -> proto::PodBrief {
+        proto::PodBrief {
+            id: pod.id().owned(),
+            title: pod.title().to_string(),
+            state: proto::PodState::from(pod.state().current()) as i32,
+            next_transition: pod.next_transition().filter(|_| !pod.archived()).map(scheduled_transition),
+            enabled_until: pod.enabled_until().map(|until| until.timestamp()).unwrap_or_default(),
+            alerts: active_alerts(pod),
+            archived: pod.archived(),
+            blocked: match pod.blocked() {
+                Some(PodBlock::ImageMissing) => proto::PodBlock::ImageMissing,
+                None => proto::PodBlock::None,
+            } as i32,
+            endpoints: self
+                .pods
+                .endpoints(pod)
+                .into_iter()
+                .map(|endpoint| proto::PodEndpoint {
+                    protocol: endpoint.protocol.docker_name().to_owned(),
+                    address: endpoint.address.to_string(),
+                    port: endpoint.port as u32,
+                    external: endpoint.external,
+                })
+                .collect(),
+            attachable: pod.config().attach.is_some(),
+            last_error: pod.failure().as_deref().map(proto::PodFailure::from),
+            stopped_at: pod.stopped_at().map(|at| at.timestamp()).unwrap_or_default(),
+            restart_breaker: restart_breaker(pod),
+            confirm_level: proto::PodConfirmLevel::from(pod.config().confirm) as i32,
+        }
+    }
+}
 
-    async fn subscribe_pod_status(
-        self: Arc<Self>,
-        _: tonic::Request<proto::PodStatusStreamRequest>,
-    ) -> Result<tonic::Response<Self::SubscribePodStatusStream>, tonic::Status> {
-        let stream = self.pods.stream().map(Box::<PodStatusApiMapper>::from(Box::new(move |(id, state)| {
-            Ok(proto::PodStatusNotification {
-                id: id.owned(),
-                state: proto::PodState::from(state) as i32,
-            })
-        })));
+/// Get the restart breaker reported for the given pod, `None` unless the breaker is open
+fn restart_breaker(pod: &Pod) -> Option<proto::PodRestartBreaker> {
+    let breaker = pod.restart_breaker();
+    Some(proto::PodRestartBreaker {
+        failures: u32::try_from(breaker.failures()).unwrap_or(u32::MAX),
+        opened: breaker.opened()?.timestamp(),
+        probe_at: breaker.probe_at(&pod.config().docker.restart).map(|at| at.timestamp()).unwrap_or_default(),
+        last_error: breaker.last_error().unwrap_or_default().to_owned(),
+    })
+}
 
-        Ok(tonic::Response::new(stream))
+/// Get the overrides of the image's default command from the given pod's Docker config
+fn pod_command(config: &PodDockerConfig) -> proto::PodCommand {
+    let args = |args: &Option<Vec<String>>| args.clone().map(|args| proto::PodCommandArgs { args });
+
+    proto::PodCommand {
+        entrypoint: args(&config.entrypoint),
+        command: args(&config.command),
+        working_dir: config.working_dir.clone().unwrap_or_default(),
     }
+}
 
-    type SubscribePodLogsStream = futures::stream::Map<
-        PodLogStream,
-        Box<PodLogApiMapper>
-    >;
+/// Split the given bytes into chunks of at most the given size without copying
+fn split_chunks(bytes: Bytes, size: usize) -> impl Iterator<Item = Bytes> {
+    (0..bytes.len())
+        .step_by(size)
+        .map(move |start| bytes.slice(start..(start + size).min(bytes.len())))
+}
 
-    async fn subscribe_pod_logs(self: Arc<Self>, req: tonic::Request<proto::PodLogStreamRequest>) -> Result<tonic::Response<Self::SubscribePodLogsStream>, tonic::Status> {
-        let pod = self.lookup_pod(req.into_inner().id)?;
-        tracing::trace!("Client subscribed to logs for {}", pod.id());
+/// Split the given image into chunks of the given size, resuming from the offset held by the
+/// client if it holds part of the image with the given hash
+fn image_chunks(artwork: Arc<PodArtwork>, held: &str, offset: u64, chunk_size: usize) -> impl Iterator<Item = proto::PodImageChunk> {
+    //A part of an image that has since changed is discarded by restarting from the beginning
+    let size = artwork.data.len();
+    let offset = match held == artwork.hash {
+        true => usize::try_from(offset).ok().filter(|offset| *offset <= size).unwrap_or_default(),
+        false => 0,
+    };
 
-        self
-            .pods
-            .subscribe_logs(pod)
-            .await
-            .map_err(|e| tonic::Status::failed_precondition(e.to_string()))
-            .map(|sub|
-                tonic::Response::new(
-                    sub
-                        .map(
-                            Box::<PodLogApiMapper>::from(
-                                Box::new(|bytes: Bytes|
-                                    Ok(
-                                        proto::PodLogChunk {
-                                            chunk: bytes.to_vec()
-                                        }
-                                    )
-                                )
-                            )
-                        )
-                )
-            )
+    let mut header = Some(proto::PodImage {
+        hash: artwork.hash.clone(),
+        data: Vec::new(),
+        format: proto::PodImageFormat::from(artwork.format) as i32,
+        size: size as u64,
+    });
+
+    //The first chunk is sent even if the client holds the whole image, to confirm its hash
+    let starts = std::iter::once(offset).chain((offset..size).step_by(chunk_size).skip(1));
+    starts.map(move |start| {
+        let end = start.saturating_add(chunk_size).min(size);
+        proto::PodImageChunk {
+            image: header.take(),
+            offset: start as u64,
+            data: artwork.data[start..end].to_vec(),
+        }
+    })
+}
+
+/// Read the given image of a pod, leaving out its contents if the client already holds an image
+/// with the given hash. Images that cannot be read are logged and reported as missing
+async fn pod_image(pod: &Pod, kind: PodArtworkKind, held: &str, omit: bool) -> Option<proto::PodImage> {
+    let artwork = match pod.artwork(kind).await {
+        Ok(artwork) => artwork?,
+        Err(e) => {
+            tracing::warn!("Not sending {:?} of pod {}: {}", kind, pod.id(), e);
+            return None
+        },
+    };
+
+    Some(proto::PodImage {
+        data: match omit || artwork.hash == held {
+            true => Vec::new(),
+            false => artwork.data.clone(),
+        },
+        hash: artwork.hash.clone(),
+        format: proto::PodImageFormat::from(artwork.format) as i32,
+        size: artwork.data.len() as u64,
+    })
+}
+
+/// Get the resource usage alerts of the given pod that are currently firing
+fn active_alerts(pod: &Pod) -> Vec<proto::PodAlert> {
+    pod
+        .active_alerts()
+        .into_iter()
+        .map(|(rule, since)| proto::PodAlert { rule: rule.to_string(), since: since.timestamp() })
+        .collect()
+}
+
+fn scheduled_transition((transition, skipped): (PodScheduledTransition, bool)) -> proto::PodScheduledTransition {
+    proto::PodScheduledTransition {
+        action: proto::PodState::from(transition.action) as i32,
+        at: transition.at.timestamp(),
+        skipped,
+    }
+}
+
+impl From<&PodAnnotation> for proto::PodAnnotation {
+    fn from(value: &PodAnnotation) -> Self {
+        Self {
+            at: value.at.timestamp(),
+            level: proto::PodAnnotationLevel::from(value.level) as i32,
+            message: value.message.clone(),
+            fields: value.fields.clone().into_iter().collect(),
+        }
+    }
+}
+
+impl From<&proto::PodLogStreamRequest> for PodLogOptions {
+    fn from(value: &proto::PodLogStreamRequest) -> Self {
+        //A time before the container started selects all output, as does an unset time
+        let since = i64::try_from(value.since_seconds)
+            .ok()
+            .filter(|secs| *secs != 0)
+            .and_then(TimeDelta::try_seconds)
+            .and_then(|duration| Utc::now().checked_sub_signed(duration));
+
+        Self {
+            tail_lines: Some(value.tail_lines).filter(|lines| *lines != 0),
+            follow: value.follow,
+            since,
+        }
+    }
+}
+
+impl From<PodLimitUsage> for proto::PodLimits {
+    fn from(value: PodLimitUsage) -> Self {
+        let count = |value: usize| u32::try_from(value).unwrap_or(u32::MAX);
+        Self {
+            pods: count(value.pods),
+            max_pods: count(value.max_pods),
+            forwarded_ports: count(value.forwarded_ports),
+            max_forwarded_ports: count(value.max_forwarded_ports),
+            max_ports_per_pod: count(value.max_ports_per_pod),
+        }
     }
 }
 
-type PodStatusApiMapper = dyn FnMut((DeimosId, PodState)) -> Result<proto::PodStatusNotification, tonic::Status> + Send + Sync;
-type PodLogApiMapper = dyn FnMut(Bytes) -> Result<proto::PodLogChunk, tonic::Status> + Send + Sync;
+impl From<PodConfirmLevel> for proto::PodConfirmLevel {
+    fn from(value: PodConfirmLevel) -> Self {
+        match value {
+            PodConfirmLevel::None => Self::None,
+            PodConfirmLevel::Confirm => Self::Confirm,
+            PodConfirmLevel::TypeToConfirm => Self::Typed,
+        }
+    }
+}
+
+impl From<PodArtworkFormat> for proto::PodImageFormat {
+    fn from(value: PodArtworkFormat) -> Self {
+        match value {
+            PodArtworkFormat::Png => Self::Png,
+            PodArtworkFormat::Jpeg => Self::Jpeg,
+            PodArtworkFormat::Svg => Self::Svg,
+            PodArtworkFormat::Gif => Self::Gif,
+        }
+    }
+}
+
+impl From<PodStatsSample> for proto::PodStats {
+    fn from(value: PodStatsSample) -> Self {
+        Self {
+            cpu_percent: value.cpu,
+            memory_used: value.memory_used,
+            memory_limit: value.memory_limit,
+            network_rx_bytes: value.network_rx,
+            network_tx_bytes: value.network_tx,
+        }
+    }
+}
+
+impl From<&PodFailure> for proto::PodFailure {
+    fn from(value: &PodFailure) -> Self {
+        Self {
+            at: value.at.timestamp(),
+            reason: value.reason.clone(),
+            output: value.output.clone().unwrap_or_default(),
+            kind: match value.kind {
+                PodFailureKind::Exited(..) => proto::PodFailureKind::Exited,
+                PodFailureKind::OutOfMemory => proto::PodFailureKind::OutOfMemory,
+                PodFailureKind::Killed => proto::PodFailureKind::Killed,
+                PodFailureKind::StartFailed => proto::PodFailureKind::StartFailed,
+            } as i32,
+            exit_code: match value.kind {
+                PodFailureKind::Exited(code) => code,
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn artwork(data: &[u8]) -> Arc<PodArtwork> {
+        Arc::new(PodArtwork { hash: format!("hash-{}", data.len()), format: PodArtworkFormat::Svg, data: data.to_vec() })
+    }
+
+    fn offsets(chunks: &[proto::PodImageChunk]) -> Vec<u64> {
+        chunks.iter().map(|chunk| chunk.offset).collect()
+    }
+
+    #[test]
+    fn image_chunks_resume_from_held_offset() {
+        let image = artwork(&[7u8; 100]);
+        let chunks = image_chunks(image.clone(), "hash-100", 40, 32).collect::<Vec<_>>();
+        assert_eq!(offsets(&chunks), [40, 72]);
+        assert_eq!(chunks[0].image.as_ref().map(|image| image.size), Some(100));
+        assert!(chunks[1].image.is_none());
+        assert_eq!(chunks.iter().map(|chunk| chunk.data.len()).sum::<usize>(), 60);
+
+        let chunks = image_chunks(image, "", 0, 32).collect::<Vec<_>>();
+        assert_eq!(offsets(&chunks), [0, 32, 64, 96]);
+    }
+
+    #[test]
+    fn image_chunks_restart_changed_images() {
+        let image = artwork(&[7u8; 100]);
+        assert_eq!(offsets(&image_chunks(image.clone(), "hash-50", 40, 64).collect::<Vec<_>>()), [0, 64]);
+        //Offsets beyond the end of the image are not trusted either
+        assert_eq!(offsets(&image_chunks(image.clone(), "hash-100", 101, 64).collect::<Vec<_>>()), [0, 64]);
+        //A client holding the whole image still receives its description
+        let chunks = image_chunks(image, "hash-100", 100, 64).collect::<Vec<_>>();
+        assert_eq!(offsets(&chunks), [100]);
+        assert!(chunks[0].image.is_some() && chunks[0].data.is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Routing for clients built against the unversioned `deimos` proto package
+
+use std::task::Poll;
+
+use tonic::codegen::http::{self, uri::PathAndQuery, Uri};
+
+/// Layer that rewrites calls made to services in the unversioned `deimos` package to the
+/// equivalent service in the `deimos.v1` package, which is wire-compatible.
+/// To be removed in the release after `deimos.v1` was introduced.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LegacyPackageLayer;
+
+/// Service produced by [LegacyPackageLayer]
+#[derive(Debug, Clone)]
+pub struct LegacyPackage<S> {
+    inner: S,
+}
+
+impl LegacyPackageLayer {
+    /// Prefix of all paths for services in the unversioned package
+    const LEGACY_PREFIX: &str = "/deimos.";
+    /// Name of the current package to route legacy calls to
+    const CURRENT: &str = "deimos.v1.";
+
+    /// Get the path that a call to the given legacy service path should be routed to, or `None`
+    /// if the path is not for a service in the legacy package
+    fn route(path: &str) -> Option<String> {
+        let rest = path.strip_prefix(Self::LEGACY_PREFIX)?;
+        match rest.starts_with("v1.") {
+            true => None,
+            false => Some(format!("/{}{}", Self::CURRENT, rest)),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for LegacyPackageLayer {
+    type Service = LegacyPackage<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LegacyPackage { inner }
+    }
+}
+
+impl<S, B> tower::Service<http::Request<B>> for LegacyPackage<S>
+where
+    S: tower::Service<http::Request<B>> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        if let Some(path) = LegacyPackageLayer::route(request.uri().path()) {
+            let mut parts = request.uri().clone().into_parts();
+            match PathAndQuery::try_from(path).map(|path| {
+                parts.path_and_query = Some(path);
+                Uri::from_parts(parts)
+            }) {
+                Ok(Ok(uri)) => {
+                    tracing::trace!("Routing legacy call {} to {}", request.uri().path(), uri.path());
+                    *request.uri_mut() = uri;
+                },
+                _ => tracing::warn!("Failed to route legacy call {}", request.uri().path()),
+            }
+        }
+
+        self.inner.call(request)
+    }
+}
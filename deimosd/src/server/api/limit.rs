@@ -0,0 +1,157 @@
+//! Reporting of requests that exceed the configured maximum message size
+
+use std::task::Poll;
+
+use deimosproto::limit::MessageSizeLimit;
+use futures::{future::MapOk, TryFutureExt};
+use tonic::codegen::http;
+
+/// Layer that replaces the generic status returned when a request message is larger than the
+/// server's decoding limit with an `INVALID_ARGUMENT` status carrying a
+/// [MESSAGE_TOO_LARGE](deimosproto::ErrorCode::MessageTooLarge) detail.
+/// Services must be configured with the same decoding limit as the layer
+#[derive(Debug, Clone, Copy)]
+pub struct MessageSizeLayer {
+    limit: MessageSizeLimit,
+}
+
+/// Service produced by [MessageSizeLayer]
+#[derive(Debug, Clone)]
+pub struct MessageSize<S> {
+    inner: S,
+    limit: MessageSizeLimit,
+}
+
+impl MessageSizeLayer {
+    /// Create a new layer for services with the given decoding limit
+    pub const fn new(limit: MessageSizeLimit) -> Self {
+        Self { limit }
+    }
+
+    /// Rewrite the status in the headers of the given response if it reports a request message
+    /// that exceeded the decoding limit.
+    /// Tonic reports these with an `OUT_OF_RANGE` status, which no service returns otherwise, so
+    /// only the code is matched and the message is replaced with one naming our own limit
+    fn rewrite<B>(limit: MessageSizeLimit, mut response: http::Response<B>) -> http::Response<B> {
+        let Some(status) = tonic::Status::from_header_map(response.headers()) else {
+            return response
+        };
+
+        if status.code() != tonic::Code::OutOfRange {
+            return response
+        }
+
+        tracing::debug!("Rejecting oversized request: {}", status.message());
+        let status = deimosproto::ErrorDetail::new(
+            deimosproto::ErrorCode::MessageTooLarge,
+            format!("Request message is larger than the limit of {} bytes", limit.bytes()),
+        )
+        .into_status(tonic::Code::InvalidArgument);
+        if let Err(e) = status.add_header(response.headers_mut()) {
+            tracing::warn!("Failed to set status for oversized request: {}", e);
+        }
+
+        response
+    }
+}
+
+impl<S> tower::Layer<S> for MessageSizeLayer {
+    type Service = MessageSize<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MessageSize { inner, limit: self.limit }
+    }
+}
+
+impl<S, B, R> tower::Service<http::Request<B>> for MessageSize<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<R>> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = MapOk<S::Future, Box<dyn FnOnce(http::Response<R>) -> http::Response<R> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let limit = self.limit;
+        self.inner.call(request).map_ok(Box::new(move |response| MessageSizeLayer::rewrite(limit, response)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Buf;
+    use tonic::codec::{DecodeBuf, Decoder, Streaming};
+
+    use super::*;
+
+    /// Decoder yielding the length of each message without parsing it
+    struct LengthDecoder;
+
+    impl Decoder for LengthDecoder {
+        type Item = usize;
+        type Error = tonic::Status;
+
+        fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<usize>, tonic::Status> {
+            let len = buf.remaining();
+            buf.advance(len);
+            Ok(Some(len))
+        }
+    }
+
+    /// Decode a single request message of the given length with tonic's decoder
+    async fn decode(len: usize, limit: MessageSizeLimit) -> Result<usize, tonic::Status> {
+        //Frame lengths are kept below 0x80 in every byte so that the frame is valid UTF-8
+        let mut frame = vec![0u8];
+        frame.extend_from_slice(&(len as u32).to_be_bytes());
+        frame.resize(frame.len() + len, b'a');
+        let body = String::from_utf8(frame).unwrap();
+
+        let mut stream = Streaming::new_request(LengthDecoder, body, None, Some(limit.bytes()));
+        stream.message().await.map(|message| message.unwrap_or_default())
+    }
+
+    fn respond(status: tonic::Status) -> http::Response<()> {
+        let mut response = http::Response::new(());
+        status.add_header(response.headers_mut()).unwrap();
+        MessageSizeLayer::rewrite(MessageSizeLimit::new(0), response)
+    }
+
+    #[tokio::test]
+    async fn tonic_reports_oversized_requests_out_of_range() {
+        let limit = MessageSizeLimit::new(0);
+        assert_eq!(decode(limit.bytes(), limit).await.unwrap(), limit.bytes());
+
+        let status = decode(limit.bytes() + 1, limit).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rewritten() {
+        let limit = MessageSizeLimit::new(0);
+        let response = respond(decode(limit.bytes() + 1, limit).await.unwrap_err());
+
+        let status = tonic::Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            deimosproto::ErrorDetail::from_status(&status).map(|detail| detail.code()),
+            Some(deimosproto::ErrorCode::MessageTooLarge),
+        );
+        assert!(status.message().contains(&limit.bytes().to_string()));
+    }
+
+    #[test]
+    fn other_statuses_are_kept() {
+        for code in [tonic::Code::Ok, tonic::Code::InvalidArgument, tonic::Code::ResourceExhausted] {
+            let response = respond(tonic::Status::new(code, "message"));
+            let status = tonic::Status::from_header_map(response.headers()).unwrap();
+            assert_eq!(status.code(), code);
+            assert_eq!(status.message(), "message");
+        }
+
+        let response = MessageSizeLayer::rewrite(MessageSizeLimit::new(0), http::Response::new(()));
+        assert!(response.headers().is_empty());
+    }
+}
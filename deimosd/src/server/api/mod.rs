@@ -1,22 +1,40 @@
 use std::future::Future;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::{Arc, PoisonError}, time::{Duration, Instant}};
 
-use auth::{ApiAuthorization, ApiAuthorizationConfig, ApiAuthorizationPersistent};
+use auth::{ApiAuthorization, ApiAuthorizationConfig, ApiAuthorizationPersistent, ApiTokenScope};
+use bind::{ApiBind, ApiBindError};
+use deadline::DeadlineLayer;
+use drain::ApiDrain;
 use igd_next::PortMappingProtocol;
+use deimosproto::{capability::{Capability, CapabilitySet}, limit::MessageSizeLimit};
+use legacy::LegacyPackageLayer;
+use limit::MessageSizeLayer;
+use pin::InternalPinAttempts;
+use status::{PodSharing, PodSharingPersistent, StatusServerConfig};
 use tokio_util::sync::CancellationToken;
 use tonic::service::interceptor::InterceptedService;
-use tonic::transport::{Server, ServerTlsConfig};
+use tonic::service::Routes;
+use tonic::transport::{server::TcpIncoming, Identity, Server, ServerTlsConfig};
 use zeroize::Zeroizing;
 
-use crate::pod::{Pod, PodState};
+use crate::pod::{annotate::PodAnnotationLevel, reserved::{ReservedPort, ReservedPortListener}, Pod, PodManager, PodState, PodStateCause};
 
-use super::upnp::{Upnp, UpnpLease, UpnpLeaseData};
+use super::upnp::{Upnp, UpnpLease, UpnpLeaseData, UpnpLeaseOwner};
 use super::Deimos;
 
 use deimosproto::{self as proto};
 
+mod attach;
 mod auth;
+mod bind;
+mod deadline;
+mod drain;
+mod export;
 mod grpc;
+mod legacy;
+mod limit;
+mod pin;
+pub mod status;
 
 /// State required exclusively for the gRPC server including UPnP port leases.
 pub struct ApiState {
@@ -24,6 +42,18 @@ pub struct ApiState {
     pub config: ApiConfig,
     /// Authorization state with all approved and pending tokens
     pub auth: ApiAuthorization,
+    /// Slugs of pods with publicly shared status pages
+    pub sharing: PodSharing,
+    /// Incorrect internal PIN attempts, used to lock out PIN-protected operations
+    pub pin: InternalPinAttempts,
+    /// Set when the daemon begins shutting down to reject changes and end streams
+    pub drain: ApiDrain,
+    /// Local addresses that the public API is bound to
+    pub bound: Vec<SocketAddr>,
+    /// Optional capabilities advertised to clients
+    pub capabilities: CapabilitySet,
+    /// Listeners for the public API, bound at startup and taken when the server is started
+    listeners: std::sync::Mutex<Vec<(SocketAddr, tokio::net::TcpListener)>>,
     /// Address leased for the API
     pub _lease: Option<UpnpLease>,
 }
@@ -32,8 +62,8 @@ pub struct ApiState {
 #[derive(Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ApiConfig {
-    /// Address to bind to when serving the public interface
-    pub bind: SocketAddr,
+    /// Address or list of addresses to bind to when serving the public interface
+    pub bind: ApiBind,
     /// Path to create a UDS socket for the internal priviledged API
     pub internal_bind: PathBuf,
     /// Enable UPnP port forwarding for the public API
@@ -43,56 +73,150 @@ pub struct ApiConfig {
     pub certificate: PathBuf,
     /// Path to TLS private key
     pub privkey: PathBuf,
-    /// Timeout for API connections
+    /// Maximum time to respond to calls to the public API, shortened by the deadline of
+    /// each call given by the client
     #[serde(default = "ApiConfig::default_timeout")]
     pub timeout: Duration,
+    /// Maximum time in seconds to respond to calls to the named methods of either API, such as
+    /// `DownloadLogs`, replacing `timeout` for those methods
+    #[serde(default)]
+    pub method_timeout_seconds: HashMap<String, u64>,
+    /// Time to wait for in-flight requests to complete when the daemon shuts down before
+    /// closing the remaining connections
+    #[serde(default = "ApiConfig::default_drain_timeout")]
+    pub drain_timeout: Duration,
     /// Configuration for the authorization component
     #[serde(default)]
     pub auth: ApiAuthorizationConfig,
+    /// Maximum total size in bytes of uncompressed logs that can be exported at once
+    #[serde(default = "ApiConfig::default_log_export_limit")]
+    pub log_export_limit: usize,
+    /// Maximum size in bytes of a single gRPC message sent or received by the public and
+    /// internal APIs. Streamed logs and archives are split into chunks well within this size
+    #[serde(default = "ApiConfig::default_max_message_size")]
+    pub max_message_size: usize,
+    /// Configuration for the HTTP server hosting shared pod status pages, disabled if not given
+    #[serde(default)]
+    pub status: Option<StatusServerConfig>,
+    /// Hash of a PIN required for privileged internal API operations, created with
+    /// `deimosctl set-pin`. Privileged operations are not PIN-protected if not given
+    #[serde(default)]
+    pub internal_pin_hash: Option<deimosproto::pin::InternalPinHash>,
+    /// Optional capabilities whose calls are refused and that are not advertised to clients.
+    /// Status pages are advertised only while `status` is configured
+    #[serde(default)]
+    pub disabled_capabilities: Vec<Capability>,
 }
 
 /// Persistent state for the API
 #[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ApiPersistent {
     pub tokens: ApiAuthorizationPersistent,
+    #[serde(default)]
+    pub sharing: PodSharingPersistent,
 }
 
 impl ApiState {
     /// Load the Deimos API service configuration and store a handle to the local Docker instance
     /// to manage containers
-    pub async fn load(persistent: ApiPersistent, config: ApiConfig, upnp: &Upnp) -> Result<Self, ApiInitError> {
+    pub async fn load(persistent: ApiPersistent, config: ApiConfig, upnp: &Upnp, pods: &PodManager) -> Result<Self, ApiInitError> {
+        let listeners = config.bind.listen()?;
+        let bound = listeners.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+
+        //Addresses sharing a port are reached through a single forwarded port
+        let ports = config.bind.external_ports();
         let lease = match config.upnp {
+            true if ports.is_empty() => {
+                tracing::warn!("UPnP is enabled for the API but it is only bound to loopback addresses, no ports will be forwarded");
+                None
+            },
             true => Some(
                 upnp
-                    .request(vec![
-                        UpnpLeaseData {
-                            port: config.bind.port(),
-                            protocol: PortMappingProtocol::TCP,
-                            name: "Deimos gRPC server".to_owned(),
-                        }
-                    ])
+                    .request(
+                        UpnpLeaseOwner::Api,
+                        ports
+                            .into_iter()
+                            .map(|port| UpnpLeaseData {
+                                port,
+                                host_port: port,
+                                protocol: PortMappingProtocol::TCP,
+                                name: "Deimos gRPC server".to_owned(),
+                            })
+                            .collect(),
+                        None,
+                    )
                     .await?
             ),
             false => None,
         };
 
         let auth = ApiAuthorization::load(persistent.tokens, config.auth.clone());
+        let sharing = PodSharing::load(persistent.sharing, pods);
+        let capabilities = Self::capabilities(&config);
 
-        Ok(Self { config, _lease: lease, auth })
+        Ok(Self {
+            capabilities,
+            config,
+            _lease: lease,
+            auth,
+            sharing,
+            pin: Default::default(),
+            drain: Default::default(),
+            bound,
+            listeners: std::sync::Mutex::new(listeners),
+        })
     }
     
+    /// Get the capabilities supported with the given config.
+    /// Every capability must be matched here so that none is advertised without being considered
+    fn capabilities(config: &ApiConfig) -> CapabilitySet {
+        Capability::ALL
+            .into_iter()
+            .filter(|capability| match capability {
+                Capability::Attach | Capability::PodStats | Capability::PodImages => !config.disabled_capabilities.contains(capability),
+                //Downloads serve the same images, so they are disabled along with them
+                Capability::PodImageDownload => {
+                    !config.disabled_capabilities.contains(capability) && !config.disabled_capabilities.contains(&Capability::PodImages)
+                },
+                Capability::StatusPages => {
+                    if config.disabled_capabilities.contains(capability) {
+                        tracing::warn!("Capability {} cannot be disabled, remove the [api.status] section instead", capability);
+                    }
+
+                    config.status.is_some()
+                },
+            })
+            .collect()
+    }
+
+    /// Fail calls to an RPC of the given capability if it is disabled
+    pub fn require(&self, capability: Capability) -> Result<(), tonic::Status> {
+        match self.capabilities.contains(capability) {
+            true => Ok(()),
+            false => Err(
+                deimosproto::ErrorDetail::new(deimosproto::ErrorCode::CapabilityDisabled, format!("Capability {} is disabled on this server", capability))
+                    .into_status(tonic::Code::Unimplemented)
+            ),
+        }
+    }
+
     /// Get persistent state to be written to a save file for the server
     pub fn save(&self) -> ApiPersistent {
         ApiPersistent {
             tokens: self.auth.persistent(),
+            sharing: self.sharing.save(),
         }
     }
 }
 
 impl Deimos {
+    /// Maximum number of bytes of a pod image sent in each message of a download
+    const IMAGE_CHUNK_SIZE: usize = 32 * 1024;
+
     /// Load all specified certificates from the paths specified in the config and attempt to run
     /// the server to completion.
-    /// This method should not return until the [CancellationToken] has been cancelled.
+    /// When the [CancellationToken] is cancelled both servers stop accepting connections, and this
+    /// method returns once all open connections have been closed gracefully
     pub async fn api_task(self: Arc<Self>, cancel: CancellationToken) {
         let public = match self.clone().run_public_server(&cancel).await {
             Ok(fut) => fut,
@@ -102,7 +226,7 @@ impl Deimos {
             }
         };
 
-        let internal = match self.run_internal_server(&cancel).await {
+        let internal = match self.clone().run_internal_server(&cancel).await {
             Ok(internal) => internal,
             Err(e) => {
                 tracing::error!("Failed to create internal gRPC server: {e}");
@@ -110,48 +234,91 @@ impl Deimos {
             }
         };
 
-        tokio::select! {
-            _ = cancel.cancelled() => {},
-            result = public => if let Err(e) = result {
-                tracing::error!("gRPC server error: {e:?}");
-            },
-            result = internal => if let Err(e) = result {
-                tracing::error!("gRPC private API error: {e:?}");
+        //Both servers have bound their sockets and pods have been loaded, so clients may connect
+        #[cfg(all(unix, feature = "systemd"))]
+        super::systemd::notify_ready();
+
+        //Pods are restored once clients can connect, so that they see the pods start
+        tokio::task::spawn(self.clone().restore_task());
+
+        let (public, internal) = tokio::join!(public, internal);
+        for (addr, result) in public {
+            if let Err(e) = result {
+                tracing::error!("gRPC server error on {addr}: {e:?}");
             }
         }
+
+        if let Err(e) = internal {
+            tracing::error!("gRPC private API error: {e:?}");
+        }
     }
 
-    async fn run_public_server(self: Arc<Self>, cancel: &CancellationToken) -> Result<impl Future<Output = Result<(), tonic::transport::Error>> + use<'_>, ApiInitError> {
+    /// Drain the API before the rest of the daemon is shut down, rejecting new changes and ending
+    /// streams before stopping the servers. In-flight requests are given up to the configured
+    /// drain timeout to complete before the given API task is aborted
+    pub(super) async fn drain_api(&self, mut api_task: tokio::task::JoinHandle<()>, api_cancel: CancellationToken) {
+        let started = Instant::now();
+        tracing::info!("Draining API connections");
+        self.api.drain.start();
+        api_cancel.cancel();
+
+        let timeout = self.api.config.drain_timeout;
+        match tokio::time::timeout(timeout, &mut api_task).await {
+            Ok(_) => tracing::info!("Drained API connections in {:?}", started.elapsed()),
+            Err(_) => {
+                tracing::warn!("API connections did not drain within {:?}, closing remaining connections", timeout);
+                api_task.abort();
+                let _ = api_task.await;
+            }
+        }
+    }
+
+    /// Create a server for each of the listeners bound at startup, sharing the same services and
+    /// TLS identity. The returned future completes once every server has shut down, with the
+    /// result of each server and the address it was bound to
+    async fn run_public_server(self: Arc<Self>, cancel: &CancellationToken) -> Result<impl Future<Output = Vec<(SocketAddr, Result<(), tonic::transport::Error>)>> + use<'_>, ApiInitError> {
         let config = &self.api.config;
 
-        let certificate = deimosproto::util::load_check_permissions(&config.certificate)
-            .await
-            .map(Zeroizing::new)
-            .map_err(|err| ApiInitError::LoadSensitiveFile(config.privkey.clone(), err))?;
-        let privkey = deimosproto::util::load_check_permissions(&config.privkey)
-            .await
-            .map(Zeroizing::new)
-            .map_err(|err| ApiInitError::LoadSensitiveFile(config.certificate.clone(), err))?;
-
-        let identity = tonic::transport::Identity::from_pem(certificate, privkey);
-
-        let mut server = Server::builder()
-            .timeout(config.timeout)
-            .tls_config(
-                ServerTlsConfig::new()
-                    .identity(identity)
-            )?;
-
-        Ok(server
-            .add_service(
-                InterceptedService::new(
-                    proto::server::DeimosServiceServer::from_arc(self.clone()),
-                    self.api.auth.clone(),
-                )
+        let (_, identity) = config.load_identity().await?;
+
+        let limit = config.message_size_limit();
+        let routes = Routes::new(
+            InterceptedService::new(
+                proto::server::DeimosServiceServer::from_arc(self.clone())
+                    .max_decoding_message_size(limit.bytes())
+                    .max_encoding_message_size(limit.bytes()),
+                self.api.auth.clone(),
             )
-            .add_service(proto::authserver::DeimosAuthorizationServer::from_arc(self.clone()))
-            .serve_with_shutdown(self.api.config.bind, cancel.cancelled())
         )
+        .add_service(
+            proto::authserver::DeimosAuthorizationServer::from_arc(self.clone())
+                .max_decoding_message_size(limit.bytes())
+                .max_encoding_message_size(limit.bytes())
+        );
+
+        let listeners = std::mem::take(&mut *self.api.listeners.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut servers = Vec::with_capacity(listeners.len());
+        for (addr, listener) in listeners {
+            let incoming = TcpIncoming::from_listener(listener, true, None)
+                .map_err(|err| ApiBindError { addr, err: std::io::Error::other(err) })?;
+
+            let mut server = Server::builder()
+                .layer(LegacyPackageLayer)
+                .layer(MessageSizeLayer::new(limit))
+                .layer(DeadlineLayer::new(Some(config.timeout), &config.method_timeout_seconds))
+                .tls_config(
+                    ServerTlsConfig::new()
+                        .identity(identity.clone())
+                )?;
+
+            tracing::info!("Serving public API on {}", addr);
+            let router = server.add_routes(routes.clone());
+            servers.push(async move {
+                (addr, router.serve_with_incoming_shutdown(incoming, cancel.cancelled()).await)
+            });
+        }
+
+        Ok(futures::future::join_all(servers))
     }
 
     /// Apply the settings in the given configuration to create a local socket that hosts the
@@ -163,26 +330,61 @@ impl Deimos {
             use tokio_stream::wrappers::UnixListenerStream;
             
             let bind = self.api.config.internal_bind.clone();
-            if let Some(parent) = bind.parent() {
-                std::fs::create_dir_all(parent)
-                    .map_err(|err| ApiInitError::CreateDir { path: parent.to_owned(), err })?;
-            }
 
-            if let Ok(true) = std::fs::exists(&bind) {
-                if let Err(e) = std::fs::remove_file(&bind) {
-                    tracing::warn!("Failed to delete old socket {}: {}", bind.display(), e);
+            #[cfg(feature = "systemd")]
+            let activated = match super::systemd::take_listener() {
+                Some(listener) => {
+                    let uds = listener
+                        .set_nonblocking(true)
+                        .and_then(|_| UnixListener::from_std(listener))
+                        .map_err(|err| ApiInitError::CreateSocket { path: bind.clone(), err })?;
+
+                    tracing::info!("Using socket passed by systemd for private API");
+                    Some(uds)
+                },
+                None => None,
+            };
+            #[cfg(not(feature = "systemd"))]
+            let activated = None;
+
+            let uds = match activated {
+                Some(uds) => uds,
+                None => {
+                    if let Some(parent) = bind.parent() {
+                        std::fs::create_dir_all(parent)
+                            .map_err(|err| ApiInitError::CreateDir { path: parent.to_owned(), err })?;
+                    }
+
+                    if let Ok(true) = std::fs::exists(&bind) {
+                        if let Err(e) = std::fs::remove_file(&bind) {
+                            tracing::warn!("Failed to delete old socket {}: {}", bind.display(), e);
+                        }
+                    }
+
+                    let uds = UnixListener::bind(&bind)
+                        .map_err(|err| ApiInitError::CreateSocket { path: bind.clone(), err })?;
+
+                    tracing::info!("Created socket at {} for private API", bind.display());
+                    uds
                 }
-            }
+            };
 
-            let uds = UnixListener::bind(&bind)
-                .map_err(|err| ApiInitError::CreateSocket { path: bind.clone(), err })?;
             let stream = UnixListenerStream::new(uds);
-
-            tracing::info!("Created socket at {} for private API", bind.display());
             
+            let limit = self.api.config.message_size_limit();
+            //Internal calls are only bounded by their client and the per-method maximums, as
+            //privileged operations such as pruning images may take longer than the public timeout
+            let deadline = DeadlineLayer::new(None, &self.api.config.method_timeout_seconds);
             Ok(async move {
                 Server::builder()
-                    .add_service(deimosproto::internal_server::InternalServer::from_arc(self))
+                    .layer(LegacyPackageLayer)
+                    .layer(MessageSizeLayer::new(limit))
+                    .layer(deadline)
+                    .add_service(
+                        deimosproto::internal_server::InternalServer::from_arc(self)
+                            .max_decoding_message_size(limit.bytes())
+                            .max_encoding_message_size(limit.bytes())
+                    )
                     .serve_with_incoming_shutdown(stream, cancel.cancelled())
                     .await
             })
@@ -195,12 +397,34 @@ impl Deimos {
         }
     }
 
+    /// Get the number of bytes to send in each message of a streamed response to a request with
+    /// the given metadata
+    fn chunk_size(&self, metadata: &tonic::metadata::MetadataMap) -> usize {
+        self.api.config.message_size_limit().negotiate(metadata).chunk_size()
+    }
+
     /// Get a pod by the ID as received from a client, and map not found to a [tonic::Status]
     /// indicating the error
     fn lookup_pod(&self, id: String) -> Result<Arc<Pod>, tonic::Status> {
+        self.lookup_scoped_pod(&ApiTokenScope::default(), id)
+    }
+
+    /// Get a pod by the ID as received from a client if the given scope allows access to it.
+    /// Pods outside of the scope are reported as not found so that their existence is not revealed
+    fn lookup_scoped_pod(&self, scope: &ApiTokenScope, id: String) -> Result<Arc<Pod>, tonic::Status> {
         self.pods
             .get(&id)
-            .ok_or_else(|| tonic::Status::not_found(id))
+            .filter(|pod| scope.allows(pod))
+            .ok_or_else(|| {
+                proto::ErrorDetail::new(proto::ErrorCode::PodNotFound, format!("No pod with ID {}", id))
+                    .with_pod(id)
+                    .into_status(tonic::Code::NotFound)
+            })
+    }
+
+    /// Check if the pod with the given ID is loaded and the given scope allows access to it
+    fn in_scope(&self, scope: &ApiTokenScope, id: &str) -> bool {
+        scope.allows_loaded(self.pods.get(id).as_deref())
     }
 }
 
@@ -211,6 +435,30 @@ impl From<PodState> for proto::PodState {
             PodState::Transit => proto::PodState::Transit,
             PodState::Paused => proto::PodState::Paused,
             PodState::Enabled => proto::PodState::Enabled,
+            PodState::Degraded => proto::PodState::Degraded,
+        }
+    }
+}
+
+impl From<PodAnnotationLevel> for proto::PodAnnotationLevel {
+    fn from(value: PodAnnotationLevel) -> Self {
+        match value {
+            PodAnnotationLevel::Info => proto::PodAnnotationLevel::Info,
+            PodAnnotationLevel::Warn => proto::PodAnnotationLevel::Warn,
+            PodAnnotationLevel::Error => proto::PodAnnotationLevel::Error,
+        }
+    }
+}
+
+impl From<PodStateCause> for proto::PodStateCause {
+    fn from(value: PodStateCause) -> Self {
+        match value {
+            PodStateCause::Requested => proto::PodStateCause::Requested,
+            PodStateCause::Scheduled => proto::PodStateCause::Scheduled,
+            PodStateCause::TimedEnableExpired => proto::PodStateCause::TimedEnableExpired,
+            PodStateCause::DependencyRestarted => proto::PodStateCause::DependencyRestarted,
+            PodStateCause::RestartPolicy => proto::PodStateCause::RestartPolicy,
+            PodStateCause::Restored => proto::PodStateCause::Restored,
         }
     }
 }
@@ -233,12 +481,78 @@ pub enum ApiInitError {
         path: PathBuf,
         err: std::io::Error,
     },
+    #[error("{0}")]
+    Bind(#[from] ApiBindError),
 }
 
 impl ApiConfig {
+    /// Load the TLS certificate and private key, returning the PEM-encoded certificate with the
+    /// identity created from both
+    pub async fn load_identity(&self) -> Result<(Zeroizing<Vec<u8>>, Identity), ApiInitError> {
+        let certificate = deimosproto::util::load_check_permissions(&self.certificate)
+            .await
+            .map(Zeroizing::new)
+            .map_err(|err| ApiInitError::LoadSensitiveFile(self.certificate.clone(), err))?;
+        let privkey = deimosproto::util::load_check_permissions(&self.privkey)
+            .await
+            .map(Zeroizing::new)
+            .map_err(|err| ApiInitError::LoadSensitiveFile(self.privkey.clone(), err))?;
+
+        let identity = Identity::from_pem(&certificate, privkey);
+        Ok((certificate, identity))
+    }
+
+    /// Get the ports that the public API and status page server listen on, which pods may not
+    /// publish
+    pub fn reserved_ports(&self) -> Vec<ReservedPort> {
+        let forwarded = match self.upnp {
+            true => self.bind.external_ports(),
+            false => Vec::new(),
+        };
+
+        let mut api = self.bind.iter().map(|addr| addr.port()).collect::<Vec<_>>();
+        api.sort_unstable();
+        api.dedup();
+
+        api
+            .into_iter()
+            .map(|port| ReservedPort {
+                port,
+                listener: ReservedPortListener::Api,
+                upnp: forwarded.contains(&port),
+            })
+            .chain(
+                self.status.as_ref().map(|status| ReservedPort {
+                    port: status.bind.port(),
+                    listener: ReservedPortListener::Status,
+                    upnp: false,
+                })
+            )
+            .collect()
+    }
+
     /// Get the default gRPC timeout, used to provide a value for `serde`'s automatic Deserialize
     /// implementation
     pub const fn default_timeout() -> Duration {
         Duration::from_secs(120)
     }
+
+    pub const fn default_drain_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    /// Get the default limit on exported log size
+    pub const fn default_log_export_limit() -> usize {
+        256 * 1024 * 1024
+    }
+
+    /// Get the default maximum gRPC message size
+    pub const fn default_max_message_size() -> usize {
+        MessageSizeLimit::DEFAULT.bytes()
+    }
+
+    /// Get the configured maximum gRPC message size, raised to the smallest allowed limit
+    pub const fn message_size_limit(&self) -> MessageSizeLimit {
+        MessageSizeLimit::new(self.max_message_size)
+    }
 }
@@ -0,0 +1,213 @@
+//! Optional PIN required for privileged operations on the internal API, with a lockout after
+//! repeated incorrect attempts.
+//!
+//! Every internal RPC that changes the daemon's state or reveals data beyond what a connected
+//! client could already see takes a PIN: approving, denying, and revoking tokens, pod sharing,
+//! config rollback, archiving, image pruning, environment overrides, reloading pods, updating pod
+//! state, setting the log level, and downloading logs. Log levels are gated because raising them
+//! can write request contents to the logs, and logs are gated because pods may write secrets to
+//! them. The remaining RPCs only list pending requests, tokens without their keys, config
+//! history, containers, memory use, pod and UPnP status, daemon info, and environment overrides
+//! with secret values removed, so they are left ungated and remain usable by scripts
+
+use std::time::{Duration, Instant};
+
+use deimosproto::pin::InternalPinHash;
+use zeroize::Zeroizing;
+
+use crate::server::Deimos;
+
+/// Count of incorrect PINs given since the last correct PIN or lockout
+#[derive(Debug, Default)]
+pub struct InternalPinAttempts {
+    /// Held while a PIN is verified so that concurrent guesses are counted one at a time
+    state: tokio::sync::Mutex<InternalPinAttemptState>,
+}
+
+#[derive(Debug, Default)]
+struct InternalPinAttemptState {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl InternalPinAttempts {
+    /// Number of incorrect PINs accepted before PIN-protected operations are locked
+    const MAX_FAILURES: u32 = 3;
+    /// Length of time that PIN-protected operations are locked for after too many failures
+    const LOCKOUT: Duration = Duration::from_secs(60);
+
+    /// Check the given PIN against the configured hash, recording the attempt and logging it if
+    /// it is rejected. The PIN itself is never logged
+    pub async fn check(&self, hash: &InternalPinHash, operation: &str, pin: &str, now: Instant) -> Result<(), InternalPinError> {
+        self
+            .verify(hash, pin, now)
+            .await
+            .inspect_err(|e| match e {
+                InternalPinError::Required => tracing::info!("Internal {} request did not include the required PIN", operation),
+                e => tracing::warn!("Rejected internal {} request: {}", operation, e),
+            })
+    }
+
+    /// Check the given PIN against the configured hash, recording the attempt. The hash is
+    /// verified on a blocking thread as it is deliberately expensive
+    async fn verify(&self, hash: &InternalPinHash, pin: &str, now: Instant) -> Result<(), InternalPinError> {
+        if pin.is_empty() {
+            return Err(InternalPinError::Required)
+        }
+
+        let mut state = self.state.lock().await;
+        if let Some(until) = state.locked_until {
+            match until.checked_duration_since(now) {
+                Some(remaining) if !remaining.is_zero() => return Err(InternalPinError::Locked { remaining }),
+                _ => state.locked_until = None,
+            }
+        }
+
+        let hash = hash.clone();
+        let pin = Zeroizing::new(pin.to_owned());
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&pin))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Failed to join internal PIN verification task: {}", e);
+                false
+            });
+
+        if valid {
+            state.failures = 0;
+            return Ok(())
+        }
+
+        state.failures += 1;
+        match state.failures >= Self::MAX_FAILURES {
+            true => {
+                state.failures = 0;
+                state.locked_until = Some(now + Self::LOCKOUT);
+                Err(InternalPinError::Locked { remaining: Self::LOCKOUT })
+            },
+            false => Err(InternalPinError::Invalid { remaining: Self::MAX_FAILURES - state.failures }),
+        }
+    }
+}
+
+impl Deimos {
+    /// Ensure that the given PIN allows the named operation, if an internal PIN is configured.
+    /// Rejected attempts are logged without the PIN that was given
+    pub(super) async fn check_pin(&self, operation: &str, pin: &str) -> Result<(), InternalPinError> {
+        let Some(ref hash) = self.api.config.internal_pin_hash else {
+            return Ok(())
+        };
+
+        self.api.pin.check(hash, operation, pin, Instant::now()).await
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InternalPinError {
+    #[error("This operation requires the internal PIN")]
+    Required,
+    #[error("Incorrect internal PIN, {} attempts remaining before lockout", remaining)]
+    Invalid {
+        remaining: u32,
+    },
+    #[error("Too many incorrect internal PINs, try again in {} seconds", remaining.as_secs().max(1))]
+    Locked {
+        remaining: Duration,
+    },
+}
+
+impl From<InternalPinError> for tonic::Status {
+    fn from(value: InternalPinError) -> Self {
+        let (code, error) = match value {
+            InternalPinError::Required => (tonic::Code::Unauthenticated, deimosproto::ErrorCode::PinRequired),
+            InternalPinError::Invalid { .. } => (tonic::Code::PermissionDenied, deimosproto::ErrorCode::PinInvalid),
+            InternalPinError::Locked { .. } => (tonic::Code::ResourceExhausted, deimosproto::ErrorCode::PinLocked),
+        };
+
+        deimosproto::ErrorDetail::new(error, value.to_string()).into_status(code)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::{Arc, Mutex}};
+
+    use super::*;
+
+    const PIN: &str = "correct-horse";
+
+    fn hash() -> InternalPinHash {
+        InternalPinHash::new(PIN, [3; InternalPinHash::SALT_LEN])
+    }
+
+    #[tokio::test]
+    async fn correct_pin_resets_failures() {
+        let (attempts, hash, now) = (InternalPinAttempts::default(), hash(), Instant::now());
+        assert!(matches!(attempts.verify(&hash, "", now).await, Err(InternalPinError::Required)));
+        assert!(matches!(attempts.verify(&hash, "wrong-guess", now).await, Err(InternalPinError::Invalid { remaining: 2 })));
+        assert!(matches!(attempts.verify(&hash, "wrong-guess", now).await, Err(InternalPinError::Invalid { remaining: 1 })));
+        assert!(attempts.verify(&hash, PIN, now).await.is_ok());
+        assert!(matches!(attempts.verify(&hash, "wrong-guess", now).await, Err(InternalPinError::Invalid { remaining: 2 })));
+    }
+
+    #[tokio::test]
+    async fn lockout_expires_after_its_duration() {
+        let (attempts, hash, now) = (InternalPinAttempts::default(), hash(), Instant::now());
+        for _ in 0..InternalPinAttempts::MAX_FAILURES - 1 {
+            assert!(matches!(attempts.verify(&hash, "wrong-guess", now).await, Err(InternalPinError::Invalid { .. })));
+        }
+
+        assert!(matches!(
+            attempts.verify(&hash, "wrong-guess", now).await,
+            Err(InternalPinError::Locked { remaining }) if remaining == InternalPinAttempts::LOCKOUT,
+        ));
+
+        let later = now + Duration::from_secs(45);
+        assert!(matches!(
+            attempts.verify(&hash, PIN, later).await,
+            Err(InternalPinError::Locked { remaining }) if remaining == Duration::from_secs(15),
+        ));
+
+        assert!(attempts.verify(&hash, PIN, now + InternalPinAttempts::LOCKOUT).await.is_ok());
+    }
+
+    /// Log output shared between the test and the subscriber's writer
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn rejections_are_logged_without_the_pin() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (attempts, hash, now) = (InternalPinAttempts::default(), hash(), Instant::now());
+        let _ = attempts.check(&hash, "revoke", "", now).await;
+        for _ in 0..InternalPinAttempts::MAX_FAILURES {
+            let _ = attempts.check(&hash, "revoke", "wrong-guess", now).await;
+        }
+        let _ = attempts.check(&hash, "revoke", PIN, now).await;
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Internal revoke request did not include the required PIN"));
+        assert!(logs.contains("Rejected internal revoke request: Incorrect internal PIN, 2 attempts remaining"));
+        assert!(logs.contains("Rejected internal revoke request: Too many incorrect internal PINs"));
+        assert!(!logs.contains("wrong-guess"));
+        assert!(!logs.contains(PIN));
+    }
+}
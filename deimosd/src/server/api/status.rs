@@ -0,0 +1,383 @@
+//! Public read-only status pages for pods that have been shared by an administrator, served over
+//! plain HTTP without authorization. An anonymous summary of the daemon's health may also be
+//! served for uptime monitors, containing only counts of pods
+
+use std::{collections::HashMap, future::IntoFuture, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant}};
+
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::{IntoResponse, Response}, routing::get, Json, Router};
+use chrono::Utc;
+use dashmap::DashMap;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use subtle::ConstantTimeEq;
+use tokio_util::sync::CancellationToken;
+
+use crate::{pod::{id::DeimosId, Pod, PodManager, PodState, PodStateKnown}, server::Deimos};
+
+/// Configuration for the HTTP server hosting shared pod status pages
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusServerConfig {
+    /// Address to bind the HTTP server to
+    pub bind: SocketAddr,
+    /// Maximum number of status requests accepted from a single IP address per minute
+    #[serde(default = "StatusServerConfig::default_rate_limit")]
+    pub rate_limit: u32,
+    /// Serve a summary of the daemon's health at `/healthz`, including whether Docker is
+    /// reachable and the number of pods that are enabled but no pod IDs or names
+    #[serde(default)]
+    pub expose_public_summary: bool,
+}
+
+/// Share slugs assigned to pods, and request counts used to rate limit status page access
+#[derive(Debug, Default)]
+pub struct PodSharing {
+    /// Map of pod IDs to the slug their status is shared under
+    slugs: DashMap<DeimosId, Arc<str>>,
+    /// Start of the current rate limiting window and the number of requests made in it by each address
+    requests: DashMap<IpAddr, (Instant, u32)>,
+}
+
+/// Persistent state for pod sharing
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct PodSharingPersistent {
+    /// Map of pod IDs to their share slugs
+    slugs: HashMap<String, String>,
+}
+
+/// Status of a shared pod as presented to anonymous viewers
+#[derive(Debug, serde::Serialize)]
+struct SharedPodStatus {
+    title: String,
+    /// One of `enabled`, `paused`, `disabled`, or `transit` while the pod's state is changing
+    state: &'static str,
+    /// Number of seconds the pod has been enabled for, if it is enabled
+    uptime: Option<i64>,
+}
+
+/// Health of the daemon as presented to anonymous monitors. Must never contain any information
+/// identifying a pod
+#[derive(Debug, serde::Serialize)]
+struct PublicSummary {
+    alive: bool,
+    /// Set if the Docker daemon responded to a ping
+    docker: bool,
+    /// Number of pods that are not archived
+    pods: usize,
+    /// Number of pods whose containers are running
+    enabled: usize,
+}
+
+impl PodSharing {
+    /// Number of characters in a generated slug, giving about 142 bits of entropy
+    const SLUG_LEN: usize = 24;
+    /// Length of the window that requests are counted in for rate limiting
+    const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+    /// Load share slugs from persistent state, discarding slugs for pods that no longer exist
+    pub fn load(persistent: PodSharingPersistent, pods: &PodManager) -> Self {
+        let slugs = persistent
+            .slugs
+            .into_iter()
+            .filter_map(|(id, slug)| match pods.get(&id) {
+                Some(pod) => Some((pod.id(), Arc::from(slug))),
+                None => {
+                    tracing::warn!("Discarding share link for unknown pod {}", id);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            slugs,
+            requests: DashMap::new(),
+        }
+    }
+
+    /// Get persistent state to be written to the save file
+    pub fn save(&self) -> PodSharingPersistent {
+        PodSharingPersistent {
+            slugs: self
+                .slugs
+                .iter()
+                .map(|entry| (entry.key().owned(), entry.value().to_string()))
+                .collect(),
+        }
+    }
+
+    /// Share the status of the given pod, generating a new slug if the pod is not already shared
+    /// or if `regenerate` is set
+    pub fn share(&self, id: DeimosId, regenerate: bool) -> Arc<str> {
+        let mut entry = self.slugs.entry(id).or_insert_with(Self::generate);
+        if regenerate {
+            *entry = Self::generate();
+        }
+
+        entry.clone()
+    }
+
+    /// Stop sharing the status of the given pod, returning `true` if it was shared
+    pub fn revoke(&self, id: &str) -> bool {
+        self.slugs.remove(id).is_some()
+    }
+
+    /// Get the ID of the pod shared with the given slug
+    fn lookup(&self, slug: &str) -> Option<DeimosId> {
+        self
+            .slugs
+            .iter()
+            .find(|entry| entry.value().as_bytes().ct_eq(slug.as_bytes()).into())
+            .map(|entry| entry.key().clone())
+    }
+
+    /// Record a request from the given address, returning `false` if it exceeds the rate limit
+    fn allow(&self, addr: IpAddr, limit: u32) -> bool {
+        self.allow_at(addr, limit, Instant::now())
+    }
+
+    /// Record a request from the given address made at the given time, starting a new window for
+    /// the address if its last one has ended
+    fn allow_at(&self, addr: IpAddr, limit: u32, now: Instant) -> bool {
+        let mut entry = self.requests.entry(addr).or_insert((now, 0));
+        let (start, count) = &mut *entry;
+        if now.duration_since(*start) >= Self::RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+
+        *count += 1;
+        *count <= limit
+    }
+
+    /// Forget the request counts of addresses whose window ended before the given time
+    fn expire(&self, now: Instant) {
+        self.requests.retain(|_, (start, _)| now.duration_since(*start) < Self::RATE_LIMIT_WINDOW);
+    }
+
+    /// Generate a new random slug
+    fn generate() -> Arc<str> {
+        OsRng
+            .sample_iter(Alphanumeric)
+            .take(Self::SLUG_LEN)
+            .map(char::from)
+            .collect::<String>()
+            .into()
+    }
+}
+
+impl Deimos {
+    /// Serve shared pod status pages until cancelled, if the status server is enabled
+    pub async fn status_task(self: Arc<Self>, cancel: CancellationToken) {
+        let Some(ref config) = self.api.config.status else {
+            return
+        };
+
+        let bind = config.bind;
+        let listener = match tokio::net::TcpListener::bind(bind).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Failed to bind status server to {}: {}", bind, e);
+                return
+            }
+        };
+
+        let mut router = Router::new().route("/s/:slug", get(shared_status));
+        if config.expose_public_summary {
+            tracing::info!("Serving public health summary on {}/healthz", bind);
+            router = router.route("/healthz", get(public_summary));
+        }
+
+        let router = router.with_state(self.clone());
+
+        tracing::info!("Serving shared pod status pages on {}", bind);
+
+        let serve = axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(cancel.cancelled_owned());
+
+        //Counts are reset by the next request from the same address, so only addresses that stop
+        //making requests need to be forgotten
+        let expire = async {
+            let mut interval = tokio::time::interval(PodSharing::RATE_LIMIT_WINDOW);
+            loop {
+                interval.tick().await;
+                self.api.sharing.expire(Instant::now());
+            }
+        };
+
+        tokio::select! {
+            result = serve.into_future() => if let Err(e) = result {
+                tracing::error!("Status server error: {}", e);
+            },
+            _ = expire => (),
+        }
+    }
+}
+
+/// Get the status of the pod shared with the given slug
+async fn shared_status(
+    State(deimos): State<Arc<Deimos>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(slug): Path<String>,
+) -> Response {
+    let limit = deimos
+        .api
+        .config
+        .status
+        .as_ref()
+        .map(|status| status.rate_limit)
+        .unwrap_or_default();
+
+    if !deimos.api.sharing.allow(addr.ip(), limit) {
+        return StatusCode::TOO_MANY_REQUESTS.into_response()
+    }
+
+    let Some(pod) = deimos.api.sharing.lookup(&slug).and_then(|id| deimos.pods.get(&id)) else {
+        return StatusCode::NOT_FOUND.into_response()
+    };
+
+    let (state, uptime) = shared_state(&pod);
+
+    Json(
+        SharedPodStatus {
+            title: pod.title().to_string(),
+            state,
+            uptime,
+        }
+    )
+    .into_response()
+}
+
+/// Get the state and uptime of a shared pod without waiting for a transaction, which may take
+/// minutes when stopping a pod - the pod is reported in transit instead
+fn shared_state(pod: &Pod) -> (&'static str, Option<i64>) {
+    match pod.state().try_read().as_deref() {
+        Some(PodStateKnown::Enabled(run)) => ("enabled", Some((Utc::now() - run.started).num_seconds())),
+        Some(PodStateKnown::Paused(..)) => ("paused", None),
+        Some(PodStateKnown::Disabled) => ("disabled", None),
+        None => ("transit", None),
+    }
+}
+
+/// Get an anonymous summary of the daemon's health, subject to the same rate limit as status pages
+async fn public_summary(
+    State(deimos): State<Arc<Deimos>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let limit = deimos
+        .api
+        .config
+        .status
+        .as_ref()
+        .map(|status| status.rate_limit)
+        .unwrap_or_default();
+
+    if !deimos.api.sharing.allow(addr.ip(), limit) {
+        return StatusCode::TOO_MANY_REQUESTS.into_response()
+    }
+
+    let (pods, enabled) = deimos
+        .pods
+        .pods()
+        .iter()
+        .filter(|pod| !pod.archived())
+        .fold((0, 0), |(pods, enabled), pod| {
+            (pods + 1, enabled + matches!(pod.state().current(), PodState::Enabled | PodState::Degraded) as usize)
+        });
+
+    let docker = deimos.pods.docker_reachable().await;
+    let status = match docker {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        status,
+        Json(
+            PublicSummary {
+                alive: true,
+                docker,
+                pods,
+                enabled,
+            }
+        )
+    )
+    .into_response()
+}
+
+impl StatusServerConfig {
+    /// Get the default number of requests allowed per address per minute
+    pub const fn default_rate_limit() -> u32 {
+        30
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn requests_are_limited_per_address() {
+        let sharing = PodSharing::default();
+        let now = Instant::now();
+        assert!(sharing.allow_at(A, 2, now));
+        assert!(sharing.allow_at(A, 2, now));
+        assert!(!sharing.allow_at(A, 2, now));
+        assert!(sharing.allow_at(B, 2, now));
+    }
+
+    #[test]
+    fn windows_end_lazily() {
+        let sharing = PodSharing::default();
+        let now = Instant::now();
+        assert!(sharing.allow_at(A, 1, now));
+        assert!(!sharing.allow_at(A, 1, now + PodSharing::RATE_LIMIT_WINDOW / 2));
+        //Requests refused during a window do not extend it
+        assert!(sharing.allow_at(A, 1, now + PodSharing::RATE_LIMIT_WINDOW));
+        assert!(!sharing.allow_at(A, 1, now + PodSharing::RATE_LIMIT_WINDOW));
+    }
+
+    #[test]
+    fn expire_forgets_idle_addresses() {
+        let sharing = PodSharing::default();
+        let now = Instant::now();
+        sharing.allow_at(A, 1, now);
+        sharing.allow_at(B, 1, now + PodSharing::RATE_LIMIT_WINDOW / 2);
+
+        sharing.expire(now + PodSharing::RATE_LIMIT_WINDOW);
+        assert!(!sharing.requests.contains_key(&A));
+        assert!(sharing.requests.contains_key(&B));
+    }
+
+    #[test]
+    fn slugs_are_kept_until_regenerated() {
+        let sharing = PodSharing::default();
+        let id = serde_json::from_value::<DeimosId>(serde_json::json!("pod")).unwrap();
+        let slug = sharing.share(id.clone(), false);
+        assert_eq!(slug.len(), PodSharing::SLUG_LEN);
+        assert_eq!(sharing.share(id.clone(), false), slug);
+        assert_eq!(sharing.lookup(&slug), Some(id.clone()));
+
+        let regenerated = sharing.share(id.clone(), true);
+        assert_ne!(regenerated, slug);
+        assert_eq!(sharing.lookup(&slug), None);
+
+        assert!(sharing.revoke("pod"));
+        assert!(!sharing.revoke("pod"));
+        assert_eq!(sharing.lookup(&regenerated), None);
+    }
+
+    #[tokio::test]
+    async fn transactions_do_not_block_status() {
+        let (_dir, pod) = Pod::test("id = \"pod\"\nname = \"Pod\"\n[docker]\nimage = \"alpine:3\"\n").await;
+        assert_eq!(shared_state(&pod), ("disabled", None));
+
+        let lock = pod.state().transact().await;
+        assert_eq!(shared_state(&pod), ("transit", None));
+        drop(lock);
+        assert_eq!(shared_state(&pod), ("disabled", None));
+    }
+}
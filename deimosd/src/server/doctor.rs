@@ -0,0 +1,324 @@
+//! Self-diagnostics run with `deimosd doctor`, probing each part of the deployment that the daemon
+//! depends on using the same checks that are made when the daemon starts. Every check reports
+//! whether it passed along with a remedy for any problem found, and the command fails if any
+//! check fails
+
+use std::{fmt, path::Path, process::ExitCode};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tonic::transport::{Server, ServerTlsConfig};
+
+use crate::pod::PodManager;
+
+use super::{lifecycle::DaemonLifecycle, save::SaveFile, storage::MountTable, upnp::Upnp, DeimosConfig};
+
+/// Outcome of a single diagnostic check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    /// Short name of the part of the deployment that was checked
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// Description of what was found
+    pub message: String,
+    /// Suggested fix for a failed check or a warning
+    pub remedy: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl CheckResult {
+    /// Time before the TLS certificate expires after which a warning is given
+    const CERTIFICATE_EXPIRY_WARNING: TimeDelta = TimeDelta::days(14);
+    /// Difference between the local clock and the clock of the Docker host that is tolerated
+    const CLOCK_SKEW_WARNING: TimeDelta = TimeDelta::seconds(30);
+
+    pub fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Pass, message: message.into(), remedy: None }
+    }
+
+    pub fn warn(name: &'static str, message: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Warn, message: message.into(), remedy: Some(remedy.into()) }
+    }
+
+    pub fn fail(name: &'static str, message: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Fail, message: message.into(), remedy: Some(remedy.into()) }
+    }
+}
+
+/// Run every check against the config file at the given path, printing each result as it
+/// completes. Checks that depend on the config are skipped if it cannot be loaded
+pub async fn run_doctor(config_path: &Path) -> ExitCode {
+    let mut results = Vec::new();
+    let mut report = |result: CheckResult| {
+        println!("{}", result);
+        results.push(result.status);
+    };
+
+    let config = match DeimosConfig::load(config_path).await {
+        Ok(config) => {
+            report(CheckResult::pass("config", format!("Parsed {}", config_path.display())));
+            config
+        },
+        Err(e) => {
+            report(CheckResult::fail("config", e.to_string(), "Fix the config file, or run deimosd doctor from the directory containing deimos.toml"));
+            println!("Skipping remaining checks as the config could not be loaded");
+            return ExitCode::FAILURE
+        }
+    };
+
+    let docker_time = match check_docker(&config).await {
+        Ok((result, time)) => {
+            report(result);
+            time
+        },
+        Err(result) => {
+            report(result);
+            None
+        }
+    };
+
+    check_pods(&config).await.into_iter().for_each(&mut report);
+    report(check_tls(&config).await);
+    check_save_path(&config).into_iter().for_each(&mut report);
+    report(check_upnp(&config).await);
+    report(check_bind(&config));
+    report(check_clock(&config, docker_time));
+
+    let count = |status| results.iter().filter(|result| **result == status).count();
+    println!(
+        "{} passed, {} warnings, {} failed",
+        count(CheckStatus::Pass),
+        count(CheckStatus::Warn),
+        count(CheckStatus::Fail),
+    );
+
+    match results.contains(&CheckStatus::Fail) {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+/// Connect to Docker as the pod manager does, returning the time reported by the Docker host
+async fn check_docker(config: &DeimosConfig) -> Result<(CheckResult, Option<DateTime<Utc>>), CheckResult> {
+    const NAME: &str = "docker";
+    const REMEDY: &str = "Check that Docker is running and that this user may access its socket, e.g. by adding it to the docker group";
+
+    let docker = PodManager::connect_docker(&config.pod)
+        .await
+        .map_err(|e| CheckResult::fail(NAME, e.to_string(), REMEDY))?;
+
+    let version = docker
+        .version()
+        .await
+        .map_err(|e| CheckResult::fail(NAME, format!("Failed to get Docker version: {}", e), REMEDY))?;
+
+    let time = match docker.info().await {
+        Ok(info) => info
+            .system_time
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .map(|time| time.to_utc()),
+        Err(_) => None,
+    };
+
+    let result = CheckResult::pass(
+        NAME,
+        format!(
+            "Connected to Docker {} using API version {}",
+            version.version.as_deref().unwrap_or("(unknown version)"),
+            docker.client_version(),
+        ),
+    );
+
+    Ok((result, time))
+}
+
+/// Load every pod from the pod directory, reporting each pod that cannot be loaded
+async fn check_pods(config: &DeimosConfig) -> Vec<CheckResult> {
+    const NAME: &str = "pods";
+
+    let dir = &config.pod.containerdir;
+    match PodManager::load_containers(dir, config.pod.config_history).await {
+        Ok((pods, failures)) => {
+            let mut results = failures
+                .into_iter()
+                .map(|failure| CheckResult::warn(
+                    NAME,
+                    format!("Failed to load pod from {}: {}", failure.path.display(), failure.message),
+                    "Fix the pod's config file, the pod is ignored until it can be loaded",
+                ))
+                .collect::<Vec<_>>();
+
+            results.insert(0, match pods.is_empty() {
+                true => CheckResult::warn(
+                    NAME,
+                    format!("No pods were loaded from {}", dir.display()),
+                    "Create a directory containing a docker.toml file for each pod",
+                ),
+                false => CheckResult::pass(NAME, format!("Loaded {} pods from {}", pods.len(), dir.display())),
+            });
+
+            results
+        },
+        Err(e) => vec![CheckResult::fail(NAME, e.to_string(), "Create the containerdir directory or fix its permissions")],
+    }
+}
+
+/// Load the TLS identity as the public API does, and check that the certificate is currently
+/// valid and not about to expire
+async fn check_tls(config: &DeimosConfig) -> CheckResult {
+    const NAME: &str = "tls";
+
+    let (certificate, identity) = match config.api.load_identity().await {
+        Ok(loaded) => loaded,
+        Err(e) => return CheckResult::fail(NAME, e.to_string(), "Check the certificate and privkey paths and their permissions"),
+    };
+
+    if let Err(e) = Server::builder().tls_config(ServerTlsConfig::new().identity(identity)) {
+        return CheckResult::fail(NAME, format!("Invalid certificate or private key: {}", e), "Regenerate the certificate and private key as a PEM-encoded pair")
+    }
+
+    let validity = x509_parser::pem::parse_x509_pem(&certificate)
+        .map_err(|e| e.to_string())
+        .and_then(|(_, pem)| {
+            pem
+                .parse_x509()
+                .map(|cert| (cert.validity().not_before.timestamp(), cert.validity().not_after.timestamp()))
+                .map_err(|e| e.to_string())
+        });
+
+    let (not_before, not_after) = match validity {
+        Ok((not_before, not_after)) => (
+            DateTime::from_timestamp(not_before, 0).unwrap_or_default(),
+            DateTime::from_timestamp(not_after, 0).unwrap_or_default(),
+        ),
+        Err(e) => return CheckResult::fail(NAME, format!("Failed to parse certificate {}: {}", config.api.certificate.display(), e), "Regenerate the certificate in PEM format"),
+    };
+
+    let now = Utc::now();
+    let expiry = not_after.format("%Y-%m-%d");
+    if now < not_before {
+        CheckResult::fail(NAME, format!("Certificate is not valid until {}", not_before), "Check the system clock, or regenerate the certificate")
+    } else if now >= not_after {
+        CheckResult::fail(NAME, format!("Certificate expired on {}", expiry), "Regenerate the certificate, clients reject expired certificates")
+    } else if not_after - now < CheckResult::CERTIFICATE_EXPIRY_WARNING {
+        CheckResult::warn(NAME, format!("Certificate expires on {}", expiry), "Regenerate the certificate before it expires")
+    } else {
+        CheckResult::pass(NAME, format!("Certificate and private key are valid until {}", expiry))
+    }
+}
+
+/// Check that the save file's filesystem is writable and persistent as the daemon does at startup,
+/// then create a file next to the save file
+fn check_save_path(config: &DeimosConfig) -> Vec<CheckResult> {
+    const NAME: &str = "save";
+
+    let mut results = Vec::new();
+    if let Some(mounts) = MountTable::load() {
+        if let Err(e) = config.check_save_mount(&mounts) {
+            return vec![CheckResult::fail(NAME, e.to_string(), "Move save_path to a writable filesystem")]
+        }
+
+        results.extend(
+            config
+                .volatile_storage(&mounts)
+                .into_iter()
+                .map(|(name, path, mount)| CheckResult::warn(
+                    NAME,
+                    format!("{} {} is stored on volatile filesystem {}", name, path.display(), mount),
+                    format!("Move {} to a persistent filesystem, it will be lost when the host is rebooted", name),
+                ))
+        );
+    }
+
+    results.insert(0, match SaveFile::check_writable(&config.save_path) {
+        Ok(()) => CheckResult::pass(NAME, format!("Save file {} is writable", config.save_path.display())),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("Cannot write to the directory of save file {}: {}", config.save_path.display(), e),
+            "Create the save file's directory and allow the daemon's user to write to it",
+        ),
+    });
+
+    results
+}
+
+/// Search for the gateway that UPnP leases are requested from
+async fn check_upnp(config: &DeimosConfig) -> CheckResult {
+    const NAME: &str = "upnp";
+
+    let remedy = match config.api.upnp {
+        true => "Enable UPnP on the router, or disable api.upnp and forward the API's ports manually",
+        false => "Enable UPnP on the router if any pod forwards ports with UPnP, otherwise this can be ignored",
+    };
+
+    match Upnp::search_gateway().await {
+        Ok(gateway) => CheckResult::pass(NAME, format!("Found gateway at {}", gateway.addr)),
+        Err(igd_next::SearchError::NoResponseWithinTimeout) => CheckResult::warn(NAME, "No UPnP gateway responded within the timeout", remedy),
+        Err(e) => CheckResult::warn(NAME, format!("Failed to search for a UPnP gateway: {}", e), remedy),
+    }
+}
+
+/// Bind the public API's addresses as the daemon does at startup, releasing them immediately
+fn check_bind(config: &DeimosConfig) -> CheckResult {
+    const NAME: &str = "bind";
+
+    match config.api.bind.listen() {
+        Ok(_) => CheckResult::pass(NAME, format!("Public API can be bound to {}", config.api.bind)),
+        Err(e) if e.err.kind() == std::io::ErrorKind::AddrInUse => CheckResult::warn(
+            NAME,
+            e.to_string(),
+            "Expected if deimosd is already running, otherwise stop the process using the port or change api.bind",
+        ),
+        Err(e) => CheckResult::fail(NAME, e.to_string(), "Change api.bind to an address of this host, ports below 1024 need extra privileges"),
+    }
+}
+
+/// Check that the clock has not moved behind the last heartbeat of the daemon, and that it agrees
+/// with the clock of the Docker host
+fn check_clock(config: &DeimosConfig, docker_time: Option<DateTime<Utc>>) -> CheckResult {
+    const NAME: &str = "clock";
+    const REMEDY: &str = "Enable time synchronization, e.g. with `timedatectl set-ntp true`";
+
+    let now = Utc::now();
+    if let Some(heartbeat) = DaemonLifecycle::last_heartbeat(&config.save_path).filter(|heartbeat| *heartbeat > now) {
+        return CheckResult::fail(
+            NAME,
+            format!("System clock reads {}, before the daemon's last heartbeat at {}", now.format("%Y-%m-%d %H:%M:%S"), heartbeat.format("%Y-%m-%d %H:%M:%S")),
+            REMEDY,
+        )
+    }
+
+    match docker_time {
+        Some(docker) if (docker - now).abs() > CheckResult::CLOCK_SKEW_WARNING => CheckResult::warn(
+            NAME,
+            format!("System clock differs from the Docker host's clock by {} seconds", (docker - now).num_seconds().abs()),
+            REMEDY,
+        ),
+        _ => CheckResult::pass(NAME, format!("System clock reads {}", now.format("%Y-%m-%d %H:%M:%S UTC"))),
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:<8} {}", self.status, self.name, self.message)?;
+        match self.remedy {
+            Some(ref remedy) => write!(f, "\n{:>16}{}", "-> ", remedy),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        })
+    }
+}
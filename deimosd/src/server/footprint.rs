@@ -0,0 +1,94 @@
+//! Profiles of default settings tuned for the host that the daemon runs on. A profile only
+//! supplies values for settings that the config file does not give, so that explicit settings
+//! always take precedence over the profile
+
+use std::fmt;
+
+/// Profile selected with the top-level `footprint` key of the config file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum DeimosFootprint {
+    /// Defaults of every setting
+    #[default]
+    #[serde(rename = "normal")]
+    Normal,
+    /// Smaller buffers, less frequent background work, and less verbose logging for hosts with
+    /// little memory such as single board computers
+    #[serde(rename = "small")]
+    Small,
+}
+
+impl DeimosFootprint {
+    /// Key of the config file that selects a profile
+    const KEY: &'static str = "footprint";
+
+    /// Get the settings given by this profile, as the path of keys to the setting in the config
+    /// file and its value
+    fn settings(self) -> Vec<(&'static [&'static str], toml::Value)> {
+        match self {
+            Self::Normal => Vec::new(),
+            Self::Small => vec![
+                (&["pod", "event_capacity"], toml::Value::Integer(16)),
+                (&["pod", "usage_sampling"], toml::Value::Boolean(false)),
+                (&["upnp", "ip_lookup_seconds"], toml::Value::Integer(60)),
+                (&["upnp", "renewal_seconds"], toml::Value::Integer(60 * 60)),
+                (&["upnp", "retry_seconds"], toml::Value::Integer(2 * 60)),
+                (&["log", "targets", "deimosd"], toml::Value::String(String::from("info"))),
+                (&["log", "targets", "deimosproto"], toml::Value::String(String::from("info"))),
+            ],
+        }
+    }
+
+    /// Insert the settings of the profile selected by the given config file wherever the file
+    /// does not give a value, logging each value that the profile sets.
+    /// An invalid profile name is left to be reported when the config file is parsed
+    pub fn apply(config: &mut toml::Table) {
+        let footprint = match config.get(Self::KEY).cloned().map(toml::Value::try_into::<Self>) {
+            Some(Ok(footprint)) => footprint,
+            Some(Err(_)) => return,
+            None => Self::default(),
+        };
+
+        for (path, value) in footprint.settings() {
+            let shown = value.to_string();
+            if insert_default(config, path, value) {
+                tracing::info!("Footprint '{}' sets {} = {}", footprint, path.join("."), shown);
+            }
+        }
+    }
+}
+
+impl fmt::Display for DeimosFootprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            match self {
+                Self::Normal => "normal",
+                Self::Small => "small",
+            }
+        )
+    }
+}
+
+/// Insert the given value at the path of keys if the config does not give one, creating tables
+/// along the path. Returns `false` if a value was already given or a key along the path does not
+/// hold a table
+fn insert_default(config: &mut toml::Table, path: &[&str], value: toml::Value) -> bool {
+    let Some((key, tables)) = path.split_last() else {
+        return false
+    };
+
+    let mut table = config;
+    for name in tables {
+        match table.entry(*name).or_insert_with(|| toml::Value::Table(toml::Table::new())) {
+            toml::Value::Table(inner) => table = inner,
+            _ => return false,
+        }
+    }
+
+    match table.contains_key(*key) {
+        true => false,
+        false => {
+            table.insert(String::from(*key), value);
+            true
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Tracking of daemon restarts using a heartbeat file written alongside the save file, used to
+//! classify how the previous run of the daemon was terminated
+
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio_util::sync::CancellationToken;
+
+use super::Deimos;
+
+/// Start time of the current daemon run and the classification of the previous run's termination
+#[derive(Debug)]
+pub struct DaemonLifecycle {
+    /// Path to the heartbeat file, kept separate from the save file so that it is cheap to write
+    path: PathBuf,
+    /// Time that the current run of the daemon was started at
+    started: DateTime<Utc>,
+    /// Random ID of the current run, sent with status notifications so that clients can tell
+    /// when the daemon has restarted
+    epoch: u64,
+    /// How the previous run of the daemon was terminated
+    previous: DaemonTermination,
+}
+
+/// Classification of how the previous run of the daemon ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonTermination {
+    /// No heartbeat file was found, the daemon has not been run before
+    FirstStart,
+    /// The daemon was shut down after receiving a signal
+    Clean,
+    /// The daemon stopped sending heartbeats without shutting down
+    Crash,
+    /// The host was rebooted without the daemon shutting down
+    HostReboot,
+}
+
+/// Contents of the heartbeat file
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Heartbeat {
+    /// Last time the daemon was known to be running
+    at: DateTime<Utc>,
+    /// Set when the daemon shuts down cleanly
+    #[serde(default)]
+    clean: bool,
+}
+
+impl DaemonLifecycle {
+    /// Interval to update the heartbeat file at
+    pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Read the heartbeat file stored next to the given save file and classify the termination of
+    /// the previous run
+    pub fn load(save_path: &Path) -> Self {
+        let path = save_path.with_extension("heartbeat");
+        let started = Utc::now();
+
+        let previous = match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Heartbeat>(&bytes) {
+                Ok(heartbeat) => Self::classify(&heartbeat, boot_time()),
+                Err(e) => {
+                    tracing::warn!("Failed to parse heartbeat file {}: {}", path.display(), e);
+                    DaemonTermination::Crash
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => DaemonTermination::FirstStart,
+            Err(e) => {
+                tracing::warn!("Failed to read heartbeat file {}: {}", path.display(), e);
+                DaemonTermination::Crash
+            }
+        };
+
+        Self {
+            path,
+            started,
+            epoch: rand::random(),
+            previous,
+        }
+    }
+
+    /// Read the time of the last heartbeat written next to the given save file, if any
+    pub fn last_heartbeat(save_path: &Path) -> Option<DateTime<Utc>> {
+        let bytes = std::fs::read(save_path.with_extension("heartbeat")).ok()?;
+        serde_json::from_slice::<Heartbeat>(&bytes).ok().map(|heartbeat| heartbeat.at)
+    }
+
+    /// Time that the current run of the daemon was started at
+    pub const fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    /// Random ID of the current run of the daemon
+    pub const fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// How the previous run of the daemon was terminated
+    pub const fn previous(&self) -> DaemonTermination {
+        self.previous
+    }
+
+    /// Record that the daemon is still running
+    pub async fn beat(&self) -> Result<(), std::io::Error> {
+        tokio::fs::write(&self.path, Self::serialize(false)).await
+    }
+
+    /// Mark the current run as cleanly shut down
+    pub fn shutdown(&self) -> Result<(), std::io::Error> {
+        std::fs::write(&self.path, Self::serialize(true))
+    }
+
+    fn serialize(clean: bool) -> Vec<u8> {
+        serde_json::to_vec(&Heartbeat { at: Utc::now(), clean }).unwrap_or_default()
+    }
+
+    /// Determine how the run that wrote the given heartbeat was terminated, using the boot time of
+    /// the host if it is available
+    fn classify(heartbeat: &Heartbeat, boot: Option<DateTime<Utc>>) -> DaemonTermination {
+        if heartbeat.clean {
+            DaemonTermination::Clean
+        } else if boot.is_some_and(|boot| boot > heartbeat.at) {
+            DaemonTermination::HostReboot
+        } else {
+            DaemonTermination::Crash
+        }
+    }
+}
+
+impl DaemonTermination {
+    /// Check if the previous run ended without the daemon shutting down
+    pub const fn unexpected(&self) -> bool {
+        matches!(self, Self::Crash | Self::HostReboot)
+    }
+}
+
+impl std::fmt::Display for DaemonTermination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::FirstStart => "first start",
+            Self::Clean => "clean shutdown",
+            Self::Crash => "crash",
+            Self::HostReboot => "host reboot",
+        })
+    }
+}
+
+impl From<DaemonTermination> for deimosproto::DaemonTermination {
+    fn from(value: DaemonTermination) -> Self {
+        match value {
+            DaemonTermination::FirstStart => Self::FirstStart,
+            DaemonTermination::Clean => Self::Clean,
+            DaemonTermination::Crash => Self::Crash,
+            DaemonTermination::HostReboot => Self::HostReboot,
+        }
+    }
+}
+
+impl Deimos {
+    /// Periodically write the heartbeat file until cancelled
+    pub async fn heartbeat_task(self: Arc<Self>, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(DaemonLifecycle::HEARTBEAT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            if let Err(e) = self.lifecycle.beat().await {
+                tracing::warn!("Failed to write heartbeat file {}: {}", self.lifecycle.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Get the time that the host was last booted at
+#[cfg(target_os = "linux")]
+fn boot_time() -> Option<DateTime<Utc>> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let secs = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+
+    DateTime::from_timestamp(secs, 0)
+}
+
+/// Get the time that the host was last booted at
+#[cfg(not(target_os = "linux"))]
+fn boot_time() -> Option<DateTime<Utc>> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn classify_heartbeats() {
+        let heartbeat = |clean| Heartbeat { at: at(1000), clean };
+
+        assert_eq!(DaemonLifecycle::classify(&heartbeat(true), None), DaemonTermination::Clean);
+        //A clean shutdown followed by a reboot is still clean
+        assert_eq!(DaemonLifecycle::classify(&heartbeat(true), Some(at(2000))), DaemonTermination::Clean);
+        assert_eq!(DaemonLifecycle::classify(&heartbeat(false), Some(at(2000))), DaemonTermination::HostReboot);
+        assert_eq!(DaemonLifecycle::classify(&heartbeat(false), Some(at(500))), DaemonTermination::Crash);
+        assert_eq!(DaemonLifecycle::classify(&heartbeat(false), None), DaemonTermination::Crash);
+    }
+
+    #[test]
+    fn unexpected_terminations() {
+        assert!(!DaemonTermination::FirstStart.unexpected());
+        assert!(!DaemonTermination::Clean.unexpected());
+        assert!(DaemonTermination::Crash.unexpected());
+        assert!(DaemonTermination::HostReboot.unexpected());
+    }
+
+    #[tokio::test]
+    async fn load_previous_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let save = dir.path().join("deimos.json");
+
+        let first = DaemonLifecycle::load(&save);
+        assert_eq!(first.previous(), DaemonTermination::FirstStart);
+        assert_eq!(DaemonLifecycle::last_heartbeat(&save), None);
+
+        //The daemon was killed after writing a heartbeat
+        first.beat().await.unwrap();
+        let second = DaemonLifecycle::load(&save);
+        assert_eq!(second.previous(), DaemonTermination::Crash);
+        assert!(DaemonLifecycle::last_heartbeat(&save).is_some_and(|heartbeat| heartbeat >= first.started()));
+        assert_ne!(second.epoch(), first.epoch());
+
+        second.shutdown().unwrap();
+        assert_eq!(DaemonLifecycle::load(&save).previous(), DaemonTermination::Clean);
+    }
+
+    #[test]
+    fn unreadable_heartbeat_is_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let save = dir.path().join("deimos.json");
+        std::fs::write(save.with_extension("heartbeat"), "not json").unwrap();
+
+        assert_eq!(DaemonLifecycle::load(&save).previous(), DaemonTermination::Crash);
+        assert_eq!(DaemonLifecycle::last_heartbeat(&save), None);
+    }
+}
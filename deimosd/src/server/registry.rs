@@ -0,0 +1,252 @@
+//! Service registry file listing the HTTP services of enabled pods, written for reverse proxies
+//! that watch a file for their routes. The file is replaced atomically after pods change state,
+//! waiting for bursts of changes to settle so that enabling many pods writes the file once
+
+use std::{collections::BTreeMap, net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::pod::{Pod, PodState};
+
+use super::Deimos;
+
+/// User-provided configuration of the service registry file
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Path to write the registry file to
+    pub path: PathBuf,
+    /// Format of the registry file
+    #[serde(default)]
+    pub format: RegistryFormat,
+    /// Time to wait in milliseconds for pod state changes to stop before writing the file
+    #[serde(default = "RegistryConfig::default_debounce_ms")]
+    pub debounce_ms: u64,
+}
+
+/// Format of the service registry file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum RegistryFormat {
+    /// JSON object with a list of services
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// TOML dynamic configuration read by Traefik's file provider
+    #[serde(rename = "traefik")]
+    Traefik,
+}
+
+/// HTTP service of an enabled pod
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RegistryService {
+    pub pod: String,
+    pub title: String,
+    /// Host name that requests should be routed to the pod for
+    pub host: String,
+    /// URL that the pod's service can be reached at
+    pub url: String,
+}
+
+/// Contents of a registry file in the JSON format
+#[derive(Debug, serde::Serialize)]
+struct RegistryJson<'a> {
+    services: &'a [RegistryService],
+}
+
+/// Contents of a registry file in Traefik's dynamic configuration format
+#[derive(Debug, serde::Serialize)]
+struct TraefikDynamic {
+    http: TraefikHttp,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TraefikHttp {
+    routers: BTreeMap<String, TraefikRouter>,
+    services: BTreeMap<String, TraefikService>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TraefikRouter {
+    rule: String,
+    service: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraefikService {
+    load_balancer: TraefikLoadBalancer,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TraefikLoadBalancer {
+    servers: Vec<TraefikServer>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TraefikServer {
+    url: String,
+}
+
+impl RegistryConfig {
+    /// Helper function for serde deserializer defaults
+    pub const fn default_debounce_ms() -> u64 {
+        500
+    }
+}
+
+impl RegistryService {
+    /// Get the HTTP service of the given pod if it configures one and is enabled. The service is
+    /// reached through the host port it is published on, or at the container's address if the
+    /// port is not published
+    fn of(pod: &Pod) -> Option<Self> {
+        if !matches!(pod.state().current(), PodState::Enabled | PodState::Degraded) {
+            return None
+        }
+
+        let config = pod.config();
+        let http = config.docker.http.as_ref()?;
+        let network = pod.network()?;
+        let addr = match network.ports.iter().find(|published| published.container_port == http.port) {
+            Some(published) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), published.host_port),
+            None => SocketAddr::new(network.container_ip?, http.port),
+        };
+
+        Some(Self {
+            pod: pod.id().owned(),
+            title: pod.title().to_string(),
+            host: http.host.clone(),
+            url: format!("http://{}", addr),
+        })
+    }
+}
+
+impl RegistryFormat {
+    /// Prefix of the router and service names given to pods in Traefik's configuration
+    const TRAEFIK_PREFIX: &str = "deimos-";
+
+    /// Render a registry file listing the given services
+    fn render(&self, services: &[RegistryService]) -> Result<String, RegistryWriteError> {
+        match self {
+            Self::Json => Ok(serde_json::to_string_pretty(&RegistryJson { services })?),
+            Self::Traefik => {
+                let mut http = TraefikHttp { routers: BTreeMap::new(), services: BTreeMap::new() };
+                for service in services {
+                    let name = format!("{}{}", Self::TRAEFIK_PREFIX, service.pod);
+                    http.routers.insert(
+                        name.clone(),
+                        TraefikRouter { rule: format!("Host(`{}`)", service.host), service: name.clone() },
+                    );
+                    http.services.insert(
+                        name,
+                        TraefikService {
+                            load_balancer: TraefikLoadBalancer { servers: vec![TraefikServer { url: service.url.clone() }] },
+                        },
+                    );
+                }
+
+                Ok(toml::to_string(&TraefikDynamic { http })?)
+            }
+        }
+    }
+}
+
+impl Deimos {
+    /// Write the service registry file if one is configured, then rewrite it whenever the listed
+    /// services change. The file is emptied when the daemon shuts down as all pods are disabled
+    pub async fn registry_task(self: Arc<Self>, cancel: CancellationToken) {
+        let Some(ref config) = self.registry else {
+            return
+        };
+
+        let debounce = Duration::from_millis(config.debounce_ms);
+        let mut written = self.write_registry(config, None).await;
+        let mut states = self.pods.stream();
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = self.pods.reloaded() => states = self.pods.stream(),
+                Some(_) = states.next() => {},
+            };
+
+            //Wait for the burst of changes to settle before writing
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = self.pods.reloaded() => states = self.pods.stream(),
+                    Some(_) = states.next() => continue,
+                    _ = tokio::time::sleep(debounce) => break,
+                };
+            }
+
+            if cancel.is_cancelled() {
+                break
+            }
+
+            written = self.write_registry(config, written).await;
+        }
+
+        if let Err(e) = write_atomic(config, &[]).await {
+            tracing::error!("Failed to clear service registry {}: {}", config.path.display(), e);
+        }
+    }
+
+    /// Write the services of all enabled pods to the registry file if they differ from the
+    /// services last written, returning the services that the file now lists
+    async fn write_registry(&self, config: &RegistryConfig, written: Option<Vec<RegistryService>>) -> Option<Vec<RegistryService>> {
+        let mut services = self
+            .pods
+            .pods()
+            .iter()
+            .filter_map(|pod| RegistryService::of(pod))
+            .collect::<Vec<_>>();
+        services.sort_unstable_by(|a, b| a.pod.cmp(&b.pod));
+
+        if written.as_ref() == Some(&services) {
+            return written
+        }
+
+        match write_atomic(config, &services).await {
+            Ok(()) => {
+                tracing::debug!("Wrote {} services to registry {}", services.len(), config.path.display());
+                Some(services)
+            },
+            Err(e) => {
+                tracing::error!("Failed to write service registry {}: {}", config.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Write the registry file to a temporary file next to it, replacing the registry file only once
+/// it has been written completely so that proxies never read a partial file
+async fn write_atomic(config: &RegistryConfig, services: &[RegistryService]) -> Result<(), RegistryWriteError> {
+    let contents = config.format.render(services)?;
+    let tmp = temporary_path(&config.path);
+    tokio::fs::write(&tmp, contents)
+        .await
+        .map_err(|err| RegistryWriteError::Write { path: tmp.clone(), err })?;
+
+    tokio::fs::rename(&tmp, &config.path)
+        .await
+        .map_err(|err| RegistryWriteError::Write { path: config.path.clone(), err })
+}
+
+/// Get the path of the temporary file written before replacing the file at the given path
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryWriteError {
+    #[error("Failed to write {}: {}", path.display(), err)]
+    Write { path: PathBuf, err: std::io::Error },
+    #[error("Failed to serialize JSON registry: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Failed to serialize Traefik registry: {0}")]
+    Traefik(#[from] toml::ser::Error),
+}
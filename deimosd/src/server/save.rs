@@ -0,0 +1,156 @@
+//! Writing of the save file holding the daemon's persistent state. Writes that fail are retried
+//! with a backoff, and the most recently serialized state is kept until it has been written so
+//! that a later save can still write it if the current state cannot be serialized
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use rand::Rng;
+
+use super::{Deimos, DeimosPersistent, DeimosRunError};
+
+/// Save file of the daemon and the state waiting to be written to it
+#[derive(Debug)]
+pub struct SaveFile {
+    path: PathBuf,
+    /// Serialized state that has not yet been written, held while a save is in progress so that
+    /// only one save writes the file at a time
+    pending: tokio::sync::Mutex<Option<Vec<u8>>>,
+}
+
+impl SaveFile {
+    /// Maximum number of attempts made to write the save file for each save
+    const ATTEMPTS: u32 = 4;
+    /// Time waited before the first retry, doubled for each following retry
+    const BACKOFF: Duration = Duration::from_millis(200);
+
+    /// Create a save file that writes to the given path
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            pending: Default::default(),
+        }
+    }
+
+    /// Check that a file can be created in the directory of the given save file, without changing
+    /// the save file or the temporary file used to replace it
+    pub fn check_writable(path: &Path) -> Result<(), std::io::Error> {
+        let probe = path.with_extension("probe");
+        std::fs::write(&probe, b"")?;
+        std::fs::remove_file(&probe)
+    }
+
+    /// Check if a previous save failed to write its state
+    pub fn unsaved(&self) -> bool {
+        self.pending.try_lock().map(|pending| pending.is_some()).unwrap_or(false)
+    }
+
+    /// Serialize and write the given state, replacing the previous save file only once the new
+    /// state has been written completely. If the state cannot be serialized, state serialized by
+    /// an earlier failed save is written instead
+    pub async fn write(&self, persistent: &DeimosPersistent) -> Result<(), DeimosRunError> {
+        let mut pending = self.pending.lock().await;
+        let state = match serde_json::to_vec(persistent) {
+            Ok(state) => pending.insert(state),
+            Err(e) => match pending.as_mut() {
+                Some(state) => {
+                    tracing::error!("Failed to serialize persistent state, writing the last serialized state instead: {}", e);
+                    state
+                },
+                None => return Err(e.into()),
+            },
+        };
+
+        let mut attempt = 1;
+        loop {
+            let err = match write_atomic(&self.path, state).await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+
+            //Retrying cannot free space, the host needs attention before state is lost
+            if err.kind() == std::io::ErrorKind::StorageFull {
+                tracing::error!(
+                    "No space left to write save file {} - persistent state will be lost when the daemon stops unless space is freed",
+                    self.path.display(),
+                );
+                return Err(DeimosRunError::SavePersistent { path: self.path.clone(), err })
+            }
+
+            if attempt >= Self::ATTEMPTS {
+                return Err(DeimosRunError::SavePersistent { path: self.path.clone(), err })
+            }
+
+            let backoff = Self::BACKOFF * 2u32.pow(attempt - 1);
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..=backoff / 2);
+            tracing::warn!(
+                "Failed to write save file {} (attempt {}/{}), retrying in {:?}: {}",
+                self.path.display(),
+                attempt,
+                Self::ATTEMPTS,
+                backoff + jitter,
+                err,
+            );
+
+            tokio::time::sleep(backoff + jitter).await;
+            attempt += 1;
+        }
+
+        *pending = None;
+        Ok(())
+    }
+}
+
+impl Deimos {
+    /// Write all persistent state to the save file
+    pub async fn save(&self) -> Result<(), DeimosRunError> {
+        let persistent = DeimosPersistent {
+            api: self.api.save(),
+            pods: self.pods.persistent(),
+        };
+
+        self.save_file.write(&persistent).await
+    }
+}
+
+/// Write the given contents to a temporary file next to the given path, then replace the file at
+/// the path with it. Both run on the blocking thread pool, as a slow disk would otherwise stall
+/// the runtime while the save file is locked
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_writes_are_retried_then_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("save.json");
+        let save = SaveFile::new(path.clone());
+
+        let start = tokio::time::Instant::now();
+        let err = save.write(&DeimosPersistent::default()).await.unwrap_err();
+        assert!(matches!(err, DeimosRunError::SavePersistent { .. }));
+        assert!(start.elapsed() >= SaveFile::BACKOFF * 7, "retried after {:?}", start.elapsed());
+        assert!(save.unsaved());
+
+        //The next save writes the file once it can be created
+        std::fs::create_dir(path.parent().unwrap()).unwrap();
+        save.write(&DeimosPersistent::default()).await.unwrap();
+        assert!(!save.unsaved());
+        serde_json::from_slice::<DeimosPersistent>(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn writable_check_leaves_no_files() {
+        let dir = tempfile::tempdir().unwrap();
+        SaveFile::check_writable(&dir.path().join("save.json")).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        assert!(SaveFile::check_writable(&dir.path().join("missing").join("save.json")).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Detection of volatile or read-only filesystems backing the directories that the daemon
+//! persists state to, which would otherwise only be discovered after data is lost
+
+use std::{fmt, path::{Path, PathBuf}};
+
+use super::{DeimosConfig, DeimosRunError};
+
+/// A single entry of the host's mount table
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// Path that the filesystem is mounted at
+    pub point: PathBuf,
+    /// Filesystem type, e.g. `ext4` or `tmpfs`
+    pub fstype: String,
+    /// Device or other source of the mounted filesystem
+    pub source: String,
+    /// Set if either the mount or the filesystem itself is read-only
+    pub read_only: bool,
+    /// Upper directory of an overlay filesystem that all writes are stored in
+    upperdir: Option<PathBuf>,
+}
+
+/// Mounted filesystems of the host, as seen by the daemon's mount namespace
+#[derive(Debug, Default)]
+pub struct MountTable {
+    mounts: Vec<MountInfo>,
+}
+
+impl MountTable {
+    /// Filesystem types whose contents are kept only in memory and lost on reboot
+    const VOLATILE_FSTYPES: &[&str] = &["tmpfs", "ramfs"];
+    /// Filesystem type of overlay mounts, which are volatile if their upper directory is
+    const OVERLAY_FSTYPE: &str = "overlay";
+
+    /// Read the mount table of the current process, returning `None` if mounts cannot be
+    /// inspected on this platform
+    #[cfg(target_os = "linux")]
+    pub fn load() -> Option<Self> {
+        match std::fs::read_to_string("/proc/self/mountinfo") {
+            Ok(table) => Some(Self::parse(&table)),
+            Err(e) => {
+                tracing::warn!("Failed to read mount table: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Read the mount table of the current process, returning `None` if mounts cannot be
+    /// inspected on this platform
+    #[cfg(not(target_os = "linux"))]
+    pub fn load() -> Option<Self> {
+        None
+    }
+
+    /// Parse a mount table in the format of `/proc/<pid>/mountinfo`, ignoring malformed lines
+    fn parse(table: &str) -> Self {
+        let mounts = table
+            .lines()
+            .filter_map(|line| {
+                let (mount, fs) = line.split_once(" - ")?;
+                let mut mount = mount.split(' ');
+                let point = mount.nth(4).map(Self::unescape)?;
+                let mount_options = mount.next()?;
+
+                let mut fs = fs.split(' ');
+                let fstype = fs.next()?.to_owned();
+                let source = fs.next().map(Self::unescape).unwrap_or_default();
+                let super_options = fs.next().unwrap_or_default();
+
+                let read_only = mount_options
+                    .split(',')
+                    .chain(super_options.split(','))
+                    .any(|option| option == "ro");
+                let upperdir = super_options
+                    .split(',')
+                    .find_map(|option| option.strip_prefix("upperdir="))
+                    .map(|dir| PathBuf::from(Self::unescape(dir)));
+
+                Some(MountInfo { point: PathBuf::from(point), fstype, source, read_only, upperdir })
+            })
+            .collect();
+
+        Self { mounts }
+    }
+
+    /// Replace the octal escapes used for whitespace and backslashes in mount table fields
+    fn unescape(field: &str) -> String {
+        let mut out = String::with_capacity(field.len());
+        let mut rest = field;
+        while let Some(idx) = rest.find('\\') {
+            out.push_str(&rest[..idx]);
+            let escape = rest.get(idx + 1..idx + 4).and_then(|code| u8::from_str_radix(code, 8).ok());
+            match escape {
+                Some(byte) => {
+                    out.push(byte as char);
+                    rest = &rest[idx + 4..];
+                },
+                None => {
+                    out.push('\\');
+                    rest = &rest[idx + 1..];
+                }
+            }
+        }
+
+        out.push_str(rest);
+        out
+    }
+
+    /// Get the mount containing the given path, resolving symbolic links and using the nearest
+    /// existing ancestor if the path does not exist yet
+    pub fn find(&self, path: &Path) -> Option<&MountInfo> {
+        let path = std::path::absolute(path).ok()?;
+        let path = path.ancestors().find_map(|dir| dir.canonicalize().ok())?;
+
+        //Later entries shadow earlier mounts at the same point, and max_by_key picks the last
+        //of equal elements
+        self
+            .mounts
+            .iter()
+            .filter(|mount| path.starts_with(&mount.point))
+            .max_by_key(|mount| mount.point.components().count())
+    }
+
+    /// Check if the contents of the given mount are lost when the host is rebooted
+    pub fn is_volatile(&self, mount: &MountInfo) -> bool {
+        if Self::VOLATILE_FSTYPES.contains(&mount.fstype.as_str()) {
+            return true
+        }
+
+        match (mount.fstype.as_str(), &mount.upperdir) {
+            (Self::OVERLAY_FSTYPE, Some(upper)) => self
+                .find(upper)
+                .filter(|upper| upper.point != mount.point)
+                .is_some_and(|upper| Self::VOLATILE_FSTYPES.contains(&upper.fstype.as_str())),
+            _ => false,
+        }
+    }
+}
+
+impl DeimosConfig {
+    /// Get all directories that the daemon persists state to, with a description of each
+    fn storage_paths(&self) -> [(&'static str, &Path); 2] {
+        [
+            ("save_path", &self.save_path),
+            ("containerdir", &self.pod.containerdir),
+        ]
+    }
+
+    /// Warn about any persistent directories on volatile filesystems, failing if the save file
+    /// cannot be written because its filesystem is read-only
+    pub fn check_storage(&self) -> Result<(), DeimosRunError> {
+        let Some(mounts) = MountTable::load() else {
+            tracing::debug!("Mount table is unavailable, not checking storage of persistent directories");
+            return Ok(())
+        };
+
+        for (name, path, mount) in self.volatile_storage(&mounts) {
+            tracing::warn!(
+                "{} {} is stored on volatile filesystem {} and will be lost when the host is rebooted",
+                name,
+                path.display(),
+                mount,
+            );
+        }
+
+        self.check_save_mount(&mounts)
+    }
+
+    /// Get the persistent directories that are stored on volatile filesystems with the mount
+    /// containing each
+    pub fn volatile_storage<'a>(&'a self, mounts: &'a MountTable) -> Vec<(&'static str, &'a Path, &'a MountInfo)> {
+        self
+            .storage_paths()
+            .into_iter()
+            .filter_map(|(name, path)| {
+                let Some(mount) = mounts.find(path) else {
+                    tracing::warn!("Could not determine the filesystem containing {} {}", name, path.display());
+                    return None
+                };
+
+                tracing::debug!("{} {} is stored on {}", name, path.display(), mount);
+                mounts.is_volatile(mount).then_some((name, path, mount))
+            })
+            .collect()
+    }
+
+    /// Fail if the save file cannot be written because its filesystem is read-only
+    pub fn check_save_mount(&self, mounts: &MountTable) -> Result<(), DeimosRunError> {
+        match mounts.find(&self.save_path) {
+            Some(mount) if mount.read_only => Err(
+                DeimosRunError::ReadOnlySavePath {
+                    path: self.save_path.clone(),
+                    mount: mount.to_string(),
+                }
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for MountInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) mounted at {} {}",
+            self.source,
+            self.fstype,
+            self.point.display(),
+            if self.read_only { "read-only" } else { "read-write" },
+        )?;
+
+        match self.upperdir {
+            Some(ref upper) => write!(f, " with upper directory {}", upper.display()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TABLE: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+35 22 0:30 / /tmp rw,nosuid,nodev shared:2 - tmpfs tmpfs rw,size=1024k
+40 22 8:17 / /mnt/with\\040space ro,relatime - ext4 /dev/disk\\134one rw
+41 22 0:32 / /srv/overlay rw - overlay overlay rw,lowerdir=/lower,upperdir=/tmp/upper,workdir=/tmp/work
+42 22 8:18 / /srv/sealed rw - squashfs /dev/loop0 ro
+not a mount line
+43 22 8:19 / /srv/truncated
+";
+
+    fn mount<'a>(table: &'a MountTable, point: &str) -> &'a MountInfo {
+        table.mounts.iter().find(|mount| mount.point == Path::new(point)).unwrap()
+    }
+
+    #[test]
+    fn parse_fields() {
+        let table = MountTable::parse(TABLE);
+        assert_eq!(table.mounts.len(), 5);
+
+        let root = mount(&table, "/");
+        assert_eq!(root.fstype, "ext4");
+        assert_eq!(root.source, "/dev/sda1");
+        assert!(!root.read_only);
+        assert_eq!(root.upperdir, None);
+
+        let overlay = mount(&table, "/srv/overlay");
+        assert_eq!(overlay.fstype, "overlay");
+        assert_eq!(overlay.upperdir.as_deref(), Some(Path::new("/tmp/upper")));
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let table = MountTable::parse(TABLE);
+        let spaced = mount(&table, "/mnt/with space");
+        assert_eq!(spaced.source, "/dev/disk\\one");
+        assert_eq!(MountTable::unescape("trailing\\"), "trailing\\");
+        assert_eq!(MountTable::unescape("bad\\9xy"), "bad\\9xy");
+    }
+
+    #[test]
+    fn read_only_from_mount_or_filesystem() {
+        let table = MountTable::parse(TABLE);
+        assert!(mount(&table, "/mnt/with space").read_only);
+        assert!(mount(&table, "/srv/sealed").read_only);
+        assert!(!mount(&table, "/tmp").read_only);
+    }
+
+    //Upper directories are resolved on the host, where /tmp must not be a link
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn volatile_filesystems() {
+        let table = MountTable::parse(TABLE);
+        assert!(table.is_volatile(mount(&table, "/tmp")));
+        assert!(!table.is_volatile(mount(&table, "/")));
+        //The overlay's upper directory is on the tmpfs mounted at /tmp
+        assert!(table.is_volatile(mount(&table, "/srv/overlay")));
+    }
+}
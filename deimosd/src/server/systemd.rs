@@ -0,0 +1,128 @@
+//! Integration with systemd for readiness notification, the service watchdog, and socket
+//! activation of the internal API. Every function is a no-op when the daemon is not started by
+//! systemd, so a daemon built with the `systemd` feature can still be run directly
+
+use std::{
+    os::{fd::{FromRawFd, OwnedFd}, unix::net::{UnixDatagram, UnixListener}},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use super::Deimos;
+
+/// First file descriptor passed by systemd with socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Set once the file descriptors passed with socket activation have been taken, so that they
+/// are never wrapped more than once
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Notify systemd that the daemon has started and is serving the API
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Notify systemd that the daemon is shutting down
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Send the given state to systemd's notification socket if the daemon was started with one
+fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        match path.as_encoded_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            },
+            _ => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+
+    if let Err(e) = result {
+        tracing::warn!("Failed to send {} to systemd: {}", state, e);
+    }
+}
+
+/// Get the interval that the service watchdog must be notified within, if systemd enabled the
+/// watchdog for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None
+        }
+    }
+
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec != 0)
+        .map(Duration::from_micros)
+}
+
+/// Take the listening socket passed by systemd with socket activation, returning `None` if the
+/// daemon was not socket activated or the socket was already taken
+pub fn take_listener() -> Option<UnixListener> {
+    let listen_pid = std::env::var("LISTEN_PID").ok()?;
+    if listen_pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return None
+    }
+
+    let count = std::env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    if count < 1 || LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+        return None
+    }
+
+    if count > 1 {
+        tracing::warn!("systemd passed {} sockets, only the first is used for the internal API", count);
+    }
+
+    //SAFETY: systemd passes ownership of the descriptors starting at LISTEN_FDS_START to this
+    //process, and they are only taken once
+    let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    let listener = UnixListener::from(fd);
+
+    //Fails if the descriptor is not a local socket
+    match listener.local_addr() {
+        Ok(_) => Some(listener),
+        Err(e) => {
+            tracing::error!("Socket passed by systemd cannot be used for the internal API: {}", e);
+            None
+        }
+    }
+}
+
+impl Deimos {
+    /// Notify the systemd watchdog at half of its interval for as long as the Docker daemon
+    /// responds, so that systemd restarts the daemon if it or its connection to Docker hangs
+    pub async fn watchdog_task(self: Arc<Self>, cancel: CancellationToken) {
+        let Some(timeout) = watchdog_interval() else {
+            return
+        };
+
+        tracing::info!("Notifying systemd watchdog every {:?}", timeout / 2);
+        let mut interval = tokio::time::interval(timeout / 2);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = interval.tick() => {},
+            };
+
+            match self.pods.docker_reachable().await {
+                true => notify("WATCHDOG=1"),
+                false => tracing::warn!("Docker is not reachable, withholding systemd watchdog notification"),
+            }
+        }
+    }
+}
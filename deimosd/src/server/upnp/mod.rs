@@ -1,16 +1,23 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, PoisonError};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use igd_next::aio::tokio::Tokio;
 use igd_next::aio::Gateway;
 use igd_next::PortMappingProtocol;
+use schedule::{UpnpLeaseKey, UpnpRenewalSchedule};
 use tokio_util::sync::CancellationToken;
 
+use crate::pod::id::DeimosId;
+
 use super::Deimos;
 
+mod schedule;
+
 /// State required to request port forwarding when the server is behind a NAT
 #[derive(Clone)]
 pub struct Upnp {
@@ -19,8 +26,27 @@ pub struct Upnp {
     /// Transmitter sending new UPnP leases to the maintainer thread
     /// when they are accquired
     tx: tokio::sync::mpsc::Sender<UpnpMessage>,
-    /// Local IP address, accquired from the local network interface
-    local_ip: IpAddr,
+    /// Local IP address of the interface routing to the gateway, detected again before leases
+    /// are renewed as it may change while the daemon is running
+    local_ip: Arc<std::sync::Mutex<IpAddr>>,
+    /// External address of the gateway and the ports currently forwarded through it
+    forwarded: Arc<std::sync::Mutex<UpnpForwarded>>,
+    /// Owners of every lease currently held for each port, used to reject leases that would
+    /// forward the public API's ports to a pod or a pod's ports to the public API
+    owners: Arc<std::sync::Mutex<UpnpLeaseOwners>>,
+    /// Status of the gateway and every lease, published by the maintainer task whenever it changes
+    status: tokio::sync::watch::Sender<UpnpStatus>,
+}
+
+type UpnpLeaseOwners = HashMap<UpnpLeaseKey, Vec<UpnpLeaseOwner>>;
+
+/// Ports that the gateway last accepted a lease for, used to report the address that a pod can
+/// be reached at from outside the local network
+#[derive(Debug, Default)]
+struct UpnpForwarded {
+    /// External IP address reported by the gateway
+    external_ip: Option<IpAddr>,
+    ports: HashSet<UpnpLeaseKey>,
 }
 
 /// User-provided configuration options for the UPnP client
@@ -35,11 +61,19 @@ pub struct UpnpConfig {
     /// instead of waiting for the leases to timeout.
     #[serde(default)]
     pub remove_immediate: bool,
+    /// Minimum time to wait between consecutive requests to the gateway, spreading renewals of
+    /// many leases over time
+    #[serde(default="UpnpConfig::default_request_delay_ms")]
+    pub request_delay_ms: u32,
+    /// Time to wait before retrying a lease that the gateway failed to renew, doubled for each
+    /// consecutive failure up to the renewal interval
+    #[serde(default="UpnpConfig::default_retry_seconds")]
+    pub retry_seconds: u32,
 }
 
 pub enum UpnpMessage {
     Add(UpnpLeaseData),
-    Remove(u16)
+    Remove(UpnpLeaseKey)
 }
 
 pub type UpnpReceiver = tokio::sync::mpsc::Receiver<UpnpMessage>;
@@ -50,7 +84,17 @@ pub type UpnpSender = tokio::sync::mpsc::Sender<UpnpMessage>;
 pub struct UpnpLeaseData {
     pub name: String,
     pub protocol: PortMappingProtocol,
+    /// Port opened on the gateway
     pub port: u16,
+    /// Port on the server that the gateway forwards connections to
+    pub host_port: u16,
+}
+
+/// Part of the daemon that a UPnP lease is requested for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpnpLeaseOwner {
+    Api,
+    Pod(DeimosId),
 }
 
 /// Tracking for the number of tasks that have requested the given port remain forwarded
@@ -59,6 +103,42 @@ pub struct LeaseTrack {
     pub data: UpnpLeaseData,
     /// Reference count on the number of tasks holding a permit for this lease
     pub rc: usize,
+    /// Outcome of the most recent request for this lease
+    pub status: UpnpLeaseStatus,
+    /// Time that the gateway last accepted the lease
+    pub renewed: Option<DateTime<Utc>>,
+}
+
+/// Outcome of the most recent request to the gateway for a lease
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpnpLeaseStatus {
+    /// The lease has not been requested yet, or is being requested again after the gateway or
+    /// local IP address changed
+    Pending,
+    Active,
+    /// The gateway rejected the lease or did not respond, with the error that was returned
+    Failed(String),
+}
+
+/// Current state of a single lease, as reported by [Upnp::status]
+#[derive(Debug, Clone)]
+pub struct UpnpLeaseReport {
+    pub data: UpnpLeaseData,
+    /// Parts of the daemon that hold the lease
+    pub owners: Vec<UpnpLeaseOwner>,
+    pub status: UpnpLeaseStatus,
+    pub renewed: Option<DateTime<Utc>>,
+}
+
+/// Status of the gateway and every lease maintained by the UPnP task
+#[derive(Debug, Clone, Default)]
+pub struct UpnpStatus {
+    /// Set if a gateway was found and has not stopped responding
+    pub gateway_found: bool,
+    /// External IP address last reported by the gateway
+    pub external_ip: Option<IpAddr>,
+    /// Every lease, ordered by port
+    pub leases: Vec<UpnpLeaseReport>,
 }
 
 /// Type representing a group of network ports mapped with UPNP to the device - maintains the lease
@@ -66,7 +146,9 @@ pub struct LeaseTrack {
 #[derive(Clone)]
 pub struct UpnpLease {
     tx: UpnpSender,
-    ports: Arc<[u16]>,
+    ports: Arc<[UpnpLeaseKey]>,
+    owner: UpnpLeaseOwner,
+    owners: Arc<std::sync::Mutex<UpnpLeaseOwners>>,
 }
 
 impl Deimos {
@@ -81,6 +163,9 @@ impl Deimos {
 }
 
 impl Upnp {
+    /// Number of consecutive failed requests to the gateway after which it is searched for again
+    pub const RESEARCH_FAILURES: u32 = 3;
+
     /// Retrieve the local IP address from the network adapter and create an empty map of forwarded
     /// ports
     pub async fn new(conf: UpnpConfig) -> Result<(Self, UpnpReceiver), UpnpInitError> {
@@ -100,18 +185,29 @@ impl Upnp {
 
         Ok((
             Self {
-                local_ip,
+                local_ip: Arc::new(std::sync::Mutex::new(local_ip)),
                 tx,
                 conf,
+                forwarded: Default::default(),
+                owners: Default::default(),
+                status: tokio::sync::watch::Sender::new(UpnpStatus::default()),
             },
             rx
         ))
     }
     
+    /// Search the local network for an IGD enabled gateway that leases can be requested from
+    pub async fn search_gateway() -> Result<Gateway<Tokio>, igd_next::SearchError> {
+        igd_next::aio::tokio::search_gateway(Default::default()).await
+    }
+
     /// Background task that requests UPnP leases of all ports from the gateway.
     /// This task must be running in order for UPnP leases to be actually accquired.
+    /// If requests to the gateway fail [Self::RESEARCH_FAILURES] times in a row, the gateway is
+    /// assumed to have restarted or been replaced and is searched for again every
+    /// `ip_lookup_seconds` until it is found, after which every lease is requested again
     pub async fn task(&self, mut rx: UpnpReceiver) {
-        let gateway = match igd_next::aio::tokio::search_gateway(Default::default()).await {
+        let mut gateway = match Self::search_gateway().await {
             Ok(gateway) => gateway,
             Err(igd_next::SearchError::NoResponseWithinTimeout) => {
                 tracing::warn!("No IGD enabled gateway located within timeout, port forwarding with UPnP will be disabled");
@@ -123,54 +219,264 @@ impl Upnp {
             }
         };
 
-        let mut renewal_interval = tokio::time::interval(Duration::from_secs(self.conf.renewal_seconds as u64));
-        renewal_interval.tick().await;
-        renewal_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut schedule = UpnpRenewalSchedule::new(
+            Duration::from_secs(self.conf.renewal_seconds as u64),
+            Duration::from_millis(self.conf.request_delay_ms as u64),
+            Duration::from_secs(self.conf.retry_seconds as u64),
+        );
 
-        let mut bound = HashMap::<u16, LeaseTrack>::new();
+        let mut bound = HashMap::<UpnpLeaseKey, LeaseTrack>::new();
+        self.redetect_local_ip(&gateway, &mut schedule, &mut bound).await;
+        self.publish(&bound, true);
+
+        //Number of requests to the gateway that have failed since one last succeeded
+        let mut failures = 0u32;
+        //Time to search for the gateway again at, set while the gateway is not responding
+        let mut research = None::<Instant>;
 
         loop {
+            let next = schedule.next_request();
             let msg = tokio::select! {
-                _ = renewal_interval.tick() => {
-                    for entry in bound.values() {
-                        self.accquire(&gateway, &entry.data).await;
+                _ = Self::sleep_until(research), if research.is_some() => {
+                    match Self::search_gateway().await {
+                        Ok(found) => {
+                            tracing::info!("Found IGD gateway at {}, requesting {} UPnP leases again", found.addr, bound.len());
+                            gateway = found;
+                            research = None;
+                            failures = 0;
+
+                            let now = Instant::now();
+                            for (key, entry) in bound.iter_mut() {
+                                entry.status = UpnpLeaseStatus::Pending;
+                                schedule.remove(key);
+                                schedule.insert(*key, now);
+                            }
+
+                            self.redetect_local_ip(&gateway, &mut schedule, &mut bound).await;
+                        },
+                        Err(e) => {
+                            let backoff = Duration::from_secs(self.conf.ip_lookup_seconds as u64);
+                            tracing::warn!("Failed to find IGD gateway, searching again in {}s: {}", backoff.as_secs(), e);
+                            research = Some(Instant::now() + backoff);
+                        },
                     }
 
+                    self.publish(&bound, research.is_none());
+                    continue
+                },
+                _ = Self::sleep_until(next), if research.is_none() => {
+                    let Some(key) = schedule.due(Instant::now()) else { continue };
+                    if !bound.contains_key(&key) {
+                        schedule.remove(&key);
+                        continue
+                    }
+
+                    self.redetect_local_ip(&gateway, &mut schedule, &mut bound).await;
+                    let Some(entry) = bound.get_mut(&key) else { continue };
+
+                    match self.accquire(&gateway, &entry.data).await {
+                        Ok(()) => {
+                            failures = 0;
+                            entry.status = UpnpLeaseStatus::Active;
+                            entry.renewed = Some(Utc::now());
+                            schedule.succeeded(&key, Instant::now());
+                            self.refresh_external_ip(&gateway).await;
+                            self.forwarded().ports.insert(key);
+                        },
+                        Err(e) => {
+                            failures = failures.saturating_add(1);
+                            entry.status = UpnpLeaseStatus::Failed(e.to_string());
+                            self.forwarded().ports.remove(&key);
+                            if let Some(retry) = schedule.failed(&key, Instant::now()) {
+                                tracing::warn!(
+                                    "Failed to get UPNP lease for {} port {}, retrying in {}s: {}",
+                                    key.protocol,
+                                    key.port,
+                                    retry.as_secs(),
+                                    e,
+                                );
+                            }
+
+                            if failures >= Self::RESEARCH_FAILURES {
+                                tracing::warn!(
+                                    "{} consecutive UPnP requests failed, searching for the gateway again",
+                                    failures,
+                                );
+                                research = Some(Instant::now());
+                            }
+                        },
+                    }
+
+                    self.publish(&bound, research.is_none());
                     continue
                 },
                 Some(msg) = rx.recv() => msg,
             };
 
             match msg {
-                UpnpMessage::Add(data) => match bound.get_mut(&data.port) {
+                UpnpMessage::Add(data) => match bound.get_mut(&data.key()) {
                     Some(exist) => {
+                        if exist.data.host_port != data.host_port {
+                            tracing::warn!(
+                                "UPnP lease for {} port {} named '{}' forwards to host port {}, but '{}' requested host port {}",
+                                data.protocol,
+                                data.port,
+                                exist.data.name,
+                                exist.data.host_port,
+                                data.name,
+                                data.host_port,
+                            );
+                        } else if exist.data.name != data.name {
+                            tracing::debug!(
+                                "UPnP lease for {} port {} named '{}' is shared with existing lease '{}'",
+                                data.protocol,
+                                data.port,
+                                data.name,
+                                exist.data.name,
+                            );
+                        }
+
                         exist.rc += 1;
                     },
                     None => {
-                        let port = data.port;
+                        let key = data.key();
                         let track = LeaseTrack {
                             rc: 1,
                             data,
+                            status: UpnpLeaseStatus::Pending,
+                            renewed: None,
                         };
                         
-                        self.accquire(&gateway, &track.data).await;
-                        bound.insert(port, track);
+                        schedule.insert(key, Instant::now());
+                        bound.insert(key, track);
                     }
                 },
-                UpnpMessage::Remove(port) => match bound.get_mut(&port) {
+                UpnpMessage::Remove(key) => match bound.get_mut(&key) {
                     Some(entry) => {
-                        entry.rc -= 1;
-                        if entry.rc == 0 && self.conf.remove_immediate {
-                            self.remove(&gateway, &entry.data).await;
+                        entry.rc = entry.rc.saturating_sub(1);
+                        if entry.rc == 0 {
+                            if let Some(entry) = bound.remove(&key) {
+                                schedule.remove(&key);
+                                self.forwarded().ports.remove(&key);
+                                if self.conf.remove_immediate && research.is_none() {
+                                    self.remove(&gateway, &entry.data).await;
+                                }
+                            }
                         }
-
-                        bound.remove(&port);
                     },
                     None => {
-                        tracing::warn!("Got UPnP remove port message for untracked port {}", port);
+                        tracing::warn!("Got UPnP remove port message for untracked {} port {}", key.protocol, key.port);
                     }
                 }
             }
+
+            self.publish(&bound, research.is_none());
+        }
+    }
+
+    /// Publish the status of every lease to subscribers of [Self::status]
+    fn publish(&self, bound: &HashMap<UpnpLeaseKey, LeaseTrack>, gateway_found: bool) {
+        let mut leases = {
+            let owners = self.owners.lock().unwrap_or_else(PoisonError::into_inner);
+            bound
+                .iter()
+                .map(|(key, entry)| UpnpLeaseReport {
+                    data: entry.data.clone(),
+                    owners: owners.get(key).cloned().unwrap_or_default(),
+                    status: entry.status.clone(),
+                    renewed: entry.renewed,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        leases.sort_unstable_by_key(|lease| (lease.data.port, lease.data.key().is_tcp()));
+        let external_ip = self.forwarded().external_ip;
+        self.status.send_replace(UpnpStatus { gateway_found, external_ip, leases });
+    }
+
+    /// Subscribe to the status of the gateway and every lease, updated whenever a lease is added,
+    /// removed, or requested from the gateway
+    pub fn status(&self) -> tokio::sync::watch::Receiver<UpnpStatus> {
+        self.status.subscribe()
+    }
+
+    /// Get the IP address of the server on the local network
+    pub fn local_ip(&self) -> IpAddr {
+        *self.local_ip.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Detect the local IP address again, and if it has changed remove every lease from the
+    /// gateway and schedule them to be accquired again immediately with the new address, as
+    /// renewing them would refresh mappings to an address that the server no longer holds
+    async fn redetect_local_ip(
+        &self,
+        gateway: &Gateway<Tokio>,
+        schedule: &mut UpnpRenewalSchedule,
+        bound: &mut HashMap<UpnpLeaseKey, LeaseTrack>,
+    ) {
+        let detected = match detect_local_ip(gateway.addr).await {
+            Ok(ip) => ip,
+            Err(e) => {
+                tracing::warn!("Failed to detect local IP address, keeping {}: {}", self.local_ip(), e);
+                return
+            }
+        };
+
+        let previous = std::mem::replace(&mut *self.local_ip.lock().unwrap_or_else(PoisonError::into_inner), detected);
+        if previous == detected {
+            return
+        }
+
+        tracing::warn!(
+            "Local IP address changed from {} to {}, moving {} UPnP leases to the new address",
+            previous,
+            detected,
+            bound.len(),
+        );
+
+        let now = Instant::now();
+        for (key, entry) in bound.iter_mut() {
+            entry.status = UpnpLeaseStatus::Pending;
+            self.remove(gateway, &entry.data).await;
+            self.forwarded().ports.remove(key);
+            schedule.remove(key);
+            schedule.insert(*key, now);
+        }
+    }
+
+    /// Get the external IP address that the given port is reachable at if the gateway accepted
+    /// the most recent lease for it
+    pub fn external_ip(&self, port: u16, protocol: PortMappingProtocol) -> Option<IpAddr> {
+        let forwarded = self.forwarded();
+        forwarded
+            .external_ip
+            .filter(|_| forwarded.ports.contains(&UpnpLeaseKey { port, protocol }))
+    }
+
+    fn forwarded(&self) -> std::sync::MutexGuard<'_, UpnpForwarded> {
+        self.forwarded.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Query the gateway's external IP address after a lease is accquired, as it may change
+    /// while leases are maintained
+    async fn refresh_external_ip(&self, gateway: &Gateway<Tokio>) {
+        match gateway.get_external_ip().await {
+            Ok(ip) => {
+                let mut forwarded = self.forwarded();
+                if forwarded.external_ip != Some(ip) {
+                    tracing::info!("Gateway external IP is {}", ip);
+                    forwarded.external_ip = Some(ip);
+                }
+            },
+            Err(e) => tracing::warn!("Failed to get external IP from gateway: {}", e),
+        }
+    }
+
+    /// Wait until the given time, or forever if there is nothing scheduled
+    async fn sleep_until(at: Option<Instant>) {
+        match at {
+            Some(at) => tokio::time::sleep_until(at.into()).await,
+            None => std::future::pending().await,
         }
     }
 
@@ -186,51 +492,129 @@ impl Upnp {
     }
 
     /// Request the given mapping from the IGD gateway
-    async fn accquire(&self, gateway: &Gateway<Tokio>, lease: &UpnpLeaseData) {
-        match gateway
+    async fn accquire(&self, gateway: &Gateway<Tokio>, lease: &UpnpLeaseData) -> Result<(), igd_next::AddPortError> {
+        gateway
             .add_port(
                 lease.protocol,
                 lease.port,
-                SocketAddr::new(self.local_ip, lease.port),
+                SocketAddr::new(self.local_ip(), lease.host_port),
                 self.conf.renewal_seconds + 10,
                 &lease.name,
             )
-            .await
-        {
-            Ok(_) => {
-                tracing::trace!(
-                    "Added UPNP lease for {} port {} named '{}'",
-                    lease.protocol,
-                    lease.port,
-                    lease.name
-                );
-            }
-            Err(e) => {
-                tracing::warn!(
-                    "Failed to get UPNP lease for {} port {}: {}",
-                    lease.protocol,
-                    lease.port,
-                    e
-                );
-            }
-        }
+            .await?;
+
+        tracing::trace!(
+            "Added UPNP lease for {} port {} to host port {} named '{}'",
+            lease.protocol,
+            lease.port,
+            lease.host_port,
+            lease.name
+        );
+
+        Ok(())
     }
 
     /// Request the given block of UPnP leases, returning a structure that will maintain the ports
-    /// mapped until it is dropped
+    /// mapped until it is dropped.
+    /// Pods may share forwarded ports with each other, but requesting a port that is already
+    /// forwarded for the public API from a pod or vice versa fails without forwarding any ports.
+    /// If a pod limit is given, requests from pods fail without forwarding any ports if the number
+    /// of distinct ports forwarded for all pods would exceed it
     pub async fn request(
         &self,
+        owner: UpnpLeaseOwner,
         leases: Vec<UpnpLeaseData>,
+        pod_limit: Option<usize>,
     ) -> Result<UpnpLease, UpnpError> {
+        {
+            let mut owners = self.owners.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(limit) = pod_limit.filter(|_| matches!(owner, UpnpLeaseOwner::Pod(..))) {
+                let forwarded = Self::count_pod_ports(&owners);
+                let requested = leases
+                    .iter()
+                    .map(UpnpLeaseData::key)
+                    .filter(|key| !owners.get(key).is_some_and(|held| held.iter().any(|exist| matches!(exist, UpnpLeaseOwner::Pod(..)))))
+                    .collect::<HashSet<_>>()
+                    .len();
+
+                if forwarded + requested > limit {
+                    return Err(UpnpError::PodLimit { requester: owner, requested, forwarded, limit })
+                }
+            }
+
+            for data in leases.iter() {
+                let held = owners.get(&data.key()).and_then(|held| held.iter().find(|exist| exist.conflicts(&owner)));
+                if let Some(exist) = held {
+                    return Err(
+                        UpnpError::InUse {
+                            port: data.port,
+                            protocol: data.protocol,
+                            owner: exist.clone(),
+                            requester: owner,
+                        }
+                    )
+                }
+            }
+
+            for data in leases.iter() {
+                owners.entry(data.key()).or_default().push(owner.clone());
+            }
+        }
+
         let mut ports = Vec::with_capacity(leases.len());
 
         for data in leases {
-            let port = data.port;
-            ports.push(port);
+            ports.push(data.key());
             let _ = self.tx.send(UpnpMessage::Add(data)).await;
         }
 
-        Ok(UpnpLease { tx: self.tx.clone(), ports: Arc::from(ports) })
+        Ok(
+            UpnpLease {
+                tx: self.tx.clone(),
+                ports: Arc::from(ports),
+                owner,
+                owners: self.owners.clone(),
+            }
+        )
+    }
+}
+
+/// Get the address of the local interface that traffic to the given gateway is routed through,
+/// falling back to the address guessed from the network adapters if there is no route.
+/// Connecting a UDP socket selects a route without sending any packets
+async fn detect_local_ip(gateway: SocketAddr) -> Result<IpAddr, local_ip_address::Error> {
+    let unspecified = match gateway {
+        SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let routed = async {
+        let socket = tokio::net::UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+        socket.connect(gateway).await?;
+        socket.local_addr()
+    };
+
+    match routed.await {
+        Ok(addr) if !addr.ip().is_unspecified() => Ok(addr.ip()),
+        Ok(..) => local_ip_address::local_ip(),
+        Err(e) => {
+            tracing::debug!("Failed to find interface routing to gateway {}: {}", gateway, e);
+            local_ip_address::local_ip()
+        },
+    }
+}
+
+impl Upnp {
+    /// Get the number of distinct ports currently forwarded for pods
+    pub fn pod_forwarded_ports(&self) -> usize {
+        Self::count_pod_ports(&self.owners.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn count_pod_ports(owners: &UpnpLeaseOwners) -> usize {
+        owners
+            .values()
+            .filter(|held| held.iter().any(|owner| matches!(owner, UpnpLeaseOwner::Pod(..))))
+            .count()
     }
 }
 
@@ -240,6 +624,31 @@ impl Default for UpnpConfig {
             ip_lookup_seconds: Self::default_ip_lookup_seconds(),
             renewal_seconds: Self::default_renewal_seconds(),
             remove_immediate: false,
+            request_delay_ms: Self::default_request_delay_ms(),
+            retry_seconds: Self::default_retry_seconds(),
+        }
+    }
+}
+
+impl UpnpLeaseData {
+    /// Get the key identifying the mapping on the gateway that this lease requires
+    pub const fn key(&self) -> UpnpLeaseKey {
+        UpnpLeaseKey { port: self.port, protocol: self.protocol }
+    }
+}
+
+impl UpnpLeaseOwner {
+    /// Check if leases held by this owner and the given owner may not forward the same port
+    fn conflicts(&self, other: &Self) -> bool {
+        self != other && (*self == Self::Api || *other == Self::Api)
+    }
+}
+
+impl fmt::Display for UpnpLeaseOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Api => f.write_str("the public API"),
+            Self::Pod(id) => write!(f, "pod {}", id),
         }
     }
 }
@@ -252,6 +661,14 @@ impl UpnpConfig {
     pub const fn default_ip_lookup_seconds() -> u32 {
         15
     }
+
+    pub const fn default_request_delay_ms() -> u32 {
+        250
+    }
+
+    pub const fn default_retry_seconds() -> u32 {
+        30
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -264,13 +681,55 @@ pub enum UpnpInitError {
 
 #[derive(Debug, thiserror::Error)]
 pub enum UpnpError {
-    #[error("Port {0} already reserved with IGD gateway")]
-    InUse(u16),
+    #[error("Cannot forward {protocol} port {port} for {requester}, it is already forwarded for {owner}")]
+    InUse {
+        port: u16,
+        protocol: PortMappingProtocol,
+        owner: UpnpLeaseOwner,
+        requester: UpnpLeaseOwner,
+    },
+    #[error(
+        "Cannot forward {requested} more ports for {requester}, {forwarded} ports are already forwarded for pods and max_total_forwarded_ports is {limit}"
+    )]
+    PodLimit {
+        requester: UpnpLeaseOwner,
+        requested: usize,
+        forwarded: usize,
+        limit: usize,
+    },
+}
+
+#[cfg(test)]
+impl UpnpLease {
+    /// Create a lease of no ports for a pod that has not requested any
+    pub(crate) fn test(owner: UpnpLeaseOwner) -> Self {
+        Self {
+            tx: tokio::sync::mpsc::channel(1).0,
+            ports: Arc::from([]),
+            owner,
+            owners: Default::default(),
+        }
+    }
 }
 
 impl Drop for UpnpLease {
     fn drop(&mut self) {
         if let Some(ports) = Arc::get_mut(&mut self.ports) {
+            {
+                let mut owners = self.owners.lock().unwrap_or_else(PoisonError::into_inner);
+                for port in ports.iter() {
+                    if let Some(held) = owners.get_mut(port) {
+                        if let Some(idx) = held.iter().position(|owner| *owner == self.owner) {
+                            held.swap_remove(idx);
+                        }
+
+                        if held.is_empty() {
+                            owners.remove(port);
+                        }
+                    }
+                }
+            }
+
             let ports = Vec::from(ports);
             let tx = self.tx.clone();
             tokio::task::spawn(async move {
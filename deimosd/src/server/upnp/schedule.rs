@@ -0,0 +1,283 @@
+//! Calendar of UPnP lease renewals, spreading requests to the gateway over time instead of
+//! renewing every lease at once. Kept free of any gateway communication so that the current
+//! time is always provided by the caller
+
+use std::{collections::{hash_map::Entry, HashMap}, hash::{Hash, Hasher}, time::{Duration, Instant}};
+
+use igd_next::PortMappingProtocol;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Identifies a single port mapping on the gateway, leases requested by multiple pods for the
+/// same key share one mapping
+#[derive(Debug, Clone, Copy)]
+pub struct UpnpLeaseKey {
+    pub port: u16,
+    pub protocol: PortMappingProtocol,
+}
+
+/// Tracks the deadline for renewing every lease, selecting the lease with the earliest deadline
+/// and enforcing a minimum delay between requests to the gateway
+#[derive(Debug)]
+pub struct UpnpRenewalSchedule {
+    /// Time between successful renewals of a lease
+    interval: Duration,
+    /// Minimum time between the end of one request and the start of the next
+    delay: Duration,
+    /// Time to wait before retrying a lease after its first failure, doubled for each
+    /// consecutive failure
+    retry: Duration,
+    leases: HashMap<UpnpLeaseKey, ScheduledRenewal>,
+    /// Time that the most recent request completed at
+    last_request: Option<Instant>,
+    /// Source of the jitter applied to deadlines, so that leases added at the same time do not
+    /// keep being renewed in lockstep
+    rng: StdRng,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ScheduledRenewal {
+    due: Instant,
+    /// Number of consecutive failed requests for this lease
+    failures: u32,
+}
+
+impl UpnpRenewalSchedule {
+    /// Divisor of the renewal interval giving the longest time that a renewal may be moved
+    /// ahead of its deadline by
+    const RENEWAL_JITTER_DIVISOR: u32 = 10;
+
+    pub fn new(interval: Duration, delay: Duration, retry: Duration) -> Self {
+        Self::with_rng(interval, delay, retry, StdRng::from_entropy())
+    }
+
+    fn with_rng(interval: Duration, delay: Duration, retry: Duration, rng: StdRng) -> Self {
+        Self {
+            interval,
+            delay,
+            retry,
+            leases: HashMap::new(),
+            last_request: None,
+            rng,
+        }
+    }
+
+    /// Add a lease to be accquired within one delay between requests, returning `false` if the
+    /// lease is already scheduled
+    pub fn insert(&mut self, key: UpnpLeaseKey, now: Instant) -> bool {
+        match self.leases.entry(key) {
+            Entry::Occupied(..) => false,
+            Entry::Vacant(entry) => {
+                let due = now + self.rng.gen_range(Duration::ZERO..=self.delay);
+                entry.insert(ScheduledRenewal { due, failures: 0 });
+                true
+            }
+        }
+    }
+
+    /// Stop renewing the given lease, returning `false` if it was not scheduled
+    pub fn remove(&mut self, key: &UpnpLeaseKey) -> bool {
+        self.leases.remove(key).is_some()
+    }
+
+    /// Get the time that the next request should be sent at, or `None` if no leases are
+    /// scheduled
+    pub fn next_request(&self) -> Option<Instant> {
+        let (_, due) = self.earliest()?;
+        Some(match self.last_request {
+            Some(last) => due.max(last + self.delay),
+            None => due,
+        })
+    }
+
+    /// Get the lease that should be renewed at the given time, if any.
+    /// The outcome of the request must be reported with [succeeded](Self::succeeded) or
+    /// [failed](Self::failed) to reschedule the lease
+    pub fn due(&self, now: Instant) -> Option<UpnpLeaseKey> {
+        match self.next_request()? <= now {
+            true => self.earliest().map(|(key, _)| key),
+            false => None,
+        }
+    }
+
+    /// Record that the given lease was renewed, scheduling the next renewal within the last
+    /// tenth of the interval so that renewals of leases accquired together drift apart
+    pub fn succeeded(&mut self, key: &UpnpLeaseKey, now: Instant) {
+        self.last_request = Some(now);
+        if let Some(lease) = self.leases.get_mut(key) {
+            let jitter = self.rng.gen_range(Duration::ZERO..=self.interval / Self::RENEWAL_JITTER_DIVISOR);
+            lease.failures = 0;
+            lease.due = now + self.interval - jitter;
+        }
+    }
+
+    /// Record that the gateway failed to renew the given lease, returning the time until it
+    /// will be retried
+    pub fn failed(&mut self, key: &UpnpLeaseKey, now: Instant) -> Option<Duration> {
+        self.last_request = Some(now);
+        let lease = self.leases.get_mut(key)?;
+        lease.failures = lease.failures.saturating_add(1);
+        let backoff = Self::backoff(self.retry, self.interval, lease.failures);
+        lease.due = now + backoff;
+        Some(backoff)
+    }
+
+    /// Get the time to wait before retrying a lease after the given number of consecutive
+    /// failures, never waiting longer than a full renewal interval
+    fn backoff(retry: Duration, interval: Duration, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        retry.saturating_mul(factor).min(interval)
+    }
+
+    /// Get the lease with the earliest deadline, ordering leases with equal deadlines by port so
+    /// that the order of renewals is stable
+    fn earliest(&self) -> Option<(UpnpLeaseKey, Instant)> {
+        self
+            .leases
+            .iter()
+            .min_by_key(|(key, lease)| (lease.due, key.port, key.is_tcp()))
+            .map(|(key, lease)| (*key, lease.due))
+    }
+}
+
+impl UpnpLeaseKey {
+    pub const fn is_tcp(&self) -> bool {
+        matches!(self.protocol, PortMappingProtocol::TCP)
+    }
+}
+
+impl PartialEq for UpnpLeaseKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.port == other.port && self.protocol == other.protocol
+    }
+}
+
+impl Eq for UpnpLeaseKey {}
+
+impl Hash for UpnpLeaseKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.port.hash(state);
+        self.is_tcp().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(900);
+    const DELAY: Duration = Duration::from_millis(250);
+    const RETRY: Duration = Duration::from_secs(30);
+
+    fn schedule(seed: u64) -> UpnpRenewalSchedule {
+        UpnpRenewalSchedule::with_rng(INTERVAL, DELAY, RETRY, StdRng::seed_from_u64(seed))
+    }
+
+    fn key(port: u16) -> UpnpLeaseKey {
+        UpnpLeaseKey { port, protocol: PortMappingProtocol::TCP }
+    }
+
+    /// Send every request as soon as the schedule allows until the given time, returning the
+    /// times that each lease was renewed at
+    fn run(schedule: &mut UpnpRenewalSchedule, start: Instant, until: Instant) -> HashMap<u16, Vec<Instant>> {
+        let mut renewed = HashMap::<u16, Vec<Instant>>::new();
+        let mut now = start;
+        while let Some(next) = schedule.next_request().filter(|next| *next <= until) {
+            now = now.max(next);
+            let key = schedule.due(now).unwrap();
+            schedule.succeeded(&key, now);
+            renewed.entry(key.port).or_default().push(now);
+        }
+
+        renewed
+    }
+
+    #[test]
+    fn inserted_leases_are_accquired_within_one_delay() {
+        let now = Instant::now();
+        let mut schedule = schedule(0);
+        assert!(schedule.insert(key(1), now));
+        assert!(!schedule.insert(key(1), now + INTERVAL));
+
+        let due = schedule.next_request().unwrap();
+        assert!(due >= now && due <= now + DELAY);
+        assert_eq!(schedule.due(due), Some(key(1)));
+    }
+
+    #[test]
+    fn requests_are_separated_by_delay() {
+        let start = Instant::now();
+        let mut schedule = schedule(1);
+        for port in 0..10 {
+            schedule.insert(key(port), start);
+        }
+
+        let renewed = run(&mut schedule, start, start + INTERVAL * 4);
+        let mut times = renewed.values().flatten().copied().collect::<Vec<_>>();
+        times.sort();
+        assert!(times.windows(2).all(|pair| pair[1] - pair[0] >= DELAY));
+    }
+
+    #[test]
+    fn renewals_stay_within_interval() {
+        let start = Instant::now();
+        let mut schedule = schedule(2);
+        for port in 0..10 {
+            schedule.insert(key(port), start);
+        }
+
+        let renewed = run(&mut schedule, start, start + INTERVAL * 8);
+        for times in renewed.values() {
+            assert!(times[0] <= start + DELAY * 10);
+            for pair in times.windows(2) {
+                let gap = pair[1] - pair[0];
+                assert!(gap <= INTERVAL + DELAY * 10 && gap >= INTERVAL - INTERVAL / 10, "renewed after {:?}", gap);
+            }
+        }
+    }
+
+    #[test]
+    fn leases_accquired_together_drift_apart() {
+        let start = Instant::now();
+        let mut schedule = schedule(3);
+        for port in 0..10 {
+            schedule.insert(key(port), start);
+        }
+
+        //Without jitter every lease would be renewed in one burst spaced by the delay
+        run(&mut schedule, start, start + INTERVAL * 8);
+        let mut due = schedule.leases.values().map(|lease| lease.due).collect::<Vec<_>>();
+        due.sort();
+        assert!(due[due.len() - 1] - due[0] > DELAY * 10);
+    }
+
+    #[test]
+    fn failures_back_off_up_to_interval() {
+        let now = Instant::now();
+        let mut schedule = schedule(4);
+        schedule.insert(key(1), now);
+
+        assert_eq!(schedule.failed(&key(1), now), Some(RETRY));
+        assert_eq!(schedule.failed(&key(1), now), Some(RETRY * 2));
+        for _ in 0..32 {
+            schedule.failed(&key(1), now);
+        }
+        assert_eq!(schedule.failed(&key(1), now), Some(INTERVAL));
+        assert_eq!(schedule.next_request(), Some(now + INTERVAL));
+
+        schedule.succeeded(&key(1), now);
+        assert_eq!(schedule.leases[&key(1)].failures, 0);
+        assert_eq!(schedule.failed(&key(1), now), Some(RETRY));
+        assert_eq!(schedule.failed(&key(2), now), None);
+    }
+
+    #[test]
+    fn removed_leases_are_not_due() {
+        let now = Instant::now();
+        let mut schedule = schedule(5);
+        schedule.insert(key(1), now);
+        assert!(schedule.remove(&key(1)));
+        assert!(!schedule.remove(&key(1)));
+        assert_eq!(schedule.next_request(), None);
+        assert_eq!(schedule.due(now + INTERVAL), None);
+    }
+}
@@ -1,4 +1,7 @@
+use prost_build::{Service, ServiceGenerator};
+
 const PROTO_DIR: &str = "./proto";
+const PACKAGE: &str = "deimos.v1";
 
 fn main() {
     let proto_files = std::fs::read_dir(PROTO_DIR)
@@ -13,13 +16,52 @@ fn main() {
         })
         .collect::<Vec<_>>();
 
-    if let Err(e) = tonic_build::configure()
-        .emit_rerun_if_changed(true)
-        .use_arc_self(true)
-        .server_mod_attribute("deimos", "#[cfg(feature=\"server\")]")
-        .client_mod_attribute("deimos", "#[cfg(feature=\"channel\")]")
+    println!("cargo:rerun-if-changed={}", PROTO_DIR);
+    for file in proto_files.iter() {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+
+    if let Err(e) = prost_build::Config::new()
+        .service_generator(Box::new(FeatureServiceGenerator))
         .compile_protos(&proto_files, &[PROTO_DIR])
     {
         panic!("Failed to compile protobuf files: {e}");
     }
 }
+
+/// Generates the client and server modules of each service behind the features that enable
+/// them, so that crates only compile the halves of the services they use.
+/// Messages are shared by all services and are always generated
+struct FeatureServiceGenerator;
+
+impl FeatureServiceGenerator {
+    /// Get the cfg predicates enabling the client and server modules of the given service
+    fn features(service: &Service) -> (&'static str, &'static str) {
+        match service.name.as_str() {
+            "DeimosService" => (r#"feature = "service-client""#, r#"feature = "service-server""#),
+            "DeimosAuthorization" => (r#"feature = "auth-client""#, r#"feature = "auth-server""#),
+            "Internal" => (
+                r#"all(feature = "internal", feature = "channel")"#,
+                r#"all(feature = "internal", feature = "server")"#,
+            ),
+            other => panic!("Service {other} is not assigned to a feature"),
+        }
+    }
+}
+
+impl ServiceGenerator for FeatureServiceGenerator {
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        let (client, server) = Self::features(&service);
+
+        //Module attributes are matched by package, so each service is generated separately
+        //with its own attributes
+        let mut generator = tonic_build::configure()
+            .use_arc_self(true)
+            .client_mod_attribute(PACKAGE, format!("#[cfg({client})]"))
+            .server_mod_attribute(PACKAGE, format!("#[cfg({server})]"))
+            .service_generator();
+
+        generator.generate(service, buf);
+        generator.finalize(buf);
+    }
+}
@@ -0,0 +1,172 @@
+//! Optional features of the daemon advertised to clients in [crate::ServerInfo], so that clients
+//! hide what the connected server does not support instead of probing for `UNIMPLEMENTED` errors.
+//! Capabilities are sent as their string identifiers so that clients ignore those added after
+//! they were built, and servers that advertise none predate advertisement and support every
+//! capability that existed when it was added.
+//! New capabilities are appended to [Capability::ALL] and left out of the legacy set
+
+use std::fmt;
+
+/// An optional feature of the daemon that clients may depend on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Capability {
+    /// Interactive terminal sessions opened with `AttachPodExec`
+    #[serde(rename = "attach")]
+    Attach,
+    /// Resource usage of pods streamed with `SubscribePodStats`
+    #[serde(rename = "pod-stats")]
+    PodStats,
+    /// Pod icons and banners downloaded with `GetPodImages`
+    #[serde(rename = "pod-images")]
+    PodImages,
+    /// Publicly shared pod status pages served over HTTP
+    #[serde(rename = "status-pages")]
+    StatusPages,
+    /// Resumable downloads of pod images with `DownloadPodImage`
+    #[serde(rename = "pod-image-download")]
+    PodImageDownload,
+}
+
+/// Set of capabilities advertised by a server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapabilitySet(u32);
+
+impl Capability {
+    /// Every capability, in the order they were added
+    pub const ALL: [Self; 5] = [Self::Attach, Self::PodStats, Self::PodImages, Self::StatusPages, Self::PodImageDownload];
+
+    /// Capabilities of servers from before capabilities were advertised
+    const LEGACY: [Self; 4] = [Self::Attach, Self::PodStats, Self::PodImages, Self::StatusPages];
+
+    /// Get the identifier that the capability is advertised with
+    pub const fn id(&self) -> &'static str {
+        match self {
+            Self::Attach => "attach",
+            Self::PodStats => "pod-stats",
+            Self::PodImages => "pod-images",
+            Self::StatusPages => "status-pages",
+            Self::PodImageDownload => "pod-image-download",
+        }
+    }
+
+    /// Get the capability advertised with the given identifier, or `None` if it is not known
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|capability| capability.id() == id)
+    }
+
+    /// Get the bit representing this capability in a [CapabilitySet]
+    const fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+impl CapabilitySet {
+    /// Set containing every capability
+    pub const ALL: Self = Self::of(&Capability::ALL);
+
+    /// Set containing the capabilities of servers from before capabilities were advertised
+    pub const LEGACY: Self = Self::of(&Capability::LEGACY);
+
+    /// Create a set containing the given capabilities
+    const fn of(capabilities: &[Capability]) -> Self {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < capabilities.len() {
+            bits |= capabilities[i].bit();
+            i += 1;
+        }
+
+        Self(bits)
+    }
+
+    /// Decode the capability identifiers advertised by a server, ignoring unknown identifiers.
+    /// Servers advertising no capabilities predate advertisement and are given the capabilities
+    /// that existed at the time
+    pub fn from_advertised<S: AsRef<str>>(ids: &[S]) -> Self {
+        match ids.is_empty() {
+            true => Self::LEGACY,
+            false => ids.iter().filter_map(|id| Capability::from_id(id.as_ref())).collect(),
+        }
+    }
+
+    /// Get the identifiers to advertise for every capability in the set
+    pub fn advertise(&self) -> Vec<String> {
+        self.iter().map(|capability| capability.id().to_owned()).collect()
+    }
+
+    /// Check if the set contains the given capability
+    pub const fn contains(&self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Add the given capability to the set
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= capability.bit();
+    }
+
+    /// Remove the given capability from the set
+    pub fn remove(&mut self, capability: Capability) {
+        self.0 &= !capability.bit();
+    }
+
+    /// Iterate over every capability in the set
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.into_iter().filter(|capability| self.contains(*capability))
+    }
+}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        let mut set = Self::default();
+        for capability in iter {
+            set.insert(capability);
+        }
+
+        set
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ids_round_trip() {
+        for capability in Capability::ALL {
+            assert_eq!(Capability::from_id(capability.id()), Some(capability));
+        }
+
+        assert_eq!(Capability::from_id("teleport"), None);
+    }
+
+    #[test]
+    fn servers_advertising_nothing_are_legacy() {
+        let legacy = CapabilitySet::from_advertised::<&str>(&[]);
+        assert_eq!(legacy, CapabilitySet::LEGACY);
+        assert!(legacy.contains(Capability::PodImages));
+        assert!(!legacy.contains(Capability::PodImageDownload));
+    }
+
+    #[test]
+    fn unknown_ids_are_ignored() {
+        let set = CapabilitySet::from_advertised(&["pod-stats", "teleport"]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [Capability::PodStats]);
+
+        //A server advertising only unknown capabilities supports none of the known ones
+        assert_eq!(CapabilitySet::from_advertised(&["teleport"]), CapabilitySet::default());
+    }
+
+    #[test]
+    fn advertised_set_is_decoded_unchanged() {
+        let mut set = CapabilitySet::ALL;
+        set.remove(Capability::StatusPages);
+        assert_eq!(CapabilitySet::from_advertised(&set.advertise()), set);
+        assert_eq!(CapabilitySet::from_advertised(&CapabilitySet::ALL.advertise()), CapabilitySet::ALL);
+    }
+}
@@ -0,0 +1,130 @@
+use prost::Message;
+
+use crate::{ErrorCode, ErrorDetail};
+
+/// The standard `google.rpc.Status` message that gRPC expects in the `grpc-status-details-bin`
+/// trailer, defined here with the same tags as `tonic_types` as that crate does not support the
+/// version of tonic used by Deimos
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<prost_types::Any>,
+}
+
+impl ErrorDetail {
+    /// Type URL identifying an error detail packed in a `google.protobuf.Any`
+    pub const TYPE_URL: &'static str = "type.googleapis.com/deimos.v1.ErrorDetail";
+
+    /// Create a new error detail with the given code and message
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code: code as i32,
+            message: message.into(),
+            pod_id: String::new(),
+            current_version: 0,
+        }
+    }
+
+    /// Set the ID of the pod that the failure relates to
+    pub fn with_pod(mut self, pod_id: impl Into<String>) -> Self {
+        self.pod_id = pod_id.into();
+        self
+    }
+
+    /// Set the current version of state that was modified concurrently
+    pub fn with_current_version(mut self, version: u64) -> Self {
+        self.current_version = version;
+        self
+    }
+
+    /// Create a status with the given gRPC code that carries this detail, using the detail's
+    /// message as the status message for clients that do not read details. The detail is packed
+    /// in a `google.rpc.Status` so that other gRPC libraries can read it
+    pub fn into_status(self, code: tonic::Code) -> tonic::Status {
+        let status = RpcStatus {
+            code: code as i32,
+            message: self.message.clone(),
+            details: vec![
+                prost_types::Any {
+                    type_url: Self::TYPE_URL.to_owned(),
+                    value: self.encode_to_vec(),
+                }
+            ],
+        };
+
+        tonic::Status::with_details(code, self.message, status.encode_to_vec().into())
+    }
+
+    /// Decode the error detail attached to the given status, if there is one. Details sent
+    /// without a `google.rpc.Status` wrapper by older daemons are also accepted
+    pub fn from_status(status: &tonic::Status) -> Option<Self> {
+        match status.details() {
+            [] => None,
+            details => match RpcStatus::decode(details) {
+                Ok(status) if !status.details.is_empty() => status
+                    .details
+                    .into_iter()
+                    .find(|any| any.type_url == Self::TYPE_URL)
+                    .and_then(|any| Self::decode(any.value.as_slice()).ok()),
+                _ => Self::decode(details).ok(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detail() -> ErrorDetail {
+        ErrorDetail::new(ErrorCode::StateConflict, "Pod was changed").with_pod("web").with_current_version(7)
+    }
+
+    #[test]
+    fn details_are_a_google_rpc_status() {
+        let status = detail().into_status(tonic::Code::Aborted);
+        let decoded = RpcStatus::decode(status.details()).unwrap();
+
+        assert_eq!(decoded.code, tonic::Code::Aborted as i32);
+        assert_eq!(decoded.message, "Pod was changed");
+        assert_eq!(decoded.details.len(), 1);
+        assert_eq!(decoded.details[0].type_url, "type.googleapis.com/deimos.v1.ErrorDetail");
+        assert_eq!(ErrorDetail::decode(decoded.details[0].value.as_slice()).unwrap(), detail());
+    }
+
+    #[test]
+    fn details_survive_the_status_trailers() {
+        let status = detail().into_status(tonic::Code::Aborted);
+        let mut headers = tonic::metadata::MetadataMap::new().into_headers();
+        status.add_header(&mut headers).unwrap();
+        assert!(headers.contains_key("grpc-status-details-bin"));
+
+        let received = tonic::Status::from_header_map(&headers).unwrap();
+        assert_eq!(received.code(), tonic::Code::Aborted);
+        assert_eq!(ErrorDetail::from_status(&received), Some(detail()));
+    }
+
+    #[test]
+    fn unwrapped_details_are_accepted() {
+        let status = tonic::Status::with_details(tonic::Code::Aborted, "Pod was changed", detail().encode_to_vec().into());
+        assert_eq!(ErrorDetail::from_status(&status), Some(detail()));
+    }
+
+    #[test]
+    fn other_details_are_ignored() {
+        let foreign = RpcStatus {
+            code: tonic::Code::Internal as i32,
+            message: String::from("failed"),
+            details: vec![prost_types::Any { type_url: String::from("type.googleapis.com/google.rpc.DebugInfo"), value: vec![] }],
+        };
+
+        let status = tonic::Status::with_details(tonic::Code::Internal, "failed", foreign.encode_to_vec().into());
+        assert_eq!(ErrorDetail::from_status(&status), None);
+
+        assert_eq!(ErrorDetail::from_status(&tonic::Status::internal("failed")), None);
+    }
+}
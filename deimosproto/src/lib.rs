@@ -1,18 +1,24 @@
 pub mod util;
 pub mod auth;
+pub mod capability;
+pub mod error;
+pub mod limit;
+#[cfg(feature = "internal")]
+pub mod pin;
 
-mod proto {
-    tonic::include_proto!("deimos");
+/// Types generated for version 1 of the Deimos API, also re-exported at the crate root
+pub mod v1 {
+    tonic::include_proto!("deimos.v1");
 }
 
-#[cfg(feature = "server")]
-pub use proto::deimos_service_server as server;
-#[cfg(feature = "server")]
-pub use proto::deimos_authorization_server as authserver;
+#[cfg(feature = "service-server")]
+pub use v1::deimos_service_server as server;
+#[cfg(feature = "auth-server")]
+pub use v1::deimos_authorization_server as authserver;
 
-#[cfg(feature = "channel")]
-pub use proto::deimos_service_client as client;
-#[cfg(feature = "channel")]
-pub use proto::deimos_authorization_client as authclient;
+#[cfg(feature = "service-client")]
+pub use v1::deimos_service_client as client;
+#[cfg(feature = "auth-client")]
+pub use v1::deimos_authorization_client as authclient;
 
-pub use proto::*;
+pub use v1::*;
@@ -0,0 +1,95 @@
+//! Negotiation of the maximum size of gRPC messages exchanged with the daemon
+
+use tonic::metadata::MetadataMap;
+
+/// Maximum size in bytes of a single encoded gRPC message.
+/// Clients advertise the largest message they will decode in the [Self::HTTP_HEADER_NAME] header,
+/// and the server splits streamed payloads into chunks that fit within both its own limit and the
+/// limit advertised by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageSizeLimit(usize);
+
+impl MessageSizeLimit {
+    /// Name of the header that clients advertise their decoding limit in
+    pub const HTTP_HEADER_NAME: &str = "deimos-max-message-size";
+    /// Limit used by both the server and clients if none is configured
+    pub const DEFAULT: Self = Self(8 * 1024 * 1024);
+    /// Smallest limit that may be configured, leaving room for chunks of a useful size
+    const MIN: usize = 64 * 1024;
+    /// Divisor of the limit giving the size of streamed chunks, leaving headroom for the other
+    /// fields of chunk messages and their encoding
+    const CHUNK_DIVISOR: usize = 4;
+
+    /// Create a new limit of the given number of bytes, raised to the minimum allowed limit if
+    /// it is too small
+    pub const fn new(bytes: usize) -> Self {
+        match bytes < Self::MIN {
+            true => Self(Self::MIN),
+            false => Self(bytes),
+        }
+    }
+
+    /// Get the limit in bytes
+    pub const fn bytes(&self) -> usize {
+        self.0
+    }
+
+    /// Get the number of payload bytes that should be sent in each message of a streamed response
+    pub const fn chunk_size(&self) -> usize {
+        self.0 / Self::CHUNK_DIVISOR
+    }
+
+    /// Get the smaller of this limit and the limit advertised in the given request metadata.
+    /// Requests that do not advertise a limit are assumed to use the default
+    pub fn negotiate(self, metadata: &MetadataMap) -> Self {
+        let client = metadata
+            .get(Self::HTTP_HEADER_NAME)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Self::new)
+            .unwrap_or(Self::DEFAULT);
+
+        self.min(client)
+    }
+}
+
+impl Default for MessageSizeLimit {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn advertising(value: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(MessageSizeLimit::HTTP_HEADER_NAME, value.parse().unwrap());
+        metadata
+    }
+
+    #[test]
+    fn small_limits_are_raised() {
+        assert_eq!(MessageSizeLimit::new(0).bytes(), MessageSizeLimit::MIN);
+        assert_eq!(MessageSizeLimit::new(MessageSizeLimit::MIN + 1).bytes(), MessageSizeLimit::MIN + 1);
+        assert_eq!(MessageSizeLimit::DEFAULT.chunk_size(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn negotiated_limit_is_smaller_of_both() {
+        let server = MessageSizeLimit::new(1024 * 1024);
+        assert_eq!(server.negotiate(&advertising("524288")).bytes(), 512 * 1024);
+        assert_eq!(server.negotiate(&advertising("16777216")), server);
+
+        //Clients advertising a tiny limit still receive chunks of a useful size
+        assert_eq!(server.negotiate(&advertising("1")).bytes(), MessageSizeLimit::MIN);
+    }
+
+    #[test]
+    fn missing_or_invalid_limit_uses_default() {
+        let server = MessageSizeLimit::new(64 * 1024 * 1024);
+        assert_eq!(server.negotiate(&MetadataMap::new()), MessageSizeLimit::DEFAULT);
+        assert_eq!(server.negotiate(&advertising("lots")), MessageSizeLimit::DEFAULT);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fmt, str::FromStr};
+
+use argon2::{password_hash::{PasswordHashString, SaltString}, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+
+/// An Argon2id hash of the PIN protecting privileged operations on the internal API, stored in
+/// the daemon's config file as a PHC string like `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+/// The parameters are read from the string, so hashes made with other costs can still be verified
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct InternalPinHash {
+    phc: PasswordHashString,
+}
+
+impl InternalPinHash {
+    /// Number of bytes of random salt mixed into the hash
+    pub const SALT_LEN: usize = 16;
+
+    /// Hash the given PIN with the given random salt using the default Argon2id parameters
+    pub fn new(pin: &str, salt: [u8; Self::SALT_LEN]) -> Self {
+        let salt = SaltString::encode_b64(&salt).expect("a 16 byte salt is within the PHC salt length limits");
+        let hash = Argon2::default()
+            .hash_password(pin.as_bytes(), &salt)
+            .expect("the default Argon2 parameters are valid");
+
+        Self { phc: hash.serialize() }
+    }
+
+    /// Check if the given PIN matches this hash. This is deliberately slow and should not be
+    /// called from an async task
+    pub fn verify(&self, pin: &str) -> bool {
+        Argon2::default()
+            .verify_password(pin.as_bytes(), &self.phc.password_hash())
+            .is_ok()
+    }
+}
+
+impl fmt::Display for InternalPinHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.phc.as_str())
+    }
+}
+
+impl fmt::Debug for InternalPinHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InternalPinHash({})", self.phc.algorithm())
+    }
+}
+
+impl FromStr for InternalPinHash {
+    type Err = InternalPinHashParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = PasswordHash::new(s).map_err(|_| InternalPinHashParseError::Format)?;
+        if hash.algorithm != argon2::Algorithm::Argon2id.ident() {
+            return Err(InternalPinHashParseError::Algorithm(hash.algorithm.to_string()))
+        }
+
+        if hash.salt.is_none() || hash.hash.is_none() {
+            return Err(InternalPinHashParseError::Format)
+        }
+
+        Ok(Self { phc: hash.serialize() })
+    }
+}
+
+impl TryFrom<String> for InternalPinHash {
+    type Error = InternalPinHashParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InternalPinHashParseError {
+    #[error("Expected an Argon2id PHC string in the format $argon2id$v=19$m=<memory>,t=<time>,p=<lanes>$<salt>$<hash>")]
+    Format,
+    #[error("Unsupported PIN hash algorithm '{0}', only argon2id is supported")]
+    Algorithm(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_round_trips_and_verifies() {
+        let hash = InternalPinHash::new("1234", [7; InternalPinHash::SALT_LEN]);
+        let encoded = hash.to_string();
+        assert!(encoded.starts_with("$argon2id$v=19$"));
+
+        let parsed = encoded.parse::<InternalPinHash>().unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("1234"));
+        assert!(!parsed.verify("1235"));
+        assert!(!parsed.verify(""));
+    }
+
+    #[test]
+    fn other_algorithms_are_rejected() {
+        assert!(matches!(
+            "$argon2i$v=19$m=16,t=2,p=1$c29tZXNhbHQ$aGFzaGhhc2hoYXNo".parse::<InternalPinHash>(),
+            Err(InternalPinHashParseError::Algorithm(alg)) if alg == "argon2i",
+        ));
+        assert!(matches!("blake2b$200000$abc$def".parse::<InternalPinHash>(), Err(InternalPinHashParseError::Format)));
+        assert!(matches!("$argon2id$v=19$m=16,t=2,p=1".parse::<InternalPinHash>(), Err(InternalPinHashParseError::Format)));
+    }
+
+    #[test]
+    fn debug_does_not_reveal_hash() {
+        let hash = InternalPinHash::new("1234", [7; InternalPinHash::SALT_LEN]);
+        assert_eq!(format!("{:?}", hash), "InternalPinHash(argon2id)");
+    }
+}
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Check that deimosproto builds with no features and with each service feature on its own, and
+# that every crate depending on it builds with only the features it enables
+set -e
+
+cd "$(dirname "$0")/.."
+
+for features in "" service-client service-server auth-client auth-server internal "internal channel" "internal server"; do
+    echo "Checking deimosproto with features [$features]"
+    cargo check --quiet -p deimosproto --no-default-features --features "$features" "$@"
+done
+
+for package in deimosd deimos-client-lib deimos-client; do
+    echo "Checking $package"
+    cargo check --quiet -p "$package" "$@"
+done